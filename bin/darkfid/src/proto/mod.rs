@@ -19,7 +19,10 @@
 use std::{collections::HashMap, sync::Arc};
 
 use darkfi::{
-    net::{P2p, P2pPtr, Settings},
+    net::{
+        protocol::protocol_request::{RequestResponse, RequestResponsePtr, RequestSettings},
+        P2p, P2pPtr, Settings,
+    },
     rpc::jsonrpc::JsonSubscriber,
     system::ExecutorPtr,
     validator::ValidatorPtr,
@@ -58,7 +61,9 @@ pub struct DarkfidP2pHandler {
     /// `ProtocolSync` messages handler
     sync: ProtocolSyncHandlerPtr,
     /// `ProtocolTx` messages handler
-    txs: ProtocolTxHandlerPtr,
+    pub txs: ProtocolTxHandlerPtr,
+    /// Request/response client used to query peers
+    pub requests: RequestResponsePtr,
 }
 
 impl DarkfidP2pHandler {
@@ -84,12 +89,18 @@ impl DarkfidP2pHandler {
         // Generate a new `ProtocolTx` messages handler
         let txs = ProtocolTxHandler::init(&p2p).await;
 
+        // Generate the request/response client
+        let requests = RequestResponse::new(RequestSettings {
+            timeout: settings.outbound_connect_timeout,
+            ..Default::default()
+        });
+
         info!(
             target: "darkfid::proto::mod::DarkfidP2pHandler::init",
             "Darkfid P2P handler generated successfully!"
         );
 
-        Ok(Arc::new(Self { p2p, proposals, sync, txs }))
+        Ok(Arc::new(Self { p2p, proposals, sync, txs, requests }))
     }
 
     /// Start the Darkfid P2P protocols handler for provided validator.
@@ -107,7 +118,9 @@ impl DarkfidP2pHandler {
         // Start the `ProtocolProposal` messages handler
         let proposals_sub = subscribers.get("proposals").unwrap().clone();
         let blocks_sub = subscribers.get("blocks").unwrap().clone();
-        self.proposals.start(executor, validator, &self.p2p, proposals_sub, blocks_sub).await?;
+        self.proposals
+            .start(executor, validator, &self.p2p, &self.requests, proposals_sub, blocks_sub)
+            .await?;
 
         // Start the `ProtocolSync` messages handler
         self.sync.start(executor, validator).await?;
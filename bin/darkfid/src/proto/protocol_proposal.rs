@@ -26,8 +26,11 @@ use tinyjson::JsonValue;
 use darkfi::{
     impl_p2p_message,
     net::{
-        protocol::protocol_generic::{
-            ProtocolGenericAction, ProtocolGenericHandler, ProtocolGenericHandlerPtr,
+        protocol::{
+            protocol_generic::{
+                ProtocolGenericAction, ProtocolGenericHandler, ProtocolGenericHandlerPtr,
+            },
+            protocol_request::RequestResponsePtr,
         },
         session::SESSION_DEFAULT,
         Message, P2pPtr,
@@ -80,6 +83,7 @@ impl ProtocolProposalHandler {
         executor: &ExecutorPtr,
         validator: &ValidatorPtr,
         p2p: &P2pPtr,
+        requests: &RequestResponsePtr,
         proposals_sub: JsonSubscriber,
         blocks_sub: JsonSubscriber,
     ) -> Result<()> {
@@ -89,7 +93,7 @@ impl ProtocolProposalHandler {
         );
 
         self.handler.task.clone().start(
-            handle_receive_proposal(self.handler.clone(), self.tasks.clone(), validator.clone(), p2p.clone(), proposals_sub, blocks_sub, executor.clone()),
+            handle_receive_proposal(self.handler.clone(), self.tasks.clone(), validator.clone(), p2p.clone(), requests.clone(), proposals_sub, blocks_sub, executor.clone()),
             |res| async move {
                 match res {
                     Ok(()) | Err(Error::DetachedTaskStopped) => { /* Do nothing */ }
@@ -123,11 +127,13 @@ impl ProtocolProposalHandler {
 }
 
 /// Background handler function for ProtocolProposal.
+#[allow(clippy::too_many_arguments)]
 async fn handle_receive_proposal(
     handler: ProtocolGenericHandlerPtr<ProposalMessage, ProposalMessage>,
     tasks: Arc<RwLock<HashSet<StoppableTaskPtr>>>,
     validator: ValidatorPtr,
     p2p: P2pPtr,
+    requests: RequestResponsePtr,
     proposals_sub: JsonSubscriber,
     blocks_sub: JsonSubscriber,
     executor: ExecutorPtr,
@@ -188,7 +194,7 @@ async fn handle_receive_proposal(
         let _tasks = tasks.clone();
         let _task = task.clone();
         task.clone().start(
-            handle_unknown_proposal(validator.clone(), p2p.clone(), proposals_sub.clone(), blocks_sub.clone(), channel, proposal.0),
+            handle_unknown_proposal(validator.clone(), p2p.clone(), requests.clone(), proposals_sub.clone(), blocks_sub.clone(), channel, proposal.0),
             |res| async move {
                 match res {
                     Ok(()) | Err(Error::DetachedTaskStopped) => { _tasks.write().await.remove(&_task); }
@@ -23,10 +23,10 @@ use log::{debug, error};
 
 use darkfi::{
     blockchain::{BlockInfo, Header, HeaderHash},
-    impl_p2p_message,
+    impl_p2p_message, impl_p2p_request,
     net::{
-        protocol::protocol_generic::{
-            ProtocolGenericAction, ProtocolGenericHandler, ProtocolGenericHandlerPtr,
+        protocol::protocol_request::{
+            ProtocolRequestAction, ProtocolRequestHandler, ProtocolRequestHandlerPtr,
         },
         session::SESSION_DEFAULT,
         Message, P2pPtr,
@@ -65,6 +65,7 @@ pub struct TipResponse {
 }
 
 impl_p2p_message!(TipResponse, "tipresponse");
+impl_p2p_request!(TipRequest, TipResponse, "tipunavailable");
 
 /// Structure represening a request to ask a node for up to `BATCH` headers before
 /// the provided header height.
@@ -84,7 +85,8 @@ pub struct HeaderSyncResponse {
     pub headers: Vec<Header>,
 }
 
-impl_p2p_message!(HeaderSyncResponse, "headersyncresponse");
+impl_p2p_message!(HeaderSyncResponse, "headersyncresponse", compressible);
+impl_p2p_request!(HeaderSyncRequest, HeaderSyncResponse, "headersyncunavailable");
 
 /// Structure represening a request to ask a node for up to`BATCH` blocks
 /// of provided headers.
@@ -104,7 +106,8 @@ pub struct SyncResponse {
     pub blocks: Vec<BlockInfo>,
 }
 
-impl_p2p_message!(SyncResponse, "syncresponse");
+impl_p2p_message!(SyncResponse, "syncresponse", compressible);
+impl_p2p_request!(SyncRequest, SyncResponse, "syncunavailable");
 
 /// Structure represening a request to ask a node a fork sequence.
 /// If we include a specific fork tip, they have to return its sequence,
@@ -129,7 +132,8 @@ pub struct ForkSyncResponse {
     pub proposals: Vec<Proposal>,
 }
 
-impl_p2p_message!(ForkSyncResponse, "forksyncresponse");
+impl_p2p_message!(ForkSyncResponse, "forksyncresponse", compressible);
+impl_p2p_request!(ForkSyncRequest, ForkSyncResponse, "forksyncunavailable");
 
 /// Structure represening a request to ask a node a fork header for the
 /// requested height. The fork is identified by the provided header hash.
@@ -152,6 +156,7 @@ pub struct ForkHeaderHashResponse {
 }
 
 impl_p2p_message!(ForkHeaderHashResponse, "forkheaderhashresponse");
+impl_p2p_request!(ForkHeaderHashRequest, ForkHeaderHashResponse, "forkheaderhashunavailable");
 
 /// Structure represening a request to ask a node for up to `BATCH`
 /// fork headers for provided header hashes.  The fork is identified
@@ -174,7 +179,8 @@ pub struct ForkHeadersResponse {
     pub headers: Vec<Header>,
 }
 
-impl_p2p_message!(ForkHeadersResponse, "forkheadersresponse");
+impl_p2p_message!(ForkHeadersResponse, "forkheadersresponse", compressible);
+impl_p2p_request!(ForkHeadersRequest, ForkHeadersResponse, "forkheadersunavailable");
 
 /// Structure represening a request to ask a node for up to `BATCH`
 /// fork proposals for provided header hashes.  The fork is identified
@@ -197,32 +203,32 @@ pub struct ForkProposalsResponse {
     pub proposals: Vec<Proposal>,
 }
 
-impl_p2p_message!(ForkProposalsResponse, "forkproposalsresponse");
+impl_p2p_message!(ForkProposalsResponse, "forkproposalsresponse", compressible);
+impl_p2p_request!(ForkProposalsRequest, ForkProposalsResponse, "forkproposalsunavailable");
 
 /// Atomic pointer to the `ProtocolSync` handler.
 pub type ProtocolSyncHandlerPtr = Arc<ProtocolSyncHandler>;
 
-/// Handler managing all `ProtocolSync` messages, over generic P2P protocols.
+/// Handler managing all `ProtocolSync` messages, over request/response P2P protocols.
 pub struct ProtocolSyncHandler {
-    /// The generic handler for `TipRequest` messages.
-    tip_handler: ProtocolGenericHandlerPtr<TipRequest, TipResponse>,
-    /// The generic handler for `HeaderSyncRequest` messages.
-    header_handler: ProtocolGenericHandlerPtr<HeaderSyncRequest, HeaderSyncResponse>,
-    /// The generic handler for `SyncRequest` messages.
-    sync_handler: ProtocolGenericHandlerPtr<SyncRequest, SyncResponse>,
-    /// The generic handler for `ForkSyncRequest` messages.
-    fork_sync_handler: ProtocolGenericHandlerPtr<ForkSyncRequest, ForkSyncResponse>,
-    /// The generic handler for `ForkHeaderHashRequest` messages.
-    fork_header_hash_handler:
-        ProtocolGenericHandlerPtr<ForkHeaderHashRequest, ForkHeaderHashResponse>,
-    /// The generic handler for `ForkHeadersRequest` messages.
-    fork_headers_handler: ProtocolGenericHandlerPtr<ForkHeadersRequest, ForkHeadersResponse>,
-    /// The generic handler for `ForkProposalsRequest` messages.
-    fork_proposals_handler: ProtocolGenericHandlerPtr<ForkProposalsRequest, ForkProposalsResponse>,
+    /// The request handler for `TipRequest` messages.
+    tip_handler: ProtocolRequestHandlerPtr<TipRequest>,
+    /// The request handler for `HeaderSyncRequest` messages.
+    header_handler: ProtocolRequestHandlerPtr<HeaderSyncRequest>,
+    /// The request handler for `SyncRequest` messages.
+    sync_handler: ProtocolRequestHandlerPtr<SyncRequest>,
+    /// The request handler for `ForkSyncRequest` messages.
+    fork_sync_handler: ProtocolRequestHandlerPtr<ForkSyncRequest>,
+    /// The request handler for `ForkHeaderHashRequest` messages.
+    fork_header_hash_handler: ProtocolRequestHandlerPtr<ForkHeaderHashRequest>,
+    /// The request handler for `ForkHeadersRequest` messages.
+    fork_headers_handler: ProtocolRequestHandlerPtr<ForkHeadersRequest>,
+    /// The request handler for `ForkProposalsRequest` messages.
+    fork_proposals_handler: ProtocolRequestHandlerPtr<ForkProposalsRequest>,
 }
 
 impl ProtocolSyncHandler {
-    /// Initialize the generic prototocol handlers for all `ProtocolSync` messages
+    /// Initialize the request protocol handlers for all `ProtocolSync` messages
     /// and register them to the provided P2P network, using the default session flag.
     pub async fn init(p2p: &P2pPtr) -> ProtocolSyncHandlerPtr {
         debug!(
@@ -231,18 +237,18 @@ impl ProtocolSyncHandler {
         );
 
         let tip_handler =
-            ProtocolGenericHandler::new(p2p, "ProtocolSyncTip", SESSION_DEFAULT).await;
+            ProtocolRequestHandler::new(p2p, "ProtocolSyncTip", SESSION_DEFAULT).await;
         let header_handler =
-            ProtocolGenericHandler::new(p2p, "ProtocolSyncHeader", SESSION_DEFAULT).await;
-        let sync_handler = ProtocolGenericHandler::new(p2p, "ProtocolSync", SESSION_DEFAULT).await;
+            ProtocolRequestHandler::new(p2p, "ProtocolSyncHeader", SESSION_DEFAULT).await;
+        let sync_handler = ProtocolRequestHandler::new(p2p, "ProtocolSync", SESSION_DEFAULT).await;
         let fork_sync_handler =
-            ProtocolGenericHandler::new(p2p, "ProtocolSyncFork", SESSION_DEFAULT).await;
+            ProtocolRequestHandler::new(p2p, "ProtocolSyncFork", SESSION_DEFAULT).await;
         let fork_header_hash_handler =
-            ProtocolGenericHandler::new(p2p, "ProtocolSyncForkHeaderHash", SESSION_DEFAULT).await;
+            ProtocolRequestHandler::new(p2p, "ProtocolSyncForkHeaderHash", SESSION_DEFAULT).await;
         let fork_headers_handler =
-            ProtocolGenericHandler::new(p2p, "ProtocolSyncForkHeaders", SESSION_DEFAULT).await;
+            ProtocolRequestHandler::new(p2p, "ProtocolSyncForkHeaders", SESSION_DEFAULT).await;
         let fork_proposals_handler =
-            ProtocolGenericHandler::new(p2p, "ProtocolSyncForkProposals", SESSION_DEFAULT).await;
+            ProtocolRequestHandler::new(p2p, "ProtocolSyncForkProposals", SESSION_DEFAULT).await;
 
         Arc::new(Self {
             tip_handler,
@@ -370,7 +376,7 @@ impl ProtocolSyncHandler {
 
 /// Background handler function for ProtocolSyncTip.
 async fn handle_receive_tip_request(
-    handler: ProtocolGenericHandlerPtr<TipRequest, TipResponse>,
+    handler: ProtocolRequestHandlerPtr<TipRequest>,
     validator: ValidatorPtr,
 ) -> Result<()> {
     debug!(target: "darkfid::proto::protocol_sync::handle_receive_tip_request", "START");
@@ -398,7 +404,7 @@ async fn handle_receive_tip_request(
             handler
                 .send_action(
                     channel,
-                    ProtocolGenericAction::Response(TipResponse {
+                    ProtocolRequestAction::Response(TipResponse {
                         synced: false,
                         height: None,
                         hash: None,
@@ -416,7 +422,7 @@ async fn handle_receive_tip_request(
                         target: "darkfid::proto::protocol_sync::handle_receive_tip_request",
                         "Node doesn't follow request sequence"
                     );
-                    handler.send_action(channel, ProtocolGenericAction::Skip).await;
+                    handler.send_action(channel, ProtocolRequestAction::Skip).await;
                     continue
                 }
             }
@@ -425,7 +431,7 @@ async fn handle_receive_tip_request(
                     target: "darkfid::proto::protocol_sync::handle_receive_tip_request",
                     "block_store.contains fail: {e}"
                 );
-                handler.send_action(channel, ProtocolGenericAction::Skip).await;
+                handler.send_action(channel, ProtocolRequestAction::Skip).await;
                 continue
             }
         }
@@ -438,7 +444,7 @@ async fn handle_receive_tip_request(
                     target: "darkfid::proto::protocol_sync::handle_receive_tip_request",
                     "blockchain.last fail: {e}"
                 );
-                handler.send_action(channel, ProtocolGenericAction::Skip).await;
+                handler.send_action(channel, ProtocolRequestAction::Skip).await;
                 continue
             }
         };
@@ -447,7 +453,7 @@ async fn handle_receive_tip_request(
         handler
             .send_action(
                 channel,
-                ProtocolGenericAction::Response(TipResponse {
+                ProtocolRequestAction::Response(TipResponse {
                     synced: true,
                     height: Some(tip.0),
                     hash: Some(tip.1),
@@ -459,7 +465,7 @@ async fn handle_receive_tip_request(
 
 /// Background handler function for ProtocolSyncHeader.
 async fn handle_receive_header_request(
-    handler: ProtocolGenericHandlerPtr<HeaderSyncRequest, HeaderSyncResponse>,
+    handler: ProtocolRequestHandlerPtr<HeaderSyncRequest>,
     validator: ValidatorPtr,
 ) -> Result<()> {
     debug!(target: "darkfid::proto::protocol_sync::handle_receive_header_request", "START");
@@ -482,7 +488,7 @@ async fn handle_receive_header_request(
                 target: "darkfid::proto::protocol_sync::handle_receive_header_request",
                 "Node still syncing blockchain, skipping..."
             );
-            handler.send_action(channel, ProtocolGenericAction::Skip).await;
+            handler.send_action(channel, ProtocolRequestAction::Skip).await;
             continue
         }
 
@@ -497,21 +503,21 @@ async fn handle_receive_header_request(
                     "get_headers_before fail: {}",
                     e
                 );
-                handler.send_action(channel, ProtocolGenericAction::Skip).await;
+                handler.send_action(channel, ProtocolRequestAction::Skip).await;
                 continue
             }
         };
 
         // Send response
         handler
-            .send_action(channel, ProtocolGenericAction::Response(HeaderSyncResponse { headers }))
+            .send_action(channel, ProtocolRequestAction::Response(HeaderSyncResponse { headers }))
             .await;
     }
 }
 
 /// Background handler function for ProtocolSync.
 async fn handle_receive_request(
-    handler: ProtocolGenericHandlerPtr<SyncRequest, SyncResponse>,
+    handler: ProtocolRequestHandlerPtr<SyncRequest>,
     validator: ValidatorPtr,
 ) -> Result<()> {
     debug!(target: "darkfid::proto::protocol_sync::handle_receive_request", "START");
@@ -534,7 +540,7 @@ async fn handle_receive_request(
                 target: "darkfid::proto::protocol_sync::handle_receive_request",
                 "Node still syncing blockchain, skipping..."
             );
-            handler.send_action(channel, ProtocolGenericAction::Skip).await;
+            handler.send_action(channel, ProtocolRequestAction::Skip).await;
             continue
         }
 
@@ -544,7 +550,7 @@ async fn handle_receive_request(
                 target: "darkfid::proto::protocol_sync::handle_receive_request",
                 "Node requested more blocks than allowed."
             );
-            handler.send_action(channel, ProtocolGenericAction::Skip).await;
+            handler.send_action(channel, ProtocolRequestAction::Skip).await;
             continue
         }
 
@@ -559,21 +565,21 @@ async fn handle_receive_request(
                     "get_blocks_after fail: {}",
                     e
                 );
-                handler.send_action(channel, ProtocolGenericAction::Skip).await;
+                handler.send_action(channel, ProtocolRequestAction::Skip).await;
                 continue
             }
         };
 
         // Send response
         handler
-            .send_action(channel, ProtocolGenericAction::Response(SyncResponse { blocks }))
+            .send_action(channel, ProtocolRequestAction::Response(SyncResponse { blocks }))
             .await;
     }
 }
 
 /// Background handler function for ProtocolSyncFork.
 async fn handle_receive_fork_request(
-    handler: ProtocolGenericHandlerPtr<ForkSyncRequest, ForkSyncResponse>,
+    handler: ProtocolRequestHandlerPtr<ForkSyncRequest>,
     validator: ValidatorPtr,
 ) -> Result<()> {
     debug!(target: "darkfid::proto::protocol_sync::handle_receive_fork_request", "START");
@@ -596,7 +602,7 @@ async fn handle_receive_fork_request(
                 target: "darkfid::proto::protocol_sync::handle_receive_fork_request",
                 "Node still syncing blockchain, skipping..."
             );
-            handler.send_action(channel, ProtocolGenericAction::Skip).await;
+            handler.send_action(channel, ProtocolRequestAction::Skip).await;
             continue
         }
 
@@ -615,21 +621,21 @@ async fn handle_receive_fork_request(
                     "Getting fork proposals failed: {}",
                     e
                 );
-                handler.send_action(channel, ProtocolGenericAction::Skip).await;
+                handler.send_action(channel, ProtocolRequestAction::Skip).await;
                 continue
             }
         };
 
         // Send response
         handler
-            .send_action(channel, ProtocolGenericAction::Response(ForkSyncResponse { proposals }))
+            .send_action(channel, ProtocolRequestAction::Response(ForkSyncResponse { proposals }))
             .await;
     }
 }
 
 /// Background handler function for ProtocolSyncForkHeaderHash.
 async fn handle_receive_fork_header_hash_request(
-    handler: ProtocolGenericHandlerPtr<ForkHeaderHashRequest, ForkHeaderHashResponse>,
+    handler: ProtocolRequestHandlerPtr<ForkHeaderHashRequest>,
     validator: ValidatorPtr,
 ) -> Result<()> {
     debug!(target: "darkfid::proto::protocol_sync::handle_receive_fork_header_hash_request", "START");
@@ -652,7 +658,7 @@ async fn handle_receive_fork_header_hash_request(
                 target: "darkfid::proto::protocol_sync::handle_receive_fork_header_hash_request",
                 "Node still syncing blockchain, skipping..."
             );
-            handler.send_action(channel, ProtocolGenericAction::Skip).await;
+            handler.send_action(channel, ProtocolRequestAction::Skip).await;
             continue
         }
 
@@ -671,7 +677,7 @@ async fn handle_receive_fork_header_hash_request(
                     "Getting fork header hash failed: {}",
                     e
                 );
-                handler.send_action(channel, ProtocolGenericAction::Skip).await;
+                handler.send_action(channel, ProtocolRequestAction::Skip).await;
                 continue
             }
         };
@@ -680,7 +686,7 @@ async fn handle_receive_fork_header_hash_request(
         handler
             .send_action(
                 channel,
-                ProtocolGenericAction::Response(ForkHeaderHashResponse { fork_header }),
+                ProtocolRequestAction::Response(ForkHeaderHashResponse { fork_header }),
             )
             .await;
     }
@@ -688,7 +694,7 @@ async fn handle_receive_fork_header_hash_request(
 
 /// Background handler function for ProtocolSyncForkHeaders.
 async fn handle_receive_fork_headers_request(
-    handler: ProtocolGenericHandlerPtr<ForkHeadersRequest, ForkHeadersResponse>,
+    handler: ProtocolRequestHandlerPtr<ForkHeadersRequest>,
     validator: ValidatorPtr,
 ) -> Result<()> {
     debug!(target: "darkfid::proto::protocol_sync::handle_receive_fork_headers_request", "START");
@@ -711,7 +717,7 @@ async fn handle_receive_fork_headers_request(
                 target: "darkfid::proto::protocol_sync::handle_receive_fork_headers_request",
                 "Node still syncing blockchain, skipping..."
             );
-            handler.send_action(channel, ProtocolGenericAction::Skip).await;
+            handler.send_action(channel, ProtocolRequestAction::Skip).await;
             continue
         }
 
@@ -721,7 +727,7 @@ async fn handle_receive_fork_headers_request(
                 target: "darkfid::proto::protocol_sync::handle_receive_fork_headers_request",
                 "Node requested more headers than allowed."
             );
-            handler.send_action(channel, ProtocolGenericAction::Skip).await;
+            handler.send_action(channel, ProtocolRequestAction::Skip).await;
             continue
         }
 
@@ -740,21 +746,21 @@ async fn handle_receive_fork_headers_request(
                     "Getting fork headers failed: {}",
                     e
                 );
-                handler.send_action(channel, ProtocolGenericAction::Skip).await;
+                handler.send_action(channel, ProtocolRequestAction::Skip).await;
                 continue
             }
         };
 
         // Send response
         handler
-            .send_action(channel, ProtocolGenericAction::Response(ForkHeadersResponse { headers }))
+            .send_action(channel, ProtocolRequestAction::Response(ForkHeadersResponse { headers }))
             .await;
     }
 }
 
 /// Background handler function for ProtocolSyncForkProposals.
 async fn handle_receive_fork_proposals_request(
-    handler: ProtocolGenericHandlerPtr<ForkProposalsRequest, ForkProposalsResponse>,
+    handler: ProtocolRequestHandlerPtr<ForkProposalsRequest>,
     validator: ValidatorPtr,
 ) -> Result<()> {
     debug!(target: "darkfid::proto::protocol_sync::handle_receive_fork_proposals_request", "START");
@@ -777,7 +783,7 @@ async fn handle_receive_fork_proposals_request(
                 target: "darkfid::proto::protocol_sync::handle_receive_fork_proposals_request",
                 "Node still syncing blockchain, skipping..."
             );
-            handler.send_action(channel, ProtocolGenericAction::Skip).await;
+            handler.send_action(channel, ProtocolRequestAction::Skip).await;
             continue
         }
 
@@ -787,7 +793,7 @@ async fn handle_receive_fork_proposals_request(
                 target: "darkfid::proto::protocol_sync::handle_receive_fork_proposals_request",
                 "Node requested more proposals than allowed."
             );
-            handler.send_action(channel, ProtocolGenericAction::Skip).await;
+            handler.send_action(channel, ProtocolRequestAction::Skip).await;
             continue
         }
 
@@ -806,7 +812,7 @@ async fn handle_receive_fork_proposals_request(
                     "Getting fork proposals failed: {}",
                     e
                 );
-                handler.send_action(channel, ProtocolGenericAction::Skip).await;
+                handler.send_action(channel, ProtocolRequestAction::Skip).await;
                 continue
             }
         };
@@ -815,7 +821,7 @@ async fn handle_receive_fork_proposals_request(
         handler
             .send_action(
                 channel,
-                ProtocolGenericAction::Response(ForkProposalsResponse { proposals }),
+                ProtocolRequestAction::Response(ForkProposalsResponse { proposals }),
             )
             .await;
     }
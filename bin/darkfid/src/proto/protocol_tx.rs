@@ -23,14 +23,14 @@ use tinyjson::JsonValue;
 
 use darkfi::{
     net::{
-        protocol::protocol_generic::{
-            ProtocolGenericAction, ProtocolGenericHandler, ProtocolGenericHandlerPtr,
+        protocol::protocol_dandelion::{
+            DandelionHandler, DandelionHandlerPtr, DandelionPhase, DandelionSettings,
         },
         session::SESSION_DEFAULT,
         P2pPtr,
     },
     rpc::jsonrpc::JsonSubscriber,
-    system::ExecutorPtr,
+    system::{ExecutorPtr, StoppableTask, StoppableTaskPtr},
     tx::Transaction,
     util::encoding::base64,
     validator::ValidatorPtr,
@@ -41,14 +41,17 @@ use darkfi_serial::serialize_async;
 /// Atomic pointer to the `ProtocolTx` handler.
 pub type ProtocolTxHandlerPtr = Arc<ProtocolTxHandler>;
 
-/// Handler managing [`Transaction`] messages, over a generic P2P protocol.
+/// Handler managing [`Transaction`] messages, over a Dandelion++ P2P protocol.
 pub struct ProtocolTxHandler {
-    /// The generic handler for [`Transaction`] messages.
-    handler: ProtocolGenericHandlerPtr<Transaction, Transaction>,
+    /// The Dandelion++ handler for [`Transaction`] messages.
+    handler: DandelionHandlerPtr<Transaction>,
+    /// Background task moving transactions with expired embargo,
+    /// or evicted from the stempool, into the mempool.
+    embargo_task: StoppableTaskPtr,
 }
 
 impl ProtocolTxHandler {
-    /// Initialize a generic prototocol handler for [`Transaction`] messages
+    /// Initialize a Dandelion++ prototocol handler for [`Transaction`] messages
     /// and registers it to the provided P2P network, using the default session flag.
     pub async fn init(p2p: &P2pPtr) -> ProtocolTxHandlerPtr {
         debug!(
@@ -56,12 +59,14 @@ impl ProtocolTxHandler {
             "Adding ProtocolTx to the protocol registry"
         );
 
-        let handler = ProtocolGenericHandler::new(p2p, "ProtocolTx", SESSION_DEFAULT).await;
+        let handler =
+            DandelionHandler::new(p2p, "ProtocolTx", SESSION_DEFAULT, DandelionSettings::default())
+                .await;
 
-        Arc::new(Self { handler })
+        Arc::new(Self { handler, embargo_task: StoppableTask::new() })
     }
 
-    /// Start the `ProtocolTx` background task.
+    /// Start the `ProtocolTx` background tasks.
     pub async fn start(
         &self,
         executor: &ExecutorPtr,
@@ -73,8 +78,10 @@ impl ProtocolTxHandler {
             "Starting ProtocolTx handler task..."
         );
 
+        self.handler.start(executor);
+
         self.handler.task.clone().start(
-            handle_receive_tx(self.handler.clone(), validator.clone(), subscriber),
+            handle_receive_tx(self.handler.clone(), validator.clone(), subscriber.clone()),
             |res| async move {
                 match res {
                     Ok(()) | Err(Error::DetachedTaskStopped) => { /* Do nothing */ }
@@ -85,6 +92,18 @@ impl ProtocolTxHandler {
             executor.clone(),
         );
 
+        self.embargo_task.clone().start(
+            handle_embargo_tx(self.handler.clone(), validator.clone(), subscriber),
+            |res| async move {
+                match res {
+                    Ok(()) | Err(Error::DetachedTaskStopped) => { /* Do nothing */ }
+                    Err(e) => error!(target: "darkfid::proto::protocol_tx::start", "Failed starting ProtocolTx embargo task: {e}"),
+                }
+            },
+            Error::DetachedTaskStopped,
+            executor.clone(),
+        );
+
         debug!(
             target: "darkfid::proto::protocol_tx::start",
             "ProtocolTx handler task started!"
@@ -93,24 +112,34 @@ impl ProtocolTxHandler {
         Ok(())
     }
 
-    /// Stop the `ProtocolTx` background task.
+    /// Stop the `ProtocolTx` background tasks.
     pub async fn stop(&self) {
         debug!(target: "darkfid::proto::protocol_tx::stop", "Terminating ProtocolTx handler task...");
-        self.handler.task.stop().await;
+        self.embargo_task.stop().await;
+        self.handler.stop().await;
         debug!(target: "darkfid::proto::protocol_tx::stop", "ProtocolTx handler task terminated!");
     }
+
+    /// Relay a locally created transaction, starting in stem phase.
+    /// Returns the phase the transaction was relayed in.
+    pub async fn broadcast(&self, tx: &Transaction) -> DandelionPhase {
+        self.handler.broadcast(tx).await
+    }
 }
 
 /// Background handler function for ProtocolTx.
+///
+/// Stem phase transactions are only verified, and kept out of the
+/// mempool until they get fluffed, by us or the network.
 async fn handle_receive_tx(
-    handler: ProtocolGenericHandlerPtr<Transaction, Transaction>,
+    handler: DandelionHandlerPtr<Transaction>,
     validator: ValidatorPtr,
     subscriber: JsonSubscriber,
 ) -> Result<()> {
     debug!(target: "darkfid::proto::protocol_tx::handle_receive_tx", "START");
     loop {
         // Wait for a new transaction message
-        let (channel, tx) = match handler.receiver.recv().await {
+        let (channel, tx, phase) = match handler.receiver.recv().await {
             Ok(r) => r,
             Err(e) => {
                 debug!(
@@ -127,22 +156,68 @@ async fn handle_receive_tx(
                 target: "darkfid::proto::protocol_tx::handle_receive_tx",
                 "Node still syncing blockchain, skipping..."
             );
-            handler.send_action(channel, ProtocolGenericAction::Skip).await;
             continue
         }
 
-        // Append transaction
-        if let Err(e) = validator.append_tx(&tx, true).await {
+        // Verify the transaction, appending it only if it's public
+        if let Err(e) = validator.append_tx(&tx, phase == DandelionPhase::Fluff).await {
             debug!(
                 target: "darkfid::proto::protocol_tx::handle_receive_tx",
                 "append_tx fail: {e}"
             );
-            handler.send_action(channel, ProtocolGenericAction::Skip).await;
             continue
         }
 
-        // Signal handler to broadcast the valid transaction to rest nodes
-        handler.send_action(channel, ProtocolGenericAction::Broadcast).await;
+        // Relay the valid transaction to rest nodes
+        if handler.relay(channel, &tx, phase).await == DandelionPhase::Stem {
+            continue
+        }
+
+        // Transaction went public after we relayed it in stem phase
+        if phase == DandelionPhase::Stem {
+            if let Err(e) = validator.append_tx(&tx, true).await {
+                debug!(
+                    target: "darkfid::proto::protocol_tx::handle_receive_tx",
+                    "append_tx fail: {e}"
+                );
+                continue
+            }
+        }
+
+        // Notify subscriber
+        let encoded_tx = JsonValue::String(base64::encode(&serialize_async(&tx).await));
+        subscriber.notify(vec![encoded_tx].into()).await;
+    }
+}
+
+/// Background function appending transactions that got fluffed after
+/// their embargo expired, or after getting evicted from the stempool,
+/// to the mempool.
+async fn handle_embargo_tx(
+    handler: DandelionHandlerPtr<Transaction>,
+    validator: ValidatorPtr,
+    subscriber: JsonSubscriber,
+) -> Result<()> {
+    debug!(target: "darkfid::proto::protocol_tx::handle_embargo_tx", "START");
+    loop {
+        let tx = match handler.embargo_receiver.recv().await {
+            Ok(tx) => tx,
+            Err(e) => {
+                debug!(
+                    target: "darkfid::proto::protocol_tx::handle_embargo_tx",
+                    "recv fail: {e}"
+                );
+                continue
+            }
+        };
+
+        if let Err(e) = validator.append_tx(&tx, true).await {
+            debug!(
+                target: "darkfid::proto::protocol_tx::handle_embargo_tx",
+                "append_tx fail: {e}"
+            );
+            continue
+        }
 
         // Notify subscriber
         let encoded_tx = JsonValue::String(base64::encode(&serialize_async(&tx).await));
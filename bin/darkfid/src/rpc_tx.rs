@@ -21,6 +21,7 @@ use log::{error, warn};
 use tinyjson::JsonValue;
 
 use darkfi::{
+    net::protocol::protocol_dandelion::DandelionPhase,
     rpc::jsonrpc::{
         ErrorCode::{InternalError, InvalidParams},
         JsonError, JsonResponse, JsonResult,
@@ -119,25 +120,30 @@ impl DarkfiNode {
             }
         };
 
-        // Block production participants can directly perform
-        // the state transition check and append to their
-        // pending transactions store.
-        let error_message = if self.rpc_client.is_some() {
-            "Failed to append transaction to mempool"
-        } else {
-            "Failed to validate state transition"
-        };
-        // We'll perform the state transition check here.
-        if let Err(e) = self.validator.append_tx(&tx, self.rpc_client.is_some()).await {
-            error!(target: "darkfid::rpc::tx_broadcast", "{}: {}", error_message, e);
+        // We'll perform the state transition check here. The
+        // transaction is relayed in Dandelion++ stem phase, so
+        // we keep it out of our pending transactions store until
+        // it gets fluffed.
+        if let Err(e) = self.validator.append_tx(&tx, false).await {
+            error!(target: "darkfid::rpc::tx_broadcast", "Failed to validate state transition: {}", e);
             return server_error(RpcError::TxSimulationFail, id, None)
         };
 
-        self.p2p_handler.p2p.broadcast(&tx).await;
         if !self.p2p_handler.p2p.is_connected() {
             warn!(target: "darkfid::rpc::tx_broadcast", "No connected channels to broadcast tx");
         }
 
+        // Block production participants can directly append
+        // fluffed transactions to their pending transactions store.
+        if self.p2p_handler.txs.broadcast(&tx).await == DandelionPhase::Fluff &&
+            self.rpc_client.is_some()
+        {
+            if let Err(e) = self.validator.append_tx(&tx, true).await {
+                error!(target: "darkfid::rpc::tx_broadcast", "Failed to append transaction to mempool: {}", e);
+                return server_error(RpcError::TxSimulationFail, id, None)
+            };
+        }
+
         let tx_hash = tx.hash().to_string();
         JsonResponse::new(JsonValue::String(tx_hash), id).into()
     }
@@ -16,25 +16,22 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use std::collections::HashMap;
-
 use darkfi::{
     blockchain::HeaderHash, net::ChannelPtr, rpc::jsonrpc::JsonSubscriber, system::sleep,
     util::encoding::base64, validator::consensus::Proposal, Error, Result,
 };
 use darkfi_serial::serialize_async;
 use log::{debug, info, warn};
-use rand::{prelude::SliceRandom, rngs::OsRng};
 use tinyjson::JsonValue;
 
 use crate::{
-    proto::{
-        ForkSyncRequest, ForkSyncResponse, HeaderSyncRequest, HeaderSyncResponse, SyncRequest,
-        SyncResponse, TipRequest, TipResponse, BATCH,
-    },
+    proto::{ForkSyncRequest, HeaderSyncRequest, SyncRequest, TipRequest, BATCH},
     DarkfiNodePtr,
 };
 
+/// Consecutive request failures after which a sync step gives up
+const MAX_REQUEST_FAILURES: u64 = 5;
+
 // TODO: Parallelize independent requests.
 //       We can also make them be like torrents, where we retrieve chunks not in order.
 /// async task used for block syncing.
@@ -131,124 +128,62 @@ pub async fn sync_task(node: &DarkfiNodePtr, checkpoint: Option<(u32, HeaderHash
 }
 
 /// Auxiliary function to block until node is connected to at least one synced peer,
-/// and retrieve the synced peers tips.
-async fn synced_peers(
+/// then ask all peers for their current tip and find the most common one.
+async fn most_common_tip(
     node: &DarkfiNodePtr,
     last_tip: &HeaderHash,
     checkpoint: Option<(u32, HeaderHash)>,
-) -> HashMap<(u32, [u8; 32]), Vec<ChannelPtr>> {
-    info!(target: "darkfid::task::sync::synced_peers", "Receiving tip from peers...");
+) -> (u32, Vec<ChannelPtr>) {
+    info!(target: "darkfid::task::sync::most_common_tip", "Receiving tip from peers...");
+    let requests = &node.p2p_handler.requests;
     let comms_timeout = node.p2p_handler.p2p.settings().read().await.outbound_connect_timeout;
-    let mut tips = HashMap::new();
     loop {
         // Grab channels
-        let peers = node.p2p_handler.p2p.hosts().channels();
-
-        // Ask each peer(if we got any) if they are synced
-        for peer in peers {
-            // If a checkpoint was provider, we check that the peer follows that sequence
-            if let Some(c) = checkpoint {
-                // Communication setup
-                let Ok(response_sub) = peer.subscribe_msg::<HeaderSyncResponse>().await else {
-                    debug!(target: "darkfid::task::sync::synced_peers", "Failure during `HeaderSyncResponse` communication setup with peer: {peer:?}");
-                    continue
-                };
-
-                // Node creates a `HeaderSyncRequest` and sends it
-                let request = HeaderSyncRequest { height: c.0 + 1 };
-                if let Err(e) = peer.send(&request).await {
-                    debug!(target: "darkfid::task::sync::synced_peers", "Failure during `HeaderSyncRequest` send to peer {peer:?}: {e}");
-                    continue
-                };
-
-                // Node waits for response
-                let Ok(response) = response_sub.receive_with_timeout(comms_timeout).await else {
-                    debug!(target: "darkfid::task::sync::synced_peers", "Timeout while waiting for `HeaderSyncResponse` from peer: {peer:?}");
-                    continue
-                };
+        let mut peers = node.p2p_handler.p2p.hosts().channels();
+
+        // If a checkpoint was provider, we keep the peers following that sequence
+        if let Some(c) = checkpoint {
+            let request = HeaderSyncRequest { height: c.0 + 1 };
+            peers = requests
+                .request_all(&peers, &request)
+                .await
+                .into_iter()
+                .filter_map(|(peer, response)| {
+                    if response.headers.is_empty() || response.headers.last().unwrap().hash() != c.1
+                    {
+                        debug!(target: "darkfid::task::sync::most_common_tip", "Invalid `HeaderSyncResponse` from peer: {peer:?}");
+                        return None
+                    }
+                    Some(peer)
+                })
+                .collect();
+        }
 
-                // Handle response
-                if response.headers.is_empty() || response.headers.last().unwrap().hash() != c.1 {
-                    debug!(target: "darkfid::task::sync::synced_peers", "Invalid `HeaderSyncResponse` from peer: {peer:?}");
-                    continue
+        // Ask each peer(if we got any) for their tip, and keep
+        // the heighest tip with the most synced peers.
+        let request = TipRequest { tip: *last_tip };
+        let common_tip = requests
+            .request_majority(&peers, &request, |response| {
+                if !response.synced {
+                    return None
                 }
-            }
-
-            // Communication setup
-            let Ok(response_sub) = peer.subscribe_msg::<TipResponse>().await else {
-                debug!(target: "darkfid::task::sync::synced_peers", "Failure during `TipResponse` communication setup with peer: {peer:?}");
-                continue
-            };
-
-            // Node creates a `TipRequest` and sends it
-            let request = TipRequest { tip: *last_tip };
-            if let Err(e) = peer.send(&request).await {
-                debug!(target: "darkfid::task::sync::synced_peers", "Failure during `TipRequest` send to peer {peer:?}: {e}");
-                continue
-            };
-
-            // Node waits for response
-            let Ok(response) = response_sub.receive_with_timeout(comms_timeout).await else {
-                debug!(target: "darkfid::task::sync::synced_peers", "Timeout while waiting for `TipResponse` from peer: {peer:?}");
-                continue
-            };
-
-            // Handle response
-            if response.synced && response.height.is_some() && response.hash.is_some() {
-                let tip = (response.height.unwrap(), *response.hash.unwrap().inner());
-                let Some(tip_peers) = tips.get_mut(&tip) else {
-                    tips.insert(tip, vec![peer.clone()]);
-                    continue
-                };
-                tip_peers.push(peer.clone());
-            }
-        }
+                Some((response.height?, *response.hash?.inner()))
+            })
+            .await;
 
-        // Check if we got any tips
-        if !tips.is_empty() {
-            break
+        if let Some(((height, hash), responses)) = common_tip {
+            info!(target: "darkfid::task::sync::most_common_tip", "Most common tip: {height} - {}", HeaderHash::new(hash));
+            return (height, responses.into_iter().map(|(peer, _)| peer).collect())
         }
 
-        warn!(target: "darkfid::task::sync::synced_peers", "Node is not connected to other synced nodes, waiting to retry...");
+        warn!(target: "darkfid::task::sync::most_common_tip", "Node is not connected to other synced nodes, waiting to retry...");
         let subscription = node.p2p_handler.p2p.hosts().subscribe_channel().await;
         let _ = subscription.receive().await;
         subscription.unsubscribe().await;
 
-        info!(target: "darkfid::task::sync::synced_peers", "Sleeping for {comms_timeout} to allow for more nodes to connect...");
+        info!(target: "darkfid::task::sync::most_common_tip", "Sleeping for {comms_timeout} to allow for more nodes to connect...");
         sleep(comms_timeout).await;
     }
-
-    tips
-}
-
-/// Auxiliary function to ask all peers for their current tip and find the most common one.
-async fn most_common_tip(
-    node: &DarkfiNodePtr,
-    last_tip: &HeaderHash,
-    checkpoint: Option<(u32, HeaderHash)>,
-) -> (u32, Vec<ChannelPtr>) {
-    // Grab synced peers tips
-    let tips = synced_peers(node, last_tip, checkpoint).await;
-
-    // Grab the most common highest tip peers
-    info!(target: "darkfid::task::sync::most_common_tip", "Finding most common tip...");
-    let mut common_tip = (0, [0u8; 32], vec![]);
-    for (tip, peers) in tips {
-        // Check if tip peers is less than the most common tip peers
-        if peers.len() < common_tip.2.len() {
-            continue;
-        }
-        // If peers are the same length, skip if tip height is less than
-        // the most common tip height.
-        if peers.len() == common_tip.2.len() || tip.0 < common_tip.0 {
-            continue;
-        }
-        // Keep the heighest tip with the most peers
-        common_tip = (tip.0, tip.1, peers);
-    }
-
-    info!(target: "darkfid::task::sync::most_common_tip", "Most common tip: {} - {}", common_tip.0, HeaderHash::new(common_tip.1));
-    (common_tip.0, common_tip.2)
 }
 
 /// Auxiliary function to retrieve headers backwards until our last known one and verify them.
@@ -259,55 +194,41 @@ async fn retrieve_headers(
     tip_height: u32,
 ) -> Result<()> {
     info!(target: "darkfid::task::sync::retrieve_headers", "Retrieving missing headers from peers...");
-    // Communication setup
-    let mut peer_subs = vec![];
-    for peer in peers {
-        match peer.subscribe_msg::<HeaderSyncResponse>().await {
-            Ok(response_sub) => peer_subs.push(Some(response_sub)),
-            Err(e) => {
-                debug!(target: "darkfid::task::sync::retrieve_headers", "Failure during `HeaderSyncResponse` communication setup with peer {peer:?}: {e}");
-                peer_subs.push(None)
-            }
-        }
-    }
-    let comms_timeout = node.p2p_handler.p2p.settings().read().await.outbound_connect_timeout;
+    let requests = &node.p2p_handler.requests;
 
     // We subtract 1 since tip_height is increased by one
     let total = tip_height - last_known - 1;
     let mut last_tip_height = tip_height;
-    'headers_loop: loop {
-        for (index, peer) in peers.iter().enumerate() {
-            // Grab the response sub reference
-            let Some(ref response_sub) = peer_subs[index] else {
-                continue;
-            };
-
-            // Node creates a `HeaderSyncRequest` and sends it
-            let request = HeaderSyncRequest { height: last_tip_height };
-            if let Err(e) = peer.send(&request).await {
-                debug!(target: "darkfid::task::sync::retrieve_headers", "Failure during `HeaderSyncRequest` send to peer {peer:?}: {e}");
-                continue
-            };
-
-            // Node waits for response
-            let Ok(response) = response_sub.receive_with_timeout(comms_timeout).await else {
-                debug!(target: "darkfid::task::sync::retrieve_headers", "Timeout while waiting for `HeaderSyncResponse` from peer: {peer:?}");
+    let mut failures = 0;
+    loop {
+        // Node creates a `HeaderSyncRequest` and sends it to a peer
+        let request = HeaderSyncRequest { height: last_tip_height };
+        let response = match requests.request_any(peers, &request).await {
+            Ok((_, response)) => response,
+            Err(e) => {
+                debug!(target: "darkfid::task::sync::retrieve_headers", "Failure during `HeaderSyncRequest` to peers: {e}");
+                failures += 1;
+                if failures >= MAX_REQUEST_FAILURES {
+                    return Err(e)
+                }
+                sleep(failures).await;
                 continue
-            };
-
-            // Retain only the headers after our last known
-            let mut response_headers = response.headers.to_vec();
-            response_headers.retain(|h| h.height > last_known);
-
-            if response_headers.is_empty() {
-                break 'headers_loop
             }
+        };
+        failures = 0;
+
+        // Retain only the headers after our last known
+        let mut response_headers = response.headers;
+        response_headers.retain(|h| h.height > last_known);
 
-            // Store the headers
-            node.validator.blockchain.headers.insert_sync(&response_headers)?;
-            last_tip_height = response_headers[0].height;
-            info!(target: "darkfid::task::sync::retrieve_headers", "Headers received: {}/{}", node.validator.blockchain.headers.len_sync(), total);
+        if response_headers.is_empty() {
+            break
         }
+
+        // Store the headers
+        node.validator.blockchain.headers.insert_sync(&response_headers)?;
+        last_tip_height = response_headers[0].height;
+        info!(target: "darkfid::task::sync::retrieve_headers", "Headers received: {}/{}", node.validator.blockchain.headers.len_sync(), total);
     }
 
     // Check if we retrieved any new headers
@@ -379,105 +300,88 @@ async fn retrieve_blocks(
 ) -> Result<(u32, HeaderHash)> {
     info!(target: "darkfid::task::sync::retrieve_blocks", "Retrieving missing blocks from peers...");
     let mut last_received = last_known;
-    // Communication setup
-    let mut peer_subs = vec![];
-    for peer in peers {
-        match peer.subscribe_msg::<SyncResponse>().await {
-            Ok(response_sub) => peer_subs.push(Some(response_sub)),
-            Err(e) => {
-                debug!(target: "darkfid::task::sync::retrieve_blocks", "Failure during `SyncResponse` communication setup with peer {peer:?}: {e}");
-                peer_subs.push(None)
-            }
-        }
-    }
-    let comms_timeout = node.p2p_handler.p2p.settings().read().await.outbound_connect_timeout;
+    let requests = &node.p2p_handler.requests;
 
     let mut received_blocks = 0;
     let total = node.validator.blockchain.headers.len_sync();
+    let mut failures = 0;
     'blocks_loop: loop {
-        'peers_loop: for (index, peer) in peers.iter().enumerate() {
-            // Grab the response sub reference
-            let Some(ref response_sub) = peer_subs[index] else {
-                continue;
-            };
-
-            // Grab first `BATCH` headers
-            let headers = node.validator.blockchain.headers.get_after_sync(0, BATCH)?;
-            if headers.is_empty() {
-                break 'blocks_loop
-            }
-            let mut headers_hashes = Vec::with_capacity(headers.len());
-            let mut synced_headers = Vec::with_capacity(headers.len());
-            for header in &headers {
-                headers_hashes.push(header.hash());
-                synced_headers.push(header.height);
-            }
+        // Grab first `BATCH` headers
+        let headers = node.validator.blockchain.headers.get_after_sync(0, BATCH)?;
+        if headers.is_empty() {
+            break 'blocks_loop
+        }
+        let mut headers_hashes = Vec::with_capacity(headers.len());
+        let mut synced_headers = Vec::with_capacity(headers.len());
+        for header in &headers {
+            headers_hashes.push(header.hash());
+            synced_headers.push(header.height);
+        }
 
-            // Node creates a `SyncRequest` and sends it
-            let request = SyncRequest { headers: headers_hashes.clone() };
-            if let Err(e) = peer.send(&request).await {
-                debug!(target: "darkfid::task::sync::retrieve_blocks", "Failure during `SyncRequest` send to peer {peer:?}: {e}");
+        // Node creates a `SyncRequest` and sends it to a peer
+        let request = SyncRequest { headers: headers_hashes.clone() };
+        let response = match requests.request_any(peers, &request).await {
+            Ok((_, response)) => response,
+            Err(e) => {
+                debug!(target: "darkfid::task::sync::retrieve_blocks", "Failure during `SyncRequest` to peers: {e}");
+                failures += 1;
+                if failures >= MAX_REQUEST_FAILURES {
+                    return Err(e)
+                }
+                sleep(failures).await;
                 continue
-            };
-
-            // Node waits for response
-            let Ok(response) = response_sub.receive_with_timeout(comms_timeout).await else {
-                debug!(target: "darkfid::task::sync::retrieve_blocks", "Timeout while waiting for `SyncResponse` from peer: {peer:?}");
+            }
+        };
+        failures = 0;
+
+        // Verify and store retrieved blocks
+        debug!(target: "darkfid::task::sync::retrieve_blocks", "Processing received blocks");
+        received_blocks += response.blocks.len();
+        if checkpoint_blocks {
+            if let Err(e) =
+                node.validator.add_checkpoint_blocks(&response.blocks, &headers_hashes).await
+            {
+                debug!(target: "darkfid::task::sync::retrieve_blocks", "Error while adding checkpoint blocks: {e}");
                 continue
             };
-
-            // Verify and store retrieved blocks
-            debug!(target: "darkfid::task::sync::retrieve_blocks", "Processing received blocks");
-            received_blocks += response.blocks.len();
-            if checkpoint_blocks {
-                if let Err(e) =
-                    node.validator.add_checkpoint_blocks(&response.blocks, &headers_hashes).await
+        } else {
+            for block in &response.blocks {
+                if let Err(e) = node.validator.append_proposal(&Proposal::new(block.clone())).await
                 {
-                    debug!(target: "darkfid::task::sync::retrieve_blocks", "Error while adding checkpoint blocks: {e}");
-                    continue
+                    debug!(target: "darkfid::task::sync::retrieve_blocks", "Error while appending proposal: {e}");
+                    continue 'blocks_loop
                 };
-            } else {
-                for block in &response.blocks {
-                    if let Err(e) =
-                        node.validator.append_proposal(&Proposal::new(block.clone())).await
-                    {
-                        debug!(target: "darkfid::task::sync::retrieve_blocks", "Error while appending proposal: {e}");
-                        continue 'peers_loop
-                    };
-                }
             }
-            last_received = (*synced_headers.last().unwrap(), *headers_hashes.last().unwrap());
-
-            // Remove synced headers
-            node.validator.blockchain.headers.remove_sync(&synced_headers)?;
-
-            if checkpoint_blocks {
+        }
+        last_received = (*synced_headers.last().unwrap(), *headers_hashes.last().unwrap());
+
+        // Remove synced headers
+        node.validator.blockchain.headers.remove_sync(&synced_headers)?;
+
+        if checkpoint_blocks {
+            // Notify subscriber
+            let mut notif_blocks = Vec::with_capacity(response.blocks.len());
+            info!(target: "darkfid::task::sync::retrieve_blocks", "Blocks added:");
+            for (index, block) in response.blocks.iter().enumerate() {
+                info!(target: "darkfid::task::sync::retrieve_blocks", "\t{} - {}", headers_hashes[index], headers[index].height);
+                notif_blocks.push(JsonValue::String(base64::encode(&serialize_async(block).await)));
+            }
+            block_sub.notify(JsonValue::Array(notif_blocks)).await;
+        } else {
+            // Perform confirmation for received blocks
+            let confirmed = node.validator.confirmation().await?;
+            if !confirmed.is_empty() {
                 // Notify subscriber
-                let mut notif_blocks = Vec::with_capacity(response.blocks.len());
-                info!(target: "darkfid::task::sync::retrieve_blocks", "Blocks added:");
-                for (index, block) in response.blocks.iter().enumerate() {
-                    info!(target: "darkfid::task::sync::retrieve_blocks", "\t{} - {}", headers_hashes[index], headers[index].height);
+                let mut notif_blocks = Vec::with_capacity(confirmed.len());
+                for block in confirmed {
                     notif_blocks
-                        .push(JsonValue::String(base64::encode(&serialize_async(block).await)));
+                        .push(JsonValue::String(base64::encode(&serialize_async(&block).await)));
                 }
                 block_sub.notify(JsonValue::Array(notif_blocks)).await;
-            } else {
-                // Perform confirmation for received blocks
-                let confirmed = node.validator.confirmation().await?;
-                if !confirmed.is_empty() {
-                    // Notify subscriber
-                    let mut notif_blocks = Vec::with_capacity(confirmed.len());
-                    for block in confirmed {
-                        notif_blocks.push(JsonValue::String(base64::encode(
-                            &serialize_async(&block).await,
-                        )));
-                    }
-                    block_sub.notify(JsonValue::Array(notif_blocks)).await;
-                }
             }
-
-            info!(target: "darkfid::task::sync::retrieve_blocks", "Blocks received: {}/{}", received_blocks, total);
         }
+
+        info!(target: "darkfid::task::sync::retrieve_blocks", "Blocks received: {}/{}", received_blocks, total);
     }
 
     Ok(last_received)
@@ -486,30 +390,16 @@ async fn retrieve_blocks(
 /// Auxiliary function to retrieve best fork state from a random peer.
 async fn sync_best_fork(node: &DarkfiNodePtr, peers: &[ChannelPtr], last_tip: &HeaderHash) {
     info!(target: "darkfid::task::sync::sync_best_fork", "Syncing fork states from peers...");
-    // Getting a random peer to ask for blocks
-    let peer = &peers.choose(&mut OsRng).unwrap();
-
-    // Communication setup
-    let Ok(response_sub) = peer.subscribe_msg::<ForkSyncResponse>().await else {
-        debug!(target: "darkfid::task::sync::sync_best_fork", "Failure during `ForkSyncResponse` communication setup with peer: {peer:?}");
-        return
-    };
     let notif_sub = node.subscribers.get("proposals").unwrap();
 
-    // Node creates a `ForkSyncRequest` and sends it
+    // Node creates a `ForkSyncRequest` and sends it to a random peer
     let request = ForkSyncRequest { tip: *last_tip, fork_tip: None };
-    if let Err(e) = peer.send(&request).await {
-        debug!(target: "darkfid::task::sync::sync_best_fork", "Failure during `ForkSyncRequest` send to peer {peer:?}: {e}");
-        return
-    };
-
-    // Node waits for response
-    let Ok(response) = response_sub
-        .receive_with_timeout(node.p2p_handler.p2p.settings().read().await.outbound_connect_timeout)
-        .await
-    else {
-        debug!(target: "darkfid::task::sync::sync_best_fork", "Timeout while waiting for `ForkSyncResponse` from peer: {peer:?}");
-        return
+    let response = match node.p2p_handler.requests.request_any(peers, &request).await {
+        Ok((_, response)) => response,
+        Err(e) => {
+            debug!(target: "darkfid::task::sync::sync_best_fork", "Failure during `ForkSyncRequest` to peers: {e}");
+            return
+        }
     };
 
     // Verify and store retrieved proposals
@@ -21,7 +21,7 @@ use tinyjson::JsonValue;
 
 use darkfi::{
     blockchain::BlockDifficulty,
-    net::{ChannelPtr, P2pPtr},
+    net::{protocol::protocol_request::RequestResponsePtr, ChannelPtr, P2pPtr},
     rpc::jsonrpc::JsonSubscriber,
     util::encoding::base64,
     validator::{
@@ -36,8 +36,7 @@ use darkfi::{
 use darkfi_serial::serialize_async;
 
 use crate::proto::{
-    ForkHeaderHashRequest, ForkHeaderHashResponse, ForkHeadersRequest, ForkHeadersResponse,
-    ForkProposalsRequest, ForkProposalsResponse, ForkSyncRequest, ForkSyncResponse,
+    ForkHeaderHashRequest, ForkHeadersRequest, ForkProposalsRequest, ForkSyncRequest,
     ProposalMessage, BATCH,
 };
 
@@ -45,6 +44,7 @@ use crate::proto::{
 pub async fn handle_unknown_proposal(
     validator: ValidatorPtr,
     p2p: P2pPtr,
+    requests: RequestResponsePtr,
     proposals_sub: JsonSubscriber,
     blocks_sub: JsonSubscriber,
     channel: u32,
@@ -57,12 +57,6 @@ pub async fn handle_unknown_proposal(
         return Ok(())
     };
 
-    // Communication setup
-    let Ok(response_sub) = channel.subscribe_msg::<ForkSyncResponse>().await else {
-        error!(target: "darkfid::task::handle_unknown_proposal", "Failure during `ForkSyncResponse` communication setup with peer: {channel:?}");
-        return Ok(())
-    };
-
     // Grab last known block to create the request and execute it
     let last = match validator.blockchain.last() {
         Ok(l) => l,
@@ -72,16 +66,7 @@ pub async fn handle_unknown_proposal(
         }
     };
     let request = ForkSyncRequest { tip: last.1, fork_tip: Some(proposal.hash) };
-    if let Err(e) = channel.send(&request).await {
-        debug!(target: "darkfid::task::handle_unknown_proposal", "Channel send failed: {e}");
-        return Ok(())
-    };
-
-    // Node waits for response
-    let response = match response_sub
-        .receive_with_timeout(p2p.settings().read().await.outbound_connect_timeout)
-        .await
-    {
+    let response = match requests.request(&channel, &request).await {
         Ok(r) => r,
         Err(e) => {
             debug!(target: "darkfid::task::handle_unknown_proposal", "Asking peer for fork sequence failed: {e}");
@@ -96,25 +81,29 @@ pub async fn handle_unknown_proposal(
     // Response should not be empty
     if response.proposals.is_empty() {
         warn!(target: "darkfid::task::handle_unknown_proposal", "Peer responded with empty sequence, node might be out of sync!");
-        return handle_reorg(validator, p2p, proposals_sub, blocks_sub, channel, proposal).await
+        return handle_reorg(validator, p2p, requests, proposals_sub, blocks_sub, channel, proposal)
+            .await
     }
 
     // Sequence length must correspond to requested height
     if response.proposals.len() as u32 != proposal.block.header.height - last.0 {
         debug!(target: "darkfid::task::handle_unknown_proposal", "Response sequence length is erroneous");
-        return handle_reorg(validator, p2p, proposals_sub, blocks_sub, channel, proposal).await
+        return handle_reorg(validator, p2p, requests, proposals_sub, blocks_sub, channel, proposal)
+            .await
     }
 
     // First proposal must extend canonical
     if response.proposals[0].block.header.previous != last.1 {
         debug!(target: "darkfid::task::handle_unknown_proposal", "Response sequence doesn't extend canonical");
-        return handle_reorg(validator, p2p, proposals_sub, blocks_sub, channel, proposal).await
+        return handle_reorg(validator, p2p, requests, proposals_sub, blocks_sub, channel, proposal)
+            .await
     }
 
     // Last proposal must be the same as the one requested
     if response.proposals.last().unwrap().hash != proposal.hash {
         debug!(target: "darkfid::task::handle_unknown_proposal", "Response sequence doesn't correspond to requested tip");
-        return handle_reorg(validator, p2p, proposals_sub, blocks_sub, channel, proposal).await
+        return handle_reorg(validator, p2p, requests, proposals_sub, blocks_sub, channel, proposal)
+            .await
     }
 
     // Process response proposals
@@ -154,6 +143,7 @@ pub async fn handle_unknown_proposal(
 async fn handle_reorg(
     validator: ValidatorPtr,
     p2p: P2pPtr,
+    requests: RequestResponsePtr,
     proposals_sub: JsonSubscriber,
     blocks_sub: JsonSubscriber,
     channel: ChannelPtr,
@@ -167,12 +157,6 @@ async fn handle_reorg(
         return Ok(())
     }
 
-    // Communication setup
-    let Ok(response_sub) = channel.subscribe_msg::<ForkHeaderHashResponse>().await else {
-        error!(target: "darkfid::task::handle_reorg", "Failure during `ForkHeaderHashResponse` communication setup with peer: {channel:?}");
-        return Ok(())
-    };
-
     // Keep track of received header hashes sequence
     let mut peer_header_hashes = vec![];
 
@@ -182,16 +166,7 @@ async fn handle_reorg(
     for height in (0..proposal.block.header.height).rev() {
         // Request peer header hash for this height
         let request = ForkHeaderHashRequest { height, fork_header: proposal.hash };
-        if let Err(e) = channel.send(&request).await {
-            debug!(target: "darkfid::task::handle_reorg", "Channel send failed: {e}");
-            return Ok(())
-        };
-
-        // Node waits for response
-        let response = match response_sub
-            .receive_with_timeout(p2p.settings().read().await.outbound_connect_timeout)
-            .await
-        {
+        let response = match requests.request(&channel, &request).await {
             Ok(r) => r,
             Err(e) => {
                 debug!(target: "darkfid::task::handle_reorg", "Asking peer for header hash failed: {e}");
@@ -227,12 +202,6 @@ async fn handle_reorg(
         return Ok(())
     }
 
-    // Communication setup
-    let Ok(response_sub) = channel.subscribe_msg::<ForkHeadersResponse>().await else {
-        error!(target: "darkfid::task::handle_reorg", "Failure during `ForkHeadersResponse` communication setup with peer: {channel:?}");
-        return Ok(())
-    };
-
     // Grab last common height ranks
     let last_common_height = previous_height;
     let last_difficulty = match previous_height {
@@ -268,16 +237,7 @@ async fn handle_reorg(
 
         // Request peer headers
         let request = ForkHeadersRequest { headers: batch.clone(), fork_header: proposal.hash };
-        if let Err(e) = channel.send(&request).await {
-            debug!(target: "darkfid::task::handle_reorg", "Channel send failed: {e}");
-            return Ok(())
-        };
-
-        // Node waits for response
-        let response = match response_sub
-            .receive_with_timeout(p2p.settings().read().await.outbound_connect_timeout)
-            .await
-        {
+        let response = match requests.request(&channel, &request).await {
             Ok(r) => r,
             Err(e) => {
                 debug!(target: "darkfid::task::handle_reorg", "Asking peer for headers sequence failed: {e}");
@@ -356,12 +316,6 @@ async fn handle_reorg(
     }
     drop(forks);
 
-    // Communication setup
-    let Ok(response_sub) = channel.subscribe_msg::<ForkProposalsResponse>().await else {
-        error!(target: "darkfid::task::handle_reorg", "Failure during `ForkProposalsResponse` communication setup with peer: {channel:?}");
-        return Ok(())
-    };
-
     // Create a fork from last common height
     let mut peer_fork = Fork::new(validator.consensus.blockchain.clone(), module).await?;
     peer_fork.targets_rank = last_difficulty.ranks.targets_rank.clone();
@@ -388,16 +342,7 @@ async fn handle_reorg(
 
         // Request peer proposals
         let request = ForkProposalsRequest { headers: batch.clone(), fork_header: proposal.hash };
-        if let Err(e) = channel.send(&request).await {
-            debug!(target: "darkfid::task::handle_reorg", "Channel send failed: {e}");
-            return Ok(())
-        };
-
-        // Node waits for response
-        let response = match response_sub
-            .receive_with_timeout(p2p.settings().read().await.outbound_connect_timeout)
-            .await
-        {
+        let response = match requests.request(&channel, &request).await {
             Ok(r) => r,
             Err(e) => {
                 debug!(target: "darkfid::task::handle_reorg", "Asking peer for proposals sequence failed: {e}");
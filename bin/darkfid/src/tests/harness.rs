@@ -91,11 +91,15 @@ impl Harness {
 
         // Generate validators using pregenerated vks
         let (_, vks) = vks::get_cached_pks_and_vks()?;
-        let mut settings =
-            Settings { localnet: true, inbound_connections: 3, ..Default::default() };
+        let alice_url = Url::parse(&config.alice_url)?;
+        let mut settings = Settings {
+            localnet: true,
+            inbound_connections: 3,
+            allowed_transports: vec![alice_url.scheme().to_string()],
+            ..Default::default()
+        };
 
         // Alice
-        let alice_url = Url::parse(&config.alice_url)?;
         settings.inbound_addrs = vec![alice_url.clone()];
         let alice = generate_node(&vks, &validator_config, &settings, ex, true, None).await?;
 
@@ -18,18 +18,28 @@
 
 use std::sync::Arc;
 
-use darkfi::{net::Settings, validator::utils::best_fork_index, Result};
+use darkfi::{
+    net::{
+        transport::sim::{SimNetwork, SimSettings},
+        Settings,
+    },
+    system::sim_clock::{SimClock, SimClockPtr},
+    validator::utils::best_fork_index,
+    Result,
+};
 use darkfi_contract_test_harness::init_logger;
 use darkfi_sdk::num_traits::One;
 use num_bigint::BigUint;
 use smol::Executor;
-use url::Url;
 
 use crate::tests::{generate_node, Harness, HarnessConfig};
 
-async fn sync_forks_real(ex: Arc<Executor<'static>>) -> Result<()> {
+async fn sync_forks_real(ex: Arc<Executor<'static>>, clock: SimClockPtr) -> Result<()> {
     init_logger();
 
+    // Run the nodes over a simulated network
+    let network = SimNetwork::new("sync_forks", SimSettings::default(), clock, ex.clone())?;
+
     // Initialize harness in testing mode
     let pow_target = 90;
     let pow_fixed_difficulty = Some(BigUint::one());
@@ -37,8 +47,8 @@ async fn sync_forks_real(ex: Arc<Executor<'static>>) -> Result<()> {
         pow_target,
         pow_fixed_difficulty: pow_fixed_difficulty.clone(),
         confirmation_threshold: 6,
-        alice_url: "tcp+tls://127.0.0.1:18440".to_string(),
-        bob_url: "tcp+tls://127.0.0.1:18441".to_string(),
+        alice_url: network.endpoint("alice", 18440).to_string(),
+        bob_url: network.endpoint("bob", 18441).to_string(),
     };
     let th = Harness::new(config, true, &ex).await?;
 
@@ -63,9 +73,14 @@ async fn sync_forks_real(ex: Arc<Executor<'static>>) -> Result<()> {
     th.validate_fork_chains(3, vec![3, 1, 1]).await;
 
     // We are going to create a third node and try to sync from Bob
-    let mut settings = Settings { localnet: true, inbound_connections: 3, ..Default::default() };
+    let mut settings = Settings {
+        localnet: true,
+        inbound_connections: 3,
+        allowed_transports: vec!["sim".to_string()],
+        ..Default::default()
+    };
 
-    let charlie_url = Url::parse("tcp+tls://127.0.0.1:18442")?;
+    let charlie_url = network.endpoint("charlie", 18442);
     settings.inbound_addrs = vec![charlie_url];
     let bob_url = th.bob.p2p_handler.p2p.settings().read().await.inbound_addrs[0].clone();
     settings.peers = vec![bob_url];
@@ -106,6 +121,8 @@ async fn sync_forks_real(ex: Arc<Executor<'static>>) -> Result<()> {
     }
     drop(charlie_forks);
 
+    network.stop().await;
+
     // Thanks for reading
     Ok(())
 }
@@ -113,16 +130,23 @@ async fn sync_forks_real(ex: Arc<Executor<'static>>) -> Result<()> {
 #[test]
 fn sync_forks() -> Result<()> {
     let ex = Arc::new(Executor::new());
+    let clock = SimClock::new();
     let (signal, shutdown) = smol::channel::unbounded::<()>();
 
-    easy_parallel::Parallel::new().each(0..4, |_| smol::block_on(ex.run(shutdown.recv()))).finish(
-        || {
+    // Every thread enters the simulated clock, so the nodes' timers
+    // run on virtual time
+    easy_parallel::Parallel::new()
+        .each(0..4, |_| {
+            let _guard = clock.enter();
+            smol::block_on(ex.run(shutdown.recv()))
+        })
+        .finish(|| {
+            let _guard = clock.enter();
             smol::block_on(async {
-                sync_forks_real(ex.clone()).await.unwrap();
+                sync_forks_real(ex.clone(), clock.clone()).await.unwrap();
                 drop(signal);
             })
-        },
-    );
+        });
 
     Ok(())
 }
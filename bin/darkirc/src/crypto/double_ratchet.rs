@@ -0,0 +1,463 @@
+/* This file is part of DarkFi (https://dark.fi)
+ *
+ * Copyright (C) 2020-2024 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Double Ratchet with header encryption, according to
+//! <https://signal.org/docs/specifications/doubleratchet/>
+//!
+//! Every message is encrypted with a fresh key from a symmetric-key
+//! ratchet, and the chains are reset with a new Diffie-Hellman exchange
+//! each time the conversation changes direction. Keys are forgotten once
+//! used, so compromising a session doesn't expose past messages, and it
+//! heals by itself after the next exchange.
+//!
+//! Headers are encrypted as well, so messages of a session can't be
+//! linked together by their ratchet public keys. Keys of skipped messages
+//! are kept around, up to a limit, so messages can arrive out of order.
+//!
+//! Adapted from `script/research/pqxdh`.
+
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, Payload},
+    ChaCha20Poly1305, Nonce,
+};
+use darkfi_serial::{async_trait, SerialDecodable, SerialEncodable};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::rngs::OsRng;
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// Maximum number of message keys skipped in a single chain
+pub const MAX_SKIP: u32 = 1000;
+
+/// Maximum number of skipped message keys kept in a session
+const MAX_SKIPPED_KEYS: usize = 2000;
+
+/// HKDF info of the root key ratchet
+const ROOT_KDF_INFO: &[u8] = b"darkirc_double_ratchet_root";
+
+/// HKDF info of the message keys
+const MESSAGE_KDF_INFO: &[u8] = b"darkirc_double_ratchet_message";
+
+/// Length of a plaintext header: `dh_public||prev_n||n`
+const HEADER_LEN: usize = 40;
+
+/// Length of the nonce prepended to encrypted headers
+const NONCE_LEN: usize = 12;
+
+/// Message encrypted with a ratchet
+#[derive(Clone, Debug, SerialEncodable, SerialDecodable)]
+pub struct RatchetMessage {
+    /// Encrypted header, as `nonce||ciphertext`
+    pub header: Vec<u8>,
+    /// Encrypted message
+    pub ciphertext: Vec<u8>,
+}
+
+/// Plaintext message header
+struct Header {
+    /// Current ratchet public key of the sender
+    dh: [u8; 32],
+    /// Number of messages in the sender's previous sending chain
+    prev_n: u32,
+    /// Number of the message in the sending chain
+    n: u32,
+}
+
+impl Header {
+    fn encode(&self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0u8; HEADER_LEN];
+        bytes[..32].copy_from_slice(&self.dh);
+        bytes[32..36].copy_from_slice(&self.prev_n.to_le_bytes());
+        bytes[36..].copy_from_slice(&self.n.to_le_bytes());
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != HEADER_LEN {
+            return None
+        }
+
+        Some(Self {
+            dh: bytes[..32].try_into().unwrap(),
+            prev_n: u32::from_le_bytes(bytes[32..36].try_into().unwrap()),
+            n: u32::from_le_bytes(bytes[36..].try_into().unwrap()),
+        })
+    }
+}
+
+/// Key of a message we haven't received yet
+#[derive(Clone, Debug, SerialEncodable, SerialDecodable)]
+struct SkippedKey {
+    /// Header key of the message's chain
+    header_key: [u8; 32],
+    /// Number of the message in its chain
+    n: u32,
+    /// Key of the message
+    message_key: [u8; 32],
+}
+
+/// State of one side of a Double Ratchet session
+#[derive(Clone, Debug, SerialEncodable, SerialDecodable)]
+pub struct Ratchet {
+    /// Our current ratchet secret key
+    dh_secret: [u8; 32],
+    /// Current ratchet public key of the remote side
+    dh_remote: Option<[u8; 32]>,
+    /// Root key
+    root_key: [u8; 32],
+    /// Sending chain key
+    send_chain: Option<[u8; 32]>,
+    /// Receiving chain key
+    recv_chain: Option<[u8; 32]>,
+    /// Header key of the sending chain
+    send_header: Option<[u8; 32]>,
+    /// Header key of the receiving chain
+    recv_header: Option<[u8; 32]>,
+    /// Header key of the next sending chain
+    next_send_header: [u8; 32],
+    /// Header key of the next receiving chain
+    next_recv_header: [u8; 32],
+    /// Number of messages sent in the sending chain
+    n_send: u32,
+    /// Number of messages received in the receiving chain
+    n_recv: u32,
+    /// Number of messages sent in the previous sending chain
+    prev_n: u32,
+    /// Keys of skipped messages, oldest first
+    skipped: Vec<SkippedKey>,
+    /// Associated data authenticated with every message
+    ad: Vec<u8>,
+}
+
+impl Ratchet {
+    /// Initialize the side starting the session, knowing the remote's
+    /// initial ratchet public key.
+    pub fn new_initiator(
+        secret: &[u8; 32],
+        header_key: [u8; 32],
+        next_recv_header: [u8; 32],
+        remote: &[u8; 32],
+        ad: Vec<u8>,
+    ) -> Self {
+        let dh_secret = StaticSecret::random_from_rng(OsRng);
+        let dh_out = dh_secret.diffie_hellman(&PublicKey::from(*remote));
+        let (root_key, send_chain, next_send_header) = kdf_root(secret, dh_out.as_bytes());
+
+        Self {
+            dh_secret: dh_secret.to_bytes(),
+            dh_remote: Some(*remote),
+            root_key,
+            send_chain: Some(send_chain),
+            recv_chain: None,
+            send_header: Some(header_key),
+            recv_header: None,
+            next_send_header,
+            next_recv_header,
+            n_send: 0,
+            n_recv: 0,
+            prev_n: 0,
+            skipped: vec![],
+            ad,
+        }
+    }
+
+    /// Initialize the side accepting the session, with the secret key
+    /// of its initial ratchet public key. It can't send anything before
+    /// receiving the first message.
+    pub fn new_responder(
+        secret: &[u8; 32],
+        dh_secret: [u8; 32],
+        next_send_header: [u8; 32],
+        next_recv_header: [u8; 32],
+        ad: Vec<u8>,
+    ) -> Self {
+        Self {
+            dh_secret,
+            dh_remote: None,
+            root_key: *secret,
+            send_chain: None,
+            recv_chain: None,
+            send_header: None,
+            recv_header: None,
+            next_send_header,
+            next_recv_header,
+            n_send: 0,
+            n_recv: 0,
+            prev_n: 0,
+            skipped: vec![],
+            ad,
+        }
+    }
+
+    /// Check if we can send messages in this session
+    pub fn can_send(&self) -> bool {
+        self.send_chain.is_some() && self.send_header.is_some()
+    }
+
+    /// Check if we've received anything in this session
+    pub fn has_received(&self) -> bool {
+        self.recv_chain.is_some()
+    }
+
+    /// Encrypt a message, advancing the sending chain.
+    /// Returns `None` if we can't send yet.
+    pub fn encrypt(&mut self, plaintext: &[u8]) -> Option<RatchetMessage> {
+        let (send_chain, send_header) = (self.send_chain?, self.send_header?);
+        let (send_chain, message_key) = kdf_chain(&send_chain);
+
+        let dh_public = PublicKey::from(&StaticSecret::from(self.dh_secret));
+        let header = Header { dh: dh_public.to_bytes(), prev_n: self.prev_n, n: self.n_send };
+        let header = encrypt_header(&send_header, &header);
+        let ciphertext = encrypt_message(&message_key, plaintext, &self.associated(&header));
+
+        self.send_chain = Some(send_chain);
+        self.n_send += 1;
+
+        Some(RatchetMessage { header, ciphertext })
+    }
+
+    /// Decrypt a message. Returns `None` if it isn't from this session
+    /// or can't be decrypted, in which case the state is left untouched.
+    pub fn decrypt(&mut self, message: &RatchetMessage) -> Option<Vec<u8>> {
+        let mut state = self.clone();
+        let plaintext = state.try_decrypt(message)?;
+        *self = state;
+        Some(plaintext)
+    }
+
+    fn try_decrypt(&mut self, message: &RatchetMessage) -> Option<Vec<u8>> {
+        let ad = self.associated(&message.header);
+
+        // Messages of skipped keys don't move the ratchet
+        for i in 0..self.skipped.len() {
+            let Some(header) = decrypt_header(&self.skipped[i].header_key, &message.header) else {
+                continue
+            };
+            if header.n != self.skipped[i].n {
+                continue
+            }
+
+            let skipped = self.skipped.remove(i);
+            return decrypt_message(&skipped.message_key, &message.ciphertext, &ad)
+        }
+
+        let header = match self.recv_header.and_then(|hk| decrypt_header(&hk, &message.header)) {
+            Some(header) => header,
+            None => {
+                let header = decrypt_header(&self.next_recv_header, &message.header)?;
+                self.skip_keys(header.prev_n)?;
+                self.dh_ratchet(&header);
+                header
+            }
+        };
+
+        self.skip_keys(header.n)?;
+        let (recv_chain, message_key) = kdf_chain(&self.recv_chain?);
+        self.recv_chain = Some(recv_chain);
+        self.n_recv += 1;
+
+        decrypt_message(&message_key, &message.ciphertext, &ad)
+    }
+
+    /// Store the keys of the receiving chain's messages up to `until`
+    fn skip_keys(&mut self, until: u32) -> Option<()> {
+        if until > self.n_recv.saturating_add(MAX_SKIP) {
+            return None
+        }
+
+        let (Some(mut recv_chain), Some(header_key)) = (self.recv_chain, self.recv_header) else {
+            return Some(())
+        };
+
+        while self.n_recv < until {
+            let (next_chain, message_key) = kdf_chain(&recv_chain);
+            self.skipped.push(SkippedKey { header_key, n: self.n_recv, message_key });
+            recv_chain = next_chain;
+            self.n_recv += 1;
+        }
+        self.recv_chain = Some(recv_chain);
+
+        let excess = self.skipped.len().saturating_sub(MAX_SKIPPED_KEYS);
+        self.skipped.drain(..excess);
+        Some(())
+    }
+
+    /// Step the Diffie-Hellman ratchet with the remote's new public key
+    fn dh_ratchet(&mut self, header: &Header) {
+        self.prev_n = self.n_send;
+        self.n_send = 0;
+        self.n_recv = 0;
+        self.send_header = Some(self.next_send_header);
+        self.recv_header = Some(self.next_recv_header);
+        self.dh_remote = Some(header.dh);
+
+        let remote = PublicKey::from(header.dh);
+        let dh_out = StaticSecret::from(self.dh_secret).diffie_hellman(&remote);
+        let (root_key, recv_chain, next_recv_header) = kdf_root(&self.root_key, dh_out.as_bytes());
+        self.recv_chain = Some(recv_chain);
+        self.next_recv_header = next_recv_header;
+
+        let dh_secret = StaticSecret::random_from_rng(OsRng);
+        let dh_out = dh_secret.diffie_hellman(&remote);
+        let (root_key, send_chain, next_send_header) = kdf_root(&root_key, dh_out.as_bytes());
+        self.dh_secret = dh_secret.to_bytes();
+        self.root_key = root_key;
+        self.send_chain = Some(send_chain);
+        self.next_send_header = next_send_header;
+    }
+
+    /// Associated data of a message with the given encrypted header
+    fn associated(&self, header: &[u8]) -> Vec<u8> {
+        let mut ad = Vec::with_capacity(self.ad.len() + header.len());
+        ad.extend_from_slice(&self.ad);
+        ad.extend_from_slice(header);
+        ad
+    }
+}
+
+/// Root key ratchet, returning the new root key, chain key and
+/// next header key
+fn kdf_root(root_key: &[u8; 32], dh_out: &[u8; 32]) -> ([u8; 32], [u8; 32], [u8; 32]) {
+    let mut okm = [0u8; 96];
+    Hkdf::<Sha256>::new(Some(root_key), dh_out).expand(ROOT_KDF_INFO, &mut okm).unwrap();
+    (okm[..32].try_into().unwrap(), okm[32..64].try_into().unwrap(), okm[64..].try_into().unwrap())
+}
+
+/// Chain key ratchet, returning the new chain key and message key
+fn kdf_chain(chain_key: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let hmac = |byte: u8| -> [u8; 32] {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(chain_key).unwrap();
+        mac.update(&[byte]);
+        mac.finalize().into_bytes().into()
+    };
+
+    (hmac(0x02), hmac(0x01))
+}
+
+/// Encrypt a header with a header key, which is used for a whole chain,
+/// so the nonce is random and prepended to the ciphertext.
+fn encrypt_header(header_key: &[u8; 32], header: &Header) -> Vec<u8> {
+    let cipher = ChaCha20Poly1305::new(header_key.into());
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher.encrypt(&nonce, &header.encode()[..]).unwrap();
+
+    let mut concat = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+    concat.extend_from_slice(nonce.as_slice());
+    concat.extend_from_slice(&ciphertext);
+    concat
+}
+
+/// Attempt to decrypt a header with a header key
+fn decrypt_header(header_key: &[u8; 32], header: &[u8]) -> Option<Header> {
+    if header.len() < NONCE_LEN {
+        return None
+    }
+
+    let cipher = ChaCha20Poly1305::new(header_key.into());
+    let plaintext = cipher.decrypt(Nonce::from_slice(&header[..NONCE_LEN]), &header[NONCE_LEN..]);
+    Header::decode(&plaintext.ok()?)
+}
+
+/// Derive the cipher and nonce of a message key. Message keys are only
+/// ever used once, so the nonce can be derived along with the key.
+fn message_cipher(message_key: &[u8; 32]) -> (ChaCha20Poly1305, Nonce) {
+    let mut okm = [0u8; 32 + NONCE_LEN];
+    Hkdf::<Sha256>::new(None, message_key).expand(MESSAGE_KDF_INFO, &mut okm).unwrap();
+    (ChaCha20Poly1305::new(okm[..32].into()), *Nonce::from_slice(&okm[32..]))
+}
+
+fn encrypt_message(message_key: &[u8; 32], plaintext: &[u8], ad: &[u8]) -> Vec<u8> {
+    let (cipher, nonce) = message_cipher(message_key);
+    cipher.encrypt(&nonce, Payload { msg: plaintext, aad: ad }).unwrap()
+}
+
+fn decrypt_message(message_key: &[u8; 32], ciphertext: &[u8], ad: &[u8]) -> Option<Vec<u8>> {
+    let (cipher, nonce) = message_cipher(message_key);
+    cipher.decrypt(&nonce, Payload { msg: ciphertext, aad: ad }).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session() -> (Ratchet, Ratchet) {
+        let secret = [1u8; 32];
+        let (hka, nhkb) = ([2u8; 32], [3u8; 32]);
+        let bob_secret = StaticSecret::random_from_rng(OsRng);
+        let bob_public = PublicKey::from(&bob_secret).to_bytes();
+
+        let alice = Ratchet::new_initiator(&secret, hka, nhkb, &bob_public, b"ad".to_vec());
+        let bob = Ratchet::new_responder(&secret, bob_secret.to_bytes(), nhkb, hka, b"ad".to_vec());
+        (alice, bob)
+    }
+
+    #[test]
+    fn double_ratchet_conversation() {
+        let (mut alice, mut bob) = session();
+        assert!(alice.can_send());
+        assert!(!bob.can_send());
+        assert!(bob.encrypt(b"too early").is_none());
+
+        for round in 0..3u8 {
+            let msg = alice.encrypt(&[round]).unwrap();
+            assert_eq!(bob.decrypt(&msg).unwrap(), vec![round]);
+            // Keys are only used once
+            assert!(bob.decrypt(&msg).is_none());
+
+            let reply = bob.encrypt(&[round, round]).unwrap();
+            assert_eq!(alice.decrypt(&reply).unwrap(), vec![round, round]);
+        }
+
+        // Messages of other sessions are rejected without changing ours
+        let (mut eve, _) = session();
+        let msg = eve.encrypt(b"hi").unwrap();
+        let before = bob.n_recv;
+        assert!(bob.decrypt(&msg).is_none());
+        assert_eq!(bob.n_recv, before);
+
+        // Tampered messages are rejected
+        let mut msg = alice.encrypt(b"hi").unwrap();
+        msg.ciphertext[0] ^= 1;
+        assert!(bob.decrypt(&msg).is_none());
+    }
+
+    #[test]
+    fn double_ratchet_out_of_order() {
+        let (mut alice, mut bob) = session();
+        let first: Vec<_> = (0..3u8).map(|i| alice.encrypt(&[i]).unwrap()).collect();
+
+        assert_eq!(bob.decrypt(&first[2]).unwrap(), vec![2]);
+        let reply = bob.encrypt(b"reply").unwrap();
+        assert_eq!(alice.decrypt(&reply).unwrap(), b"reply");
+
+        // Messages of a new chain arrive before the rest of the old one
+        let second = alice.encrypt(&[3]).unwrap();
+        assert_eq!(bob.decrypt(&second).unwrap(), vec![3]);
+        assert_eq!(bob.decrypt(&first[0]).unwrap(), vec![0]);
+        assert_eq!(bob.decrypt(&first[1]).unwrap(), vec![1]);
+        assert!(bob.skipped.is_empty());
+
+        // Too many skipped messages are refused
+        let mut last = None;
+        for i in 0..=MAX_SKIP + 1 {
+            last = Some(alice.encrypt(&i.to_le_bytes()).unwrap());
+        }
+        assert!(bob.decrypt(&last.unwrap()).is_none());
+    }
+}
@@ -0,0 +1,268 @@
+/* This file is part of DarkFi (https://dark.fi)
+ *
+ * Copyright (C) 2020-2024 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Forward-secret group keys for encrypted channels.
+//!
+//! Instead of a static shared secret, a forward-secret channel has an
+//! owner. The owner starts an epoch by picking a random secret and
+//! sealing it to the latest signed prekey of every member in a
+//! [`GroupControl`] message, which it signs and publishes over the event
+//! graph. A new epoch is started whenever the members or their prekeys
+//! change, so removed members can't read anything sent afterwards.
+//!
+//! Within an epoch, the key is ratcheted with a one-way function every
+//! [`RATCHET_PERIOD`], in step with the DAG rotation, and the previous
+//! key is forgotten. Compromising today's key therefore doesn't expose
+//! the messages of previous days, even if they were archived.
+//!
+//! Since the DAG rotation prunes control messages, the owner starts a
+//! fresh epoch after every prune, so members syncing afterwards still
+//! receive a key.
+//!
+//! Prekeys are deleted by their owners once their lifetime is over, so
+//! an archived control message can't be opened anymore afterwards, even
+//! if the long-term DM key of a member leaks. Members who haven't
+//! published any prekeys aren't given a key until they do. This doesn't
+//! protect against a member's device being compromised while it still
+//! holds the prekey or the current group key, nor against the owner,
+//! who picks every secret.
+
+use crypto_box::{
+    aead::{Aead, AeadCore},
+    ChaChaBox, PublicKey, SecretKey,
+};
+use darkfi_serial::{async_trait, SerialDecodable, SerialEncodable};
+use rand::rngs::OsRng;
+
+use super::saltbox;
+
+/// Length of a ratchet period in milliseconds, one DAG rotation
+pub const RATCHET_PERIOD: u64 = crate::DAYS_ROTATION * 86_400_000;
+
+/// Key derivation context of the group ID
+const GROUP_ID_CONTEXT: &str = "darkirc channel group id";
+
+/// Key derivation context of the first key of an epoch
+const EPOCH_KEY_CONTEXT: &str = "darkirc channel group epoch key";
+
+/// Key derivation context of the key ratchet
+const RATCHET_CONTEXT: &str = "darkirc channel group key ratchet";
+
+/// Control message starting a new group epoch, signed by the group owner
+#[derive(Clone, Debug, SerialEncodable, SerialDecodable)]
+pub struct GroupControl {
+    /// ID of the group
+    pub group: [u8; 32],
+    /// Number of the started epoch
+    pub epoch: u64,
+    /// The epoch secret, sealed to each member's signed prekey
+    pub sealed: Vec<Vec<u8>>,
+}
+
+/// Key of a group epoch
+#[derive(Clone, Debug, PartialEq, SerialEncodable, SerialDecodable)]
+pub struct EpochKey {
+    /// Number of the epoch
+    pub epoch: u64,
+    /// Key of the epoch, ratcheted up to the current period
+    pub key: [u8; 32],
+}
+
+/// Keys we hold for a group
+#[derive(Clone, Debug, Default, SerialEncodable, SerialDecodable)]
+pub struct GroupKeys {
+    /// Latest epoch started by the owner
+    pub epoch: u64,
+    /// Period the keys are ratcheted up to
+    pub period: u64,
+    /// Keys of the epochs usable in the current period, newest last
+    pub keys: Vec<EpochKey>,
+    /// Digest of the member prekeys the latest epoch was sealed to.
+    /// Only tracked by the owner, to know when to start a new epoch.
+    pub members: [u8; 32],
+    /// ID of the control event that started the latest epoch.
+    /// Only tracked by the owner, to start a new epoch once it's pruned.
+    pub control: [u8; 32],
+}
+
+impl GroupKeys {
+    /// Start a new epoch from a control message sent in the given period.
+    /// `secret` is `None` if we aren't a member of the epoch. Returns
+    /// `false` if we already knew about the epoch.
+    pub fn start_epoch(&mut self, epoch: u64, secret: Option<[u8; 32]>, period: u64) -> bool {
+        if epoch <= self.epoch {
+            return false
+        }
+
+        self.advance(period);
+        self.epoch = epoch;
+
+        // We've been removed from the group
+        let Some(secret) = secret else {
+            self.keys.clear();
+            return true
+        };
+
+        // Epochs started in a past period are ratcheted up to ours
+        let mut key = blake3::derive_key(EPOCH_KEY_CONTEXT, &secret);
+        for _ in period..self.period {
+            key = ratchet(&key);
+        }
+
+        self.keys.push(EpochKey { epoch, key });
+        true
+    }
+
+    /// Ratchet the keys up to the given period, forgetting the previous
+    /// ones. Keys of older epochs are only kept for messages still in
+    /// flight during the period a new epoch started in, so they are
+    /// dropped here. Returns `false` if nothing changed.
+    pub fn advance(&mut self, period: u64) -> bool {
+        if period <= self.period {
+            return false
+        }
+
+        let stale = self.keys.len().saturating_sub(1);
+        self.keys.drain(..stale);
+
+        for epoch_key in self.keys.iter_mut() {
+            for _ in self.period..period {
+                epoch_key.key = ratchet(&epoch_key.key);
+            }
+        }
+
+        self.period = period;
+        true
+    }
+
+    /// Return the key new messages should be encrypted with, if we're
+    /// a member of the latest epoch
+    pub fn current(&self) -> Option<&EpochKey> {
+        self.keys.last().filter(|epoch_key| epoch_key.epoch == self.epoch)
+    }
+}
+
+/// Derive the ID of the group of a channel with the given owner
+pub fn group_id(channel: &str, owner: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = blake3::Hasher::new_derive_key(GROUP_ID_CONTEXT);
+    hasher.update(owner);
+    hasher.update(channel.as_bytes());
+    hasher.finalize().into()
+}
+
+/// Return the ratchet period of the given timestamp in milliseconds
+pub fn period(timestamp: u64) -> u64 {
+    timestamp / RATCHET_PERIOD
+}
+
+/// Ratchet a key forward. The previous key can't be recovered from the
+/// returned one.
+fn ratchet(key: &[u8; 32]) -> [u8; 32] {
+    blake3::derive_key(RATCHET_CONTEXT, key)
+}
+
+/// Create the `ChaChaBox` encrypting channel messages with an epoch key.
+/// Like static channel secrets, the key is used on both sides of the box.
+pub fn epoch_box(key: &[u8; 32]) -> ChaChaBox {
+    let secret = SecretKey::from(*key);
+    ChaChaBox::new(&secret.public_key(), &secret)
+}
+
+/// Seal an epoch secret to a member's prekey, using an ephemeral
+/// key so the sender can't be told from the sealed box.
+///
+/// The format is `ephemeral_public||nonce||ciphertext`.
+pub fn seal(member: &PublicKey, secret: &[u8; 32]) -> Vec<u8> {
+    let ephemeral = SecretKey::generate(&mut OsRng);
+    let salt_box = ChaChaBox::new(member, &ephemeral);
+
+    let nonce = ChaChaBox::generate_nonce(&mut OsRng);
+    let ciphertext = salt_box.encrypt(&nonce, &secret[..]).unwrap();
+
+    let mut sealed = Vec::with_capacity(32 + 24 + ciphertext.len());
+    sealed.extend_from_slice(ephemeral.public_key().as_bytes());
+    sealed.extend_from_slice(nonce.as_slice());
+    sealed.extend_from_slice(&ciphertext);
+    sealed
+}
+
+/// Attempt to open a sealed epoch secret with one of our prekeys.
+/// Returns `None` if it wasn't sealed to us.
+pub fn open(sealed: &[u8], secret_key: &SecretKey) -> Option<[u8; 32]> {
+    if sealed.len() < 32 {
+        return None
+    }
+
+    let ephemeral: [u8; 32] = sealed[..32].try_into().unwrap();
+    let salt_box = ChaChaBox::new(&PublicKey::from(ephemeral), secret_key);
+    saltbox::try_decrypt(&salt_box, &sealed[32..])?.try_into().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn group_key_ratchet() {
+        let secret = [7u8; 32];
+
+        // Members learning about the epoch at different times agree on the key
+        let mut early = GroupKeys::default();
+        assert!(early.start_epoch(1, Some(secret), 100));
+        let mut late = GroupKeys::default();
+        late.advance(103);
+        assert!(late.start_epoch(1, Some(secret), 100));
+        early.advance(103);
+        assert_eq!(early.current(), late.current());
+
+        // Every period has a different key
+        let before = early.current().unwrap().key;
+        assert!(early.advance(104));
+        assert_ne!(early.current().unwrap().key, before);
+        assert!(!early.advance(104));
+
+        // Old epochs are ignored
+        assert!(!early.start_epoch(1, Some(secret), 104));
+
+        // A new epoch in the same period keeps the previous key around
+        // for messages in flight, until the period ends.
+        assert!(early.start_epoch(2, Some([8u8; 32]), 104));
+        assert_eq!(early.keys.len(), 2);
+        assert_eq!(early.current().unwrap().epoch, 2);
+        early.advance(105);
+        assert_eq!(early.keys.len(), 1);
+        assert_eq!(early.current().unwrap().epoch, 2);
+
+        // Being left out of an epoch drops all the keys
+        assert!(early.start_epoch(3, None, 105));
+        assert!(early.current().is_none());
+        assert!(early.keys.is_empty());
+    }
+
+    #[test]
+    fn group_key_sealing() {
+        let alice = SecretKey::generate(&mut OsRng);
+        let bob = SecretKey::generate(&mut OsRng);
+        let secret = [42u8; 32];
+
+        let sealed = seal(&alice.public_key(), &secret);
+        assert_eq!(open(&sealed, &alice), Some(secret));
+        assert_eq!(open(&sealed, &bob), None);
+        assert_eq!(open(&sealed[..40], &alice), None);
+    }
+}
@@ -21,5 +21,17 @@
 /// ChaCha box, used for channel encryption, and optionally DM encryption.
 pub mod saltbox;
 
+/// Forward-secret group keys, used for channels with an owner
+pub mod group;
+
+/// XEdDSA signatures with X25519 keys, used for DM prekeys
+pub mod xeddsa;
+
+/// PQXDH key agreement, used to establish DM sessions
+pub mod pqxdh;
+
+/// Double Ratchet, used for DM sessions
+pub mod double_ratchet;
+
 /// bcrypt utilities
 pub mod bcrypt;
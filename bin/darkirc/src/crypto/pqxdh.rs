@@ -0,0 +1,314 @@
+/* This file is part of DarkFi (https://dark.fi)
+ *
+ * Copyright (C) 2020-2024 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! PQXDH key agreement for DM sessions, according to
+//! <https://signal.org/docs/specifications/pqxdh/>
+//!
+//! Everyone with a DM key periodically publishes a [`PrekeyBundle`] over
+//! the event graph, holding a signed X25519 prekey and a signed Kyber
+//! prekey. Contacts use the latest bundle they've seen to start a
+//! [`DmSession`] without us being online, and attach an [`InitMessage`]
+//! to their messages until we answer. The shared secret is then carried
+//! on with a [Double Ratchet](super::double_ratchet).
+//!
+//! There is no server handing out one-time prekeys, so they aren't used.
+//! Replayed initial messages are detected by the session's origin, and
+//! prekeys are deleted after [`PREKEY_LIFETIME`] so old handshakes can't
+//! be recomputed.
+//!
+//! Adapted from `script/research/pqxdh`.
+
+use darkfi_serial::{async_trait, SerialDecodable, SerialEncodable};
+use hkdf::Hkdf;
+use kyber_kem::{kem_decrypt_1024, kem_encrypt_1024, kem_keypair_1024};
+use rand::rngs::OsRng;
+use sha2::Sha512;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use super::{
+    double_ratchet::{Ratchet, RatchetMessage},
+    group::RATCHET_PERIOD,
+    xeddsa,
+};
+
+/// How long our prekeys are kept after being published, in milliseconds
+pub const PREKEY_LIFETIME: u64 = 7 * RATCHET_PERIOD;
+
+/// Maximum number of sessions kept per contact
+pub const MAX_SESSIONS: usize = 4;
+
+/// Key derivation context of the tag identifying the bundles of a key
+const BUNDLE_TAG_CONTEXT: &str = "darkirc prekey bundle tag";
+
+/// Domain separator of the prekey bundle signatures
+const BUNDLE_SIGNATURE_DOMAIN: &[u8] = b"darkirc prekey bundle";
+
+/// HKDF info of the PQXDH shared secret
+const PQXDH_INFO: &[u8] = b"darkirc_pqxdh_CURVE25519_SHA-512_CRYSTALS-KYBER-1024";
+
+/// Published prekeys of a DM key
+#[derive(Clone, Debug, SerialEncodable, SerialDecodable)]
+pub struct PrekeyBundle {
+    /// DM public key the bundle belongs to
+    pub identity: [u8; 32],
+    /// Signed X25519 prekey
+    pub signed_prekey: [u8; 32],
+    /// Signed Kyber-1024 prekey
+    pub pq_prekey: Vec<u8>,
+    /// Creation time of the bundle, in milliseconds
+    pub timestamp: u64,
+    /// XEdDSA signature of the bundle by its identity key
+    pub signature: [u8; 64],
+}
+
+impl PrekeyBundle {
+    fn signed_data(&self) -> Vec<u8> {
+        let mut data = BUNDLE_SIGNATURE_DOMAIN.to_vec();
+        data.extend_from_slice(&self.identity);
+        data.extend_from_slice(&self.signed_prekey);
+        data.extend_from_slice(&self.pq_prekey);
+        data.extend_from_slice(&self.timestamp.to_le_bytes());
+        data
+    }
+
+    /// Verify the bundle's signature
+    pub fn verify(&self) -> bool {
+        xeddsa::verify(&PublicKey::from(self.identity), &self.signed_data(), &self.signature)
+    }
+}
+
+/// Our prekeys, with their secret keys
+#[derive(Clone, Debug, SerialEncodable, SerialDecodable)]
+pub struct Prekeys {
+    /// Secret key of the signed prekey
+    pub signed_prekey: [u8; 32],
+    /// Secret key of the Kyber prekey
+    pub pq_prekey: Vec<u8>,
+    /// The published bundle
+    pub bundle: PrekeyBundle,
+}
+
+impl Prekeys {
+    /// Generate new prekeys and sign their bundle with our DM secret key
+    pub fn generate(identity: &StaticSecret, timestamp: u64) -> Self {
+        let signed_prekey = StaticSecret::random_from_rng(OsRng);
+        let (pq_secret, pq_public) = kem_keypair_1024(&mut OsRng);
+
+        let mut bundle = PrekeyBundle {
+            identity: PublicKey::from(identity).to_bytes(),
+            signed_prekey: PublicKey::from(&signed_prekey).to_bytes(),
+            pq_prekey: pq_public.to_vec(),
+            timestamp,
+            signature: [0u8; 64],
+        };
+        bundle.signature = xeddsa::sign(identity, &bundle.signed_data());
+
+        Self { signed_prekey: signed_prekey.to_bytes(), pq_prekey: pq_secret.to_vec(), bundle }
+    }
+}
+
+/// Initial message of a session, sent along with the initiator's
+/// messages until the responder answers
+#[derive(Clone, Debug, SerialEncodable, SerialDecodable)]
+pub struct InitMessage {
+    /// DM public key of the initiator
+    pub identity: [u8; 32],
+    /// Ephemeral public key of the initiator
+    pub ephemeral: [u8; 32],
+    /// Signed prekey of the responder's bundle that was used
+    pub signed_prekey: [u8; 32],
+    /// Kyber ciphertext encapsulated to the responder's Kyber prekey
+    pub pq_ciphertext: Vec<u8>,
+}
+
+impl InitMessage {
+    /// ID of the session started by this message
+    pub fn id(&self) -> [u8; 32] {
+        blake3::hash(&self.ephemeral).into()
+    }
+}
+
+/// Encrypted direct message
+#[derive(Clone, Debug, SerialEncodable, SerialDecodable)]
+pub struct DirectMessage {
+    /// Initial message, if the session wasn't answered yet
+    pub init: Option<InitMessage>,
+    /// The ratchet message
+    pub message: RatchetMessage,
+}
+
+/// DM session with a contact
+#[derive(Clone, Debug, SerialEncodable, SerialDecodable)]
+pub struct DmSession {
+    /// ID of the session, from its initial message
+    pub origin: [u8; 32],
+    /// Initial message to attach while we haven't received anything
+    pub init: Option<InitMessage>,
+    /// Double Ratchet state
+    pub ratchet: Ratchet,
+}
+
+impl DmSession {
+    /// Start a session with the owner of the given bundle.
+    /// Returns `None` if the bundle is invalid.
+    pub fn initiate(identity: &StaticSecret, bundle: &PrekeyBundle) -> Option<Self> {
+        if !bundle.verify() {
+            return None
+        }
+
+        let remote_identity = PublicKey::from(bundle.identity);
+        let signed_prekey = PublicKey::from(bundle.signed_prekey);
+        let ephemeral = StaticSecret::random_from_rng(OsRng);
+        let (pq_ciphertext, shared_secret) =
+            kem_encrypt_1024(bundle.pq_prekey.as_slice().try_into().ok()?, &mut OsRng);
+
+        let dh1 = identity.diffie_hellman(&signed_prekey);
+        let dh2 = ephemeral.diffie_hellman(&remote_identity);
+        let dh3 = ephemeral.diffie_hellman(&signed_prekey);
+        if !(dh1.was_contributory() && dh2.was_contributory() && dh3.was_contributory()) {
+            return None
+        }
+
+        let (secret, header_key, next_header_key) =
+            derive_keys(&[dh1.as_bytes(), dh2.as_bytes(), dh3.as_bytes(), &shared_secret[..]]);
+
+        let init = InitMessage {
+            identity: PublicKey::from(identity).to_bytes(),
+            ephemeral: PublicKey::from(&ephemeral).to_bytes(),
+            signed_prekey: bundle.signed_prekey,
+            pq_ciphertext: pq_ciphertext.to_vec(),
+        };
+
+        let ad = associated_data(&init.identity, &bundle.identity);
+        let ratchet =
+            Ratchet::new_initiator(&secret, header_key, next_header_key, &bundle.signed_prekey, ad);
+
+        Some(Self { origin: init.id(), init: Some(init), ratchet })
+    }
+
+    /// Accept a session started with the given initial message, using
+    /// the prekeys it was made for. Returns `None` if the handshake fails.
+    pub fn respond(identity: &StaticSecret, prekeys: &Prekeys, init: &InitMessage) -> Option<Self> {
+        if init.signed_prekey != prekeys.bundle.signed_prekey {
+            return None
+        }
+
+        let remote_identity = PublicKey::from(init.identity);
+        let ephemeral = PublicKey::from(init.ephemeral);
+        let signed_prekey = StaticSecret::from(prekeys.signed_prekey);
+        let shared_secret = kem_decrypt_1024(
+            init.pq_ciphertext.as_slice().try_into().ok()?,
+            prekeys.pq_prekey.as_slice().try_into().ok()?,
+        );
+
+        let dh1 = signed_prekey.diffie_hellman(&remote_identity);
+        let dh2 = identity.diffie_hellman(&ephemeral);
+        let dh3 = signed_prekey.diffie_hellman(&ephemeral);
+        if !(dh1.was_contributory() && dh2.was_contributory() && dh3.was_contributory()) {
+            return None
+        }
+
+        let (secret, header_key, next_header_key) =
+            derive_keys(&[dh1.as_bytes(), dh2.as_bytes(), dh3.as_bytes(), &shared_secret[..]]);
+
+        let ad = associated_data(&init.identity, &prekeys.bundle.identity);
+        let ratchet =
+            Ratchet::new_responder(&secret, prekeys.signed_prekey, next_header_key, header_key, ad);
+
+        Some(Self { origin: init.id(), init: None, ratchet })
+    }
+
+    /// Encrypt a message. Returns `None` if we can't send in this session yet.
+    pub fn encrypt(&mut self, plaintext: &[u8]) -> Option<DirectMessage> {
+        let message = self.ratchet.encrypt(plaintext)?;
+        Some(DirectMessage { init: self.init.clone(), message })
+    }
+
+    /// Decrypt a message. Returns `None` if it isn't from this session.
+    pub fn decrypt(&mut self, message: &DirectMessage) -> Option<Vec<u8>> {
+        let plaintext = self.ratchet.decrypt(&message.message)?;
+        // The other side has the session now
+        self.init = None;
+        Some(plaintext)
+    }
+}
+
+/// Derive the tag identifying the prekey bundles of a DM public key
+pub fn bundle_tag(identity: &[u8; 32]) -> [u8; 32] {
+    blake3::derive_key(BUNDLE_TAG_CONTEXT, identity)
+}
+
+/// Derive the shared secret and the initial header keys of a session
+fn derive_keys(key_material: &[&[u8]]) -> ([u8; 32], [u8; 32], [u8; 32]) {
+    // Curve25519 domain separation, as in the specification
+    let mut ikm = vec![0xff; 32];
+    for km in key_material {
+        ikm.extend_from_slice(km);
+    }
+
+    let mut okm = [0u8; 96];
+    Hkdf::<Sha512>::new(Some(&[0u8; 64]), &ikm).expand(PQXDH_INFO, &mut okm).unwrap();
+    (okm[..32].try_into().unwrap(), okm[32..64].try_into().unwrap(), okm[64..].try_into().unwrap())
+}
+
+/// Associated data of a session: `initiator_identity||responder_identity`
+fn associated_data(initiator: &[u8; 32], responder: &[u8; 32]) -> Vec<u8> {
+    let mut ad = Vec::with_capacity(64);
+    ad.extend_from_slice(initiator);
+    ad.extend_from_slice(responder);
+    ad
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pqxdh_session() {
+        let alice = StaticSecret::random_from_rng(OsRng);
+        let bob = StaticSecret::random_from_rng(OsRng);
+        let prekeys = Prekeys::generate(&bob, 1);
+
+        // Forged bundles are refused
+        let mut forged = prekeys.bundle.clone();
+        forged.timestamp = 2;
+        assert!(!forged.verify());
+        assert!(DmSession::initiate(&alice, &forged).is_none());
+
+        let mut alice_session = DmSession::initiate(&alice, &prekeys.bundle).unwrap();
+        let msg = alice_session.encrypt(b"hello").unwrap();
+        let init = msg.init.clone().unwrap();
+        assert_eq!(init.identity, PublicKey::from(&alice).to_bytes());
+
+        // Only the owner of the prekeys can accept the session
+        let mallory = StaticSecret::random_from_rng(OsRng);
+        let mut mallory_session = DmSession::respond(&mallory, &prekeys, &init).unwrap();
+        assert!(mallory_session.decrypt(&msg).is_none());
+
+        let mut bob_session = DmSession::respond(&bob, &prekeys, &init).unwrap();
+        assert_eq!(bob_session.origin, alice_session.origin);
+        assert_eq!(bob_session.decrypt(&msg).unwrap(), b"hello");
+
+        // The initial message is attached until Bob answers
+        assert!(alice_session.encrypt(b"again").unwrap().init.is_some());
+        let reply = bob_session.encrypt(b"hi").unwrap();
+        assert!(reply.init.is_none());
+        assert_eq!(alice_session.decrypt(&reply).unwrap(), b"hi");
+        assert!(alice_session.encrypt(b"bye").unwrap().init.is_none());
+    }
+}
@@ -0,0 +1,105 @@
+/* This file is part of DarkFi (https://dark.fi)
+ *
+ * Copyright (C) 2020-2024 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! XEdDSA signatures made with X25519 keys, according to
+//! <https://signal.org/docs/specifications/xeddsa/>
+//!
+//! Our DM keys are X25519 keys, which can't sign. XEdDSA lets us sign
+//! our prekey bundles with them anyway, so contacts only need to know
+//! the single public key they already have in their config.
+//!
+//! Adapted from `script/research/pqxdh`.
+
+use curve25519_dalek::{
+    constants::ED25519_BASEPOINT_TABLE,
+    montgomery::MontgomeryPoint,
+    scalar::{clamp_integer, Scalar},
+};
+use ed25519_dalek::{Signature, VerifyingKey};
+use rand::{rngs::OsRng, RngCore};
+use sha2::{Digest, Sha512};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// Sign a message with an X25519 secret key
+pub fn sign(secret: &StaticSecret, msg: &[u8]) -> [u8; 64] {
+    let mut nonce = [0u8; 64];
+    OsRng.fill_bytes(&mut nonce);
+
+    // The conversion of a Curve25519 public key to an Ed25519 one is
+    // only unique up to the sign of the x coordinate, so the secret
+    // key is negated if needed to always end up with a sign of zero.
+    let scalar_k = Scalar::from_bytes_mod_order(clamp_integer(secret.to_bytes()));
+    let mut public = (ED25519_BASEPOINT_TABLE * &scalar_k).compress();
+    let sign = public.0[31] >> 7;
+    public.0[31] &= 0x7F;
+    let k = if sign == 1 { -scalar_k } else { scalar_k };
+
+    // r = hash1(k || M || Z) (mod q)
+    let mut hasher = Sha512::new();
+    hasher.update([0xfe; 1]);
+    hasher.update([0xff; 31]);
+    hasher.update(k.as_bytes());
+    hasher.update(msg);
+    hasher.update(nonce);
+    let r = Scalar::from_hash(hasher);
+
+    // R = rB
+    let cap_r = (ED25519_BASEPOINT_TABLE * &r).compress();
+
+    // h = hash(R || A || M) (mod q)
+    let mut hasher = Sha512::new();
+    hasher.update(cap_r.as_bytes());
+    hasher.update(public.as_bytes());
+    hasher.update(msg);
+    let h = Scalar::from_hash(hasher);
+
+    // s = r + hk (mod q)
+    let s = r + h * k;
+
+    let mut signature = [0u8; 64];
+    signature[..32].copy_from_slice(cap_r.as_bytes());
+    signature[32..].copy_from_slice(s.as_bytes());
+    signature
+}
+
+/// Verify a message signature made with the secret key of the given
+/// X25519 public key
+pub fn verify(public: &PublicKey, msg: &[u8], signature: &[u8; 64]) -> bool {
+    let Some(edwards) = MontgomeryPoint(public.to_bytes()).to_edwards(0) else { return false };
+    let Ok(public) = VerifyingKey::from_bytes(&edwards.compress().to_bytes()) else { return false };
+
+    public.verify_strict(msg, &Signature::from_bytes(signature)).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn xeddsa_sign_verify() {
+        let secret = StaticSecret::random_from_rng(OsRng);
+        let public = PublicKey::from(&secret);
+        let other = PublicKey::from(&StaticSecret::random_from_rng(OsRng));
+
+        let msg = b"darkirc prekey bundle";
+        let signature = sign(&secret, msg);
+        assert!(verify(&public, msg, &signature));
+        assert!(!verify(&public, b"another message", &signature));
+        assert!(!verify(&other, msg, &signature));
+    }
+}
@@ -25,7 +25,7 @@ use std::{
 };
 
 use darkfi::{
-    event_graph::{proto::EventPut, Event, NULL_ID},
+    event_graph::{Event, NULL_ID},
     system::Subscription,
     Error, Result,
 };
@@ -41,8 +41,11 @@ use smol::{
 };
 
 use super::{
+    channel_dag_topic,
+    ircv3::{format_server_time, parse_tags, MsgTags, SUPPORTED_CAPS},
+    rpl::ERR_CANNOTSENDTOCHAN,
     server::{IrcServer, MAX_MSG_LEN},
-    Msg, NickServ, OldPrivmsg, SERVER_NAME,
+    Msg, NickServ, OldPrivmsg, Privmsg, SERVER_NAME,
 };
 
 const PENALTY_LIMIT: usize = 5;
@@ -59,6 +62,31 @@ pub enum ReplyType {
     Cap(String),
     /// NOTICE reply (from, to, what)
     Notice((String, String, String)),
+    /// Client reply relayed from the event graph, with IRCv3 message tags
+    Tagged((MsgTags, String, String)),
+    /// IRCv3 BATCH marker, only sent if the client enabled batches
+    Batch(String),
+    /// IRCv3 standard FAIL reply
+    Fail(String),
+}
+
+/// Build the replies relaying a message from the event graph, one per
+/// line, tagged with the timestamp and ID of its event.
+pub fn message_replies(event: &Event, privmsg: &Privmsg, batch: Option<&str>) -> Vec<ReplyType> {
+    let mut replies = vec![];
+    for line in privmsg.msg.lines().filter(|line| !line.is_empty()) {
+        // Message IDs have to be unique, so only the first line gets one
+        let tags = MsgTags {
+            time: event.timestamp,
+            msgid: replies.is_empty().then(|| event.id()),
+            batch: batch.map(|batch| batch.to_string()),
+        };
+
+        let msg = format!("PRIVMSG {} :{}", privmsg.channel, line);
+        replies.push(ReplyType::Tagged((tags, privmsg.nick.clone(), msg)));
+    }
+
+    replies
 }
 
 /// Stateful IRC client handler, used for each client connection
@@ -105,8 +133,9 @@ impl Client {
         incoming: Subscription<Event>,
         addr: SocketAddr,
     ) -> Result<Self> {
-        let caps =
+        let mut caps =
             HashMap::from([("no-history".to_string(), false), ("no-autojoin".to_string(), false)]);
+        caps.extend(SUPPORTED_CAPS.iter().map(|cap| (cap.to_string(), false)));
 
         let username = Arc::new(RwLock::new(String::from("*")));
         let nickname = Arc::new(RwLock::new(String::from("*")));
@@ -190,7 +219,16 @@ impl Client {
                                     }
 
                                     // Otherwise, broadcast it
-                                    self.server.darkirc.p2p.broadcast(&EventPut(event)).await;
+                                    self.server.darkirc.event_graph.event_broadcast(&event, &[]).await;
+
+                                    // And echo it back if the client asked for it
+                                    if self.has_cap("echo-message").await {
+                                        for reply in self.echo_replies(&event).await {
+                                            if let Err(e) = self.reply(&mut writer, &reply).await {
+                                                error!("[IRC CLIENT] Failed echoing PRIVMSG to client: {}", e);
+                                            }
+                                        }
+                                    }
                                 }
                             }
                         }
@@ -227,6 +265,16 @@ impl Client {
                         }
                     }
 
+                    // Group control messages and prekey bundles only update our
+                    // keys. The server handles them too, but we might get them first.
+                    if self.server.handle_control(&r).await {
+                        if let Err(e) = self.mark_seen(&event_id).await {
+                            error!("[IRC CLIENT] (multiplex_connection) self.mark_seen({}) failed: {}", event_id, e);
+                            return Err(e)
+                        }
+                        continue
+                    }
+
                     // Try to deserialize the `Event`'s content into a `Privmsg`
                     let mut privmsg = match Msg::deserialize(r.content()).await {
                         Ok(Msg::V1(old_msg)) => old_msg.into_new(),
@@ -238,7 +286,7 @@ impl Client {
                     };
 
                     // If successful, potentially decrypt it:
-                    self.server.try_decrypt(&mut privmsg).await;
+                    self.server.try_decrypt(&r, &mut privmsg).await;
 
                     // We should skip any attempts to contact services from the network.
                     if ["nickserv", "chanserv"].contains(&privmsg.nick.to_lowercase().as_str()) {
@@ -264,18 +312,8 @@ impl Client {
                     }
                     drop(chans_lock);
 
-                    // Handle message lines individually
-                    for line in privmsg.msg.lines() {
-                        // Skip empty lines
-                        if line.is_empty() {
-                            continue
-                        }
-
-                        // Format the message
-                        let msg = format!("PRIVMSG {} :{}", privmsg.channel, line);
-
-                        // Send it to the client
-                        let reply = ReplyType::Client((privmsg.nick.clone(), msg));
+                    // Handle message lines individually, and send them to the client
+                    for reply in message_replies(&r, &privmsg, None) {
                         if let Err(e) = self.reply(&mut writer, &reply).await {
                             error!("[IRC CLIENT] Failed writing PRIVMSG to client: {}", e);
                             continue
@@ -305,6 +343,16 @@ impl Client {
             ReplyType::Notice((src, dst, msg)) => {
                 format!(":{}!~anon@darkirc NOTICE {} :{}", src, dst, msg)
             }
+            ReplyType::Tagged((tags, nick, msg)) => {
+                format!("{}:{}!~anon@darkirc {}", self.format_tags(tags).await, nick, msg)
+            }
+            ReplyType::Batch(msg) => {
+                if !self.has_cap("batch").await {
+                    return Ok(())
+                }
+                format!(":{} BATCH {}", SERVER_NAME, msg)
+            }
+            ReplyType::Fail(msg) => format!(":{} FAIL {}", SERVER_NAME, msg),
         };
 
         debug!("[{}] <-- {}", self.addr, r);
@@ -316,6 +364,49 @@ impl Client {
         Ok(())
     }
 
+    /// Check if the client enabled the given capability
+    pub async fn has_cap(&self, cap: &str) -> bool {
+        self.caps.read().await.get(cap) == Some(&true)
+    }
+
+    /// Format the message tags the client enabled the capabilities of
+    async fn format_tags(&self, tags: &MsgTags) -> String {
+        let mut formatted = vec![];
+
+        if self.has_cap("server-time").await {
+            formatted.push(format!("time={}", format_server_time(tags.time)));
+        }
+
+        if let (true, Some(msgid)) = (self.has_cap("message-tags").await, &tags.msgid) {
+            formatted.push(format!("msgid={}", msgid));
+        }
+
+        if let (true, Some(batch)) = (self.has_cap("batch").await, &tags.batch) {
+            formatted.push(format!("batch={}", batch));
+        }
+
+        if formatted.is_empty() {
+            return String::new()
+        }
+
+        format!("@{} ", formatted.join(";"))
+    }
+
+    /// Build the replies echoing a message we sent back to the client
+    async fn echo_replies(&self, event: &Event) -> Vec<ReplyType> {
+        let mut privmsg = match Msg::deserialize(event.content()).await {
+            Ok(Msg::V1(old_msg)) => old_msg.into_new(),
+            Ok(Msg::V2(new_msg)) => new_msg,
+            Err(_) => return vec![],
+        };
+
+        // DMs decrypt to the contact's name, but we're the sender
+        self.server.try_decrypt(event, &mut privmsg).await;
+        privmsg.nick = self.nickname.read().await.to_string();
+
+        message_replies(event, &privmsg, None)
+    }
+
     /// Handle the incoming line given sent by the IRC client
     async fn process_client_line<W>(
         &self,
@@ -343,6 +434,14 @@ impl Client {
             return Err(Error::ParseFailed("Line doesn't end with CR/LF"))
         }
 
+        // Clients with `message-tags` may prefix their lines with tags.
+        // We don't act on any of them, so they're dropped here.
+        let (_, rest) = parse_tags(&line);
+        if rest.trim().is_empty() {
+            return Ok(None)
+        }
+        let mut line = rest.to_string();
+
         // Prefix the message part of PRIVMSG with ':' if is not already.
         // Or realname part of USER command.
         let mut words: Vec<String> = line.split_whitespace().map(|s| s.to_string()).collect();
@@ -371,7 +470,9 @@ impl Client {
         // Handle the command. These implementations are in `command.rs`.
         let replies: Vec<ReplyType> = match cmd.as_str() {
             "ADMIN" => self.handle_cmd_admin(&args).await?,
+            "BACKFILL" => self.handle_cmd_backfill(&args).await?,
             "CAP" => self.handle_cmd_cap(&args).await?,
+            "CHATHISTORY" => self.handle_cmd_chathistory(&args).await?,
             "INFO" => self.handle_cmd_info(&args).await?,
             "JOIN" => self.handle_cmd_join(&args, true).await?,
             "LIST" => self.handle_cmd_list(&args).await?,
@@ -387,6 +488,8 @@ impl Client {
             "TOPIC" => self.handle_cmd_topic(&args).await?,
             "USER" => self.handle_cmd_user(&args).await?,
             "VERSION" => self.handle_cmd_version(&args).await?,
+            // Tag-only messages like typing notifications aren't relayed
+            "TAGMSG" => vec![],
             "QUIT" => return Err(Error::ChannelStopped),
             _ => {
                 warn!("[IRC CLIENT] Unimplemented \"{}\" command", cmd);
@@ -421,13 +524,13 @@ impl Client {
             if !args_queue.is_empty() {
                 for _ in 0..args_queue.len() {
                     let args = args_queue.pop_front().unwrap();
-                    pending_events.push(self.privmsg_to_event(args).await);
+                    pending_events.extend(self.privmsg_to_event(writer, args).await);
                 }
                 return Ok(Some(pending_events))
             }
 
             // If queue is empty, create an event and return it
-            let event = self.privmsg_to_event(args).await;
+            let Some(event) = self.privmsg_to_event(writer, args).await else { return Ok(None) };
 
             return Ok(Some(vec![event]))
         }
@@ -435,8 +538,14 @@ impl Client {
         Ok(None)
     }
 
-    // Internal helper function that creates an Event from PRIVMSG arguments
-    async fn privmsg_to_event(&self, args: String) -> Event {
+    // Internal helper function that creates an Event from PRIVMSG arguments.
+    // Returns `None` if the message can't be encrypted for its channel,
+    // or if it can't be signalled on a spam protected network, in which
+    // case the client is told the message wasn't sent.
+    async fn privmsg_to_event<W>(&self, writer: &mut W, args: String) -> Option<Event>
+    where
+        W: AsyncWrite + Unpin,
+    {
         let channel = args.split_ascii_whitespace().next().unwrap().to_string();
         let msg_offset = args.find(':').unwrap() + 1;
         let (_, msg) = args.split_at(msg_offset);
@@ -444,20 +553,71 @@ impl Client {
         // Truncate messages longer than MAX_MSG_LEN
         let msg = if msg.len() > MAX_MSG_LEN { msg.split_at(MAX_MSG_LEN).0 } else { msg };
 
+        // Channel messages are tagged with the channel's topic, so nodes
+        // that don't follow the channel don't have to sync them. DMs stay
+        // in the global topic.
+        let dag_topic = if channel.starts_with('#') {
+            match self.server.channels.read().await.get(&channel) {
+                Some(chan) => Some(chan.dag_topic),
+                None => Some(channel_dag_topic(&channel, None)),
+            }
+        } else {
+            None
+        };
+
         // TODO: This is kept as old version of privmsg, since now we
         // can deserialize both old and new versions, after some time
         // this will be replaced with Privmsg (new version)
         let mut privmsg = OldPrivmsg {
-            channel,
+            channel: channel.clone(),
             nick: self.nickname.read().await.to_string(),
             msg: msg.to_string(),
         };
 
+        // Keep the plaintext of DMs, since we can't decrypt them ourselves
+        // once they're sent in a DM session.
+        let is_dm = self.server.contacts.read().await.contains_key(&privmsg.channel);
+        let dm_plaintext = is_dm.then(|| privmsg.clone().into_new());
+
         // Encrypt the Privmsg if an encryption method is available.
-        self.server.try_encrypt(&mut privmsg).await;
+        if !self.server.try_encrypt(&mut privmsg).await {
+            return None
+        }
 
         // Build a DAG event and return it.
-        Event::new(serialize_async(&privmsg).await, &self.server.darkirc.event_graph).await
+        let event_graph = &self.server.darkirc.event_graph;
+        let mut event = match dag_topic {
+            Some(topic) => {
+                Event::with_topic(topic, serialize_async(&privmsg).await, event_graph).await
+            }
+            None => Event::new(serialize_async(&privmsg).await, event_graph).await,
+        };
+
+        // Signal the event if the network is spam protected
+        if let (Some(rln), Some(secret)) =
+            (&self.server.darkirc.rln, &self.server.darkirc.rln_secret)
+        {
+            if let Err(e) = rln.signal(&mut event, *secret).await {
+                error!("[IRC CLIENT] Failed creating RLN signal: {}", e);
+                // Unsignalled events are rejected by our peers, so don't
+                // pretend the message was sent.
+                let nick = self.nickname.read().await.to_string();
+                let reply = ReplyType::Server((
+                    ERR_CANNOTSENDTOCHAN,
+                    format!("{} {} :Cannot send to channel (RLN signal failed)", nick, channel),
+                ));
+                if let Err(e) = self.reply(writer, &reply).await {
+                    error!("[IRC CLIENT] Failed notifying client: {}", e);
+                }
+                return None
+            }
+        }
+
+        if let Some(plaintext) = dm_plaintext {
+            self.server.dm_cache_insert(&event.id(), &plaintext).await;
+        }
+
+        Some(event)
     }
 
     /// Atomically mark a message as seen for this client.
@@ -51,15 +51,25 @@
 
 use std::{collections::HashSet, sync::atomic::Ordering::SeqCst};
 
-use darkfi::Result;
+use darkfi::{
+    event_graph::{archive::MAX_ARCHIVE_FETCH, query::MAX_QUERY_EVENTS, Event},
+    Result,
+};
 use log::{error, info};
+use rand::{rngs::OsRng, RngCore};
 
 use super::{
-    client::{Client, ReplyType},
+    channel_dag_topic,
+    client::{message_replies, Client, ReplyType},
+    ircv3::{parse_server_time, CHATHISTORY_LIMIT},
     rpl::*,
     server::MAX_NICK_LEN,
-    IrcChannel, Msg, SERVER_NAME,
+    IrcChannel, Msg, Privmsg, SERVER_NAME,
 };
+
+/// Position of a message in the event graph's timestamp order,
+/// as `(timestamp, event_id)`
+type Position = (u64, [u8; 32]);
 use crate::crypto::bcrypt::bcrypt_hash_password;
 
 impl Client {
@@ -90,6 +100,97 @@ impl Client {
         Ok(replies)
     }
 
+    /// `BACKFILL <channel> <start> <end>`
+    ///
+    /// Non-standard command replaying the history of `<channel>` between
+    /// the UNIX timestamps `<start>` and `<end>` (in seconds) from the
+    /// event archive. This covers past DAG rotations, which are no longer
+    /// replayed on `JOIN`. If we're not running in archive mode, the
+    /// history is fetched from a peer that is. At most the oldest
+    /// `MAX_ARCHIVE_FETCH` archived events of the period are looked at,
+    /// and the client is told where to continue from if there are more.
+    pub async fn handle_cmd_backfill(&self, args: &str) -> Result<Vec<ReplyType>> {
+        if !self.registered.load(SeqCst) {
+            self.penalty.fetch_add(1, SeqCst);
+            return Ok(vec![ReplyType::Server((
+                ERR_NOTREGISTERED,
+                format!("* :{}", NOT_REGISTERED),
+            ))])
+        }
+
+        let nick = self.nickname.read().await.to_string();
+        let mut tokens = args.split_ascii_whitespace();
+
+        let (Some(channel), Some(Ok(start)), Some(Ok(end))) = (
+            tokens.next(),
+            tokens.next().map(|t| t.parse::<u64>()),
+            tokens.next().map(|t| t.parse::<u64>()),
+        ) else {
+            self.penalty.fetch_add(1, SeqCst);
+            return Ok(vec![ReplyType::Server((
+                ERR_NEEDMOREPARAMS,
+                format!("{} BACKFILL :{}", nick, INVALID_SYNTAX),
+            ))])
+        };
+
+        let event_graph = &self.server.darkirc.event_graph;
+        let (start, end) = (start.saturating_mul(1000), end.saturating_mul(1000));
+
+        // Try our own archive first, and ask the network otherwise
+        let mut events = event_graph.archive_get(start, end).await?;
+        if events.is_empty() {
+            events = match event_graph.fetch_archive(start, end).await {
+                Ok(events) => events,
+                Err(e) => {
+                    error!("[IRC CLIENT] (backfill) fetch_archive() failed: {}", e);
+                    return Ok(vec![ReplyType::Notice((
+                        SERVER_NAME.to_string(),
+                        nick,
+                        format!("Could not fetch the history of {}: {}", channel, e),
+                    ))])
+                }
+            };
+        }
+
+        let mut replies = vec![];
+        for event in events.iter() {
+            // Try to deserialize it. (Here we skip errors)
+            let mut privmsg = match Msg::deserialize(event.content()).await {
+                Ok(Msg::V1(old_msg)) => old_msg.into_new(),
+                Ok(Msg::V2(new_msg)) => new_msg,
+                Err(_) => continue,
+            };
+
+            // Potentially decrypt the privmsg
+            self.server.try_decrypt(event, &mut privmsg).await;
+
+            if privmsg.channel != channel {
+                continue
+            }
+
+            replies.extend(message_replies(event, &privmsg, None));
+        }
+
+        // Let the client know where to continue from if we didn't get
+        // the whole period.
+        if events.len() >= MAX_ARCHIVE_FETCH {
+            let last = events.last().unwrap().timestamp / 1000;
+            replies.push(ReplyType::Notice((
+                SERVER_NAME.to_string(),
+                nick,
+                format!(
+                    "History of {} is truncated, BACKFILL {} {} {} for more",
+                    channel,
+                    channel,
+                    last,
+                    end / 1000,
+                ),
+            )));
+        }
+
+        Ok(replies)
+    }
+
     /// `CAP <args>`
     pub async fn handle_cmd_cap(&self, args: &str) -> Result<Vec<ReplyType>> {
         let mut tokens = args.split_ascii_whitespace();
@@ -210,6 +311,102 @@ impl Client {
         ))])
     }
 
+    /// `CHATHISTORY <subcommand> <target> <reference> [<reference>] <limit>`
+    ///
+    /// IRCv3 `draft/chathistory`, replaying the history of a channel or
+    /// contact from the event graph in a `chathistory` batch. Messages are
+    /// referenced with `msgid=<event ID>` or `timestamp=<server-time>`.
+    /// The `LATEST`, `BEFORE`, `AFTER`, `AROUND` and `BETWEEN`
+    /// subcommands are supported.
+    pub async fn handle_cmd_chathistory(&self, args: &str) -> Result<Vec<ReplyType>> {
+        if !self.registered.load(SeqCst) {
+            self.penalty.fetch_add(1, SeqCst);
+            return Ok(vec![ReplyType::Server((
+                ERR_NOTREGISTERED,
+                format!("* :{}", NOT_REGISTERED),
+            ))])
+        }
+
+        let fail = |code: &str, context: &str, description: &str| {
+            vec![ReplyType::Fail(format!("CHATHISTORY {} {} :{}", code, context, description))]
+        };
+
+        let tokens: Vec<&str> = args.split_ascii_whitespace().collect();
+        let Some(subcommand) = tokens.first().map(|s| s.to_uppercase()) else {
+            return Ok(fail("NEED_MORE_PARAMS", "*", "Missing subcommand"))
+        };
+
+        let params = match subcommand.as_str() {
+            "LATEST" | "BEFORE" | "AFTER" | "AROUND" => 4,
+            "BETWEEN" => 5,
+            _ => return Ok(fail("INVALID_PARAMS", &subcommand, "Unsupported subcommand")),
+        };
+
+        if tokens.len() < params {
+            return Ok(fail("NEED_MORE_PARAMS", &subcommand, "Missing parameters"))
+        }
+
+        let target = tokens[1];
+        let Ok(limit) = tokens[params - 1].parse::<usize>() else {
+            return Ok(fail("INVALID_PARAMS", &subcommand, "Invalid limit"))
+        };
+        let limit = limit.min(CHATHISTORY_LIMIT);
+
+        // Only channels and our contacts have a history
+        if !target.starts_with('#') && !self.server.contacts.read().await.contains_key(target) {
+            return Ok(fail("INVALID_TARGET", &subcommand, "Unknown target"))
+        }
+
+        // Messages right before or after a reference, which is excluded
+        let (Some(before), Some(after)) = (
+            self.history_position(tokens[2], false).await,
+            self.history_position(tokens[2], true).await,
+        ) else {
+            return Ok(fail("INVALID_PARAMS", &subcommand, "Invalid message reference"))
+        };
+
+        let messages = match subcommand.as_str() {
+            "LATEST" => {
+                let floor = if tokens[2] == "*" { None } else { Some(after) };
+                self.history_before(target, None, floor, limit).await?
+            }
+            "BEFORE" => self.history_before(target, Some(before), None, limit).await?,
+            "AFTER" => self.history_after(target, after, None, limit).await?,
+            "AROUND" => {
+                let mut messages =
+                    self.history_before(target, Some(before), None, limit / 2).await?;
+                let remaining = limit - messages.len();
+                messages.extend(self.history_after(target, after, None, remaining).await?);
+                messages
+            }
+            "BETWEEN" => {
+                let (Some(end_before), Some(end_after)) = (
+                    self.history_position(tokens[3], false).await,
+                    self.history_position(tokens[3], true).await,
+                ) else {
+                    return Ok(fail("INVALID_PARAMS", &subcommand, "Invalid message reference"))
+                };
+
+                // The limit applies from the first reference on
+                if after <= end_before {
+                    self.history_after(target, after, Some(end_before), limit).await?
+                } else {
+                    self.history_before(target, Some(before), Some(end_after), limit).await?
+                }
+            }
+            _ => unreachable!(),
+        };
+
+        let batch = format!("{:08x}", OsRng.next_u32());
+        let mut replies = vec![ReplyType::Batch(format!("+{} chathistory {}", batch, target))];
+        for (event, privmsg) in messages.iter() {
+            replies.extend(message_replies(event, privmsg, Some(&batch)));
+        }
+        replies.push(ReplyType::Batch(format!("-{}", batch)));
+
+        Ok(replies)
+    }
+
     /// `INFO [<target>]`
     ///
     /// Gives information about the `<target>` server, or the current server if
@@ -308,6 +505,8 @@ impl Client {
                     topic: String::new(),
                     nicks: HashSet::from([nick.clone()]),
                     saltbox: None,
+                    group: None,
+                    dag_topic: channel_dag_topic(channel, None),
                 };
                 server_channels.insert(channel.clone(), chan);
             }
@@ -329,6 +528,11 @@ impl Client {
         drop(active_channels);
         drop(server_channels);
 
+        // Follow the new channels' topics if we're syncing selectively
+        if !channels.is_empty() {
+            self.server.update_topic_filter().await;
+        }
+
         if hist {
             // Potentially extend the replies with channel history
             replies.extend(self.get_history(&channels).await.unwrap());
@@ -709,6 +913,14 @@ impl Client {
             return Ok(vec![ReplyType::Server((ERR_NOSUCHNICK, format!("{} :{}", nick, target)))])
         }
 
+        // Forward-secret channels need a key from their owner first
+        if !self.server.group_can_send(target).await {
+            return Ok(vec![ReplyType::Server((
+                ERR_CANNOTSENDTOCHAN,
+                format!("{} {} :Cannot send to channel (no key received yet)", nick, target),
+            ))])
+        }
+
         Ok(vec![])
     }
 
@@ -910,6 +1122,13 @@ impl Client {
                     env!("CARGO_PKG_VERSION")
                 ),
             )),
+            ReplyType::Server((
+                RPL_ISUPPORT,
+                format!(
+                    "{} CHATHISTORY={} MSGREFTYPES=msgid,timestamp :are supported by this server",
+                    nick, CHATHISTORY_LIMIT
+                ),
+            )),
         ];
 
         // Append the MOTD
@@ -958,58 +1177,200 @@ impl Client {
             return Ok(vec![])
         }
 
-        // Fetch and order all the events from the DAG
-        let dag_events = self.server.darkirc.event_graph.order_events().await;
+        // Page through the DAG oldest first, instead of loading and
+        // sorting all of it at once
+        let event_graph = &self.server.darkirc.event_graph;
+        let mut cursor = (0, blake3::Hash::from_bytes([0x00; 32]));
 
         // Here we'll hold the events in order we'll push to the client
         let mut replies = vec![];
 
-        for event in dag_events.iter() {
-            let event_id = event.id();
-            // If it was seen, skip
-            match self.is_seen(&event_id).await {
-                Ok(true) => continue,
-                Ok(false) => {}
-                Err(e) => {
-                    error!("[IRC CLIENT] (get_history) self.is_seen({}) failed: {}", event_id, e);
+        loop {
+            let page =
+                event_graph.events_after_position(cursor.0, &cursor.1, MAX_QUERY_EVENTS).await?;
+            let Some(last) = page.last() else { break };
+            cursor = (last.timestamp, last.id());
+
+            for event in page.iter() {
+                let event_id = event.id();
+                // If it was seen, skip
+                match self.is_seen(&event_id).await {
+                    Ok(true) => continue,
+                    Ok(false) => {}
+                    Err(e) => {
+                        error!(
+                            "[IRC CLIENT] (get_history) self.is_seen({}) failed: {}",
+                            event_id, e
+                        );
+                        return Err(e)
+                    }
+                }
+
+                // Group control messages and prekey bundles only update our keys
+                if self.server.handle_control(event).await {
+                    continue
+                }
+
+                // Try to deserialize it. (Here we skip errors)
+                let mut privmsg = match Msg::deserialize(event.content()).await {
+                    Ok(Msg::V1(old_msg)) => old_msg.into_new(),
+                    Ok(Msg::V2(new_msg)) => new_msg,
+                    Err(_) => continue,
+                };
+
+                // Potentially decrypt the privmsg
+                self.server.try_decrypt(event, &mut privmsg).await;
+
+                // If the privmsg is intented for any of the given
+                // channels, contacts or oursleves, add it as a reply and
+                // mark it as seen in the seen_events tree.
+                let contacts = self.server.contacts.read().await;
+                if !channels.contains(&privmsg.channel) &&
+                    !contacts.contains_key(&privmsg.channel) &&
+                    !contacts.contains_key(&privmsg.nick)
+                {
+                    continue
+                }
+
+                // Insert nicks into channels
+                if let Some(chan) = self.server.channels.write().await.get_mut(&privmsg.channel) {
+                    chan.nicks.insert(privmsg.nick.clone());
+                }
+
+                replies.extend(message_replies(event, &privmsg, None));
+                if let Err(e) = self.mark_seen(&event_id).await {
+                    error!("[IRC CLIENT] (get_history) self.mark_seen({}) failed: {}", event_id, e);
                     return Err(e)
                 }
             }
 
-            // Try to deserialize it. (Here we skip errors)
-            let mut privmsg = match Msg::deserialize(event.content()).await {
-                Ok(Msg::V1(old_msg)) => old_msg.into_new(),
-                Ok(Msg::V2(new_msg)) => new_msg,
-                Err(_) => continue,
+            if page.len() < MAX_QUERY_EVENTS {
+                break
+            }
+        }
+
+        Ok(replies)
+    }
+
+    /// Resolve a `CHATHISTORY` message reference into a position. Timestamps
+    /// fall between messages, so they resolve to a position right before or
+    /// right after the messages sent at that time, depending on `after`.
+    async fn history_position(&self, reference: &str, after: bool) -> Option<Position> {
+        if reference == "*" {
+            return Some((0, [0x00; 32]))
+        }
+
+        if let Some(time) = reference.strip_prefix("timestamp=") {
+            let timestamp = parse_server_time(time)?;
+            return Some((timestamp, if after { [0xff; 32] } else { [0x00; 32] }))
+        }
+
+        let event_id = blake3::Hash::from_hex(reference.strip_prefix("msgid=")?).ok()?;
+        let event = self.server.darkirc.event_graph.dag_get(&event_id).await.ok()??;
+        Some((event.timestamp, *event_id.as_bytes()))
+    }
+
+    /// Return the message of an event if it belongs to the history of `target`
+    async fn history_message(&self, event: &Event, target: &str) -> Option<Privmsg> {
+        // Group control messages and prekey bundles only update our keys
+        if self.server.handle_control(event).await {
+            return None
+        }
+
+        let mut privmsg = match Msg::deserialize(event.content()).await {
+            Ok(Msg::V1(old_msg)) => old_msg.into_new(),
+            Ok(Msg::V2(new_msg)) => new_msg,
+            Err(_) => return None,
+        };
+
+        self.server.try_decrypt(event, &mut privmsg).await;
+        (privmsg.channel == target).then_some(privmsg)
+    }
+
+    /// Collect at most `limit` messages of `target` before the `upper`
+    /// position, or the latest ones, stopping at the `floor` position.
+    /// The messages are returned oldest first.
+    async fn history_before(
+        &self,
+        target: &str,
+        mut upper: Option<Position>,
+        floor: Option<Position>,
+        limit: usize,
+    ) -> Result<Vec<(Event, Privmsg)>> {
+        let event_graph = &self.server.darkirc.event_graph;
+        let mut messages = vec![];
+
+        'pages: while messages.len() < limit {
+            // The event graph can't filter by channel, so we page through
+            // all events until we have enough messages.
+            let page = match upper {
+                Some((timestamp, event_id)) => {
+                    let event_id = blake3::Hash::from_bytes(event_id);
+                    event_graph
+                        .events_before_position(timestamp, &event_id, MAX_QUERY_EVENTS)
+                        .await?
+                }
+                None => event_graph.events_before(None, MAX_QUERY_EVENTS).await?,
             };
 
-            // Potentially decrypt the privmsg
-            self.server.try_decrypt(&mut privmsg).await;
-
-            // If the privmsg is intented for any of the given
-            // channels, contacts or oursleves, add it as a reply and
-            // mark it as seen in the seen_events tree.
-            let contacts = self.server.contacts.read().await;
-            if !channels.contains(&privmsg.channel) &&
-                !contacts.contains_key(&privmsg.channel) &&
-                !contacts.contains_key(&privmsg.nick)
-            {
-                continue
-            }
+            let Some(oldest) = page.first() else { break };
+            upper = Some((oldest.timestamp, *oldest.id().as_bytes()));
+
+            for event in page.iter().rev() {
+                if floor.is_some_and(|floor| (event.timestamp, *event.id().as_bytes()) <= floor) {
+                    break 'pages
+                }
 
-            // Insert nicks into channels
-            if let Some(chan) = self.server.channels.write().await.get_mut(&privmsg.channel) {
-                chan.nicks.insert(privmsg.nick.clone());
+                if let Some(privmsg) = self.history_message(event, target).await {
+                    messages.push((event.clone(), privmsg));
+                    if messages.len() == limit {
+                        break 'pages
+                    }
+                }
             }
+        }
 
-            let msg = format!("PRIVMSG {} :{}", privmsg.channel, privmsg.msg);
-            replies.push(ReplyType::Client((privmsg.nick, msg)));
-            if let Err(e) = self.mark_seen(&event_id).await {
-                error!("[IRC CLIENT] (get_history) self.mark_seen({}) failed: {}", event_id, e);
-                return Err(e)
+        messages.reverse();
+        Ok(messages)
+    }
+
+    /// Collect at most `limit` messages of `target` after the `lower`
+    /// position, stopping at the `ceiling` position. The messages are
+    /// returned oldest first.
+    async fn history_after(
+        &self,
+        target: &str,
+        mut lower: Position,
+        ceiling: Option<Position>,
+        limit: usize,
+    ) -> Result<Vec<(Event, Privmsg)>> {
+        let event_graph = &self.server.darkirc.event_graph;
+        let mut messages = vec![];
+
+        'pages: while messages.len() < limit {
+            let event_id = blake3::Hash::from_bytes(lower.1);
+            let page =
+                event_graph.events_after_position(lower.0, &event_id, MAX_QUERY_EVENTS).await?;
+
+            let Some(newest) = page.last() else { break };
+            lower = (newest.timestamp, *newest.id().as_bytes());
+
+            for event in page.iter() {
+                if ceiling
+                    .is_some_and(|ceiling| (event.timestamp, *event.id().as_bytes()) >= ceiling)
+                {
+                    break 'pages
+                }
+
+                if let Some(privmsg) = self.history_message(event, target).await {
+                    messages.push((event.clone(), privmsg));
+                    if messages.len() == limit {
+                        break 'pages
+                    }
+                }
             }
         }
 
-        Ok(replies)
+        Ok(messages)
     }
 }
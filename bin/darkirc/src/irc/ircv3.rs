@@ -0,0 +1,178 @@
+/* This file is part of DarkFi (https://dark.fi)
+ *
+ * Copyright (C) 2020-2024 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! IRCv3 extensions, see <https://ircv3.net/irc/>
+//!
+//! Messages relayed from the event graph are tagged with the timestamp
+//! and ID of their event, so clients can show the right time for replayed
+//! history and reference messages in `CHATHISTORY` requests.
+
+use darkfi::util::time::DateTime;
+
+/// Capabilities we support, along with our own `no-history` and
+/// `no-autojoin`
+pub const SUPPORTED_CAPS: [&str; 5] =
+    ["server-time", "message-tags", "echo-message", "batch", "draft/chathistory"];
+
+/// Maximum number of messages returned for a `CHATHISTORY` request
+pub const CHATHISTORY_LIMIT: usize = 500;
+
+/// Message tags of a message relayed from the event graph
+#[derive(Clone, Debug)]
+pub struct MsgTags {
+    /// Timestamp of the event, in milliseconds, sent as `time`
+    pub time: u64,
+    /// ID of the event, sent as `msgid`
+    pub msgid: Option<blake3::Hash>,
+    /// Reference of the batch the message is part of
+    pub batch: Option<String>,
+}
+
+/// Format a timestamp in milliseconds as a `server-time` value,
+/// e.g. `2019-01-04T14:33:26.123Z`
+pub fn format_server_time(timestamp: u64) -> String {
+    let date_time = DateTime::from_timestamp(timestamp / 1000, 0);
+    format!("{}.{:03}Z", date_time, timestamp % 1000)
+}
+
+/// Parse a `server-time` value into a timestamp in milliseconds
+pub fn parse_server_time(time: &str) -> Option<u64> {
+    let time = time.strip_suffix('Z')?;
+    let (date_time, millis) = match time.split_once('.') {
+        Some((date_time, fraction)) => {
+            if fraction.is_empty() || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+                return None
+            }
+            // Only keep millisecond precision
+            let mut digits = fraction.bytes().take(3).map(|b| (b - b'0') as u64);
+            let millis = (0..3).fold(0, |acc, _| acc * 10 + digits.next().unwrap_or(0));
+            (date_time, millis)
+        }
+        None => (time, 0),
+    };
+
+    let date_time = DateTime::from_timestamp_str(date_time).ok()?;
+    if date_time.year < 1970 {
+        return None
+    }
+
+    let days = days_from_civil(date_time.year as u64, date_time.month as u64, date_time.day as u64);
+    let secs = days * 86400 +
+        date_time.hour as u64 * 3600 +
+        date_time.min as u64 * 60 +
+        date_time.sec as u64;
+
+    Some(secs * 1000 + millis)
+}
+
+/// Split the `@tags` prefix off a line sent by a client, returning the
+/// unescaped tags and the rest of the line. Lines without tags are
+/// returned as they are.
+pub fn parse_tags(line: &str) -> (Vec<(String, String)>, &str) {
+    let Some(line) = line.strip_prefix('@') else { return (vec![], line) };
+    let (tags, rest) = line.split_once(' ').unwrap_or((line, ""));
+
+    let tags = tags
+        .split(';')
+        .filter(|tag| !tag.is_empty())
+        .map(|tag| {
+            let (key, value) = tag.split_once('=').unwrap_or((tag, ""));
+            (key.to_string(), unescape_tag_value(value))
+        })
+        .collect();
+
+    (tags, rest.trim_start())
+}
+
+/// Unescape a tag value, see <https://ircv3.net/specs/extensions/message-tags#escaping-values>
+fn unescape_tag_value(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue
+        }
+
+        // A trailing backslash is dropped
+        match chars.next() {
+            Some(':') => unescaped.push(';'),
+            Some('s') => unescaped.push(' '),
+            Some('r') => unescaped.push('\r'),
+            Some('n') => unescaped.push('\n'),
+            Some(c) => unescaped.push(c),
+            None => {}
+        }
+    }
+    unescaped
+}
+
+/// Number of days between the UNIX epoch and the given date, from
+/// <http://howardhinnant.github.io/date_algorithms.html#days_from_civil>
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let month_index = if month > 2 { month - 3 } else { month + 9 };
+    let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn server_time() {
+        assert_eq!(format_server_time(1546612406123), "2019-01-04T14:33:26.123Z");
+        assert_eq!(parse_server_time("2019-01-04T14:33:26.123Z"), Some(1546612406123));
+        assert_eq!(parse_server_time("2019-01-04T14:33:26Z"), Some(1546612406000));
+        assert_eq!(parse_server_time("2019-01-04T14:33:26.1Z"), Some(1546612406100));
+        assert_eq!(parse_server_time("2024-02-29T00:00:00.000001Z"), Some(1709164800000));
+        assert_eq!(parse_server_time("1970-01-01T00:00:00.000Z"), Some(0));
+
+        for timestamp in [0, 951782400999, 1546612406123, 4102444800000] {
+            assert_eq!(parse_server_time(&format_server_time(timestamp)), Some(timestamp));
+        }
+
+        assert_eq!(parse_server_time("2019-01-04T14:33:26.123"), None);
+        assert_eq!(parse_server_time("2019-01-04T14:33:26.Z"), None);
+        assert_eq!(parse_server_time("2019-02-30T14:33:26Z"), None);
+        assert_eq!(parse_server_time("yesterday"), None);
+    }
+
+    #[test]
+    fn client_tags() {
+        let (tags, rest) =
+            parse_tags("@label=abc;+draft/reply=x\\sy\\:z;+typing PRIVMSG #dev :hi there");
+        assert_eq!(rest, "PRIVMSG #dev :hi there");
+        assert_eq!(
+            tags,
+            vec![
+                ("label".to_string(), "abc".to_string()),
+                ("+draft/reply".to_string(), "x y;z".to_string()),
+                ("+typing".to_string(), String::new()),
+            ]
+        );
+
+        assert_eq!(parse_tags("PRIVMSG #dev :hi"), (vec![], "PRIVMSG #dev :hi"));
+        assert_eq!(parse_tags("@+typing=active").1, "");
+        assert_eq!(unescape_tag_value("a\\\\b\\"), "a\\b");
+    }
+}
@@ -19,7 +19,11 @@
 use std::{collections::HashSet, sync::Arc};
 
 use crypto_box::ChaChaBox;
-use darkfi::{Error, Result};
+use darkfi::{
+    event_graph::{Event, EventValidator},
+    Error, Result,
+};
+use darkfi_sdk::crypto::{Keypair, PublicKey};
 use darkfi_serial::{async_trait, deserialize_async_partial, SerialDecodable, SerialEncodable};
 
 /// IRC client state
@@ -38,6 +42,9 @@ pub(crate) use services::nickserv::NickServ;
 /// IRC numerics and server replies
 pub(crate) mod rpl;
 
+/// IRCv3 extensions
+pub(crate) mod ircv3;
+
 /// Hardcoded server name
 const SERVER_NAME: &str = "irc.dark.fi";
 
@@ -125,16 +132,56 @@ impl Msg {
     }
 }
 
+/// Event graph validator rejecting events that aren't IRC messages
+pub struct MsgValidator;
+
+#[async_trait]
+impl EventValidator for MsgValidator {
+    async fn validate(&self, event: &Event) -> std::result::Result<(), String> {
+        match Msg::deserialize(event.content()).await {
+            Ok(_) => Ok(()),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+}
+
 /// IRC channel definition
 #[derive(Clone)]
 pub struct IrcChannel {
     pub topic: String,
     pub nicks: HashSet<String>,
     pub saltbox: Option<Arc<ChaChaBox>>,
+    /// Forward-secret group the channel is encrypted for, if any
+    pub group: Option<Arc<ChannelGroup>>,
+    /// Event graph topic the channel's messages are tagged with
+    pub dag_topic: blake3::Hash,
+}
+
+/// Forward-secret group definition of a channel
+pub struct ChannelGroup {
+    /// ID of the group, derived from the channel name and the owner
+    pub id: [u8; 32],
+    /// Public key of the owner, signing the group control messages
+    pub owner: PublicKey,
+    /// Our keypair, if we own the group
+    pub owner_keypair: Option<Keypair>,
+    /// DM public keys of the members, if we own the group
+    pub members: Vec<crypto_box::PublicKey>,
+}
+
+/// Derive the event graph topic of a channel. Encrypted channels derive
+/// it from their secret, so it doesn't reveal the channel name.
+pub fn channel_dag_topic(name: &str, secret: Option<&[u8; 32]>) -> blake3::Hash {
+    match secret {
+        Some(secret) => blake3::derive_key("darkirc encrypted channel topic", secret).into(),
+        None => blake3::derive_key("darkirc channel topic", name.as_bytes()).into(),
+    }
 }
 
 /// IRC contact definition
 #[derive(Clone)]
 pub struct IrcContact {
     pub saltbox: Option<Arc<ChaChaBox>>,
+    /// DM public key of the contact, identifying its DM sessions
+    pub public: crypto_box::PublicKey,
 }
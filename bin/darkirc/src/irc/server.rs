@@ -16,19 +16,26 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use std::{collections::HashMap, fs::File, io::BufReader, path::PathBuf, sync::Arc};
+use std::{
+    collections::HashMap, fs::File, io::BufReader, path::PathBuf, sync::Arc, time::UNIX_EPOCH,
+};
 
+use crypto_box::ChaChaBox;
 use darkfi::{
-    event_graph::Event,
-    system::{StoppableTask, StoppableTaskPtr, Subscription},
+    event_graph::{Event, TopicFilter},
+    system::{msleep, StoppableTask, StoppableTaskPtr, Subscription},
     util::path::expand_path,
     Error, Result,
 };
+use darkfi_serial::{deserialize_async, serialize_async};
+use futures::FutureExt;
 use futures_rustls::{
     rustls::{self, pki_types::PrivateKeyDer},
     TlsAcceptor,
 };
 use log::{debug, error, info, warn};
+use rand::{rngs::OsRng, RngCore};
+use sled_overlay::sled;
 use smol::{
     fs,
     lock::{Mutex, RwLock},
@@ -37,11 +44,24 @@ use smol::{
     Executor,
 };
 use url::Url;
+use x25519_dalek::StaticSecret;
 
-use super::{client::Client, IrcChannel, IrcContact, Priv, Privmsg};
+use super::{
+    channel_dag_topic, client::Client, ChannelGroup, IrcChannel, IrcContact, Msg, OldPrivmsg, Priv,
+    Privmsg,
+};
 use crate::{
-    crypto::saltbox,
-    settings::{parse_autojoin_channels, parse_configured_channels, parse_configured_contacts},
+    crypto::{
+        group::{self, GroupControl, GroupKeys, RATCHET_PERIOD},
+        pqxdh::{
+            self, DirectMessage, DmSession, PrekeyBundle, Prekeys, MAX_SESSIONS, PREKEY_LIFETIME,
+        },
+        saltbox,
+    },
+    settings::{
+        parse_autojoin_channels, parse_configured_channels, parse_configured_contacts,
+        parse_dm_chacha_secret, parse_selective_sync,
+    },
     DarkIrc,
 };
 
@@ -51,6 +71,9 @@ pub const MAX_NICK_LEN: usize = 24;
 /// Max message length
 pub const MAX_MSG_LEN: usize = 512;
 
+/// Key derivation context of the key the DM cache is encrypted with
+const DM_CACHE_CONTEXT: &str = "darkirc dm cache";
+
 /// IRC server instance
 pub struct IrcServer {
     /// DarkIrc instance
@@ -67,6 +90,27 @@ pub struct IrcServer {
     pub channels: RwLock<HashMap<String, IrcChannel>>,
     /// Configured IRC contacts
     pub contacts: RwLock<HashMap<String, IrcContact>>,
+    /// Our DM secret key, used to open the keys of forward-secret groups
+    dm_secret: RwLock<Option<crypto_box::SecretKey>>,
+    /// Sled tree holding the keys of forward-secret groups
+    groups: sled::Tree,
+    /// Lock serializing updates to the group keys
+    groups_lock: Mutex<()>,
+    /// Sled tree holding our DM prekeys, by signed prekey
+    prekeys: sled::Tree,
+    /// Sled tree holding the latest prekey bundle of each contact
+    bundles: sled::Tree,
+    /// Sled tree holding our DM sessions, by contact DM public key
+    dm_sessions: sled::Tree,
+    /// Sled tree holding the plaintext of DMs, by event ID. Messages of DM
+    /// sessions can only be decrypted once, so they are kept here for as
+    /// long as their event is in the DAG, encrypted under a key derived
+    /// from our DM secret key.
+    dm_cache: sled::Tree,
+    /// Lock serializing updates to the DM sessions
+    dm_lock: Mutex<()>,
+    /// Only follow the topics of our channels in the event graph
+    selective_sync: RwLock<bool>,
     /// Active client connections
     clients: Mutex<HashMap<u16, StoppableTaskPtr>>,
     /// IRC server Password
@@ -125,6 +169,13 @@ impl IrcServer {
             _ => None,
         };
 
+        let groups = darkirc.sled.open_tree("darkirc_groups")?;
+        let prekeys = darkirc.sled.open_tree("darkirc_prekeys")?;
+        let bundles = darkirc.sled.open_tree("darkirc_prekey_bundles")?;
+        let dm_sessions = darkirc.sled.open_tree("darkirc_dm_sessions")?;
+        // Older versions kept DM plaintexts on disk unencrypted, get rid of them
+        darkirc.sled.drop_tree("darkirc_dm_cache")?;
+        let dm_cache = darkirc.sled.open_tree("darkirc_dm_history")?;
         let self_ = Arc::new(Self {
             darkirc,
             config_path,
@@ -133,6 +184,15 @@ impl IrcServer {
             autojoin: RwLock::new(Vec::new()),
             channels: RwLock::new(HashMap::new()),
             contacts: RwLock::new(HashMap::new()),
+            dm_secret: RwLock::new(None),
+            groups,
+            groups_lock: Mutex::new(()),
+            prekeys,
+            bundles,
+            dm_sessions,
+            dm_cache,
+            dm_lock: Mutex::new(()),
+            selective_sync: RwLock::new(false),
             clients: Mutex::new(HashMap::new()),
             password,
         });
@@ -163,15 +223,48 @@ impl IrcServer {
         // Parse configured contacts
         let contacts = parse_configured_contacts(&contents)?;
 
+        // Parse our DM secret
+        let dm_secret = parse_dm_chacha_secret(&contents)?;
+
+        // Parse selective sync flag
+        let selective_sync = parse_selective_sync(&contents)?;
+
         // FIXME: This will remove clients' joined channels. They need to stay.
         // Only if everything is fine, replace.
         *self.autojoin.write().await = autojoin;
         *self.channels.write().await = channels;
         *self.contacts.write().await = contacts;
+        *self.dm_secret.write().await = dm_secret;
+        *self.selective_sync.write().await = selective_sync;
+
+        self.update_topic_filter().await;
+
+        // Hand out new keys for the groups we own if their members changed
+        self.group_rekey().await;
 
         Ok(())
     }
 
+    /// Update the event graph topics we follow. With selective sync, we
+    /// follow the autojoin, configured and joined channels. Otherwise we
+    /// follow every channel. DMs are untagged, so they always reach us.
+    pub async fn update_topic_filter(&self) {
+        let filter = if *self.selective_sync.read().await {
+            let channels = self.channels.read().await;
+            let mut topics: Vec<blake3::Hash> = channels.values().map(|c| c.dag_topic).collect();
+            for name in self.autojoin.read().await.iter() {
+                if !channels.contains_key(name) {
+                    topics.push(channel_dag_topic(name, None));
+                }
+            }
+            TopicFilter::only(topics)
+        } else {
+            TopicFilter::all()
+        };
+
+        self.darkirc.event_graph.set_topic_filter(filter).await;
+    }
+
     /// Start accepting new IRC connections.
     pub async fn listen(self: Arc<Self>, ex: Arc<Executor<'_>>) -> Result<()> {
         loop {
@@ -281,10 +374,39 @@ impl IrcServer {
         }
     }
 
+    /// Random value looking like an encrypted channel or nick, for the
+    /// fields DM sessions don't use.
+    fn dummy_ciphertext() -> String {
+        // nonce||ciphertext||tag of MAX_NICK_LEN bytes
+        let mut bytes = [0u8; 24 + MAX_NICK_LEN + 16];
+        OsRng.fill_bytes(&mut bytes);
+        bs58::encode(bytes).into_string()
+    }
+
     /// Try encrypting a given `Privmsg` if there is such a channel/contact.
-    pub async fn try_encrypt<T: Priv>(&self, privmsg: &mut T) {
+    /// Returns `false` if the message is for a forward-secret channel we
+    /// don't have a key for, in which case it must not be sent.
+    pub async fn try_encrypt<T: Priv>(&self, privmsg: &mut T) -> bool {
         if let Some((name, channel)) = self.channels.read().await.get_key_value(privmsg.channel()) {
-            if let Some(saltbox) = &channel.saltbox {
+            let saltbox = match (&channel.saltbox, &channel.group) {
+                (Some(saltbox), _) => Some(saltbox.clone()),
+                (None, Some(group)) => match self.group_keys(&group.id).await {
+                    Ok(keys) => match keys.current() {
+                        Some(epoch_key) => Some(Arc::new(group::epoch_box(&epoch_key.key))),
+                        None => {
+                            warn!("No key for forward-secret channel {}, not sending", name);
+                            return false
+                        }
+                    },
+                    Err(e) => {
+                        error!("Failed loading the group keys of {}: {}", name, e);
+                        return false
+                    }
+                },
+                (None, None) => None,
+            };
+
+            if let Some(saltbox) = &saltbox {
                 // We will use a dummy channel value of MAX_NICK_LEN,
                 // since its not used, so all encrypted messages look the same.
                 *privmsg.channel() = saltbox::encrypt(saltbox, &[0x00; MAX_NICK_LEN]);
@@ -292,11 +414,28 @@ impl IrcServer {
                 *privmsg.nick() = saltbox::encrypt(saltbox, &Self::pad(privmsg.nick()));
                 *privmsg.msg() = saltbox::encrypt(saltbox, privmsg.msg().as_bytes());
                 debug!("Successfully encrypted message for {}", name);
-                return
+                return true
             }
         };
 
         if let Some((name, contact)) = self.contacts.read().await.get_key_value(privmsg.channel()) {
+            // Use a DM session if the contact published prekeys,
+            // and fall back to the static key otherwise.
+            match self.dm_encrypt(&contact.public, privmsg.msg().as_bytes()).await {
+                Ok(Some(message)) => {
+                    *privmsg.channel() = Self::dummy_ciphertext();
+                    *privmsg.nick() = Self::dummy_ciphertext();
+                    *privmsg.msg() = bs58::encode(serialize_async(&message).await).into_string();
+                    debug!("Successfully encrypted message for {} in a DM session", name);
+                    return true
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    error!("Failed encrypting message for {} in a DM session: {}", name, e);
+                    return false
+                }
+            }
+
             if let Some(saltbox) = &contact.saltbox {
                 // We will use dummy channel and nick values of MAX_NICK_LEN,
                 // since they are not used, so all encrypted messages look the same.
@@ -306,10 +445,18 @@ impl IrcServer {
                 debug!("Successfully encrypted message for {}", name);
             }
         };
+
+        true
     }
 
-    /// Try decrypting a given potentially encrypted `Privmsg` object.
-    pub async fn try_decrypt(&self, privmsg: &mut Privmsg) {
+    /// Try decrypting a given potentially encrypted `Privmsg` object
+    /// carried by the given event.
+    pub async fn try_decrypt(&self, event: &Event, privmsg: &mut Privmsg) {
+        if let Some(cached) = self.dm_cached(&event.id()).await {
+            *privmsg = cached;
+            return
+        }
+
         // If all fields have base58, then we can consider decrypting.
         let channel_ciphertext = match bs58::decode(&privmsg.channel).into_vec() {
             Ok(v) => v,
@@ -330,9 +477,25 @@ impl IrcServer {
         // for decryption, iff all passes, we will return a modified
         // (i.e. decrypted) privmsg, otherwise we return the original.
         for (name, channel) in self.channels.read().await.iter() {
-            let Some(saltbox) = &channel.saltbox else { continue };
+            // Forward-secret channels can be decrypted with any epoch
+            // key we hold for the current period.
+            let saltboxes = match (&channel.saltbox, &channel.group) {
+                (Some(saltbox), _) => vec![saltbox.clone()],
+                (None, Some(group)) => match self.group_keys(&group.id).await {
+                    Ok(keys) => {
+                        keys.keys.iter().map(|k| Arc::new(group::epoch_box(&k.key))).collect()
+                    }
+                    Err(e) => {
+                        error!("Failed loading the group keys of {}: {}", name, e);
+                        continue
+                    }
+                },
+                (None, None) => continue,
+            };
 
-            if saltbox::try_decrypt(saltbox, &channel_ciphertext).is_none() {
+            let Some(saltbox) =
+                saltboxes.iter().find(|s| saltbox::try_decrypt(s, &channel_ciphertext).is_some())
+            else {
                 continue
             };
 
@@ -378,5 +541,644 @@ impl IrcServer {
             debug!("Successfully decrypted message from {}", name);
             return
         }
+
+        self.try_decrypt_dm(event, privmsg, &msg_ciphertext).await;
+    }
+
+    /// Try decrypting a message of one of our DM sessions. On success,
+    /// the plaintext is cached, since it can't be decrypted again.
+    async fn try_decrypt_dm(&self, event: &Event, privmsg: &mut Privmsg, ciphertext: &[u8]) {
+        let Ok(message) = deserialize_async::<DirectMessage>(ciphertext).await else { return };
+        let Some(identity) = self.dm_identity().await else { return };
+
+        let _lock = self.dm_lock.lock().await;
+
+        // Another client might have decrypted it while we were waiting
+        let event_id = event.id();
+        if let Some(cached) = self.dm_cached(&event_id).await {
+            *privmsg = cached;
+            return
+        }
+
+        for (name, contact) in self.contacts.read().await.iter() {
+            let plaintext = match self.dm_decrypt(&identity, &contact.public, &message).await {
+                Ok(Some(plaintext)) => plaintext,
+                Ok(None) => continue,
+                Err(e) => {
+                    error!("Failed decrypting message from {} in a DM session: {}", name, e);
+                    return
+                }
+            };
+
+            privmsg.channel = name.to_string();
+            privmsg.nick = name.to_string();
+            privmsg.msg = String::from_utf8_lossy(&plaintext).into();
+            self.dm_cache_insert(&event_id, privmsg).await;
+            debug!("Successfully decrypted message from {} in a DM session", name);
+            return
+        }
+    }
+
+    /// Our DM secret key as an X25519 key, identifying us in DM sessions
+    async fn dm_identity(&self) -> Option<StaticSecret> {
+        self.dm_secret.read().await.as_ref().map(|secret| StaticSecret::from(secret.to_bytes()))
+    }
+
+    /// Load our DM sessions with a contact, the active one first
+    async fn dm_sessions(&self, contact: &[u8; 32]) -> Result<Vec<DmSession>> {
+        match self.dm_sessions.get(contact)? {
+            Some(bytes) => Ok(deserialize_async(&bytes).await?),
+            None => Ok(vec![]),
+        }
+    }
+
+    /// Encrypt a DM in our active session with a contact, starting one
+    /// from their latest prekey bundle if needed. Returns `None` if we
+    /// have no usable bundle of theirs.
+    async fn dm_encrypt(
+        &self,
+        contact: &crypto_box::PublicKey,
+        plaintext: &[u8],
+    ) -> Result<Option<DirectMessage>> {
+        let Some(identity) = self.dm_identity().await else { return Ok(None) };
+        let contact = contact.as_bytes();
+
+        let _lock = self.dm_lock.lock().await;
+        let mut sessions = self.dm_sessions(contact).await?;
+
+        if !sessions.first().is_some_and(|session| session.ratchet.can_send()) {
+            let Some(bytes) = self.bundles.get(contact)? else { return Ok(None) };
+            let bundle: PrekeyBundle = deserialize_async(&bytes).await?;
+
+            // Leave a day for our message to arrive before the
+            // contact deletes the prekeys.
+            let now = UNIX_EPOCH.elapsed().unwrap().as_millis() as u64;
+            if bundle.timestamp + PREKEY_LIFETIME - RATCHET_PERIOD < now {
+                return Ok(None)
+            }
+
+            let Some(session) = DmSession::initiate(&identity, &bundle) else { return Ok(None) };
+            sessions.insert(0, session);
+            sessions.truncate(MAX_SESSIONS);
+        }
+
+        let message = sessions[0].encrypt(plaintext);
+        self.dm_sessions.insert(contact, serialize_async(&sessions).await)?;
+        Ok(message)
+    }
+
+    /// Try decrypting a DM from the given contact with our sessions, or
+    /// in a new session if it carries an initial message for our prekeys.
+    /// The session it was decrypted with becomes the active one.
+    async fn dm_decrypt(
+        &self,
+        identity: &StaticSecret,
+        contact: &crypto_box::PublicKey,
+        message: &DirectMessage,
+    ) -> Result<Option<Vec<u8>>> {
+        let contact = contact.as_bytes();
+        let mut sessions = self.dm_sessions(contact).await?;
+
+        let mut decrypted = None;
+        for (i, session) in sessions.iter_mut().enumerate() {
+            if let Some(plaintext) = session.decrypt(message) {
+                decrypted = Some((i, plaintext));
+                break
+            }
+        }
+
+        // A session started by the contact. Replayed initial messages
+        // are ignored, or they would reset the session.
+        if let Some(init) = message.init.as_ref() {
+            if decrypted.is_none() &&
+                &init.identity == contact &&
+                !sessions.iter().any(|session| session.origin == init.id())
+            {
+                if let Some(bytes) = self.prekeys.get(init.signed_prekey)? {
+                    let prekeys: Prekeys = deserialize_async(&bytes).await?;
+                    if let Some(mut session) = DmSession::respond(identity, &prekeys, init) {
+                        if let Some(plaintext) = session.decrypt(message) {
+                            sessions.push(session);
+                            decrypted = Some((sessions.len() - 1, plaintext));
+                        }
+                    }
+                }
+            }
+        }
+
+        let Some((i, plaintext)) = decrypted else { return Ok(None) };
+
+        let session = sessions.remove(i);
+        sessions.insert(0, session);
+        sessions.truncate(MAX_SESSIONS);
+        self.dm_sessions.insert(contact, serialize_async(&sessions).await)?;
+
+        Ok(Some(plaintext))
+    }
+
+    /// The box the cached DMs are encrypted with at rest. Its key is
+    /// derived from our DM secret key, which isn't stored in the database.
+    async fn dm_cache_box(&self) -> Option<ChaChaBox> {
+        let dm_secret = self.dm_secret.read().await;
+        let key = blake3::derive_key(DM_CACHE_CONTEXT, &dm_secret.as_ref()?.to_bytes());
+        let secret = crypto_box::SecretKey::from(key);
+        Some(ChaChaBox::new(&secret.public_key(), &secret))
+    }
+
+    /// Fetch the cached plaintext of a DM
+    async fn dm_cached(&self, event_id: &blake3::Hash) -> Option<Privmsg> {
+        let bytes = match self.dm_cache.get(event_id.as_bytes()) {
+            Ok(Some(bytes)) => bytes,
+            Ok(None) => return None,
+            Err(e) => {
+                error!("Failed reading the DM cache: {}", e);
+                return None
+            }
+        };
+
+        let ciphertext = bs58::decode(&bytes).into_vec().ok()?;
+        let plaintext = saltbox::try_decrypt(&self.dm_cache_box().await?, &ciphertext)?;
+        deserialize_async(&plaintext).await.ok()
+    }
+
+    /// Cache the plaintext of a DM. Our own DMs are cached when sent,
+    /// since we can't decrypt the messages of our sending chains.
+    pub async fn dm_cache_insert(&self, event_id: &blake3::Hash, privmsg: &Privmsg) {
+        let Some(cache_box) = self.dm_cache_box().await else { return };
+        let ciphertext = saltbox::encrypt(&cache_box, &serialize_async(privmsg).await);
+        if let Err(e) = self.dm_cache.insert(event_id.as_bytes(), ciphertext.as_bytes()) {
+            error!("Failed writing the DM cache: {}", e);
+        }
+    }
+
+    /// Remove the cached DMs whose events were pruned from the DAG
+    async fn dm_prune_cache(&self) {
+        for item in self.dm_cache.iter() {
+            let Ok((key, _)) = item else { continue };
+            let Ok(event_id) = <[u8; 32]>::try_from(key.as_ref()) else { continue };
+            let event_id = blake3::Hash::from_bytes(event_id);
+            if let Ok(None) = self.darkirc.event_graph.dag_get(&event_id).await {
+                if let Err(e) = self.dm_cache.remove(key) {
+                    error!("Failed pruning the DM cache: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Handle a prekey bundle published by one of our contacts, keeping
+    /// the latest one to start DM sessions with. Returns `true` if the
+    /// event was a prekey bundle, in which case it should not be shown
+    /// to clients.
+    pub async fn handle_prekey_bundle(&self, event: &Event) -> bool {
+        let privmsg = match Msg::deserialize(event.content()).await {
+            Ok(Msg::V1(old_msg)) => old_msg.into_new(),
+            Ok(Msg::V2(new_msg)) => new_msg,
+            Err(_) => return false,
+        };
+
+        if !privmsg.nick.is_empty() {
+            return false
+        }
+        let Ok(tag) = bs58::decode(&privmsg.channel).into_vec() else { return false };
+
+        let Some((name, identity, member)) = self.bundle_owner(&tag).await else {
+            // Our own bundles aren't shown either
+            return self.dm_secret.read().await.as_ref().is_some_and(|secret| {
+                pqxdh::bundle_tag(secret.public_key().as_bytes())[..] == tag[..]
+            })
+        };
+
+        let Ok(bundle_bytes) = bs58::decode(&privmsg.msg).into_vec() else { return true };
+        let Ok(bundle) = deserialize_async::<PrekeyBundle>(&bundle_bytes).await else {
+            warn!("Invalid prekey bundle from contact {}", name);
+            return true
+        };
+
+        if bundle.identity != identity || !bundle.verify() {
+            warn!("Prekey bundle from {} has an invalid signature", name);
+            return true
+        }
+
+        let lock = self.dm_lock.lock().await;
+        match self.bundles.get(bundle.identity) {
+            Ok(Some(bytes)) => {
+                if let Ok(stored) = deserialize_async::<PrekeyBundle>(&bytes).await {
+                    if stored.timestamp >= bundle.timestamp {
+                        return true
+                    }
+                }
+            }
+            Ok(None) => {}
+            Err(e) => {
+                error!("Failed reading the prekey bundle of {}: {}", name, e);
+                return true
+            }
+        }
+
+        if let Err(e) = self.bundles.insert(bundle.identity, bundle_bytes) {
+            error!("Failed storing the prekey bundle of {}: {}", name, e);
+            return true
+        }
+
+        drop(lock);
+        info!("Got new DM prekeys from {}", name);
+
+        // Members of our groups get the epoch secret sealed to their
+        // new prekeys.
+        if member {
+            self.group_rekey().await;
+        }
+
+        true
+    }
+
+    /// Find whose prekey bundles carry the given tag, among our contacts
+    /// and the members of the groups we own. Returns their name, their
+    /// DM public key and whether they are a group member.
+    async fn bundle_owner(&self, tag: &[u8]) -> Option<(String, [u8; 32], bool)> {
+        let matches =
+            |public: &crypto_box::PublicKey| pqxdh::bundle_tag(public.as_bytes())[..] == tag[..];
+
+        for (name, contact) in self.contacts.read().await.iter() {
+            if matches(&contact.public) {
+                return Some((format!("contact {name}"), *contact.public.as_bytes(), false))
+            }
+        }
+
+        for (name, channel) in self.channels.read().await.iter() {
+            let Some(group) = &channel.group else { continue };
+            if let Some(member) = group.members.iter().find(|member| matches(member)) {
+                return Some((format!("a member of {name}"), *member.as_bytes(), true))
+            }
+        }
+
+        None
+    }
+
+    /// Publish new DM prekeys if we haven't in the current period yet,
+    /// and delete the ones past their lifetime.
+    pub async fn dm_publish_prekeys(&self) {
+        // Prekeys are only published once we're synced, so the
+        // bundle references the latest state of the DAG.
+        if !*self.darkirc.event_graph.synced.read().await {
+            return
+        }
+
+        let Some(identity) = self.dm_identity().await else { return };
+        if let Err(e) = self.dm_rotate_prekeys(&identity).await {
+            error!("Failed publishing DM prekeys: {}", e);
+        }
+    }
+
+    async fn dm_rotate_prekeys(&self, identity: &StaticSecret) -> Result<()> {
+        let now = UNIX_EPOCH.elapsed().unwrap().as_millis() as u64;
+        let public = x25519_dalek::PublicKey::from(identity).to_bytes();
+
+        let _lock = self.dm_lock.lock().await;
+        let mut latest = 0;
+        for item in self.prekeys.iter() {
+            let (key, bytes) = item?;
+            let prekeys: Prekeys = deserialize_async(&bytes).await?;
+
+            // Our DM key might have changed in the meantime
+            if prekeys.bundle.identity != public || prekeys.bundle.timestamp + PREKEY_LIFETIME < now
+            {
+                self.prekeys.remove(key)?;
+                continue
+            }
+
+            latest = latest.max(prekeys.bundle.timestamp);
+        }
+
+        if latest > 0 && group::period(latest) == group::period(now) {
+            return Ok(())
+        }
+
+        let prekeys = Prekeys::generate(identity, now);
+
+        // Bundles look like encrypted messages, but carry a tag
+        // derived from our DM public key in place of the channel.
+        let privmsg = OldPrivmsg {
+            channel: bs58::encode(pqxdh::bundle_tag(&public)).into_string(),
+            nick: String::new(),
+            msg: bs58::encode(serialize_async(&prekeys.bundle).await).into_string(),
+        };
+
+        let event_graph = &self.darkirc.event_graph;
+        let mut event = Event::new(serialize_async(&privmsg).await, event_graph).await;
+        if let (Some(rln), Some(rln_secret)) = (&self.darkirc.rln, &self.darkirc.rln_secret) {
+            rln.signal(&mut event, *rln_secret).await?;
+        }
+
+        self.prekeys.insert(prekeys.bundle.signed_prekey, serialize_async(&prekeys).await)?;
+        event_graph.dag_insert(&[event.clone()]).await?;
+        event_graph.event_broadcast(&event, &[]).await;
+
+        info!("Published new DM prekeys");
+        Ok(())
+    }
+
+    /// Load the keys we hold for a group, ratcheted up to the current
+    /// period. Keys of past periods are overwritten in the database.
+    async fn group_keys(&self, group_id: &[u8; 32]) -> Result<GroupKeys> {
+        let _lock = self.groups_lock.lock().await;
+        let mut keys = match self.groups.get(group_id)? {
+            Some(bytes) => deserialize_async(&bytes).await?,
+            None => GroupKeys::default(),
+        };
+
+        if keys.advance(group::period(UNIX_EPOCH.elapsed().unwrap().as_millis() as u64)) {
+            self.groups.insert(group_id, serialize_async(&keys).await)?;
+        }
+
+        Ok(keys)
+    }
+
+    /// Check if we can send messages to the given channel. Forward-secret
+    /// channels can't be sent to before we've received a key for them.
+    pub async fn group_can_send(&self, channel: &str) -> bool {
+        let Some(group) = self.channels.read().await.get(channel).and_then(|c| c.group.clone())
+        else {
+            return true
+        };
+
+        matches!(self.group_keys(&group.id).await, Ok(keys) if keys.current().is_some())
+    }
+
+    /// Ratchet the keys of all our forward-secret groups to the current period
+    async fn group_ratchet(&self) {
+        let channels = self.channels.read().await.clone();
+        for (name, channel) in channels.iter() {
+            let Some(group) = &channel.group else { continue };
+            if let Err(e) = self.group_keys(&group.id).await {
+                error!("Failed ratcheting the group keys of {}: {}", name, e);
+            }
+        }
+    }
+
+    /// Handle a group control message published by the owner of one of our
+    /// forward-secret channels, opening the new epoch key if it was sealed
+    /// to us. Returns `true` if the event was a control message, in which
+    /// case it should not be shown to clients.
+    pub async fn handle_group_control(&self, event: &Event) -> bool {
+        let Some(author) = event.author() else { return false };
+
+        let privmsg = match Msg::deserialize(event.content()).await {
+            Ok(Msg::V1(old_msg)) => old_msg.into_new(),
+            Ok(Msg::V2(new_msg)) => new_msg,
+            Err(_) => return false,
+        };
+
+        let channels = self.channels.read().await;
+        let Some((name, group)) = channels.iter().find_map(|(name, channel)| {
+            let group = channel.group.as_ref()?;
+            (&group.owner == author && bs58::encode(group.id).into_string() == privmsg.channel)
+                .then_some((name, group))
+        }) else {
+            return false
+        };
+
+        let Ok(control_bytes) = bs58::decode(&privmsg.msg).into_vec() else { return true };
+        let Ok(control) = deserialize_async::<GroupControl>(&control_bytes).await else {
+            warn!("Invalid group control message for channel {}", name);
+            return true
+        };
+
+        if control.group != group.id {
+            warn!("Group control message for channel {} has a wrong group ID", name);
+            return true
+        }
+
+        let secret = match self.group_open(&control).await {
+            Ok(secret) => secret,
+            Err(e) => {
+                error!("Failed reading our prekeys: {}", e);
+                return true
+            }
+        };
+
+        let _lock = self.groups_lock.lock().await;
+        let mut keys = match self.groups.get(group.id) {
+            Ok(Some(bytes)) => match deserialize_async::<GroupKeys>(&bytes).await {
+                Ok(keys) => keys,
+                Err(e) => {
+                    error!("Failed reading the group keys of {}: {}", name, e);
+                    return true
+                }
+            },
+            Ok(None) => GroupKeys::default(),
+            Err(e) => {
+                error!("Failed reading the group keys of {}: {}", name, e);
+                return true
+            }
+        };
+
+        if !keys.start_epoch(control.epoch, secret, group::period(event.timestamp)) {
+            return true
+        }
+        keys.advance(group::period(UNIX_EPOCH.elapsed().unwrap().as_millis() as u64));
+
+        if let Err(e) = self.groups.insert(group.id, serialize_async(&keys).await) {
+            error!("Failed storing the group keys of {}: {}", name, e);
+            return true
+        }
+
+        match keys.current() {
+            Some(_) => info!("Started epoch {} of channel {}", control.epoch, name),
+            None => warn!("We are not a member of channel {} anymore", name),
+        }
+
+        true
+    }
+
+    /// Open the epoch secret of a control message with one of our
+    /// prekeys, if it was sealed to us. Prekeys past their lifetime are
+    /// deleted, so old control messages can't be opened anymore.
+    async fn group_open(&self, control: &GroupControl) -> Result<Option<[u8; 32]>> {
+        for item in self.prekeys.iter() {
+            let (_, bytes) = item?;
+            let prekeys: Prekeys = deserialize_async(&bytes).await?;
+            let prekey = crypto_box::SecretKey::from(prekeys.signed_prekey);
+            if let Some(secret) = control.sealed.iter().find_map(|s| group::open(s, &prekey)) {
+                return Ok(Some(secret))
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// The signed prekey of a member's latest bundle, if the member
+    /// keeps it around long enough for our control message to arrive
+    async fn group_member_prekey(
+        &self,
+        member: &crypto_box::PublicKey,
+        now: u64,
+    ) -> Result<Option<crypto_box::PublicKey>> {
+        let Some(bytes) = self.bundles.get(member.as_bytes())? else { return Ok(None) };
+        let bundle: PrekeyBundle = deserialize_async(&bytes).await?;
+        if bundle.timestamp + PREKEY_LIFETIME - RATCHET_PERIOD < now {
+            return Ok(None)
+        }
+
+        Ok(Some(crypto_box::PublicKey::from(bundle.signed_prekey)))
+    }
+
+    /// Start a new epoch in every forward-secret group we own whose
+    /// members or their prekeys changed since the last one, or whose
+    /// control message was pruned from the DAG, sealing a fresh secret
+    /// to the prekeys of each member in a signed control message.
+    pub async fn group_rekey(&self) {
+        // Control messages are only sent once we're synced, so they
+        // reference the latest state of the DAG.
+        if !*self.darkirc.event_graph.synced.read().await {
+            return
+        }
+
+        let channels = self.channels.read().await.clone();
+        for (name, channel) in channels.iter() {
+            let Some(group) = &channel.group else { continue };
+            if group.owner_keypair.is_none() {
+                continue
+            }
+
+            if let Err(e) = self.group_rekey_channel(name, channel, group).await {
+                error!("Failed starting a new epoch for channel {}: {}", name, e);
+            }
+        }
+    }
+
+    /// Start a new epoch in the given group we own, if needed
+    async fn group_rekey_channel(
+        &self,
+        name: &str,
+        channel: &IrcChannel,
+        group: &ChannelGroup,
+    ) -> Result<()> {
+        let keypair = group.owner_keypair.as_ref().unwrap();
+        let now = UNIX_EPOCH.elapsed().unwrap().as_millis() as u64;
+
+        // Secrets are sealed to the members' prekeys, which they delete
+        // after a while, so the control messages can't be opened with
+        // their long-term keys later. Members we have no usable prekeys
+        // of are left out until they publish some.
+        let mut prekeys = vec![];
+        for member in group.members.iter() {
+            match self.group_member_prekey(member, now).await? {
+                Some(prekey) => prekeys.push(prekey),
+                None => warn!("No prekeys of a member of channel {} yet, leaving it out", name),
+            }
+        }
+        prekeys.sort_by_key(|prekey| *prekey.as_bytes());
+
+        let mut hasher = blake3::Hasher::new();
+        for prekey in prekeys.iter() {
+            hasher.update(prekey.as_bytes());
+        }
+        let members_digest: [u8; 32] = hasher.finalize().into();
+
+        let _lock = self.groups_lock.lock().await;
+        let mut keys = match self.groups.get(group.id)? {
+            Some(bytes) => deserialize_async(&bytes).await?,
+            None => GroupKeys::default(),
+        };
+
+        let event_graph = &self.darkirc.event_graph;
+        let control_id = blake3::Hash::from_bytes(keys.control);
+        if keys.members == members_digest &&
+            keys.current().is_some() &&
+            event_graph.dag_get(&control_id).await?.is_some()
+        {
+            return Ok(())
+        }
+
+        // Epochs are numbered by their creation time, so we never reuse
+        // an epoch number even if we lost our database.
+        let epoch = now.max(keys.epoch + 1);
+        let mut secret = [0u8; 32];
+        OsRng.fill_bytes(&mut secret);
+
+        let control = GroupControl {
+            group: group.id,
+            epoch,
+            sealed: prekeys.iter().map(|prekey| group::seal(prekey, &secret)).collect(),
+        };
+
+        // Control messages look like encrypted messages, but carry
+        // the group ID in place of the channel.
+        let privmsg = OldPrivmsg {
+            channel: bs58::encode(group.id).into_string(),
+            nick: String::new(),
+            msg: bs58::encode(serialize_async(&control).await).into_string(),
+        };
+
+        let mut event =
+            Event::with_topic(channel.dag_topic, serialize_async(&privmsg).await, event_graph)
+                .await;
+        event.sign(keypair);
+
+        // The RLN signal may move the event timestamp, so it is signed again
+        if let (Some(rln), Some(rln_secret)) = (&self.darkirc.rln, &self.darkirc.rln_secret) {
+            rln.signal(&mut event, *rln_secret).await?;
+            event.sign(keypair);
+        }
+
+        keys.start_epoch(epoch, Some(secret), group::period(event.timestamp));
+        keys.advance(group::period(now));
+        keys.members = members_digest;
+        keys.control = *event.id().as_bytes();
+
+        event_graph.dag_insert(&[event.clone()]).await?;
+        self.groups.insert(group.id, serialize_async(&keys).await)?;
+        event_graph.event_broadcast(&event, &[]).await;
+
+        info!("Started epoch {} of channel {} with {} members", epoch, name, prekeys.len());
+        Ok(())
+    }
+
+    /// Handle group control messages and prekey bundles. Returns `true`
+    /// if the event was one of them, in which case it should not be shown
+    /// to clients.
+    pub async fn handle_control(&self, event: &Event) -> bool {
+        self.handle_group_control(event).await || self.handle_prekey_bundle(event).await
+    }
+
+    /// Background task keeping the keys of our forward-secret groups and
+    /// DM sessions up to date. It handles incoming control messages and
+    /// prekey bundles, ratchets the group keys and publishes new prekeys
+    /// at the start of every period, so old keys don't linger around.
+    /// The groups we own are given a new epoch once the DAG is pruned,
+    /// and whenever the prekeys of their members expired.
+    pub async fn key_task(self: Arc<Self>) -> Result<()> {
+        let incoming = self.darkirc.event_graph.event_pub.clone().subscribe().await;
+        let pruned = self.darkirc.event_graph.prune_pub.clone().subscribe().await;
+
+        // Catch up with control messages already in the DAG
+        for event in self.darkirc.event_graph.order_events().await.iter() {
+            self.handle_control(event).await;
+        }
+        self.group_ratchet().await;
+        self.dm_prune_cache().await;
+
+        loop {
+            let now = UNIX_EPOCH.elapsed().unwrap().as_millis() as u64;
+            let until_next_period = RATCHET_PERIOD - now % RATCHET_PERIOD;
+
+            futures::select! {
+                event = incoming.receive().fuse() => {
+                    self.handle_control(&event).await;
+                }
+
+                _ = pruned.receive().fuse() => {
+                    self.group_rekey().await;
+                }
+
+                _ = msleep(until_next_period).fuse() => {
+                    self.group_ratchet().await;
+                    self.dm_publish_prekeys().await;
+                    self.dm_prune_cache().await;
+                    self.group_rekey().await;
+                }
+            }
+        }
     }
 }
@@ -20,7 +20,11 @@ use std::{collections::HashSet, io::Write, path::PathBuf, sync::Arc};
 
 use darkfi::{
     async_daemonize, cli_desc,
-    event_graph::{proto::ProtocolEventGraph, EventGraph, EventGraphPtr},
+    event_graph::{
+        proto::ProtocolEventGraph,
+        rln::{ProtocolRln, Rln, RlnPtr},
+        EventGraph, EventGraphPtr, EventValidatorPtr, ValidatorChain,
+    },
     net::{session::SESSION_DEFAULT, settings::SettingsOpt, P2p, P2pPtr},
     rpc::{
         jsonrpc::JsonSubscriber,
@@ -31,9 +35,13 @@ use darkfi::{
     Error, Result,
 };
 
-use log::{debug, error, info};
+use darkfi_sdk::{
+    crypto::{pasta_prelude::*, Keypair},
+    pasta::pallas,
+};
+use log::{debug, error, info, warn};
 use rand::rngs::OsRng;
-use settings::list_configured_contacts;
+use settings::{list_configured_contacts, parse_rln_base};
 use sled_overlay::sled;
 use smol::{fs, lock::Mutex, stream::StreamExt, Executor};
 use structopt_toml::{serde::Deserialize, structopt::StructOpt, StructOptToml};
@@ -42,17 +50,18 @@ use url::Url;
 const CONFIG_FILE: &str = "darkirc_config.toml";
 const CONFIG_FILE_CONTENTS: &str = include_str!("../darkirc_config.toml");
 
+/// DAG rotation period in days. The keys of forward-secret channels
+/// are ratcheted once per rotation.
+const DAYS_ROTATION: u64 = 1;
+
 /// IRC server and client handler implementation
 mod irc;
-use irc::server::IrcServer;
+use irc::{server::IrcServer, MsgValidator};
 
 /// Cryptography utilities
 mod crypto;
 use crypto::bcrypt::bcrypt_hash_password;
 
-// RLN
-//mod rln;
-
 /// JSON-RPC methods
 mod rpc;
 
@@ -109,6 +118,10 @@ struct Args {
     #[structopt(long)]
     gen_channel_secret: bool,
 
+    /// Generate a new forward-secret channel owner keypair and exit
+    #[structopt(long)]
+    gen_channel_owner: bool,
+
     /// Recover NaCl public key from a secret key
     #[structopt(long = "get-chacha-pubkey")]
     chacha_secret: Option<String>,
@@ -117,6 +130,10 @@ struct Args {
     #[structopt(long)]
     skip_dag_sync: bool,
 
+    /// Archive pruned DAG events and serve them to peers
+    #[structopt(long)]
+    archive: bool,
+
     /// IRC Password (Encrypted with bcrypt-2b)
     #[structopt(long)]
     pub password: Option<String>,
@@ -129,6 +146,23 @@ struct Args {
     #[structopt(long)]
     list_contacts: bool,
 
+    /// Generate a new RLN identity and exit
+    #[structopt(long)]
+    gen_rln_identity: bool,
+
+    /// RLN identity commitments of the network members.
+    /// RLN spam protection is enabled if this is not empty.
+    #[structopt(long)]
+    rln_members: Vec<String>,
+
+    /// RLN identity secret used to signal our messages
+    #[structopt(long)]
+    rln_secret: Option<String>,
+
+    #[structopt(long, default_value = "1")]
+    /// RLN epoch length in seconds, one message per epoch is allowed
+    rln_epoch: u64,
+
     /// P2P network settings
     #[structopt(flatten)]
     net: SettingsOpt,
@@ -149,6 +183,10 @@ pub struct DarkIrc {
     deg_sub: JsonSubscriber,
     /// Replay logs (DB) path
     replay_datastore: PathBuf,
+    /// RLN spam protection, if enabled
+    rln: Option<RlnPtr>,
+    /// RLN identity secret used to signal our messages
+    rln_secret: Option<pallas::Base>,
 }
 
 impl DarkIrc {
@@ -159,6 +197,8 @@ impl DarkIrc {
         dnet_sub: JsonSubscriber,
         deg_sub: JsonSubscriber,
         replay_datastore: PathBuf,
+        rln: Option<RlnPtr>,
+        rln_secret: Option<pallas::Base>,
     ) -> Self {
         Self {
             p2p,
@@ -168,6 +208,8 @@ impl DarkIrc {
             dnet_sub,
             deg_sub,
             replay_datastore,
+            rln,
+            rln_secret,
         }
     }
 }
@@ -195,6 +237,29 @@ async fn realmain(args: Args, ex: Arc<Executor<'static>>) -> Result<()> {
         return Ok(())
     }
 
+    if args.gen_channel_owner {
+        let keypair = Keypair::random(&mut OsRng);
+        println!("Place this in your config file:\n");
+        println!("[channel.\"#yourchannelname\"]");
+        println!("owner = \"{}\"", keypair.public);
+        println!("owner_secret = \"{}\"", keypair.secret);
+        println!("members = []");
+        println!("\nAnd ask the members to add this to theirs:\n");
+        println!("[channel.\"#yourchannelname\"]");
+        println!("owner = \"{}\"", keypair.public);
+        return Ok(())
+    }
+
+    if args.gen_rln_identity {
+        let secret = pallas::Base::random(&mut OsRng);
+        let commitment = Rln::identity_commitment(secret);
+        println!("Place this in your config file:\n");
+        println!("rln_secret = \"{}\"", bs58::encode(secret.to_repr()).into_string());
+        println!("\nAnd ask the network to add this to their rln_members:\n");
+        println!("{}", bs58::encode(commitment.to_repr()).into_string());
+        return Ok(())
+    }
+
     if let Some(chacha_secret) = args.chacha_secret {
         let bytes = match bs58::decode(chacha_secret).into_vec() {
             Ok(v) => v,
@@ -268,13 +333,39 @@ async fn realmain(args: Args, ex: Arc<Executor<'static>>) -> Result<()> {
     let mut p2p_settings: darkfi::net::Settings = args.net.into();
     p2p_settings.app_version = semver::Version::parse(env!("CARGO_PKG_VERSION")).unwrap();
     let p2p = P2p::new(p2p_settings, ex.clone()).await?;
+
+    let rln_secret = match args.rln_secret {
+        Some(secret) => Some(parse_rln_base(&secret)?),
+        None => None,
+    };
+    let rln = if args.rln_members.is_empty() {
+        None
+    } else {
+        info!("Initializing RLN spam protection");
+        let rln = Rln::new(&sled_db, args.rln_epoch * 1000, p2p.clone()).await?;
+        for member in &args.rln_members {
+            rln.register(parse_rln_base(member)?).await;
+        }
+        if rln_secret.is_none() {
+            warn!("No rln_secret configured, you won't be able to send messages");
+        }
+        Some(rln)
+    };
+
+    let validator: EventValidatorPtr = match &rln {
+        Some(rln) => Arc::new(ValidatorChain(vec![Arc::new(MsgValidator), rln.clone()])),
+        None => Arc::new(MsgValidator),
+    };
+
     let event_graph = EventGraph::new(
         p2p.clone(),
         sled_db.clone(),
         replay_datastore.clone(),
         replay_mode,
         "darkirc_dag",
-        1,
+        DAYS_ROTATION,
+        args.archive,
+        Some(validator),
         ex.clone(),
     )
     .await?;
@@ -291,6 +382,17 @@ async fn realmain(args: Args, ex: Arc<Executor<'static>>) -> Result<()> {
         })
         .await;
 
+    if let Some(rln) = &rln {
+        info!("Registering RLN P2P protocol");
+        let rln_ = rln.clone();
+        registry
+            .register(SESSION_DEFAULT, move |channel, _| {
+                let rln_ = rln_.clone();
+                async move { ProtocolRln::init(rln_, channel).await.unwrap() }
+            })
+            .await;
+    }
+
     info!("Starting dnet subs task");
     let dnet_sub = JsonSubscriber::new("dnet.subscribe_events");
     let dnet_sub_ = dnet_sub.clone();
@@ -347,6 +449,8 @@ async fn realmain(args: Args, ex: Arc<Executor<'static>>) -> Result<()> {
         dnet_sub,
         deg_sub,
         replay_datastore.clone(),
+        rln,
+        rln_secret,
     ));
     let darkirc_ = Arc::clone(&darkirc);
     let rpc_task = StoppableTask::new();
@@ -389,6 +493,20 @@ async fn realmain(args: Args, ex: Arc<Executor<'static>>) -> Result<()> {
         ex.clone(),
     );
 
+    info!("Starting channel and DM keys task");
+    let key_task = StoppableTask::new();
+    key_task.clone().start(
+        irc_server.clone().key_task(),
+        |res| async move {
+            match res {
+                Ok(()) | Err(Error::DetachedTaskStopped) => { /* Do nothing */ }
+                Err(e) => error!("Failed stopping keys task: {}", e),
+            }
+        },
+        Error::DetachedTaskStopped,
+        ex.clone(),
+    );
+
     info!("Starting P2P network");
     p2p.clone().start().await?;
 
@@ -419,6 +537,11 @@ async fn realmain(args: Args, ex: Arc<Executor<'static>>) -> Result<()> {
         }
     }
 
+    // Now that we're synced, hand out keys for the channels we own
+    // and publish our DM prekeys.
+    irc_server.group_rekey().await;
+    irc_server.dm_publish_prekeys().await;
+
     // Signal handling for graceful termination.
     let (signals_handler, signals_task) = SignalHandler::new(ex)?;
     signals_handler.wait_termination(signals_task).await?;
@@ -434,6 +557,7 @@ async fn realmain(args: Args, ex: Arc<Executor<'static>>) -> Result<()> {
 
     info!("Stopping IRC server");
     irc_task.stop().await;
+    key_task.stop().await;
     prune_task.stop().await;
 
     info!("Flushing sled database...");
@@ -49,6 +49,7 @@ impl RequestHandler<()> for DarkIrc {
             "deg.switch" => self.deg_switch(req.id, req.params).await,
             "deg.subscribe_events" => self.deg_subscribe_events(req.id, req.params).await,
             "eventgraph.get_info" => self.eg_get_info(req.id, req.params).await,
+            "eventgraph.query" => self.eg_query(req.id, req.params).await,
             "eventgraph.replay" => self.eg_rep_info(req.id, req.params).await,
 
             _ => JsonError::new(ErrorCode::MethodNotFound, None, req.id).into(),
@@ -155,6 +156,17 @@ impl DarkIrc {
         self.event_graph.eventgraph_info(id, params).await
     }
 
+    // RPCAPI:
+    // Query EVENTGRAPH events by time range, or page through them
+    // forwards or backwards from a given event.
+    //
+    // --> {"jsonrpc": "2.0", "method": "eventgraph.query", "params": ["between", 1700000000000, 1700003600000], "id": 42}
+    // --> {"jsonrpc": "2.0", "method": "eventgraph.query", "params": ["before", null, 50], "id": 42}
+    // <-- {"jsonrpc": "2.0", "result": [{"id": "...", "timestamp": ..., ...}, ...], "id": 42}
+    async fn eg_query(&self, id: u16, params: JsonValue) -> JsonResult {
+        self.event_graph.eventgraph_query(id, params).await
+    }
+
     // RPCAPI:
     // Get replayed EVENTGRAPH info.
     //
@@ -23,9 +23,47 @@ use std::{
 
 use crypto_box::PublicKey;
 use darkfi::{Error::ParseFailed, Result};
+use darkfi_sdk::{
+    crypto::{pasta_prelude::PrimeField, Keypair, SecretKey},
+    pasta::pallas,
+};
 use log::info;
 
-use crate::irc::{IrcChannel, IrcContact};
+use crate::{
+    crypto::group::group_id,
+    irc::{channel_dag_topic, ChannelGroup, IrcChannel, IrcContact},
+};
+
+/// Parse a base58-encoded RLN secret or identity commitment
+pub fn parse_rln_base(data: &str) -> Result<pallas::Base> {
+    let Ok(bytes) = bs58::decode(data).into_vec() else {
+        return Err(ParseFailed("RLN value is not valid base58"))
+    };
+
+    let Ok(bytes) = bytes.try_into() else {
+        return Err(ParseFailed("Decoded RLN value is not 32 bytes long"))
+    };
+
+    match pallas::Base::from_repr(bytes).into() {
+        Some(v) => Ok(v),
+        None => Err(ParseFailed("RLN value is not a valid field element")),
+    }
+}
+
+/// Parse the selective sync flag from a TOML map. If set, we only
+/// sync and get relayed the messages of the configured channels.
+///
+/// ```toml
+/// selective_sync = true
+/// ```
+pub fn parse_selective_sync(data: &toml::Value) -> Result<bool> {
+    let Some(selective_sync) = data.get("selective_sync") else { return Ok(false) };
+    let Some(selective_sync) = selective_sync.as_bool() else {
+        return Err(ParseFailed("selective_sync not a boolean"))
+    };
+
+    Ok(selective_sync)
+}
 
 /// Parse configured autojoin channels from a TOML map.
 ///
@@ -65,7 +103,7 @@ pub fn parse_autojoin_channels(data: &toml::Value) -> Result<Vec<String>> {
 /// [crypto]
 /// dm_chacha_secret = "7CkVuFgwTUpJn5Sv67Q3fyEDpa28yrSeL5Hg2GqQ4jfM"
 /// ```
-fn parse_dm_chacha_secret(data: &toml::Value) -> Result<Option<crypto_box::SecretKey>> {
+pub fn parse_dm_chacha_secret(data: &toml::Value) -> Result<Option<crypto_box::SecretKey>> {
     let Some(table) = data.as_table() else { return Err(ParseFailed("TOML not a map")) };
     let Some(crypto) = table.get("crypto") else { return Ok(None) };
     let Some(crypto) = crypto.as_table() else { return Err(ParseFailed("`crypto` not a map")) };
@@ -155,12 +193,77 @@ pub fn parse_configured_contacts(data: &toml::Value) -> Result<HashMap<String, I
         }
 
         info!("Instantiated ChaChaBox for contact \"{}\"", name);
-        ret.insert(name.to_string(), IrcContact { saltbox });
+        ret.insert(name.to_string(), IrcContact { saltbox, public });
     }
 
     Ok(ret)
 }
 
+/// Parse the forward-secret group of a channel from its TOML map.
+/// Members only set the owner, while the owner also sets its secret
+/// key and the DM public keys of the members.
+///
+/// ```toml
+/// [channel."#memes"]
+/// owner = "Fgsc8tBRgS5jDrrQuxXVfb7gGWwCeSXCFxrr7pC9Vkb8"
+/// owner_secret = "2F1vG5H1wFFxUvYXyJTL7bMtsKJF4GzCJ4rmxd4h1ovx"
+/// members = ["7CkVuFgwTUpJn5Sv67Q3fyEDpa28yrSeL5Hg2GqQ4jfM"]
+/// ```
+fn parse_channel_group(name: &str, items: &toml::Value) -> Result<Option<ChannelGroup>> {
+    let Some(owner) = items.get("owner") else { return Ok(None) };
+    let Some(owner) = owner.as_str() else { return Err(ParseFailed("Channel owner not a string")) };
+    let Ok(owner) = owner.parse::<darkfi_sdk::crypto::PublicKey>() else {
+        return Err(ParseFailed("Channel owner not a valid public key"))
+    };
+
+    let owner_keypair = match items.get("owner_secret") {
+        Some(secret) => {
+            let Some(secret) = secret.as_str() else {
+                return Err(ParseFailed("Channel owner_secret not a string"))
+            };
+            let Ok(secret) = secret.parse::<SecretKey>() else {
+                return Err(ParseFailed("Channel owner_secret not a valid secret key"))
+            };
+            let keypair = Keypair::new(secret);
+            if keypair.public != owner {
+                return Err(ParseFailed("Channel owner_secret does not match the owner"))
+            }
+            Some(keypair)
+        }
+        None => None,
+    };
+
+    let mut members = vec![];
+    if let Some(items) = items.get("members") {
+        let Some(items) = items.as_array() else {
+            return Err(ParseFailed("Channel members not an array"))
+        };
+
+        for item in items {
+            let Some(public_str) = item.as_str() else {
+                return Err(ParseFailed("Channel member not a string"))
+            };
+
+            let Ok(public_bytes) = bs58::decode(public_str).into_vec() else {
+                return Err(ParseFailed("Invalid base58 for channel member pubkey"))
+            };
+
+            let Ok(public_bytes) = <[u8; 32]>::try_from(public_bytes) else {
+                return Err(ParseFailed("Invalid channel member pubkey (not 32 bytes)"))
+            };
+
+            members.push(PublicKey::from(public_bytes));
+        }
+    }
+
+    if !members.is_empty() && owner_keypair.is_none() {
+        return Err(ParseFailed("Channel members can only be set by the owner"))
+    }
+
+    let id = group_id(name, &owner.to_bytes());
+    Ok(Some(ChannelGroup { id, owner, owner_keypair, members }))
+}
+
 /// Parse a TOML string for any configured channels and return
 /// a map containing said configurations.
 ///
@@ -177,7 +280,13 @@ pub fn parse_configured_channels(data: &toml::Value) -> Result<HashMap<String, I
     let Some(chans) = chans.as_table() else { return Err(ParseFailed("`channel` not a map")) };
 
     for (name, items) in chans {
-        let mut chan = IrcChannel { topic: String::new(), nicks: HashSet::new(), saltbox: None };
+        let mut chan = IrcChannel {
+            topic: String::new(),
+            nicks: HashSet::new(),
+            saltbox: None,
+            group: None,
+            dag_topic: channel_dag_topic(name, None),
+        };
 
         if let Some(topic) = items.get("topic") {
             if let Some(topic) = topic.as_str() {
@@ -199,6 +308,7 @@ pub fn parse_configured_channels(data: &toml::Value) -> Result<HashMap<String, I
                 }
 
                 let secret_bytes: [u8; 32] = secret_bytes.try_into().unwrap();
+                chan.dag_topic = channel_dag_topic(name, Some(&secret_bytes));
                 let secret = crypto_box::SecretKey::from(secret_bytes);
                 let public = secret.public_key();
                 chan.saltbox = Some(Arc::new(crypto_box::ChaChaBox::new(&public, &secret)));
@@ -208,6 +318,16 @@ pub fn parse_configured_channels(data: &toml::Value) -> Result<HashMap<String, I
             }
         }
 
+        if let Some(group) = parse_channel_group(name, items)? {
+            if chan.saltbox.is_some() {
+                return Err(ParseFailed("Channel can't have both a secret and an owner"))
+            }
+
+            chan.dag_topic = channel_dag_topic(name, Some(&group.id));
+            chan.group = Some(Arc::new(group));
+            info!("Configured forward-secret group for channel {}", name);
+        }
+
         info!("Configured channel {}", name);
         ret.insert(name.to_string(), chan);
     }
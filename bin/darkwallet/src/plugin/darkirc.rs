@@ -209,6 +209,8 @@ impl DarkIrc {
             false,
             "darkirc_dag",
             1,
+            false,
+            None,
             ex.clone(),
         )
         .await
@@ -16,17 +16,28 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
+use std::{
+    collections::HashMap,
+    io::{stdout, Write},
+    sync::Arc,
+};
+
 use clap::{Parser, Subcommand};
 use log::info;
-use serde_json::json;
 use simplelog::{ColorChoice, TermLogger, TerminalMode};
+use smol::Executor;
+use tinyjson::JsonValue;
 use url::Url;
 
 use darkfi::{
     cli_desc,
-    rpc::{client::RpcClient, jsonrpc::JsonRequest},
+    rpc::{
+        client::RpcClient,
+        jsonrpc::{ErrorCode, JsonError, JsonRequest, JsonResult},
+    },
+    system::{Publisher, StoppableTask},
     util::cli::{get_log_config, get_log_level},
-    Result,
+    Error, Result,
 };
 
 #[derive(Parser)]
@@ -47,104 +58,224 @@ struct Args {
 
 #[derive(Subcommand)]
 enum Subcmd {
-    /// List fud folder contents
-    List,
-
-    /// Sync fud folder contents and signal network for record changes
-    Sync,
+    /// Put a file onto the fud network
+    Put {
+        /// Path to the file
+        file: String,
+    },
 
-    /// Retrieve provided file name from the fud network
+    /// Retrieve provided file hash from the fud network
     Get {
-        #[clap(short, long)]
-        /// File name
+        /// File hash
+        file_hash: String,
+    },
+
+    /// Privately share a file, returning the capability needed to retrieve it
+    PutPrivate {
+        /// Path to the file
         file: String,
+
+        #[clap(long)]
+        /// Derive the key from the file, so identical shares get deduplicated
+        convergent: bool,
+    },
+
+    /// Retrieve and decrypt a privately shared file from the fud network
+    GetPrivate {
+        /// Share capability
+        capability: String,
+
+        /// Path to write the decrypted file to
+        output: String,
     },
+
+    /// List the active downloads
+    Downloads,
+
+    /// Follow the progress of all downloads
+    Watch,
 }
 
 struct Fu {
     pub rpc_client: RpcClient,
+    pub endpoint: Url,
 }
 
 impl Fu {
-    async fn close_connection(&self) -> Result<()> {
-        self.rpc_client.close().await
+    async fn close_connection(&self) {
+        self.rpc_client.stop().await
     }
 
-    async fn list(&self) -> Result<()> {
-        let req = JsonRequest::new("list", json!([]));
+    async fn put(&self, file: String) -> Result<()> {
+        let req = JsonRequest::new("put", JsonValue::Array(vec![JsonValue::String(file)]));
         let rep = self.rpc_client.request(req).await?;
+        println!("{}", rep.get::<String>().unwrap());
+        Ok(())
+    }
 
-        // Extract response
-        let content = rep[0].as_array().unwrap();
-        let new = rep[1].as_array().unwrap();
-        let deleted = rep[2].as_array().unwrap();
-
-        // Print info
-        info!("----------Content-------------");
-        if content.is_empty() {
-            info!("No file records exists in DHT.");
-        } else {
-            for name in content {
-                info!("\t{}", name.as_str().unwrap());
-            }
-        }
-        info!("------------------------------");
-
-        info!("----------New files-----------");
-        if new.is_empty() {
-            info!("No new files to import.");
-        } else {
-            for name in new {
-                info!("\t{}", name.as_str().unwrap());
-            }
-        }
-        info!("------------------------------");
-
-        info!("----------Removed keys--------");
-        if deleted.is_empty() {
-            info!("No keys were removed.");
-        } else {
-            for key in deleted {
-                info!("\t{}", key.as_str().unwrap());
-            }
+    async fn get(&self, file_hash: String, ex: Arc<Executor<'static>>) -> Result<()> {
+        // Render the progress of our download while we wait for it
+        let progress = ex.spawn(watch(self.endpoint.clone(), Some(file_hash.clone()), ex.clone()));
+
+        let req = JsonRequest::new("get", JsonValue::Array(vec![JsonValue::String(file_hash)]));
+        let rep = self.rpc_client.request(req).await;
+        progress.cancel().await;
+        println!();
+
+        let rep = rep?;
+        let chunks = rep.get::<Vec<JsonValue>>().unwrap();
+        info!("File is stored in {} chunks:", chunks.len());
+        for chunk in chunks {
+            println!("{}", chunk.get::<String>().unwrap());
         }
-        info!("------------------------------");
+        Ok(())
+    }
 
+    async fn put_private(&self, file: String, convergent: bool) -> Result<()> {
+        let params = vec![JsonValue::String(file), JsonValue::Boolean(convergent)];
+        let req = JsonRequest::new("put_private", JsonValue::Array(params));
+        let rep = self.rpc_client.request(req).await?;
+        println!("{}", rep.get::<String>().unwrap());
         Ok(())
     }
 
-    async fn sync(&self) -> Result<()> {
-        let req = JsonRequest::new("sync", json!([]));
-        self.rpc_client.request(req).await?;
-        info!("Daemon synced successfully!");
+    async fn get_private(
+        &self,
+        capability: String,
+        output: String,
+        ex: Arc<Executor<'static>>,
+    ) -> Result<()> {
+        // The file hash is hidden in the capability, so we follow every download
+        let progress = ex.spawn(watch(self.endpoint.clone(), None, ex.clone()));
+
+        let params = vec![JsonValue::String(capability), JsonValue::String(output)];
+        let req = JsonRequest::new("get_private", JsonValue::Array(params));
+        let rep = self.rpc_client.request(req).await;
+        progress.cancel().await;
+
+        info!("File was written to: {}", rep?.get::<String>().unwrap());
         Ok(())
     }
 
-    async fn get(&self, file: String) -> Result<()> {
-        let req = JsonRequest::new("get", json!([file]));
+    async fn downloads(&self) -> Result<()> {
+        let req = JsonRequest::new("downloads", JsonValue::Array(vec![]));
         let rep = self.rpc_client.request(req).await?;
-        let path = rep.as_str().unwrap();
-        info!("File waits you at: {}", path);
+
+        let downloads = rep.get::<Vec<JsonValue>>().unwrap();
+        if downloads.is_empty() {
+            println!("No active downloads.");
+        }
+        for download in downloads {
+            println!("{}", render(download.get::<HashMap<String, JsonValue>>().unwrap()));
+        }
         Ok(())
     }
 }
 
-#[async_std::main]
-async fn main() -> Result<()> {
+/// Render a download progress object as a single line.
+fn render(progress: &HashMap<String, JsonValue>) -> String {
+    let file_hash = progress["file_hash"].get::<String>().unwrap();
+    let event = progress["event"].get::<String>().unwrap();
+    let total = *progress["chunks_total"].get::<f64>().unwrap() as usize;
+    let done = *progress["chunks_done"].get::<f64>().unwrap() as usize;
+    let bytes = *progress["bytes_fetched"].get::<f64>().unwrap();
+
+    const WIDTH: usize = 30;
+    let filled = if total == 0 { 0 } else { done * WIDTH / total };
+    format!(
+        "{}… [{}{}] {}/{} chunks, {:.2} MiB fetched ({})",
+        &file_hash[..16],
+        "#".repeat(filled),
+        "-".repeat(WIDTH - filled),
+        done,
+        total,
+        bytes / (1024.0 * 1024.0),
+        event,
+    )
+}
+
+/// Subscribe to download progress notifications and render them,
+/// optionally only the ones of provided file hash, on a single line.
+async fn watch(endpoint: Url, file_hash: Option<String>, ex: Arc<Executor<'static>>) -> Result<()> {
+    let publisher = Publisher::new();
+    let subscription = publisher.clone().subscribe().await;
+    let _publisher = publisher.clone();
+    let _ex = ex.clone();
+    StoppableTask::new().start(
+        async move {
+            let rpc_client = RpcClient::new(endpoint, _ex).await?;
+            let req = JsonRequest::new("subscribe_downloads", JsonValue::Array(vec![]));
+            rpc_client.subscribe(req, _publisher).await
+        },
+        |res| async move {
+            if let Err(e) = res {
+                eprintln!("JSON-RPC server error: {e:?}");
+                publisher
+                    .notify(JsonResult::Error(JsonError::new(ErrorCode::InternalError, None, 0)))
+                    .await;
+            }
+        },
+        Error::RpcServerStopped,
+        ex,
+    );
+
+    loop {
+        match subscription.receive().await {
+            JsonResult::Notification(n) => {
+                for progress in n.params.get::<Vec<JsonValue>>().unwrap() {
+                    let progress = progress.get::<HashMap<String, JsonValue>>().unwrap();
+                    if let Some(ref file_hash) = file_hash {
+                        if progress["file_hash"].get::<String>().unwrap() != file_hash {
+                            continue
+                        }
+                    }
+
+                    match file_hash {
+                        Some(_) => print!("\r{}", render(progress)),
+                        None => println!("{}", render(progress)),
+                    }
+                    stdout().flush()?;
+                }
+            }
+
+            JsonResult::Error(e) => {
+                return Err(Error::UnexpectedJsonRpc(format!("Got error from JSON-RPC: {e:?}")))
+            }
+
+            x => {
+                return Err(Error::UnexpectedJsonRpc(format!(
+                    "Got unexpected data from JSON-RPC: {x:?}"
+                )))
+            }
+        }
+    }
+}
+
+fn main() -> Result<()> {
     let args = Args::parse();
 
     let log_level = get_log_level(args.verbose);
     let log_config = get_log_config(args.verbose);
     TermLogger::init(log_level, log_config, TerminalMode::Mixed, ColorChoice::Auto)?;
 
-    let rpc_client = RpcClient::new(args.endpoint, None).await?;
-    let fu = Fu { rpc_client };
+    let ex = Arc::new(Executor::new());
+
+    smol::block_on(ex.run(async {
+        let rpc_client = RpcClient::new(args.endpoint.clone(), ex.clone()).await?;
+        let fu = Fu { rpc_client, endpoint: args.endpoint };
 
-    match args.command {
-        Subcmd::List => fu.list().await,
-        Subcmd::Sync => fu.sync().await,
-        Subcmd::Get { file } => fu.get(file).await,
-    }?;
+        let result = match args.command {
+            Subcmd::Put { file } => fu.put(file).await,
+            Subcmd::Get { file_hash } => fu.get(file_hash, ex.clone()).await,
+            Subcmd::PutPrivate { file, convergent } => fu.put_private(file, convergent).await,
+            Subcmd::GetPrivate { capability, output } => {
+                fu.get_private(capability, output, ex.clone()).await
+            }
+            Subcmd::Downloads => fu.downloads().await,
+            Subcmd::Watch => watch(fu.endpoint.clone(), None, ex.clone()).await,
+        };
 
-    fu.close_connection().await
+        fu.close_connection().await;
+        result
+    }))
 }
@@ -0,0 +1,500 @@
+/* This file is part of DarkFi (https://dark.fi)
+ *
+ * Copyright (C) 2020-2024 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Parallel multi-source file downloads.
+//!
+//! Missing chunks of a file are fetched concurrently by a pool of
+//! workers, each picking the best ranked provider of its chunk that
+//! isn't busy with another one. Providers are ranked by the throughput
+//! they served us with, and failed chunks are retried with providers
+//! that weren't tried for them yet.
+//!
+//! Active downloads are recorded in the `downloads` directory so they
+//! get resumed when the daemon restarts. Geode keeps the verified part
+//! of partially fetched chunks, so resumed chunks continue where they
+//! stopped instead of starting over.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use log::{debug, error, info, warn};
+use smol::{fs, lock::Mutex, stream::StreamExt};
+use tinyjson::JsonValue;
+use url::Url;
+
+use darkfi::{
+    geode::ChunkGroup,
+    net::{connector::Connector, protocol::ProtocolVersion, ChannelPtr},
+    system::{Publisher, PublisherPtr},
+    Error, Result,
+};
+
+use super::{
+    proto::{FudChunkGroupRequest, FudChunkPut, FudFilePut, FudFileRequest},
+    Fud,
+};
+
+/// Maximum number of chunks of a file fetched concurrently
+pub const MAX_PARALLEL_CHUNKS: usize = 4;
+
+/// Maximum number of providers a chunk is tried with
+pub const MAX_CHUNK_ATTEMPTS: usize = 5;
+
+/// Time to wait for a reply from a provider (in seconds)
+pub const REPLY_TIMEOUT: u64 = 30;
+
+/// Transfer statistics of a provider, used to rank providers.
+#[derive(Clone, Debug, Default)]
+pub struct ProviderStats {
+    /// Smoothed throughput in bytes per second
+    pub throughput: f64,
+    /// Number of chunks fetched from the provider
+    pub successes: u64,
+    /// Number of consecutive failed fetches from the provider
+    pub failures: u64,
+}
+
+impl ProviderStats {
+    /// Score of the provider, higher is better. Providers we haven't
+    /// fetched anything from yet come first, so they get a chance to
+    /// prove themselves.
+    fn score(&self) -> f64 {
+        if self.successes == 0 && self.failures == 0 {
+            return f64::INFINITY
+        }
+
+        self.throughput / (1 + self.failures) as f64
+    }
+
+    /// Record a successful fetch of `bytes` that took `elapsed`.
+    fn record_success(&mut self, bytes: u64, elapsed: Duration) {
+        let sample = bytes as f64 / elapsed.as_secs_f64().max(0.001);
+        self.throughput = match self.successes {
+            0 => sample,
+            _ => 0.7 * self.throughput + 0.3 * sample,
+        };
+        self.successes += 1;
+        self.failures = 0;
+    }
+
+    /// Record a failed fetch.
+    fn record_failure(&mut self) {
+        self.failures += 1;
+    }
+}
+
+/// Order provided peers by their ranking, best first.
+pub fn rank_providers(
+    stats: &HashMap<Url, ProviderStats>,
+    peers: impl IntoIterator<Item = Url>,
+) -> Vec<Url> {
+    let mut peers: Vec<(f64, Url)> = peers
+        .into_iter()
+        .map(|peer| (stats.get(&peer).map(|s| s.score()).unwrap_or(f64::INFINITY), peer))
+        .collect();
+    peers.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+    peers.into_iter().map(|(_, peer)| peer).collect()
+}
+
+/// Progress of a download.
+#[derive(Clone, Debug, Default)]
+pub struct DownloadProgress {
+    /// Number of chunks of the file, once its metadata is known
+    pub chunks_total: usize,
+    /// Number of chunks we hold
+    pub chunks_done: usize,
+    /// Bytes fetched so far by this download
+    pub bytes_fetched: u64,
+}
+
+/// An active download.
+pub struct Download {
+    /// Hash of the file being downloaded
+    pub file_hash: blake3::Hash,
+    /// Progress of the download
+    pub progress: Mutex<DownloadProgress>,
+    /// Notified with the outcome once the download finishes
+    pub finished: PublisherPtr<bool>,
+}
+
+impl Download {
+    pub fn new(file_hash: blake3::Hash) -> Arc<Self> {
+        Arc::new(Self {
+            file_hash,
+            progress: Mutex::new(DownloadProgress::default()),
+            finished: Publisher::new(),
+        })
+    }
+
+    /// JSON representation of the download progress, along with the
+    /// provided event name.
+    pub async fn to_json(&self, event: &str) -> JsonValue {
+        let progress = self.progress.lock().await;
+        JsonValue::Object(HashMap::from([
+            ("file_hash".to_string(), JsonValue::String(self.file_hash.to_hex().to_string())),
+            ("event".to_string(), JsonValue::String(event.to_string())),
+            ("chunks_total".to_string(), JsonValue::Number(progress.chunks_total as f64)),
+            ("chunks_done".to_string(), JsonValue::Number(progress.chunks_done as f64)),
+            ("bytes_fetched".to_string(), JsonValue::Number(progress.bytes_fetched as f64)),
+        ]))
+    }
+}
+
+/// Record an active download in provided directory, so it can be
+/// resumed after a restart.
+pub async fn persist(downloads_path: &Path, file_hash: &blake3::Hash) -> Result<()> {
+    fs::write(downloads_path.join(file_hash.to_hex().as_str()), b"").await?;
+    Ok(())
+}
+
+/// Remove the record of a download from provided directory.
+pub async fn unpersist(downloads_path: &Path, file_hash: &blake3::Hash) -> Result<()> {
+    let path = downloads_path.join(file_hash.to_hex().as_str());
+    if let Err(e) = fs::remove_file(path).await {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            return Err(e.into())
+        }
+    }
+    Ok(())
+}
+
+/// Read the downloads recorded in provided directory.
+pub async fn pending(downloads_path: &Path) -> Result<Vec<blake3::Hash>> {
+    let mut pending = vec![];
+    let mut entries = fs::read_dir(downloads_path).await?;
+    while let Some(entry) = entries.next().await {
+        let path: PathBuf = entry?.path();
+        let Some(name) = path.file_name().and_then(|n| n.to_str()) else { continue };
+        match blake3::Hash::from_hex(name) {
+            Ok(file_hash) => pending.push(file_hash),
+            Err(_) => warn!(target: "fud::download", "Ignoring unknown download record {:?}", path),
+        }
+    }
+    Ok(pending)
+}
+
+/// Background task that receives download requests and spawns a
+/// download for each of them.
+pub async fn download_task(fud: Arc<Fud>) -> Result<()> {
+    info!(target: "fud::download", "Started background download task");
+    let executor = fud.p2p.executor();
+    loop {
+        let file_hash = fud.download_rx.recv().await.unwrap();
+        let Some(download) = fud.downloads.lock().await.get(&file_hash).cloned() else { continue };
+        executor.spawn(run_download(fud.clone(), download)).detach();
+    }
+}
+
+/// Run provided download to completion, and notify its outcome.
+async fn run_download(fud: Arc<Fud>, download: Arc<Download>) {
+    let file_hash = download.file_hash;
+    info!(target: "fud::download", "Downloading {}", file_hash);
+    fud.download_sub.notify(JsonValue::Array(vec![download.to_json("started").await])).await;
+
+    let result = fetch_file(&fud, &download).await;
+
+    let event = match &result {
+        Ok(()) => {
+            info!(target: "fud::download", "Finished downloading {}", file_hash);
+            "finished"
+        }
+        Err(e) => {
+            error!(target: "fud::download", "Downloading {} failed: {}", file_hash, e);
+            "failed"
+        }
+    };
+
+    // Failed downloads are not resumed, as they will fail again until
+    // the file is requested anew. What we fetched so far is kept.
+    if let Err(e) = unpersist(&fud.downloads_path, &file_hash).await {
+        error!(target: "fud::download", "Failed removing download record of {}: {}", file_hash, e);
+    }
+
+    let mut downloads = fud.downloads.lock().await;
+    downloads.remove(&file_hash);
+    fud.download_sub.notify(JsonValue::Array(vec![download.to_json(event).await])).await;
+    download.finished.notify(result.is_ok()).await;
+}
+
+/// Fetch the metadata of the file if we don't have it, and then all
+/// its missing chunks.
+async fn fetch_file(fud: &Arc<Fud>, download: &Arc<Download>) -> Result<()> {
+    let file_hash = download.file_hash;
+    let chunked_file = match fud.geode.get(&file_hash).await {
+        Ok(v) => v,
+        Err(Error::GeodeFileNotFound) => {
+            fetch_metadata(fud, &file_hash).await?;
+            let chunked_file = fud.geode.get(&file_hash).await?;
+
+            let m = FudFilePut {
+                file_hash,
+                chunk_hashes: chunked_file.iter().map(|(h, _)| *h).collect(),
+            };
+            fud.p2p.broadcast(&m).await;
+
+            chunked_file
+        }
+        Err(e) => return Err(e),
+    };
+
+    let missing: VecDeque<(blake3::Hash, Vec<Url>)> = chunked_file
+        .iter()
+        .filter(|(_, path)| path.is_none())
+        .map(|(chunk_hash, _)| (*chunk_hash, vec![]))
+        .collect();
+
+    let mut progress = download.progress.lock().await;
+    progress.chunks_total = chunked_file.iter().len();
+    progress.chunks_done = progress.chunks_total - missing.len();
+    drop(progress);
+
+    if missing.is_empty() {
+        return Ok(())
+    }
+
+    // Spawn the workers and wait for all of them to finish
+    let workers = missing.len().min(MAX_PARALLEL_CHUNKS);
+    let state = Arc::new(WorkerState {
+        queue: Mutex::new(missing),
+        busy: Mutex::new(HashSet::new()),
+        failed: Mutex::new(vec![]),
+    });
+
+    let executor = fud.p2p.executor();
+    let tasks: Vec<_> = (0..workers)
+        .map(|_| executor.spawn(chunk_worker(fud.clone(), download.clone(), state.clone())))
+        .collect();
+    for task in tasks {
+        task.await;
+    }
+
+    let failed = state.failed.lock().await;
+    if !failed.is_empty() {
+        warn!(target: "fud::download", "Could not fetch {} chunks of {}", failed.len(), file_hash);
+        return Err(Error::GeodeChunkRouteNotFound)
+    }
+
+    Ok(())
+}
+
+/// State shared by the chunk workers of a download.
+struct WorkerState {
+    /// Chunks left to fetch, along with the providers already tried
+    queue: Mutex<VecDeque<(blake3::Hash, Vec<Url>)>>,
+    /// Providers currently serving a chunk
+    busy: Mutex<HashSet<Url>>,
+    /// Chunks that couldn't be fetched from any provider
+    failed: Mutex<Vec<blake3::Hash>>,
+}
+
+/// Worker fetching chunks of the download queue until it's empty.
+async fn chunk_worker(fud: Arc<Fud>, download: Arc<Download>, state: Arc<WorkerState>) {
+    loop {
+        let Some((chunk_hash, mut tried)) = state.queue.lock().await.pop_front() else { return };
+
+        // Pick the best idle provider we haven't tried yet, or the best
+        // busy one if they are all busy.
+        let peers = match fud.chunks_router.read().await.get(&chunk_hash) {
+            Some(peers) => peers.iter().filter(|p| !tried.contains(p)).cloned().collect(),
+            None => vec![],
+        };
+        let ranked = rank_providers(&*fud.providers.read().await, peers);
+        let mut busy = state.busy.lock().await;
+        let Some(peer) = ranked.iter().find(|p| !busy.contains(*p)).or(ranked.first()).cloned()
+        else {
+            drop(busy);
+            warn!(target: "fud::download", "No more providers to fetch chunk {} from", chunk_hash);
+            state.failed.lock().await.push(chunk_hash);
+            continue
+        };
+        busy.insert(peer.clone());
+        drop(busy);
+
+        tried.push(peer.clone());
+        let start = Instant::now();
+        let result = fetch_chunk(&fud, &chunk_hash, &peer).await;
+        state.busy.lock().await.remove(&peer);
+
+        match result {
+            Ok(bytes) => {
+                let elapsed = start.elapsed();
+                fud.providers.write().await.entry(peer).or_default().record_success(bytes, elapsed);
+
+                let mut progress = download.progress.lock().await;
+                progress.chunks_done += 1;
+                progress.bytes_fetched += bytes;
+                drop(progress);
+
+                fud.p2p.broadcast(&FudChunkPut { chunk_hash }).await;
+                fud.download_sub
+                    .notify(JsonValue::Array(vec![download.to_json("chunk").await]))
+                    .await;
+            }
+
+            Err(e) => {
+                warn!(
+                    target: "fud::download",
+                    "Fetching chunk {} from {} failed: {}", chunk_hash, peer, e,
+                );
+                fud.providers.write().await.entry(peer.clone()).or_default().record_failure();
+
+                // Peers serving invalid or no data are removed from the router
+                if matches!(e, Error::GeodeInvalidChunk | Error::GeodeChunkNotFound) {
+                    debug!(
+                        target: "fud::download",
+                        "Removing peer {} from {} chunk router", peer, chunk_hash,
+                    );
+                    if let Some(peers) = fud.chunks_router.write().await.get_mut(&chunk_hash) {
+                        peers.remove(&peer);
+                    }
+                }
+
+                if tried.len() < MAX_CHUNK_ATTEMPTS {
+                    state.queue.lock().await.push_back((chunk_hash, tried));
+                } else {
+                    state.failed.lock().await.push(chunk_hash);
+                }
+            }
+        }
+    }
+}
+
+/// Connect to provided peer and perform the version handshake. The
+/// channel is only used for our requests, so it doesn't get registered
+/// with any session.
+async fn connect(fud: &Fud, peer: &Url) -> Result<ChannelPtr> {
+    let executor = fud.p2p.executor();
+    let connector = Connector::new(fud.p2p.settings(), Arc::downgrade(&fud.p2p.session_manual()));
+    let (_, channel) = connector.connect(peer).await?;
+
+    let protocol_version = ProtocolVersion::new(channel.clone(), fud.p2p.settings()).await;
+    channel.clone().start(executor.clone());
+    protocol_version.run(executor).await?;
+
+    Ok(channel)
+}
+
+/// Fetch the metadata of a file from its providers, best ranked first.
+async fn fetch_metadata(fud: &Fud, file_hash: &blake3::Hash) -> Result<()> {
+    let peers = match fud.metadata_router.read().await.get(file_hash) {
+        Some(peers) => peers.iter().cloned().collect(),
+        None => vec![],
+    };
+    let peers = rank_providers(&*fud.providers.read().await, peers);
+
+    if peers.is_empty() {
+        warn!(target: "fud::download", "File {} not in routing table, cannot fetch", file_hash);
+        return Err(Error::GeodeFileRouteNotFound)
+    }
+
+    for peer in peers {
+        info!(target: "fud::download", "Fetching {} metadata from {}", file_hash, peer);
+        let result = fetch_metadata_from(fud, file_hash, &peer).await;
+        match result {
+            Ok(()) => return Ok(()),
+            Err(e) => {
+                warn!(
+                    target: "fud::download",
+                    "Fetching {} metadata from {} failed: {}", file_hash, peer, e,
+                );
+                fud.providers.write().await.entry(peer.clone()).or_default().record_failure();
+
+                if matches!(e, Error::GeodeInvalidFile | Error::GeodeFileNotFound) {
+                    debug!(
+                        target: "fud::download",
+                        "Removing peer {} from {} file router", peer, file_hash,
+                    );
+                    if let Some(peers) = fud.metadata_router.write().await.get_mut(file_hash) {
+                        peers.remove(&peer);
+                    }
+                }
+            }
+        }
+    }
+
+    Err(Error::GeodeFileRouteNotFound)
+}
+
+/// Fetch the metadata of a file from provided peer.
+async fn fetch_metadata_from(fud: &Fud, file_hash: &blake3::Hash, peer: &Url) -> Result<()> {
+    let channel = connect(fud, peer).await?;
+
+    let result = async {
+        let request = FudFileRequest { file_hash: *file_hash };
+        let reply = match fud.requests.request(&channel, &request).await {
+            Ok(reply) => reply,
+            Err(Error::RequestUnavailable) => return Err(Error::GeodeFileNotFound),
+            Err(e) => return Err(e),
+        };
+        fud.geode.insert_file(file_hash, &reply.chunk_hashes).await
+    }
+    .await;
+
+    channel.stop().await;
+    result
+}
+
+/// Fetch a chunk from provided peer, resuming from what we already
+/// fetched of it. Returns the number of bytes fetched.
+async fn fetch_chunk(fud: &Fud, chunk_hash: &blake3::Hash, peer: &Url) -> Result<u64> {
+    let channel = connect(fud, peer).await?;
+
+    let result = async {
+        let offset = fud.geode.chunk_progress(chunk_hash).await?;
+        let request = FudChunkGroupRequest { chunk_hash: *chunk_hash, offset };
+        let replies = fud.requests.request_stream(&channel, &request).await?;
+
+        // Every group of the chunk is verified as it arrives, so
+        // a peer sending garbage is caught after a single group.
+        let result = async {
+            let mut bytes = 0;
+            loop {
+                let reply = match replies.next().await {
+                    Ok(reply) => reply,
+                    Err(Error::RequestUnavailable) => return Err(Error::GeodeChunkNotFound),
+                    Err(e) => return Err(e),
+                };
+
+                if reply.chunk_hash != *chunk_hash {
+                    continue
+                }
+
+                bytes += reply.data.len() as u64;
+                let group =
+                    ChunkGroup { offset: reply.offset, data: reply.data, proof: reply.proof };
+                if fud.geode.insert_chunk_group(chunk_hash, reply.chunk_size, &group).await? {
+                    return Ok(bytes)
+                }
+            }
+        }
+        .await;
+
+        replies.close().await;
+        result
+    }
+    .await;
+
+    // The channel is only used for this request, so replies we didn't
+    // read are discarded along with it.
+    channel.stop().await;
+    result
+}
@@ -18,11 +18,13 @@
 
 use std::{
     collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
     sync::Arc,
 };
 
 use async_trait::async_trait;
-use log::{debug, error, info, warn};
+use log::{error, info, warn};
+use rand::{rngs::OsRng, RngCore};
 use smol::{
     channel,
     fs::File,
@@ -36,26 +38,35 @@ use url::Url;
 
 use darkfi::{
     async_daemonize, cli_desc,
-    geode::Geode,
+    geode::{ChunkedFile, Geode},
     net::{
-        self, connector::Connector, protocol::ProtocolVersion, session::Session,
-        settings::SettingsOpt, P2p, P2pPtr,
+        self,
+        protocol::protocol_request::{
+            ProtocolRequestHandler, RequestResponse, RequestResponsePtr, RequestSettings,
+        },
+        settings::SettingsOpt,
+        P2p, P2pPtr,
     },
     rpc::{
-        jsonrpc::{ErrorCode, JsonError, JsonRequest, JsonResponse, JsonResult},
+        jsonrpc::{ErrorCode, JsonError, JsonRequest, JsonResponse, JsonResult, JsonSubscriber},
         server::{listen_and_serve, RequestHandler},
     },
-    system::{StoppableTask, StoppableTaskPtr},
+    system::{StoppableTask, StoppableTaskPtr, Subscription},
     util::path::expand_path,
     Error, Result,
 };
 
 /// P2P protocols
 mod proto;
-use proto::{
-    FudChunkPut, FudChunkReply, FudChunkRequest, FudFilePut, FudFileReply, FudFileRequest,
-    ProtocolFud,
-};
+use proto::{FudFilePut, ProtocolFud};
+
+/// Parallel multi-source downloads
+mod download;
+use download::{Download, ProviderStats};
+
+/// Encrypted private file sharing
+mod share;
+use share::{Capability, KeyMode};
 
 const CONFIG_FILE: &str = "fud_config.toml";
 const CONFIG_FILE_CONTENTS: &str = include_str!("../fud_config.toml");
@@ -99,10 +110,21 @@ pub struct Fud {
     /// The Geode instance
     geode: Geode,
 
-    file_fetch_tx: channel::Sender<(blake3::Hash, Result<()>)>,
-    file_fetch_rx: channel::Receiver<(blake3::Hash, Result<()>)>,
-    chunk_fetch_tx: channel::Sender<(blake3::Hash, Result<()>)>,
-    chunk_fetch_rx: channel::Receiver<(blake3::Hash, Result<()>)>,
+    /// Active downloads
+    downloads: Mutex<HashMap<blake3::Hash, Arc<Download>>>,
+    /// Path to the directory recording active downloads
+    downloads_path: PathBuf,
+    /// Transfer statistics of the providers we fetched from
+    providers: RwLock<HashMap<Url, ProviderStats>>,
+    /// Client sending file and chunk requests to providers
+    requests: RequestResponsePtr,
+    /// Download requests for the background download task
+    download_tx: channel::Sender<blake3::Hash>,
+    download_rx: channel::Receiver<blake3::Hash>,
+    /// JSON-RPC subscriber for download progress notifications
+    download_sub: JsonSubscriber,
+    /// Path to the directory holding files while they're being encrypted
+    tmp_path: PathBuf,
 
     rpc_connections: Mutex<HashSet<StoppableTaskPtr>>,
 }
@@ -115,6 +137,10 @@ impl RequestHandler<()> for Fud {
 
             "put" => self.put(req.id, req.params).await,
             "get" => self.get(req.id, req.params).await,
+            "put_private" => self.put_private(req.id, req.params).await,
+            "get_private" => self.get_private(req.id, req.params).await,
+            "downloads" => self.downloads(req.id, req.params).await,
+            "subscribe_downloads" => self.subscribe_downloads(req.id, req.params).await,
 
             "dnet_switch" => self.dnet_switch(req.id, req.params).await,
             _ => JsonError::new(ErrorCode::MethodNotFound, None, req.id).into(),
@@ -172,6 +198,8 @@ impl Fud {
     // RPCAPI:
     // Fetch a file from the network. Takes a file hash as parameter.
     // Returns the paths to the local chunks of the file, if found/fetched.
+    // Missing chunks are fetched through a download, whose progress can be
+    // followed with `subscribe_downloads`.
     //
     // --> {"jsonrpc": "2.0", "method": "get", "params": ["1211...abfd"], "id": 42}
     // <-- {"jsonrpc": "2.0", "result: ["~/.local/share/darkfi/fud/chunks/fab1...2314", ...], "id": 42}
@@ -186,97 +214,145 @@ impl Fud {
             Err(_) => return JsonError::new(ErrorCode::InvalidParams, None, id).into(),
         };
 
-        let chunked_file = match self.geode.get(&file_hash).await {
+        let chunked_file = match self.fetch_file(file_hash).await {
             Ok(v) => v,
-            Err(Error::GeodeNeedsGc) => todo!(),
-            Err(Error::GeodeFileNotFound) => {
-                info!("Requested file {} not found in Geode, triggering fetch", file_hash);
-                self.file_fetch_tx.send((file_hash, Ok(()))).await.unwrap();
-                info!("Waiting for background file fetch task...");
-                let (i_file_hash, status) = self.file_fetch_rx.recv().await.unwrap();
-                match status {
-                    Ok(()) => {
-                        let ch_file = self.geode.get(&file_hash).await.unwrap();
-
-                        let m = FudFilePut {
-                            file_hash: i_file_hash,
-                            chunk_hashes: ch_file.iter().map(|(h, _)| *h).collect(),
-                        };
-
-                        self.p2p.broadcast(&m).await;
-
-                        ch_file
-                    }
-
-                    Err(Error::GeodeFileRouteNotFound) => {
-                        // TODO: Return FileNotFound error
-                        return JsonError::new(ErrorCode::InternalError, None, id).into()
-                    }
-
-                    Err(e) => panic!("{}", e),
-                }
+            Err(e) => {
+                error!("Failed fetching {}: {}", file_hash, e);
+                return JsonError::new(ErrorCode::InternalError, Some(e.to_string()), id).into()
             }
-
-            Err(e) => panic!("{}", e),
         };
 
-        if chunked_file.is_complete() {
-            let chunks: Vec<JsonValue> = chunked_file
-                .iter()
-                .map(|(_, path)| {
-                    JsonValue::String(
-                        path.as_ref().unwrap().clone().into_os_string().into_string().unwrap(),
-                    )
-                })
-                .collect();
-
-            return JsonResponse::new(JsonValue::Array(chunks), id).into()
-        }
+        let chunks: Vec<JsonValue> = chunked_file
+            .iter()
+            .map(|(_, path)| {
+                JsonValue::String(
+                    path.as_ref().unwrap().clone().into_os_string().into_string().unwrap(),
+                )
+            })
+            .collect();
 
-        // Fetch any missing chunks
-        let mut missing_chunks = vec![];
-        for (chunk, path) in chunked_file.iter() {
-            if path.is_none() {
-                missing_chunks.push(*chunk);
-            }
+        JsonResponse::new(JsonValue::Array(chunks), id).into()
+    }
+
+    // RPCAPI:
+    // Privately share a file onto the network. Takes a local filesystem path
+    // as a parameter, and optionally `true` to use convergent encryption, so
+    // the same file shared twice results in the same chunks.
+    // The file is encrypted before being inserted, and the returned share
+    // capability is needed to retrieve it with `get_private`.
+    //
+    // --> {"jsonrpc": "2.0", "method": "put_private", "params": ["/foo.txt", false], "id": 42}
+    // <-- {"jsonrpc": "2.0", "result: "3Jk9...Vq2e", "id": 42}
+    async fn put_private(&self, id: u16, params: JsonValue) -> JsonResult {
+        let params = params.get::<Vec<JsonValue>>().unwrap();
+        if params.is_empty() || params.len() > 2 || !params[0].is_string() {
+            return JsonError::new(ErrorCode::InvalidParams, None, id).into()
         }
 
-        for chunk in missing_chunks {
-            self.chunk_fetch_tx.send((chunk, Ok(()))).await.unwrap();
-            let (i_chunk_hash, status) = self.chunk_fetch_rx.recv().await.unwrap();
+        let mode = match params.get(1) {
+            None => KeyMode::Random,
+            Some(JsonValue::Boolean(false)) => KeyMode::Random,
+            Some(JsonValue::Boolean(true)) => KeyMode::Convergent,
+            Some(_) => return JsonError::new(ErrorCode::InvalidParams, None, id).into(),
+        };
 
-            match status {
-                Ok(()) => {
-                    let m = FudChunkPut { chunk_hash: i_chunk_hash };
-                    self.p2p.broadcast(&m).await;
-                    break
-                }
-                Err(Error::GeodeChunkRouteNotFound) => continue,
+        let path = params[0].get::<String>().unwrap();
+        let path = match expand_path(path.as_str()) {
+            Ok(v) => v,
+            Err(_) => return JsonError::new(ErrorCode::InvalidParams, None, id).into(),
+        };
 
-                Err(e) => panic!("{}", e),
+        match self.insert_private(&path, mode).await {
+            Ok(capability) => {
+                JsonResponse::new(JsonValue::String(capability.to_string()), id).into()
+            }
+            Err(e) => {
+                error!("Failed privately sharing file {:?}: {}", path, e);
+                JsonError::new(ErrorCode::InternalError, None, id).into()
             }
         }
+    }
+
+    // RPCAPI:
+    // Fetch a privately shared file from the network and decrypt it. Takes a
+    // share capability and a local filesystem path to write the file to.
+    // Returns the path the decrypted file was written to.
+    //
+    // --> {"jsonrpc": "2.0", "method": "get_private", "params": ["3Jk9...Vq2e", "/foo.txt"], "id": 42}
+    // <-- {"jsonrpc": "2.0", "result: "/foo.txt", "id": 42}
+    async fn get_private(&self, id: u16, params: JsonValue) -> JsonResult {
+        let params = params.get::<Vec<JsonValue>>().unwrap();
+        if params.len() != 2 || !params[0].is_string() || !params[1].is_string() {
+            return JsonError::new(ErrorCode::InvalidParams, None, id).into()
+        }
+
+        let capability: Capability = match params[0].get::<String>().unwrap().parse() {
+            Ok(v) => v,
+            Err(_) => return JsonError::new(ErrorCode::InvalidParams, None, id).into(),
+        };
 
-        let chunked_file = match self.geode.get(&file_hash).await {
+        let output = params[1].get::<String>().unwrap();
+        let output = match expand_path(output.as_str()) {
             Ok(v) => v,
-            Err(e) => panic!("{}", e),
+            Err(_) => return JsonError::new(ErrorCode::InvalidParams, None, id).into(),
         };
 
-        if !chunked_file.is_complete() {
-            todo!();
-            // Return JsonError missing chunks
+        let file_hash = capability.file_hash;
+        let chunked_file = match self.fetch_file(file_hash).await {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Failed fetching {}: {}", file_hash, e);
+                return JsonError::new(ErrorCode::InternalError, Some(e.to_string()), id).into()
+            }
+        };
+
+        let chunks: Vec<PathBuf> =
+            chunked_file.iter().map(|(_, path)| path.clone().unwrap()).collect();
+
+        if let Err(e) = share::decrypt_file(&capability.key, &chunks, &output).await {
+            error!("Failed decrypting {} into {:?}: {}", file_hash, output, e);
+            return JsonError::new(ErrorCode::InternalError, Some(e.to_string()), id).into()
         }
 
-        let chunks: Vec<JsonValue> = chunked_file
-            .iter()
-            .map(|(_, path)| {
-                JsonValue::String(
-                    path.as_ref().unwrap().clone().into_os_string().into_string().unwrap(),
-                )
-            })
-            .collect();
+        let output = output.into_os_string().into_string().unwrap();
+        JsonResponse::new(JsonValue::String(output), id).into()
+    }
 
-        JsonResponse::new(JsonValue::Array(chunks), id).into()
+    // RPCAPI:
+    // List the active downloads along with their progress.
+    //
+    // --> {"jsonrpc": "2.0", "method": "downloads", "params": [], "id": 42}
+    // <-- {"jsonrpc": "2.0", "result: [{"file_hash": "1211...abfd", "event": "progress", "chunks_total": 40, "chunks_done": 12, "bytes_fetched": 3145728}, ...], "id": 42}
+    async fn downloads(&self, id: u16, params: JsonValue) -> JsonResult {
+        let params = params.get::<Vec<JsonValue>>().unwrap();
+        if !params.is_empty() {
+            return JsonError::new(ErrorCode::InvalidParams, None, id).into()
+        }
+
+        let downloads: Vec<Arc<Download>> = self.downloads.lock().await.values().cloned().collect();
+        let mut ret = vec![];
+        for download in downloads {
+            ret.push(download.to_json("progress").await);
+        }
+
+        JsonResponse::new(JsonValue::Array(ret), id).into()
+    }
+
+    // RPCAPI:
+    // Subscribe to download progress notifications. A notification is sent
+    // when a download starts, when each of its chunks is fetched, and when
+    // it finishes or fails, with `event` being `started`, `chunk`,
+    // `finished` or `failed` respectively.
+    //
+    // --> {"jsonrpc": "2.0", "method": "subscribe_downloads", "params": [], "id": 42}
+    // <-- {"jsonrpc": "2.0", "method": "downloads", "params": [{"file_hash": "1211...abfd", "event": "chunk", "chunks_total": 40, "chunks_done": 13, "bytes_fetched": 3407872}]}
+    async fn subscribe_downloads(&self, id: u16, params: JsonValue) -> JsonResult {
+        let params = params.get::<Vec<JsonValue>>().unwrap();
+        if !params.is_empty() {
+            return JsonError::new(ErrorCode::InvalidParams, None, id).into()
+        }
+
+        self.download_sub.clone().into()
     }
 
     // RPCAPI:
@@ -302,228 +378,74 @@ impl Fud {
 
         JsonResponse::new(JsonValue::Boolean(true), id).into()
     }
-}
 
-/// Background task that receives file fetch requests and tries to
-/// fetch objects from the network using the routing table.
-/// TODO: This can be optimised a lot for connection reuse, etc.
-async fn fetch_file_task(fud: Arc<Fud>, executor: Arc<Executor<'_>>) -> Result<()> {
-    info!("Started background file fetch task");
-    loop {
-        let (file_hash, _) = fud.file_fetch_rx.recv().await.unwrap();
-        info!("fetch_file_task: Received {}", file_hash);
-
-        let mut metadata_router = fud.metadata_router.write().await;
-        let peers = metadata_router.get_mut(&file_hash);
-
-        if peers.is_none() {
-            warn!("File {} not in routing table, cannot fetch", file_hash);
-            fud.file_fetch_tx.send((file_hash, Err(Error::GeodeFileRouteNotFound))).await.unwrap();
-            continue
+    /// Start downloading provided file, unless it's already being
+    /// downloaded, and subscribe to the outcome of the download.
+    async fn download(&self, file_hash: blake3::Hash) -> Result<Subscription<bool>> {
+        let mut downloads = self.downloads.lock().await;
+        if let Some(download) = downloads.get(&file_hash) {
+            return Ok(download.finished.clone().subscribe().await)
         }
 
-        let mut found = false;
-        let peers = peers.unwrap();
-        let mut invalid_file_routes = vec![];
-
-        for peer in peers.iter() {
-            let session_out = fud.p2p.session_outbound();
-            let session_weak = Arc::downgrade(&fud.p2p.session_outbound());
-
-            info!("Connecting to {} to fetch {}", peer, file_hash);
-            let connector = Connector::new(fud.p2p.settings(), session_weak);
-            match connector.connect(peer).await {
-                Ok((url, channel)) => {
-                    let proto_ver = ProtocolVersion::new(
-                        channel.clone(),
-                        fud.p2p.settings().clone(),
-                        fud.p2p.hosts().clone(),
-                    )
-                    .await;
-
-                    let handshake_task = session_out.perform_handshake_protocols(
-                        proto_ver,
-                        channel.clone(),
-                        executor.clone(),
-                    );
-
-                    channel.clone().start(executor.clone());
-
-                    if let Err(e) = handshake_task.await {
-                        error!("Handshake with {} failed: {}", url, e);
-                        // Delete peer from router
-                        invalid_file_routes.push(peer.clone());
-                        continue
-                    }
-
-                    let msg_subscriber = channel.subscribe_msg::<FudFileReply>().await.unwrap();
-                    let request = FudFileRequest { file_hash };
-
-                    if let Err(e) = channel.send(&request).await {
-                        error!("Failed sending FudFileRequest({}) to {}: {}", file_hash, url, e);
-                        continue
-                    }
-
-                    // TODO: With timeout!
-                    let reply = match msg_subscriber.receive().await {
-                        Ok(v) => v,
-                        Err(e) => {
-                            error!("Error receiving FudFileReply from subscriber: {}", e);
-                            continue
-                        }
-                    };
-
-                    msg_subscriber.unsubscribe().await;
-                    channel.stop().await;
-
-                    if let Err(e) = fud.geode.insert_file(&file_hash, &reply.chunk_hashes).await {
-                        error!("Failed inserting file {} to Geode: {}", file_hash, e);
-                        continue
-                    }
-
-                    found = true;
-                    break
-                }
-
-                Err(e) => {
-                    error!("Failed to connect to {}: {}", peer, e);
-                    continue
-                }
-            }
+        download::persist(&self.downloads_path, &file_hash).await?;
+        let download = Download::new(file_hash);
+        let subscription = download.finished.clone().subscribe().await;
+        downloads.insert(file_hash, download);
+        self.download_tx.send(file_hash).await?;
+
+        Ok(subscription)
+    }
+
+    /// Return provided file from Geode, downloading it first if we
+    /// don't hold all of its chunks.
+    async fn fetch_file(&self, file_hash: blake3::Hash) -> Result<ChunkedFile> {
+        match self.geode.get(&file_hash).await {
+            Ok(v) if v.is_complete() => return Ok(v),
+            Ok(_) | Err(Error::GeodeFileNotFound) => {}
+            Err(e) => return Err(e),
         }
 
-        for peer in invalid_file_routes {
-            debug!("Removing peer {} from {} file router", peer, file_hash);
-            peers.remove(&peer);
+        info!("Requested file {} is not complete in Geode, triggering download", file_hash);
+        let subscription = self.download(file_hash).await?;
+
+        info!("Waiting for download of {}...", file_hash);
+        let success = subscription.receive().await;
+        subscription.unsubscribe().await;
+
+        if !success {
+            return Err(Error::Custom(format!("Failed downloading {}", file_hash)))
         }
 
-        if !found {
-            warn!("Did not manage to fetch {} file metadata", file_hash);
-            fud.file_fetch_tx.send((file_hash, Err(Error::GeodeFileRouteNotFound))).await.unwrap();
-            continue
+        let chunked_file = self.geode.get(&file_hash).await?;
+        if !chunked_file.is_complete() {
+            return Err(Error::Custom(format!("File {} is missing chunks", file_hash)))
         }
 
-        info!("Successfully fetched {} file metadata", file_hash);
-        fud.file_fetch_tx.send((file_hash, Ok(()))).await.unwrap();
+        Ok(chunked_file)
     }
-}
 
-/// Background task that receives chunk fetch requests and tries to
-/// fetch objects from the network using the routing table.
-/// TODO: This can be optimised a lot for connection reuse, etc.
-async fn fetch_chunk_task(fud: Arc<Fud>, executor: Arc<Executor<'_>>) -> Result<()> {
-    info!("Started background chunk fetch task");
-    loop {
-        let (chunk_hash, _) = fud.chunk_fetch_rx.recv().await.unwrap();
-        info!("fetch_chunk_task: Received {}", chunk_hash);
-
-        let mut chunk_router = fud.chunks_router.write().await;
-        let peers = chunk_router.get_mut(&chunk_hash);
-
-        if peers.is_none() {
-            warn!("Chunk {} not in routing table, cannot fetch", chunk_hash);
-            fud.chunk_fetch_tx
-                .send((chunk_hash, Err(Error::GeodeChunkRouteNotFound)))
-                .await
-                .unwrap();
-            continue
-        }
+    /// Encrypt the file at provided path and insert the encrypted file
+    /// into Geode. Returns the capability of the shared file.
+    async fn insert_private(&self, path: &Path, mode: KeyMode) -> Result<Capability> {
+        let tmp_file = self.tmp_path.join(format!("{:016x}", OsRng.next_u64()));
 
-        let mut found = false;
-        let peers = peers.unwrap();
-        let mut invalid_chunk_routes = vec![];
-
-        for peer in peers.iter() {
-            let session_out = fud.p2p.session_outbound();
-            let session_weak = Arc::downgrade(&fud.p2p.session_outbound());
-
-            info!("Connecting to {} to fetch {}", peer, chunk_hash);
-            let connector = Connector::new(fud.p2p.settings(), session_weak);
-            match connector.connect(peer).await {
-                Ok((url, channel)) => {
-                    let proto_ver = ProtocolVersion::new(
-                        channel.clone(),
-                        fud.p2p.settings().clone(),
-                        fud.p2p.hosts().clone(),
-                    )
-                    .await;
-
-                    let handshake_task = session_out.perform_handshake_protocols(
-                        proto_ver,
-                        channel.clone(),
-                        executor.clone(),
-                    );
-
-                    channel.clone().start(executor.clone());
-
-                    if let Err(e) = handshake_task.await {
-                        error!("Handshake with {} failed: {}", url, e);
-                        // Delete peer from router
-                        invalid_chunk_routes.push(peer.clone());
-                        continue
-                    }
-
-                    let msg_subscriber = channel.subscribe_msg::<FudChunkReply>().await.unwrap();
-                    let request = FudChunkRequest { chunk_hash };
-
-                    if let Err(e) = channel.send(&request).await {
-                        error!("Failed sending FudChunkRequest({}) to {}: {}", chunk_hash, url, e);
-                        continue
-                    }
-
-                    // TODO: With timeout!
-                    let reply = match msg_subscriber.receive().await {
-                        Ok(v) => v,
-                        Err(e) => {
-                            error!("Error receiving FudChunkReply from subscriber: {}", e);
-                            continue
-                        }
-                    };
-
-                    msg_subscriber.unsubscribe().await;
-                    channel.stop().await;
-
-                    match fud.geode.insert_chunk(&reply.chunk).await {
-                        Ok(inserted_hash) => {
-                            if inserted_hash != chunk_hash {
-                                warn!("Received chunk does not match requested chunk");
-                                invalid_chunk_routes.push(peer.clone());
-                                continue
-                            }
-                        }
-                        Err(e) => {
-                            error!("Failed inserting chunk {} to Geode: {}", chunk_hash, e);
-                            continue
-                        }
-                    }
-
-                    found = true;
-                    break
-                }
-
-                Err(e) => {
-                    error!("Failed to connect to {}: {}", peer, e);
-                    continue
-                }
-            }
+        let inserted = async {
+            let key = share::encrypt_file(path, &tmp_file, mode).await?;
+            let fd = File::open(&tmp_file).await?;
+            let (file_hash, chunk_hashes) = self.geode.insert(fd).await?;
+            Ok::<_, Error>((Capability { file_hash, key }, chunk_hashes))
         }
+        .await;
 
-        for peer in invalid_chunk_routes {
-            debug!("Removing peer {} from {} chunk router", peer, chunk_hash);
-            peers.remove(&peer);
+        if let Err(e) = smol::fs::remove_file(&tmp_file).await {
+            warn!("Failed removing temporary file {:?}: {}", tmp_file, e);
         }
 
-        if !found {
-            warn!("Did not manage to fetch {} chunk", chunk_hash);
-            fud.chunk_fetch_tx
-                .send((chunk_hash, Err(Error::GeodeChunkRouteNotFound)))
-                .await
-                .unwrap();
-            continue
-        }
+        let (capability, chunk_hashes) = inserted?;
+        let fud_file = FudFilePut { file_hash: capability.file_hash, chunk_hashes };
+        self.p2p.broadcast(&fud_file).await;
 
-        info!("Successfully fetched {} chunk", chunk_hash);
-        fud.chunk_fetch_tx.send((chunk_hash, Ok(()))).await.unwrap();
+        Ok(capability)
     }
 }
 
@@ -539,46 +461,46 @@ async fn realmain(args: Args, ex: Arc<Executor<'static>>) -> Result<()> {
     info!("Instantiating Geode instance");
     let geode = Geode::new(&basedir).await?;
 
+    // Directory recording active downloads, so they get resumed
+    let downloads_path = basedir.join("downloads");
+    smol::fs::create_dir_all(&downloads_path).await?;
+
+    // Directory holding privately shared files while they're encrypted
+    let tmp_path = basedir.join("tmp");
+    smol::fs::create_dir_all(&tmp_path).await?;
+
     info!("Instantiating P2P network");
     let p2p = P2p::new(args.net.into(), ex.clone()).await;
 
     // Daemon instantiation
-    let (file_fetch_tx, file_fetch_rx) = smol::channel::unbounded();
-    let (chunk_fetch_tx, chunk_fetch_rx) = smol::channel::unbounded();
+    let (download_tx, download_rx) = smol::channel::unbounded();
     let fud = Arc::new(Fud {
         metadata_router,
         chunks_router,
         p2p: p2p.clone(),
         geode,
-        file_fetch_tx,
-        file_fetch_rx,
-        chunk_fetch_tx,
-        chunk_fetch_rx,
+        downloads: Mutex::new(HashMap::new()),
+        downloads_path,
+        providers: RwLock::new(HashMap::new()),
+        requests: RequestResponse::new(RequestSettings {
+            timeout: download::REPLY_TIMEOUT,
+            ..Default::default()
+        }),
+        download_tx,
+        download_rx,
+        download_sub: JsonSubscriber::new("downloads"),
+        tmp_path,
         rpc_connections: Mutex::new(HashSet::new()),
     });
 
-    info!(target: "fud", "Starting fetch file task");
-    let file_task = StoppableTask::new();
-    file_task.clone().start(
-        fetch_file_task(fud.clone(), ex.clone()),
-        |res| async {
-            match res {
-                Ok(()) | Err(Error::DetachedTaskStopped) => { /* Do nothing */ }
-                Err(e) => error!(target: "fud", "Failed starting fetch file task: {}", e),
-            }
-        },
-        Error::DetachedTaskStopped,
-        ex.clone(),
-    );
-
-    info!(target: "fud", "Starting fetch chunk task");
-    let chunk_task = StoppableTask::new();
-    chunk_task.clone().start(
-        fetch_chunk_task(fud.clone(), ex.clone()),
+    info!(target: "fud", "Starting download task");
+    let download_task = StoppableTask::new();
+    download_task.clone().start(
+        download::download_task(fud.clone()),
         |res| async {
             match res {
                 Ok(()) | Err(Error::DetachedTaskStopped) => { /* Do nothing */ }
-                Err(e) => error!(target: "fud", "Failed starting fetch chunk task: {}", e),
+                Err(e) => error!(target: "fud", "Failed starting download task: {}", e),
             }
         },
         Error::DetachedTaskStopped,
@@ -609,22 +531,58 @@ async fn realmain(args: Args, ex: Arc<Executor<'static>>) -> Result<()> {
             async move { ProtocolFud::init(fud_, channel, p2p).await.unwrap() }
         })
         .await;
+
+    let file_handler =
+        ProtocolRequestHandler::new(&p2p, "ProtocolFudFile", net::session::SESSION_DEFAULT).await;
+    file_handler.task.clone().start(
+        proto::handle_file_requests(file_handler.clone(), fud.clone()),
+        |res| async {
+            match res {
+                Ok(()) | Err(Error::DetachedTaskStopped) => { /* Do nothing */ }
+                Err(e) => error!(target: "fud", "Failed starting file requests handler: {}", e),
+            }
+        },
+        Error::DetachedTaskStopped,
+        ex.clone(),
+    );
+
+    let chunk_handler =
+        ProtocolRequestHandler::new(&p2p, "ProtocolFudChunk", net::session::SESSION_DEFAULT).await;
+    chunk_handler.task.clone().start(
+        proto::handle_chunk_requests(chunk_handler.clone(), fud.clone()),
+        |res| async {
+            match res {
+                Ok(()) | Err(Error::DetachedTaskStopped) => { /* Do nothing */ }
+                Err(e) => error!(target: "fud", "Failed starting chunk requests handler: {}", e),
+            }
+        },
+        Error::DetachedTaskStopped,
+        ex.clone(),
+    );
+
     p2p.clone().start().await?;
 
+    // Resume the downloads that were interrupted
+    for file_hash in download::pending(&fud.downloads_path).await? {
+        info!(target: "fud", "Resuming download of {}", file_hash);
+        fud.download(file_hash).await?.unsubscribe().await;
+    }
+
     // Signal handling for graceful termination.
     let (signals_handler, signals_task) = SignalHandler::new(ex)?;
     signals_handler.wait_termination(signals_task).await?;
     info!("Caught termination signal, cleaning up and exiting...");
 
-    info!(target: "fud", "Stopping fetch file task...");
-    file_task.stop().await;
-
-    info!(target: "fud", "Stopping fetch chunk task...");
-    chunk_task.stop().await;
+    info!(target: "fud", "Stopping download task...");
+    download_task.stop().await;
 
     info!(target: "fud", "Stopping JSON-RPC server...");
     rpc_task.stop().await;
 
+    info!(target: "fud", "Stopping request handlers...");
+    file_handler.task.stop().await;
+    chunk_handler.task.stop().await;
+
     info!("Stopping P2P network");
     p2p.stop().await;
 
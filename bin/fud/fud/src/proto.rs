@@ -20,9 +20,9 @@ use std::{collections::HashSet, sync::Arc};
 
 use async_trait::async_trait;
 use darkfi::{
-    geode::MAX_CHUNK_SIZE,
-    impl_p2p_message,
+    impl_p2p_message, impl_p2p_request,
     net::{
+        protocol::protocol_request::{ProtocolRequestAction, ProtocolRequestHandlerPtr},
         ChannelPtr, Message, MessageSubscription, P2pPtr, ProtocolBase, ProtocolBasePtr,
         ProtocolJobsManager, ProtocolJobsManagerPtr,
     },
@@ -30,7 +30,7 @@ use darkfi::{
 };
 use darkfi_serial::{SerialDecodable, SerialEncodable};
 use log::{debug, error};
-use smol::{fs::File, io::AsyncReadExt, Executor};
+use smol::Executor;
 use url::Url;
 
 use super::Fud;
@@ -80,31 +80,35 @@ pub struct FudFileReply {
     pub chunk_hashes: Vec<blake3::Hash>,
 }
 impl_p2p_message!(FudFileReply, "FudFileReply");
-
-/// Message representing a chunk request from the network
+// Peers without the file answer with a `FudFileNotFound` message
+impl_p2p_request!(FudFileRequest, FudFileReply, "FudFileNotFound");
+
+/// Message representing a chunk request from the network.
+/// The chunk is sent starting from `offset`, so fetching a partially
+/// fetched chunk can resume where it stopped. It is named apart from the
+/// former `FudChunkRequest`, answered with the whole chunk, so older peers
+/// don't misread it.
 #[derive(Debug, Clone, SerialEncodable, SerialDecodable)]
-pub struct FudChunkRequest {
+pub struct FudChunkGroupRequest {
     pub chunk_hash: blake3::Hash,
+    pub offset: u64,
 }
-impl_p2p_message!(FudChunkRequest, "FudChunkRequest");
+impl_p2p_message!(FudChunkGroupRequest, "FudChunkGroupRequest");
 
-/// Message representing a chunk reply from the network
+/// Message representing a chunk reply from the network. A chunk is sent
+/// as a sequence of replies, each holding a group of the chunk and its
+/// inclusion proof, so every group is verified as soon as it arrives.
 #[derive(Debug, Clone, SerialEncodable, SerialDecodable)]
-pub struct FudChunkReply {
-    // TODO: This sould be a chunk-sized array, but then we need padding?
-    pub chunk: Vec<u8>,
+pub struct FudChunkGroupReply {
+    pub chunk_hash: blake3::Hash,
+    pub chunk_size: u64,
+    pub offset: u64,
+    pub data: Vec<u8>,
+    pub proof: Vec<blake3::Hash>,
 }
-impl_p2p_message!(FudChunkReply, "FudChunkReply");
-
-/// Message representing a chunk reply when a file is not found
-#[derive(Debug, Clone, SerialEncodable, SerialDecodable)]
-pub struct FudFileNotFound;
-impl_p2p_message!(FudFileNotFound, "FudFileNotFound");
-
-/// Message representing a chunk reply when a chunk is not found
-#[derive(Debug, Clone, SerialEncodable, SerialDecodable)]
-pub struct FudChunkNotFound;
-impl_p2p_message!(FudChunkNotFound, "FudChunkNotFound");
+impl_p2p_message!(FudChunkGroupReply, "FudChunkGroupReply");
+// Peers without the chunk answer with a `FudChunkNotFound` message
+impl_p2p_request!(FudChunkGroupRequest, FudChunkGroupReply, "FudChunkNotFound");
 
 /// P2P protocol implementation for fud.
 pub struct ProtocolFud {
@@ -113,8 +117,6 @@ pub struct ProtocolFud {
     chunk_put_sub: MessageSubscription<FudChunkPut>,
     file_route_sub: MessageSubscription<FudFileRoute>,
     chunk_route_sub: MessageSubscription<FudChunkRoute>,
-    file_request_sub: MessageSubscription<FudFileRequest>,
-    chunk_request_sub: MessageSubscription<FudChunkRequest>,
     fud: Arc<Fud>,
     p2p: P2pPtr,
     jobsman: ProtocolJobsManagerPtr,
@@ -132,15 +134,11 @@ impl ProtocolFud {
         msg_subsystem.add_dispatch::<FudChunkPut>().await;
         msg_subsystem.add_dispatch::<FudFileRoute>().await;
         msg_subsystem.add_dispatch::<FudChunkRoute>().await;
-        msg_subsystem.add_dispatch::<FudFileRequest>().await;
-        msg_subsystem.add_dispatch::<FudChunkRequest>().await;
 
         let file_put_sub = channel.subscribe_msg::<FudFilePut>().await?;
         let chunk_put_sub = channel.subscribe_msg::<FudChunkPut>().await?;
         let file_route_sub = channel.subscribe_msg::<FudFileRoute>().await?;
         let chunk_route_sub = channel.subscribe_msg::<FudChunkRoute>().await?;
-        let file_request_sub = channel.subscribe_msg::<FudFileRequest>().await?;
-        let chunk_request_sub = channel.subscribe_msg::<FudChunkRequest>().await?;
 
         Ok(Arc::new(Self {
             channel: channel.clone(),
@@ -148,8 +146,6 @@ impl ProtocolFud {
             chunk_put_sub,
             file_route_sub,
             chunk_route_sub,
-            file_request_sub,
-            chunk_request_sub,
             fud,
             p2p,
             jobsman: ProtocolJobsManager::new("ProtocolFud", channel.clone()),
@@ -357,94 +353,6 @@ impl ProtocolFud {
                 .await;
         }
     }
-
-    async fn handle_fud_file_request(self: Arc<Self>) -> Result<()> {
-        debug!(target: "fud::ProtocolFud::handle_fud_file_request()", "START");
-
-        loop {
-            let file_request = match self.file_request_sub.receive().await {
-                Ok(v) => v,
-                Err(e) => {
-                    error!(
-                        target: "fud::ProtocolFud::handle_fud_file_request()",
-                        "recv fail: {}", e,
-                    );
-                    continue
-                }
-            };
-
-            let chunked_file = match self.fud.geode.get(&file_request.file_hash).await {
-                Ok(v) => v,
-                Err(Error::GeodeNeedsGc) => {
-                    // TODO: Run GC
-                    continue
-                }
-
-                Err(Error::GeodeFileNotFound) => match self.channel.send(&FudFileNotFound).await {
-                    Ok(()) => continue,
-                    Err(_e) => continue,
-                },
-
-                Err(_e) => continue,
-            };
-
-            let file_reply = FudFileReply {
-                chunk_hashes: chunked_file.iter().map(|(chunk, _)| *chunk).collect(),
-            };
-
-            match self.channel.send(&file_reply).await {
-                Ok(()) => continue,
-                Err(_e) => continue,
-            }
-        }
-    }
-
-    async fn handle_fud_chunk_request(self: Arc<Self>) -> Result<()> {
-        debug!(target: "fud::ProtocolFud::handle_fud_chunk_request()", "START");
-
-        loop {
-            let chunk_request = match self.chunk_request_sub.receive().await {
-                Ok(v) => v,
-                Err(e) => {
-                    error!(
-                        target: "fud::ProtocolFud::handle_fud_chunk_request()",
-                        "recv fail: {}", e,
-                    );
-                    continue
-                }
-            };
-
-            let chunk_path = match self.fud.geode.get_chunk(&chunk_request.chunk_hash).await {
-                Ok(v) => v,
-                Err(Error::GeodeNeedsGc) => {
-                    // TODO: Run GC
-                    continue
-                }
-
-                Err(Error::GeodeChunkNotFound) => {
-                    match self.channel.send(&FudChunkNotFound).await {
-                        Ok(()) => continue,
-                        Err(_e) => continue,
-                    }
-                }
-
-                Err(_e) => continue,
-            };
-
-            // The consistency should already be checked in Geode, so we're
-            // fine not checking and unwrapping here.
-            let mut buf = [0u8; MAX_CHUNK_SIZE];
-            let mut chunk_fd = File::open(&chunk_path).await.unwrap();
-            let bytes_read = chunk_fd.read(&mut buf).await.unwrap();
-            let chunk_slice = &buf[..bytes_read];
-
-            let reply = FudChunkReply { chunk: chunk_slice.to_vec() };
-            match self.channel.send(&reply).await {
-                Ok(()) => continue,
-                Err(_e) => continue,
-            }
-        }
-    }
 }
 
 #[async_trait]
@@ -456,8 +364,6 @@ impl ProtocolBase for ProtocolFud {
         self.jobsman.clone().spawn(self.clone().handle_fud_chunk_put(), executor.clone()).await;
         self.jobsman.clone().spawn(self.clone().handle_fud_file_route(), executor.clone()).await;
         self.jobsman.clone().spawn(self.clone().handle_fud_chunk_route(), executor.clone()).await;
-        self.jobsman.clone().spawn(self.clone().handle_fud_file_request(), executor.clone()).await;
-        self.jobsman.clone().spawn(self.clone().handle_fud_chunk_request(), executor.clone()).await;
         debug!(target: "fud::ProtocolFud::start()", "END");
         Ok(())
     }
@@ -466,3 +372,80 @@ impl ProtocolBase for ProtocolFud {
         "ProtocolFud"
     }
 }
+
+/// Background handler serving file requests with the file metadata.
+pub async fn handle_file_requests(
+    handler: ProtocolRequestHandlerPtr<FudFileRequest>,
+    fud: Arc<Fud>,
+) -> Result<()> {
+    debug!(target: "fud::proto::handle_file_requests()", "START");
+
+    loop {
+        let (channel, file_request) = match handler.receiver.recv().await {
+            Ok(v) => v,
+            Err(e) => {
+                error!(target: "fud::proto::handle_file_requests()", "recv fail: {}", e);
+                continue
+            }
+        };
+
+        let chunked_file = match fud.geode.get(&file_request.file_hash).await {
+            Ok(v) => v,
+            Err(Error::GeodeNeedsGc) => {
+                // TODO: Run GC
+                handler.send_action(channel, ProtocolRequestAction::Skip).await;
+                continue
+            }
+            Err(_e) => {
+                handler.send_action(channel, ProtocolRequestAction::Skip).await;
+                continue
+            }
+        };
+
+        let file_reply =
+            FudFileReply { chunk_hashes: chunked_file.iter().map(|(chunk, _)| *chunk).collect() };
+        handler.send_action(channel, ProtocolRequestAction::Response(file_reply)).await;
+    }
+}
+
+/// Background handler serving chunk requests, with a reply for each
+/// group of the chunk from the requested offset on.
+pub async fn handle_chunk_requests(
+    handler: ProtocolRequestHandlerPtr<FudChunkGroupRequest>,
+    fud: Arc<Fud>,
+) -> Result<()> {
+    debug!(target: "fud::proto::handle_chunk_requests()", "START");
+
+    loop {
+        let (channel, chunk_request) = match handler.receiver.recv().await {
+            Ok(v) => v,
+            Err(e) => {
+                error!(target: "fud::proto::handle_chunk_requests()", "recv fail: {}", e);
+                continue
+            }
+        };
+
+        let chunk_hash = chunk_request.chunk_hash;
+        let (chunk_size, groups) =
+            match fud.geode.get_chunk_groups(&chunk_hash, chunk_request.offset).await {
+                Ok(v) => v,
+                Err(_e) => {
+                    handler.send_action(channel, ProtocolRequestAction::Skip).await;
+                    continue
+                }
+            };
+
+        // Geode verified the chunk while splitting it into groups
+        let replies = groups
+            .into_iter()
+            .map(|group| FudChunkGroupReply {
+                chunk_hash,
+                chunk_size,
+                offset: group.offset,
+                data: group.data,
+                proof: group.proof,
+            })
+            .collect();
+        handler.send_action(channel, ProtocolRequestAction::Responses(replies)).await;
+    }
+}
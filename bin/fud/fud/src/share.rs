@@ -0,0 +1,313 @@
+/* This file is part of DarkFi (https://dark.fi)
+ *
+ * Copyright (C) 2020-2024 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Encrypted private file sharing.
+//!
+//! Files shared privately are encrypted before they are inserted into
+//! Geode, so the chunks peers store and relay are opaque to them. Only
+//! the holders of the file's [`Capability`], which is exchanged out of
+//! band, are able to decrypt them.
+//!
+//! A file is split into segments of [`SEGMENT_SIZE`] bytes, and every
+//! segment is encrypted with ChaCha20-Poly1305 into exactly one Geode
+//! chunk. The nonce of a segment is its index, and the last segment is
+//! marked in its associated data, so the segments can't be reordered
+//! or the file truncated without it being noticed. A key only ever
+//! encrypts a single plaintext, so nonces are never reused.
+//!
+//! The key is random by default. With convergent encryption it's derived
+//! from the plaintext instead, so the same file shared twice results in
+//! the same chunks, which get deduplicated. The downside is that anyone
+//! holding the file can tell it's being shared, so it should only be
+//! used for files that aren't secret by themselves.
+
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Nonce,
+};
+use rand::{rngs::OsRng, RngCore};
+use smol::{
+    fs::{self, File},
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+};
+
+use darkfi::{geode::MAX_CHUNK_SIZE, Error, Result};
+
+/// Length of the authentication tag of an encrypted segment
+const TAG_SIZE: usize = 16;
+
+/// Size of a plaintext segment, which encrypts into a full chunk
+pub const SEGMENT_SIZE: usize = MAX_CHUNK_SIZE - TAG_SIZE;
+
+/// BLAKE3 context used to derive convergent encryption keys
+const CONVERGENT_KEY_CONTEXT: &str = "darkfi fud convergent encryption key";
+
+/// How the encryption key of a shared file is chosen
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyMode {
+    /// A random key, so every share results in different chunks
+    Random,
+    /// A key derived from the plaintext, so shares get deduplicated
+    Convergent,
+}
+
+/// Capability granting access to a privately shared file, made of
+/// the hash of the encrypted file and the key to decrypt it.
+/// It's encoded as a single base58 string.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Capability {
+    /// Hash of the encrypted file in Geode
+    pub file_hash: blake3::Hash,
+    /// Key the file is encrypted with
+    pub key: [u8; 32],
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut bytes = [0u8; 64];
+        bytes[..32].copy_from_slice(self.file_hash.as_bytes());
+        bytes[32..].copy_from_slice(&self.key);
+        write!(f, "{}", bs58::encode(bytes).into_string())
+    }
+}
+
+impl FromStr for Capability {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let Ok(bytes) = bs58::decode(s).into_vec() else {
+            return Err(Error::ParseFailed("Invalid share capability"))
+        };
+
+        if bytes.len() != 64 {
+            return Err(Error::ParseFailed("Invalid share capability"))
+        }
+
+        let file_hash = blake3::Hash::from_bytes(bytes[..32].try_into().unwrap());
+        let key = bytes[32..].try_into().unwrap();
+        Ok(Self { file_hash, key })
+    }
+}
+
+/// Nonce of the segment at provided index
+fn nonce(index: u64) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[..8].copy_from_slice(&index.to_le_bytes());
+    nonce
+}
+
+/// Encrypt a single segment of a file.
+pub fn encrypt_segment(key: &[u8; 32], index: u64, is_last: bool, plaintext: &[u8]) -> Vec<u8> {
+    let cipher = ChaCha20Poly1305::new(key.into());
+    let payload = Payload { msg: plaintext, aad: &[is_last as u8] };
+    cipher.encrypt(Nonce::from_slice(&nonce(index)), payload).unwrap()
+}
+
+/// Decrypt a single segment of a file. Returns `None` if the segment
+/// wasn't encrypted with provided key at provided position.
+pub fn decrypt_segment(
+    key: &[u8; 32],
+    index: u64,
+    is_last: bool,
+    ciphertext: &[u8],
+) -> Option<Vec<u8>> {
+    let cipher = ChaCha20Poly1305::new(key.into());
+    let payload = Payload { msg: ciphertext, aad: &[is_last as u8] };
+    cipher.decrypt(Nonce::from_slice(&nonce(index)), payload).ok()
+}
+
+/// Read from provided stream until the buffer is full or the stream ends.
+async fn read_segment(stream: &mut (impl AsyncRead + Unpin), buf: &mut [u8]) -> Result<usize> {
+    let mut total = 0;
+    while total < buf.len() {
+        let bytes_read = stream.read(&mut buf[total..]).await?;
+        if bytes_read == 0 {
+            break
+        }
+        total += bytes_read;
+    }
+
+    Ok(total)
+}
+
+/// Derive the convergent encryption key of the file at provided path.
+async fn convergent_key(path: &Path) -> Result<[u8; 32]> {
+    let mut hasher = blake3::Hasher::new_derive_key(CONVERGENT_KEY_CONTEXT);
+    let mut fd = File::open(path).await?;
+    let mut buf = vec![0u8; SEGMENT_SIZE];
+    loop {
+        let bytes_read = fd.read(&mut buf).await?;
+        if bytes_read == 0 {
+            break
+        }
+        hasher.update(&buf[..bytes_read]);
+    }
+
+    Ok(*hasher.finalize().as_bytes())
+}
+
+/// Encrypt the file at `path` into `output`, with a key chosen according
+/// to provided [`KeyMode`]. Returns the key the file was encrypted with.
+pub async fn encrypt_file(path: &Path, output: &Path, mode: KeyMode) -> Result<[u8; 32]> {
+    let key = match mode {
+        KeyMode::Random => {
+            let mut key = [0u8; 32];
+            OsRng.fill_bytes(&mut key);
+            key
+        }
+        KeyMode::Convergent => convergent_key(path).await?,
+    };
+
+    let mut input = File::open(path).await?;
+    let mut out = File::create(output).await?;
+
+    let mut buf = vec![0u8; SEGMENT_SIZE];
+    let mut bytes_read = read_segment(&mut input, &mut buf).await?;
+    let mut index = 0;
+
+    // An empty file still results in a single, empty, segment.
+    loop {
+        // We read one segment ahead, as the last segment is marked.
+        let mut next_buf = vec![0u8; SEGMENT_SIZE];
+        let next_bytes_read = match bytes_read {
+            SEGMENT_SIZE => read_segment(&mut input, &mut next_buf).await?,
+            _ => 0,
+        };
+
+        let is_last = next_bytes_read == 0;
+        out.write_all(&encrypt_segment(&key, index, is_last, &buf[..bytes_read])).await?;
+        if is_last {
+            break
+        }
+
+        buf = next_buf;
+        bytes_read = next_bytes_read;
+        index += 1;
+    }
+
+    out.sync_all().await?;
+    Ok(key)
+}
+
+/// Decrypt the chunks of a privately shared file, in order, into `output`.
+/// Nothing is left at `output` if any of the chunks fails to decrypt.
+pub async fn decrypt_file(key: &[u8; 32], chunks: &[PathBuf], output: &Path) -> Result<()> {
+    let mut out = File::create(output).await?;
+
+    for (index, chunk) in chunks.iter().enumerate() {
+        let ciphertext = fs::read(chunk).await?;
+        let is_last = index == chunks.len() - 1;
+
+        let Some(plaintext) = decrypt_segment(key, index as u64, is_last, &ciphertext) else {
+            drop(out);
+            fs::remove_file(output).await?;
+            return Err(Error::Custom(format!("Failed decrypting chunk {} of shared file", index)))
+        };
+
+        out.write_all(&plaintext).await?;
+    }
+
+    out.sync_all().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capability_encoding() {
+        let cap = Capability { file_hash: blake3::hash(b"fud"), key: [7u8; 32] };
+        assert_eq!(cap.to_string().parse::<Capability>().unwrap(), cap);
+        assert!("".parse::<Capability>().is_err());
+        assert!("0OIl".parse::<Capability>().is_err());
+        assert!(bs58::encode([1u8; 63]).into_string().parse::<Capability>().is_err());
+    }
+
+    #[test]
+    fn segment_tampering() {
+        let key = [1u8; 32];
+        let ciphertext = encrypt_segment(&key, 3, false, b"segment");
+        assert_eq!(ciphertext.len(), b"segment".len() + TAG_SIZE);
+        assert_eq!(decrypt_segment(&key, 3, false, &ciphertext).unwrap(), b"segment");
+
+        // Wrong key, reordered, or truncated segments don't decrypt
+        assert!(decrypt_segment(&[2u8; 32], 3, false, &ciphertext).is_none());
+        assert!(decrypt_segment(&key, 4, false, &ciphertext).is_none());
+        assert!(decrypt_segment(&key, 3, true, &ciphertext).is_none());
+
+        let mut tampered = ciphertext.clone();
+        tampered[0] ^= 1;
+        assert!(decrypt_segment(&key, 3, false, &tampered).is_none());
+    }
+
+    #[test]
+    fn file_roundtrip() {
+        let dir = std::env::temp_dir().join(format!("fud_share_{}", OsRng.next_u64()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        smol::block_on(async {
+            for size in [0, 1, SEGMENT_SIZE, 2 * SEGMENT_SIZE + 42] {
+                let plaintext: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+                let (input, encrypted, output) =
+                    (dir.join("input"), dir.join("encrypted"), dir.join("output"));
+                fs::write(&input, &plaintext).await.unwrap();
+
+                let key = encrypt_file(&input, &encrypted, KeyMode::Random).await.unwrap();
+
+                // Split the ciphertext the way Geode chunks it
+                let ciphertext = fs::read(&encrypted).await.unwrap();
+                assert_eq!(ciphertext.len(), size + size.div_ceil(SEGMENT_SIZE).max(1) * TAG_SIZE);
+                let mut chunks = vec![];
+                for (i, chunk) in ciphertext.chunks(MAX_CHUNK_SIZE).enumerate() {
+                    let path = dir.join(format!("chunk{}", i));
+                    fs::write(&path, chunk).await.unwrap();
+                    chunks.push(path);
+                }
+
+                decrypt_file(&key, &chunks, &output).await.unwrap();
+                assert_eq!(fs::read(&output).await.unwrap(), plaintext);
+
+                // Dropping the last chunk is detected
+                if chunks.len() > 1 {
+                    let truncated = &chunks[..chunks.len() - 1];
+                    assert!(decrypt_file(&key, truncated, &output).await.is_err());
+                    assert!(!output.exists());
+                }
+            }
+
+            // Convergent keys only depend on the plaintext
+            let input = dir.join("input");
+            let a = encrypt_file(&input, &dir.join("a"), KeyMode::Convergent).await.unwrap();
+            let b = encrypt_file(&input, &dir.join("b"), KeyMode::Convergent).await.unwrap();
+            assert_eq!(a, b);
+            assert_eq!(
+                fs::read(dir.join("a")).await.unwrap(),
+                fs::read(dir.join("b")).await.unwrap()
+            );
+        });
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -16,19 +16,24 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 
 use clap::{Parser, Subcommand};
 use darkfi::{
-    rpc::client::RpcClient,
+    rpc::{
+        client::RpcClient,
+        jsonrpc::{ErrorCode, JsonError, JsonRequest, JsonResult},
+    },
+    system::{Publisher, StoppableTask},
     util::cli::{get_log_config, get_log_level},
-    Result,
+    Error, Result,
 };
 use simplelog::{ColorChoice, TermLogger, TerminalMode};
 use smol::Executor;
+use tinyjson::JsonValue;
 use url::Url;
 
-use genevd::GenEvent;
+use genevd::{schema::Schema, GenEvent};
 
 mod rpc;
 use rpc::Gen;
@@ -50,9 +55,138 @@ struct Args {
 
 #[derive(Subcommand)]
 enum SubCmd {
-    Add { values: Vec<String> },
+    Add {
+        values: Vec<String>,
+    },
 
     List,
+
+    /// Register a schema
+    Register {
+        name: String,
+        /// Fields, as `name:type`, type being one of string, int, float,
+        /// bool or timestamp, suffixed with `?` if optional
+        fields: Vec<String>,
+    },
+
+    /// Insert a record of a schema
+    Insert {
+        schema: String,
+        /// Field values, as `name=value`
+        values: Vec<String>,
+    },
+
+    /// Query the records of a schema
+    Query {
+        schema: String,
+        /// Field values to filter by, as `name=value`
+        filters: Vec<String>,
+
+        #[clap(long)]
+        /// Start of the time range, in milliseconds
+        start: Option<u64>,
+
+        #[clap(long)]
+        /// End of the time range, in milliseconds
+        end: Option<u64>,
+    },
+
+    /// Watch the new records of a schema
+    Watch {
+        schema: String,
+        /// Field values to filter by, as `name=value`
+        filters: Vec<String>,
+    },
+}
+
+/// Parse `name=value` arguments into the JSON values of schema fields
+fn parse_values(schema: &Schema, args: &[String]) -> Result<HashMap<String, JsonValue>> {
+    let mut values = HashMap::new();
+    for arg in args {
+        let Some((name, value)) = arg.split_once('=') else {
+            return Err(Error::Custom(format!("Invalid field value: {}", arg)))
+        };
+
+        let Some((_, field)) = schema.field(name) else {
+            return Err(Error::Custom(format!("Unknown field: {}", name)))
+        };
+
+        let value = match field.kind.parse(value) {
+            Some(v) => (&v).into(),
+            None if value == "null" => JsonValue::Null,
+            None => {
+                return Err(Error::Custom(format!(
+                    "Field {} should be of type {}",
+                    name, field.kind
+                )))
+            }
+        };
+        values.insert(name.to_string(), value);
+    }
+
+    Ok(values)
+}
+
+fn print_record(record: &JsonValue) -> Result<()> {
+    let record = record.get::<HashMap<String, JsonValue>>().unwrap();
+    println!(
+        "{} {} {}",
+        *record["timestamp"].get::<f64>().unwrap() as u64,
+        record["id"].get::<String>().unwrap(),
+        record["fields"].stringify()?,
+    );
+    Ok(())
+}
+
+/// Subscribe to the new records matching a query and print them.
+async fn watch(
+    endpoint: Url,
+    schema: String,
+    filters: HashMap<String, JsonValue>,
+    ex: Arc<Executor<'static>>,
+) -> Result<()> {
+    let publisher = Publisher::new();
+    let subscription = publisher.clone().subscribe().await;
+    let _publisher = publisher.clone();
+    let _ex = ex.clone();
+    StoppableTask::new().start(
+        async move {
+            let rpc_client = RpcClient::new(endpoint, _ex).await?;
+            let params = vec![JsonValue::String(schema), JsonValue::Object(filters)];
+            let req = JsonRequest::new("query.subscribe", JsonValue::Array(params));
+            rpc_client.subscribe(req, _publisher).await
+        },
+        |res| async move {
+            if let Err(e) = res {
+                eprintln!("JSON-RPC server error: {e:?}");
+                publisher
+                    .notify(JsonResult::Error(JsonError::new(ErrorCode::InternalError, None, 0)))
+                    .await;
+            }
+        },
+        Error::RpcServerStopped,
+        ex,
+    );
+
+    loop {
+        match subscription.receive().await {
+            JsonResult::Notification(n) => {
+                for record in n.params.get::<Vec<JsonValue>>().unwrap() {
+                    print_record(record)?;
+                }
+            }
+
+            JsonResult::Error(e) => {
+                return Err(Error::UnexpectedJsonRpc(format!("Got error from JSON-RPC: {e:?}")))
+            }
+
+            x => {
+                return Err(Error::UnexpectedJsonRpc(format!(
+                    "Got unexpected data from JSON-RPC: {x:?}"
+                )))
+            }
+        }
+    }
 }
 
 fn main() -> Result<()> {
@@ -65,7 +199,7 @@ fn main() -> Result<()> {
     let executor = Arc::new(Executor::new());
 
     smol::block_on(executor.run(async {
-        let rpc_client = RpcClient::new(args.endpoint, executor.clone()).await?;
+        let rpc_client = RpcClient::new(args.endpoint.clone(), executor.clone()).await?;
         let gen = Gen { rpc_client };
 
         match args.command {
@@ -90,6 +224,46 @@ fn main() -> Result<()> {
                         );
                     }
                 }
+
+                SubCmd::Register { name, fields } => {
+                    let mut pairs = vec![];
+                    for field in fields {
+                        let Some((field, kind)) = field.split_once(':') else {
+                            return Err(Error::Custom(format!("Invalid field: {}", field)))
+                        };
+                        pairs.push((field.to_string(), kind.to_string()));
+                    }
+
+                    // Check the schema before sending it
+                    Schema::new(&name, &pairs)?;
+                    println!("{}", gen.register(&name, &pairs).await?);
+                }
+
+                SubCmd::Insert { schema, values } => {
+                    let Some(schema_) = gen.get_schema(&schema).await? else {
+                        return Err(Error::Custom(format!("Unknown schema: {}", schema)))
+                    };
+                    gen.insert(&schema, parse_values(&schema_, &values)?).await?;
+                }
+
+                SubCmd::Query { schema, filters, start, end } => {
+                    let Some(schema_) = gen.get_schema(&schema).await? else {
+                        return Err(Error::Custom(format!("Unknown schema: {}", schema)))
+                    };
+                    let filters = parse_values(&schema_, &filters)?;
+                    for record in gen.query(&schema, filters, start, end).await? {
+                        print_record(&record)?;
+                    }
+                }
+
+                SubCmd::Watch { schema, filters } => {
+                    let Some(schema_) = gen.get_schema(&schema).await? else {
+                        return Err(Error::Custom(format!("Unknown schema: {}", schema)))
+                    };
+                    let filters = parse_values(&schema_, &filters)?;
+                    gen.close_connection().await;
+                    return watch(args.endpoint, schema, filters, executor.clone()).await
+                }
             },
             None => println!("none"),
         }
@@ -16,13 +16,15 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
+use std::collections::HashMap;
+
 use darkfi::{
     rpc::{client::RpcClient, jsonrpc::JsonRequest},
     util::encoding::base64,
     Result,
 };
 use darkfi_serial::{deserialize, serialize};
-use genevd::GenEvent;
+use genevd::{schema::Schema, GenEvent};
 use log::debug;
 use tinyjson::JsonValue;
 
@@ -58,4 +60,72 @@ impl Gen {
 
         Ok(events)
     }
+
+    /// Register a schema, returning its ID.
+    pub async fn register(&self, name: &str, fields: &[(String, String)]) -> Result<String> {
+        let fields =
+            fields.iter().map(|(f, t)| (f.clone(), JsonValue::String(t.clone()))).collect();
+        let params = vec![JsonValue::String(name.to_string()), JsonValue::Object(fields)];
+
+        let req = JsonRequest::new("schema.register", JsonValue::Array(params));
+        let rep = self.rpc_client.request(req).await?;
+
+        debug!("Got reply: {:?}", rep);
+        Ok(rep.get::<String>().unwrap().clone())
+    }
+
+    /// Get a registered schema by name.
+    pub async fn get_schema(&self, name: &str) -> Result<Option<Schema>> {
+        let req = JsonRequest::new(
+            "schema.get",
+            JsonValue::Array(vec![JsonValue::String(name.to_string())]),
+        );
+        let rep = self.rpc_client.request(req).await?;
+
+        debug!("Got reply: {:?}", rep);
+        let Some(schema) = rep.get::<HashMap<String, JsonValue>>() else { return Ok(None) };
+
+        let fields: Vec<_> = schema["fields"]
+            .get::<HashMap<String, JsonValue>>()
+            .unwrap()
+            .iter()
+            .map(|(f, t)| (f.clone(), t.get::<String>().unwrap().clone()))
+            .collect();
+
+        Ok(Some(Schema::new(name, &fields)?))
+    }
+
+    /// Insert a record of a schema.
+    pub async fn insert(&self, schema: &str, fields: HashMap<String, JsonValue>) -> Result<()> {
+        let params = vec![JsonValue::String(schema.to_string()), JsonValue::Object(fields)];
+
+        let req = JsonRequest::new("insert", JsonValue::Array(params));
+        let rep = self.rpc_client.request(req).await?;
+
+        debug!("Got reply: {:?}", rep);
+        Ok(())
+    }
+
+    /// Query the records of a schema matching the filters, within a time range.
+    pub async fn query(
+        &self,
+        schema: &str,
+        filters: HashMap<String, JsonValue>,
+        start: Option<u64>,
+        end: Option<u64>,
+    ) -> Result<Vec<JsonValue>> {
+        let bound = |t: Option<u64>| t.map_or(JsonValue::Null, |t| JsonValue::Number(t as f64));
+        let params = vec![
+            JsonValue::String(schema.to_string()),
+            JsonValue::Object(filters),
+            bound(start),
+            bound(end),
+        ];
+
+        let req = JsonRequest::new("query", JsonValue::Array(params));
+        let rep = self.rpc_client.request(req).await?;
+
+        debug!("Got reply: {:?}", rep);
+        Ok(rep.get::<Vec<JsonValue>>().unwrap().clone())
+    }
 }
@@ -16,7 +16,13 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use darkfi_serial::{async_trait, SerialDecodable, SerialEncodable};
+use darkfi::event_graph::{Event, EventValidator};
+use darkfi_serial::{
+    async_trait, deserialize_async, deserialize_async_partial, SerialDecodable, SerialEncodable,
+};
+
+pub mod schema;
+use schema::Payload;
 
 #[derive(SerialEncodable, SerialDecodable, Clone, Debug)]
 pub struct GenEvent {
@@ -24,3 +30,23 @@ pub struct GenEvent {
     pub title: String,
     pub text: String,
 }
+
+/// Event graph validator rejecting events that are neither [`GenEvent`]s
+/// nor [`Payload`]s. Records aren't checked against their schema here,
+/// as whether it was received yet differs between nodes, which would
+/// make their DAGs diverge. They're checked when they're queried.
+pub struct GenEventValidator;
+
+#[async_trait]
+impl EventValidator for GenEventValidator {
+    async fn validate(&self, event: &Event) -> std::result::Result<(), String> {
+        if deserialize_async::<Payload>(event.content()).await.is_ok() {
+            return Ok(())
+        }
+
+        match deserialize_async_partial::<GenEvent>(event.content()).await {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("Invalid GenEvent: {e}")),
+        }
+    }
+}
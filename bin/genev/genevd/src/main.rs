@@ -20,7 +20,7 @@ use std::sync::{Arc, OnceLock};
 
 use darkfi::{
     async_daemonize, cli_desc,
-    event_graph::{proto::ProtocolEventGraph, EventGraph, EventGraphPtr, NULL_ID},
+    event_graph::{proto::ProtocolEventGraph, Event, EventGraph, EventGraphPtr, NULL_ID},
     net::{session::SESSION_DEFAULT, settings::SettingsOpt, P2p},
     rpc::{
         jsonrpc::JsonSubscriber,
@@ -30,14 +30,24 @@ use darkfi::{
     util::path::expand_path,
     Error, Result,
 };
+use darkfi_serial::deserialize_async;
 use log::{debug, error, info};
 use sled_overlay::sled;
-use smol::{fs, lock::RwLock, stream::StreamExt};
+use smol::{
+    fs,
+    lock::{Mutex, RwLock},
+    stream::StreamExt,
+};
 use structopt_toml::{serde::Deserialize, structopt::StructOpt, StructOptToml};
 use url::Url;
 
+use genevd::{
+    schema::{Payload, SchemaRegistry},
+    GenEventValidator,
+};
+
 mod rpc;
-use rpc::JsonRpcInterface;
+use rpc::{record_json, JsonRpcInterface, QuerySubscribers};
 
 const CONFIG_FILE: &str = "genev_config.toml";
 const CONFIG_FILE_CONTENTS: &str = include_str!("../genev_config.toml");
@@ -81,10 +91,41 @@ struct Args {
     verbose: u8,
 }
 
+/// Register the schema of a typed event, or notify the subscribers of
+/// the queries matching its record
+async fn handle_payload(event: &Event, schemas: &SchemaRegistry, query_subs: &QuerySubscribers) {
+    let Ok(payload) = deserialize_async::<Payload>(event.content()).await else { return };
+
+    let record = match payload {
+        Payload::Schema(schema) => {
+            match schemas.insert(&schema, event.timestamp) {
+                Ok(true) => debug!("Registered schema {}", schema.name),
+                Ok(false) => error!("Conflicting schema {} in event {}", schema.name, event.id()),
+                Err(e) => error!("Failed registering schema {}: {}", schema.name, e),
+            }
+            return
+        }
+        Payload::Record(record) => record,
+    };
+
+    let mut query_subs = query_subs.lock().await;
+    // Drop the subscriptions whose RPC connection was closed
+    query_subs.retain(|(_, sub)| Arc::strong_count(&sub.publisher) > 1);
+
+    for (query, sub) in query_subs.iter() {
+        if query.matches(&record, event.timestamp) {
+            let json = record_json(event, &query.schema, &record);
+            sub.notify(vec![json].into()).await;
+        }
+    }
+}
+
 async fn start_sync_loop(
     event_graph: EventGraphPtr,
     last_sent: RwLock<blake3::Hash>,
     seen: OnceLock<sled::Tree>,
+    schemas: SchemaRegistry,
+    query_subs: QuerySubscribers,
 ) -> Result<()> {
     let incoming = event_graph.event_pub.clone().subscribe().await;
     let seen_events = seen.get().unwrap();
@@ -100,6 +141,7 @@ async fn start_sync_loop(
         }
 
         debug!("new event: {:?}", event);
+        handle_payload(&event, &schemas, &query_subs).await;
     }
 }
 
@@ -117,6 +159,7 @@ async fn realmain(settings: Args, executor: Arc<smol::Executor<'static>>) -> Res
     let replay_mode = settings.replay_mode;
 
     let sled_db = sled::open(datastore_path.clone())?;
+    let schemas = SchemaRegistry::new(&sled_db)?;
     let p2p = P2p::new(settings.net.into(), executor.clone()).await?;
     let event_graph = EventGraph::new(
         p2p.clone(),
@@ -125,6 +168,8 @@ async fn realmain(settings: Args, executor: Arc<smol::Executor<'static>>) -> Res
         replay_mode,
         "genevd_dag",
         1,
+        false,
+        Some(Arc::new(GenEventValidator)),
         executor.clone(),
     )
     .await?;
@@ -170,17 +215,34 @@ async fn realmain(settings: Args, executor: Arc<smol::Executor<'static>>) -> Res
         *event_graph.synced.write().await = true;
     }
 
+    // Register the schemas of the synced events. The earliest
+    // registration of a name wins, whatever order they're seen in.
+    for event in event_graph.order_events().await.iter() {
+        if let Ok(Payload::Schema(schema)) = deserialize_async(event.content()).await {
+            if let Err(e) = schemas.insert(&schema, event.timestamp) {
+                error!("Failed registering schema {}: {}", schema.name, e);
+            }
+        }
+    }
+
     ////////////////////
     // Listner
     ////////////////////
     let last_sent = RwLock::new(NULL_ID);
     let seen = OnceLock::new();
     seen.set(sled_db.open_tree("genevdb").unwrap()).unwrap();
+    let query_subs: QuerySubscribers = Arc::new(Mutex::new(vec![]));
 
     info!(target: "genevd", "Starting sync loop task");
     let sync_loop_task = StoppableTask::new();
     sync_loop_task.clone().start(
-        start_sync_loop(event_graph.clone(), last_sent, seen.clone()),
+        start_sync_loop(
+            event_graph.clone(),
+            last_sent,
+            seen.clone(),
+            schemas.clone(),
+            query_subs.clone(),
+        ),
         |res| async {
             match res {
                 Ok(()) | Err(Error::DetachedTaskStopped) => { /* Do nothing */ }
@@ -248,6 +310,8 @@ async fn realmain(settings: Args, executor: Arc<smol::Executor<'static>>) -> Res
         p2p.clone(),
         dnet_sub,
         deg_sub,
+        schemas,
+        query_subs,
     ));
     let rpc_task = StoppableTask::new();
     let rpc_interface_ = rpc_interface.clone();
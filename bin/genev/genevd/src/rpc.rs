@@ -16,7 +16,10 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use std::collections::HashSet;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 
 use async_trait::async_trait;
 use log::{debug, error};
@@ -24,7 +27,7 @@ use smol::lock::{Mutex, MutexGuard};
 use tinyjson::JsonValue;
 
 use darkfi::{
-    event_graph::{proto::EventPut, Event, EventGraphPtr},
+    event_graph::{proto::EventPut, query::MAX_QUERY_EVENTS, Event, EventGraphPtr},
     net,
     rpc::{
         jsonrpc::{ErrorCode, JsonError, JsonRequest, JsonResponse, JsonResult, JsonSubscriber},
@@ -35,8 +38,24 @@ use darkfi::{
     util::encoding::base64,
 };
 
-use darkfi_serial::{deserialize, deserialize_async_partial, serialize_async};
-use genevd::GenEvent;
+use darkfi_serial::{deserialize, deserialize_async, deserialize_async_partial, serialize_async};
+use genevd::{
+    schema::{Payload, Query, Record, Schema, SchemaRegistry},
+    GenEvent,
+};
+
+/// Live record queries, and the subscribers notified of their new matches
+pub type QuerySubscribers = Arc<Mutex<Vec<(Query, JsonSubscriber)>>>;
+
+/// JSON representation of a record, along with its event metadata
+pub fn record_json(event: &Event, schema: &Schema, record: &Record) -> JsonValue {
+    JsonValue::Object(HashMap::from([
+        ("id".to_string(), JsonValue::String(event.id().to_string())),
+        ("timestamp".to_string(), JsonValue::Number(event.timestamp as f64)),
+        ("schema".to_string(), JsonValue::String(schema.name.clone())),
+        ("fields".to_string(), schema.record_json(record)),
+    ]))
+}
 
 pub struct JsonRpcInterface {
     _nickname: String,
@@ -45,6 +64,8 @@ pub struct JsonRpcInterface {
     rpc_connections: Mutex<HashSet<StoppableTaskPtr>>,
     dnet_sub: JsonSubscriber,
     deg_sub: JsonSubscriber,
+    schemas: SchemaRegistry,
+    query_subs: QuerySubscribers,
 }
 
 #[async_trait]
@@ -54,6 +75,13 @@ impl RequestHandler<()> for JsonRpcInterface {
             "add" => self.add(req.id, req.params).await,
             "list" => self.list(req.id, req.params).await,
 
+            "schema.register" => self.schema_register(req.id, req.params).await,
+            "schema.get" => self.schema_get(req.id, req.params).await,
+            "schema.list" => self.schema_list(req.id, req.params).await,
+            "insert" => self.insert(req.id, req.params).await,
+            "query" => self.query(req.id, req.params).await,
+            "query.subscribe" => self.query_subscribe(req.id, req.params).await,
+
             "ping" => self.pong(req.id, req.params).await,
             "dnet.subscribe_events" => self.dnet_subscribe_events(req.id, req.params).await,
             "dnet.switch" => self.dnet_switch(req.id, req.params).await,
@@ -63,6 +91,7 @@ impl RequestHandler<()> for JsonRpcInterface {
             "deg.subscribe_events" => self.deg_subscribe_events(req.id, req.params).await,
 
             "eventgraph.get_info" => self.eg_get_info(req.id, req.params).await,
+            "eventgraph.query" => self.eg_query(req.id, req.params).await,
 
             _ => return JsonError::new(ErrorCode::MethodNotFound, None, req.id).into(),
         }
@@ -86,6 +115,8 @@ impl JsonRpcInterface {
         p2p: net::P2pPtr,
         dnet_sub: JsonSubscriber,
         deg_sub: JsonSubscriber,
+        schemas: SchemaRegistry,
+        query_subs: QuerySubscribers,
     ) -> Self {
         Self {
             _nickname,
@@ -94,9 +125,28 @@ impl JsonRpcInterface {
             rpc_connections: Mutex::new(HashSet::new()),
             dnet_sub,
             deg_sub,
+            schemas,
+            query_subs,
         }
     }
 
+    /// Insert a payload into the DAG and broadcast it
+    async fn publish(&self, payload: &Payload) -> darkfi::Result<Event> {
+        let event = Event::new(serialize_async(payload).await, &self.event_graph).await;
+        self.event_graph.dag_insert(&[event.clone()]).await?;
+        self.p2p.broadcast(&EventPut(event.clone())).await;
+        Ok(event)
+    }
+
+    /// Parse the schema name and field values heading the params of
+    /// record methods
+    fn schema_params(&self, params: &[JsonValue]) -> Option<(Schema, HashMap<String, JsonValue>)> {
+        let name = params.first()?.get::<String>()?;
+        let fields = params.get(1)?.get::<HashMap<String, JsonValue>>()?;
+        let schema = self.schemas.get(name).ok()??;
+        Some((schema, fields.clone()))
+    }
+
     // RPCAPI:
     // Initializes a subscription to p2p dnet events.
     // Once a subscription is established, `darkirc` will send JSON-RPC notifications of
@@ -191,6 +241,17 @@ impl JsonRpcInterface {
         self.event_graph.eventgraph_info(id, params).await
     }
 
+    // RPCAPI:
+    // Query EVENTGRAPH events by time range, or page through them
+    // forwards or backwards from a given event.
+    //
+    // --> {"jsonrpc": "2.0", "method": "eventgraph.query", "params": ["between", 1700000000000, 1700003600000], "id": 42}
+    // --> {"jsonrpc": "2.0", "method": "eventgraph.query", "params": ["before", null, 50], "id": 42}
+    // <-- {"jsonrpc": "2.0", "result": [{"id": "...", "timestamp": ..., ...}, ...], "id": 42}
+    async fn eg_query(&self, id: u16, params: JsonValue) -> JsonResult {
+        self.event_graph.eventgraph_query(id, params).await
+    }
+
     // RPCAPI:
     // Add a new event
     // --> {"jsonrpc": "2.0", "method": "add", "params": [], "id": 1}
@@ -230,6 +291,11 @@ impl JsonRpcInterface {
 
         for event in dag_events.iter() {
             let event_id = event.id();
+            // Typed events are served by `query`
+            if deserialize_async::<Payload>(event.content()).await.is_ok() {
+                continue
+            }
+
             // Try to deserialize it. (Here we skip errors)
             let genevent: GenEvent = match deserialize_async_partial(event.content()).await {
                 Ok((v, _)) => v,
@@ -248,4 +314,234 @@ impl JsonRpcInterface {
 
         JsonResponse::new(enc, id).into()
     }
+
+    // RPCAPI:
+    // Register a schema, given its name and the types of its fields.
+    // Types are `string`, `int`, `float`, `bool` and `timestamp`, suffixed
+    // with `?` for optional fields. Returns the schema ID.
+    // --> {"jsonrpc": "2.0", "method": "schema.register", "params": ["poll", {"title": "string", "votes": "int?"}], "id": 1}
+    // <-- {"jsonrpc": "2.0", "result": "schema_id", "id": 1}
+    async fn schema_register(&self, id: u16, params: JsonValue) -> JsonResult {
+        let params = params.get::<Vec<JsonValue>>().unwrap();
+        if params.len() != 2 || !params[0].is_string() || !params[1].is_object() {
+            return JsonError::new(ErrorCode::InvalidParams, None, id).into()
+        }
+
+        let name = params[0].get::<String>().unwrap();
+        let mut fields = vec![];
+        for (field, kind) in params[1].get::<HashMap<String, JsonValue>>().unwrap() {
+            let Some(kind) = kind.get::<String>() else {
+                return JsonError::new(ErrorCode::InvalidParams, None, id).into()
+            };
+            fields.push((field.clone(), kind.clone()));
+        }
+
+        let schema = match Schema::new(name, &fields) {
+            Ok(v) => v,
+            Err(e) => {
+                return JsonError::new(ErrorCode::InvalidParams, Some(e.to_string()), id).into()
+            }
+        };
+
+        match self.schemas.get(name) {
+            Ok(Some(bound)) if bound.id() != schema.id() => {
+                let msg = format!("Schema {} is already registered", name);
+                return JsonError::new(ErrorCode::InvalidParams, Some(msg), id).into()
+            }
+            Ok(_) => {}
+            Err(e) => {
+                error!("Failed registering schema {}: {}", name, e);
+                return JsonError::new(ErrorCode::InternalError, None, id).into()
+            }
+        }
+
+        let event = match self.publish(&Payload::Schema(schema.clone())).await {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Failed inserting new event to DAG: {}", e);
+                return JsonError::new(ErrorCode::InternalError, None, id).into()
+            }
+        };
+
+        // A concurrent registration of the name may still win, once
+        // it's received, if it was published earlier.
+        if let Err(e) = self.schemas.insert(&schema, event.timestamp) {
+            error!("Failed registering schema {}: {}", name, e);
+            return JsonError::new(ErrorCode::InternalError, None, id).into()
+        }
+
+        JsonResponse::new(JsonValue::String(schema.id().to_string()), id).into()
+    }
+
+    // RPCAPI:
+    // Get a registered schema by name
+    // --> {"jsonrpc": "2.0", "method": "schema.get", "params": ["poll"], "id": 1}
+    // <-- {"jsonrpc": "2.0", "result": {"name": "poll", "id": "schema_id", "fields": {...}}, "id": 1}
+    async fn schema_get(&self, id: u16, params: JsonValue) -> JsonResult {
+        let params = params.get::<Vec<JsonValue>>().unwrap();
+        if params.len() != 1 || !params[0].is_string() {
+            return JsonError::new(ErrorCode::InvalidParams, None, id).into()
+        }
+
+        match self.schemas.get(params[0].get::<String>().unwrap()) {
+            Ok(Some(schema)) => JsonResponse::new((&schema).into(), id).into(),
+            Ok(None) => JsonResponse::new(JsonValue::Null, id).into(),
+            Err(e) => {
+                error!("Failed reading schema: {}", e);
+                JsonError::new(ErrorCode::InternalError, None, id).into()
+            }
+        }
+    }
+
+    // RPCAPI:
+    // List registered schemas
+    // --> {"jsonrpc": "2.0", "method": "schema.list", "params": [], "id": 1}
+    // <-- {"jsonrpc": "2.0", "result": [{"name": "poll", "id": "schema_id", "fields": {...}}, ...], "id": 1}
+    async fn schema_list(&self, id: u16, params: JsonValue) -> JsonResult {
+        let params = params.get::<Vec<JsonValue>>().unwrap();
+        if !params.is_empty() {
+            return JsonError::new(ErrorCode::InvalidParams, None, id).into()
+        }
+
+        match self.schemas.list() {
+            Ok(schemas) => {
+                let schemas = schemas.iter().map(|s| s.into()).collect();
+                JsonResponse::new(JsonValue::Array(schemas), id).into()
+            }
+            Err(e) => {
+                error!("Failed reading schemas: {}", e);
+                JsonError::new(ErrorCode::InternalError, None, id).into()
+            }
+        }
+    }
+
+    // RPCAPI:
+    // Insert a record of a registered schema. Missing optional fields are null.
+    // --> {"jsonrpc": "2.0", "method": "insert", "params": ["poll", {"title": "lunch", "votes": 3}], "id": 1}
+    // <-- {"jsonrpc": "2.0", "result": true, "id": 1}
+    async fn insert(&self, id: u16, params: JsonValue) -> JsonResult {
+        let params = params.get::<Vec<JsonValue>>().unwrap();
+        if params.len() != 2 {
+            return JsonError::new(ErrorCode::InvalidParams, None, id).into()
+        }
+
+        let Some((schema, fields)) = self.schema_params(params) else {
+            return JsonError::new(ErrorCode::InvalidParams, None, id).into()
+        };
+
+        let record = match schema.record(&fields) {
+            Ok(v) => v,
+            Err(e) => {
+                return JsonError::new(ErrorCode::InvalidParams, Some(e.to_string()), id).into()
+            }
+        };
+
+        if let Err(e) = self.publish(&Payload::Record(record)).await {
+            error!("Failed inserting new event to DAG: {}", e);
+            return JsonError::new(ErrorCode::InternalError, None, id).into()
+        }
+
+        JsonResponse::new(JsonValue::Boolean(true), id).into()
+    }
+
+    // RPCAPI:
+    // Query the records of a schema having the given field values, within
+    // an optional time range in milliseconds, the end being excluded.
+    // At most 1000 records are returned, oldest first.
+    // --> {"jsonrpc": "2.0", "method": "query", "params": ["poll", {"votes": 3}, 1700000000000, null], "id": 1}
+    // <-- {"jsonrpc": "2.0", "result": [{"id": "event_id", "timestamp": ..., "schema": "poll", "fields": {...}}, ...], "id": 1}
+    async fn query(&self, id: u16, params: JsonValue) -> JsonResult {
+        let params = params.get::<Vec<JsonValue>>().unwrap();
+        if params.len() != 4 {
+            return JsonError::new(ErrorCode::InvalidParams, None, id).into()
+        }
+
+        let Some((schema, filters)) = self.schema_params(params) else {
+            return JsonError::new(ErrorCode::InvalidParams, None, id).into()
+        };
+
+        let mut range = [None, None];
+        for (bound, param) in range.iter_mut().zip(&params[2..]) {
+            match param {
+                JsonValue::Null => {}
+                JsonValue::Number(n) if *n >= 0.0 => *bound = Some(*n as u64),
+                _ => return JsonError::new(ErrorCode::InvalidParams, None, id).into(),
+            }
+        }
+
+        let query = match Query::new(schema, &filters, range[0], range[1]) {
+            Ok(v) => v,
+            Err(e) => {
+                return JsonError::new(ErrorCode::InvalidParams, Some(e.to_string()), id).into()
+            }
+        };
+
+        // Scan the range a page at a time, so we never hold more than
+        // MAX_QUERY_EVENTS events or return more records than that.
+        let mut records = vec![];
+        let mut page =
+            self.event_graph.events_between(query.start, query.end, None, MAX_QUERY_EVENTS).await;
+        loop {
+            let events = match page {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("Failed reading events: {}", e);
+                    return JsonError::new(ErrorCode::InternalError, None, id).into()
+                }
+            };
+            let Some(last) = events.last() else { break };
+            let (cursor, last_page) =
+                ((last.timestamp, last.id()), events.len() < MAX_QUERY_EVENTS);
+
+            for event in events {
+                if event.timestamp >= query.end || records.len() == MAX_QUERY_EVENTS {
+                    break
+                }
+
+                let Ok(Payload::Record(record)) = deserialize_async(event.content()).await else {
+                    continue
+                };
+
+                if query.matches(&record, event.timestamp) {
+                    records.push(record_json(&event, &query.schema, &record));
+                }
+            }
+
+            if last_page || cursor.0 >= query.end || records.len() == MAX_QUERY_EVENTS {
+                break
+            }
+            page = self
+                .event_graph
+                .events_between(cursor.0, query.end, Some(&cursor.1), MAX_QUERY_EVENTS)
+                .await;
+        }
+
+        JsonResponse::new(JsonValue::Array(records), id).into()
+    }
+
+    // RPCAPI:
+    // Subscribe to new records of a schema having the given field values.
+    // --> {"jsonrpc": "2.0", "method": "query.subscribe", "params": ["poll", {"votes": 3}], "id": 1}
+    // <-- {"jsonrpc": "2.0", "method": "query.subscribe", "params": [{"id": "event_id", "timestamp": ..., "schema": "poll", "fields": {...}}]}
+    async fn query_subscribe(&self, id: u16, params: JsonValue) -> JsonResult {
+        let params = params.get::<Vec<JsonValue>>().unwrap();
+        if params.len() != 2 {
+            return JsonError::new(ErrorCode::InvalidParams, None, id).into()
+        }
+
+        let Some((schema, filters)) = self.schema_params(params) else {
+            return JsonError::new(ErrorCode::InvalidParams, None, id).into()
+        };
+
+        let query = match Query::new(schema, &filters, None, None) {
+            Ok(v) => v,
+            Err(e) => {
+                return JsonError::new(ErrorCode::InvalidParams, Some(e.to_string()), id).into()
+            }
+        };
+
+        let sub = JsonSubscriber::new("query.subscribe");
+        self.query_subs.lock().await.push((query, sub.clone()));
+        sub.into()
+    }
 }
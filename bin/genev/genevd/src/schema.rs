@@ -0,0 +1,488 @@
+/* This file is part of DarkFi (https://dark.fi)
+ *
+ * Copyright (C) 2020-2024 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Typed schemas for generic events.
+//!
+//! Apps register a named [`Schema`], listing the fields of their events
+//! along with their types, and then insert [`Record`]s of it. Both are
+//! sent over the DAG as a [`Payload`]. Records are validated against
+//! their schema when they're created, and can be queried by their field
+//! values and by time range.
+//!
+//! A schema name is bound to the earliest schema registered with it, by
+//! the timestamp of its event and then by schema ID, so every node binds
+//! it to the same one whatever order it receives registrations in. Records
+//! carry the ID of the schema they were made for, so records of a
+//! conflicting registration are told apart. The DAG accepts any record,
+//! as its schema may not have been received yet, and records not matching
+//! their schema are left out of queries and subscriptions instead.
+
+use std::{collections::HashMap, fmt, str::FromStr};
+
+use darkfi::{Error, Result};
+use darkfi_serial::{
+    async_trait, deserialize, serialize, FutAsyncWriteExt, SerialDecodable, SerialEncodable,
+};
+use sled_overlay::sled;
+use tinyjson::JsonValue;
+
+/// sled tree holding the registered schemas
+const SLED_SCHEMAS_TREE: &[u8] = b"genev_schemas";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, SerialEncodable, SerialDecodable)]
+pub enum FieldType {
+    String,
+    Int,
+    Float,
+    Bool,
+    /// UNIX timestamp
+    Timestamp,
+}
+
+impl FieldType {
+    /// Parse a value of this type from its JSON representation
+    pub fn from_json(&self, value: &JsonValue) -> Option<Value> {
+        match (self, value) {
+            (Self::String, JsonValue::String(s)) => Some(Value::String(s.clone())),
+            (Self::Int, JsonValue::Number(n)) if n.fract() == 0.0 && n.abs() < i64::MAX as f64 => {
+                Some(Value::Int(*n as i64))
+            }
+            (Self::Float, JsonValue::Number(n)) => Some(Value::Float(*n)),
+            (Self::Bool, JsonValue::Boolean(b)) => Some(Value::Bool(*b)),
+            (Self::Timestamp, JsonValue::Number(n)) if n.fract() == 0.0 && *n >= 0.0 => {
+                Some(Value::Timestamp(*n as u64))
+            }
+            _ => None,
+        }
+    }
+
+    /// Parse a value of this type from a string, as given on a command line
+    pub fn parse(&self, s: &str) -> Option<Value> {
+        match self {
+            Self::String => Some(Value::String(s.to_string())),
+            Self::Int => s.parse().ok().map(Value::Int),
+            Self::Float => s.parse().ok().map(Value::Float),
+            Self::Bool => s.parse().ok().map(Value::Bool),
+            Self::Timestamp => s.parse().ok().map(Value::Timestamp),
+        }
+    }
+}
+
+impl fmt::Display for FieldType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self {
+            Self::String => "string",
+            Self::Int => "int",
+            Self::Float => "float",
+            Self::Bool => "bool",
+            Self::Timestamp => "timestamp",
+        };
+        write!(f, "{}", kind)
+    }
+}
+
+impl FromStr for FieldType {
+    type Err = Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "string" => Ok(Self::String),
+            "int" => Ok(Self::Int),
+            "float" => Ok(Self::Float),
+            "bool" => Ok(Self::Bool),
+            "timestamp" => Ok(Self::Timestamp),
+            _ => Err(Error::Custom(format!("Unknown field type: {}", s))),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, SerialEncodable, SerialDecodable)]
+pub struct Field {
+    pub name: String,
+    pub kind: FieldType,
+    /// Whether the field may be null
+    pub optional: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, SerialEncodable, SerialDecodable)]
+pub enum Value {
+    Null,
+    String(String),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Timestamp(u64),
+}
+
+impl From<&Value> for JsonValue {
+    fn from(value: &Value) -> JsonValue {
+        match value {
+            Value::Null => JsonValue::Null,
+            Value::String(s) => JsonValue::String(s.clone()),
+            Value::Int(n) => JsonValue::Number(*n as f64),
+            Value::Float(n) => JsonValue::Number(*n),
+            Value::Bool(b) => JsonValue::Boolean(*b),
+            Value::Timestamp(n) => JsonValue::Number(*n as f64),
+        }
+    }
+}
+
+/// Named list of typed fields
+#[derive(Clone, Debug, PartialEq, SerialEncodable, SerialDecodable)]
+pub struct Schema {
+    pub name: String,
+    pub fields: Vec<Field>,
+}
+
+impl Schema {
+    /// Create a schema from field names and types. The type of optional
+    /// fields is suffixed with `?`, like `int?`.
+    pub fn new(name: &str, fields: &[(String, String)]) -> Result<Self> {
+        if name.is_empty() {
+            return Err(Error::Custom("Schema name is empty".to_string()))
+        }
+
+        let mut ret = vec![];
+        for (field, kind) in fields {
+            if field.is_empty() || ret.iter().any(|f: &Field| f.name == *field) {
+                return Err(Error::Custom(format!("Invalid field name: {:?}", field)))
+            }
+
+            let (kind, optional) = match kind.strip_suffix('?') {
+                Some(kind) => (kind, true),
+                None => (kind.as_str(), false),
+            };
+
+            ret.push(Field { name: field.clone(), kind: kind.parse()?, optional });
+        }
+
+        // Fields are sorted, so the same schema always has the same ID
+        ret.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(Self { name: name.to_string(), fields: ret })
+    }
+
+    /// Schema ID, committing to its name and fields
+    pub fn id(&self) -> blake3::Hash {
+        blake3::hash(&serialize(self))
+    }
+
+    /// Position and definition of a field
+    pub fn field(&self, name: &str) -> Option<(usize, &Field)> {
+        self.fields.iter().enumerate().find(|(_, f)| f.name == name)
+    }
+
+    /// Build a record of this schema from the JSON values of its fields.
+    /// Missing optional fields are null.
+    pub fn record(&self, values: &HashMap<String, JsonValue>) -> Result<Record> {
+        if let Some(name) = values.keys().find(|k| self.field(k).is_none()) {
+            return Err(Error::Custom(format!("Unknown field: {}", name)))
+        }
+
+        let mut ret = vec![];
+        for field in &self.fields {
+            let value = match values.get(&field.name) {
+                None | Some(JsonValue::Null) if field.optional => Value::Null,
+                None => return Err(Error::Custom(format!("Missing field: {}", field.name))),
+                Some(v) => field.kind.from_json(v).ok_or_else(|| {
+                    Error::Custom(format!("Field {} should be of type {}", field.name, field.kind))
+                })?,
+            };
+            ret.push(value);
+        }
+
+        Ok(Record { schema: self.name.clone(), schema_id: *self.id().as_bytes(), values: ret })
+    }
+
+    /// Check that a record is of this schema
+    pub fn validate(&self, record: &Record) -> Result<()> {
+        if record.schema != self.name || record.schema_id != *self.id().as_bytes() {
+            return Err(Error::Custom(format!("Record is not of schema {}", self.name)))
+        }
+
+        if record.values.len() != self.fields.len() {
+            return Err(Error::Custom("Record has the wrong number of fields".to_string()))
+        }
+
+        for (field, value) in self.fields.iter().zip(record.values.iter()) {
+            let valid = match (field.kind, value) {
+                (_, Value::Null) => field.optional,
+                (FieldType::String, Value::String(_)) |
+                (FieldType::Int, Value::Int(_)) |
+                (FieldType::Float, Value::Float(_)) |
+                (FieldType::Bool, Value::Bool(_)) |
+                (FieldType::Timestamp, Value::Timestamp(_)) => true,
+                _ => false,
+            };
+
+            if !valid {
+                return Err(Error::Custom(format!(
+                    "Field {} should be of type {}",
+                    field.name, field.kind
+                )))
+            }
+        }
+
+        Ok(())
+    }
+
+    /// JSON object of the fields of a record of this schema
+    pub fn record_json(&self, record: &Record) -> JsonValue {
+        let fields = self
+            .fields
+            .iter()
+            .zip(record.values.iter())
+            .map(|(field, value)| (field.name.clone(), value.into()))
+            .collect();
+
+        JsonValue::Object(fields)
+    }
+}
+
+impl From<&Schema> for JsonValue {
+    fn from(schema: &Schema) -> JsonValue {
+        let fields = schema
+            .fields
+            .iter()
+            .map(|f| {
+                let kind = if f.optional { format!("{}?", f.kind) } else { f.kind.to_string() };
+                (f.name.clone(), JsonValue::String(kind))
+            })
+            .collect();
+
+        JsonValue::Object(HashMap::from([
+            ("name".to_string(), JsonValue::String(schema.name.clone())),
+            ("id".to_string(), JsonValue::String(schema.id().to_hex().to_string())),
+            ("fields".to_string(), JsonValue::Object(fields)),
+        ]))
+    }
+}
+
+/// Event of a schema, its values being in the order of the schema fields
+#[derive(Clone, Debug, PartialEq, SerialEncodable, SerialDecodable)]
+pub struct Record {
+    pub schema: String,
+    pub schema_id: [u8; 32],
+    pub values: Vec<Value>,
+}
+
+/// Content of typed genev events
+#[derive(Clone, Debug, PartialEq, SerialEncodable, SerialDecodable)]
+pub enum Payload {
+    Schema(Schema),
+    Record(Record),
+}
+
+/// Filter on the records of a schema
+#[derive(Clone, Debug)]
+pub struct Query {
+    pub schema: Schema,
+    /// Positions of the filtered fields, and the value they must have
+    filters: Vec<(usize, Value)>,
+    /// Time range of the records, in milliseconds, the end being excluded
+    pub start: u64,
+    pub end: u64,
+}
+
+impl Query {
+    pub fn new(
+        schema: Schema,
+        filters: &HashMap<String, JsonValue>,
+        start: Option<u64>,
+        end: Option<u64>,
+    ) -> Result<Self> {
+        let mut ret = vec![];
+        for (name, value) in filters {
+            let Some((i, field)) = schema.field(name) else {
+                return Err(Error::Custom(format!("Unknown field: {}", name)))
+            };
+
+            let value = match value {
+                JsonValue::Null => Value::Null,
+                v => field.kind.from_json(v).ok_or_else(|| {
+                    Error::Custom(format!("Field {} should be of type {}", field.name, field.kind))
+                })?,
+            };
+            ret.push((i, value));
+        }
+
+        Ok(Self { schema, filters: ret, start: start.unwrap_or(0), end: end.unwrap_or(u64::MAX) })
+    }
+
+    /// Whether a record with provided timestamp matches the query
+    pub fn matches(&self, record: &Record, timestamp: u64) -> bool {
+        self.schema.validate(record).is_ok() &&
+            timestamp >= self.start &&
+            timestamp < self.end &&
+            self.filters.iter().all(|(i, value)| record.values[*i] == *value)
+    }
+}
+
+/// Persistent registry of schemas, by name, along with the timestamp of
+/// the event registering them
+#[derive(Clone)]
+pub struct SchemaRegistry(sled::Tree);
+
+impl SchemaRegistry {
+    pub fn new(sled_db: &sled::Db) -> Result<Self> {
+        Ok(Self(sled_db.open_tree(SLED_SCHEMAS_TREE)?))
+    }
+
+    pub fn get(&self, name: &str) -> Result<Option<Schema>> {
+        match self.0.get(name.as_bytes())? {
+            Some(bytes) => Ok(Some(deserialize::<(u64, Schema)>(&bytes)?.1)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn list(&self) -> Result<Vec<Schema>> {
+        let mut ret = vec![];
+        for item in self.0.iter() {
+            let (_, bytes) = item?;
+            ret.push(deserialize::<(u64, Schema)>(&bytes)?.1);
+        }
+        Ok(ret)
+    }
+
+    /// Register a schema published at provided timestamp. Its name is
+    /// bound to it unless an earlier registration, by timestamp and then
+    /// by schema ID, was already received. Returns `false` if the name is
+    /// bound to another schema.
+    pub fn insert(&self, schema: &Schema, timestamp: u64) -> Result<bool> {
+        loop {
+            let current = self.0.get(schema.name.as_bytes())?;
+            if let Some(bytes) = &current {
+                let (bound_at, bound): (u64, Schema) = deserialize(bytes)?;
+                if bound.id() == schema.id() && bound_at <= timestamp {
+                    return Ok(true)
+                }
+                if (bound_at, bound.id().as_bytes()) < (timestamp, schema.id().as_bytes()) {
+                    return Ok(false)
+                }
+            }
+
+            // Retry if another registration was bound meanwhile
+            let binding = serialize(&(timestamp, schema.clone()));
+            if self.0.compare_and_swap(schema.name.as_bytes(), current, Some(binding))?.is_ok() {
+                return Ok(true)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schema() -> Schema {
+        let fields = [("title", "string"), ("votes", "int"), ("closed", "bool?")];
+        let fields: Vec<_> = fields.iter().map(|(f, t)| (f.to_string(), t.to_string())).collect();
+        Schema::new("poll", &fields).unwrap()
+    }
+
+    fn values(json: &str) -> HashMap<String, JsonValue> {
+        json.parse::<JsonValue>().unwrap().get::<HashMap<String, JsonValue>>().unwrap().clone()
+    }
+
+    #[test]
+    fn schema_records() {
+        let schema = schema();
+        let mut fields = vec![];
+        for f in schema.fields.iter().rev() {
+            let kind = if f.optional { format!("{}?", f.kind) } else { f.kind.to_string() };
+            fields.push((f.name.clone(), kind));
+        }
+        assert_eq!(Schema::new("poll", &fields).unwrap().id(), schema.id());
+
+        let bad = [("a".to_string(), "int".to_string()), ("a".to_string(), "bool".to_string())];
+        assert!(Schema::new("bad", &bad).is_err());
+        assert!(Schema::new("bad", &[("a".to_string(), "date".to_string())]).is_err());
+
+        let record = schema.record(&values(r#"{"title": "lunch", "votes": 3}"#)).unwrap();
+        assert_eq!(record.values, vec![Value::Null, Value::String("lunch".into()), Value::Int(3)]);
+        schema.validate(&record).unwrap();
+        let record_json = schema.record_json(&record);
+        assert_eq!(schema.record(record_json.get().unwrap()).unwrap(), record);
+
+        assert!(schema.record(&values(r#"{"title": "lunch"}"#)).is_err());
+        assert!(schema.record(&values(r#"{"title": "lunch", "votes": 3.5}"#)).is_err());
+        assert!(schema.record(&values(r#"{"title": 1, "votes": 3}"#)).is_err());
+        assert!(schema.record(&values(r#"{"title": "a", "votes": 3, "x": 1}"#)).is_err());
+
+        let mut other = schema.clone();
+        other.fields[2].kind = FieldType::Float;
+        assert!(other.validate(&record).is_err());
+    }
+
+    #[test]
+    fn queries() {
+        let schema = schema();
+        let lunch = schema.record(&values(r#"{"title": "lunch", "votes": 3}"#)).unwrap();
+        let dinner =
+            schema.record(&values(r#"{"title": "dinner", "votes": 3, "closed": true}"#)).unwrap();
+
+        let query = Query::new(schema.clone(), &values(r#"{"votes": 3}"#), None, None).unwrap();
+        assert!(query.matches(&lunch, 0) && query.matches(&dinner, 0));
+
+        let query =
+            Query::new(schema.clone(), &values(r#"{"closed": null}"#), Some(10), Some(20)).unwrap();
+        assert!(query.matches(&lunch, 10));
+        assert!(!query.matches(&lunch, 20));
+        assert!(!query.matches(&dinner, 15));
+
+        assert!(Query::new(schema.clone(), &values(r#"{"votes": "3"}"#), None, None).is_err());
+        assert!(Query::new(schema, &values(r#"{"x": 3}"#), None, None).is_err());
+    }
+
+    #[test]
+    fn registry() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let registry = SchemaRegistry::new(&db).unwrap();
+        let schema = schema();
+
+        assert!(registry.insert(&schema, 10).unwrap());
+        assert!(registry.insert(&schema, 10).unwrap());
+        let mut other = schema.clone();
+        other.fields.remove(0);
+        assert!(!registry.insert(&other, 20).unwrap());
+        assert_eq!(registry.get("poll").unwrap(), Some(schema.clone()));
+        assert_eq!(registry.list().unwrap().len(), 1);
+
+        // An earlier registration received later takes the name over,
+        // so every node ends up with the same binding
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let late = SchemaRegistry::new(&db).unwrap();
+        assert!(late.insert(&other, 20).unwrap());
+        assert!(late.insert(&schema, 10).unwrap());
+        assert!(!late.insert(&other, 20).unwrap());
+        assert_eq!(late.get("poll").unwrap(), Some(schema.clone()));
+
+        // Registrations at the same time are ordered by schema ID
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tied = SchemaRegistry::new(&db).unwrap();
+        let first = if schema.id().as_bytes() < other.id().as_bytes() { &schema } else { &other };
+        tied.insert(&schema, 10).unwrap();
+        tied.insert(&other, 10).unwrap();
+        assert_eq!(tied.get("poll").unwrap().as_ref(), Some(first));
+
+        // Records of the conflicting schema don't match queries
+        let record = other.record(&values(r#"{"title": "lunch", "votes": 3}"#)).unwrap();
+        let query = Query::new(schema, &HashMap::new(), None, None).unwrap();
+        assert!(!query.matches(&record, 0));
+    }
+}
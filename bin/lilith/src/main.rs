@@ -20,14 +20,16 @@ use std::{
     collections::{HashMap, HashSet},
     process::exit,
     sync::Arc,
-    time::UNIX_EPOCH,
+    time::{Duration, UNIX_EPOCH},
 };
 
 use async_trait::async_trait;
 use log::{debug, error, info, warn};
 use semver::Version;
 use smol::{
+    io::{AsyncReadExt, AsyncWriteExt},
     lock::{Mutex, MutexGuard},
+    net::TcpListener,
     stream::StreamExt,
     Executor,
 };
@@ -44,14 +46,20 @@ use darkfi::{
         jsonrpc::*,
         server::{listen_and_serve, RequestHandler},
     },
-    system::{sleep, StoppableTask, StoppableTaskPtr},
-    util::path::get_config_path,
+    system::{sleep, timeout::timeout, StoppableTask, StoppableTaskPtr},
+    util::path::{expand_path, get_config_path},
     Error, Result,
 };
 
+mod metrics;
+use metrics::{prometheus, AsnDb, NetMetrics, Report, COLORS};
+
 const CONFIG_FILE: &str = "lilith_config.toml";
 const CONFIG_FILE_CONTENTS: &str = include_str!("../lilith_config.toml");
 
+/// Time to wait for a metrics request to be received (in seconds)
+const METRICS_READ_TIMEOUT: u64 = 10;
+
 #[derive(Clone, Debug, serde::Deserialize, StructOpt, StructOptToml)]
 #[serde(default)]
 #[structopt(name = "lilith", about = cli_desc!())]
@@ -75,6 +83,18 @@ struct Args {
     #[structopt(long, default_value = "120")]
     /// Interval after which to check whitelist peers
     whitelist_refinery_interval: u64,
+
+    #[structopt(long, default_value = "60")]
+    /// Interval after which to sample hostlists for churn metrics
+    metrics_interval: u64,
+
+    #[structopt(long)]
+    /// Prometheus metrics HTTP listen URL
+    metrics_listen: Option<Url>,
+
+    #[structopt(long)]
+    /// IP to ASN database (iptoasn.com TSV format) for host diversity metrics
+    asn_db: Option<String>,
 }
 
 /// Struct representing a spawned P2P network
@@ -83,6 +103,8 @@ struct Spawn {
     pub name: String,
     /// P2P pointer
     pub p2p: P2pPtr,
+    /// Health metrics
+    pub metrics: Arc<NetMetrics>,
 }
 
 impl Spawn {
@@ -130,6 +152,12 @@ impl Spawn {
             ("goldlist".to_string(), JsonValue::Array(self.get_goldlist().await)),
         ]))
     }
+
+    async fn report(&self, asn_db: Option<&AsnDb>) -> Report {
+        let hosts = self.p2p.hosts();
+        let hostlists = COLORS.map(|(color, _)| hosts.container.fetch_all(color));
+        self.metrics.report(hostlists, asn_db)
+    }
 }
 
 /// Defines the network-specific settings
@@ -155,6 +183,8 @@ struct NetInfo {
 struct Lilith {
     /// Spawned networks
     pub networks: Vec<Spawn>,
+    /// IP to ASN database used for host diversity metrics
+    pub asn_db: Option<AsnDb>,
     /// JSON-RPC connection tracker
     pub rpc_connections: Mutex<HashSet<StoppableTaskPtr>>,
 }
@@ -175,6 +205,7 @@ impl Lilith {
     async fn whitelist_refinery(
         network_name: String,
         p2p: P2pPtr,
+        metrics: Arc<NetMetrics>,
         refinery_interval: u64,
     ) -> Result<()> {
         debug!(target: "net::refinery::whitelist_refinery", "Starting whitelist refinery for \"{}\"",
@@ -194,6 +225,7 @@ impl Lilith {
                         debug!(target: "net::refinery::whitelist_refinery", "Addr={} not available!",
                        url.clone());
 
+                        metrics.refinery_skipped();
                         continue
                     }
 
@@ -201,6 +233,8 @@ impl Lilith {
                         debug!(target: "net::refinery:::whitelist_refinery",
                        "Host {} is not responsive. Downgrading from whitelist", url);
 
+                        let now = UNIX_EPOCH.elapsed().unwrap().as_secs();
+                        metrics.refinery_result(url, false, now);
                         hosts.greylist_host(url, *last_seen)?;
 
                         continue
@@ -211,6 +245,7 @@ impl Lilith {
 
                     // This node is active. Update the last seen field.
                     let last_seen = UNIX_EPOCH.elapsed().unwrap().as_secs();
+                    metrics.refinery_result(url, true, last_seen);
 
                     hosts.whitelist_host(url, last_seen)?;
                 }
@@ -223,6 +258,94 @@ impl Lilith {
             }
         }
     }
+    /// Periodically sample the hostlists of a network, to measure host churn.
+    async fn churn_monitor(p2p: P2pPtr, metrics: Arc<NetMetrics>, interval: u64) -> Result<()> {
+        loop {
+            let hosts = p2p.hosts();
+            metrics.sample(&COLORS.map(|(color, _)| {
+                hosts.container.fetch_all(color).into_iter().map(|(url, _)| url).collect()
+            }));
+
+            sleep(interval).await;
+        }
+    }
+
+    /// Count the outcomes of inbound handshakes of a network.
+    async fn handshake_monitor(p2p: P2pPtr, metrics: Arc<NetMetrics>) -> Result<()> {
+        let handshake_sub = p2p.session_inbound().subscribe_handshakes().await;
+        loop {
+            let (url, res) = handshake_sub.receive().await;
+            metrics.handshake_result(&url, &res);
+        }
+    }
+
+    /// Health reports of all spawned networks
+    async fn reports(&self) -> Vec<(String, Report)> {
+        let mut reports = vec![];
+        for spawn in &self.networks {
+            reports.push((spawn.name.clone(), spawn.report(self.asn_db.as_ref()).await));
+        }
+        reports
+    }
+
+    /// Serve the health metrics of all networks over HTTP, in the Prometheus
+    /// text format.
+    async fn metrics_server(
+        self: Arc<Self>,
+        listen: Url,
+        ex: Arc<Executor<'static>>,
+    ) -> Result<()> {
+        if !["tcp", "http"].contains(&listen.scheme()) {
+            return Err(Error::UnsupportedTransport(listen.scheme().to_string()))
+        }
+
+        let (Some(host), Some(port)) = (listen.host_str(), listen.port_or_known_default()) else {
+            return Err(Error::InvalidAddress)
+        };
+
+        let listener = TcpListener::bind((host, port)).await?;
+        loop {
+            let (mut stream, addr) = match listener.accept().await {
+                Ok(v) => v,
+                Err(e) => {
+                    // Likely out of file descriptors, back off a bit
+                    warn!(target: "lilith", "Failed accepting metrics connection: {}", e);
+                    sleep(1).await;
+                    continue
+                }
+            };
+
+            let self_ = self.clone();
+            ex.spawn(async move {
+                // Requests are small, we only check they're GETs
+                let mut buf = [0u8; 1024];
+                let read = stream.read(&mut buf);
+                let res = match timeout(Duration::from_secs(METRICS_READ_TIMEOUT), read).await {
+                    Ok(Ok(n)) if buf[..n].starts_with(b"GET ") => {
+                        ("200 OK", prometheus(&self_.reports().await))
+                    }
+                    Ok(_) => ("405 Method Not Allowed", String::new()),
+                    Err(_) => {
+                        debug!(target: "lilith", "Metrics request from {} timed out", addr);
+                        return
+                    }
+                };
+
+                let rep = format!(
+                    "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    res.0,
+                    res.1.len(),
+                    res.1
+                );
+
+                if let Err(e) = stream.write_all(rep.as_bytes()).await {
+                    debug!(target: "lilith", "Failed serving metrics to {}: {}", addr, e);
+                }
+            })
+            .detach();
+        }
+    }
+
     // RPCAPI:
     // Returns all spawned networks names with their node addresses.
     // --> {"jsonrpc": "2.0", "method": "spawns", "params": [], "id": 42}
@@ -238,6 +361,60 @@ impl Lilith {
 
         JsonResponse::new(json, id).into()
     }
+
+    // RPCAPI:
+    // Returns the health metrics of all spawned networks, or of the given one:
+    // hostlist sizes, hosts by transport, hosts added and removed since startup,
+    // whitelist refinery outcomes, inbound handshake outcomes by transport, and
+    // the network diversity of the advertised hosts.
+    // --> {"jsonrpc": "2.0", "method": "metrics", "params": [], "id": 42}
+    // --> {"jsonrpc": "2.0", "method": "metrics", "params": ["darkirc_v4"], "id": 42}
+    // <-- {"jsonrpc": "2.0", "result": {"darkirc_v4": {"hosts": {...}, "refinery": {...}, ...}}, "id": 42}
+    async fn metrics(&self, id: u16, params: JsonValue) -> JsonResult {
+        let params = params.get::<Vec<JsonValue>>().unwrap();
+        let network = match params.as_slice() {
+            [] => None,
+            [JsonValue::String(name)] => Some(name),
+            _ => return JsonError::new(ErrorCode::InvalidParams, None, id).into(),
+        };
+
+        let mut reports = HashMap::new();
+        for (name, report) in self.reports().await {
+            if network.is_none_or(|n| *n == name) {
+                reports.insert(name, (&report).into());
+            }
+        }
+
+        if network.is_some() && reports.is_empty() {
+            return JsonError::new(ErrorCode::InvalidParams, Some("Unknown network".to_string()), id)
+                .into()
+        }
+
+        JsonResponse::new(JsonValue::Object(reports), id).into()
+    }
+
+    // RPCAPI:
+    // Returns the known hosts of a network with their quality information:
+    // hostlist, transport, last seen timestamp, network group (ASN, or IP
+    // prefix without an ASN database) and country of IP hosts, and the
+    // timestamp and outcome of their last whitelist refinery ping.
+    // --> {"jsonrpc": "2.0", "method": "host_quality", "params": ["darkirc_v4"], "id": 42}
+    // <-- {"jsonrpc": "2.0", "result": [{"addr": "tcp+tls://...", "color": "white", ...}, ...], "id": 42}
+    async fn host_quality(&self, id: u16, params: JsonValue) -> JsonResult {
+        let params = params.get::<Vec<JsonValue>>().unwrap();
+        let [JsonValue::String(network)] = params.as_slice() else {
+            return JsonError::new(ErrorCode::InvalidParams, None, id).into()
+        };
+
+        let Some(spawn) = self.networks.iter().find(|s| s.name == *network) else {
+            return JsonError::new(ErrorCode::InvalidParams, Some("Unknown network".to_string()), id)
+                .into()
+        };
+
+        let report = spawn.report(self.asn_db.as_ref()).await;
+        let hosts = report.hosts.iter().map(|h| h.into()).collect();
+        JsonResponse::new(JsonValue::Array(hosts), id).into()
+    }
 }
 
 #[async_trait]
@@ -246,6 +423,8 @@ impl RequestHandler<()> for Lilith {
         return match req.method.as_str() {
             "ping" => self.pong(req.id, req.params).await,
             "spawns" => self.spawns(req.id, req.params).await,
+            "metrics" => self.metrics(req.id, req.params).await,
+            "host_quality" => self.host_quality(req.id, req.params).await,
             _ => JsonError::new(ErrorCode::MethodNotFound, None, req.id).into(),
         }
     }
@@ -374,7 +553,7 @@ async fn spawn_net(name: String, info: &NetInfo, ex: Arc<Executor<'static>>) ->
     info!(target: "lilith", "Starting seed network node for \"{}\" on {:?}", name, addrs_str);
     p2p.clone().start().await?;
 
-    let spawn = Spawn { name, p2p };
+    let spawn = Spawn { name, p2p, metrics: Arc::new(NetMetrics::default()) };
     Ok(spawn)
 }
 
@@ -402,14 +581,25 @@ async fn realmain(args: Args, ex: Arc<Executor<'static>>) -> Result<()> {
         }
     }
 
+    let asn_db = match args.asn_db {
+        Some(path) => {
+            let data = std::fs::read_to_string(expand_path(&path)?)?;
+            let asn_db = AsnDb::parse(&data)?;
+            info!(target: "lilith", "Loaded ASN database from {}", path);
+            Some(asn_db)
+        }
+        None => None,
+    };
+
     // Set up main daemon and background refinery_tasks
-    let lilith = Arc::new(Lilith { networks, rpc_connections: Mutex::new(HashSet::new()) });
+    let lilith = Arc::new(Lilith { networks, asn_db, rpc_connections: Mutex::new(HashSet::new()) });
     let mut refinery_tasks = HashMap::new();
+    let mut metrics_tasks = vec![];
     for network in &lilith.networks {
         let name = network.name.clone();
         let task = StoppableTask::new();
         task.clone().start(
-            Lilith::whitelist_refinery(name.clone(), network.p2p.clone(), args.whitelist_refinery_interval),
+            Lilith::whitelist_refinery(name.clone(), network.p2p.clone(), network.metrics.clone(), args.whitelist_refinery_interval),
             |res| async move {
                 match res {
                     Ok(()) | Err(Error::DetachedTaskStopped) => { /* Do nothing */ }
@@ -420,6 +610,47 @@ async fn realmain(args: Args, ex: Arc<Executor<'static>>) -> Result<()> {
             ex.clone(),
         );
         refinery_tasks.insert(network.name.clone(), task);
+
+        let task = StoppableTask::new();
+        task.clone().start(
+            Lilith::churn_monitor(
+                network.p2p.clone(),
+                network.metrics.clone(),
+                args.metrics_interval,
+            ),
+            |_| async { /* Do nothing */ },
+            Error::DetachedTaskStopped,
+            ex.clone(),
+        );
+        metrics_tasks.push(task);
+
+        let task = StoppableTask::new();
+        task.clone().start(
+            Lilith::handshake_monitor(network.p2p.clone(), network.metrics.clone()),
+            |_| async { /* Do nothing */ },
+            Error::DetachedTaskStopped,
+            ex.clone(),
+        );
+        metrics_tasks.push(task);
+    }
+
+    // Prometheus metrics server
+    let mut metrics_server_task = None;
+    if let Some(metrics_listen) = args.metrics_listen {
+        info!(target: "lilith", "Starting Prometheus metrics server on {}", metrics_listen);
+        let task = StoppableTask::new();
+        task.clone().start(
+            lilith.clone().metrics_server(metrics_listen, ex.clone()),
+            |res| async move {
+                match res {
+                    Ok(()) | Err(Error::DetachedTaskStopped) => { /* Do nothing */ }
+                    Err(e) => error!(target: "lilith", "Failed starting metrics server: {}", e),
+                }
+            },
+            Error::DetachedTaskStopped,
+            ex.clone(),
+        );
+        metrics_server_task = Some(task);
     }
 
     // JSON-RPC server
@@ -446,6 +677,14 @@ async fn realmain(args: Args, ex: Arc<Executor<'static>>) -> Result<()> {
     info!(target: "lilith", "Stopping JSON-RPC server...");
     rpc_task.stop().await;
 
+    info!(target: "lilith", "Stopping metrics tasks...");
+    if let Some(task) = metrics_server_task {
+        task.stop().await;
+    }
+    for task in metrics_tasks {
+        task.stop().await;
+    }
+
     // Cleanly stop p2p networks
     for spawn in &lilith.networks {
         info!(target: "lilith", "Stopping \"{}\" task", spawn.name);
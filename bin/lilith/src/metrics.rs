@@ -0,0 +1,615 @@
+/* This file is part of DarkFi (https://dark.fi)
+ *
+ * Copyright (C) 2020-2024 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Health metrics of the spawned seed networks.
+//!
+//! Each network keeps counters of its whitelist refinery outcomes and
+//! inbound handshakes, and periodic snapshots of its hostlists to measure
+//! host churn. Reports add the transport distribution of the hosts, and
+//! the network diversity of the advertised (white) hosts, grouped by ASN
+//! when an IP to ASN database is configured, or by IP prefix otherwise.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::Write,
+    net::IpAddr,
+    sync::Mutex,
+};
+
+use darkfi::{net::hosts::HostColor, Error, Result};
+use tinyjson::JsonValue;
+use url::{Host, Url};
+
+/// Hostlists covered by the metrics
+pub const COLORS: [(HostColor, &str); 3] =
+    [(HostColor::Grey, "grey"), (HostColor::White, "white"), (HostColor::Gold, "gold")];
+
+/// IP to ASN database, in the TSV format of <https://iptoasn.com>:
+/// `range_start  range_end  AS_number  country_code  AS_description`
+pub struct AsnDb {
+    /// Ranges sorted by their start, with their ASN and country
+    ranges: Vec<(u128, u128, u32, String)>,
+}
+
+/// Map IPv4 addresses into the IPv6 space, so both are ordered together
+fn ip_to_u128(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(ip) => u128::from(ip.to_ipv6_mapped()),
+        IpAddr::V6(ip) => u128::from(ip),
+    }
+}
+
+impl AsnDb {
+    pub fn parse(data: &str) -> Result<Self> {
+        let mut ranges = vec![];
+        for (i, line) in data.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue
+            }
+            let cols: Vec<&str> = line.split('\t').collect();
+
+            let (Some(start), Some(end), Some(asn), Some(country)) =
+                (cols.first(), cols.get(1), cols.get(2), cols.get(3))
+            else {
+                return Err(Error::ParseFailed("Invalid ASN database line"))
+            };
+
+            let (Ok(start), Ok(end), Ok(asn)) =
+                (start.parse::<IpAddr>(), end.parse::<IpAddr>(), asn.parse::<u32>())
+            else {
+                return Err(Error::Custom(format!("Invalid ASN database line {}", i + 1)))
+            };
+
+            // AS 0 marks unrouted ranges
+            if asn == 0 {
+                continue
+            }
+
+            ranges.push((ip_to_u128(start), ip_to_u128(end), asn, country.to_string()));
+        }
+
+        ranges.sort_unstable_by_key(|r| r.0);
+        Ok(Self { ranges })
+    }
+
+    /// Find the ASN and country of an IP address
+    pub fn lookup(&self, ip: IpAddr) -> Option<(u32, &str)> {
+        let ip = ip_to_u128(ip);
+        let i = self.ranges.partition_point(|r| r.0 <= ip);
+        let (_, end, asn, country) = self.ranges.get(i.checked_sub(1)?)?;
+        (ip <= *end).then_some((*asn, country.as_str()))
+    }
+}
+
+/// IP address of a host URL, if it isn't a domain name. IPv4 hosts of
+/// non-special schemes like `tcp` are parsed as domains, so they are
+/// parsed again here.
+fn host_ip(url: &Url) -> Option<IpAddr> {
+    match url.host()? {
+        Host::Ipv4(ip) => Some(IpAddr::V4(ip)),
+        Host::Ipv6(ip) => Some(IpAddr::V6(ip)),
+        Host::Domain(domain) => domain.parse().ok(),
+    }
+}
+
+/// Network group of an IP address: its ASN and country when found in the
+/// database, or its /16 (IPv4) or /32 (IPv6) prefix otherwise.
+fn ip_group(ip: IpAddr, asn_db: Option<&AsnDb>) -> (String, Option<String>) {
+    if let Some(db) = asn_db {
+        return match db.lookup(ip) {
+            Some((asn, country)) => (format!("AS{}", asn), Some(country.to_string())),
+            None => ("unknown".to_string(), None),
+        }
+    }
+
+    match ip {
+        IpAddr::V4(ip) => {
+            let o = ip.octets();
+            (format!("{}.{}.0.0/16", o[0], o[1]), None)
+        }
+        IpAddr::V6(ip) => {
+            let s = ip.segments();
+            (format!("{:x}:{:x}::/32", s[0], s[1]), None)
+        }
+    }
+}
+
+/// Outcomes of the whitelist refinery
+#[derive(Clone, Debug, Default)]
+pub struct RefineryStats {
+    /// Hosts that were pinged
+    pub attempts: u64,
+    /// Hosts that responded and were kept on the whitelist
+    pub successes: u64,
+    /// Hosts that didn't respond and were downgraded to the greylist
+    pub failures: u64,
+    /// Hosts that couldn't be refined, as they were in use
+    pub skipped: u64,
+}
+
+/// Outcomes of inbound handshakes over a transport
+#[derive(Clone, Debug, Default)]
+pub struct HandshakeStats {
+    pub ok: u64,
+    pub failed: u64,
+}
+
+#[derive(Default)]
+struct Churn {
+    /// Last snapshot of each hostlist
+    last: [HashSet<Url>; 3],
+    /// Hosts added to and removed from each hostlist since startup
+    added: [u64; 3],
+    removed: [u64; 3],
+}
+
+/// Health counters of a spawned network
+#[derive(Default)]
+pub struct NetMetrics {
+    refinery: Mutex<RefineryStats>,
+    /// Last refinery outcome of each host, with its timestamp
+    refined: Mutex<HashMap<Url, (u64, bool)>>,
+    /// Inbound handshake outcomes by transport
+    handshakes: Mutex<BTreeMap<String, HandshakeStats>>,
+    last_handshake_error: Mutex<Option<String>>,
+    churn: Mutex<Churn>,
+}
+
+impl NetMetrics {
+    pub fn refinery_skipped(&self) {
+        self.refinery.lock().unwrap().skipped += 1;
+    }
+
+    pub fn refinery_result(&self, url: &Url, ok: bool, timestamp: u64) {
+        let mut stats = self.refinery.lock().unwrap();
+        stats.attempts += 1;
+        if ok {
+            stats.successes += 1;
+        } else {
+            stats.failures += 1;
+        }
+        self.refined.lock().unwrap().insert(url.clone(), (timestamp, ok));
+    }
+
+    pub fn handshake_result(&self, url: &Url, res: &Result<()>) {
+        let mut handshakes = self.handshakes.lock().unwrap();
+        let stats = handshakes.entry(url.scheme().to_string()).or_default();
+        match res {
+            Ok(()) => stats.ok += 1,
+            Err(e) => {
+                stats.failed += 1;
+                *self.last_handshake_error.lock().unwrap() = Some(format!("{}: {}", url, e));
+            }
+        }
+    }
+
+    /// Record a snapshot of the hostlists, in the order of [`COLORS`],
+    /// counting the hosts added and removed since the last one
+    pub fn sample(&self, hostlists: &[Vec<Url>; 3]) {
+        let mut churn = self.churn.lock().unwrap();
+        for (i, hosts) in hostlists.iter().enumerate() {
+            let hosts: HashSet<Url> = hosts.iter().cloned().collect();
+            churn.added[i] += hosts.difference(&churn.last[i]).count() as u64;
+            churn.removed[i] += churn.last[i].difference(&hosts).count() as u64;
+            churn.last[i] = hosts;
+        }
+
+        // Forget the refinery outcomes of hosts we no longer know about
+        let known: HashSet<&Url> = churn.last.iter().flatten().collect();
+        self.refined.lock().unwrap().retain(|url, _| known.contains(url));
+    }
+
+    /// Build a report of the network health, given its current hostlists
+    /// in the order of [`COLORS`]
+    pub fn report(&self, hostlists: [Vec<(Url, u64)>; 3], asn_db: Option<&AsnDb>) -> Report {
+        let churn = self.churn.lock().unwrap();
+        let refined = self.refined.lock().unwrap();
+
+        let mut transports = BTreeMap::new();
+        let mut hosts = vec![];
+        for (i, list) in hostlists.iter().enumerate() {
+            for (url, last_seen) in list {
+                *transports.entry((COLORS[i].1, url.scheme().to_string())).or_insert(0) += 1;
+
+                let ip = host_ip(url);
+                let (group, country) = match ip {
+                    Some(ip) => ip_group(ip, asn_db),
+                    None => (String::new(), None),
+                };
+
+                hosts.push(HostQuality {
+                    url: url.clone(),
+                    color: COLORS[i].1,
+                    last_seen: *last_seen,
+                    group: ip.map(|_| group),
+                    country,
+                    refined: refined.get(url).copied(),
+                });
+            }
+        }
+
+        // Diversity of the advertised hosts
+        let mut groups: HashMap<&str, usize> = HashMap::new();
+        let mut countries = HashSet::new();
+        let mut diversity = Diversity { by_asn: asn_db.is_some(), ..Default::default() };
+        for host in hosts.iter().filter(|h| h.color == "white") {
+            match &host.group {
+                Some(group) => {
+                    diversity.ip_hosts += 1;
+                    *groups.entry(group).or_insert(0) += 1;
+                    countries.extend(host.country.as_deref());
+                }
+                None => diversity.non_ip_hosts += 1,
+            }
+        }
+        diversity.groups = groups.len();
+        diversity.countries = countries.len();
+        diversity.largest_group = groups.values().max().copied().unwrap_or(0);
+
+        Report {
+            hosts,
+            hostlist_sizes: [hostlists[0].len(), hostlists[1].len(), hostlists[2].len()],
+            transports,
+            added: churn.added,
+            removed: churn.removed,
+            refinery: self.refinery.lock().unwrap().clone(),
+            handshakes: self.handshakes.lock().unwrap().clone(),
+            last_handshake_error: self.last_handshake_error.lock().unwrap().clone(),
+            diversity,
+        }
+    }
+}
+
+/// Quality information of a known host
+pub struct HostQuality {
+    pub url: Url,
+    pub color: &'static str,
+    pub last_seen: u64,
+    /// Network group of IP hosts
+    pub group: Option<String>,
+    pub country: Option<String>,
+    /// Timestamp and outcome of the last refinery ping
+    pub refined: Option<(u64, bool)>,
+}
+
+impl From<&HostQuality> for JsonValue {
+    fn from(host: &HostQuality) -> JsonValue {
+        let opt = |s: &Option<String>| s.clone().map_or(JsonValue::Null, JsonValue::String);
+        let (refined_at, refine_ok) = match host.refined {
+            Some((ts, ok)) => (JsonValue::Number(ts as f64), JsonValue::Boolean(ok)),
+            None => (JsonValue::Null, JsonValue::Null),
+        };
+
+        JsonValue::Object(HashMap::from([
+            ("addr".to_string(), JsonValue::String(host.url.to_string())),
+            ("color".to_string(), JsonValue::String(host.color.to_string())),
+            ("transport".to_string(), JsonValue::String(host.url.scheme().to_string())),
+            ("last_seen".to_string(), JsonValue::Number(host.last_seen as f64)),
+            ("group".to_string(), opt(&host.group)),
+            ("country".to_string(), opt(&host.country)),
+            ("refined_at".to_string(), refined_at),
+            ("refine_ok".to_string(), refine_ok),
+        ]))
+    }
+}
+
+/// Network diversity of the advertised hosts
+#[derive(Debug, Default)]
+pub struct Diversity {
+    /// Whether hosts are grouped by ASN, rather than by IP prefix
+    pub by_asn: bool,
+    pub ip_hosts: usize,
+    /// Hosts with a domain name, like Tor onion addresses
+    pub non_ip_hosts: usize,
+    pub groups: usize,
+    pub countries: usize,
+    /// Number of hosts in the largest group
+    pub largest_group: usize,
+}
+
+impl Diversity {
+    /// Share of the IP hosts in the largest group
+    pub fn largest_group_ratio(&self) -> f64 {
+        if self.ip_hosts == 0 {
+            return 0.0
+        }
+        self.largest_group as f64 / self.ip_hosts as f64
+    }
+}
+
+/// Health report of a network
+pub struct Report {
+    pub hosts: Vec<HostQuality>,
+    /// Number of hosts of each hostlist, in the order of [`COLORS`]
+    pub hostlist_sizes: [usize; 3],
+    /// Number of hosts by hostlist and transport
+    pub transports: BTreeMap<(&'static str, String), usize>,
+    pub added: [u64; 3],
+    pub removed: [u64; 3],
+    pub refinery: RefineryStats,
+    pub handshakes: BTreeMap<String, HandshakeStats>,
+    pub last_handshake_error: Option<String>,
+    pub diversity: Diversity,
+}
+
+impl From<&Report> for JsonValue {
+    fn from(report: &Report) -> JsonValue {
+        let num = |n: u64| JsonValue::Number(n as f64);
+        let per_color = |f: &dyn Fn(usize) -> u64| {
+            JsonValue::Object(
+                COLORS.iter().enumerate().map(|(i, c)| (c.1.to_string(), num(f(i)))).collect(),
+            )
+        };
+
+        let mut transports: HashMap<String, JsonValue> =
+            COLORS.iter().map(|c| (c.1.to_string(), JsonValue::Object(HashMap::new()))).collect();
+        for ((color, transport), count) in &report.transports {
+            let JsonValue::Object(map) = transports.get_mut(*color).unwrap() else {
+                unreachable!()
+            };
+            map.insert(transport.clone(), num(*count as u64));
+        }
+
+        let refinery = &report.refinery;
+        let success_rate = if refinery.attempts == 0 {
+            JsonValue::Null
+        } else {
+            JsonValue::Number(refinery.successes as f64 / refinery.attempts as f64)
+        };
+
+        let handshakes = report
+            .handshakes
+            .iter()
+            .map(|(transport, stats)| {
+                let stats = HashMap::from([
+                    ("ok".to_string(), num(stats.ok)),
+                    ("failed".to_string(), num(stats.failed)),
+                ]);
+                (transport.clone(), JsonValue::Object(stats))
+            })
+            .collect();
+
+        let d = &report.diversity;
+        let diversity = HashMap::from([
+            (
+                "grouping".to_string(),
+                JsonValue::String(if d.by_asn { "asn" } else { "prefix" }.to_string()),
+            ),
+            ("ip_hosts".to_string(), num(d.ip_hosts as u64)),
+            ("non_ip_hosts".to_string(), num(d.non_ip_hosts as u64)),
+            ("groups".to_string(), num(d.groups as u64)),
+            ("countries".to_string(), num(d.countries as u64)),
+            ("largest_group_ratio".to_string(), JsonValue::Number(d.largest_group_ratio())),
+        ]);
+
+        JsonValue::Object(HashMap::from([
+            ("hosts".to_string(), per_color(&|i| report.hostlist_sizes[i] as u64)),
+            ("transports".to_string(), JsonValue::Object(transports)),
+            ("added".to_string(), per_color(&|i| report.added[i])),
+            ("removed".to_string(), per_color(&|i| report.removed[i])),
+            (
+                "refinery".to_string(),
+                JsonValue::Object(HashMap::from([
+                    ("attempts".to_string(), num(refinery.attempts)),
+                    ("successes".to_string(), num(refinery.successes)),
+                    ("failures".to_string(), num(refinery.failures)),
+                    ("skipped".to_string(), num(refinery.skipped)),
+                    ("success_rate".to_string(), success_rate),
+                ])),
+            ),
+            ("handshakes".to_string(), JsonValue::Object(handshakes)),
+            (
+                "last_handshake_error".to_string(),
+                report.last_handshake_error.clone().map_or(JsonValue::Null, JsonValue::String),
+            ),
+            ("diversity".to_string(), JsonValue::Object(diversity)),
+        ]))
+    }
+}
+
+/// Render the reports of all networks in the Prometheus text format
+pub fn prometheus(reports: &[(String, Report)]) -> String {
+    let mut out = String::new();
+
+    let mut metric = |name: &str, kind: &str, help: &str, samples: Vec<(String, f64)>| {
+        writeln!(out, "# HELP lilith_{} {}", name, help).unwrap();
+        writeln!(out, "# TYPE lilith_{} {}", name, kind).unwrap();
+        for (labels, value) in samples {
+            writeln!(out, "lilith_{}{{{}}} {}", name, labels, value).unwrap();
+        }
+    };
+
+    // Label values are network names, transports and colors, none of
+    // which contain characters needing escaping.
+    let per_color = |f: &dyn Fn(&Report, usize) -> f64| {
+        let mut samples = vec![];
+        for (net, report) in reports {
+            for (i, (_, color)) in COLORS.iter().enumerate() {
+                samples.push((format!("network=\"{}\",color=\"{}\"", net, color), f(report, i)));
+            }
+        }
+        samples
+    };
+    let per_net = |f: &dyn Fn(&Report) -> f64| {
+        reports.iter().map(|(net, r)| (format!("network=\"{}\"", net), f(r))).collect()
+    };
+
+    metric(
+        "hosts",
+        "gauge",
+        "Number of hosts in a hostlist",
+        per_color(&|r, i| r.hostlist_sizes[i] as f64),
+    );
+
+    let mut samples = vec![];
+    for (net, report) in reports {
+        for ((color, transport), count) in &report.transports {
+            let labels =
+                format!("network=\"{}\",color=\"{}\",transport=\"{}\"", net, color, transport);
+            samples.push((labels, *count as f64));
+        }
+    }
+    metric("hosts_by_transport", "gauge", "Number of hosts in a hostlist by transport", samples);
+
+    metric(
+        "hosts_added_total",
+        "counter",
+        "Hosts added to a hostlist",
+        per_color(&|r, i| r.added[i] as f64),
+    );
+    metric(
+        "hosts_removed_total",
+        "counter",
+        "Hosts removed from a hostlist",
+        per_color(&|r, i| r.removed[i] as f64),
+    );
+
+    metric(
+        "refinery_attempts_total",
+        "counter",
+        "Whitelist hosts pinged by the refinery",
+        per_net(&|r| r.refinery.attempts as f64),
+    );
+    metric(
+        "refinery_successes_total",
+        "counter",
+        "Whitelist hosts found responsive by the refinery",
+        per_net(&|r| r.refinery.successes as f64),
+    );
+    metric(
+        "refinery_failures_total",
+        "counter",
+        "Whitelist hosts downgraded by the refinery",
+        per_net(&|r| r.refinery.failures as f64),
+    );
+    metric(
+        "refinery_skipped_total",
+        "counter",
+        "Whitelist hosts the refinery couldn't ping",
+        per_net(&|r| r.refinery.skipped as f64),
+    );
+
+    let mut samples = vec![];
+    for (net, report) in reports {
+        for (transport, stats) in &report.handshakes {
+            for (result, count) in [("ok", stats.ok), ("failed", stats.failed)] {
+                let labels = format!(
+                    "network=\"{}\",transport=\"{}\",result=\"{}\"",
+                    net, transport, result
+                );
+                samples.push((labels, count as f64));
+            }
+        }
+    }
+    metric("inbound_handshakes_total", "counter", "Inbound handshakes by outcome", samples);
+
+    metric(
+        "advertised_ip_hosts",
+        "gauge",
+        "Whitelist hosts with an IP address",
+        per_net(&|r| r.diversity.ip_hosts as f64),
+    );
+    metric(
+        "advertised_network_groups",
+        "gauge",
+        "Distinct ASNs, or IP prefixes, of whitelist hosts",
+        per_net(&|r| r.diversity.groups as f64),
+    );
+    metric(
+        "advertised_countries",
+        "gauge",
+        "Distinct countries of whitelist hosts",
+        per_net(&|r| r.diversity.countries as f64),
+    );
+    metric(
+        "advertised_largest_group_ratio",
+        "gauge",
+        "Share of whitelist IP hosts in the largest network group",
+        per_net(&|r| r.diversity.largest_group_ratio()),
+    );
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ASN_DB: &str = "1.0.0.0\t1.0.0.255\t13335\tUS\tCLOUDFLARENET\n\
+                          1.0.1.0\t1.0.3.255\t0\tNone\tNot routed\n\
+                          2.0.0.0\t2.0.255.255\t3215\tFR\tOrange\n\
+                          2001:db8::\t2001:db8::ffff\t64496\tDE\tExample\n";
+
+    fn url(s: &str) -> Url {
+        Url::parse(s).unwrap()
+    }
+
+    #[test]
+    fn asn_lookup() {
+        let db = AsnDb::parse(ASN_DB).unwrap();
+        assert_eq!(db.lookup("1.0.0.7".parse().unwrap()), Some((13335, "US")));
+        assert_eq!(db.lookup("1.0.2.1".parse().unwrap()), None);
+        assert_eq!(db.lookup("2.0.4.1".parse().unwrap()), Some((3215, "FR")));
+        assert_eq!(db.lookup("2001:db8::1".parse().unwrap()), Some((64496, "DE")));
+        assert_eq!(db.lookup("0.1.1.1".parse().unwrap()), None);
+        assert!(AsnDb::parse("1.0.0.0\t1.0.0.255\tAS1\tUS\n").is_err());
+    }
+
+    #[test]
+    fn health_report() {
+        let metrics = NetMetrics::default();
+        let (a, b, c) =
+            (url("tcp://1.0.0.1:1"), url("tcp+tls://2.0.0.1:1"), url("tor://x.onion:1"));
+
+        metrics.sample(&[vec![a.clone()], vec![], vec![]]);
+        metrics.sample(&[vec![], vec![a.clone(), b.clone(), c.clone()], vec![]]);
+        metrics.refinery_result(&a, true, 10);
+        metrics.refinery_result(&b, false, 11);
+        metrics.refinery_skipped();
+        metrics.handshake_result(&a, &Ok(()));
+        metrics.handshake_result(&a, &Err(Error::ChannelTimeout));
+
+        let white =
+            vec![(a.clone(), 1), (b.clone(), 1), (c.clone(), 1), (url("tcp://1.0.0.2:1"), 1)];
+        let db = AsnDb::parse(ASN_DB).unwrap();
+        let report = metrics.report([vec![], white.clone(), vec![]], Some(&db));
+
+        assert_eq!(report.added, [1, 3, 0]);
+        assert_eq!(report.removed, [1, 0, 0]);
+        assert_eq!(report.refinery.attempts, 2);
+        assert_eq!(report.refinery.skipped, 1);
+        assert_eq!(report.handshakes["tcp"].failed, 1);
+        assert_eq!(report.transports[&("white", "tcp".to_string())], 2);
+        assert_eq!(report.hosts[1].refined, Some((11, false)));
+
+        let d = &report.diversity;
+        assert_eq!((d.ip_hosts, d.non_ip_hosts, d.groups, d.countries), (3, 1, 2, 2));
+        assert!((d.largest_group_ratio() - 2.0 / 3.0).abs() < f64::EPSILON);
+
+        // Without a database, hosts are grouped by prefix
+        let report = metrics.report([vec![], white, vec![]], None);
+        assert_eq!(report.diversity.groups, 2);
+        assert_eq!(report.hosts[0].group.as_deref(), Some("1.0.0.0/16"));
+
+        let text = prometheus(&[("net".to_string(), report)]);
+        assert!(text.contains("lilith_hosts{network=\"net\",color=\"white\"} 4\n"));
+        assert!(text.contains(
+            "lilith_inbound_handshakes_total{network=\"net\",transport=\"tcp\",result=\"failed\"} 1\n"
+        ));
+    }
+}
@@ -65,6 +65,9 @@ async def change_task_status(refid, status, server_name, port):
 async def modify_task(refid, changes, server_name, port):
     return await query("modify", [refid, changes], server_name, int(port))
 
+async def get_relations(refid, server_name, port):
+    return await query("get_relations", [refid], server_name, int(port))
+
 async def switch_workspace(workspace, server_name, port):
     return await query("switch_ws", [workspace], server_name, int(port))
 
@@ -0,0 +1,660 @@
+/* This file is part of DarkFi (https://dark.fi)
+ *
+ * Copyright (C) 2020-2024 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Workspace membership and access control.
+//!
+//! Every member of a workspace has a secret of their own, from which an
+//! Ed25519 keypair signing their events and a X25519 key are derived.
+//! Admins publish the workspace's roster, mapping the identities of its
+//! members to a [`Role`], as events signed with their key. The holder of
+//! the workspace's `write_key` is the root of trust and always an admin.
+//!
+//! Tasks are encrypted with the workspace secret of the latest epoch.
+//! Removing a member rotates it: a new secret is generated, and the
+//! secrets of every epoch are encrypted to each remaining member in the
+//! roster. Rosters themselves are encrypted with the configured
+//! `read_key`, so members joining later can read them and recover the
+//! secrets, while removed members can't read what follows their removal.
+//!
+//! Every roster update is derived from a base roster, identified by its
+//! version and signer, and has the version following it. The signer has
+//! to be an admin of the base roster, so whether an update is accepted
+//! doesn't depend on the roster a replica has when it receives it, and
+//! updates derived from a roster not received yet wait for it.
+//! Roster updates are ordered by their version, and then by the key of
+//! the admin who signed them, and the highest one wins, so replicas end
+//! up with the same roster whatever the order they receive updates in.
+//! Secrets of rotations made by losing updates are kept, so what was
+//! sent with them can still be read.
+
+use std::{collections::BTreeMap, fmt, str::FromStr};
+
+use crypto_box::{
+    aead::{Aead, AeadCore},
+    ChaChaBox, PublicKey, SecretKey,
+};
+use darkfi::{Error, Result};
+use darkfi_serial::{
+    async_trait, deserialize, serialize, FutAsyncWriteExt, SerialDecodable, SerialEncodable,
+};
+use rand::{rngs::OsRng, RngCore};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use sled_overlay::sled;
+
+use crate::{
+    crdt::{OpKind, TaskUpdate},
+    error::{TaudError, TaudResult},
+};
+
+/// sled tree holding the access control state of workspaces
+const SLED_ACL_TREE: &[u8] = b"tau_acl";
+
+/// blake3 context deriving the X25519 key of a member from their secret
+const MEMBER_BOX_KEY_CONTEXT: &str = "darkfi taud member box key";
+
+/// Permissions of a workspace member, each role having the permissions
+/// of the ones before it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, SerialEncodable, SerialDecodable)]
+pub enum Role {
+    /// Decrypt the workspace's tasks
+    Read,
+    /// Comment on tasks
+    Comment,
+    /// Add and modify tasks
+    Edit,
+    /// Manage the roster
+    Admin,
+}
+
+impl Role {
+    /// Whether a member with this role may publish provided task update
+    pub fn can_update(&self, update: &TaskUpdate) -> bool {
+        match self {
+            Role::Read => false,
+            Role::Comment => update.ops.iter().all(|op| match &op.kind {
+                OpKind::Comment(_) => true,
+                OpKind::Event(event) => event.action == "comment",
+                _ => false,
+            }),
+            Role::Edit | Role::Admin => true,
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let role = match self {
+            Role::Read => "read",
+            Role::Comment => "comment",
+            Role::Edit => "edit",
+            Role::Admin => "admin",
+        };
+        write!(f, "{}", role)
+    }
+}
+
+impl FromStr for Role {
+    type Err = Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(Role::Read),
+            "comment" => Ok(Role::Comment),
+            "edit" => Ok(Role::Edit),
+            "admin" => Ok(Role::Admin),
+            _ => Err(Error::ParseFailed("unable to parse role")),
+        }
+    }
+}
+
+/// Public identity of a workspace member, shared as base58
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, SerialEncodable, SerialDecodable)]
+pub struct MemberId {
+    /// Ed25519 public key verifying the member's events
+    pub sign_key: [u8; 32],
+    /// X25519 public key workspace secrets are encrypted to
+    pub box_key: [u8; 32],
+}
+
+impl fmt::Display for MemberId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bytes = [self.sign_key, self.box_key].concat();
+        write!(f, "{}", bs58::encode(bytes).into_string())
+    }
+}
+
+impl FromStr for MemberId {
+    type Err = Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let Ok(bytes) = bs58::decode(s).into_vec() else {
+            return Err(Error::ParseFailed("Member id not valid base58"))
+        };
+        if bytes.len() != 64 {
+            return Err(Error::ParseFailed("Member id not 64 bytes long"))
+        }
+
+        Ok(Self {
+            sign_key: bytes[..32].try_into().unwrap(),
+            box_key: bytes[32..].try_into().unwrap(),
+        })
+    }
+}
+
+/// Keys of a workspace member, derived from their secret
+pub struct MemberKey {
+    sign_key: Ed25519KeyPair,
+    box_key: SecretKey,
+}
+
+impl MemberKey {
+    pub fn new(secret: &[u8; 32]) -> Self {
+        Self {
+            sign_key: Ed25519KeyPair::from_seed_unchecked(secret).unwrap(),
+            box_key: SecretKey::from(blake3::derive_key(MEMBER_BOX_KEY_CONTEXT, secret)),
+        }
+    }
+
+    pub fn id(&self) -> MemberId {
+        MemberId {
+            sign_key: self.sign_key.public_key().as_ref().try_into().unwrap(),
+            box_key: self.box_key.public_key().to_bytes(),
+        }
+    }
+
+    /// Keypair signing the member's events
+    pub fn sign_key(&self) -> &Ed25519KeyPair {
+        &self.sign_key
+    }
+
+    /// Decrypt secrets encrypted to this member
+    fn open(&self, ephemeral: &[u8; 32], sealed: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < 24 {
+            return None
+        }
+        let chacha_box = ChaChaBox::new(&PublicKey::from(*ephemeral), &self.box_key);
+        chacha_box.decrypt(sealed[..24].into(), &sealed[24..]).ok()
+    }
+}
+
+/// Build the box encrypting events with a workspace secret
+pub fn secret_box(secret: &[u8; 32]) -> ChaChaBox {
+    let secret_key = SecretKey::from(*secret);
+    ChaChaBox::new(&secret_key.public_key(), &secret_key)
+}
+
+/// Members of a workspace, published by its admins
+#[derive(Clone, Debug, Default, PartialEq, SerialEncodable, SerialDecodable)]
+pub struct Roster {
+    /// Version of the roster this one was derived from, plus one
+    pub version: u64,
+    /// Version of the roster this one was derived from
+    pub base_version: u64,
+    /// Key of the admin who signed the roster this one was derived from
+    pub base_signer: Vec<u8>,
+    /// Incremented every time the workspace secret is rotated
+    pub epoch: u32,
+    /// Epoch of the roster this one was derived from
+    pub base_epoch: u32,
+    /// Members removed from the roster this one was derived from
+    pub removed: Vec<MemberId>,
+    pub members: BTreeMap<MemberId, Role>,
+    /// Public key the workspace secrets were encrypted with
+    pub ephemeral: [u8; 32],
+    /// Workspace secrets of every epoch after the first, encrypted to
+    /// each member
+    pub secrets: BTreeMap<MemberId, Vec<u8>>,
+}
+
+/// Access control state of a workspace
+#[derive(Clone, Debug, SerialEncodable, SerialDecodable)]
+pub struct AclState {
+    pub roster: Roster,
+    /// Key of the admin who signed the roster
+    signer: Vec<u8>,
+    /// Workspace secrets of the roster by epoch, the first being the
+    /// `read_key`
+    secrets: Vec<[u8; 32]>,
+    /// Secrets of rotations made by roster updates that lost
+    retired: Vec<[u8; 32]>,
+    /// Members of every roster received, by version and signer, which
+    /// the updates derived from them are checked against
+    history: BTreeMap<(u64, Vec<u8>), BTreeMap<MemberId, Role>>,
+    /// Roster updates derived from a roster not received yet, by signer
+    pending: Vec<(Roster, Vec<u8>)>,
+}
+
+impl AclState {
+    pub fn new(read_key: [u8; 32]) -> Self {
+        Self {
+            roster: Roster::default(),
+            signer: vec![],
+            secrets: vec![read_key],
+            retired: vec![],
+            // The initial roster only has the root as admin
+            history: BTreeMap::from([((0, vec![]), BTreeMap::new())]),
+            pending: vec![],
+        }
+    }
+
+    /// Known workspace secrets, latest first
+    pub fn secrets(&self) -> impl Iterator<Item = &[u8; 32]> {
+        self.secrets.iter().rev().chain(self.retired.iter())
+    }
+
+    /// Secret of the latest known epoch, encrypting new tasks
+    pub fn secret(&self) -> &[u8; 32] {
+        self.secrets.last().unwrap()
+    }
+
+    /// Secret of the first epoch, encrypting rosters
+    pub fn read_key(&self) -> &[u8; 32] {
+        &self.secrets[0]
+    }
+
+    /// Role of the signer of an event. `root` is the public part of the
+    /// workspace's `write_key`.
+    pub fn role(&self, signer: &[u8], root: &[u8]) -> Option<Role> {
+        Self::member_role(&self.roster.members, signer, root)
+    }
+
+    fn member_role(members: &BTreeMap<MemberId, Role>, signer: &[u8], root: &[u8]) -> Option<Role> {
+        if signer == root {
+            return Some(Role::Admin)
+        }
+
+        members.iter().find(|(id, _)| id.sign_key == signer).map(|(_, role)| *role)
+    }
+
+    /// Roster derived from ours, which the changes are made to
+    fn derive(&self) -> Roster {
+        let mut roster = self.roster.clone();
+        roster.version += 1;
+        roster.base_version = self.roster.version;
+        roster.base_signer.clone_from(&self.signer);
+        roster.base_epoch = roster.epoch;
+        roster
+    }
+
+    /// Roster adding provided member, or changing their role
+    pub fn set_member(&self, id: MemberId, role: Role) -> Roster {
+        let mut roster = self.derive();
+        roster.removed = vec![];
+        roster.members.insert(id, role);
+        Self::seal(roster, &self.secrets)
+    }
+
+    /// Roster removing provided member, and rotating the workspace secret
+    pub fn remove_member(&self, id: &MemberId) -> TaudResult<Roster> {
+        let mut roster = self.derive();
+        if roster.members.remove(id).is_none() {
+            return Err(TaudError::InvalidData(format!("{} is not a member", id)))
+        }
+        roster.epoch += 1;
+        roster.removed = vec![id.clone()];
+
+        let mut secret = [0u8; 32];
+        OsRng.fill_bytes(&mut secret);
+        let mut secrets = self.secrets.clone();
+        secrets.push(secret);
+
+        Ok(Self::seal(roster, &secrets))
+    }
+
+    /// Encrypt the secrets of every epoch after the first to each member
+    fn seal(mut roster: Roster, secrets: &[[u8; 32]]) -> Roster {
+        let ephemeral = SecretKey::generate(&mut OsRng);
+        let plaintext = serialize(&secrets[1..].to_vec());
+
+        roster.ephemeral = ephemeral.public_key().to_bytes();
+        roster.secrets = roster
+            .members
+            .keys()
+            .map(|id| {
+                let chacha_box = ChaChaBox::new(&PublicKey::from(id.box_key), &ephemeral);
+                let nonce = ChaChaBox::generate_nonce(&mut OsRng);
+                let mut sealed = nonce.to_vec();
+                sealed.extend(chacha_box.encrypt(&nonce, &plaintext[..]).unwrap());
+                (id.clone(), sealed)
+            })
+            .collect();
+
+        roster
+    }
+
+    /// Apply a roster update signed by `signer`, who has to be an admin
+    /// of the roster it was derived from. `root` is the public part of the
+    /// workspace's `write_key`. The update replaces the roster if it's
+    /// ordered after it, otherwise only the secrets it holds are kept.
+    /// If `key` is a member of the update, the workspace secrets are
+    /// recovered from it. Updates derived from a roster we don't have
+    /// yet are applied once it arrives.
+    pub fn apply(
+        &mut self,
+        roster: Roster,
+        signer: &[u8],
+        root: &[u8],
+        key: Option<&MemberKey>,
+    ) -> TaudResult<()> {
+        if roster.base_version.checked_add(1) != Some(roster.version) {
+            return Err(TaudError::InvalidData("Roster version mismatch".into()))
+        }
+
+        let base = (roster.base_version, roster.base_signer.clone());
+        let Some(base_members) = self.history.get(&base) else {
+            let update = (roster, signer.to_vec());
+            if !self.pending.contains(&update) {
+                self.pending.push(update);
+            }
+            return Ok(())
+        };
+
+        if Self::member_role(base_members, signer, root) != Some(Role::Admin) {
+            return Err(TaudError::InvalidData("Roster not signed by an admin".into()))
+        }
+
+        // Removals are checked against the roster the update was
+        // derived from, rather than ours, which may differ.
+        if roster.removed.iter().any(|id| roster.members.contains_key(id)) {
+            return Err(TaudError::InvalidData("Removed member still in roster".into()))
+        }
+        if roster.epoch != roster.base_epoch + !roster.removed.is_empty() as u32 {
+            return Err(TaudError::InvalidData("Roster epoch mismatch".into()))
+        }
+
+        let secrets = key
+            .and_then(|key| {
+                let sealed = roster.secrets.get(&key.id())?;
+                key.open(&roster.ephemeral, sealed)
+            })
+            .and_then(|plaintext| deserialize::<Vec<[u8; 32]>>(&plaintext).ok())
+            .filter(|secrets| secrets.len() == roster.epoch as usize);
+
+        let version = (roster.version, signer.to_vec());
+        let is_new = !self.history.contains_key(&version);
+        self.history.insert(version, roster.members.clone());
+
+        if (roster.version, signer) <= (self.roster.version, self.signer.as_slice()) {
+            for secret in secrets.into_iter().flatten() {
+                self.retire(secret);
+            }
+        } else {
+            if let Some(secrets) = secrets {
+                for secret in self.secrets.split_off(1) {
+                    if !secrets.contains(&secret) {
+                        self.retire(secret);
+                    }
+                }
+                self.retired.retain(|secret| !secrets.contains(secret));
+                self.secrets.extend(secrets);
+            }
+
+            self.roster = roster;
+            self.signer = signer.to_vec();
+        }
+
+        if is_new {
+            self.apply_pending(root, key);
+        }
+        Ok(())
+    }
+
+    /// Apply the pending updates whose base roster arrived
+    fn apply_pending(&mut self, root: &[u8], key: Option<&MemberKey>) {
+        let (ready, pending) = std::mem::take(&mut self.pending).into_iter().partition(
+            |(roster, _): &(Roster, Vec<u8>)| {
+                self.history.contains_key(&(roster.base_version, roster.base_signer.clone()))
+            },
+        );
+        self.pending = pending;
+
+        for (roster, signer) in ready {
+            // Invalid updates are dropped, as they would have been if
+            // they had arrived after their base
+            let _ = self.apply(roster, &signer, root, key);
+        }
+    }
+
+    /// Keep a secret which isn't part of the roster
+    fn retire(&mut self, secret: [u8; 32]) {
+        if !self.secrets.contains(&secret) && !self.retired.contains(&secret) {
+            self.retired.push(secret);
+        }
+    }
+}
+
+/// Persistent store of the access control state of workspaces
+#[derive(Clone)]
+pub struct AclStore(sled::Tree);
+
+impl AclStore {
+    pub fn new(sled_db: &sled::Db) -> Result<Self> {
+        Ok(Self(sled_db.open_tree(SLED_ACL_TREE)?))
+    }
+
+    /// Load the state of a workspace, starting from its `read_key` when
+    /// no roster was received yet.
+    pub fn load(&self, workspace: &str, read_key: &[u8; 32]) -> TaudResult<AclState> {
+        match self.0.get(workspace.as_bytes()).map_err(Error::from)? {
+            Some(bytes) => Ok(deserialize(&bytes)?),
+            None => Ok(AclState::new(*read_key)),
+        }
+    }
+
+    pub fn put(&self, workspace: &str, state: &AclState) -> TaudResult<()> {
+        self.0.insert(workspace.as_bytes(), serialize(state)).map_err(Error::from)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        crdt::{OpId, TaskOp},
+        task_info::{Comment, TaskEvent},
+    };
+
+    fn member() -> MemberKey {
+        let mut secret = [0u8; 32];
+        OsRng.fill_bytes(&mut secret);
+        MemberKey::new(&secret)
+    }
+
+    #[test]
+    fn role_permissions() {
+        let op = |kind| TaskOp { id: OpId { clock: 1, replica: "r".to_string() }, kind };
+        let comment = TaskUpdate {
+            ref_id: "task".to_string(),
+            ops: vec![
+                op(OpKind::Comment(Comment::new("hi", "bob"))),
+                op(OpKind::Event(TaskEvent::new(
+                    "comment".to_string(),
+                    "bob".to_string(),
+                    "hi".to_string(),
+                ))),
+            ],
+        };
+        let edit = TaskUpdate {
+            ref_id: "task".to_string(),
+            ops: vec![op(OpKind::Title("t".to_string()))],
+        };
+
+        assert!(!Role::Read.can_update(&comment));
+        assert!(Role::Comment.can_update(&comment));
+        assert!(!Role::Comment.can_update(&edit));
+        assert!(Role::Edit.can_update(&edit));
+        assert!(Role::Admin.can_update(&edit));
+
+        let id = member().id();
+        assert_eq!(id.to_string().parse::<MemberId>().unwrap(), id);
+        assert_eq!("comment".parse::<Role>().unwrap(), Role::Comment);
+    }
+
+    #[test]
+    fn roster_updates() {
+        let root = [1u8; 32];
+        let (alice, bob) = (member(), member());
+        let alice_key = alice.id().sign_key;
+        let mut published = vec![];
+
+        // Everyone starts from the read_key, the root adds alice and bob
+        let mut state = AclState::new([0u8; 32]);
+        let mut bob_state = state.clone();
+        let roster = state.set_member(alice.id(), Role::Admin);
+        state.apply(roster.clone(), &root, &root, None).unwrap();
+        published.push((roster, root.to_vec()));
+        let roster = state.set_member(bob.id(), Role::Edit);
+
+        // Only admins of the base roster may update it
+        assert!(state.clone().apply(roster.clone(), &bob.id().sign_key, &root, None).is_err());
+
+        let mut alice_state = state.clone();
+        alice_state.apply(roster.clone(), &alice_key, &root, Some(&alice)).unwrap();
+        bob_state.apply(state.roster.clone(), &root, &root, None).unwrap();
+        bob_state.apply(roster.clone(), &alice_key, &root, Some(&bob)).unwrap();
+        published.push((roster.clone(), alice_key.to_vec()));
+
+        // Applying an update again changes nothing
+        let mut again = bob_state.clone();
+        again.apply(roster, &alice_key, &root, Some(&bob)).unwrap();
+        assert_eq!(again.roster, bob_state.roster);
+        assert_eq!(bob_state.role(bob.id().sign_key.as_ref(), &root), Some(Role::Edit));
+
+        // Versions follow the base roster's
+        let mut jump = alice_state.set_member(bob.id(), Role::Admin);
+        jump.version = u64::MAX;
+        assert!(alice_state.clone().apply(jump.clone(), &alice_key, &root, None).is_err());
+        jump.base_version = u64::MAX;
+        assert!(alice_state.clone().apply(jump, &alice_key, &root, None).is_err());
+
+        // Removing a member has to rotate the secret
+        let mut roster = alice_state.remove_member(&bob.id()).unwrap();
+        let mut unrotated = roster.clone();
+        unrotated.epoch -= 1;
+        assert!(alice_state.clone().apply(unrotated, &alice_key, &root, Some(&alice)).is_err());
+
+        alice_state.apply(roster.clone(), &alice_key, &root, Some(&alice)).unwrap();
+        published.push((roster.clone(), alice_key.to_vec()));
+        assert_ne!(alice_state.secret(), alice_state.read_key());
+        assert_eq!(alice_state.secrets().count(), 2);
+
+        // Bob sees the new roster, but doesn't get the new secret
+        roster.secrets.insert(bob.id(), roster.secrets[&alice.id()].clone());
+        bob_state.apply(roster, &alice_key, &root, Some(&bob)).unwrap();
+        assert_eq!(bob_state.secret(), bob_state.read_key());
+        assert_eq!(bob_state.role(bob.id().sign_key.as_ref(), &root), None);
+
+        // Members added later recover every secret, once they received
+        // the rosters their own was derived from
+        let carol = member();
+        let roster = alice_state.set_member(carol.id(), Role::Read);
+        let mut carol_state = AclState::new([0u8; 32]);
+        carol_state.apply(roster.clone(), &alice_key, &root, Some(&carol)).unwrap();
+        assert_eq!(carol_state.secret(), carol_state.read_key());
+        for (roster, signer) in published {
+            carol_state.apply(roster, &signer, &root, Some(&carol)).unwrap();
+        }
+        assert_eq!(carol_state.roster, roster);
+        assert_eq!(carol_state.secret(), alice_state.secret());
+    }
+
+    #[test]
+    fn concurrent_roster_updates() {
+        let root = [1u8; 32];
+        let (alice, bob, carol, dave) = (member(), member(), member(), member());
+        let (alice_key, bob_key) = (alice.id().sign_key, bob.id().sign_key);
+
+        let mut state = AclState::new([0u8; 32]);
+        for id in [alice.id(), bob.id(), carol.id()] {
+            let roster = state.set_member(id, Role::Admin);
+            state.apply(roster, &root, &root, None).unwrap();
+        }
+
+        // Alice adds dave while bob concurrently removes carol
+        let added = state.set_member(dave.id(), Role::Read);
+        let removed = state.remove_member(&carol.id()).unwrap();
+
+        let mut states = vec![];
+        for updates in [
+            [(&added, &alice_key), (&removed, &bob_key)],
+            [(&removed, &bob_key), (&added, &alice_key)],
+        ] {
+            let mut state = state.clone();
+            for (roster, signer) in updates {
+                state.apply(roster.clone(), signer, &root, Some(&alice)).unwrap();
+            }
+            states.push(state);
+        }
+
+        // Both orders converge to the update signed with the highest key
+        let winner = if alice_key > bob_key { &added } else { &removed };
+        assert_eq!(&states[0].roster, winner);
+        assert_eq!(states[0].roster, states[1].roster);
+        assert_eq!(states[0].secret(), states[1].secret());
+
+        // The secret bob rotated to is kept either way
+        let mut secrets: Vec<_> = states[0].secrets().collect();
+        let mut other: Vec<_> = states[1].secrets().collect();
+        secrets.sort();
+        other.sort();
+        assert_eq!(secrets, other);
+        assert_eq!(secrets.len(), 2);
+    }
+
+    #[test]
+    fn concurrent_demotion() {
+        let root = [1u8; 32];
+        let (alice, bob, carol) = (member(), member(), member());
+        let (alice_key, bob_key) = (alice.id().sign_key, bob.id().sign_key);
+
+        let mut state = AclState::new([0u8; 32]);
+        for id in [alice.id(), bob.id()] {
+            let roster = state.set_member(id, Role::Admin);
+            state.apply(roster, &root, &root, None).unwrap();
+        }
+
+        // Alice demotes bob while bob concurrently adds carol. Bob was
+        // an admin of the roster both were derived from, so his update
+        // is valid whatever order they're received in.
+        let demoted = state.set_member(bob.id(), Role::Read);
+        let added = state.set_member(carol.id(), Role::Read);
+
+        let mut states = vec![];
+        for updates in [
+            [(&demoted, &alice_key), (&added, &bob_key)],
+            [(&added, &bob_key), (&demoted, &alice_key)],
+        ] {
+            let mut state = state.clone();
+            for (roster, signer) in updates {
+                state.apply(roster.clone(), signer, &root, None).unwrap();
+            }
+            states.push(state);
+        }
+
+        let winner = if alice_key > bob_key { &demoted } else { &added };
+        assert_eq!(&states[0].roster, winner);
+        assert_eq!(states[0].roster, states[1].roster);
+
+        // Updates bob derives from the demotion are refused
+        let mut state = state.clone();
+        state.apply(demoted, &alice_key, &root, None).unwrap();
+        let roster = state.set_member(carol.id(), Role::Admin);
+        assert!(state.apply(roster, &bob_key, &root, None).is_err());
+    }
+}
@@ -0,0 +1,699 @@
+/* This file is part of DarkFi (https://dark.fi)
+ *
+ * Copyright (C) 2020-2024 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Conflict-free replicated tasks.
+//!
+//! Tasks are modeled as CRDTs, so concurrent edits made by different
+//! people merge deterministically, whatever the order their updates are
+//! received in. Titles, descriptions, states, due dates, ranks, parents
+//! and recurrences are last-writer-wins registers, tags, assignees,
+//! projects, blockers and reminders are observed-remove sets, and
+//! comments and events are append-only logs.
+//!
+//! Changes to a task are sent as a [`TaskUpdate`], a list of operations
+//! computed by diffing the edited task against its replicated state.
+//! Every operation is identified by an [`OpId`], made of a Lamport clock
+//! and the random identifier of the update it belongs to, which totally
+//! orders the operations of a task. Applying an operation is idempotent
+//! and commutes with applying any other one, so replicas which have seen
+//! the same operations end up in the same state.
+//!
+//! The replicated state of the tasks is kept in a sled tree by the
+//! [`TaskStore`], while their materialized [`TaskInfo`] is still saved
+//! on disk, where the rest of taud reads it from.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+};
+
+use darkfi::{util::time::Timestamp, Error, Result};
+use darkfi_serial::{
+    async_trait, deserialize, serialize, FutAsyncWriteExt, SerialDecodable, SerialEncodable,
+};
+use sled_overlay::sled;
+
+use crate::{
+    error::{TaudError, TaudResult},
+    relations::Relation,
+    task_info::{Comment, Recurrence, TaskEvent, TaskInfo},
+    util::gen_id,
+};
+
+/// sled tree holding the replicated state of tasks
+const SLED_TASKS_TREE: &[u8] = b"tau_tasks";
+
+/// How far ahead of the time their update was published at (in seconds)
+/// operations may be. Updates past it are refused, so the clock can't be
+/// pushed to overflow.
+const MAX_CLOCK_DRIFT: u64 = 1_000_000;
+
+/// Identifier of an operation on a task
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, SerialEncodable, SerialDecodable)]
+pub struct OpId {
+    /// Lamport clock of the operation
+    pub clock: u64,
+    /// Identifier of the update the operation is part of
+    pub replica: String,
+}
+
+/// Last-writer-wins register
+#[derive(Clone, Debug, PartialEq, SerialEncodable, SerialDecodable)]
+pub struct LwwRegister<T: Send + Sync> {
+    value: T,
+    /// Operation that last wrote the register, `None` for its initial value
+    id: Option<OpId>,
+}
+
+impl<T: Send + Sync> LwwRegister<T> {
+    pub fn new(value: T) -> Self {
+        Self { value, id: None }
+    }
+
+    pub fn get(&self) -> &T {
+        &self.value
+    }
+
+    /// Write provided value, unless a later operation already wrote it.
+    pub fn set(&mut self, value: T, id: OpId) {
+        if Some(&id) > self.id.as_ref() {
+            self.value = value;
+            self.id = Some(id);
+        }
+    }
+}
+
+/// Observed-remove set. A removal only cancels the additions it has
+/// observed, so an element added concurrently to its removal is kept.
+#[derive(Clone, Debug, Default, PartialEq, SerialEncodable, SerialDecodable)]
+pub struct OrSet {
+    /// Elements along with the operations which added them
+    adds: BTreeMap<String, BTreeSet<OpId>>,
+    /// Additions which were removed
+    removed: BTreeSet<OpId>,
+}
+
+impl OrSet {
+    /// Add an element. Removed additions are remembered, so an addition
+    /// received after its removal stays removed.
+    pub fn add(&mut self, value: String, id: OpId) {
+        if !self.removed.contains(&id) {
+            self.adds.entry(value).or_default().insert(id);
+        }
+    }
+
+    /// Remove the given additions.
+    pub fn remove(&mut self, ids: &[OpId]) {
+        self.removed.extend(ids.iter().cloned());
+        self.adds.retain(|_, adds| {
+            adds.retain(|id| !self.removed.contains(id));
+            !adds.is_empty()
+        });
+    }
+
+    pub fn contains(&self, value: &str) -> bool {
+        self.adds.contains_key(value)
+    }
+
+    /// Additions of provided element, which its removal has to cancel.
+    pub fn observed(&self, value: &str) -> Vec<OpId> {
+        self.adds.get(value).map(|ids| ids.iter().cloned().collect()).unwrap_or_default()
+    }
+
+    /// Elements of the set, in the order they were first added.
+    pub fn values(&self) -> Vec<String> {
+        let mut values: Vec<_> =
+            self.adds.iter().map(|(value, ids)| (ids.first().unwrap(), value)).collect();
+        values.sort();
+        values.into_iter().map(|(_, value)| value.clone()).collect()
+    }
+}
+
+/// Append-only log, ordered by the operations which appended to it
+#[derive(Clone, Debug, PartialEq, SerialEncodable, SerialDecodable)]
+pub struct AppendLog<T: Send + Sync> {
+    entries: BTreeMap<OpId, T>,
+}
+
+impl<T: PartialEq + Send + Sync> AppendLog<T> {
+    pub fn new() -> Self {
+        Self { entries: BTreeMap::new() }
+    }
+
+    pub fn append(&mut self, value: T, id: OpId) {
+        self.entries.entry(id).or_insert(value);
+    }
+
+    pub fn contains(&self, value: &T) -> bool {
+        self.entries.values().any(|v| v == value)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &T> {
+        self.entries.values()
+    }
+}
+
+impl<T: PartialEq + Send + Sync> Default for AppendLog<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Fields of a task which are set once, when it's created
+#[derive(Clone, Debug, PartialEq, SerialEncodable, SerialDecodable)]
+pub struct TaskBase {
+    pub workspace: String,
+    pub owner: String,
+    pub created_at: Timestamp,
+}
+
+/// Set fields of a task
+#[derive(Clone, Copy, Debug, PartialEq, Eq, SerialEncodable, SerialDecodable)]
+pub enum SetField {
+    Tags,
+    Assign,
+    Project,
+    BlockedBy,
+    /// Reminder timestamps, as strings
+    Reminders,
+}
+
+/// Operation on a task
+#[derive(Clone, Debug, PartialEq, SerialEncodable, SerialDecodable)]
+pub enum OpKind {
+    Create(TaskBase),
+    Title(String),
+    Desc(String),
+    State(String),
+    Due(Option<Timestamp>),
+    Rank(Option<f32>),
+    /// Add an element to a set
+    Add(SetField, String),
+    /// Remove the given additions from a set
+    Remove(SetField, Vec<OpId>),
+    Comment(Comment),
+    Event(TaskEvent),
+    Parent(Option<String>),
+    Recurrence(Option<Recurrence>),
+}
+
+#[derive(Clone, Debug, PartialEq, SerialEncodable, SerialDecodable)]
+pub struct TaskOp {
+    pub id: OpId,
+    pub kind: OpKind,
+}
+
+/// Operations on a task, sent over the network
+#[derive(Clone, Debug, PartialEq, SerialEncodable, SerialDecodable)]
+pub struct TaskUpdate {
+    pub ref_id: String,
+    pub ops: Vec<TaskOp>,
+}
+
+/// Replicated state of a task
+#[derive(Clone, Debug, PartialEq, SerialEncodable, SerialDecodable)]
+pub struct TaskCrdt {
+    pub ref_id: String,
+    base: LwwRegister<Option<TaskBase>>,
+    title: LwwRegister<String>,
+    desc: LwwRegister<String>,
+    state: LwwRegister<String>,
+    due: LwwRegister<Option<Timestamp>>,
+    rank: LwwRegister<Option<f32>>,
+    tags: OrSet,
+    assign: OrSet,
+    project: OrSet,
+    parent: LwwRegister<Option<String>>,
+    blocked_by: OrSet,
+    recurrence: LwwRegister<Option<Recurrence>>,
+    reminders: OrSet,
+    comments: AppendLog<Comment>,
+    events: AppendLog<TaskEvent>,
+    /// Highest clock among the applied operations
+    clock: u64,
+}
+
+impl TaskCrdt {
+    pub fn new(ref_id: &str) -> Self {
+        Self {
+            ref_id: ref_id.to_string(),
+            base: LwwRegister::new(None),
+            title: LwwRegister::new(String::new()),
+            desc: LwwRegister::new(String::new()),
+            state: LwwRegister::new("open".to_string()),
+            due: LwwRegister::new(None),
+            rank: LwwRegister::new(None),
+            tags: OrSet::default(),
+            assign: OrSet::default(),
+            project: OrSet::default(),
+            parent: LwwRegister::new(None),
+            blocked_by: OrSet::default(),
+            recurrence: LwwRegister::new(None),
+            reminders: OrSet::default(),
+            comments: AppendLog::new(),
+            events: AppendLog::new(),
+            clock: 0,
+        }
+    }
+
+    /// Whether the creation of the task was received. Updates may
+    /// arrive before it, in which case the task isn't visible yet.
+    pub fn is_created(&self) -> bool {
+        self.base.get().is_some()
+    }
+
+    /// Operation which set provided relationship of the task. Dependency
+    /// cycles are broken by dropping the relationships set last.
+    pub fn relation_id(&self, relation: &Relation) -> Option<OpId> {
+        match relation {
+            Relation::Parent(_) => self.parent.id.clone(),
+            Relation::BlockedBy(blocker) => self.blocked_by.observed(blocker).into_iter().next(),
+        }
+    }
+
+    fn set(&self, field: SetField) -> &OrSet {
+        match field {
+            SetField::Tags => &self.tags,
+            SetField::Assign => &self.assign,
+            SetField::Project => &self.project,
+            SetField::BlockedBy => &self.blocked_by,
+            SetField::Reminders => &self.reminders,
+        }
+    }
+
+    fn set_mut(&mut self, field: SetField) -> &mut OrSet {
+        match field {
+            SetField::Tags => &mut self.tags,
+            SetField::Assign => &mut self.assign,
+            SetField::Project => &mut self.project,
+            SetField::BlockedBy => &mut self.blocked_by,
+            SetField::Reminders => &mut self.reminders,
+        }
+    }
+
+    /// Apply an operation. Operations of other tasks must not be applied.
+    pub fn apply(&mut self, op: &TaskOp) {
+        self.clock = self.clock.max(op.id.clock);
+        let id = op.id.clone();
+
+        match &op.kind {
+            OpKind::Create(base) => self.base.set(Some(base.clone()), id),
+            OpKind::Title(title) => self.title.set(title.clone(), id),
+            OpKind::Desc(desc) => self.desc.set(desc.clone(), id),
+            OpKind::State(state) => self.state.set(state.clone(), id),
+            OpKind::Due(due) => self.due.set(*due, id),
+            OpKind::Rank(rank) => self.rank.set(*rank, id),
+            OpKind::Add(field, value) => self.set_mut(*field).add(value.clone(), id),
+            OpKind::Remove(field, ids) => self.set_mut(*field).remove(ids),
+            OpKind::Comment(comment) => self.comments.append(comment.clone(), id),
+            OpKind::Event(event) => self.events.append(event.clone(), id),
+            OpKind::Parent(parent) => self.parent.set(parent.clone(), id),
+            OpKind::Recurrence(recurrence) => self.recurrence.set(*recurrence, id),
+        }
+    }
+
+    /// Apply the operations of an update published at the given time,
+    /// refusing the whole update if any of them is too far ahead of it.
+    /// This only depends on the update, so every replica refuses the same
+    /// ones, whatever order it sees them in.
+    pub fn apply_update(&mut self, update: &TaskUpdate, published: Timestamp) -> TaudResult<()> {
+        let max_clock = published
+            .inner()
+            .checked_add(MAX_CLOCK_DRIFT)
+            .ok_or_else(|| TaudError::InvalidData("Task clock overflow".to_string()))?;
+
+        if update.ops.iter().any(|op| op.id.clock > max_clock) {
+            return Err(TaudError::InvalidData(format!(
+                "Update of task {} is too far ahead of its publication time",
+                update.ref_id
+            )))
+        }
+
+        for op in &update.ops {
+            self.apply(op);
+        }
+        Ok(())
+    }
+
+    /// Materialize the task, if it was created.
+    pub fn to_task_info(&self) -> Option<TaskInfo> {
+        let base = self.base.get().as_ref()?;
+
+        let mut reminders: Vec<Timestamp> = self
+            .reminders
+            .values()
+            .iter()
+            .filter_map(|x| x.parse::<u64>().ok())
+            .map(Timestamp::from_u64)
+            .collect();
+        reminders.sort();
+
+        Some(TaskInfo {
+            ref_id: self.ref_id.clone(),
+            workspace: base.workspace.clone(),
+            title: self.title.get().clone(),
+            tags: self.tags.values(),
+            desc: self.desc.get().clone(),
+            owner: base.owner.clone(),
+            assign: self.assign.values(),
+            project: self.project.values(),
+            due: *self.due.get(),
+            rank: *self.rank.get(),
+            created_at: base.created_at,
+            state: self.state.get().clone(),
+            events: self.events.values().cloned().collect(),
+            comments: self.comments.values().cloned().collect(),
+            parent: self.parent.get().clone(),
+            blocked_by: self.blocked_by.values(),
+            recurrence: *self.recurrence.get(),
+            reminders,
+        })
+    }
+
+    /// Operations turning this state into provided task, ordered after
+    /// every operation applied so far.
+    pub fn diff(&self, task: &TaskInfo) -> TaskUpdate {
+        self.diff_at(task, self.clock + 1, gen_id(16))
+    }
+
+    /// Operations turning this state into provided task snapshot, as
+    /// tasks used to be sent whole. They are ordered by the time of the
+    /// snapshot's latest event, and identified by its contents, so every
+    /// replica derives the same operations from it.
+    pub fn diff_snapshot(&self, task: &TaskInfo) -> TaskUpdate {
+        let clock = task.events.iter().map(|e| e.timestamp).fold(task.created_at, Ord::max);
+        let replica = blake3::hash(&serialize(task)).to_hex().to_string();
+        self.diff_at(task, clock.inner(), replica)
+    }
+
+    fn diff_at(&self, task: &TaskInfo, mut clock: u64, replica: String) -> TaskUpdate {
+        let mut ops = vec![];
+        let mut push = |kind| {
+            ops.push(TaskOp { id: OpId { clock, replica: replica.clone() }, kind });
+            clock += 1;
+        };
+
+        if !self.is_created() {
+            push(OpKind::Create(TaskBase {
+                workspace: task.workspace.clone(),
+                owner: task.owner.clone(),
+                created_at: task.created_at,
+            }));
+        }
+
+        if *self.title.get() != task.title {
+            push(OpKind::Title(task.title.clone()));
+        }
+        if *self.desc.get() != task.desc {
+            push(OpKind::Desc(task.desc.clone()));
+        }
+        if *self.state.get() != task.state {
+            push(OpKind::State(task.state.clone()));
+        }
+        if *self.due.get() != task.due {
+            push(OpKind::Due(task.due));
+        }
+        if *self.rank.get() != task.rank {
+            push(OpKind::Rank(task.rank));
+        }
+        if *self.parent.get() != task.parent {
+            push(OpKind::Parent(task.parent.clone()));
+        }
+        if *self.recurrence.get() != task.recurrence {
+            push(OpKind::Recurrence(task.recurrence));
+        }
+
+        let reminders: Vec<String> = task.reminders.iter().map(|x| x.inner().to_string()).collect();
+
+        for (field, values) in [
+            (SetField::Tags, &task.tags),
+            (SetField::Assign, &task.assign),
+            (SetField::Project, &task.project),
+            (SetField::BlockedBy, &task.blocked_by),
+            (SetField::Reminders, &reminders),
+        ] {
+            let set = self.set(field);
+            for value in values.iter().filter(|v| !set.contains(v)) {
+                push(OpKind::Add(field, value.clone()));
+            }
+            for value in set.values().iter().filter(|v| !values.contains(v)) {
+                push(OpKind::Remove(field, set.observed(value)));
+            }
+        }
+
+        for comment in task.comments.iter().filter(|c| !self.comments.contains(c)) {
+            push(OpKind::Comment(comment.clone()));
+        }
+        for event in task.events.iter().filter(|e| !self.events.contains(e)) {
+            push(OpKind::Event(event.clone()));
+        }
+
+        TaskUpdate { ref_id: self.ref_id.clone(), ops }
+    }
+}
+
+/// Persistent store of the replicated state of tasks
+#[derive(Clone)]
+pub struct TaskStore(sled::Tree);
+
+impl TaskStore {
+    pub fn new(sled_db: &sled::Db) -> Result<Self> {
+        Ok(Self(sled_db.open_tree(SLED_TASKS_TREE)?))
+    }
+
+    pub fn get(&self, ref_id: &str) -> TaudResult<Option<TaskCrdt>> {
+        match self.0.get(ref_id.as_bytes()).map_err(Error::from)? {
+            Some(bytes) => Ok(Some(deserialize(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn put(&self, crdt: &TaskCrdt) -> TaudResult<()> {
+        self.0.insert(crdt.ref_id.as_bytes(), serialize(crdt)).map_err(Error::from)?;
+        Ok(())
+    }
+
+    /// Load the replicated state of a task. Tasks saved on disk before
+    /// they were replicated are migrated from their snapshot, and unknown
+    /// tasks start from an empty state.
+    pub fn load(&self, ref_id: &str, dataset_path: &Path) -> TaudResult<TaskCrdt> {
+        if let Some(crdt) = self.get(ref_id)? {
+            return Ok(crdt)
+        }
+
+        let mut crdt = TaskCrdt::new(ref_id);
+        if let Ok(task) = TaskInfo::load(ref_id, dataset_path) {
+            crdt.apply_update(&crdt.diff_snapshot(&task), Timestamp::current_time())?;
+            self.put(&crdt)?;
+        }
+
+        Ok(crdt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Publication time of the updates
+    const NOW: Timestamp = Timestamp::from_u64(1_000);
+
+    fn task() -> TaskInfo {
+        let mut task = TaskInfo::new(
+            "ws".to_string(),
+            "title",
+            "desc",
+            "alice",
+            None,
+            None,
+            Timestamp::from_u64(1),
+        )
+        .unwrap();
+        task.set_tags(&["+a".to_string(), "+b".to_string()]);
+        task
+    }
+
+    fn permutations(n: usize) -> Vec<Vec<usize>> {
+        if n == 0 {
+            return vec![vec![]]
+        }
+        let mut ret = vec![];
+        for perm in permutations(n - 1) {
+            for i in 0..n {
+                let mut perm = perm.clone();
+                perm.insert(i, n - 1);
+                ret.push(perm);
+            }
+        }
+        ret
+    }
+
+    /// Apply the updates in every order, checking that all replicas converge.
+    fn converge(updates: &[TaskUpdate]) -> TaskCrdt {
+        let replicas: Vec<TaskCrdt> = permutations(updates.len())
+            .into_iter()
+            .map(|perm| {
+                let mut crdt = TaskCrdt::new(&updates[0].ref_id);
+                for i in perm {
+                    crdt.apply_update(&updates[i], NOW).unwrap();
+                    // Applying an update twice changes nothing
+                    crdt.apply_update(&updates[i], NOW).unwrap();
+                }
+                crdt
+            })
+            .collect();
+
+        for replica in &replicas[1..] {
+            assert_eq!(replica, &replicas[0]);
+        }
+        replicas[0].clone()
+    }
+
+    #[test]
+    fn concurrent_edits_merge() {
+        let task = task();
+        let create = TaskCrdt::new(&task.ref_id).diff(&task);
+        let mut base = TaskCrdt::new(&task.ref_id);
+        base.apply_update(&create, NOW).unwrap();
+
+        // Alice retitles the task and removes a tag, while Bob comments,
+        // assigns himself, and adds the same tag back.
+        let mut alice = task.clone();
+        alice.set_title("new title");
+        alice.set_tags(&["-a".to_string()]);
+        let alice = base.diff(&alice);
+
+        let mut bob = task.clone();
+        bob.set_comment(Comment::new("on it", "bob"));
+        bob.set_assign(&["@bob".to_string()]);
+        bob.set_tags(&["+a".to_string(), "+c".to_string()]);
+        let bob = base.diff(&bob);
+
+        let merged = converge(&[create, alice, bob]).to_task_info().unwrap();
+        assert_eq!(merged.title, "new title");
+        assert_eq!(merged.assign, vec!["bob"]);
+        assert_eq!(merged.comments.len(), 1);
+        // Bob didn't add `a` again as he already had it, so Alice's
+        // removal wins, but `c` which she didn't observe stays.
+        assert_eq!(merged.tags, vec!["b", "c"]);
+    }
+
+    #[test]
+    fn concurrent_register_writes() {
+        let task = task();
+        let create = TaskCrdt::new(&task.ref_id).diff(&task);
+        let mut base = TaskCrdt::new(&task.ref_id);
+        base.apply_update(&create, NOW).unwrap();
+
+        let mut alice = task.clone();
+        alice.set_desc("alice");
+        let alice = base.diff(&alice);
+
+        let mut bob = task.clone();
+        bob.set_desc("bob");
+        let bob = base.diff(&bob);
+
+        // Both writes have the same clock, so the update identifiers
+        // decide, and every replica picks the same one.
+        let winner = if alice.ops[0].id > bob.ops[0].id { "alice" } else { "bob" };
+        let merged = converge(&[create, alice, bob]).to_task_info().unwrap();
+        assert_eq!(merged.desc, winner);
+    }
+
+    #[test]
+    fn removal_before_addition() {
+        let task = task();
+        let create = TaskCrdt::new(&task.ref_id).diff(&task);
+        let mut base = TaskCrdt::new(&task.ref_id);
+        base.apply_update(&create, NOW).unwrap();
+
+        let mut edited = task.clone();
+        edited.set_tags(&["-a".to_string(), "-b".to_string()]);
+        let removal = base.diff(&edited);
+
+        // The removal arrives first, and the task isn't visible yet
+        let mut crdt = TaskCrdt::new(&task.ref_id);
+        crdt.apply_update(&removal, NOW).unwrap();
+        assert!(crdt.to_task_info().is_none());
+
+        crdt.apply_update(&create, NOW).unwrap();
+        assert!(crdt.to_task_info().unwrap().tags.is_empty());
+    }
+
+    #[test]
+    fn snapshots_are_deterministic() {
+        let task = task();
+        let a = TaskCrdt::new(&task.ref_id).diff_snapshot(&task);
+        let b = TaskCrdt::new(&task.ref_id).diff_snapshot(&task);
+        assert_eq!(a, b);
+
+        let mut crdt = TaskCrdt::new(&task.ref_id);
+        crdt.apply_update(&a, NOW).unwrap();
+        assert_eq!(crdt.to_task_info().unwrap(), task);
+        assert!(crdt.diff(&task).ops.is_empty());
+    }
+
+    #[test]
+    fn far_ahead_updates_refused_in_any_order() {
+        let task = task();
+        let create = TaskCrdt::new(&task.ref_id).diff(&task);
+        let mut base = TaskCrdt::new(&task.ref_id);
+        base.apply_update(&create, NOW).unwrap();
+
+        // Both are published now, but the first one is within the drift
+        // bound while the second one, following it, is past it.
+        let mut edited = task.clone();
+        edited.set_desc("near");
+        let mut near = base.diff(&edited);
+        near.ops[0].id.clock = NOW.inner() + MAX_CLOCK_DRIFT * 9 / 10;
+        base.apply_update(&near, NOW).unwrap();
+
+        edited.set_desc("far");
+        let mut far = base.diff(&edited);
+        far.ops[0].id.clock = NOW.inner() + MAX_CLOCK_DRIFT * 18 / 10;
+
+        let updates = [create, near, far];
+        let replicas: Vec<TaskCrdt> = permutations(updates.len())
+            .into_iter()
+            .map(|perm| {
+                let mut crdt = TaskCrdt::new(&task.ref_id);
+                for i in perm {
+                    assert_eq!(crdt.apply_update(&updates[i], NOW).is_ok(), i != 2);
+                }
+                crdt
+            })
+            .collect();
+
+        for replica in &replicas[1..] {
+            assert_eq!(replica, &replicas[0]);
+        }
+        assert_eq!(replicas[0].to_task_info().unwrap().desc, "near");
+    }
+
+    #[test]
+    fn far_future_clocks_are_refused() {
+        let task = task();
+        let mut update = TaskCrdt::new(&task.ref_id).diff(&task);
+        update.ops[0].id.clock = u64::MAX;
+
+        let mut crdt = TaskCrdt::new(&task.ref_id);
+        assert!(crdt.apply_update(&update, NOW).is_err());
+        assert!(crdt.to_task_info().is_none());
+        assert_eq!(crdt.diff(&task).ops[0].id.clock, 1);
+    }
+}
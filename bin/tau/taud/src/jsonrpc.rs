@@ -42,19 +42,24 @@ use darkfi::{
 };
 
 use taud::{
+    acl::{AclStore, MemberId, Role},
+    crdt::TaskStore,
     error::{to_json_result, TaudError, TaudResult},
     month_tasks::MonthTasks,
-    task_info::{Comment, TaskInfo},
+    relations::{check_relations, next_instances, restore_dropped_relations},
+    task_info::{Comment, Recurrence, TaskInfo},
     util::set_event,
 };
 
-use crate::Workspace;
+use crate::{Message, Workspace};
 
 const DEFAULT_WORKSPACE: &str = "darkfi-dev";
 
 pub struct JsonRpcInterface {
     dataset_path: PathBuf,
-    notify_queue_sender: smol::channel::Sender<TaskInfo>,
+    notify_queue_sender: smol::channel::Sender<(String, Message)>,
+    task_store: TaskStore,
+    acl_store: AclStore,
     nickname: String,
     workspace: Mutex<String>,
     workspaces: Arc<HashMap<String, Workspace>>,
@@ -62,6 +67,7 @@ pub struct JsonRpcInterface {
     event_graph: EventGraphPtr,
     dnet_sub: JsonSubscriber,
     deg_sub: JsonSubscriber,
+    reminders_sub: JsonSubscriber,
     rpc_connections: Mutex<HashSet<StoppableTaskPtr>>,
 }
 
@@ -76,12 +82,20 @@ impl RequestHandler<()> for JsonRpcInterface {
             "set_state" => self.set_state(req.params).await,
             "set_comment" => self.set_comment(req.params).await,
             "get_task_by_ref_id" => self.get_task_by_ref_id(req.params).await,
+            "get_relations" => self.get_relations(req.params).await,
             "switch_ws" => self.switch_ws(req.params).await,
             "get_ws" => self.get_ws(req.params).await,
             "export" => self.export_to(req.params).await,
             "import" => self.import_from(req.params).await,
             "fetch_deactive_tasks" => self.fetch_deactive_tasks(req.params).await,
             "fetch_archive_task" => self.fetch_archive_task(req.params).await,
+            "acl.get_roster" => self.acl_get_roster(req.params).await,
+            "acl.whoami" => self.acl_whoami(req.params).await,
+            "acl.set_member" => self.acl_set_member(req.params).await,
+            "acl.remove_member" => self.acl_remove_member(req.params).await,
+            "reminders.subscribe_events" => {
+                return self.reminders_subscribe_events(req.id, req.params).await
+            }
 
             "ping" => return self.pong(req.id, req.params).await,
             "dnet.subscribe_events" => return self.dnet_subscribe_events(req.id, req.params).await,
@@ -90,6 +104,7 @@ impl RequestHandler<()> for JsonRpcInterface {
             "deg.switch" => self.deg_switch(req.id, req.params).await,
             "deg.subscribe_events" => return self.deg_subscribe_events(req.id, req.params).await,
             "eventgraph.get_info" => return self.eg_get_info(req.id, req.params).await,
+            "eventgraph.query" => return self.eg_query(req.id, req.params).await,
 
             // TODO: make this optional
             "p2p.get_info" => return self.p2p_get_info(req.id, req.params).await,
@@ -114,13 +129,16 @@ impl JsonRpcInterface {
     #[allow(clippy::too_many_arguments)]
     pub fn new(
         dataset_path: PathBuf,
-        notify_queue_sender: smol::channel::Sender<TaskInfo>,
+        notify_queue_sender: smol::channel::Sender<(String, Message)>,
+        task_store: TaskStore,
+        acl_store: AclStore,
         nickname: String,
         workspaces: Arc<HashMap<String, Workspace>>,
         p2p: net::P2pPtr,
         event_graph: EventGraphPtr,
         dnet_sub: JsonSubscriber,
         deg_sub: JsonSubscriber,
+        reminders_sub: JsonSubscriber,
     ) -> Self {
         let workspace = Mutex::new(DEFAULT_WORKSPACE.to_string());
         Self {
@@ -129,11 +147,14 @@ impl JsonRpcInterface {
             workspace,
             workspaces,
             notify_queue_sender,
+            task_store,
+            acl_store,
             p2p,
             event_graph,
             rpc_connections: Mutex::new(HashSet::new()),
             dnet_sub,
             deg_sub,
+            reminders_sub,
         }
     }
 
@@ -193,6 +214,22 @@ impl JsonRpcInterface {
         self.deg_sub.clone().into()
     }
 
+    // RPCAPI:
+    // Initializes a subscription to task reminders.
+    // Once a subscription is established, `taud` will send JSON-RPC notifications
+    // of reminders as they become due to the subscriber.
+    //
+    // --> {"jsonrpc": "2.0", "method": "reminders.subscribe_events", "params": [], "id": 1}
+    // <-- {"jsonrpc": "2.0", "method": "reminders.subscribe_events", "params": [`reminder`]}
+    pub async fn reminders_subscribe_events(&self, id: u16, params: JsonValue) -> JsonResult {
+        let params = params.get::<Vec<JsonValue>>().unwrap();
+        if !params.is_empty() {
+            return JsonError::new(ErrorCode::InvalidParams, None, id).into()
+        }
+
+        self.reminders_sub.clone().into()
+    }
+
     // RPCAPI:
     // Activate or deactivate deg in the EVENTGRAPH.
     // By sending `true`, deg will be activated, and by sending `false` deg
@@ -231,6 +268,19 @@ impl JsonRpcInterface {
         self.event_graph.eventgraph_info(id, params).await
     }
 
+    // RPCAPI:
+    // Query EVENTGRAPH events by time range, or page through them
+    // forwards or backwards from a given event. Truncated ranges come
+    // with a `next` cursor to continue them from.
+    //
+    // --> {"jsonrpc": "2.0", "method": "eventgraph.query", "params": ["between", 1700000000000, 1700003600000], "id": 42}
+    // <-- {"jsonrpc": "2.0", "result": {"events": [{"id": "...", "timestamp": ..., ...}, ...], "next": null}, "id": 42}
+    // --> {"jsonrpc": "2.0", "method": "eventgraph.query", "params": ["before", null, 50], "id": 42}
+    // <-- {"jsonrpc": "2.0", "result": [{"id": "...", "timestamp": ..., ...}, ...], "id": 42}
+    async fn eg_query(&self, id: u16, params: JsonValue) -> JsonResult {
+        self.event_graph.eventgraph_query(id, params).await
+    }
+
     // RPCAPI:
     // Add new task and returns `true` upon success.
     // --> {"jsonrpc": "2.0", "method": "add",
@@ -320,7 +370,7 @@ impl JsonRpcInterface {
         };
 
         let ws = self.workspace.lock().await.clone();
-        if self.workspaces.get(&ws).unwrap().write_key.is_none() {
+        if !self.has_role(&ws, Role::Edit)? {
             info!("You don't have write access!");
             return Ok(JsonValue::Boolean(false))
         }
@@ -338,7 +388,7 @@ impl JsonRpcInterface {
         new_task.set_assign(&assigns);
         new_task.set_tags(&tags);
 
-        self.notify_queue_sender.send(new_task.clone()).await.map_err(Error::from)?;
+        self.send_task(new_task).await?;
         Ok(JsonValue::Boolean(true))
     }
 
@@ -388,6 +438,11 @@ impl JsonRpcInterface {
 
     // RPCAPI:
     // Modify task and returns `true` upon success.
+    // Relationships are set with `parent` (a task id or null), `blocked_by`
+    // (task ids prefixed with `+` to add or `-` to remove them), `recurrence`
+    // (`daily`, `weekly`, `monthly`, `2w`, ... or null) and `reminders` (a
+    // list of timestamps). Modifications introducing a dependency cycle are
+    // rejected.
     // --> {"jsonrpc": "2.0", "method": "modify", "params": [task_id, {"title": "new title"} ], "id": 1}
     // <-- {"jsonrpc": "2.0", "result": true, "id": 1}
     async fn modify(&self, params: JsonValue) -> TaudResult<JsonValue> {
@@ -399,7 +454,7 @@ impl JsonRpcInterface {
         }
 
         let ws = self.workspace.lock().await.clone();
-        if self.workspaces.get(&ws).unwrap().write_key.is_none() {
+        if !self.has_role(&ws, Role::Edit)? {
             info!("You don't have write access!");
             return Ok(JsonValue::Boolean(false))
         }
@@ -407,16 +462,21 @@ impl JsonRpcInterface {
         let task = self.check_params_for_modify(
             params[0].get::<String>().unwrap(),
             params[1].get::<HashMap<String, JsonValue>>().unwrap(),
-            ws,
+            ws.clone(),
         )?;
 
-        self.notify_queue_sender.send(task).await.map_err(Error::from)?;
+        let tasks = MonthTasks::load_current_tasks(&self.dataset_path, ws, true)?;
+        check_relations(&task, &tasks)?;
+
+        self.send_task(task).await?;
 
         Ok(JsonValue::Boolean(true))
     }
 
     // RPCAPI:
     // Set state for a task and returns `true` upon success.
+    // Stopping a recurring task spawns its next instance, along with the
+    // next instances of its subtasks.
     // --> {"jsonrpc": "2.0", "method": "set_state", "params": [task_id, state], "id": 1}
     // <-- {"jsonrpc": "2.0", "result": true, "id": 1}
     async fn set_state(&self, params: JsonValue) -> TaudResult<JsonValue> {
@@ -432,20 +492,35 @@ impl JsonRpcInterface {
 
         let state = params[1].get::<String>().unwrap();
         let ws = self.workspace.lock().await.clone();
-        if self.workspaces.get(&ws).unwrap().write_key.is_none() {
+        if !self.has_role(&ws, Role::Edit)? {
             info!("You don't have write access!");
             return Ok(JsonValue::Boolean(false))
         }
 
         let mut task: TaskInfo =
-            self.load_task_by_ref_id(params[0].get::<String>().unwrap(), ws)?;
+            self.load_task_by_ref_id(params[0].get::<String>().unwrap(), ws.clone())?;
 
+        let mut instances = vec![];
         if states.contains(&state.as_str()) {
+            if state == "stop" && task.recurrence.is_some() {
+                let mut subtasks = MonthTasks::load_current_tasks(&self.dataset_path, ws, true)?;
+                subtasks.retain(|t| t.parent.as_ref() == Some(&task.ref_id));
+                instances = next_instances(&task, &subtasks, Timestamp::current_time());
+            }
+
             task.set_state(state);
             set_event(&mut task, "state", &self.nickname, state);
         }
 
-        self.notify_queue_sender.send(task).await.map_err(Error::from)?;
+        self.send_task(task).await?;
+
+        for instance in instances {
+            // Already spawned when the task was stopped before
+            if self.task_store.load(&instance.ref_id, &self.dataset_path)?.is_created() {
+                continue
+            }
+            self.send_task(instance).await?;
+        }
 
         Ok(JsonValue::Boolean(true))
     }
@@ -466,8 +541,8 @@ impl JsonRpcInterface {
         let comment_content = params[1].get::<String>().unwrap();
 
         let ws = self.workspace.lock().await.clone();
-        if self.workspaces.get(&ws).unwrap().write_key.is_none() {
-            info!("You don't have write access!");
+        if !self.has_role(&ws, Role::Comment)? {
+            info!("You don't have comment access!");
             return Ok(JsonValue::Boolean(false))
         }
 
@@ -476,7 +551,7 @@ impl JsonRpcInterface {
         task.set_comment(Comment::new(comment_content, &self.nickname));
         set_event(&mut task, "comment", &self.nickname, comment_content);
 
-        self.notify_queue_sender.send(task).await.map_err(Error::from)?;
+        self.send_task(task).await?;
 
         Ok(JsonValue::Boolean(true))
     }
@@ -500,6 +575,47 @@ impl JsonRpcInterface {
         Ok(task)
     }
 
+    // RPCAPI:
+    // Get the relationships of a task: its parent, subtasks, the tasks
+    // blocking it and the tasks it blocks.
+    // --> {"jsonrpc": "2.0", "method": "get_relations", "params": [task_id], "id": 1}
+    // <-- {"jsonrpc": "2.0", "result": {"parent": .., "subtasks": [..], "blocked_by": [..], "blocks": [..]}, "id": 1}
+    async fn get_relations(&self, params: JsonValue) -> TaudResult<JsonValue> {
+        let params = params.get::<Vec<JsonValue>>().unwrap();
+        debug!(target: "tau", "JsonRpc::get_relations() params {:?}", params);
+
+        if params.len() != 1 || !params[0].is_string() {
+            return Err(TaudError::InvalidData("len of params should be 1".into()))
+        }
+
+        let ref_id = params[0].get::<String>().unwrap();
+        let ws = self.workspace.lock().await.clone();
+        let tasks = MonthTasks::load_current_tasks(&self.dataset_path, ws, true)?;
+        let task = tasks.iter().find(|t| t.ref_id == *ref_id).ok_or(TaudError::InvalidId)?;
+
+        let ids = |f: &dyn Fn(&TaskInfo) -> bool| {
+            JsonValue::Array(
+                tasks
+                    .iter()
+                    .filter(|t| f(t))
+                    .map(|t| JsonValue::String(t.ref_id.clone()))
+                    .collect(),
+            )
+        };
+
+        let parent = match &task.parent {
+            Some(parent) => JsonValue::String(parent.clone()),
+            None => JsonValue::Null,
+        };
+
+        Ok(JsonValue::Object(HashMap::from([
+            ("parent".to_string(), parent),
+            ("subtasks".to_string(), ids(&|t| t.parent.as_ref() == Some(ref_id))),
+            ("blocked_by".to_string(), ids(&|t| task.blocked_by.contains(&t.ref_id))),
+            ("blocks".to_string(), ids(&|t| t.blocked_by.contains(ref_id))),
+        ])))
+    }
+
     // RPCAPI:
     // Get all tasks.
     // --> {"jsonrpc": "2.0", "method": "fetch_deactive_tasks", "params": [task_id], "id": 1}
@@ -604,6 +720,122 @@ impl JsonRpcInterface {
         Ok(JsonValue::String(ws))
     }
 
+    // RPCAPI:
+    // Get the roster of the current workspace, mapping the ids of its
+    // members to their role.
+    // --> {"jsonrpc": "2.0", "method": "acl.get_roster", "params": [], "id": 1}
+    // <-- {"jsonrpc": "2.0", "result": {"version": 2, "epoch": 0, "members": {member_id: "edit", ...}}, "id": 1}
+    async fn acl_get_roster(&self, params: JsonValue) -> TaudResult<JsonValue> {
+        let params = params.get::<Vec<JsonValue>>().unwrap();
+        debug!(target: "tau", "JsonRpc::acl_get_roster() params {:?}", params);
+
+        let ws = self.workspace.lock().await.clone();
+        let workspace = self.workspaces.get(&ws).unwrap();
+        let roster = self.acl_store.load(&ws, &workspace.read_key)?.roster;
+
+        let members = roster
+            .members
+            .iter()
+            .map(|(id, role)| (id.to_string(), JsonValue::String(role.to_string())))
+            .collect();
+
+        Ok(JsonValue::Object(HashMap::from([
+            ("version".to_string(), JsonValue::Number(roster.version as f64)),
+            ("epoch".to_string(), JsonValue::Number(roster.epoch as f64)),
+            ("members".to_string(), JsonValue::Object(members)),
+        ])))
+    }
+
+    // RPCAPI:
+    // Get our member id and role in the current workspace. The member id
+    // is null without a configured `member_secret`, and the role is null
+    // if we aren't a member.
+    // --> {"jsonrpc": "2.0", "method": "acl.whoami", "params": [], "id": 1}
+    // <-- {"jsonrpc": "2.0", "result": {"member_id": "..", "role": "comment"}, "id": 1}
+    async fn acl_whoami(&self, params: JsonValue) -> TaudResult<JsonValue> {
+        let params = params.get::<Vec<JsonValue>>().unwrap();
+        debug!(target: "tau", "JsonRpc::acl_whoami() params {:?}", params);
+
+        let ws = self.workspace.lock().await.clone();
+        let workspace = self.workspaces.get(&ws).unwrap();
+        let acl = self.acl_store.load(&ws, &workspace.read_key)?;
+
+        let member_id = match &workspace.member_key {
+            Some(key) => JsonValue::String(key.id().to_string()),
+            None => JsonValue::Null,
+        };
+
+        let role = match workspace.role(&acl) {
+            Some(role) => JsonValue::String(role.to_string()),
+            None => JsonValue::Null,
+        };
+
+        Ok(JsonValue::Object(HashMap::from([
+            ("member_id".to_string(), member_id),
+            ("role".to_string(), role),
+        ])))
+    }
+
+    // RPCAPI:
+    // Add a member to the current workspace, or change their role, and
+    // returns `true` upon success. Roles are `read`, `comment`, `edit` and
+    // `admin`. Only admins may change the roster.
+    // --> {"jsonrpc": "2.0", "method": "acl.set_member", "params": [member_id, role], "id": 1}
+    // <-- {"jsonrpc": "2.0", "result": true, "id": 1}
+    async fn acl_set_member(&self, params: JsonValue) -> TaudResult<JsonValue> {
+        let params = params.get::<Vec<JsonValue>>().unwrap();
+        debug!(target: "tau", "JsonRpc::acl_set_member() params {:?}", params);
+
+        if params.len() != 2 || !params[0].is_string() || !params[1].is_string() {
+            return Err(TaudError::InvalidData("len of params should be 2".into()))
+        }
+
+        let member_id: MemberId = params[0].get::<String>().unwrap().parse()?;
+        let role: Role = params[1].get::<String>().unwrap().parse()?;
+
+        let ws = self.workspace.lock().await.clone();
+        if !self.has_role(&ws, Role::Admin)? {
+            info!("You don't have admin access!");
+            return Ok(JsonValue::Boolean(false))
+        }
+
+        let workspace = self.workspaces.get(&ws).unwrap();
+        let roster = self.acl_store.load(&ws, &workspace.read_key)?.set_member(member_id, role);
+        self.notify_queue_sender.send((ws, Message::Roster(roster))).await.map_err(Error::from)?;
+
+        Ok(JsonValue::Boolean(true))
+    }
+
+    // RPCAPI:
+    // Remove a member from the current workspace and returns `true` upon
+    // success. The workspace secret is rotated, so the removed member
+    // can't decrypt the tasks which follow. Only admins may change the
+    // roster.
+    // --> {"jsonrpc": "2.0", "method": "acl.remove_member", "params": [member_id], "id": 1}
+    // <-- {"jsonrpc": "2.0", "result": true, "id": 1}
+    async fn acl_remove_member(&self, params: JsonValue) -> TaudResult<JsonValue> {
+        let params = params.get::<Vec<JsonValue>>().unwrap();
+        debug!(target: "tau", "JsonRpc::acl_remove_member() params {:?}", params);
+
+        if params.len() != 1 || !params[0].is_string() {
+            return Err(TaudError::InvalidData("len of params should be 1".into()))
+        }
+
+        let member_id: MemberId = params[0].get::<String>().unwrap().parse()?;
+
+        let ws = self.workspace.lock().await.clone();
+        if !self.has_role(&ws, Role::Admin)? {
+            info!("You don't have admin access!");
+            return Ok(JsonValue::Boolean(false))
+        }
+
+        let workspace = self.workspaces.get(&ws).unwrap();
+        let roster = self.acl_store.load(&ws, &workspace.read_key)?.remove_member(&member_id)?;
+        self.notify_queue_sender.send((ws, Message::Roster(roster))).await.map_err(Error::from)?;
+
+        Ok(JsonValue::Boolean(true))
+    }
+
     // RPCAPI:
     // Export tasks.
     // --> {"jsonrpc": "2.0", "method": "export_to", "params": [path], "id": 1}
@@ -655,7 +887,7 @@ impl JsonRpcInterface {
         let path = params[0].get::<String>().unwrap();
         let path = expand_path(path)?.join("exported_tasks");
         let ws = self.workspace.lock().await.clone();
-        if self.workspaces.get(&ws).unwrap().write_key.is_none() {
+        if !self.has_role(&ws, Role::Edit)? {
             info!("You don't have write access!");
             return Ok(JsonValue::Boolean(false))
         }
@@ -671,11 +903,37 @@ impl JsonRpcInterface {
                 continue
             }
 
-            self.notify_queue_sender.send(task).await.map_err(Error::from)?;
+            self.send_task(task).await?;
         }
         Ok(JsonValue::Boolean(true))
     }
 
+    /// Send the changes made to a task as an update of its replicated
+    /// state, in the task's workspace.
+    async fn send_task(&self, mut task: TaskInfo) -> TaudResult<()> {
+        let crdt = self.task_store.load(&task.ref_id, &self.dataset_path)?;
+        if let (Ok(stored), Some(replicated)) =
+            (TaskInfo::load(&task.ref_id, &self.dataset_path), crdt.to_task_info())
+        {
+            restore_dropped_relations(&mut task, &stored, &replicated);
+        }
+        let update = crdt.diff(&task);
+        if update.ops.is_empty() {
+            return Ok(())
+        }
+
+        let message = (task.workspace, Message::Task(update));
+        self.notify_queue_sender.send(message).await.map_err(Error::from)?;
+        Ok(())
+    }
+
+    /// Whether we have at least provided role in the workspace
+    fn has_role(&self, ws: &str, role: Role) -> TaudResult<bool> {
+        let workspace = self.workspaces.get(ws).unwrap();
+        let acl = self.acl_store.load(ws, &workspace.read_key)?;
+        Ok(workspace.role(&acl).is_some_and(|r| r >= role))
+    }
+
     fn load_task_by_ref_id(&self, task_ref_id: &str, ws: String) -> TaudResult<TaskInfo> {
         let tasks = MonthTasks::load_current_tasks(&self.dataset_path, ws, false)?;
         let task = tasks.into_iter().find(|t| (t.get_ref_id()) == task_ref_id);
@@ -771,6 +1029,68 @@ impl JsonRpcInterface {
             }
         }
 
+        if fields.contains_key("parent") {
+            match &fields["parent"] {
+                JsonValue::Null => {
+                    task.set_parent(None);
+                    set_event(&mut task, "parent", &self.nickname, "None")
+                }
+                JsonValue::String(parent) => {
+                    task.set_parent(Some(parent.clone()));
+                    set_event(&mut task, "parent", &self.nickname, parent)
+                }
+                _ => return Err(TaudError::InvalidData("Invalid parameter \"parent\"".into())),
+            }
+        }
+
+        if fields.contains_key("blocked_by") {
+            let blocked_by: Vec<String> = fields["blocked_by"]
+                .get::<Vec<JsonValue>>()
+                .unwrap()
+                .iter()
+                .map(|x| x.get::<String>().unwrap().clone())
+                .collect();
+
+            if !blocked_by.is_empty() {
+                task.set_blocked_by(&blocked_by);
+                set_event(&mut task, "blocked_by", &self.nickname, &blocked_by.join(", "));
+            }
+        }
+
+        if fields.contains_key("recurrence") {
+            match &fields["recurrence"] {
+                JsonValue::Null => {
+                    task.set_recurrence(None);
+                    set_event(&mut task, "recurrence", &self.nickname, "None")
+                }
+                JsonValue::String(recurrence) => {
+                    let recurrence: Recurrence = recurrence.parse()?;
+                    task.set_recurrence(Some(recurrence));
+                    set_event(&mut task, "recurrence", &self.nickname, &recurrence.to_string())
+                }
+                _ => return Err(TaudError::InvalidData("Invalid parameter \"recurrence\"".into())),
+            }
+        }
+
+        if fields.contains_key("reminders") {
+            let Some(reminders) = fields["reminders"].get::<Vec<JsonValue>>() else {
+                return Err(TaudError::InvalidData("Invalid parameter \"reminders\"".into()))
+            };
+
+            let mut timestamps = vec![];
+            for reminder in reminders {
+                let Some(ts) = reminder.get::<f64>() else {
+                    return Err(TaudError::InvalidData("Invalid parameter \"reminders\"".into()))
+                };
+                timestamps.push(Timestamp::from_u64(*ts as u64));
+            }
+            timestamps.sort();
+
+            let content: Vec<String> = timestamps.iter().map(|ts| ts.to_string()).collect();
+            task.set_reminders(&timestamps);
+            set_event(&mut task, "reminders", &self.nickname, &content.join(", "));
+        }
+
         Ok(task)
     }
 }
@@ -31,11 +31,11 @@ use crypto_box::{
 };
 use darkfi_serial::{
     async_trait, deserialize, deserialize_async_partial, serialize, serialize_async,
-    SerialDecodable, SerialEncodable,
+    FutAsyncWriteExt, SerialDecodable, SerialEncodable,
 };
 use futures::{select, FutureExt};
 use libc::mkfifo;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use rand::rngs::OsRng;
 use ring::{
     rand::SystemRandom,
@@ -50,7 +50,8 @@ use darkfi::{
     async_daemonize,
     event_graph::{
         proto::{EventPut, ProtocolEventGraph},
-        Event, EventGraph, EventGraphPtr,
+        query::MAX_QUERY_EVENTS,
+        Event, EventGraph, EventGraphPtr, EventValidator,
     },
     net::{session::SESSION_DEFAULT, P2p, P2pPtr},
     rpc::{
@@ -58,39 +59,62 @@ use darkfi::{
         server::{listen_and_serve, RequestHandler},
     },
     system::{sleep, StoppableTask},
-    util::path::{expand_path, get_config_path},
+    util::{
+        path::{expand_path, get_config_path},
+        time::Timestamp,
+    },
     Error, Result,
 };
 
 mod jsonrpc;
+mod reminders;
 mod settings;
 
 use taud::{
+    acl::{secret_box, AclState, AclStore, MemberKey, Role, Roster},
+    crdt::{TaskCrdt, TaskStore, TaskUpdate},
     error::{TaudError, TaudResult},
-    task_info::{TaskEvent, TaskInfo},
+    month_tasks::MonthTasks,
+    relations::drop_cyclic_relations,
+    task_info::{TaskEvent, TaskInfo, TaskSnapshot},
     util::pipe_write,
 };
 
 use crate::{
     jsonrpc::JsonRpcInterface,
+    reminders::reminders_loop,
     settings::{Args, CONFIG_FILE, CONFIG_FILE_CONTENTS},
 };
 
 struct Workspace {
-    read_key: ChaChaBox,
+    /// Workspace secret of the first epoch
+    read_key: [u8; 32],
     write_key: Option<Ed25519KeyPair>,
     write_pubkey: UnparsedPublicKey<Vec<u8>>,
+    member_key: Option<MemberKey>,
 }
 
 impl Workspace {
     fn new() -> Self {
-        let secret_key = SecretKey::generate(&mut OsRng);
         Self {
-            read_key: ChaChaBox::new(&secret_key.public_key(), &secret_key),
+            read_key: SecretKey::generate(&mut OsRng).to_bytes(),
             write_key: None,
             write_pubkey: UnparsedPublicKey::new(&ED25519, vec![0]),
+            member_key: None,
         }
     }
+
+    /// Key signing our events: the workspace's write key if we have it,
+    /// our member key otherwise.
+    fn sign_key(&self) -> Option<&Ed25519KeyPair> {
+        self.write_key.as_ref().or(self.member_key.as_ref().map(|key| key.sign_key()))
+    }
+
+    /// Our role in the workspace
+    fn role(&self, acl: &AclState) -> Option<Role> {
+        let sign_key = self.sign_key()?;
+        acl.role(sign_key.public_key().as_ref(), self.write_pubkey.as_ref())
+    }
 }
 
 #[derive(Debug, Clone, SerialEncodable, SerialDecodable)]
@@ -98,30 +122,69 @@ pub struct EncryptedTask {
     payload: String,
 }
 
+/// Event graph validator rejecting events that aren't encrypted tasks
+struct EncryptedTaskValidator;
+
+#[async_trait]
+impl EventValidator for EncryptedTaskValidator {
+    async fn validate(&self, event: &Event) -> std::result::Result<(), String> {
+        match deserialize_async_partial::<EncryptedTask>(event.content()).await {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("Invalid EncryptedTask: {e}")),
+        }
+    }
+}
+
+/// Task signed with the workspace's write key, as sent before workspaces
+/// had members
 #[derive(SerialEncodable, SerialDecodable)]
 struct SignedTask {
     task: Vec<u8>,
     signature: Vec<u8>,
 }
 
-impl SignedTask {
-    fn new(task: &TaskInfo, signature: Signature) -> Self {
-        Self { task: serialize(task), signature: signature.as_ref().to_vec() }
-    }
+/// Content of the events of a workspace
+#[derive(SerialEncodable, SerialDecodable)]
+pub enum Message {
+    Task(TaskUpdate),
+    Roster(Roster),
 }
 
-/// Sign then encrypt a task
-fn encrypt_sign_task(task: &TaskInfo, workspace: &Workspace) -> TaudResult<EncryptedTask> {
-    debug!(target: "taud", "start encrypting task");
-    if workspace.write_key.is_none() {
-        error!(target: "taud", "You don't have write access")
+/// Message signed by a member of the workspace, or with its write key
+#[derive(SerialEncodable, SerialDecodable)]
+struct SignedMessage {
+    message: Vec<u8>,
+    signer: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+impl SignedMessage {
+    fn new(message: &Message, sign_key: &Ed25519KeyPair) -> Self {
+        let message = serialize(message);
+        let signature: Signature = sign_key.sign(&message[..]);
+        Self {
+            message,
+            signer: sign_key.public_key().as_ref().to_vec(),
+            signature: signature.as_ref().to_vec(),
+        }
     }
-    let signature: Signature = workspace.write_key.as_ref().unwrap().sign(&serialize(task)[..]);
-    let signed_task = SignedTask::new(task, signature);
+}
+
+/// Sign then encrypt a message with provided workspace secret
+fn encrypt_sign_message(
+    message: &Message,
+    workspace: &Workspace,
+    secret: &[u8; 32],
+) -> TaudResult<EncryptedTask> {
+    debug!(target: "taud", "start encrypting message");
+    let Some(sign_key) = workspace.sign_key() else {
+        return Err(TaudError::EncryptionError("You don't have write access".to_string()))
+    };
+    let signed_message = SignedMessage::new(message, sign_key);
 
     let nonce = ChaChaBox::generate_nonce(&mut OsRng);
-    let payload = &serialize(&signed_task)[..];
-    let mut payload = workspace.read_key.encrypt(&nonce, payload)?;
+    let payload = &serialize(&signed_message)[..];
+    let mut payload = secret_box(secret).encrypt(&nonce, payload)?;
 
     let mut concat = vec![];
     concat.append(&mut nonce.as_slice().to_vec());
@@ -132,10 +195,7 @@ fn encrypt_sign_task(task: &TaskInfo, workspace: &Workspace) -> TaudResult<Encry
     Ok(EncryptedTask { payload })
 }
 
-fn try_decrypt_task(
-    encrypt_task: &EncryptedTask,
-    chacha_box: &ChaChaBox,
-) -> TaudResult<SignedTask> {
+fn try_decrypt_task(encrypt_task: &EncryptedTask, chacha_box: &ChaChaBox) -> TaudResult<Vec<u8>> {
     debug!(target: "taud", "start decrypting task");
 
     let bytes = match bs58::decode(&encrypt_task.payload).into_vec() {
@@ -156,9 +216,7 @@ fn try_decrypt_task(
     // let nonce = encrypt_task.nonce.as_slice();
     let decrypted_task = chacha_box.decrypt(nonce, message)?;
 
-    let signed_task = deserialize(&decrypted_task)?;
-
-    Ok(signed_task)
+    Ok(decrypted_task)
 }
 
 fn parse_configured_workspaces(data: &toml::Value) -> Result<HashMap<String, Workspace>> {
@@ -183,10 +241,7 @@ fn parse_configured_workspaces(data: &toml::Value) -> Result<HashMap<String, Wor
                     return Err(Error::ParseFailed("Workspace read_key not 32 bytes long"))
                 }
 
-                let read_key_bytes: [u8; 32] = read_key_bytes.try_into().unwrap();
-                let read_key = crypto_box::SecretKey::from(read_key_bytes);
-                let public = read_key.public_key();
-                ws.read_key = ChaChaBox::new(&public, &read_key);
+                ws.read_key = read_key_bytes.try_into().unwrap();
             } else {
                 return Err(Error::ParseFailed("Workspace read_key not a string"))
             }
@@ -229,6 +284,26 @@ fn parse_configured_workspaces(data: &toml::Value) -> Result<HashMap<String, Wor
             }
         }
 
+        if let Some(member_secret) = items.get("member_secret") {
+            if let Some(member_secret) = member_secret.as_str() {
+                if !member_secret.is_empty() {
+                    let Ok(secret) = bs58::decode(member_secret).into_vec() else {
+                        return Err(Error::ParseFailed("Workspace member_secret not valid base58"))
+                    };
+
+                    let Ok(secret) = <[u8; 32]>::try_from(secret) else {
+                        return Err(Error::ParseFailed("Workspace member_secret not 32 bytes long"))
+                    };
+
+                    let member_key = MemberKey::new(&secret);
+                    info!(target: "taud", "Member {} of {} workspace", member_key.id(), name);
+                    ws.member_key = Some(member_key);
+                }
+            } else {
+                return Err(Error::ParseFailed("Workspace member_secret not a string"))
+            }
+        }
+
         if let Some(wrt_key) = ws.write_key.as_ref() {
             if wrt_key.public_key().as_ref() != ws.write_pubkey.as_ref() {
                 error!(target: "taud", "Wrong keypair for {} workspace, the workspace is not added!", name);
@@ -287,12 +362,14 @@ pub async fn is_seen(
 #[allow(clippy::too_many_arguments)]
 async fn start_sync_loop(
     event_graph: EventGraphPtr,
-    broadcast_rcv: smol::channel::Receiver<TaskInfo>,
+    broadcast_rcv: smol::channel::Receiver<(String, Message)>,
     workspaces: Arc<HashMap<String, Workspace>>,
     sled_db: sled::Db,
     settings: Args,
     p2p: P2pPtr,
     seen: OnceLock<sled::Tree>,
+    task_store: TaskStore,
+    acl_store: AclStore,
 ) -> TaudResult<()> {
     let incoming = event_graph.event_pub.clone().subscribe().await;
 
@@ -300,11 +377,24 @@ async fn start_sync_loop(
         select! {
             // Process message from Tau client
             task_event = broadcast_rcv.recv().fuse() => {
-                let tk = task_event.map_err(Error::from)?;
-                if workspaces.contains_key(&tk.workspace) {
-                    let ws = workspaces.get(&tk.workspace).unwrap();
-                    let encrypted_task = encrypt_sign_task(&tk, ws)?;
-                    info!(target: "taud", "Send the task: ref: {}", tk.ref_id);
+                let (ws_name, message) = task_event.map_err(Error::from)?;
+                if workspaces.contains_key(&ws_name) {
+                    let ws = workspaces.get(&ws_name).unwrap();
+                    let acl = acl_store.load(&ws_name, &ws.read_key)?;
+                    // Rosters are encrypted with the read_key, so members
+                    // added later can read them and recover the secrets.
+                    let secret = match message {
+                        Message::Task(_) => acl.secret(),
+                        Message::Roster(_) => acl.read_key(),
+                    };
+                    let encrypted_task = match encrypt_sign_message(&message, ws, secret) {
+                        Ok(v) => v,
+                        Err(e) => {
+                            error!(target: "taud", "Failed encrypting message: {}", e);
+                            continue
+                        }
+                    };
+                    info!(target: "taud", "Send a message to workspace: {}", ws_name);
                     // Build a DAG event and return it.
                     let event = Event::new(
                         serialize_async(&encrypted_task).await,
@@ -338,52 +428,146 @@ async fn start_sync_loop(
                         continue
                     }
                 };
-                on_receive_task(&enc_task, &workspaces, &settings)
+                let published = Timestamp::from_u64(task_event.timestamp / 1000);
+                on_receive_task(&enc_task, published, &workspaces, &settings, &task_store, &acl_store)
                     .await?;
             }
         }
     }
 }
 
-/// Handle a received task, decrypt it, verify it, optionally write it
-/// to a named pipe and save it on disk.
+/// Handle a received message, decrypt it, verify it and check that its
+/// signer is allowed to send it. Rosters update the workspace's access
+/// control state, while task updates are merged into the task's
+/// replicated state, optionally written to a named pipe and saved on disk.
+/// `published` is the time of the event carrying the message, which
+/// bounds the clocks of task updates.
 async fn on_receive_task(
     enc_task: &EncryptedTask,
+    published: Timestamp,
     workspaces: &HashMap<String, Workspace>,
     settings: &Args,
+    task_store: &TaskStore,
+    acl_store: &AclStore,
 ) -> TaudResult<()> {
     for (ws_name, workspace) in workspaces.iter() {
-        let signed_task = try_decrypt_task(enc_task, &workspace.read_key);
-        if let Err(e) = signed_task {
-            debug!(target: "taud", "Unable to decrypt the task: {}", e);
+        let mut acl = acl_store.load(ws_name, &workspace.read_key)?;
+        let Some(decrypted) =
+            acl.secrets().find_map(|secret| try_decrypt_task(enc_task, &secret_box(secret)).ok())
+        else {
+            debug!(target: "taud", "Unable to decrypt the message for {}", ws_name);
+            continue
+        };
+
+        let datastore_path = expand_path(&settings.datastore)?;
+
+        let (message, signer, role) = match deserialize::<SignedMessage>(&decrypted) {
+            Ok(signed) => {
+                let signer = UnparsedPublicKey::new(&ED25519, &signed.signer);
+                if signer.verify(&signed.message, &signed.signature).is_err() {
+                    error!(target: "taud", "Message is not verified: invalid signature");
+                    continue
+                }
+
+                let role = acl.role(&signed.signer, workspace.write_pubkey.as_ref());
+                (deserialize(&signed.message)?, signed.signer, role)
+            }
+            // Tasks used to be signed with the workspace's write key
+            Err(_) => {
+                let signed_task: SignedTask = deserialize(&decrypted)?;
+                if workspace.write_pubkey.verify(&signed_task.task, &signed_task.signature).is_err()
+                {
+                    error!(target: "taud", "Task is not verified: wrong write_public_key");
+                    error!(target: "taud", "Task is not saved");
+                    continue
+                }
+
+                let update: TaskUpdate = match deserialize(&signed_task.task) {
+                    Ok(v) => v,
+                    // Tasks used to be sent as whole snapshots, which we turn
+                    // into operations against the state we have.
+                    Err(_) => {
+                        let task: TaskInfo = deserialize::<TaskSnapshot>(&signed_task.task)?.into();
+                        task_store.load(&task.ref_id, &datastore_path)?.diff_snapshot(&task)
+                    }
+                };
+
+                (Message::Task(update), workspace.write_pubkey.as_ref().to_vec(), Some(Role::Admin))
+            }
+        };
+
+        let update = match message {
+            Message::Task(update) => update,
+            Message::Roster(roster) => {
+                let root = workspace.write_pubkey.as_ref();
+                if let Err(e) = acl.apply(roster, &signer, root, workspace.member_key.as_ref()) {
+                    error!(target: "taud", "Roster of {} is not applied: {}", ws_name, e);
+                    continue
+                }
+
+                info!(target: "taud", "Update the roster of {}: version {}", ws_name, acl.roster.version);
+                acl_store.put(ws_name, &acl)?;
+                continue
+            }
+        };
+
+        if !role.is_some_and(|role| role.can_update(&update)) {
+            error!(target: "taud", "Task {} is not saved: signer isn't allowed to update it", update.ref_id);
             continue
         }
 
-        if workspace
-            .write_pubkey
-            .verify(&signed_task.as_ref().unwrap().task, &signed_task.as_ref().unwrap().signature)
-            .is_err()
-        {
-            // *verified.lock().await = false;
-            error!(target: "taud", "Task is not verified: wrong write_public_key");
-            error!(target: "taud", "Task is not saved");
+        let mut crdt = task_store.load(&update.ref_id, &datastore_path)?;
+        let loaded_task = crdt.to_task_info();
+        if let Err(e) = crdt.apply_update(&update, published) {
+            error!(target: "taud", "Task {} is not saved: {}", update.ref_id, e);
             continue
         }
-        // else {
-        //     *verified.lock().await = true;
-        // }
+        task_store.put(&crdt)?;
+
+        // Updates can be received before the task's creation, in which
+        // case they're applied once it arrives.
+        let Some(mut task) = crdt.to_task_info() else {
+            debug!(target: "taud", "Task {} is not created yet", update.ref_id);
+            continue
+        };
 
-        let mut task: TaskInfo = deserialize(&signed_task.unwrap().task)?;
         info!(target: "taud", "Save the task: ref: {}", task.ref_id);
         task.workspace.clone_from(ws_name);
-        let datastore_path = expand_path(&settings.datastore)?;
+
+        // Concurrent edits may have introduced a dependency cycle. Cycles
+        // are broken on the replicated state of the whole workspace, so
+        // relationships of other tasks may be dropped or restored too.
+        let stored = MonthTasks::load_current_tasks(&datastore_path, ws_name.clone(), true)?;
+        let mut crdts: HashMap<String, TaskCrdt> = HashMap::new();
+        let mut tasks = vec![];
+        for stored_task in stored.iter().filter(|t| t.ref_id != task.ref_id) {
+            let other = task_store.load(&stored_task.ref_id, &datastore_path)?;
+            let Some(mut other_task) = other.to_task_info() else { continue };
+            other_task.workspace.clone_from(ws_name);
+            crdts.insert(other_task.ref_id.clone(), other);
+            tasks.push(other_task);
+        }
+        crdts.insert(task.ref_id.clone(), crdt);
+        tasks.push(task);
+
+        let dropped = drop_cyclic_relations(&mut tasks, |t, r| crdts[&t.ref_id].relation_id(r));
+        for (ref_id, relation) in dropped {
+            warn!(target: "taud", "Task {} relation ignored as cyclic: {:?}", ref_id, relation);
+        }
+
+        let task = tasks.pop().unwrap();
+        for other_task in tasks {
+            if !stored.contains(&other_task) {
+                other_task.save(&datastore_path)?;
+            }
+        }
 
         // Push a notification to a fifo if set
         if settings.piped {
-            // if we can't load the task then it's a new task.
+            // if we didn't have the task then it's a new task.
             // otherwise it's a modification.
-            match TaskInfo::load(&task.ref_id, &datastore_path) {
-                Ok(loaded_task) => {
+            match loaded_task {
+                Some(loaded_task) => {
                     let loaded_events = loaded_task.events;
                     let mut events = task.events.clone();
                     events.retain(|ev| !loaded_events.contains(ev));
@@ -396,7 +580,7 @@ async fn on_receive_task(
                     let json: JsonValue = (&task_clone).into();
                     pipe_write.write_all(json.stringify().unwrap().as_bytes())?;
                 }
-                Err(_) => {
+                None => {
                     let file = settings.pipe_path.clone();
                     let mut pipe_write = pipe_write(file)?;
                     let mut task_clone = task.clone();
@@ -499,6 +683,19 @@ async fn realmain(settings: Args, executor: Arc<smol::Executor<'static>>) -> Res
         return Ok(())
     }
 
+    if settings.generate_member {
+        // Secret the member's signing and encryption keys are derived from
+        let secret = SecretKey::generate(&mut OsRng).to_bytes();
+        let member_key = MemberKey::new(&secret);
+
+        println!("Please add the following to the workspace's section of the config file:");
+        println!("member_secret = \"{}\"", bs58::encode(secret).into_string());
+        println!("Then ask an admin of the workspace to add your member id:");
+        println!("{}", member_key.id());
+
+        return Ok(())
+    }
+
     let workspaces = Arc::new(get_workspaces(&settings).await?);
     // let verified = Arc::new(Mutex::new(false));
 
@@ -519,6 +716,8 @@ async fn realmain(settings: Args, executor: Arc<smol::Executor<'static>>) -> Res
 
     info!(target: "taud", "Instantiating event DAG");
     let sled_db = sled::open(datastore)?;
+    let task_store = TaskStore::new(&sled_db)?;
+    let acl_store = AclStore::new(&sled_db)?;
     let p2p = P2p::new(settings.net.clone().into(), executor.clone()).await?;
     let event_graph = EventGraph::new(
         p2p.clone(),
@@ -527,6 +726,8 @@ async fn realmain(settings: Args, executor: Arc<smol::Executor<'static>>) -> Res
         replay_mode,
         "taud_dag",
         0,
+        false,
+        Some(Arc::new(EncryptedTaskValidator)),
         executor.clone(),
     )
     .await?;
@@ -541,7 +742,7 @@ async fn realmain(settings: Args, executor: Arc<smol::Executor<'static>>) -> Res
         })
         .await;
 
-    let (broadcast_snd, broadcast_rcv) = smol::channel::unbounded::<TaskInfo>();
+    let (broadcast_snd, broadcast_rcv) = smol::channel::unbounded::<(String, Message)>();
 
     info!(target: "taud", "Starting P2P network");
     p2p.clone().start().await?;
@@ -579,21 +780,37 @@ async fn realmain(settings: Args, executor: Arc<smol::Executor<'static>>) -> Res
     ////////////////////
     // get history
     ////////////////////
-    let dag_events = event_graph.order_events().await;
+    // Page through the DAG oldest first, instead of loading and sorting
+    // all of it at once. Task updates merge regardless of their order.
+    let mut cursor = (0, blake3::Hash::from_bytes([0x00; 32]));
+    loop {
+        let page = event_graph.events_after_position(cursor.0, &cursor.1, MAX_QUERY_EVENTS).await?;
+        let Some(last) = page.last() else { break };
+        cursor = (last.timestamp, last.id());
+
+        for event in page.iter() {
+            let event_id = event.id();
+            // If it was seen, skip
+            if is_seen(sled_db.clone(), seen.clone(), &event_id).await? {
+                continue
+            }
+            mark_seen(sled_db.clone(), seen.clone(), &event_id).await?;
 
-    for event in dag_events.iter() {
-        let event_id = event.id();
-        // If it was seen, skip
-        if is_seen(sled_db.clone(), seen.clone(), &event_id).await? {
-            continue
-        }
-        mark_seen(sled_db.clone(), seen.clone(), &event_id).await?;
+            // Try to deserialize it. (Here we skip errors)
+            let Ok((enc_task, _)) = deserialize_async_partial(event.content()).await else {
+                continue
+            };
 
-        // Try to deserialize it. (Here we skip errors)
-        let Ok((enc_task, _)) = deserialize_async_partial(event.content()).await else { continue };
+            // Potentially decrypt the privmsg
+            let published = Timestamp::from_u64(event.timestamp / 1000);
+            on_receive_task(&enc_task, published, &workspaces, &settings, &task_store, &acl_store)
+                .await
+                .unwrap();
+        }
 
-        // Potentially decrypt the privmsg
-        on_receive_task(&enc_task, &workspaces, &settings).await.unwrap();
+        if page.len() < MAX_QUERY_EVENTS {
+            break
+        }
     }
 
     ////////////////////
@@ -611,6 +828,8 @@ async fn realmain(settings: Args, executor: Arc<smol::Executor<'static>>) -> Res
             settings.clone(),
             p2p.clone(),
             seen.clone(),
+            task_store.clone(),
+            acl_store.clone(),
         ),
         |res| async {
             match res {
@@ -675,18 +894,41 @@ async fn realmain(settings: Args, executor: Arc<smol::Executor<'static>>) -> Res
         executor.clone(),
     );
 
+    info!(target: "taud", "Starting reminders task");
+    let reminders_sub = JsonSubscriber::new("reminders.subscribe_events");
+    let reminders_task = StoppableTask::new();
+    reminders_task.clone().start(
+        reminders_loop(
+            datastore_path.clone(),
+            workspaces.keys().cloned().collect(),
+            sled_db.clone(),
+            reminders_sub.clone(),
+        ),
+        |res| async {
+            match res {
+                Ok(()) | Err(TaudError::Darkfi(Error::DetachedTaskStopped)) => { /* Do nothing */ }
+                Err(e) => error!(target: "taud", "Failed stopping reminders task: {}", e),
+            }
+        },
+        TaudError::Darkfi(Error::DetachedTaskStopped),
+        executor.clone(),
+    );
+
     //
     // RPC interface
     //
     let rpc_interface = Arc::new(JsonRpcInterface::new(
         datastore_path.clone(),
         broadcast_snd,
+        task_store,
+        acl_store,
         nickname.unwrap(),
         workspaces.clone(),
         p2p.clone(),
         event_graph.clone(),
         json_sub,
         deg_sub,
+        reminders_sub,
     ));
     let rpc_task = StoppableTask::new();
     rpc_task.clone().start(
@@ -716,6 +958,7 @@ async fn realmain(settings: Args, executor: Arc<smol::Executor<'static>>) -> Res
     rpc_task.stop().await;
     dnet_task.stop().await;
     deg_task.stop().await;
+    reminders_task.stop().await;
 
     info!(target: "taud", "Flushing sled database...");
     let flushed_bytes = sled_db.flush_async().await?;
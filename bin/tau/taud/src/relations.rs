@@ -0,0 +1,348 @@
+/* This file is part of DarkFi (https://dark.fi)
+ *
+ * Copyright (C) 2020-2024 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Relationships between tasks.
+//!
+//! A task may be a subtask of another one, and may be blocked by other
+//! tasks. A task depends on its blockers and on its subtasks, so none of
+//! them may in turn depend on it.
+//!
+//! Relationships received from other replicas may still form cycles,
+//! as concurrent edits are merged without checking them. Such cycles are
+//! broken when the tasks are materialized, by dropping the relationships
+//! set by the latest operations, so every replica drops the same ones.
+//! They stay in the replicated state, which edits are diffed against.
+//!
+//! Recurring tasks spawn their next instance, along with instances of
+//! their subtasks, when they are stopped. The identifiers of the new
+//! instances are derived from the stopped ones, so that stopping a task
+//! more than once doesn't spawn duplicates.
+
+use std::collections::{HashMap, HashSet};
+
+use darkfi::util::time::Timestamp;
+
+use crate::{
+    error::{TaudError, TaudResult},
+    task_info::{Recurrence, TaskInfo},
+    util::derive_id,
+};
+
+/// Check that the tasks referenced by provided task exist among the
+/// workspace's tasks, and that its relationships don't introduce a
+/// dependency cycle.
+pub fn check_relations(task: &TaskInfo, tasks: &[TaskInfo]) -> TaudResult<()> {
+    let mut all: HashMap<&str, &TaskInfo> = tasks.iter().map(|t| (t.ref_id.as_str(), t)).collect();
+    all.insert(&task.ref_id, task);
+
+    for ref_id in task.parent.iter().chain(task.blocked_by.iter()) {
+        if *ref_id == task.ref_id {
+            return Err(TaudError::InvalidData(format!("Task {} can't depend on itself", ref_id)))
+        }
+        if !all.contains_key(ref_id.as_str()) {
+            return Err(TaudError::InvalidData(format!("Task {} not found", ref_id)))
+        }
+    }
+
+    if has_cycle(task, tasks) {
+        return Err(TaudError::InvalidData(format!("Dependency cycle through task {}", task.ref_id)))
+    }
+
+    Ok(())
+}
+
+/// A relationship of a task to another one
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Relation {
+    /// The task is a subtask of the other one
+    Parent(String),
+    /// The task is blocked by the other one
+    BlockedBy(String),
+}
+
+/// Drop the relationships among the workspace's tasks which introduce a
+/// dependency cycle. Relationships are added back in the order given by
+/// `order`, i.e. the operations which set them, and the ones closing a
+/// cycle are dropped, so the result doesn't depend on the order the
+/// tasks were received in. Returns the dropped relationships, along with
+/// the references of their tasks.
+pub fn drop_cyclic_relations<K: Ord>(
+    tasks: &mut [TaskInfo],
+    order: impl Fn(&TaskInfo, &Relation) -> K,
+) -> Vec<(String, Relation)> {
+    let mut relations = vec![];
+    for (i, task) in tasks.iter().enumerate() {
+        let parent = task.parent.iter().cloned().map(Relation::Parent);
+        let blocked_by = task.blocked_by.iter().cloned().map(Relation::BlockedBy);
+        for relation in parent.chain(blocked_by) {
+            relations.push((order(task, &relation), task.ref_id.clone(), relation, i));
+        }
+    }
+    relations.sort();
+
+    for task in tasks.iter_mut() {
+        task.parent = None;
+        task.blocked_by.clear();
+    }
+
+    let mut dropped = vec![];
+    for (_, ref_id, relation, i) in relations {
+        match &relation {
+            Relation::Parent(parent) => tasks[i].parent = Some(parent.clone()),
+            Relation::BlockedBy(blocker) => tasks[i].blocked_by.push(blocker.clone()),
+        }
+
+        if has_cycle(&tasks[i], tasks) {
+            match relation {
+                Relation::Parent(_) => tasks[i].parent = None,
+                Relation::BlockedBy(_) => {
+                    tasks[i].blocked_by.pop();
+                }
+            }
+            dropped.push((ref_id, relation));
+        }
+    }
+
+    dropped
+}
+
+/// Add back the relationships of the replicated state of an edited task
+/// which were dropped when it was materialized and which the edit didn't
+/// change, so diffing it against the replicated state doesn't remove them.
+/// `stored` is the materialized task it was edited from.
+pub fn restore_dropped_relations(task: &mut TaskInfo, stored: &TaskInfo, replicated: &TaskInfo) {
+    if task.parent == stored.parent {
+        task.parent.clone_from(&replicated.parent);
+    }
+
+    for blocker in &replicated.blocked_by {
+        if !stored.blocked_by.contains(blocker) && !task.blocked_by.contains(blocker) {
+            task.blocked_by.push(blocker.clone());
+        }
+    }
+}
+
+/// Whether the relationships of provided task introduce a dependency
+/// cycle among the workspace's tasks.
+fn has_cycle(task: &TaskInfo, tasks: &[TaskInfo]) -> bool {
+    let mut all: HashMap<&str, &TaskInfo> = tasks.iter().map(|t| (t.ref_id.as_str(), t)).collect();
+    all.insert(&task.ref_id, task);
+
+    // Edges from each task to the tasks it depends on
+    let mut deps: HashMap<&str, Vec<&str>> = HashMap::new();
+    for t in all.values() {
+        for blocker in &t.blocked_by {
+            deps.entry(&t.ref_id).or_default().push(blocker);
+        }
+        if let Some(parent) = &t.parent {
+            deps.entry(parent).or_default().push(&t.ref_id);
+        }
+    }
+
+    // Only the edges of provided task changed, so any new cycle goes
+    // through it.
+    let mut visited = HashSet::new();
+    let mut stack: Vec<&str> = deps.get(task.ref_id.as_str()).cloned().unwrap_or_default();
+    while let Some(ref_id) = stack.pop() {
+        if ref_id == task.ref_id {
+            return true
+        }
+        if visited.insert(ref_id) {
+            stack.extend(deps.get(ref_id).into_iter().flatten());
+        }
+    }
+
+    false
+}
+
+/// Move a timestamp forward by the recurrence, until it's after `now`.
+fn advance(ts: Timestamp, recurrence: &Recurrence, now: Timestamp) -> Timestamp {
+    let mut ts = recurrence.next(ts);
+    while ts <= now {
+        ts = recurrence.next(ts);
+    }
+    ts
+}
+
+/// Next instances of a stopped recurring task and of its direct subtasks.
+/// Returns nothing if the task doesn't recur.
+pub fn next_instances(task: &TaskInfo, subtasks: &[TaskInfo], now: Timestamp) -> Vec<TaskInfo> {
+    let Some(recurrence) = task.recurrence else { return vec![] };
+
+    let ids: HashMap<&str, String> =
+        subtasks.iter().chain([task]).map(|t| (t.ref_id.as_str(), derive_id(&t.ref_id))).collect();
+
+    let instance = |t: &TaskInfo, parent: Option<String>| {
+        let mut next = t.clone();
+        next.ref_id = ids[t.ref_id.as_str()].clone();
+        next.parent = parent;
+        next.state = "open".to_string();
+        next.created_at = now;
+        next.events = vec![];
+        next.comments = vec![];
+        next.due = t.due.map(|due| advance(due, &recurrence, now));
+        next.reminders = t.reminders.iter().map(|r| advance(*r, &recurrence, now)).collect();
+        // Blockers among the subtasks are replaced by their next instance
+        for blocker in next.blocked_by.iter_mut() {
+            if let Some(id) = ids.get(blocker.as_str()) {
+                blocker.clone_from(id);
+            }
+        }
+        next
+    };
+
+    let root = instance(task, task.parent.clone());
+    let mut instances: Vec<TaskInfo> =
+        subtasks.iter().map(|t| instance(t, Some(root.ref_id.clone()))).collect();
+    instances.insert(0, root);
+    instances
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn task(title: &str) -> TaskInfo {
+        TaskInfo::new("ws".to_string(), title, "", "alice", None, None, Timestamp::from_u64(1))
+            .unwrap()
+    }
+
+    #[test]
+    fn reject_cycles() {
+        let a = task("a");
+        let mut b = task("b");
+        let mut c = task("c");
+
+        // c is a subtask of a, and b is blocked by c
+        c.set_parent(Some(a.ref_id.clone()));
+        b.set_blocked_by(&[format!("+{}", c.ref_id)]);
+        let tasks = vec![a.clone(), b.clone(), c.clone()];
+        check_relations(&c, &tasks).unwrap();
+        check_relations(&b, &tasks).unwrap();
+
+        // a being blocked by b is fine, c being blocked by b isn't
+        let mut a_ = a.clone();
+        a_.set_blocked_by(&[format!("+{}", b.ref_id)]);
+        check_relations(&a_, &tasks).unwrap();
+        let tasks = vec![a_.clone(), b.clone(), c.clone()];
+        let mut c_ = c.clone();
+        c_.set_blocked_by(&[format!("+{}", b.ref_id)]);
+        assert!(check_relations(&c_, &tasks).is_err());
+
+        // Neither is a being a subtask of c, nor blocking itself
+        let mut a_ = a.clone();
+        a_.set_parent(Some(c.ref_id.clone()));
+        assert!(check_relations(&a_, &tasks).is_err());
+        let mut b_ = b.clone();
+        b_.set_blocked_by(&[format!("+{}", b.ref_id)]);
+        assert!(check_relations(&b_, &tasks).is_err());
+
+        // Referenced tasks must exist
+        let mut b_ = b;
+        b_.set_parent(Some("unknown".to_string()));
+        assert!(check_relations(&b_, &tasks).is_err());
+    }
+
+    #[test]
+    fn drop_received_cycles() {
+        let mut a = task("a");
+        let mut b = task("b");
+        let c = task("c");
+
+        // b is blocked by a, and a is then received as a subtask of
+        // itself, blocked by b and c
+        b.set_blocked_by(&[format!("+{}", a.ref_id)]);
+        a.set_blocked_by(&[format!("+{}", b.ref_id), format!("+{}", c.ref_id)]);
+        a.set_parent(Some(a.ref_id.clone()));
+
+        let b_on_a = (b.ref_id.clone(), Relation::BlockedBy(a.ref_id.clone()));
+        let a_on_b = (a.ref_id.clone(), Relation::BlockedBy(b.ref_id.clone()));
+        let a_in_a = (a.ref_id.clone(), Relation::Parent(a.ref_id.clone()));
+        let a_on_c = (a.ref_id.clone(), Relation::BlockedBy(c.ref_id.clone()));
+        let mut ids: HashMap<(String, Relation), u64> = HashMap::from([
+            (b_on_a.clone(), 1),
+            (a_in_a.clone(), 2),
+            (a_on_b.clone(), 3),
+            (a_on_c, 4),
+        ]);
+
+        // Every replica drops the latest relationships closing a cycle,
+        // whatever order it has the tasks in
+        let mut tasks = vec![a.clone(), b.clone(), c.clone()];
+        let dropped = drop_cyclic_relations(&mut tasks, |t, r| ids[&(t.ref_id.clone(), r.clone())]);
+        assert_eq!(dropped, vec![a_in_a, a_on_b.clone()]);
+        assert_eq!(tasks[0].parent, None);
+        assert_eq!(tasks[0].blocked_by, vec![c.ref_id.clone()]);
+        assert_eq!(tasks[1].blocked_by, vec![a.ref_id.clone()]);
+        check_relations(&tasks[0], &tasks).unwrap();
+
+        let mut reversed = vec![c.clone(), b.clone(), a.clone()];
+        let dropped_ =
+            drop_cyclic_relations(&mut reversed, |t, r| ids[&(t.ref_id.clone(), r.clone())]);
+        assert_eq!(dropped_, dropped);
+        assert_eq!(reversed, tasks.into_iter().rev().collect::<Vec<_>>());
+
+        // Had a been blocked by b first, b's blocker would be dropped
+        ids.insert(b_on_a.clone(), 5);
+        let mut tasks = vec![a.clone(), b.clone(), c.clone()];
+        let dropped = drop_cyclic_relations(&mut tasks, |t, r| ids[&(t.ref_id.clone(), r.clone())]);
+        assert_eq!(dropped[1], b_on_a);
+        assert_eq!(tasks[0].blocked_by, vec![b.ref_id.clone(), c.ref_id.clone()]);
+        assert!(tasks[1].blocked_by.is_empty());
+
+        // Editing a keeps the dropped relationships in its replicated state
+        let stored = tasks[0].clone();
+        let mut edited = stored.clone();
+        edited.set_title("edited");
+        restore_dropped_relations(&mut edited, &stored, &a);
+        assert_eq!(edited.parent, a.parent);
+        assert_eq!(edited.blocked_by, a.blocked_by);
+    }
+
+    #[test]
+    fn spawn_next_instances() {
+        let day = 86400;
+        let now = Timestamp::from_u64(10 * day);
+
+        let mut release = task("release");
+        release.set_recurrence(Some("weekly".parse().unwrap()));
+        release.set_due(Some(Timestamp::from_u64(day)));
+        release.set_state("stop");
+
+        let mut build = task("build");
+        build.set_parent(Some(release.ref_id.clone()));
+        let mut tag = task("tag");
+        tag.set_parent(Some(release.ref_id.clone()));
+        tag.set_blocked_by(&[format!("+{}", build.ref_id)]);
+
+        assert!(next_instances(&build, &[], now).is_empty());
+
+        let subtasks = vec![build.clone(), tag.clone()];
+        let next = next_instances(&release, &subtasks, now);
+        assert_eq!(next.len(), 3);
+        assert_eq!(next, next_instances(&release, &subtasks, now));
+
+        assert_eq!(next[0].ref_id, derive_id(&release.ref_id));
+        assert_eq!(next[0].state, "open");
+        assert_eq!(next[0].due, Some(Timestamp::from_u64(15 * day)));
+        assert_eq!(next[0].recurrence, release.recurrence);
+        assert_eq!(next[1].parent, Some(next[0].ref_id.clone()));
+        assert_eq!(next[2].parent, Some(next[0].ref_id.clone()));
+        assert_eq!(next[2].blocked_by, vec![next[1].ref_id.clone()]);
+    }
+}
@@ -0,0 +1,90 @@
+/* This file is part of DarkFi (https://dark.fi)
+ *
+ * Copyright (C) 2020-2024 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::{collections::HashMap, path::PathBuf};
+
+use log::{debug, info};
+use sled_overlay::sled;
+use tinyjson::JsonValue;
+
+use darkfi::{rpc::jsonrpc::JsonSubscriber, system::sleep, util::time::Timestamp, Error};
+
+use taud::{error::TaudResult, month_tasks::MonthTasks, task_info::TaskInfo};
+
+/// sled tree holding the reminders which were already sent
+const SLED_REMINDERS_TREE: &[u8] = b"tau_reminders";
+
+/// Seconds between checks for due reminders
+const REMINDERS_INTERVAL: u64 = 30;
+
+/// Reminders older than this many seconds are dropped instead of being
+/// sent, e.g. when the daemon was offline when they were due.
+const REMINDERS_MAX_AGE: u64 = 86400;
+
+fn reminder_json(task: &TaskInfo, reminder: &Timestamp) -> JsonValue {
+    let due = match task.due {
+        Some(due) => JsonValue::String(due.inner().to_string()),
+        None => JsonValue::Null,
+    };
+
+    JsonValue::Object(HashMap::from([
+        ("workspace".to_string(), JsonValue::String(task.workspace.clone())),
+        ("ref_id".to_string(), JsonValue::String(task.ref_id.clone())),
+        ("title".to_string(), JsonValue::String(task.title.clone())),
+        ("due".to_string(), due),
+        ("reminder".to_string(), JsonValue::String(reminder.inner().to_string())),
+    ]))
+}
+
+/// Periodically look for due reminders of the open tasks of the given
+/// workspaces, and notify the subscribers of each of them once.
+pub async fn reminders_loop(
+    dataset_path: PathBuf,
+    workspaces: Vec<String>,
+    sled_db: sled::Db,
+    reminders_sub: JsonSubscriber,
+) -> TaudResult<()> {
+    let sent = sled_db.open_tree(SLED_REMINDERS_TREE).map_err(Error::from)?;
+
+    loop {
+        let now = Timestamp::current_time();
+
+        for ws in workspaces.iter() {
+            let tasks = MonthTasks::load_current_tasks(&dataset_path, ws.clone(), false)?;
+
+            for task in tasks.iter() {
+                for reminder in task.reminders.iter().filter(|r| **r <= now) {
+                    let key = format!("{}:{}", task.ref_id, reminder.inner());
+                    if sent.insert(key.as_bytes(), &[]).map_err(Error::from)?.is_some() {
+                        continue
+                    }
+
+                    if now.inner() - reminder.inner() > REMINDERS_MAX_AGE {
+                        debug!(target: "taud", "Dropping stale reminder of task {}", task.ref_id);
+                        continue
+                    }
+
+                    info!(target: "taud", "Reminder of task {}: {}", task.ref_id, task.title);
+                    reminders_sub.notify(vec![reminder_json(task, reminder)].into()).await;
+                }
+            }
+        }
+
+        sleep(REMINDERS_INTERVAL).await;
+    }
+}
@@ -61,6 +61,10 @@ pub struct Args {
     #[structopt(long)]
     pub generate: bool,
 
+    /// Generate a new workspace member secret
+    #[structopt(long)]
+    pub generate_member: bool,
+
     /// Secret Key To Encrypt/Decrypt tasks
     #[structopt(long)]
     pub workspaces: Vec<String>,
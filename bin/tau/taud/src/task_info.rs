@@ -23,7 +23,8 @@ use std::{
     str::FromStr,
 };
 
-use darkfi_serial::{async_trait, SerialDecodable, SerialEncodable};
+use chrono::{Months, TimeZone, Utc};
+use darkfi_serial::{async_trait, FutAsyncWriteExt, SerialDecodable, SerialEncodable};
 use log::debug;
 use tinyjson::JsonValue;
 
@@ -188,6 +189,77 @@ impl Comment {
     }
 }
 
+#[derive(Clone, Copy, Debug, SerialEncodable, SerialDecodable, PartialEq, Eq)]
+pub enum RecurrenceUnit {
+    Day,
+    Week,
+    Month,
+}
+
+/// Recurrence rule of a task. When a recurring task is stopped, its next
+/// instance is spawned, due `every` units of time after it.
+#[derive(Clone, Copy, Debug, SerialEncodable, SerialDecodable, PartialEq, Eq)]
+pub struct Recurrence {
+    pub every: u32,
+    pub unit: RecurrenceUnit,
+}
+
+impl Recurrence {
+    /// Time of the occurrence following provided one.
+    pub fn next(&self, ts: Timestamp) -> Timestamp {
+        let secs = match self.unit {
+            RecurrenceUnit::Day => ts.inner() + self.every as u64 * 86400,
+            RecurrenceUnit::Week => ts.inner() + self.every as u64 * 7 * 86400,
+            RecurrenceUnit::Month => {
+                let date = Utc.timestamp_opt(ts.inner() as i64, 0).unwrap();
+                date.checked_add_months(Months::new(self.every)).unwrap().timestamp() as u64
+            }
+        };
+
+        Timestamp::from_u64(secs)
+    }
+}
+
+impl fmt::Display for Recurrence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let unit = match self.unit {
+            RecurrenceUnit::Day => "d",
+            RecurrenceUnit::Week => "w",
+            RecurrenceUnit::Month => "m",
+        };
+        write!(f, "{}{}", self.every, unit)
+    }
+}
+
+impl FromStr for Recurrence {
+    type Err = Error;
+
+    /// Parses `daily`, `weekly` and `monthly`, or a number of days, weeks
+    /// or months like `2d`, `3w` or `6m`.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let s = s.to_lowercase();
+        let (every, unit) = match s.as_str() {
+            "daily" => ("1", "d"),
+            "weekly" => ("1", "w"),
+            "monthly" => ("1", "m"),
+            _ if s.len() > 1 => s.split_at(s.len() - 1),
+            _ => return Err(Error::ParseFailed("unable to parse recurrence")),
+        };
+
+        let unit = match unit {
+            "d" => RecurrenceUnit::Day,
+            "w" => RecurrenceUnit::Week,
+            "m" => RecurrenceUnit::Month,
+            _ => return Err(Error::ParseFailed("unable to parse recurrence")),
+        };
+
+        match every.parse::<u32>() {
+            Ok(every) if every > 0 => Ok(Self { every, unit }),
+            _ => Err(Error::ParseFailed("unable to parse recurrence")),
+        }
+    }
+}
+
 #[derive(Clone, Debug, SerialEncodable, SerialDecodable, PartialEq)]
 pub struct TaskInfo {
     pub ref_id: String,
@@ -204,6 +276,58 @@ pub struct TaskInfo {
     pub state: String,
     pub events: Vec<TaskEvent>,
     pub comments: Vec<Comment>,
+    /// Task this task is a subtask of
+    pub parent: Option<String>,
+    /// Tasks which have to be stopped before this one
+    pub blocked_by: Vec<String>,
+    pub recurrence: Option<Recurrence>,
+    /// Times at which a reminder of the task is sent
+    pub reminders: Vec<Timestamp>,
+}
+
+/// Layout tasks were sent over the network with, before tasks were
+/// replicated as operations.
+#[derive(SerialDecodable)]
+pub struct TaskSnapshot {
+    pub ref_id: String,
+    pub workspace: String,
+    pub title: String,
+    pub tags: Vec<String>,
+    pub desc: String,
+    pub owner: String,
+    pub assign: Vec<String>,
+    pub project: Vec<String>,
+    pub due: Option<Timestamp>,
+    pub rank: Option<f32>,
+    pub created_at: Timestamp,
+    pub state: String,
+    pub events: Vec<TaskEvent>,
+    pub comments: Vec<Comment>,
+}
+
+impl From<TaskSnapshot> for TaskInfo {
+    fn from(task: TaskSnapshot) -> TaskInfo {
+        TaskInfo {
+            ref_id: task.ref_id,
+            workspace: task.workspace,
+            title: task.title,
+            tags: task.tags,
+            desc: task.desc,
+            owner: task.owner,
+            assign: task.assign,
+            project: task.project,
+            due: task.due,
+            rank: task.rank,
+            created_at: task.created_at,
+            state: task.state,
+            events: task.events,
+            comments: task.comments,
+            parent: None,
+            blocked_by: vec![],
+            recurrence: None,
+            reminders: vec![],
+        }
+    }
 }
 
 impl From<&TaskInfo> for JsonValue {
@@ -238,6 +362,22 @@ impl From<&TaskInfo> for JsonValue {
         let events: Vec<JsonValue> = task.events.iter().map(|x| x.clone().into()).collect();
         let comments: Vec<JsonValue> = task.comments.iter().map(|x| x.clone().into()).collect();
 
+        let parent = match &task.parent {
+            Some(parent) => JsonValue::String(parent.clone()),
+            None => JsonValue::Null,
+        };
+
+        let blocked_by: Vec<JsonValue> =
+            task.blocked_by.iter().map(|x| JsonValue::String(x.clone())).collect();
+
+        let recurrence = match task.recurrence {
+            Some(recurrence) => JsonValue::String(recurrence.to_string()),
+            None => JsonValue::Null,
+        };
+
+        let reminders: Vec<JsonValue> =
+            task.reminders.iter().map(|x| JsonValue::String(x.inner().to_string())).collect();
+
         JsonValue::Object(HashMap::from([
             ("ref_id".to_string(), ref_id),
             ("workspace".to_string(), workspace),
@@ -253,6 +393,10 @@ impl From<&TaskInfo> for JsonValue {
             ("state".to_string(), state),
             ("events".to_string(), JsonValue::Array(events)),
             ("comments".to_string(), JsonValue::Array(comments)),
+            ("parent".to_string(), parent),
+            ("blocked_by".to_string(), JsonValue::Array(blocked_by)),
+            ("recurrence".to_string(), recurrence),
+            ("reminders".to_string(), JsonValue::Array(reminders)),
         ]))
     }
 }
@@ -290,6 +434,35 @@ impl From<JsonValue> for TaskInfo {
         let events: Vec<TaskEvent> = events.iter().map(|x| x.into()).collect();
         let comments: Vec<Comment> = comments.iter().map(|x| (*x).clone().into()).collect();
 
+        // Tasks saved before relationships, recurrence and reminders
+        // were introduced don't have them.
+        let map = value.get::<HashMap<String, JsonValue>>().unwrap();
+
+        let parent = map.get("parent").and_then(|x| x.get::<String>()).cloned();
+
+        let blocked_by = match map.get("blocked_by") {
+            Some(v) => v
+                .get::<Vec<JsonValue>>()
+                .unwrap()
+                .iter()
+                .map(|x| x.get::<String>().unwrap().clone())
+                .collect(),
+            None => vec![],
+        };
+
+        let recurrence =
+            map.get("recurrence").and_then(|x| x.get::<String>()).map(|x| x.parse().unwrap());
+
+        let reminders = match map.get("reminders") {
+            Some(v) => v
+                .get::<Vec<JsonValue>>()
+                .unwrap()
+                .iter()
+                .map(|x| Timestamp::from_u64(x.get::<String>().unwrap().parse::<u64>().unwrap()))
+                .collect(),
+            None => vec![],
+        };
+
         TaskInfo {
             ref_id: value["ref_id"].get::<String>().unwrap().clone(),
             workspace: value["workspace"].get::<String>().unwrap().clone(),
@@ -305,6 +478,10 @@ impl From<JsonValue> for TaskInfo {
             state: value["state"].get::<String>().unwrap().clone(),
             events,
             comments,
+            parent,
+            blocked_by,
+            recurrence,
+            reminders,
         }
     }
 }
@@ -343,6 +520,10 @@ impl TaskInfo {
             state: "open".into(),
             comments: vec![],
             events: vec![],
+            parent: None,
+            blocked_by: vec![],
+            recurrence: None,
+            reminders: vec![],
         })
     }
 
@@ -452,6 +633,34 @@ impl TaskInfo {
         self.due = d;
     }
 
+    pub fn set_parent(&mut self, parent: Option<String>) {
+        debug!(target: "tau", "TaskInfo::set_parent()");
+        self.parent = parent;
+    }
+
+    pub fn set_blocked_by(&mut self, blocked_by: &[String]) {
+        debug!(target: "tau", "TaskInfo::set_blocked_by()");
+        for blocker in blocked_by.iter() {
+            let stripped = &blocker[1..];
+            if blocker.starts_with('+') && !self.blocked_by.contains(&stripped.to_string()) {
+                self.blocked_by.push(stripped.to_string());
+            }
+            if blocker.starts_with('-') {
+                self.blocked_by.retain(|blocker| blocker != stripped);
+            }
+        }
+    }
+
+    pub fn set_recurrence(&mut self, recurrence: Option<Recurrence>) {
+        debug!(target: "tau", "TaskInfo::set_recurrence()");
+        self.recurrence = recurrence;
+    }
+
+    pub fn set_reminders(&mut self, reminders: &[Timestamp]) {
+        debug!(target: "tau", "TaskInfo::set_reminders()");
+        reminders.clone_into(&mut self.reminders);
+    }
+
     pub fn set_state(&mut self, state: &str) {
         debug!(target: "tau", "TaskInfo::set_state()");
         if self.get_state() == state {
@@ -460,3 +669,25 @@ impl TaskInfo {
         self.state = state.to_string();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recurrence() {
+        let weekly: Recurrence = "weekly".parse().unwrap();
+        assert_eq!(weekly, Recurrence { every: 1, unit: RecurrenceUnit::Week });
+        assert_eq!(weekly.to_string(), "1w");
+        assert_eq!("2D".parse::<Recurrence>().unwrap().to_string(), "2d");
+        assert!("0d".parse::<Recurrence>().is_err());
+        assert!("d".parse::<Recurrence>().is_err());
+        assert!("3y".parse::<Recurrence>().is_err());
+
+        // 2024-01-31 00:00:00 UTC
+        let ts = Timestamp::from_u64(1706659200);
+        assert_eq!(weekly.next(ts).inner(), 1706659200 + 7 * 86400);
+        // 2024-02-29 00:00:00 UTC
+        assert_eq!("1m".parse::<Recurrence>().unwrap().next(ts).inner(), 1709164800);
+    }
+}
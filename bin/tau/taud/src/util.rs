@@ -43,3 +43,9 @@ pub fn pipe_write<P: AsRef<Path>>(path: P) -> Result<File> {
 pub fn gen_id(len: usize) -> String {
     OsRng.sample_iter(&Alphanumeric).take(len).map(char::from).collect()
 }
+
+/// Derive a task identifier from another one, the same way on every node.
+pub fn derive_id(ref_id: &str) -> String {
+    let hash = blake3::hash(ref_id.as_bytes());
+    bs58::encode(hash.as_bytes()).into_string().chars().take(30).collect()
+}
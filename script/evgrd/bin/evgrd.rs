@@ -284,6 +284,8 @@ async fn realmain(args: Args, ex: Arc<Executor<'static>>) -> Result<()> {
         replay_mode,
         "evgrd_dag",
         1,
+        false,
+        None,
         ex.clone(),
     )
     .await?;
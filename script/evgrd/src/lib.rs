@@ -181,6 +181,9 @@ impl LocalEventGraph {
                 content: GENESIS_CONTENTS.to_vec(),
                 parents: [NULL_ID; N_EVENT_PARENTS],
                 layer: 0,
+                author: None,
+                rln: None,
+                topic: None,
             };
 
             // Sleep until it's time to rotate.
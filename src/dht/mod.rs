@@ -0,0 +1,560 @@
+/* This file is part of DarkFi (https://dark.fi)
+ *
+ * Copyright (C) 2020-2024 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Kademlia distributed hash table.
+//!
+//! Nodes are identified by 256-bit IDs, and the distance between two IDs
+//! is their XOR, interpreted as a big-endian integer. Each node keeps a
+//! [`RoutingTable`] of the nodes it knows, with more entries close to its
+//! own ID, so any other ID can be reached in `O(log n)` hops.
+//!
+//! Lookups are iterative: the looking up node asks the `alpha` closest
+//! nodes it knows about for their closest nodes to the target, and keeps
+//! asking the closest ones it learned of, until the `k` closest nodes
+//! have all responded.
+//!
+//! Instead of storing values, the DHT stores provider records, mapping
+//! a key to the nodes that can serve it, e.g. a file hash to the nodes
+//! holding the file. Nodes announce themselves as providers of a key to
+//! the `k` nodes closest to it with [`Dht::provide`], and others find
+//! them with [`Dht::find_providers`]. Records expire after a TTL, so
+//! providers republish theirs periodically while they keep providing.
+//!
+//! Every message carries the contact of its sender, so nodes learn about
+//! each other while they talk. Transport is abstracted by the
+//! [`DhtNetwork`] trait, which [`ProtocolDht`] implements over the P2P
+//! network.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use darkfi_serial::{SerialDecodable, SerialEncodable};
+use futures::future::join_all;
+use log::{debug, error, info};
+use smol::lock::RwLock;
+use url::Url;
+
+use crate::{
+    system::{sleep, ExecutorPtr, StoppableTask, StoppableTaskPtr},
+    Error, Result,
+};
+
+/// Kademlia routing table
+pub mod routing;
+pub use routing::RoutingTable;
+
+/// DHT P2P protocol messages and handler
+pub mod proto;
+pub use proto::{
+    DhtAddProviderReply, DhtAddProviderRequest, DhtFindNodeReply, DhtFindNodeRequest,
+    DhtFindValueReply, DhtFindValueRequest, ProtocolDht, ProtocolDhtPtr,
+};
+
+#[cfg(test)]
+mod tests;
+
+/// Contact information of a DHT node.
+#[derive(Clone, Debug, PartialEq, Eq, SerialEncodable, SerialDecodable)]
+pub struct DhtNode {
+    /// Node ID
+    pub id: blake3::Hash,
+    /// Addresses the node can be reached at
+    pub addresses: Vec<Url>,
+}
+
+/// XOR distance between two IDs.
+pub fn distance(a: &blake3::Hash, b: &blake3::Hash) -> [u8; blake3::OUT_LEN] {
+    let mut distance = [0; blake3::OUT_LEN];
+    for (i, (x, y)) in a.as_bytes().iter().zip(b.as_bytes()).enumerate() {
+        distance[i] = x ^ y;
+    }
+    distance
+}
+
+/// DHT configuration.
+#[derive(Clone, Debug)]
+pub struct DhtSettings {
+    /// Bucket size, and number of nodes records are stored at
+    pub k: usize,
+    /// Number of concurrent requests during lookups
+    pub alpha: usize,
+    /// Time provider records are kept for (in seconds)
+    pub provider_ttl: u64,
+    /// Interval to republish our provider records at (in seconds)
+    pub republish_interval: u64,
+    /// Maximum number of provider records we store per key
+    pub max_providers_per_key: usize,
+    /// Maximum number of provider records we store per sender
+    pub max_records_per_sender: usize,
+}
+
+impl Default for DhtSettings {
+    fn default() -> Self {
+        Self {
+            k: 20,
+            alpha: 3,
+            provider_ttl: 86400,
+            republish_interval: 3600,
+            max_providers_per_key: 64,
+            max_records_per_sender: 1024,
+        }
+    }
+}
+
+/// Transport used by the DHT to send requests to other nodes.
+#[async_trait]
+pub trait DhtNetwork: Send + Sync {
+    /// Send a `FIND_NODE` request to provided node.
+    async fn find_node(
+        &self,
+        node: &DhtNode,
+        request: &DhtFindNodeRequest,
+    ) -> Result<DhtFindNodeReply>;
+
+    /// Send a `FIND_VALUE` request to provided node.
+    async fn find_value(
+        &self,
+        node: &DhtNode,
+        request: &DhtFindValueRequest,
+    ) -> Result<DhtFindValueReply>;
+
+    /// Send an `ADD_PROVIDER` request to provided node.
+    async fn add_provider(
+        &self,
+        node: &DhtNode,
+        request: &DhtAddProviderRequest,
+    ) -> Result<DhtAddProviderReply>;
+}
+
+pub type DhtNetworkPtr = Arc<dyn DhtNetwork>;
+
+/// A provider record we store for some key.
+struct ProviderRecord {
+    /// The providing node
+    node: DhtNode,
+    /// When the record expires, unless it gets republished
+    expires: Instant,
+}
+
+/// Outcome of an iterative lookup.
+pub(crate) struct Lookup {
+    /// Closest responding nodes to the target, closest first
+    pub nodes: Vec<DhtNode>,
+    /// Providers found, if we were looking for them
+    pub providers: Vec<DhtNode>,
+}
+
+pub type DhtPtr = Arc<Dht>;
+
+/// A Kademlia DHT node.
+pub struct Dht {
+    /// Our own contact information
+    node: DhtNode,
+    /// DHT configuration
+    settings: DhtSettings,
+    /// Our routing table
+    table: RwLock<RoutingTable>,
+    /// Provider records we store, mapped by key and provider ID
+    providers: RwLock<HashMap<blake3::Hash, HashMap<blake3::Hash, ProviderRecord>>>,
+    /// Keys we provide ourselves, and keep republishing
+    published: RwLock<HashSet<blake3::Hash>>,
+    /// Transport to reach other nodes with
+    network: DhtNetworkPtr,
+    /// Background task refreshing the routing table, republishing our
+    /// records and pruning expired ones
+    task: StoppableTaskPtr,
+}
+
+impl Dht {
+    /// Create a new DHT node with provided contact information,
+    /// reaching other nodes through provided network.
+    pub fn new(node: DhtNode, settings: DhtSettings, network: DhtNetworkPtr) -> DhtPtr {
+        let table = RwLock::new(RoutingTable::new(node.id, settings.k));
+        Arc::new(Self {
+            node,
+            settings,
+            table,
+            providers: RwLock::new(HashMap::new()),
+            published: RwLock::new(HashSet::new()),
+            network,
+            task: StoppableTask::new(),
+        })
+    }
+
+    /// Our own contact information.
+    pub fn node(&self) -> &DhtNode {
+        &self.node
+    }
+
+    /// Our routing table.
+    pub fn table(&self) -> &RwLock<RoutingTable> {
+        &self.table
+    }
+
+    /// Start the background task refreshing our routing table,
+    /// republishing the records we provide and pruning expired ones.
+    pub fn start(self: &Arc<Self>, executor: &ExecutorPtr) {
+        self.task.clone().start(
+            self.clone().maintenance(),
+            |res| async move {
+                match res {
+                    Ok(()) | Err(Error::DetachedTaskStopped) => { /* Do nothing */ }
+                    Err(e) => {
+                        error!(target: "dht::start", "Failed starting DHT maintenance task: {e}")
+                    }
+                }
+            },
+            Error::DetachedTaskStopped,
+            executor.clone(),
+        );
+    }
+
+    /// Stop the background task.
+    pub async fn stop(&self) {
+        self.task.stop().await;
+    }
+
+    /// Add provided nodes to our routing table and refresh it through
+    /// them, letting the nodes close to us know about us.
+    /// Returns the number of nodes in our routing table afterwards.
+    pub async fn bootstrap(&self, nodes: Vec<DhtNode>) -> usize {
+        for node in nodes {
+            self.add_node(node).await;
+        }
+
+        self.refresh().await;
+        self.table.read().await.len()
+    }
+
+    /// Refresh our routing table by looking ourselves up, to populate the
+    /// buckets around our own ID, and then a random ID in each bucket
+    /// further away than our closest neighbour, so the rest of the ID
+    /// space remains reachable.
+    pub async fn refresh(&self) {
+        self.lookup_nodes(&self.node.id).await;
+
+        let targets: Vec<blake3::Hash> = {
+            let table = self.table.read().await;
+            let Some(first) = table.first_bucket() else { return };
+            (first + 1..blake3::OUT_LEN * 8).map(|index| table.random_id(index)).collect()
+        };
+
+        for target in targets {
+            self.lookup_nodes(&target).await;
+        }
+    }
+
+    /// Mark provided node as seen in our routing table.
+    /// Nodes we can't reach aren't added.
+    pub async fn add_node(&self, node: DhtNode) {
+        if node.addresses.is_empty() {
+            return
+        }
+
+        self.table.write().await.update(node);
+    }
+
+    /// Remove provided node from our routing table.
+    pub async fn remove_node(&self, id: &blake3::Hash) {
+        self.table.write().await.remove(id);
+    }
+
+    /// Handle a `FIND_NODE` request, returning the closest nodes we
+    /// know of to the target.
+    pub async fn handle_find_node(&self, request: &DhtFindNodeRequest) -> DhtFindNodeReply {
+        self.add_node(request.sender.clone()).await;
+        let nodes = self.closest_nodes(&request.target, &request.sender.id).await;
+        DhtFindNodeReply { sender: self.node.clone(), nodes }
+    }
+
+    /// Handle a `FIND_VALUE` request, returning the providers we know
+    /// of for the key, along with the closest nodes we know of to it.
+    pub async fn handle_find_value(&self, request: &DhtFindValueRequest) -> DhtFindValueReply {
+        self.add_node(request.sender.clone()).await;
+        let providers = self.local_providers(&request.key).await;
+        let nodes = self.closest_nodes(&request.key, &request.sender.id).await;
+        DhtFindValueReply { sender: self.node.clone(), providers, nodes }
+    }
+
+    /// Handle an `ADD_PROVIDER` request, storing a provider record of
+    /// the key for the sender. Senders that advertise no addresses are
+    /// refused, as are new records past the per-key or per-sender caps.
+    /// Refreshing an existing record is always accepted.
+    pub async fn handle_add_provider(
+        &self,
+        request: &DhtAddProviderRequest,
+    ) -> DhtAddProviderReply {
+        self.add_node(request.sender.clone()).await;
+
+        let accepted = !request.sender.addresses.is_empty() &&
+            self.store_remote_provider(&request.key, request.sender.clone()).await;
+
+        DhtAddProviderReply { sender: self.node.clone(), accepted }
+    }
+
+    /// Iteratively look up the `k` closest nodes to the target.
+    pub async fn lookup_nodes(&self, target: &blake3::Hash) -> Vec<DhtNode> {
+        self.lookup(target, false).await.nodes
+    }
+
+    /// Iteratively look up the providers of a key.
+    pub async fn find_providers(&self, key: &blake3::Hash) -> Vec<DhtNode> {
+        let mut providers = self.local_providers(key).await;
+        for provider in self.lookup(key, true).await.providers {
+            if !providers.iter().any(|p| p.id == provider.id) {
+                providers.push(provider);
+            }
+        }
+        providers
+    }
+
+    /// Announce ourselves as a provider of a key to the `k` closest
+    /// nodes to it. The record gets republished periodically until
+    /// [`Dht::unprovide`] is called.
+    /// Returns the number of nodes that accepted the record.
+    pub async fn provide(&self, key: &blake3::Hash) -> usize {
+        self.published.write().await.insert(*key);
+        self.store_provider(key, self.node.clone()).await;
+        self.publish(key).await
+    }
+
+    /// Stop republishing our provider record of a key. Records already
+    /// stored by other nodes remain until they expire.
+    pub async fn unprovide(&self, key: &blake3::Hash) {
+        self.published.write().await.remove(key);
+        if let Some(records) = self.providers.write().await.get_mut(key) {
+            records.remove(&self.node.id);
+        }
+    }
+
+    /// Remove all expired provider records.
+    pub async fn prune_providers(&self) {
+        let now = Instant::now();
+        let mut providers = self.providers.write().await;
+        for records in providers.values_mut() {
+            records.retain(|_, record| record.expires > now);
+        }
+        providers.retain(|_, records| !records.is_empty());
+    }
+
+    /// Auxiliary function to send our provider record of a key to the
+    /// `k` closest nodes to it.
+    async fn publish(&self, key: &blake3::Hash) -> usize {
+        let nodes = self.lookup_nodes(key).await;
+        let request = DhtAddProviderRequest { sender: self.node.clone(), key: *key };
+
+        let futures = nodes.iter().map(|node| self.network.add_provider(node, &request));
+        let mut accepted = 0;
+        for (node, result) in nodes.iter().zip(join_all(futures).await) {
+            match result {
+                Ok(reply) if reply.sender.id == node.id => accepted += reply.accepted as usize,
+                Ok(_) => self.remove_node(&node.id).await,
+                Err(e) => {
+                    debug!(target: "dht::publish", "Publishing {key} to {} failed: {e}", node.id);
+                    self.remove_node(&node.id).await;
+                }
+            }
+        }
+
+        debug!(target: "dht::publish", "Published {key} to {accepted} nodes");
+        accepted
+    }
+
+    /// Auxiliary function to store a provider record, refreshing its
+    /// expiry if it already exists.
+    async fn store_provider(&self, key: &blake3::Hash, node: DhtNode) {
+        let expires = Instant::now() + Duration::from_secs(self.settings.provider_ttl);
+        let record = ProviderRecord { node, expires };
+        self.providers.write().await.entry(*key).or_default().insert(record.node.id, record);
+    }
+
+    /// Auxiliary function to store a provider record received from
+    /// another node, enforcing the per-key and per-sender caps on new
+    /// records. Returns `true` if the record was stored.
+    async fn store_remote_provider(&self, key: &blake3::Hash, node: DhtNode) -> bool {
+        let now = Instant::now();
+        let mut providers = self.providers.write().await;
+
+        let refresh = providers.get(key).is_some_and(|records| records.contains_key(&node.id));
+        if !refresh {
+            let key_records = providers
+                .get(key)
+                .map_or(0, |records| records.values().filter(|r| r.expires > now).count());
+            if key_records >= self.settings.max_providers_per_key {
+                return false
+            }
+
+            let sender_records = providers
+                .values()
+                .filter_map(|records| records.get(&node.id))
+                .filter(|record| record.expires > now)
+                .count();
+            if sender_records >= self.settings.max_records_per_sender {
+                return false
+            }
+        }
+
+        let expires = now + Duration::from_secs(self.settings.provider_ttl);
+        let record = ProviderRecord { node, expires };
+        providers.entry(*key).or_default().insert(record.node.id, record);
+        true
+    }
+
+    /// Auxiliary function to grab up to `k` unexpired providers of a key
+    /// we store.
+    async fn local_providers(&self, key: &blake3::Hash) -> Vec<DhtNode> {
+        let now = Instant::now();
+        let providers = self.providers.read().await;
+        let Some(records) = providers.get(key) else { return vec![] };
+
+        records
+            .values()
+            .filter(|record| record.expires > now)
+            .take(self.settings.k)
+            .map(|record| record.node.clone())
+            .collect()
+    }
+
+    /// Auxiliary function to grab the `k` closest nodes we know of to
+    /// the target, excluding the requesting node.
+    async fn closest_nodes(&self, target: &blake3::Hash, exclude: &blake3::Hash) -> Vec<DhtNode> {
+        let mut nodes = self.table.read().await.closest(target, self.settings.k + 1);
+        nodes.retain(|node| &node.id != exclude);
+        nodes.truncate(self.settings.k);
+        nodes
+    }
+
+    /// Auxiliary function to send a lookup request to provided node,
+    /// returning the nodes and providers it responded with.
+    async fn query(
+        &self,
+        node: &DhtNode,
+        target: &blake3::Hash,
+        find_value: bool,
+    ) -> Result<(DhtNode, Vec<DhtNode>, Vec<DhtNode>)> {
+        let (sender, nodes, providers) = if find_value {
+            let request = DhtFindValueRequest { sender: self.node.clone(), key: *target };
+            let reply = self.network.find_value(node, &request).await?;
+            (reply.sender, reply.nodes, reply.providers)
+        } else {
+            let request = DhtFindNodeRequest { sender: self.node.clone(), target: *target };
+            let reply = self.network.find_node(node, &request).await?;
+            (reply.sender, reply.nodes, vec![])
+        };
+
+        if sender.id != node.id {
+            return Err(Error::DhtInvalidReply)
+        }
+
+        Ok((sender, nodes, providers))
+    }
+
+    /// Iterative lookup of the target. Each round queries the `alpha`
+    /// closest nodes we haven't queried yet among the `k` closest we
+    /// know of, until all of those have been queried. When looking for
+    /// providers, we stop after the first round that found some.
+    pub(crate) async fn lookup(&self, target: &blake3::Hash, find_value: bool) -> Lookup {
+        let k = self.settings.k;
+        let mut shortlist = self.table.read().await.closest(target, k);
+        let mut seen: HashSet<blake3::Hash> = shortlist.iter().map(|node| node.id).collect();
+        seen.insert(self.node.id);
+        let mut queried = HashSet::new();
+        let mut providers: Vec<DhtNode> = vec![];
+        let mut rounds = 0;
+
+        loop {
+            let batch: Vec<DhtNode> = shortlist
+                .iter()
+                .take(k)
+                .filter(|node| !queried.contains(&node.id))
+                .take(self.settings.alpha)
+                .cloned()
+                .collect();
+            if batch.is_empty() {
+                break
+            }
+
+            rounds += 1;
+            for node in &batch {
+                queried.insert(node.id);
+            }
+
+            let futures = batch.iter().map(|node| self.query(node, target, find_value));
+            for (node, result) in batch.iter().zip(join_all(futures).await) {
+                match result {
+                    Ok((sender, nodes, found)) => {
+                        self.add_node(sender).await;
+                        for node in nodes {
+                            if !node.addresses.is_empty() && seen.insert(node.id) {
+                                shortlist.push(node);
+                            }
+                        }
+                        for provider in found {
+                            if !providers.iter().any(|p| p.id == provider.id) {
+                                providers.push(provider);
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        debug!(target: "dht::lookup", "Querying {} failed: {e}", node.id);
+                        self.remove_node(&node.id).await;
+                        shortlist.retain(|n| n.id != node.id);
+                    }
+                }
+            }
+
+            shortlist.sort_by_cached_key(|node| distance(target, &node.id));
+            if find_value && !providers.is_empty() {
+                break
+            }
+        }
+
+        shortlist.retain(|node| queried.contains(&node.id));
+        shortlist.truncate(k);
+
+        debug!(
+            target: "dht::lookup",
+            "Lookup of {target} finished after {rounds} rounds with {} nodes and {} providers",
+            shortlist.len(), providers.len(),
+        );
+
+        Lookup { nodes: shortlist, providers }
+    }
+
+    /// Background task pruning expired provider records, refreshing our
+    /// routing table and republishing the records we provide.
+    async fn maintenance(self: Arc<Self>) -> Result<()> {
+        loop {
+            sleep(self.settings.republish_interval).await;
+
+            self.prune_providers().await;
+            self.refresh().await;
+
+            let keys: Vec<blake3::Hash> = self.published.read().await.iter().copied().collect();
+            info!(target: "dht::maintenance", "Republishing {} provider records", keys.len());
+            for key in keys {
+                self.store_provider(&key, self.node.clone()).await;
+                self.publish(&key).await;
+            }
+        }
+    }
+}
@@ -0,0 +1,380 @@
+/* This file is part of DarkFi (https://dark.fi)
+ *
+ * Copyright (C) 2020-2024 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::{collections::HashMap, sync::Arc};
+
+use async_trait::async_trait;
+use darkfi_serial::{SerialDecodable, SerialEncodable};
+use log::{debug, error};
+use smol::lock::RwLock;
+
+use super::{DhtNetwork, DhtNode, DhtPtr};
+use crate::{
+    impl_p2p_message, impl_p2p_request,
+    net::{
+        connector::Connector,
+        protocol::{
+            protocol_request::{
+                ProtocolRequestAction, ProtocolRequestHandler, ProtocolRequestHandlerPtr,
+                RequestMessage, RequestResponse, RequestResponsePtr, RequestSettings,
+            },
+            ProtocolVersion,
+        },
+        session::SESSION_DEFAULT,
+        ChannelPtr, Message, P2pPtr,
+    },
+    system::ExecutorPtr,
+    Error, Result,
+};
+
+/// Request for the closest nodes the receiver knows of to a target ID.
+#[derive(Clone, Debug, SerialEncodable, SerialDecodable)]
+pub struct DhtFindNodeRequest {
+    /// Contact of the requesting node
+    pub sender: DhtNode,
+    /// ID to find the closest nodes to
+    pub target: blake3::Hash,
+}
+
+impl_p2p_message!(DhtFindNodeRequest, "dhtfindnoderequest");
+
+/// Response to `DhtFindNodeRequest`.
+#[derive(Clone, Debug, SerialEncodable, SerialDecodable)]
+pub struct DhtFindNodeReply {
+    /// Contact of the responding node
+    pub sender: DhtNode,
+    /// Closest nodes to the target
+    pub nodes: Vec<DhtNode>,
+}
+
+impl_p2p_message!(DhtFindNodeReply, "dhtfindnodereply");
+impl_p2p_request!(DhtFindNodeRequest, DhtFindNodeReply, "dhtfindnodeunavailable");
+
+/// Request for the providers of a key the receiver knows of.
+#[derive(Clone, Debug, SerialEncodable, SerialDecodable)]
+pub struct DhtFindValueRequest {
+    /// Contact of the requesting node
+    pub sender: DhtNode,
+    /// Key to find the providers of
+    pub key: blake3::Hash,
+}
+
+impl_p2p_message!(DhtFindValueRequest, "dhtfindvaluerequest");
+
+/// Response to `DhtFindValueRequest`, containing the providers of the
+/// key the responder knows of, and its closest nodes to the key so the
+/// lookup can continue if there are none.
+#[derive(Clone, Debug, SerialEncodable, SerialDecodable)]
+pub struct DhtFindValueReply {
+    /// Contact of the responding node
+    pub sender: DhtNode,
+    /// Providers of the key
+    pub providers: Vec<DhtNode>,
+    /// Closest nodes to the key
+    pub nodes: Vec<DhtNode>,
+}
+
+impl_p2p_message!(DhtFindValueReply, "dhtfindvaluereply");
+impl_p2p_request!(DhtFindValueRequest, DhtFindValueReply, "dhtfindvalueunavailable");
+
+/// Request to store a provider record of a key for the sender.
+#[derive(Clone, Debug, SerialEncodable, SerialDecodable)]
+pub struct DhtAddProviderRequest {
+    /// Contact of the providing node
+    pub sender: DhtNode,
+    /// Key the sender provides
+    pub key: blake3::Hash,
+}
+
+impl_p2p_message!(DhtAddProviderRequest, "dhtaddproviderrequest");
+
+/// Response to `DhtAddProviderRequest`.
+#[derive(Clone, Debug, SerialEncodable, SerialDecodable)]
+pub struct DhtAddProviderReply {
+    /// Contact of the responding node
+    pub sender: DhtNode,
+    /// Flag indicating the record was stored
+    pub accepted: bool,
+}
+
+impl_p2p_message!(DhtAddProviderReply, "dhtaddproviderreply");
+impl_p2p_request!(DhtAddProviderRequest, DhtAddProviderReply, "dhtaddproviderunavailable");
+
+/// Atomic pointer to the `ProtocolDht` handler.
+pub type ProtocolDhtPtr = Arc<ProtocolDht>;
+
+/// Handler serving DHT requests over request/response P2P protocols,
+/// and [`DhtNetwork`] implementation sending them.
+///
+/// Requests are sent over the channel we already have with a node, if
+/// any. Otherwise we connect to one of its addresses just for the
+/// request, without registering the connection with our sessions.
+pub struct ProtocolDht {
+    /// P2P network pointer
+    p2p: P2pPtr,
+    /// Client sending our requests
+    client: RequestResponsePtr,
+    /// The request handler for `DhtFindNodeRequest` messages.
+    find_node_handler: ProtocolRequestHandlerPtr<DhtFindNodeRequest>,
+    /// The request handler for `DhtFindValueRequest` messages.
+    find_value_handler: ProtocolRequestHandlerPtr<DhtFindValueRequest>,
+    /// The request handler for `DhtAddProviderRequest` messages.
+    add_provider_handler: ProtocolRequestHandlerPtr<DhtAddProviderRequest>,
+    /// Channel IDs mapped by the ID of the node at the other end.
+    /// Node IDs are self-asserted, so they're only recorded from replies
+    /// received over a channel to one of the node's advertised addresses.
+    channels: RwLock<HashMap<blake3::Hash, u32>>,
+}
+
+impl ProtocolDht {
+    /// Initialize the request protocol handlers for all DHT messages
+    /// and register them to the provided P2P network, using the default
+    /// session flag.
+    pub async fn init(p2p: &P2pPtr, settings: RequestSettings) -> ProtocolDhtPtr {
+        debug!(target: "dht::proto::init", "Adding all DHT protocols to the protocol registry");
+
+        let find_node_handler =
+            ProtocolRequestHandler::new(p2p, "ProtocolDhtFindNode", SESSION_DEFAULT).await;
+        let find_value_handler =
+            ProtocolRequestHandler::new(p2p, "ProtocolDhtFindValue", SESSION_DEFAULT).await;
+        let add_provider_handler =
+            ProtocolRequestHandler::new(p2p, "ProtocolDhtAddProvider", SESSION_DEFAULT).await;
+
+        Arc::new(Self {
+            p2p: p2p.clone(),
+            client: RequestResponse::new(settings),
+            find_node_handler,
+            find_value_handler,
+            add_provider_handler,
+            channels: RwLock::new(HashMap::new()),
+        })
+    }
+
+    /// Start all DHT protocols background tasks, serving requests with
+    /// provided DHT node.
+    pub fn start(self: &Arc<Self>, executor: &ExecutorPtr, dht: &DhtPtr) {
+        debug!(target: "dht::proto::start", "Starting DHT protocols handlers tasks...");
+
+        self.find_node_handler.task.clone().start(
+            handle_receive_find_node(self.clone(), dht.clone()),
+            |res| async move {
+                match res {
+                    Ok(()) | Err(Error::DetachedTaskStopped) => { /* Do nothing */ }
+                    Err(e) => error!(target: "dht::proto::start", "Failed starting ProtocolDhtFindNode handler task: {e}"),
+                }
+            },
+            Error::DetachedTaskStopped,
+            executor.clone(),
+        );
+
+        self.find_value_handler.task.clone().start(
+            handle_receive_find_value(self.clone(), dht.clone()),
+            |res| async move {
+                match res {
+                    Ok(()) | Err(Error::DetachedTaskStopped) => { /* Do nothing */ }
+                    Err(e) => error!(target: "dht::proto::start", "Failed starting ProtocolDhtFindValue handler task: {e}"),
+                }
+            },
+            Error::DetachedTaskStopped,
+            executor.clone(),
+        );
+
+        self.add_provider_handler.task.clone().start(
+            handle_receive_add_provider(self.clone(), dht.clone()),
+            |res| async move {
+                match res {
+                    Ok(()) | Err(Error::DetachedTaskStopped) => { /* Do nothing */ }
+                    Err(e) => error!(target: "dht::proto::start", "Failed starting ProtocolDhtAddProvider handler task: {e}"),
+                }
+            },
+            Error::DetachedTaskStopped,
+            executor.clone(),
+        );
+
+        debug!(target: "dht::proto::start", "DHT protocols handlers tasks started!");
+    }
+
+    /// Stop all DHT protocols background tasks.
+    pub async fn stop(&self) {
+        debug!(target: "dht::proto::stop", "Terminating DHT protocols handlers tasks...");
+        self.find_node_handler.task.stop().await;
+        self.find_value_handler.task.stop().await;
+        self.add_provider_handler.task.stop().await;
+        debug!(target: "dht::proto::stop", "DHT protocols handlers tasks terminated!");
+    }
+
+    /// Bootstrap provided DHT node through the peers we are connected
+    /// to, learning their contacts by looking ourselves up through them.
+    /// Returns the number of nodes in the routing table afterwards.
+    pub async fn bootstrap(&self, dht: &DhtPtr) -> usize {
+        let request = DhtFindNodeRequest { sender: dht.node().clone(), target: dht.node().id };
+
+        let mut nodes = vec![];
+        for (channel, reply) in self.client.request_all(&self.p2p.hosts().peers(), &request).await {
+            if reply.sender.addresses.contains(channel.address()) {
+                self.channels.write().await.insert(reply.sender.id, channel.info.id);
+            }
+            nodes.push(reply.sender);
+            nodes.extend(reply.nodes);
+        }
+
+        dht.bootstrap(nodes).await
+    }
+
+    /// Auxiliary function to grab an existing channel to provided node.
+    async fn channel(&self, node: &DhtNode) -> Option<ChannelPtr> {
+        if let Some(id) = self.channels.read().await.get(&node.id) {
+            if let Some(channel) = self.p2p.get_channel(*id) {
+                return Some(channel)
+            }
+        }
+
+        self.p2p.hosts().channels().into_iter().find(|c| node.addresses.contains(c.address()))
+    }
+
+    /// Auxiliary function to send a request to provided node, either
+    /// over an existing channel, or over a new one to one of its
+    /// addresses, which is stopped afterwards.
+    async fn request<Q: RequestMessage>(&self, node: &DhtNode, request: &Q) -> Result<Q::Response> {
+        if let Some(channel) = self.channel(node).await {
+            return self.client.request(&channel, request).await
+        }
+
+        let executor = self.p2p.executor();
+        for addr in &node.addresses {
+            let connector =
+                Connector::new(self.p2p.settings(), Arc::downgrade(&self.p2p.session_manual()));
+            let channel = match connector.connect(addr).await {
+                Ok((_, channel)) => channel,
+                Err(e) => {
+                    debug!(target: "dht::proto::request", "Failed to connect to {addr}: {e}");
+                    continue
+                }
+            };
+
+            let protocol_version = ProtocolVersion::new(channel.clone(), self.p2p.settings()).await;
+            channel.clone().start(executor.clone());
+            if let Err(e) = protocol_version.run(executor.clone()).await {
+                debug!(target: "dht::proto::request", "Handshake with {addr} failed: {e}");
+                continue
+            }
+
+            let result = self.client.request(&channel, request).await;
+            channel.stop().await;
+            return result
+        }
+
+        Err(Error::DhtNodeUnreachable)
+    }
+}
+
+#[async_trait]
+impl DhtNetwork for ProtocolDht {
+    async fn find_node(
+        &self,
+        node: &DhtNode,
+        request: &DhtFindNodeRequest,
+    ) -> Result<DhtFindNodeReply> {
+        self.request(node, request).await
+    }
+
+    async fn find_value(
+        &self,
+        node: &DhtNode,
+        request: &DhtFindValueRequest,
+    ) -> Result<DhtFindValueReply> {
+        self.request(node, request).await
+    }
+
+    async fn add_provider(
+        &self,
+        node: &DhtNode,
+        request: &DhtAddProviderRequest,
+    ) -> Result<DhtAddProviderReply> {
+        self.request(node, request).await
+    }
+}
+
+/// Background handler function for ProtocolDhtFindNode.
+async fn handle_receive_find_node(protocol: ProtocolDhtPtr, dht: DhtPtr) -> Result<()> {
+    debug!(target: "dht::proto::handle_receive_find_node", "START");
+    loop {
+        // Wait for a new find node request message
+        let (channel, request) = match protocol.find_node_handler.receiver.recv().await {
+            Ok(r) => r,
+            Err(e) => {
+                debug!(target: "dht::proto::handle_receive_find_node", "recv fail: {e}");
+                continue
+            }
+        };
+
+        debug!(target: "dht::proto::handle_receive_find_node", "Received request: {request:?}");
+
+        let reply = dht.handle_find_node(&request).await;
+        protocol
+            .find_node_handler
+            .send_action(channel, ProtocolRequestAction::Response(reply))
+            .await;
+    }
+}
+
+/// Background handler function for ProtocolDhtFindValue.
+async fn handle_receive_find_value(protocol: ProtocolDhtPtr, dht: DhtPtr) -> Result<()> {
+    debug!(target: "dht::proto::handle_receive_find_value", "START");
+    loop {
+        // Wait for a new find value request message
+        let (channel, request) = match protocol.find_value_handler.receiver.recv().await {
+            Ok(r) => r,
+            Err(e) => {
+                debug!(target: "dht::proto::handle_receive_find_value", "recv fail: {e}");
+                continue
+            }
+        };
+
+        debug!(target: "dht::proto::handle_receive_find_value", "Received request: {request:?}");
+
+        let reply = dht.handle_find_value(&request).await;
+        protocol
+            .find_value_handler
+            .send_action(channel, ProtocolRequestAction::Response(reply))
+            .await;
+    }
+}
+
+/// Background handler function for ProtocolDhtAddProvider.
+async fn handle_receive_add_provider(protocol: ProtocolDhtPtr, dht: DhtPtr) -> Result<()> {
+    debug!(target: "dht::proto::handle_receive_add_provider", "START");
+    loop {
+        // Wait for a new add provider request message
+        let (channel, request) = match protocol.add_provider_handler.receiver.recv().await {
+            Ok(r) => r,
+            Err(e) => {
+                debug!(target: "dht::proto::handle_receive_add_provider", "recv fail: {e}");
+                continue
+            }
+        };
+
+        debug!(target: "dht::proto::handle_receive_add_provider", "Received request: {request:?}");
+
+        let reply = dht.handle_add_provider(&request).await;
+        protocol
+            .add_provider_handler
+            .send_action(channel, ProtocolRequestAction::Response(reply))
+            .await;
+    }
+}
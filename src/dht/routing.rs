@@ -0,0 +1,159 @@
+/* This file is part of DarkFi (https://dark.fi)
+ *
+ * Copyright (C) 2020-2024 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::collections::VecDeque;
+
+use rand::{rngs::OsRng, Rng};
+
+use super::{distance, DhtNode};
+
+/// A single k-bucket, holding up to `k` nodes ordered from the least
+/// to the most recently seen one, along with a cache of replacement
+/// candidates that didn't fit in the bucket.
+#[derive(Default)]
+struct KBucket {
+    nodes: VecDeque<DhtNode>,
+    replacements: VecDeque<DhtNode>,
+}
+
+/// Kademlia routing table of a DHT node.
+///
+/// Bucket `i` holds the nodes whose XOR distance to our own ID lies in
+/// `[2^i, 2^(i+1))`. Full buckets keep their long-lived nodes and queue
+/// new ones as replacements, which get promoted once a node of the
+/// bucket is removed after failing to respond.
+pub struct RoutingTable {
+    /// Our own node ID
+    local_id: blake3::Hash,
+    /// Maximum number of nodes per bucket
+    k: usize,
+    /// The k-buckets, one per bit of the ID space
+    buckets: Vec<KBucket>,
+}
+
+impl RoutingTable {
+    /// Create an empty routing table for provided local node ID.
+    pub fn new(local_id: blake3::Hash, k: usize) -> Self {
+        let buckets = (0..blake3::OUT_LEN * 8).map(|_| KBucket::default()).collect();
+        Self { local_id, k, buckets }
+    }
+
+    /// Index of the bucket provided node ID belongs to, or `None` for
+    /// our own ID.
+    fn bucket_index(&self, id: &blake3::Hash) -> Option<usize> {
+        let distance = distance(&self.local_id, id);
+        let byte = distance.iter().position(|b| *b != 0)?;
+        let leading_zeros = byte * 8 + distance[byte].leading_zeros() as usize;
+        Some(self.buckets.len() - 1 - leading_zeros)
+    }
+
+    /// Mark provided node as seen. Known nodes are moved to the tail of
+    /// their bucket and get their addresses refreshed, while new ones are
+    /// inserted if their bucket has room, or cached as replacements
+    /// otherwise. Returns `true` if the node is in the table afterwards.
+    pub fn update(&mut self, node: DhtNode) -> bool {
+        let Some(index) = self.bucket_index(&node.id) else { return false };
+        let k = self.k;
+        let bucket = &mut self.buckets[index];
+
+        if let Some(position) = bucket.nodes.iter().position(|n| n.id == node.id) {
+            bucket.nodes.remove(position);
+            bucket.nodes.push_back(node);
+            return true
+        }
+
+        if bucket.nodes.len() < k {
+            bucket.nodes.push_back(node);
+            return true
+        }
+
+        bucket.replacements.retain(|n| n.id != node.id);
+        bucket.replacements.push_back(node);
+        if bucket.replacements.len() > k {
+            bucket.replacements.pop_front();
+        }
+
+        false
+    }
+
+    /// Remove provided node ID from the table, promoting the most
+    /// recently seen replacement of its bucket in its place.
+    pub fn remove(&mut self, id: &blake3::Hash) -> Option<DhtNode> {
+        let index = self.bucket_index(id)?;
+        let bucket = &mut self.buckets[index];
+
+        bucket.replacements.retain(|n| &n.id != id);
+        let position = bucket.nodes.iter().position(|n| &n.id == id)?;
+        let removed = bucket.nodes.remove(position);
+
+        if let Some(replacement) = bucket.replacements.pop_back() {
+            bucket.nodes.push_back(replacement);
+        }
+
+        removed
+    }
+
+    /// Check if provided node ID is in the table.
+    pub fn contains(&self, id: &blake3::Hash) -> bool {
+        let Some(index) = self.bucket_index(id) else { return false };
+        self.buckets[index].nodes.iter().any(|n| &n.id == id)
+    }
+
+    /// Return up to `n` nodes of the table, closest to provided target
+    /// first.
+    pub fn closest(&self, target: &blake3::Hash, n: usize) -> Vec<DhtNode> {
+        let mut nodes: Vec<DhtNode> =
+            self.buckets.iter().flat_map(|b| b.nodes.iter().cloned()).collect();
+        nodes.sort_by_cached_key(|node| distance(target, &node.id));
+        nodes.truncate(n);
+        nodes
+    }
+
+    /// Index of the closest non-empty bucket to our own ID.
+    pub fn first_bucket(&self) -> Option<usize> {
+        self.buckets.iter().position(|b| !b.nodes.is_empty())
+    }
+
+    /// Generate a random ID falling into provided bucket.
+    pub fn random_id(&self, index: usize) -> blake3::Hash {
+        let mut distance: [u8; blake3::OUT_LEN] = OsRng.gen();
+
+        // Clear the bits above the bucket one and set it
+        let byte = blake3::OUT_LEN - 1 - index / 8;
+        let bit = index % 8;
+        distance[..byte].fill(0);
+        distance[byte] &= (1 << bit) - 1;
+        distance[byte] |= 1 << bit;
+
+        let mut id = *self.local_id.as_bytes();
+        for (i, d) in distance.iter().enumerate() {
+            id[i] ^= d;
+        }
+        blake3::Hash::from_bytes(id)
+    }
+
+    /// Total number of nodes in the table.
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|b| b.nodes.len()).sum()
+    }
+
+    /// Check if the table holds no nodes.
+    pub fn is_empty(&self) -> bool {
+        self.buckets.iter().all(|b| b.nodes.is_empty())
+    }
+}
@@ -0,0 +1,305 @@
+/* This file is part of DarkFi (https://dark.fi)
+ *
+ * Copyright (C) 2020-2024 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use async_trait::async_trait;
+use smol::{lock::RwLock, Executor};
+use url::Url;
+
+use super::{
+    distance, Dht, DhtAddProviderReply, DhtAddProviderRequest, DhtFindNodeReply,
+    DhtFindNodeRequest, DhtFindValueReply, DhtFindValueRequest, DhtNetwork, DhtNode, DhtPtr,
+    DhtSettings, RoutingTable,
+};
+use crate::{
+    system::{sleep, ExecutorPtr},
+    Error, Result,
+};
+
+/// In-memory network delivering requests straight to the nodes' handlers.
+#[derive(Default)]
+struct SimNetwork {
+    nodes: RwLock<HashMap<blake3::Hash, DhtPtr>>,
+    offline: RwLock<HashSet<blake3::Hash>>,
+    requests: AtomicUsize,
+}
+
+impl SimNetwork {
+    async fn get(&self, node: &DhtNode) -> Result<DhtPtr> {
+        self.requests.fetch_add(1, Ordering::SeqCst);
+        if self.offline.read().await.contains(&node.id) {
+            return Err(Error::DhtNodeUnreachable)
+        }
+        self.nodes.read().await.get(&node.id).cloned().ok_or(Error::DhtNodeUnreachable)
+    }
+}
+
+#[async_trait]
+impl DhtNetwork for SimNetwork {
+    async fn find_node(
+        &self,
+        node: &DhtNode,
+        request: &DhtFindNodeRequest,
+    ) -> Result<DhtFindNodeReply> {
+        Ok(self.get(node).await?.handle_find_node(request).await)
+    }
+
+    async fn find_value(
+        &self,
+        node: &DhtNode,
+        request: &DhtFindValueRequest,
+    ) -> Result<DhtFindValueReply> {
+        Ok(self.get(node).await?.handle_find_value(request).await)
+    }
+
+    async fn add_provider(
+        &self,
+        node: &DhtNode,
+        request: &DhtAddProviderRequest,
+    ) -> Result<DhtAddProviderReply> {
+        Ok(self.get(node).await?.handle_add_provider(request).await)
+    }
+}
+
+fn sim_node(i: usize) -> DhtNode {
+    let id = blake3::hash(&(i as u64).to_le_bytes());
+    let addresses = vec![Url::parse(&format!("tcp://127.0.0.1:{}", 10000 + i)).unwrap()];
+    DhtNode { id, addresses }
+}
+
+/// Spawn `n` nodes, each bootstrapping through the first one.
+async fn spawn_network(n: usize, settings: DhtSettings) -> (Arc<SimNetwork>, Vec<DhtPtr>) {
+    let network = Arc::new(SimNetwork::default());
+    let mut nodes: Vec<DhtPtr> = vec![];
+
+    for i in 0..n {
+        let dht = Dht::new(sim_node(i), settings.clone(), network.clone());
+        network.nodes.write().await.insert(dht.node().id, dht.clone());
+        if let Some(seed) = nodes.first() {
+            assert!(dht.bootstrap(vec![seed.node().clone()]).await > 0);
+        }
+        nodes.push(dht);
+    }
+
+    (network, nodes)
+}
+
+/// IDs of the `k` closest nodes to the target out of all provided ones.
+fn closest_ids(nodes: &[DhtPtr], target: &blake3::Hash, k: usize) -> Vec<blake3::Hash> {
+    let mut ids: Vec<blake3::Hash> = nodes.iter().map(|dht| dht.node().id).collect();
+    ids.sort_by_cached_key(|id| distance(target, id));
+    ids.truncate(k);
+    ids
+}
+
+#[test]
+fn dht_routing_table() {
+    let local = sim_node(0);
+    let mut table = RoutingTable::new(local.id, 2);
+
+    // Our own ID never makes it into the table
+    assert!(!table.update(local.clone()));
+    assert!(table.is_empty());
+
+    // Find three nodes falling into the same bucket
+    let bucket = |id: &blake3::Hash| distance(&local.id, id)[0].leading_zeros();
+    let mut same = vec![];
+    for i in 1.. {
+        let node = sim_node(i);
+        if bucket(&node.id) == 0 {
+            same.push(node);
+        }
+        if same.len() == 3 {
+            break
+        }
+    }
+
+    // The third one doesn't fit and becomes a replacement
+    assert!(table.update(same[0].clone()));
+    assert!(table.update(same[1].clone()));
+    assert!(!table.update(same[2].clone()));
+    assert_eq!(table.len(), 2);
+    assert!(!table.contains(&same[2].id));
+
+    // Seeing a known node again keeps it in the table
+    assert!(table.update(same[0].clone()));
+
+    // Removing a node promotes the replacement
+    assert_eq!(table.remove(&same[1].id), Some(same[1].clone()));
+    assert!(table.contains(&same[2].id));
+    assert_eq!(table.len(), 2);
+
+    // Closest nodes are returned ordered by distance
+    let target = same[2].id;
+    let closest = table.closest(&target, 2);
+    assert_eq!(closest[0], same[2]);
+    assert!(distance(&target, &closest[0].id) < distance(&target, &closest[1].id));
+
+    // Random IDs fall into the requested bucket
+    for index in [0, 7, 8, 100, 255] {
+        let distance = distance(&local.id, &table.random_id(index));
+        let byte = distance.iter().position(|b| *b != 0).unwrap();
+        let leading_zeros = byte * 8 + distance[byte].leading_zeros() as usize;
+        assert_eq!(255 - leading_zeros, index);
+    }
+}
+
+#[test]
+fn dht_lookup_converges() {
+    smol::block_on(async {
+        let n = 256;
+        let settings = DhtSettings { k: 8, ..Default::default() };
+        let (network, nodes) = spawn_network(n, settings.clone()).await;
+
+        let max_rounds = 2 * (n as f64).log2().ceil() as usize;
+        for i in 0..32 {
+            let target = blake3::hash(format!("target {i}").as_bytes());
+            let dht = &nodes[(i * 7) % n];
+
+            network.requests.store(0, Ordering::SeqCst);
+            let lookup = dht.lookup(&target, false).await;
+
+            let found: Vec<blake3::Hash> = lookup.nodes.iter().map(|n| n.id).collect();
+            let mut expected = closest_ids(&nodes, &target, settings.k + 1);
+            expected.retain(|id| id != &dht.node().id);
+            expected.truncate(settings.k);
+
+            assert_eq!(found, expected);
+
+            // Each round sends at most alpha requests
+            let requests = network.requests.load(Ordering::SeqCst);
+            assert!(requests <= max_rounds * settings.alpha, "lookup sent {requests} requests");
+        }
+    });
+}
+
+#[test]
+fn dht_find_providers() {
+    smol::block_on(async {
+        let n = 128;
+        let settings = DhtSettings { k: 8, ..Default::default() };
+        let (network, nodes) = spawn_network(n, settings.clone()).await;
+
+        let key = blake3::hash(b"some file");
+        let provider = &nodes[42];
+        assert_eq!(provider.provide(&key).await, settings.k);
+
+        // The record is stored at the k closest nodes to the key
+        let mut closest = closest_ids(&nodes, &key, settings.k + 1);
+        closest.retain(|id| id != &provider.node().id);
+        closest.truncate(settings.k);
+        for id in closest {
+            let dht = network.nodes.read().await[&id].clone();
+            assert_eq!(dht.local_providers(&key).await, vec![provider.node().clone()]);
+        }
+
+        // Everyone finds the provider, even with some nodes offline
+        for dht in nodes.iter().skip(100) {
+            network.offline.write().await.insert(dht.node().id);
+        }
+        for dht in nodes.iter().take(100) {
+            assert_eq!(dht.find_providers(&key).await, vec![provider.node().clone()]);
+        }
+
+        // Unknown keys have no providers
+        let unknown = blake3::hash(b"unknown file");
+        assert!(nodes[0].find_providers(&unknown).await.is_empty());
+    });
+}
+
+#[test]
+fn dht_provider_expiry() {
+    let ex: ExecutorPtr = Arc::new(Executor::new());
+    smol::block_on(ex.run(async {
+        let settings = DhtSettings {
+            k: 4,
+            alpha: 2,
+            provider_ttl: 2,
+            republish_interval: 1,
+            ..Default::default()
+        };
+        let (_, nodes) = spawn_network(16, settings).await;
+
+        let key = blake3::hash(b"some file");
+        let provider = &nodes[3];
+        let seeker = &nodes[11];
+
+        // Records outlive their TTL while they get republished
+        provider.start(&ex);
+        assert!(provider.provide(&key).await > 0);
+        sleep(4).await;
+        for dht in &nodes {
+            dht.prune_providers().await;
+        }
+        assert_eq!(seeker.find_providers(&key).await, vec![provider.node().clone()]);
+
+        // Once we stop providing, they expire
+        provider.unprovide(&key).await;
+        provider.stop().await;
+        sleep(3).await;
+        for dht in &nodes {
+            dht.prune_providers().await;
+        }
+        assert!(seeker.find_providers(&key).await.is_empty());
+    }));
+}
+
+#[test]
+fn dht_provider_caps() {
+    smol::block_on(async {
+        let settings = DhtSettings {
+            max_providers_per_key: 2,
+            max_records_per_sender: 3,
+            ..Default::default()
+        };
+        let network = Arc::new(SimNetwork::default());
+        let dht = Dht::new(sim_node(0), settings, network);
+
+        let add = |sender: DhtNode, key: blake3::Hash| {
+            let dht = dht.clone();
+            async move { dht.handle_add_provider(&DhtAddProviderRequest { sender, key }).await }
+        };
+
+        // Senders without addresses are refused
+        let key = blake3::hash(b"key");
+        let hidden = DhtNode { id: blake3::hash(b"hidden"), addresses: vec![] };
+        assert!(!add(hidden, key).await.accepted);
+
+        // Per-key cap, refreshes still go through
+        assert!(add(sim_node(1), key).await.accepted);
+        assert!(add(sim_node(2), key).await.accepted);
+        assert!(!add(sim_node(3), key).await.accepted);
+        assert!(add(sim_node(1), key).await.accepted);
+        assert_eq!(dht.local_providers(&key).await.len(), 2);
+
+        // Per-sender cap
+        for i in 0..2 {
+            let key = blake3::hash(&[i]);
+            assert!(add(sim_node(1), key).await.accepted);
+        }
+        assert!(!add(sim_node(1), blake3::hash(b"one too many")).await.accepted);
+        assert!(add(sim_node(4), blake3::hash(b"one too many")).await.accepted);
+    });
+}
@@ -131,6 +131,9 @@ pub enum Error {
     #[error("Channel timed out")]
     ChannelTimeout,
 
+    #[error("Peer can't serve the request")]
+    RequestUnavailable,
+
     #[error("Failed to reach any seeds")]
     SeedFailed,
 
@@ -192,6 +195,9 @@ pub enum Error {
     #[error("Invalid state transition: current_state={0}, end_state={1}")]
     HostStateBlocked(String, String),
 
+    #[error("Invalid P2P namespace: {0}")]
+    InvalidNamespace(String),
+
     // =============
     // Crypto errors
     // =============
@@ -446,6 +452,15 @@ pub enum Error {
     #[error("Event is invalid")]
     EventIsInvalid,
 
+    #[error("Event was rejected: {0}")]
+    EventRejected(String),
+
+    #[error("Invalid RLN signal: {0}")]
+    RlnSignalInvalid(&'static str),
+
+    #[error("RLN identity double-signalled in epoch {0}")]
+    RlnDoubleSignal(u64),
+
     // ====================
     // Miscellaneous errors
     // ====================
@@ -531,12 +546,27 @@ pub enum Error {
     #[error("Geode chunk route not found")]
     GeodeChunkRouteNotFound,
 
+    #[error("Geode file metadata does not match the file hash")]
+    GeodeInvalidFile,
+
+    #[error("Geode chunk does not match the chunk hash")]
+    GeodeInvalidChunk,
+
+    #[error("DHT node is unreachable")]
+    DhtNodeUnreachable,
+
+    #[error("Received invalid DHT reply")]
+    DhtInvalidReply,
+
     // ==================
     // Event Graph errors
     // ==================
     #[error("DAG sync failed")]
     DagSyncFailed,
 
+    #[error("Event archive fetch failed")]
+    ArchiveFetchFailed,
+
     // =========
     // Catch-all
     // =========
@@ -0,0 +1,177 @@
+/* This file is part of DarkFi (https://dark.fi)
+ *
+ * Copyright (C) 2020-2024 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Long-term archive of pruned events.
+//!
+//! When the DAG is rotated, every event in it is dropped. Nodes running
+//! in archive mode move these events into an append-only store instead,
+//! keyed by `timestamp || event_id`, so history of past rotations can be
+//! queried by time and served to peers asking for it.
+
+use std::ops::Bound;
+
+use darkfi_serial::{deserialize_async, serialize_async};
+use sled_overlay::sled;
+
+use super::{util::timestamp_key, Event};
+use crate::Result;
+
+/// Name of the feature advertised by nodes running in archive mode
+pub const ARCHIVE_FEATURE: &str = "event_graph/archive";
+
+/// Version of the archive feature
+pub const ARCHIVE_VERSION: u32 = 1;
+
+/// Maximum number of events sent in a single archive reply
+pub const MAX_ARCHIVE_EVENTS: usize = 512;
+
+/// Maximum number of events paged in by a single archive lookup,
+/// from our own archive or from a peer's
+pub const MAX_ARCHIVE_FETCH: usize = 16 * MAX_ARCHIVE_EVENTS;
+
+/// Append-only, time-indexed store of events pruned from the DAG
+pub struct EventArchive {
+    /// Sled tree holding the archived events
+    tree: sled::Tree,
+}
+
+impl EventArchive {
+    /// Open the archive belonging to the given DAG tree
+    pub fn new(sled_db: &sled::Db, dag_tree_name: &str) -> Result<Self> {
+        let tree = sled_db.open_tree(format!("{}_archive", dag_tree_name))?;
+        Ok(Self { tree })
+    }
+
+    /// Atomically append the given events to the archive.
+    /// Archiving an event again just rewrites the same entry.
+    pub async fn insert(&self, events: &[Event]) -> Result<()> {
+        let mut batch = sled::Batch::default();
+        for event in events {
+            let key = timestamp_key(event.timestamp, event.id().as_bytes());
+            batch.insert(&key[..], serialize_async(event).await);
+        }
+
+        self.tree.apply_batch(batch)?;
+        Ok(())
+    }
+
+    /// Fetch at most `limit` archived events with timestamps in
+    /// `[start, end)`, ordered by timestamp. If `after` is given, only
+    /// events ordered after the event with that ID and `start` timestamp
+    /// are returned, which is used to paginate through a range.
+    pub async fn get_range(
+        &self,
+        start: u64,
+        end: u64,
+        after: Option<blake3::Hash>,
+        limit: usize,
+    ) -> Result<Vec<Event>> {
+        if start >= end {
+            return Ok(vec![])
+        }
+
+        let lower = match after {
+            Some(id) => Bound::Excluded(timestamp_key(start, id.as_bytes())),
+            None => Bound::Included(timestamp_key(start, &[0u8; 32])),
+        };
+        let upper = Bound::Excluded(timestamp_key(end, &[0u8; 32]));
+
+        let mut events = vec![];
+        for item in self.tree.range::<[u8; 40], _>((lower, upper)).take(limit) {
+            let (_, value) = item?;
+            events.push(deserialize_async(&value).await?);
+        }
+
+        Ok(events)
+    }
+
+    /// Return the number of archived events
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    /// Check if the archive is empty
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event_graph::{NULL_ID, N_EVENT_PARENTS};
+
+    fn event(timestamp: u64, n: u8) -> Event {
+        Event {
+            timestamp,
+            content: vec![n],
+            parents: [NULL_ID; N_EVENT_PARENTS],
+            layer: 1,
+            author: None,
+            rln: None,
+            topic: None,
+        }
+    }
+
+    #[test]
+    fn archive_range_queries() -> Result<()> {
+        smol::block_on(async {
+            let sled_db = sled::Config::new().temporary(true).open()?;
+            let archive = EventArchive::new(&sled_db, "dag")?;
+            assert!(archive.is_empty());
+
+            // Insert events out of order, and some of them twice
+            let events: Vec<Event> =
+                (0..10).map(|n| event(1000 + (n as u64 / 2) * 10, n)).collect();
+            archive.insert(&events[5..]).await?;
+            archive.insert(&events[..7]).await?;
+            assert_eq!(archive.len(), 10);
+
+            // Ranges are ordered by timestamp with an exclusive end
+            let range = archive.get_range(1010, 1030, None, 100).await?;
+            assert_eq!(range.len(), 4);
+            assert!(range.windows(2).all(|w| w[0].timestamp <= w[1].timestamp));
+            assert!(range.iter().all(|e| e.timestamp >= 1010 && e.timestamp < 1030));
+
+            // Paginating yields every event exactly once
+            let mut fetched = vec![];
+            let mut start = 0;
+            let mut after = None;
+            loop {
+                let page = archive.get_range(start, u64::MAX, after, 3).await?;
+                if page.is_empty() {
+                    break
+                }
+                let last = page.last().unwrap();
+                start = last.timestamp;
+                after = Some(last.id());
+                fetched.extend(page);
+            }
+            assert_eq!(fetched.len(), 10);
+            for e in events.iter() {
+                assert!(fetched.contains(e));
+            }
+
+            // Empty and inverted ranges
+            assert!(archive.get_range(2000, 3000, None, 100).await?.is_empty());
+            assert!(archive.get_range(1030, 1010, None, 100).await?.is_empty());
+
+            Ok(())
+        })
+    }
+}
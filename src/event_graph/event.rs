@@ -16,9 +16,23 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use std::{collections::HashSet, time::UNIX_EPOCH};
+use std::{
+    collections::HashSet,
+    io::{Error, ErrorKind, Read, Write},
+    time::UNIX_EPOCH,
+};
 
-use darkfi_serial::{async_trait, deserialize_async, Encodable, SerialDecodable, SerialEncodable};
+use darkfi_sdk::{
+    crypto::{
+        schnorr::{SchnorrPublic, SchnorrSecret, Signature},
+        Keypair, MerkleNode, PublicKey,
+    },
+    pasta::pallas,
+};
+use darkfi_serial::{
+    async_trait, deserialize_async, AsyncDecodable, AsyncEncodable, AsyncRead, AsyncWrite,
+    Decodable, Encodable, SerialDecodable, SerialEncodable,
+};
 use sled_overlay::{sled, SledTreeOverlay};
 
 use crate::Result;
@@ -28,8 +42,25 @@ use super::{
     N_EVENT_PARENTS,
 };
 
-/// Representation of an event in the Event Graph
-#[derive(Debug, Clone, PartialEq, SerialEncodable, SerialDecodable)]
+/// Name of the feature advertised by peers speaking the versioned
+/// event encoding
+pub const EVENT_FEATURE: &str = "event_graph/events";
+
+/// Version of the event encoding. Bump it whenever the layout of
+/// [`Event`] changes, so nodes can tell incompatible peers apart.
+pub const EVENT_VERSION: u32 = 1;
+
+/// Marker written in place of the timestamp by events using the
+/// versioned encoding. No valid event can carry this timestamp.
+const EVENT_VERSION_MARKER: u64 = u64::MAX;
+
+/// Representation of an event in the Event Graph.
+///
+/// Events without an author, RLN signal and topic are encoded in the
+/// original layout, so existing DAGs stay readable. Any other event is
+/// prefixed with [`EVENT_VERSION_MARKER`] and the [`EVENT_VERSION`]
+/// it was encoded with.
+#[derive(Debug, Clone, PartialEq)]
 pub struct Event {
     /// Timestamp of the event in whole seconds
     pub timestamp: u64,
@@ -39,37 +70,213 @@ pub struct Event {
     pub parents: [blake3::Hash; N_EVENT_PARENTS],
     /// DAG layer index of the event
     pub layer: u64,
+    /// Authenticated author of the event, if it is signed
+    pub author: Option<EventAuthor>,
+    /// Rate-Limit-Nullifier signal, if the DAG is spam protected
+    pub rln: Option<EventRln>,
+    /// Topic the event belongs to, `None` for the global topic
+    pub topic: Option<blake3::Hash>,
+}
+
+impl Encodable for Event {
+    fn encode<S: Write>(&self, s: &mut S) -> std::io::Result<usize> {
+        let mut len = 0;
+        if self.is_legacy() {
+            len += self.timestamp.encode(s)?;
+        } else {
+            len += EVENT_VERSION_MARKER.encode(s)?;
+            len += EVENT_VERSION.encode(s)?;
+            len += self.timestamp.encode(s)?;
+        }
+        len += self.content.encode(s)?;
+        len += self.parents.encode(s)?;
+        len += self.layer.encode(s)?;
+        if !self.is_legacy() {
+            len += self.author.encode(s)?;
+            len += self.rln.encode(s)?;
+            len += self.topic.encode(s)?;
+        }
+        Ok(len)
+    }
+}
+
+#[async_trait]
+impl AsyncEncodable for Event {
+    async fn encode_async<S: AsyncWrite + Unpin + Send>(
+        &self,
+        s: &mut S,
+    ) -> std::io::Result<usize> {
+        let mut len = 0;
+        if self.is_legacy() {
+            len += self.timestamp.encode_async(s).await?;
+        } else {
+            len += EVENT_VERSION_MARKER.encode_async(s).await?;
+            len += EVENT_VERSION.encode_async(s).await?;
+            len += self.timestamp.encode_async(s).await?;
+        }
+        len += self.content.encode_async(s).await?;
+        len += self.parents.encode_async(s).await?;
+        len += self.layer.encode_async(s).await?;
+        if !self.is_legacy() {
+            len += self.author.encode_async(s).await?;
+            len += self.rln.encode_async(s).await?;
+            len += self.topic.encode_async(s).await?;
+        }
+        Ok(len)
+    }
+}
+
+impl Decodable for Event {
+    fn decode<D: Read>(d: &mut D) -> std::io::Result<Self> {
+        let mut timestamp: u64 = Decodable::decode(d)?;
+        let versioned = timestamp == EVENT_VERSION_MARKER;
+        if versioned {
+            let version: u32 = Decodable::decode(d)?;
+            if version != EVENT_VERSION {
+                return Err(Error::new(ErrorKind::Other, "Unsupported event version"))
+            }
+            timestamp = Decodable::decode(d)?;
+        }
+        let content = Decodable::decode(d)?;
+        let parents = Decodable::decode(d)?;
+        let layer = Decodable::decode(d)?;
+        let (author, rln, topic) = if versioned {
+            (Decodable::decode(d)?, Decodable::decode(d)?, Decodable::decode(d)?)
+        } else {
+            (None, None, None)
+        };
+        Ok(Self { timestamp, content, parents, layer, author, rln, topic })
+    }
+}
+
+#[async_trait]
+impl AsyncDecodable for Event {
+    async fn decode_async<D: AsyncRead + Unpin + Send>(d: &mut D) -> std::io::Result<Self> {
+        let mut timestamp: u64 = AsyncDecodable::decode_async(d).await?;
+        let versioned = timestamp == EVENT_VERSION_MARKER;
+        if versioned {
+            let version: u32 = AsyncDecodable::decode_async(d).await?;
+            if version != EVENT_VERSION {
+                return Err(Error::new(ErrorKind::Other, "Unsupported event version"))
+            }
+            timestamp = AsyncDecodable::decode_async(d).await?;
+        }
+        let content = AsyncDecodable::decode_async(d).await?;
+        let parents = AsyncDecodable::decode_async(d).await?;
+        let layer = AsyncDecodable::decode_async(d).await?;
+        let (author, rln, topic) = if versioned {
+            (
+                AsyncDecodable::decode_async(d).await?,
+                AsyncDecodable::decode_async(d).await?,
+                AsyncDecodable::decode_async(d).await?,
+            )
+        } else {
+            (None, None, None)
+        };
+        Ok(Self { timestamp, content, parents, layer, author, rln, topic })
+    }
+}
+
+/// Authenticated author of an [`Event`]
+#[derive(Debug, Clone, PartialEq, SerialEncodable, SerialDecodable)]
+pub struct EventAuthor {
+    /// Public key of the author, which is part of the event ID
+    pub public_key: PublicKey,
+    /// Schnorr signature over the event ID
+    pub signature: Signature,
+}
+
+/// Rate-Limit-Nullifier signal attached to an [`Event`].
+/// It is not part of the event ID, since the signal itself is
+/// bound to the ID through the message hash used in the proof.
+#[derive(Debug, Clone, PartialEq, SerialEncodable, SerialDecodable)]
+pub struct EventRln {
+    /// RLN epoch the event was signalled in
+    pub epoch: u64,
+    /// Membership Merkle root the proof was made against
+    pub root: MerkleNode,
+    /// Nullifier unique to the signalling identity and epoch
+    pub internal_nullifier: pallas::Base,
+    /// Secret share of the signalling identity
+    pub y: pallas::Base,
+    /// Serialized ZK proof of the signal
+    pub proof: Vec<u8>,
 }
 
 impl Event {
+    /// Whether the event can be encoded in the original layout, the only
+    /// one peers without the [`EVENT_FEATURE`] can decode.
+    pub fn is_legacy(&self) -> bool {
+        self.author.is_none() &&
+            self.rln.is_none() &&
+            self.topic.is_none() &&
+            self.timestamp != EVENT_VERSION_MARKER
+    }
+
     /// Create a new event with the given data and an [`EventGraph`] reference.
     /// The timestamp of the event will be the current time, and the parents
     /// will be `N_EVENT_PARENTS` from the current event graph unreferenced tips.
     /// The parents can also include NULL, but this should be handled by the rest
     /// of the codebase.
     pub async fn new(data: Vec<u8>, event_graph: &EventGraph) -> Self {
-        let (layer, parents) = event_graph.get_next_layer_with_parents().await;
+        let (layer, parents) = event_graph.get_next_layer_with_parents(&None).await;
         Self {
             timestamp: UNIX_EPOCH.elapsed().unwrap().as_millis() as u64,
             content: data,
             parents,
             layer,
+            author: None,
+            rln: None,
+            topic: None,
         }
     }
 
+    /// Same as `Event::new()` but tags the event with the given topic.
+    /// The parents are taken from the topic's own unreferenced tips.
+    pub async fn with_topic(topic: blake3::Hash, data: Vec<u8>, event_graph: &EventGraph) -> Self {
+        let topic = Some(topic);
+        let (layer, parents) = event_graph.get_next_layer_with_parents(&topic).await;
+        Self {
+            timestamp: UNIX_EPOCH.elapsed().unwrap().as_millis() as u64,
+            content: data,
+            parents,
+            layer,
+            author: None,
+            rln: None,
+            topic,
+        }
+    }
+
+    /// Same as `Event::new()` but signs the event with the given [`Keypair`],
+    /// so peers can verify who authored it.
+    pub async fn new_signed(data: Vec<u8>, keypair: &Keypair, event_graph: &EventGraph) -> Self {
+        let mut event = Self::new(data, event_graph).await;
+        event.sign(keypair);
+        event
+    }
+
     /// Same as `Event::new()` but allows specifying the timestamp explicitly.
     pub async fn with_timestamp(timestamp: u64, data: Vec<u8>, event_graph: &EventGraph) -> Self {
-        let (layer, parents) = event_graph.get_next_layer_with_parents().await;
-        Self { timestamp, content: data, parents, layer }
+        let (layer, parents) = event_graph.get_next_layer_with_parents(&None).await;
+        Self { timestamp, content: data, parents, layer, author: None, rln: None, topic: None }
     }
 
-    /// Hash the [`Event`] to retrieve its ID
+    /// Hash the [`Event`] to retrieve its ID. For signed events the
+    /// author's public key is hashed as well, so the signature can't
+    /// be swapped for someone else's. The topic of tagged events is
+    /// hashed too, so it can't be altered while relaying.
     pub fn id(&self) -> blake3::Hash {
         let mut hasher = blake3::Hasher::new();
         self.timestamp.encode(&mut hasher).unwrap();
         self.content.encode(&mut hasher).unwrap();
         self.parents.encode(&mut hasher).unwrap();
         self.layer.encode(&mut hasher).unwrap();
+        if let Some(author) = &self.author {
+            author.public_key.encode(&mut hasher).unwrap();
+        }
+        if let Some(topic) = &self.topic {
+            topic.encode(&mut hasher).unwrap();
+        }
         hasher.finalize()
     }
 
@@ -78,6 +285,31 @@ impl Event {
         &self.content
     }
 
+    /// Sign the event with the given [`Keypair`]. This changes the event ID,
+    /// so it has to be done before the event is inserted or broadcasted.
+    pub fn sign(&mut self, keypair: &Keypair) {
+        self.author =
+            Some(EventAuthor { public_key: keypair.public, signature: Signature::dummy() });
+        let signature = keypair.secret.sign(self.id().as_bytes());
+        self.author.as_mut().unwrap().signature = signature;
+    }
+
+    /// Return the public key of the event's author, if it is signed.
+    /// The signature is only checked by [`Event::verify_author`], which
+    /// is part of the event validation.
+    pub fn author(&self) -> Option<&PublicKey> {
+        self.author.as_ref().map(|author| &author.public_key)
+    }
+
+    /// Verify the author's signature over the event ID. Unsigned events
+    /// are always considered valid.
+    pub fn verify_author(&self) -> bool {
+        match &self.author {
+            Some(author) => author.public_key.verify(self.id().as_bytes(), &author.signature),
+            None => true,
+        }
+    }
+
     /*
     /// Check if an [`Event`] is considered too old.
     fn is_too_old(&self) -> bool {
@@ -101,6 +333,11 @@ impl Event {
             return Ok(false)
         }
 
+        // Check the author's signature, if the event is signed
+        if !self.verify_author() {
+            return Ok(false)
+        }
+
         // Check if the event timestamp is after genesis timestamp
         if self.timestamp < genesis_timestamp - EVENT_TIME_DRIFT {
             return Ok(false)
@@ -117,8 +354,9 @@ impl Event {
 
         // Validate the parents. We have to check that at least one parent
         // is not NULL, that the parents exist, that no two parents are the
-        // same, and that the parent exists in previous layers, to prevent
-        // recursive references(circles).
+        // same, that the parent exists in previous layers, to prevent
+        // recursive references(circles), and that the parent is either the
+        // genesis event or belongs to the same topic.
         let mut seen = HashSet::new();
         let self_id = self.id();
 
@@ -149,6 +387,10 @@ impl Event {
                 return Ok(false)
             }
 
+            if parent.layer > 0 && parent.topic != self.topic {
+                return Ok(false)
+            }
+
             seen.insert(parent_id);
         }
 
@@ -176,6 +418,11 @@ impl Event {
             return false
         }
 
+        // Check the author's signature, if the event is signed
+        if !self.verify_author() {
+            return false
+        }
+
         // Check if the event is too old or too new
         let now = UNIX_EPOCH.elapsed().unwrap().as_millis() as u64;
         let too_old = self.timestamp < now - EVENT_TIME_DRIFT;
@@ -213,6 +460,8 @@ impl Event {
 mod tests {
     use std::sync::Arc;
 
+    use darkfi_serial::serialize_async;
+    use rand::rngs::OsRng;
     use smol::Executor;
 
     use crate::{
@@ -226,7 +475,7 @@ mod tests {
         let ex = Arc::new(Executor::new());
         let p2p = P2p::new(Settings::default(), ex.clone()).await?;
         let sled_db = sled::Config::new().temporary(true).open().unwrap();
-        EventGraph::new(p2p, sled_db, "/tmp".into(), false, "dag", 1, ex).await
+        EventGraph::new(p2p, sled_db, "/tmp".into(), false, "dag", 1, false, None, ex).await
     }
 
     #[test]
@@ -284,4 +533,124 @@ mod tests {
             Ok(())
         })
     }
+
+    #[test]
+    fn signed_events() -> Result<()> {
+        smol::block_on(async {
+            // Generate a dummy event graph
+            let event_graph = make_event_graph().await?;
+
+            // Create a new valid signed event
+            let keypair = Keypair::random(&mut OsRng);
+            let signed_event = Event::new_signed(vec![1u8], &keypair, &event_graph).await;
+            assert_eq!(signed_event.author(), Some(&keypair.public));
+            assert!(signed_event.verify_author());
+            assert!(signed_event.dag_validate(&event_graph).await?);
+
+            // Signing changes the event ID
+            let mut unsigned_event = signed_event.clone();
+            unsigned_event.author = None;
+            assert_ne!(unsigned_event.id(), signed_event.id());
+
+            // Tampering with the content invalidates the signature
+            let mut event_tampered_content = signed_event.clone();
+            event_tampered_content.content = vec![2u8];
+            assert!(!event_tampered_content.verify_author());
+            assert!(!event_tampered_content.dag_validate(&event_graph).await?);
+
+            // Claiming someone else's signature as ours doesn't work either
+            let mut event_swapped_author = signed_event.clone();
+            event_swapped_author.author.as_mut().unwrap().public_key =
+                Keypair::random(&mut OsRng).public;
+            assert!(!event_swapped_author.verify_author());
+            assert!(!event_swapped_author.dag_validate(&event_graph).await?);
+
+            // Thanks for reading
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn event_encoding() -> Result<()> {
+        smol::block_on(async {
+            // Generate a dummy event graph
+            let event_graph = make_event_graph().await?;
+
+            // Plain events keep the original layout, so old DAGs still decode
+            let legacy_event = Event::new(vec![1u8], &event_graph).await;
+            let mut legacy_bytes = vec![];
+            legacy_event.timestamp.encode(&mut legacy_bytes)?;
+            legacy_event.content.encode(&mut legacy_bytes)?;
+            legacy_event.parents.encode(&mut legacy_bytes)?;
+            legacy_event.layer.encode(&mut legacy_bytes)?;
+            assert_eq!(serialize_async(&legacy_event).await, legacy_bytes);
+            assert_eq!(deserialize_async::<Event>(&legacy_bytes).await?, legacy_event);
+
+            // Signed and topic events use the versioned layout
+            let keypair = Keypair::random(&mut OsRng);
+            let mut event = Event::with_topic(blake3::hash(b"#dev"), vec![2u8], &event_graph).await;
+            event.sign(&keypair);
+            let bytes = serialize_async(&event).await;
+            assert_eq!(bytes[..8], EVENT_VERSION_MARKER.to_le_bytes());
+            assert_eq!(deserialize_async::<Event>(&bytes).await?, event);
+
+            // Events of an unknown version are refused
+            let mut bytes = bytes;
+            bytes[8..12].copy_from_slice(&(EVENT_VERSION + 1).to_le_bytes());
+            assert!(deserialize_async::<Event>(&bytes).await.is_err());
+
+            // Thanks for reading
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn topic_events() -> Result<()> {
+        smol::block_on(async {
+            // Generate a dummy event graph
+            let event_graph = make_event_graph().await?;
+            let genesis_id = event_graph.current_genesis.read().await.id();
+            let dev = blake3::hash(b"#dev");
+            let random = blake3::hash(b"#random");
+
+            // Every topic starts off the genesis event
+            let global_event = Event::new(vec![1u8], &event_graph).await;
+            event_graph.dag_insert(&[global_event.clone()]).await?;
+            let dev_event = Event::with_topic(dev, vec![2u8], &event_graph).await;
+            assert_eq!(dev_event.parents[0], genesis_id);
+            assert_eq!(dev_event.layer, 1);
+            event_graph.dag_insert(&[dev_event.clone()]).await?;
+
+            // Topic events only reference their own topic's tips
+            let dev_event2 = Event::with_topic(dev, vec![3u8], &event_graph).await;
+            assert_eq!(dev_event2.parents[0], dev_event.id());
+            assert!(!dev_event2.parents.contains(&global_event.id()));
+            event_graph.dag_insert(&[dev_event2.clone()]).await?;
+
+            // The topic is part of the event ID
+            let mut retagged_event = dev_event2.clone();
+            retagged_event.topic = Some(random);
+            assert_ne!(retagged_event.id(), dev_event2.id());
+
+            // Referencing events of another topic is invalid
+            let mut cross_topic_event = Event::with_topic(random, vec![4u8], &event_graph).await;
+            cross_topic_event.parents[1] = dev_event2.id();
+            cross_topic_event.layer = 3;
+            assert!(!cross_topic_event.dag_validate(&event_graph).await?);
+
+            // Events can be ordered within a topic
+            let dev_events = event_graph.order_topic_events(&Some(dev)).await;
+            let dev_ids: Vec<blake3::Hash> = dev_events.iter().map(|e| e.id()).collect();
+            assert!(dev_ids.contains(&dev_event.id()));
+            assert!(dev_ids.contains(&dev_event2.id()));
+            assert!(!dev_ids.contains(&global_event.id()));
+
+            // ...or over the whole DAG
+            let all_events = event_graph.order_events().await;
+            assert_eq!(all_events.len(), 4);
+
+            // Thanks for reading
+            Ok(())
+        })
+    }
 }
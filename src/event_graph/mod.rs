@@ -17,7 +17,7 @@
  */
 
 use std::{
-    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
     path::PathBuf,
     sync::Arc,
 };
@@ -31,25 +31,59 @@ use smol::{
     Executor,
 };
 use tinyjson::JsonValue::{self};
+use url::Url;
 
 use crate::{
     event_graph::util::replayer_log,
-    net::P2pPtr,
+    net::{ChannelPtr, P2pPtr},
     rpc::{
-        jsonrpc::{JsonResponse, JsonResult},
+        jsonrpc::{ErrorCode, JsonError, JsonResponse, JsonResult},
         util::json_map,
     },
     system::{msleep, Publisher, PublisherPtr, StoppableTask, StoppableTaskPtr, Subscription},
     Error, Result,
 };
 
+/// Long-term archive of pruned events
+pub mod archive;
+use archive::{
+    EventArchive, ARCHIVE_FEATURE, ARCHIVE_VERSION, MAX_ARCHIVE_EVENTS, MAX_ARCHIVE_FETCH,
+};
+
 /// An event graph event
 pub mod event;
-pub use event::Event;
+pub use event::{Event, EventAuthor, EventRln};
+use event::{EVENT_FEATURE, EVENT_VERSION};
 
 /// P2P protocol implementation for the Event Graph
 pub mod proto;
-use proto::{EventRep, EventReq, TipRep, TipReq};
+use proto::{
+    can_decode_all, ArchiveRep, ArchiveReq, EventBulkRep, EventBulkReq, EventPut, EventRep,
+    EventReq, RangeRep, RangeReq, TipRep, TipReq, TopicSub,
+};
+
+/// Time-ordered index and pagination queries over the DAG
+pub mod query;
+use query::{TimestampIndex, MAX_QUERY_EVENTS};
+
+/// Range-based set reconciliation of the DAG
+pub mod reconcile;
+use reconcile::{
+    SyncIndex, MAX_BULK_EVENTS, MAX_RANGES, MAX_ROUNDS, RECONCILE_FEATURE, RECONCILE_VERSION,
+};
+
+/// Topic-scoped sub-DAGs
+pub mod topic;
+pub use topic::TopicFilter;
+use topic::{TOPICS_FEATURE, TOPICS_VERSION};
+
+/// Application-level event validation
+pub mod validation;
+pub use validation::{EventValidator, EventValidatorPtr, ValidatorChain};
+
+/// Rate-Limit-Nullifier spam protection
+#[cfg(feature = "zk")]
+pub mod rln;
 
 /// Utility functions
 pub mod util;
@@ -78,6 +112,9 @@ pub const NULL_ID: blake3::Hash = blake3::Hash::from_bytes([0x00; blake3::OUT_LE
 /// Atomic pointer to an [`EventGraph`] instance.
 pub type EventGraphPtr = Arc<EventGraph>;
 
+/// Set of DAG tips mapped by their layer
+type LayerTips = BTreeMap<u64, HashSet<blake3::Hash>>;
+
 /// An Event Graph instance
 pub struct EventGraph {
     /// Pointer to the P2P network instance
@@ -89,23 +126,39 @@ pub struct EventGraph {
     /// Run in replay_mode where if set we log Sled DB instructions
     /// into `datastore`, useful to reacreate a faulty DAG to debug.
     replay_mode: bool,
-    /// The set of unreferenced DAG tips
-    unreferenced_tips: RwLock<BTreeMap<u64, HashSet<blake3::Hash>>>,
+    /// The set of unreferenced DAG tips of each topic
+    unreferenced_tips: RwLock<HashMap<Option<blake3::Hash>, LayerTips>>,
     /// A `HashSet` containg event IDs and their 1-level parents.
     /// These come from the events we've sent out using `EventPut`.
     /// They are used with `EventReq` to decide if we should reply
     /// or not. Additionally it is also used when we broadcast the
     /// `TipRep` message telling peers about our unreferenced tips.
     broadcasted_ids: RwLock<HashSet<blake3::Hash>>,
+    /// Index of the events in the DAG sorted by timestamp
+    ts_index: TimestampIndex,
+    /// Keys of the events in the DAG sorted by layer, used
+    /// to reconcile the DAG with our peers
+    sync_index: RwLock<SyncIndex>,
+    /// Topics we follow
+    topic_filter: RwLock<TopicFilter>,
+    /// Topics followed by the peers that told us about them
+    peer_topics: RwLock<HashMap<Url, TopicFilter>>,
     /// DAG Pruning Task
     pub prune_task: OnceCell<StoppableTaskPtr>,
     /// Event publisher, this notifies whenever an event is
     /// inserted into the DAG
     pub event_pub: PublisherPtr<Event>,
+    /// Prune publisher, this notifies with the new genesis event
+    /// whenever the DAG is pruned
+    pub prune_pub: PublisherPtr<Event>,
     /// Current genesis event
     current_genesis: RwLock<Event>,
     /// Currently configured DAG rotation, in days
     days_rotation: u64,
+    /// Archive of pruned events, if running in archive mode
+    archive: Option<EventArchive>,
+    /// Application-level validator for incoming and outgoing events
+    validator: Option<EventValidatorPtr>,
     /// Flag signalling DAG has finished initial sync
     pub synced: RwLock<bool>,
     /// Enable graph debugging
@@ -128,6 +181,11 @@ impl EventGraph {
     /// * `dag_tree_name` the name of disk-backed tree (or DAG name).
     /// * `days_rotation` marks the lifetime of the DAG before it's
     ///   pruned.
+    /// * `archive` set the flag to move pruned events into a long-term
+    ///   archive and serve them to peers, instead of dropping them.
+    /// * `validator` optional application-level validator every event
+    ///   has to pass before it is inserted into the DAG.
+    #[allow(clippy::too_many_arguments)]
     pub async fn new(
         p2p: P2pPtr,
         sled_db: sled::Db,
@@ -135,13 +193,30 @@ impl EventGraph {
         replay_mode: bool,
         dag_tree_name: &str,
         days_rotation: u64,
+        archive: bool,
+        validator: Option<EventValidatorPtr>,
         ex: Arc<Executor<'_>>,
     ) -> Result<EventGraphPtr> {
         let dag = sled_db.open_tree(dag_tree_name)?;
-        let unreferenced_tips = RwLock::new(BTreeMap::new());
+        let ts_index = TimestampIndex::new(&sled_db, dag_tree_name)?;
+        let unreferenced_tips = RwLock::new(HashMap::new());
         let broadcasted_ids = RwLock::new(HashSet::new());
         let event_pub = Publisher::new();
 
+        // Let our peers know which event encoding we speak, and that
+        // they can reconcile their DAG with ours
+        p2p.add_feature(EVENT_FEATURE, EVENT_VERSION);
+        p2p.add_feature(RECONCILE_FEATURE, RECONCILE_VERSION);
+        p2p.add_feature(TOPICS_FEATURE, TOPICS_VERSION);
+
+        // In archive mode, let our peers know they can ask us for history
+        let archive = if archive {
+            p2p.add_feature(ARCHIVE_FEATURE, ARCHIVE_VERSION);
+            Some(EventArchive::new(&sled_db, dag_tree_name)?)
+        } else {
+            None
+        };
+
         // Create the current genesis event based on the `days_rotation`
         let current_genesis = generate_genesis(days_rotation);
         let self_ = Arc::new(Self {
@@ -151,10 +226,17 @@ impl EventGraph {
             replay_mode,
             unreferenced_tips,
             broadcasted_ids,
+            ts_index,
+            sync_index: RwLock::new(SyncIndex::new()),
+            topic_filter: RwLock::new(TopicFilter::all()),
+            peer_topics: RwLock::new(HashMap::new()),
             prune_task: OnceCell::new(),
             event_pub,
+            prune_pub: Publisher::new(),
             current_genesis: RwLock::new(current_genesis.clone()),
             days_rotation,
+            archive,
+            validator,
             synced: RwLock::new(false),
             deg_enabled: RwLock::new(false),
             deg_publisher: Publisher::new(),
@@ -168,11 +250,37 @@ impl EventGraph {
                 "[EVENTGRAPH] DAG does not contain current genesis, pruning existing data",
             );
             self_.dag_prune(current_genesis).await?;
+        } else if !self_.dag_decodable().await? {
+            // A DAG written with an incompatible event encoding can't
+            // be used, so start over from genesis and sync it again.
+            warn!(
+                target: "event_graph::new()",
+                "[EVENTGRAPH] DAG contains events of an unsupported version, pruning existing data",
+            );
+            self_.dag_prune(current_genesis).await?;
+        }
+
+        // The index is written after the DAG, so if we crashed in between
+        // or are running on a DAG created before the index existed, it
+        // has to be rebuilt.
+        if self_.ts_index.len() != self_.dag.len() {
+            info!(
+                target: "event_graph::new()",
+                "[EVENTGRAPH] Timestamp index is out of date, rebuilding it",
+            );
+            let mut events = vec![];
+            for value in self_.dag.iter().values() {
+                events.push(deserialize_async(&value?).await?);
+            }
+            self_.ts_index.reset(&events)?;
         }
 
         // Find the unreferenced tips in the current DAG state.
         *self_.unreferenced_tips.write().await = self_.find_unreferenced_tips().await;
 
+        // Index the events in the current DAG state for reconciliation.
+        *self_.sync_index.write().await = self_.find_sync_keys().await;
+
         // Spawn the DAG pruning task
         if days_rotation > 0 {
             let prune_task = StoppableTask::new();
@@ -198,8 +306,237 @@ impl EventGraph {
         self.days_rotation
     }
 
-    /// Sync the DAG from connected peers
+    /// Return the topics we follow
+    pub async fn topic_filter(&self) -> TopicFilter {
+        self.topic_filter.read().await.clone()
+    }
+
+    /// Check if we follow the given topic
+    pub async fn follows(&self, topic: &Option<blake3::Hash>) -> bool {
+        self.topic_filter.read().await.matches(topic)
+    }
+
+    /// Set the topics we follow and let our peers know, so they stop
+    /// relaying us events of other topics. Events of topics we already
+    /// hold are kept, but we stop syncing them.
+    pub async fn set_topic_filter(&self, filter: TopicFilter) {
+        *self.topic_filter.write().await = filter.clone();
+
+        let mut peers = vec![];
+        for channel in self.p2p.hosts().peers() {
+            if channel.has_feature(TOPICS_FEATURE, TOPICS_VERSION).await {
+                peers.push(channel);
+            }
+        }
+
+        if !peers.is_empty() {
+            self.p2p.broadcast_to(&TopicSub(filter), &peers).await;
+        }
+    }
+
+    /// Note down the topics followed by the given peer. Peers that
+    /// are no longer connected are forgotten along the way.
+    async fn set_peer_topics(&self, url: &Url, filter: TopicFilter) {
+        let connected: HashSet<Url> =
+            self.p2p.hosts().peers().iter().map(|c| c.address().clone()).collect();
+
+        let mut peer_topics = self.peer_topics.write().await;
+        peer_topics.retain(|url, _| connected.contains(url));
+        peer_topics.insert(url.clone(), filter);
+    }
+
+    /// Broadcast an event to the connected peers following its topic,
+    /// excluding the given ones. Peers that didn't tell us the topics
+    /// they follow get every event they can decode.
+    pub async fn event_broadcast(&self, event: &Event, exclude: &[Url]) {
+        let peer_topics = self.peer_topics.read().await;
+        let candidates: Vec<ChannelPtr> = self
+            .p2p
+            .hosts()
+            .peers()
+            .into_iter()
+            .filter(|c| !exclude.contains(c.address()))
+            .filter(|c| peer_topics.get(c.address()).map_or(true, |f| f.matches(&event.topic)))
+            .collect();
+        drop(peer_topics);
+
+        let mut peers = Vec::with_capacity(candidates.len());
+        for channel in candidates {
+            if event.is_legacy() || can_decode_all(&channel).await {
+                peers.push(channel);
+            }
+        }
+
+        self.p2p.broadcast_to(&EventPut(event.clone()), &peers).await;
+    }
+
+    /// Sync the DAG from connected peers.
+    /// Peers supporting set reconciliation are reconciled with one by one,
+    /// otherwise we fall back to walking back from the peers' DAG tips.
     pub async fn dag_sync(&self) -> Result<()> {
+        let channels = self.p2p.hosts().peers();
+
+        let mut reconciled_peers = 0;
+        for channel in channels.iter() {
+            if !channel.has_feature(RECONCILE_FEATURE, RECONCILE_VERSION).await {
+                continue
+            }
+
+            match self.dag_reconcile(channel).await {
+                Ok(()) => reconciled_peers += 1,
+                Err(e) => {
+                    error!(
+                        target: "event_graph::dag_sync()",
+                        "[EVENTGRAPH] Sync: Failed reconciling with peer {}, skipping ({})",
+                        channel.address(), e,
+                    );
+                }
+            }
+        }
+
+        if reconciled_peers > 0 {
+            *self.synced.write().await = true;
+            info!(
+                target: "event_graph::dag_sync()",
+                "[EVENTGRAPH] DAG synced successfully with {} peers!", reconciled_peers,
+            );
+            return Ok(())
+        }
+
+        self.dag_sync_tips().await
+    }
+
+    /// Reconcile our DAG with the given peer's. We find the events we're
+    /// missing with range-based set reconciliation, then fetch them in bulk
+    /// and insert them one layer at a time, so parents come before children.
+    /// Events we reject are skipped along with their descendants, so a
+    /// single bad event doesn't throw away the rest of the sync.
+    async fn dag_reconcile(&self, channel: &ChannelPtr) -> Result<()> {
+        let url = channel.address();
+        let comms_timeout = self.p2p.settings().read().await.outbound_connect_timeout;
+        debug!(target: "event_graph::dag_reconcile()", "Reconciling DAG with {}", url);
+
+        let range_rep_sub = channel.subscribe_msg::<RangeRep>().await?;
+        let bulk_rep_sub = channel.subscribe_msg::<EventBulkRep>().await?;
+
+        // Find the keys we're missing among the topics we follow
+        let filter = self.topic_filter.read().await.clone();
+        let mut missing = BTreeSet::new();
+        let mut ranges = vec![reconcile::initial_range(&*self.sync_index.read().await, &filter)];
+        let mut rounds = 0;
+        while !ranges.is_empty() {
+            if rounds == MAX_ROUNDS {
+                error!(
+                    target: "event_graph::dag_reconcile()",
+                    "[EVENTGRAPH] Sync: Reconciliation with {} did not converge", url,
+                );
+                return Err(Error::DagSyncFailed)
+            }
+            rounds += 1;
+
+            let mut next_ranges = vec![];
+            for batch in ranges.chunks(MAX_RANGES) {
+                channel.send(&RangeReq { filter: filter.clone(), ranges: batch.to_vec() }).await?;
+                let reply = range_rep_sub.receive_with_timeout(comms_timeout).await?;
+                let index = self.sync_index.read().await;
+                next_ranges.extend(reconcile::process(&index, &filter, &reply.0, &mut missing));
+            }
+            ranges = next_ranges;
+        }
+
+        debug!(
+            target: "event_graph::dag_reconcile()",
+            "Found {} missing events at {} in {} rounds", missing.len(), url, rounds,
+        );
+
+        // Fetch the missing events in bulk
+        let mut missing_ids = vec![];
+        for (_, id) in missing {
+            if !self.dag.contains_key(id)? {
+                missing_ids.push(blake3::Hash::from_bytes(id));
+            }
+        }
+
+        let mut received_events: BTreeMap<u64, Vec<Event>> = BTreeMap::new();
+        for batch in missing_ids.chunks(MAX_BULK_EVENTS) {
+            channel.send(&EventBulkReq(batch.to_vec())).await?;
+            let reply = bulk_rep_sub.receive_with_timeout(comms_timeout).await?;
+
+            let mut requested: HashSet<&blake3::Hash> = batch.iter().collect();
+            for event in reply.0.iter() {
+                if !requested.remove(&event.id()) {
+                    error!(
+                        target: "event_graph::dag_reconcile()",
+                        "[EVENTGRAPH] Sync: Peer {} replied with a wrong event: {}",
+                        url, event.id(),
+                    );
+                    return Err(Error::DagSyncFailed)
+                }
+
+                received_events.entry(event.layer).or_default().push(event.clone());
+            }
+
+            if !requested.is_empty() {
+                error!(
+                    target: "event_graph::dag_reconcile()",
+                    "[EVENTGRAPH] Sync: Peer {} did not send {} of the requested events",
+                    url, requested.len(),
+                );
+                return Err(Error::DagSyncFailed)
+            }
+        }
+
+        // Events of the same layer can't reference each other, so each
+        // layer can be inserted at once after the previous ones.
+        let mut inserted = 0;
+        let mut rejected = HashSet::new();
+        for (_, layer_events) in received_events {
+            let mut events = Vec::with_capacity(layer_events.len());
+            for event in layer_events {
+                if event.parents.iter().any(|parent| rejected.contains(parent)) {
+                    warn!(
+                        target: "event_graph::dag_reconcile()",
+                        "[EVENTGRAPH] Sync: Skipping event {} from {}, its parent was rejected",
+                        event.id(), url,
+                    );
+                    rejected.insert(event.id());
+                    continue
+                }
+                events.push(event);
+            }
+
+            if self.dag_insert(&events).await.is_ok() {
+                inserted += events.len();
+                continue
+            }
+
+            // Some event of the layer is invalid, so find out which
+            for event in events {
+                match self.dag_insert(&[event.clone()]).await {
+                    Ok(_) => inserted += 1,
+                    Err(e) => {
+                        warn!(
+                            target: "event_graph::dag_reconcile()",
+                            "[EVENTGRAPH] Sync: Skipping event {} from {}: {}",
+                            event.id(), url, e,
+                        );
+                        rejected.insert(event.id());
+                    }
+                }
+            }
+        }
+
+        info!(
+            target: "event_graph::dag_reconcile()",
+            "[EVENTGRAPH] Reconciled DAG with {}: {} events in {} rounds, {} rejected",
+            url, inserted, rounds, rejected.len(),
+        );
+        Ok(())
+    }
+
+    /// Sync the DAG from connected peers by walking back from their tips.
+    /// This is used with peers that don't support set reconciliation.
+    async fn dag_sync_tips(&self) -> Result<()> {
         // We do an optimistic sync where we ask all our connected peers for
         // the latest layer DAG tips (unreferenced events) and then we accept
         // the ones we see the most times.
@@ -428,11 +765,16 @@ impl EventGraph {
         } // <-- while !missing_parents.is_empty
 
         // At this point we should've got all the events.
-        // We should add them to the DAG.
+        // We should add the ones of the topics we follow to the DAG.
+        // Their parents always belong to the same topic, so skipping
+        // the rest doesn't leave any of them dangling.
+        let filter = self.topic_filter.read().await.clone();
         let mut events = vec![];
         for (_, tips) in received_events {
             for tip in tips {
-                events.push(tip);
+                if filter.matches(&tip.topic) {
+                    events.push(tip);
+                }
             }
         }
         self.dag_insert(&events).await?;
@@ -455,6 +797,23 @@ impl EventGraph {
         let mut unreferenced_tips = self.unreferenced_tips.write().await;
         let mut broadcasted_ids = self.broadcasted_ids.write().await;
         let mut current_genesis = self.current_genesis.write().await;
+        let mut sync_index = self.sync_index.write().await;
+
+        // In archive mode, move the events into the archive first. The
+        // archive is keyed by event, so if we crash before the DAG is
+        // cleared, archiving them again on the next prune is harmless.
+        if let Some(archive) = &self.archive {
+            let mut events = vec![];
+            for value in self.dag.iter().values() {
+                let event: Event = deserialize_async(&value?).await?;
+                if event.layer > 0 {
+                    events.push(event);
+                }
+            }
+
+            debug!(target: "event_graph::dag_prune()", "Archiving {} events...", events.len());
+            archive.insert(&events).await?;
+        }
 
         // Atomically clear the DAG and write the new genesis event.
         let mut batch = sled::Batch::default();
@@ -467,15 +826,22 @@ impl EventGraph {
         if let Err(e) = self.dag.apply_batch(batch) {
             panic!("Failed pruning DAG, sled apply_batch error: {}", e);
         }
+        self.ts_index.reset(&[genesis_event.clone()])?;
 
         // Clear unreferenced tips and bcast ids
-        *unreferenced_tips = BTreeMap::new();
-        unreferenced_tips.insert(0, HashSet::from([genesis_event.id()]));
+        *unreferenced_tips = HashMap::new();
+        unreferenced_tips.insert(None, BTreeMap::from([(0, HashSet::from([genesis_event.id()]))]));
         *current_genesis = genesis_event;
         *broadcasted_ids = HashSet::new();
+        *sync_index =
+            SyncIndex::from([((current_genesis.layer, *current_genesis.id().as_bytes()), None)]);
+        let genesis_event = current_genesis.clone();
         drop(unreferenced_tips);
         drop(broadcasted_ids);
         drop(current_genesis);
+        drop(sync_index);
+
+        self.prune_pub.notify(genesis_event).await;
 
         debug!(target: "event_graph::dag_prune()", "DAG pruned successfully");
         Ok(())
@@ -499,6 +865,9 @@ impl EventGraph {
                 content: GENESIS_CONTENTS.to_vec(),
                 parents: [NULL_ID; N_EVENT_PARENTS],
                 layer: 0,
+                author: None,
+                rln: None,
+                topic: None,
             };
 
             // Sleep until it's time to rotate.
@@ -517,8 +886,8 @@ impl EventGraph {
     /// All provided events must be valid. An overlay is used over the DAG tree,
     /// temporary writting each event in order. After all events have been
     /// validated and inserted successfully, we write the overlay to sled.
-    /// This will append the new events into the unreferenced tips set, and
-    /// remove the events' parents from it. It will also append the events'
+    /// This will append the new events into their topic's unreferenced tips
+    /// set, and remove the events' parents from it. It will also append the events'
     /// level-1 parents to the `broadcasted_ids` set, so the P2P protocol
     /// knows that any requests for them are actually legitimate.
     /// TODO: The `broadcasted_ids` set should periodically be pruned, when
@@ -559,6 +928,17 @@ impl EventGraph {
                 return Err(Error::EventIsInvalid)
             }
 
+            // Let the application check the event content
+            if let Some(validator) = &self.validator {
+                if let Err(reason) = validator.validate(event).await {
+                    error!(
+                        target: "event_graph::dag_insert()",
+                        "Event {} was rejected: {}", event_id, reason,
+                    );
+                    return Err(Error::EventRejected(reason))
+                }
+            }
+
             let event_se = serialize_async(event).await;
 
             // Add the event to the overlay
@@ -579,11 +959,18 @@ impl EventGraph {
         if let Err(e) = self.dag.apply_batch(batch) {
             panic!("Failed applying dag_insert batch to sled: {}", e);
         }
+        self.ts_index.insert(events)?;
 
         // Iterate over given events to update references and
         // send out notifications about them
+        let mut sync_index = self.sync_index.write().await;
         for event in events {
             let event_id = event.id();
+            sync_index.insert((event.layer, *event_id.as_bytes()), event.topic);
+
+            // Tips are tracked per topic, since events may only
+            // reference parents of their own topic.
+            let topic_tips = unreferenced_tips.entry(event.topic).or_default();
 
             // Update the unreferenced DAG tips set
             debug!(
@@ -602,7 +989,7 @@ impl EventGraph {
                     // NOTE: this might be too exhaustive, but the
                     // assumption is that previous layers unreferenced
                     // tips will be few.
-                    for (layer, tips) in unreferenced_tips.iter_mut() {
+                    for (layer, tips) in topic_tips.iter_mut() {
                         if layer >= &event.layer {
                             continue
                         }
@@ -611,18 +998,18 @@ impl EventGraph {
                     broadcasted_ids.insert(*parent_id);
                 }
             }
-            unreferenced_tips.retain(|_, tips| !tips.is_empty());
+            topic_tips.retain(|_, tips| !tips.is_empty());
             debug!(
                 target: "event_graph::dag_insert()",
                 "Adding {} to unreferenced tips", event_id,
             );
 
-            if let Some(layer_tips) = unreferenced_tips.get_mut(&event.layer) {
+            if let Some(layer_tips) = topic_tips.get_mut(&event.layer) {
                 layer_tips.insert(event_id);
             } else {
                 let mut layer_tips = HashSet::new();
                 layer_tips.insert(event_id);
-                unreferenced_tips.insert(event.layer, layer_tips);
+                topic_tips.insert(event.layer, layer_tips);
             }
 
             // Send out notifications about the new event
@@ -630,6 +1017,7 @@ impl EventGraph {
         }
 
         // Drop the exclusive locks
+        drop(sync_index);
         drop(unreferenced_tips);
         drop(broadcasted_ids);
 
@@ -644,12 +1032,243 @@ impl EventGraph {
         Ok(Some(event))
     }
 
+    /// Fetch the events with the given IDs from the DAG, skipping
+    /// any that got pruned in the meantime.
+    async fn dag_get_many(&self, event_ids: &[blake3::Hash]) -> Result<Vec<Event>> {
+        let mut events = Vec::with_capacity(event_ids.len());
+        for event_id in event_ids {
+            if let Some(event) = self.dag_get(event_id).await? {
+                events.push(event);
+            }
+        }
+
+        Ok(events)
+    }
+
+    /// Fetch at most `limit` events in the DAG with timestamps in
+    /// `[start, end)`, oldest first. If `after` is given, the range is
+    /// continued after the event with that ID and `start` timestamp,
+    /// which doesn't have to be in the DAG anymore.
+    pub async fn events_between(
+        &self,
+        start: u64,
+        end: u64,
+        after: Option<&blake3::Hash>,
+        limit: usize,
+    ) -> Result<Vec<Event>> {
+        self.dag_get_many(&self.ts_index.between(start, end, after, limit)?).await
+    }
+
+    /// Fetch at most `limit` events in the DAG ordered after the event
+    /// with the given ID, oldest first. Returns an empty list if the
+    /// event is not in the DAG.
+    pub async fn events_after(&self, event_id: &blake3::Hash, limit: usize) -> Result<Vec<Event>> {
+        let Some(event) = self.dag_get(event_id).await? else { return Ok(vec![]) };
+        self.dag_get_many(&self.ts_index.after(event.timestamp, event_id, limit)?).await
+    }
+
+    /// Fetch at most `limit` events in the DAG ordered before the event
+    /// with the given ID, or the latest ones if no ID is given. The result
+    /// is ordered oldest first, so it can be prepended to the page it was
+    /// requested from. Returns an empty list if the event is not in the DAG.
+    pub async fn events_before(
+        &self,
+        event_id: Option<&blake3::Hash>,
+        limit: usize,
+    ) -> Result<Vec<Event>> {
+        let cursor = match event_id {
+            Some(event_id) => {
+                let Some(event) = self.dag_get(event_id).await? else { return Ok(vec![]) };
+                Some((event.timestamp, event_id))
+            }
+            None => None,
+        };
+
+        self.dag_get_many(&self.ts_index.before(cursor, limit)?).await
+    }
+
+    /// Fetch at most `limit` events in the DAG ordered right after the
+    /// given `(timestamp, event_id)` position, oldest first. The position
+    /// doesn't have to be an event in the DAG, so pagination can resume
+    /// from a pruned event or an arbitrary time: a zeroed ID includes
+    /// the events with the given timestamp, and a maxed ID skips them.
+    pub async fn events_after_position(
+        &self,
+        timestamp: u64,
+        event_id: &blake3::Hash,
+        limit: usize,
+    ) -> Result<Vec<Event>> {
+        self.dag_get_many(&self.ts_index.after(timestamp, event_id, limit)?).await
+    }
+
+    /// Fetch at most `limit` events in the DAG ordered right before the
+    /// given `(timestamp, event_id)` position, oldest first. Like with
+    /// [`EventGraph::events_after_position`], it doesn't have to be an
+    /// event in the DAG.
+    pub async fn events_before_position(
+        &self,
+        timestamp: u64,
+        event_id: &blake3::Hash,
+        limit: usize,
+    ) -> Result<Vec<Event>> {
+        self.dag_get_many(&self.ts_index.before(Some((timestamp, event_id)), limit)?).await
+    }
+
+    /// Page through our local archive for events with timestamps in
+    /// `[start, end)`, returning at most the oldest `MAX_ARCHIVE_FETCH`
+    /// of them. Returns an empty list if we're not running in archive
+    /// mode.
+    pub async fn archive_get(&self, start: u64, end: u64) -> Result<Vec<Event>> {
+        let Some(archive) = &self.archive else { return Ok(vec![]) };
+
+        let mut events = vec![];
+        let mut cursor: (u64, Option<blake3::Hash>) = (start, None);
+        loop {
+            let page = archive.get_range(cursor.0, end, cursor.1, MAX_ARCHIVE_EVENTS).await?;
+            let last_page = page.len() < MAX_ARCHIVE_EVENTS;
+            if let Some(event) = page.last() {
+                cursor = (event.timestamp, Some(event.id()));
+            }
+
+            events.extend(page);
+            if last_page || events.len() >= MAX_ARCHIVE_FETCH {
+                break
+            }
+        }
+
+        events.truncate(MAX_ARCHIVE_FETCH);
+        Ok(events)
+    }
+
+    /// Fetch the events with timestamps in `[start, end)` from a peer
+    /// running in archive mode, trying them one by one until one of
+    /// them answers. Archived events can't be checked against the DAG
+    /// they were pruned from, so only their signatures and the
+    /// application validator are checked. Since that isn't enough to
+    /// trust them, they are never added to our own archive.
+    pub async fn fetch_archive(&self, start: u64, end: u64) -> Result<Vec<Event>> {
+        let channels = self.p2p.hosts().peers();
+
+        for channel in channels.iter() {
+            if !channel.has_feature(ARCHIVE_FEATURE, ARCHIVE_VERSION).await {
+                continue
+            }
+
+            match self.fetch_archive_from(channel, start, end).await {
+                Ok(events) => return Ok(events),
+                Err(e) => {
+                    error!(
+                        target: "event_graph::fetch_archive()",
+                        "[EVENTGRAPH] Failed fetching archive from peer {}, skipping ({})",
+                        channel.address(), e,
+                    );
+                }
+            }
+        }
+
+        Err(Error::ArchiveFetchFailed)
+    }
+
+    /// Page through the given peer's archive for events with timestamps
+    /// in `[start, end)`, pulling at most the oldest `MAX_ARCHIVE_FETCH`
+    /// of them.
+    async fn fetch_archive_from(
+        &self,
+        channel: &ChannelPtr,
+        start: u64,
+        end: u64,
+    ) -> Result<Vec<Event>> {
+        let url = channel.address();
+        let comms_timeout = self.p2p.settings().read().await.outbound_connect_timeout;
+        debug!(target: "event_graph::fetch_archive_from()", "Fetching archive from {}", url);
+
+        let archive_rep_sub = channel.subscribe_msg::<ArchiveRep>().await?;
+
+        let mut events = vec![];
+        let mut cursor: (u64, Option<blake3::Hash>) = (start, None);
+        loop {
+            channel.send(&ArchiveReq { start: cursor.0, end, after: cursor.1 }).await?;
+            let reply = archive_rep_sub.receive_with_timeout(comms_timeout).await?;
+
+            if reply.0.len() > MAX_ARCHIVE_EVENTS {
+                error!(
+                    target: "event_graph::fetch_archive_from()",
+                    "[EVENTGRAPH] Peer {} replied with too many archived events", url,
+                );
+                return Err(Error::ArchiveFetchFailed)
+            }
+
+            // Events have to be valid, within the requested range and
+            // strictly ordered after the previous page.
+            for event in reply.0.iter() {
+                let event_id = event.id();
+                let after_cursor = match cursor.1 {
+                    Some(id) => (event.timestamp, event_id.as_bytes()) > (cursor.0, id.as_bytes()),
+                    None => event.timestamp >= cursor.0,
+                };
+
+                if !after_cursor ||
+                    event.timestamp >= end ||
+                    event.content.is_empty() ||
+                    !event.verify_author()
+                {
+                    error!(
+                        target: "event_graph::fetch_archive_from()",
+                        "[EVENTGRAPH] Peer {} replied with an invalid archived event: {}",
+                        url, event_id,
+                    );
+                    return Err(Error::ArchiveFetchFailed)
+                }
+
+                // Let the application check the event content
+                if let Some(validator) = &self.validator {
+                    if let Err(reason) = validator.validate(event).await {
+                        error!(
+                            target: "event_graph::fetch_archive_from()",
+                            "[EVENTGRAPH] Peer {} replied with a rejected archived event {}: {}",
+                            url, event_id, reason,
+                        );
+                        return Err(Error::ArchiveFetchFailed)
+                    }
+                }
+
+                cursor = (event.timestamp, Some(event_id));
+            }
+
+            let last_page = reply.0.len() < MAX_ARCHIVE_EVENTS;
+            events.extend(reply.0.iter().cloned());
+            if last_page || events.len() >= MAX_ARCHIVE_FETCH {
+                break
+            }
+        }
+        events.truncate(MAX_ARCHIVE_FETCH);
+
+        info!(
+            target: "event_graph::fetch_archive_from()",
+            "[EVENTGRAPH] Fetched {} archived events from {}", events.len(), url,
+        );
+        Ok(events)
+    }
+
     /// Get next layer along with its N_EVENT_PARENTS from the unreferenced
-    /// tips of the DAG. Since tips are mapped by their layer, we go backwards
-    /// until we fill the vector, ensuring we always use latest layers tips as
-    /// parents.
-    async fn get_next_layer_with_parents(&self) -> (u64, [blake3::Hash; N_EVENT_PARENTS]) {
+    /// tips of the given topic. Since tips are mapped by their layer, we go
+    /// backwards until we fill the vector, ensuring we always use latest
+    /// layers tips as parents. Topics without any events yet start off the
+    /// genesis event.
+    async fn get_next_layer_with_parents(
+        &self,
+        topic: &Option<blake3::Hash>,
+    ) -> (u64, [blake3::Hash; N_EVENT_PARENTS]) {
         let unreferenced_tips = self.unreferenced_tips.read().await;
+        let genesis_tips;
+        let unreferenced_tips = match unreferenced_tips.get(topic) {
+            Some(tips) => tips,
+            None => {
+                let genesis_id = self.current_genesis.read().await.id();
+                genesis_tips = BTreeMap::from([(0, HashSet::from([genesis_id]))]);
+                &genesis_tips
+            }
+        };
 
         let mut parents = [NULL_ID; N_EVENT_PARENTS];
         let mut index = 0;
@@ -669,29 +1288,48 @@ impl EventGraph {
         (next_layer, parents)
     }
 
-    /// Find the unreferenced tips in the current DAG state, mapped by their layers.
-    async fn find_unreferenced_tips(&self) -> BTreeMap<u64, HashSet<blake3::Hash>> {
+    /// Check that every event in the DAG can be decoded
+    async fn dag_decodable(&self) -> Result<bool> {
+        for value in self.dag.iter().values() {
+            if deserialize_async::<Event>(&value?).await.is_err() {
+                return Ok(false)
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Find the unreferenced tips in the current DAG state, mapped by their
+    /// topics and layers. Entries that can't be read are skipped.
+    async fn find_unreferenced_tips(&self) -> HashMap<Option<blake3::Hash>, LayerTips> {
         // First get all the event IDs
         let mut tips = HashSet::new();
-        for iter_elem in self.dag.iter() {
-            let (id, _) = iter_elem.unwrap();
-            let id = blake3::Hash::from_bytes((&id as &[u8]).try_into().unwrap());
-            tips.insert(id);
+        let mut events = HashMap::new();
+        for (id, event) in self.dag.iter().flatten() {
+            let Ok(id) = <[u8; 32]>::try_from(&id as &[u8]) else { continue };
+            let Ok(event) = deserialize_async::<Event>(&event).await else {
+                warn!(
+                    target: "event_graph::find_unreferenced_tips()",
+                    "[EVENTGRAPH] Skipping undecodable event {}", blake3::Hash::from_bytes(id),
+                );
+                continue
+            };
+            tips.insert(blake3::Hash::from_bytes(id));
+            events.insert(blake3::Hash::from_bytes(id), event);
         }
 
-        // Iterate again to find unreferenced IDs
-        for iter_elem in self.dag.iter() {
-            let (_, event) = iter_elem.unwrap();
-            let event: Event = deserialize_async(&event).await.unwrap();
+        // Remove every ID that is referenced as a parent
+        for event in events.values() {
             for parent in event.parents.iter() {
                 tips.remove(parent);
             }
         }
 
-        // Build the layers map
-        let mut map: BTreeMap<u64, HashSet<blake3::Hash>> = BTreeMap::new();
+        // Build the layers map of each topic
+        let mut topics: HashMap<Option<blake3::Hash>, LayerTips> = HashMap::new();
         for tip in tips {
-            let event = self.dag_get(&tip).await.unwrap().unwrap();
+            let event = &events[&tip];
+            let map = topics.entry(event.topic).or_default();
             if let Some(layer_tips) = map.get_mut(&event.layer) {
                 layer_tips.insert(tip);
             } else {
@@ -701,12 +1339,34 @@ impl EventGraph {
             }
         }
 
-        map
+        topics
+    }
+
+    /// Find the reconciliation keys of all the events in the DAG.
+    /// Entries that can't be read are skipped.
+    async fn find_sync_keys(&self) -> SyncIndex {
+        let mut keys = SyncIndex::new();
+        for (id, event) in self.dag.iter().flatten() {
+            let Ok(id) = <[u8; 32]>::try_from(&id as &[u8]) else { continue };
+            let Ok(event) = deserialize_async::<Event>(&event).await else {
+                warn!(
+                    target: "event_graph::find_sync_keys()",
+                    "[EVENTGRAPH] Skipping undecodable event {}", blake3::Hash::from_bytes(id),
+                );
+                continue
+            };
+            keys.insert((event.layer, id), event.topic);
+        }
+
+        keys
     }
 
     /// Internal function used for DAG sorting.
-    async fn get_unreferenced_tips_sorted(&self) -> [blake3::Hash; N_EVENT_PARENTS] {
-        let (_, tips) = self.get_next_layer_with_parents().await;
+    async fn get_unreferenced_tips_sorted(
+        &self,
+        topic: &Option<blake3::Hash>,
+    ) -> [blake3::Hash; N_EVENT_PARENTS] {
+        let (_, tips) = self.get_next_layer_with_parents(topic).await;
 
         // Convert the hash to BigUint for sorting
         let mut sorted: Vec<_> =
@@ -729,15 +1389,34 @@ impl EventGraph {
         tips_sorted
     }
 
-    /// Perform a topological sort of the DAG.
+    /// Perform a topological sort of the DAG, over all of its topics.
     pub async fn order_events(&self) -> Vec<Event> {
+        let mut topics: Vec<Option<blake3::Hash>> =
+            self.unreferenced_tips.read().await.keys().copied().collect();
+        if !topics.contains(&None) {
+            topics.push(None);
+        }
+
+        self.order_topics_events(&topics).await
+    }
+
+    /// Perform a topological sort of the sub-DAG of the given topic,
+    /// `None` being the global topic.
+    pub async fn order_topic_events(&self, topic: &Option<blake3::Hash>) -> Vec<Event> {
+        self.order_topics_events(&[*topic]).await
+    }
+
+    /// Perform a topological sort of the sub-DAGs of the given topics.
+    async fn order_topics_events(&self, topics: &[Option<blake3::Hash>]) -> Vec<Event> {
         let mut ordered_events = VecDeque::new();
         let mut visited = HashSet::new();
 
-        for tip in self.get_unreferenced_tips_sorted().await {
-            if !visited.contains(&tip) && tip != NULL_ID {
-                let tip = self.dag_get(&tip).await.unwrap().unwrap();
-                ordered_events.extend(self.dfs_topological_sort(tip, &mut visited).await);
+        for topic in topics {
+            for tip in self.get_unreferenced_tips_sorted(topic).await {
+                if !visited.contains(&tip) && tip != NULL_ID {
+                    let tip = self.dag_get(&tip).await.unwrap().unwrap();
+                    ordered_events.extend(self.dfs_topological_sort(tip, &mut visited).await);
+                }
             }
         }
 
@@ -823,6 +1502,111 @@ impl EventGraph {
         JsonResponse::new(result, id).into()
     }
 
+    // RPCAPI:
+    // Query the DAG by time. Events are returned oldest first, and at
+    // most 1000 of them are returned by any query. Timestamps are in
+    // milliseconds since the UNIX epoch.
+    // A `between` query returns the events along with a `next` cursor,
+    // which is `null` once the whole range was returned. Otherwise the
+    // range is continued by passing the cursor's timestamp as the start
+    // and its event ID as an extra parameter.
+    // --> {"jsonrpc": "2.0", "method": "eventgraph.query", "params": ["between", 1700000000000, 1700003600000], "id": 1}
+    // <-- {"jsonrpc": "2.0", "result": {"events": [{"id": "...", "timestamp": ..., ...}, ...], "next": [1700000042000, "event_id"]}, "id": 1}
+    // --> {"jsonrpc": "2.0", "method": "eventgraph.query", "params": ["between", 1700000042000, 1700003600000, "event_id"], "id": 1}
+    // <-- {"jsonrpc": "2.0", "result": {"events": [...], "next": null}, "id": 1}
+    // --> {"jsonrpc": "2.0", "method": "eventgraph.query", "params": ["after", "event_id", 50], "id": 1}
+    // --> {"jsonrpc": "2.0", "method": "eventgraph.query", "params": ["before", null, 50], "id": 1}
+    // <-- {"jsonrpc": "2.0", "result": [{"id": "...", "timestamp": ..., ...}, ...], "id": 1}
+    pub async fn eventgraph_query(&self, id: u16, params: JsonValue) -> JsonResult {
+        let Some(params) = params.get::<Vec<JsonValue>>() else {
+            return JsonError::new(ErrorCode::InvalidParams, None, id).into()
+        };
+        if !(3..=4).contains(&params.len()) || !params[0].is_string() {
+            return JsonError::new(ErrorCode::InvalidParams, None, id).into()
+        }
+        let between = params[0].get::<String>().unwrap() == "between";
+        if params.len() == 4 && !between {
+            return JsonError::new(ErrorCode::InvalidParams, None, id).into()
+        }
+
+        let parse_id = |v: &JsonValue| -> Option<blake3::Hash> {
+            blake3::Hash::from_hex(v.get::<String>()?).ok()
+        };
+        let parse_num = |v: &JsonValue| -> Option<f64> {
+            let n = *v.get::<f64>()?;
+            (n >= 0.0 && n.fract() == 0.0).then_some(n)
+        };
+
+        let events = match params[0].get::<String>().unwrap().as_str() {
+            "between" => {
+                let (Some(start), Some(end)) = (parse_num(&params[1]), parse_num(&params[2]))
+                else {
+                    return JsonError::new(ErrorCode::InvalidParams, None, id).into()
+                };
+                let after = match params.get(3) {
+                    None => None,
+                    Some(v) => match parse_id(v) {
+                        Some(event_id) => Some(event_id),
+                        None => return JsonError::new(ErrorCode::InvalidParams, None, id).into(),
+                    },
+                };
+                self.events_between(start as u64, end as u64, after.as_ref(), MAX_QUERY_EVENTS)
+                    .await
+            }
+
+            "after" => {
+                let (Some(event_id), Some(limit)) = (parse_id(&params[1]), parse_num(&params[2]))
+                else {
+                    return JsonError::new(ErrorCode::InvalidParams, None, id).into()
+                };
+                let limit = (limit as usize).min(MAX_QUERY_EVENTS);
+                self.events_after(&event_id, limit).await
+            }
+
+            "before" => {
+                let event_id = match &params[1] {
+                    JsonValue::Null => None,
+                    v => match parse_id(v) {
+                        Some(event_id) => Some(event_id),
+                        None => return JsonError::new(ErrorCode::InvalidParams, None, id).into(),
+                    },
+                };
+                let Some(limit) = parse_num(&params[2]) else {
+                    return JsonError::new(ErrorCode::InvalidParams, None, id).into()
+                };
+                let limit = (limit as usize).min(MAX_QUERY_EVENTS);
+                self.events_before(event_id.as_ref(), limit).await
+            }
+
+            _ => return JsonError::new(ErrorCode::InvalidParams, None, id).into(),
+        };
+
+        let events = match events {
+            Ok(v) => v,
+            Err(e) => {
+                error!(target: "event_graph::eventgraph_query()", "Failed querying DAG: {}", e);
+                return JsonError::new(ErrorCode::InternalError, None, id).into()
+            }
+        };
+
+        // Full pages of a range get a cursor to continue from
+        let next = match events.last() {
+            Some(last) if between && events.len() == MAX_QUERY_EVENTS => JsonValue::Array(vec![
+                JsonValue::Number(last.timestamp as f64),
+                JsonValue::String(last.id().to_string()),
+            ]),
+            _ => JsonValue::Null,
+        };
+
+        let events = JsonValue::Array(events.into_iter().map(JsonValue::from).collect());
+        if !between {
+            return JsonResponse::new(events, id).into()
+        }
+
+        let result = json_map([("events", events), ("next", next)]);
+        JsonResponse::new(result, id).into()
+    }
+
     /// Fetch all the events that are on a higher layers than the
     /// provided ones.
     pub async fn fetch_successors_of(
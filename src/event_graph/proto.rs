@@ -28,7 +28,13 @@ use darkfi_serial::{async_trait, SerialDecodable, SerialEncodable};
 use log::{debug, error, trace, warn};
 use smol::Executor;
 
-use super::{Event, EventGraphPtr, NULL_ID};
+use super::{
+    archive::MAX_ARCHIVE_EVENTS,
+    event::{EVENT_FEATURE, EVENT_VERSION},
+    reconcile::{self, SyncRange, MAX_BULK_EVENTS, MAX_RANGES},
+    topic::{TopicFilter, MAX_FILTER_TOPICS, TOPICS_FEATURE, TOPICS_VERSION},
+    Event, EventGraphPtr, NULL_ID,
+};
 use crate::{impl_p2p_message, net::*, Error, Result};
 
 /// Malicious behaviour threshold. If the threshold is reached, we will
@@ -51,6 +57,20 @@ pub struct ProtocolEventGraph {
     tip_req_sub: MessageSubscription<TipReq>,
     /// `MessageSubscriber` for `TipRep`
     _tip_rep_sub: MessageSubscription<TipRep>,
+    /// `MessageSubscriber` for `RangeReq`
+    range_req_sub: MessageSubscription<RangeReq>,
+    /// `MessageSubscriber` for `RangeRep`
+    _range_rep_sub: MessageSubscription<RangeRep>,
+    /// `MessageSubscriber` for `EventBulkReq`
+    bulk_req_sub: MessageSubscription<EventBulkReq>,
+    /// `MessageSubscriber` for `EventBulkRep`
+    _bulk_rep_sub: MessageSubscription<EventBulkRep>,
+    /// `MessageSubscriber` for `ArchiveReq`
+    archive_req_sub: MessageSubscription<ArchiveReq>,
+    /// `MessageSubscriber` for `ArchiveRep`
+    _archive_rep_sub: MessageSubscription<ArchiveRep>,
+    /// `MessageSubscriber` for `TopicSub`
+    topic_sub_sub: MessageSubscription<TopicSub>,
     /// Peer malicious message count
     malicious_count: AtomicUsize,
     /// P2P jobs manager pointer
@@ -70,7 +90,7 @@ impl_p2p_message!(EventReq, "EventGraph::EventReq");
 /// A P2P message representing an event reply
 #[derive(Clone, SerialEncodable, SerialDecodable)]
 pub struct EventRep(pub Vec<Event>);
-impl_p2p_message!(EventRep, "EventGraph::EventRep");
+impl_p2p_message!(EventRep, "EventGraph::EventRep", compressible);
 
 /// A P2P message representing a request for a peer's DAG tips
 #[derive(Clone, SerialEncodable, SerialDecodable)]
@@ -82,6 +102,63 @@ impl_p2p_message!(TipReq, "EventGraph::TipReq");
 pub struct TipRep(pub BTreeMap<u64, HashSet<blake3::Hash>>);
 impl_p2p_message!(TipRep, "EventGraph::TipRep");
 
+/// A P2P message carrying DAG key ranges to reconcile, considering
+/// only the events of the topics in `filter`.
+/// Only sent to peers advertising the reconciliation feature.
+#[derive(Clone, SerialEncodable, SerialDecodable)]
+pub struct RangeReq {
+    pub filter: TopicFilter,
+    pub ranges: Vec<SyncRange>,
+}
+impl_p2p_message!(RangeReq, "EventGraph::RangeReq");
+
+/// A P2P message answering a `RangeReq`
+#[derive(Clone, SerialEncodable, SerialDecodable)]
+pub struct RangeRep(pub Vec<SyncRange>);
+impl_p2p_message!(RangeRep, "EventGraph::RangeRep", compressible);
+
+/// A P2P message requesting the events found missing by reconciliation
+#[derive(Clone, SerialEncodable, SerialDecodable)]
+pub struct EventBulkReq(pub Vec<blake3::Hash>);
+impl_p2p_message!(EventBulkReq, "EventGraph::EventBulkReq");
+
+/// A P2P message replying to an `EventBulkReq`
+#[derive(Clone, SerialEncodable, SerialDecodable)]
+pub struct EventBulkRep(pub Vec<Event>);
+impl_p2p_message!(EventBulkRep, "EventGraph::EventBulkRep", compressible);
+
+/// A P2P message requesting archived events with timestamps in
+/// `[start, end)`. If `after` is set, only events ordered after the
+/// event with that ID and the `start` timestamp are requested.
+/// Only sent to peers advertising the archive feature.
+#[derive(Clone, SerialEncodable, SerialDecodable)]
+pub struct ArchiveReq {
+    pub start: u64,
+    pub end: u64,
+    pub after: Option<blake3::Hash>,
+}
+impl_p2p_message!(ArchiveReq, "EventGraph::ArchiveReq");
+
+/// A P2P message replying to an `ArchiveReq` with at most
+/// `MAX_ARCHIVE_EVENTS` events ordered by timestamp
+#[derive(Clone, SerialEncodable, SerialDecodable)]
+pub struct ArchiveRep(pub Vec<Event>);
+impl_p2p_message!(ArchiveRep, "EventGraph::ArchiveRep", compressible);
+
+/// A P2P message telling a peer which topics we follow, so it only
+/// relays us events of these topics.
+/// Only sent to peers advertising the topics feature.
+#[derive(Clone, SerialEncodable, SerialDecodable)]
+pub struct TopicSub(pub TopicFilter);
+impl_p2p_message!(TopicSub, "EventGraph::TopicSub");
+
+/// Whether the peer on `channel` speaks the versioned event encoding.
+/// Older peers only decode events in the legacy layout, see
+/// [`Event::is_legacy`], so the other events are never sent to them.
+pub(super) async fn can_decode_all(channel: &ChannelPtr) -> bool {
+    channel.has_feature(EVENT_FEATURE, EVENT_VERSION).await
+}
+
 #[async_trait]
 impl ProtocolBase for ProtocolEventGraph {
     async fn start(self: Arc<Self>, ex: Arc<Executor<'_>>) -> Result<()> {
@@ -89,6 +166,16 @@ impl ProtocolBase for ProtocolEventGraph {
         self.jobsman.clone().spawn(self.clone().handle_event_put(), ex.clone()).await;
         self.jobsman.clone().spawn(self.clone().handle_event_req(), ex.clone()).await;
         self.jobsman.clone().spawn(self.clone().handle_tip_req(), ex.clone()).await;
+        self.jobsman.clone().spawn(self.clone().handle_range_req(), ex.clone()).await;
+        self.jobsman.clone().spawn(self.clone().handle_bulk_req(), ex.clone()).await;
+        self.jobsman.clone().spawn(self.clone().handle_archive_req(), ex.clone()).await;
+        self.jobsman.clone().spawn(self.clone().handle_topic_sub(), ex.clone()).await;
+
+        // Let the peer know which topics we follow
+        if self.channel.has_feature(TOPICS_FEATURE, TOPICS_VERSION).await {
+            self.channel.send(&TopicSub(self.event_graph.topic_filter().await)).await?;
+        }
+
         Ok(())
     }
 
@@ -105,12 +192,26 @@ impl ProtocolEventGraph {
         msg_subsystem.add_dispatch::<EventRep>().await;
         msg_subsystem.add_dispatch::<TipReq>().await;
         msg_subsystem.add_dispatch::<TipRep>().await;
+        msg_subsystem.add_dispatch::<RangeReq>().await;
+        msg_subsystem.add_dispatch::<RangeRep>().await;
+        msg_subsystem.add_dispatch::<EventBulkReq>().await;
+        msg_subsystem.add_dispatch::<EventBulkRep>().await;
+        msg_subsystem.add_dispatch::<ArchiveReq>().await;
+        msg_subsystem.add_dispatch::<ArchiveRep>().await;
+        msg_subsystem.add_dispatch::<TopicSub>().await;
 
         let ev_put_sub = channel.subscribe_msg::<EventPut>().await?;
         let ev_req_sub = channel.subscribe_msg::<EventReq>().await?;
         let ev_rep_sub = channel.subscribe_msg::<EventRep>().await?;
         let tip_req_sub = channel.subscribe_msg::<TipReq>().await?;
         let _tip_rep_sub = channel.subscribe_msg::<TipRep>().await?;
+        let range_req_sub = channel.subscribe_msg::<RangeReq>().await?;
+        let _range_rep_sub = channel.subscribe_msg::<RangeRep>().await?;
+        let bulk_req_sub = channel.subscribe_msg::<EventBulkReq>().await?;
+        let _bulk_rep_sub = channel.subscribe_msg::<EventBulkRep>().await?;
+        let archive_req_sub = channel.subscribe_msg::<ArchiveReq>().await?;
+        let _archive_rep_sub = channel.subscribe_msg::<ArchiveRep>().await?;
+        let topic_sub_sub = channel.subscribe_msg::<TopicSub>().await?;
 
         Ok(Arc::new(Self {
             channel: channel.clone(),
@@ -120,6 +221,13 @@ impl ProtocolEventGraph {
             ev_rep_sub,
             tip_req_sub,
             _tip_rep_sub,
+            range_req_sub,
+            _range_rep_sub,
+            bulk_req_sub,
+            _bulk_rep_sub,
+            archive_req_sub,
+            _archive_rep_sub,
+            topic_sub_sub,
             malicious_count: AtomicUsize::new(0),
             jobsman: ProtocolJobsManager::new("ProtocolEventGraph", channel.clone()),
         }))
@@ -145,6 +253,34 @@ impl ProtocolEventGraph {
         Ok(())
     }
 
+    /// Penalize the peer for an event we failed to insert into the DAG.
+    /// Events rejected by the application's validator are content the
+    /// peer should've never relayed, so reaching the malicious threshold
+    /// with them gets the peer banned rather than just disconnected.
+    async fn handle_insert_error(self: Arc<Self>, error: Error) -> Result<()> {
+        let Error::EventRejected(reason) = error else {
+            return self.increase_malicious_count().await
+        };
+
+        let malicious_count = self.malicious_count.fetch_add(1, SeqCst);
+        if malicious_count + 1 >= MALICIOUS_THRESHOLD {
+            error!(
+                target: "event_graph::protocol::handle_insert_error()",
+                "[EVENTGRAPH] Peer {} reached malicious threshold with rejected events. Banning.",
+                self.channel.address(),
+            );
+            self.channel.ban(self.channel.address()).await;
+            return Err(Error::ChannelStopped)
+        }
+
+        warn!(
+            target: "event_graph::protocol::handle_insert_error()",
+            "[EVENTGRAPH] Peer {} sent us a rejected event: {}", self.channel.address(), reason,
+        );
+
+        Ok(())
+    }
+
     /// Protocol function handling `EventPut`.
     /// This is triggered whenever someone broadcasts (or relays) a new
     /// event on the network.
@@ -178,6 +314,16 @@ impl ProtocolEventGraph {
                 continue
             }
 
+            // If we don't follow the event's topic, we'll ignore it. The
+            // peer may simply not know our topics yet, so it's not malicious.
+            if !self.event_graph.follows(&event.topic).await {
+                debug!(
+                    target: "event_graph::protocol::handle_event_put()",
+                    "Event {} belongs to a topic we don't follow", event_id,
+                );
+                continue
+            }
+
             // We received an event. Check if we already have it in our DAG.
             // Check event is not older that current genesis event timestamp.
             // Also check if we have the event's parents. In the case we do
@@ -333,8 +479,8 @@ impl ProtocolEventGraph {
                         events.push(tip);
                     }
                 }
-                if self.event_graph.dag_insert(&events).await.is_err() {
-                    self.clone().increase_malicious_count().await?;
+                if let Err(e) = self.event_graph.dag_insert(&events).await {
+                    self.clone().handle_insert_error(e).await?;
                     continue
                 }
             } // <-- !missing_parents.is_empty()
@@ -346,16 +492,13 @@ impl ProtocolEventGraph {
                 target: "event_graph::protocol::handle_event_put()",
                 "Got all parents necessary for insertion",
             );
-            if self.event_graph.dag_insert(&[event.clone()]).await.is_err() {
-                self.clone().increase_malicious_count().await?;
+            if let Err(e) = self.event_graph.dag_insert(&[event.clone()]).await {
+                self.clone().handle_insert_error(e).await?;
                 continue
             }
 
-            // Relay the event to other peers.
-            self.event_graph
-                .p2p
-                .broadcast_with_exclude(&EventPut(event), &[self.channel.address().clone()])
-                .await;
+            // Relay the event to other peers following its topic.
+            self.event_graph.event_broadcast(&event, &[self.channel.address().clone()]).await;
         }
     }
 
@@ -452,6 +595,12 @@ impl ProtocolEventGraph {
             //bcast_ids.remove(&event_id);
             drop(bcast_ids);
 
+            // Peers without the versioned event encoding would fail
+            // decoding the rest, so they only get legacy events.
+            if !can_decode_all(&self.channel).await {
+                events.retain(|event| event.is_legacy());
+            }
+
             // Reply with the event
             self.channel.send(&EventRep(events)).await?;
         }
@@ -480,8 +629,14 @@ impl ProtocolEventGraph {
             // TODO: Rate limit
 
             // We received a tip request. Let's find them, add them to
-            // our bcast ids list, and reply with them.
-            let layers = self.event_graph.unreferenced_tips.read().await.clone();
+            // our bcast ids list, and reply with them. The tips of all
+            // the topics are merged, as the peer walks back from each.
+            let mut layers: BTreeMap<u64, HashSet<blake3::Hash>> = BTreeMap::new();
+            for topic_tips in self.event_graph.unreferenced_tips.read().await.values() {
+                for (layer, tips) in topic_tips.iter() {
+                    layers.entry(*layer).or_default().extend(tips.iter().copied());
+                }
+            }
             let mut bcast_ids = self.event_graph.broadcasted_ids.write().await;
             for (_, tips) in layers.iter() {
                 for tip in tips {
@@ -493,4 +648,145 @@ impl ProtocolEventGraph {
             self.channel.send(&TipRep(layers)).await?;
         }
     }
+
+    /// Protocol function handling `RangeReq`.
+    /// This is triggered when a peer reconciles its DAG with ours. We
+    /// answer with our keys or fingerprints of the mismatching ranges.
+    async fn handle_range_req(self: Arc<Self>) -> Result<()> {
+        loop {
+            let (filter, ranges) = match self.range_req_sub.receive().await {
+                Ok(v) => (v.filter.clone(), v.ranges.clone()),
+                Err(_) => continue,
+            };
+            trace!(
+                target: "event_graph::protocol::handle_range_req()",
+                "Got RangeReq: {} ranges [{}]", ranges.len(), self.channel.address(),
+            );
+
+            // Check if node has finished syncing its DAG
+            if !*self.event_graph.synced.read().await {
+                debug!(
+                    target: "event_graph::protocol::handle_range_req()",
+                    "DAG is still syncing, skipping..."
+                );
+                continue
+            }
+
+            if ranges.len() > MAX_RANGES || filter.topic_count() > Some(MAX_FILTER_TOPICS) {
+                warn!(
+                    target: "event_graph::protocol::handle_range_req()",
+                    "[EVENTGRAPH] Peer {} sent too many ranges or topics",
+                    self.channel.address(),
+                );
+                self.clone().increase_malicious_count().await?;
+                continue
+            }
+
+            let index = self.event_graph.sync_index.read().await;
+            let reply = reconcile::respond(&index, &filter, &ranges);
+            drop(index);
+            self.channel.send(&RangeRep(reply)).await?;
+        }
+    }
+
+    /// Protocol function handling `EventBulkReq`.
+    /// This is triggered after a peer reconciled its DAG with ours and
+    /// requests the events it found missing. Unlike `EventReq`, any event
+    /// in our DAG can be requested, bounded by the request size.
+    async fn handle_bulk_req(self: Arc<Self>) -> Result<()> {
+        loop {
+            let event_ids = match self.bulk_req_sub.receive().await {
+                Ok(v) => v.0.clone(),
+                Err(_) => continue,
+            };
+            trace!(
+                target: "event_graph::protocol::handle_bulk_req()",
+                "Got EventBulkReq: {} events [{}]", event_ids.len(), self.channel.address(),
+            );
+
+            // Check if node has finished syncing its DAG
+            if !*self.event_graph.synced.read().await {
+                debug!(
+                    target: "event_graph::protocol::handle_bulk_req()",
+                    "DAG is still syncing, skipping..."
+                );
+                continue
+            }
+
+            if event_ids.len() > MAX_BULK_EVENTS {
+                warn!(
+                    target: "event_graph::protocol::handle_bulk_req()",
+                    "[EVENTGRAPH] Peer {} requested too many events ({})",
+                    self.channel.address(), event_ids.len(),
+                );
+                self.clone().increase_malicious_count().await?;
+                continue
+            }
+
+            let mut events = Vec::with_capacity(event_ids.len());
+            for event_id in event_ids.iter() {
+                if let Some(event) = self.event_graph.dag_get(event_id).await? {
+                    events.push(event);
+                }
+            }
+
+            self.channel.send(&EventBulkRep(events)).await?;
+        }
+    }
+
+    /// Protocol function handling `ArchiveReq`.
+    /// This is triggered whenever someone asks us for the history of
+    /// past DAG rotations. Requests are ignored if we're not running
+    /// in archive mode.
+    async fn handle_archive_req(self: Arc<Self>) -> Result<()> {
+        loop {
+            let req = match self.archive_req_sub.receive().await {
+                Ok(v) => v,
+                Err(_) => continue,
+            };
+            trace!(
+                target: "event_graph::protocol::handle_archive_req()",
+                "Got ArchiveReq: [{}, {}) [{}]", req.start, req.end, self.channel.address(),
+            );
+
+            let Some(archive) = &self.event_graph.archive else {
+                debug!(
+                    target: "event_graph::protocol::handle_archive_req()",
+                    "Not running in archive mode, skipping..."
+                );
+                continue
+            };
+
+            let events =
+                archive.get_range(req.start, req.end, req.after, MAX_ARCHIVE_EVENTS).await?;
+            self.channel.send(&ArchiveRep(events)).await?;
+        }
+    }
+
+    /// Protocol function handling `TopicSub`.
+    /// This is triggered whenever the peer tells us which topics it
+    /// follows, so we only relay it events of these topics.
+    async fn handle_topic_sub(self: Arc<Self>) -> Result<()> {
+        loop {
+            let filter = match self.topic_sub_sub.receive().await {
+                Ok(v) => v.0.clone(),
+                Err(_) => continue,
+            };
+            trace!(
+                target: "event_graph::protocol::handle_topic_sub()",
+                "Got TopicSub: {:?} topics [{}]", filter.topic_count(), self.channel.address(),
+            );
+
+            if filter.topic_count() > Some(MAX_FILTER_TOPICS) {
+                warn!(
+                    target: "event_graph::protocol::handle_topic_sub()",
+                    "[EVENTGRAPH] Peer {} follows too many topics", self.channel.address(),
+                );
+                self.clone().increase_malicious_count().await?;
+                continue
+            }
+
+            self.event_graph.set_peer_topics(self.channel.address(), filter).await;
+        }
+    }
 }
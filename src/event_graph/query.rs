@@ -0,0 +1,218 @@
+/* This file is part of DarkFi (https://dark.fi)
+ *
+ * Copyright (C) 2020-2024 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Time-ordered index over the DAG.
+//!
+//! The DAG tree is keyed by event ID, so answering "what happened between
+//! these two times" would mean scanning and sorting the whole DAG. The
+//! [`TimestampIndex`] keeps a second sled tree keyed by
+//! `timestamp || event_id`, which allows range queries and cursor-based
+//! pagination in both directions.
+
+use std::ops::Bound;
+
+use sled_overlay::sled;
+
+use super::{util::timestamp_key, Event};
+use crate::Result;
+
+/// Maximum number of events returned by a single `eventgraph.query` call
+pub const MAX_QUERY_EVENTS: usize = 1000;
+
+/// Index of the DAG events ordered by `(timestamp, id)`
+pub struct TimestampIndex {
+    /// Sled tree holding the index keys
+    tree: sled::Tree,
+}
+
+impl TimestampIndex {
+    /// Open the timestamp index belonging to the given DAG tree
+    pub fn new(sled_db: &sled::Db, dag_tree_name: &str) -> Result<Self> {
+        let tree = sled_db.open_tree(format!("{}_ts_index", dag_tree_name))?;
+        Ok(Self { tree })
+    }
+
+    /// Atomically add the given events to the index
+    pub fn insert(&self, events: &[Event]) -> Result<()> {
+        let mut batch = sled::Batch::default();
+        for event in events {
+            let key = timestamp_key(event.timestamp, event.id().as_bytes());
+            batch.insert(&key[..], sled::IVec::default());
+        }
+
+        self.tree.apply_batch(batch)?;
+        Ok(())
+    }
+
+    /// Atomically clear the index, leaving only the given events in it
+    pub fn reset(&self, events: &[Event]) -> Result<()> {
+        let mut batch = sled::Batch::default();
+        for key in self.tree.iter().keys() {
+            batch.remove(key?);
+        }
+        for event in events {
+            let key = timestamp_key(event.timestamp, event.id().as_bytes());
+            batch.insert(&key[..], sled::IVec::default());
+        }
+
+        self.tree.apply_batch(batch)?;
+        Ok(())
+    }
+
+    /// Return the IDs of at most `limit` events with timestamps in
+    /// `[start, end)`, oldest first. If `after` is given, only events
+    /// ordered after the event with that ID and `start` timestamp are
+    /// returned, which is used to continue a truncated range.
+    pub fn between(
+        &self,
+        start: u64,
+        end: u64,
+        after: Option<&blake3::Hash>,
+        limit: usize,
+    ) -> Result<Vec<blake3::Hash>> {
+        if start >= end {
+            return Ok(vec![])
+        }
+
+        let lower = match after {
+            Some(id) => Bound::Excluded(timestamp_key(start, id.as_bytes())),
+            None => Bound::Included(timestamp_key(start, &[0u8; 32])),
+        };
+        let upper = Bound::Excluded(timestamp_key(end, &[0u8; 32]));
+        self.collect(self.tree.range::<[u8; 40], _>((lower, upper)).take(limit))
+    }
+
+    /// Return the IDs of at most `limit` events ordered right after the
+    /// event with the given timestamp and ID, oldest first.
+    pub fn after(
+        &self,
+        timestamp: u64,
+        event_id: &blake3::Hash,
+        limit: usize,
+    ) -> Result<Vec<blake3::Hash>> {
+        let lower = Bound::Excluded(timestamp_key(timestamp, event_id.as_bytes()));
+        self.collect(self.tree.range::<[u8; 40], _>((lower, Bound::Unbounded)).take(limit))
+    }
+
+    /// Return the IDs of at most `limit` events ordered right before the
+    /// event with the given timestamp and ID, or the latest events if no
+    /// cursor is given. The result is ordered oldest first.
+    pub fn before(
+        &self,
+        cursor: Option<(u64, &blake3::Hash)>,
+        limit: usize,
+    ) -> Result<Vec<blake3::Hash>> {
+        let upper = match cursor {
+            Some((timestamp, event_id)) => {
+                Bound::Excluded(timestamp_key(timestamp, event_id.as_bytes()))
+            }
+            None => Bound::Unbounded,
+        };
+
+        let iter = self.tree.range::<[u8; 40], _>((Bound::Unbounded, upper)).rev().take(limit);
+        let mut ids = self.collect(iter)?;
+        ids.reverse();
+        Ok(ids)
+    }
+
+    /// Extract the event IDs out of the given index entries
+    fn collect(
+        &self,
+        iter: impl Iterator<Item = sled::Result<(sled::IVec, sled::IVec)>>,
+    ) -> Result<Vec<blake3::Hash>> {
+        let mut ids = vec![];
+        for item in iter {
+            let (key, _) = item?;
+            let id: [u8; 32] = key[8..].try_into().unwrap();
+            ids.push(blake3::Hash::from_bytes(id));
+        }
+
+        Ok(ids)
+    }
+
+    /// Return the number of indexed events
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    /// Check if the index is empty
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event_graph::{NULL_ID, N_EVENT_PARENTS};
+
+    fn event(timestamp: u64, n: u8) -> Event {
+        Event {
+            timestamp,
+            content: vec![n],
+            parents: [NULL_ID; N_EVENT_PARENTS],
+            layer: 1,
+            author: None,
+            rln: None,
+            topic: None,
+        }
+    }
+
+    #[test]
+    fn timestamp_index_queries() -> Result<()> {
+        let sled_db = sled::Config::new().temporary(true).open()?;
+        let index = TimestampIndex::new(&sled_db, "dag")?;
+        assert!(index.is_empty());
+
+        // Two events per timestamp, inserted out of order
+        let mut events: Vec<Event> =
+            (0..10).map(|n| event(1000 + (n as u64 / 2) * 10, n)).collect();
+        index.insert(&events[5..])?;
+        index.insert(&events[..5])?;
+        assert_eq!(index.len(), 10);
+
+        // Sort the events the way the index does
+        events.sort_by_key(|e| timestamp_key(e.timestamp, e.id().as_bytes()));
+        let ids: Vec<blake3::Hash> = events.iter().map(|e| e.id()).collect();
+
+        // Ranges have an exclusive end
+        assert_eq!(index.between(1010, 1030, None, 10)?, ids[2..6]);
+        assert_eq!(index.between(1010, 1030, None, 3)?, ids[2..5]);
+        assert!(index.between(1030, 1010, None, 10)?.is_empty());
+        assert!(index.between(2000, 3000, None, 10)?.is_empty());
+
+        // Truncated ranges continue after their last event
+        assert_eq!(index.between(events[4].timestamp, 1030, Some(&ids[4]), 10)?, ids[5..6]);
+
+        // Forward pagination
+        assert_eq!(index.after(events[3].timestamp, &ids[3], 4)?, ids[4..8]);
+        assert_eq!(index.after(events[8].timestamp, &ids[8], 4)?, ids[9..]);
+        assert!(index.after(events[9].timestamp, &ids[9], 4)?.is_empty());
+
+        // Backward pagination
+        assert_eq!(index.before(None, 3)?, ids[7..]);
+        assert_eq!(index.before(Some((events[7].timestamp, &ids[7])), 3)?, ids[4..7]);
+        assert_eq!(index.before(Some((events[2].timestamp, &ids[2])), 3)?, ids[..2]);
+
+        // Resetting leaves only the given events
+        index.reset(&events[..1])?;
+        assert_eq!(index.before(None, 10)?, ids[..1]);
+
+        Ok(())
+    }
+}
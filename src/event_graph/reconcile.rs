@@ -0,0 +1,332 @@
+/* This file is part of DarkFi (https://dark.fi)
+ *
+ * Copyright (C) 2020-2024 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Range-based set reconciliation of the DAG.
+//!
+//! Both peers keep their event IDs sorted by `(layer, id)`. The syncing
+//! node sends the fingerprint of a key range, and the peer compares it
+//! against the fingerprint of the same range on its side. Matching ranges
+//! are done. Small mismatching ranges are answered with the peer's keys,
+//! and big ones are split into [`SPLIT_FACTOR`] subranges whose
+//! fingerprints are sent back, so the syncing node can recurse into the
+//! ones that differ. This converges in a logarithmic number of round
+//! trips, after which the missing events are transferred in bulk.
+//!
+//! Both sides only consider the events of the topics the syncing node
+//! follows, so it never learns about events it doesn't care about.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ops::Bound,
+};
+
+use darkfi_serial::{async_trait, SerialDecodable, SerialEncodable};
+
+use super::topic::TopicFilter;
+
+/// Name of the feature advertised by peers supporting reconciliation
+pub const RECONCILE_FEATURE: &str = "event_graph/reconcile";
+
+/// Version of the reconciliation feature
+pub const RECONCILE_VERSION: u32 = 2;
+
+/// Ranges with at most this many keys are answered with the keys
+pub const MAX_RANGE_KEYS: usize = 64;
+
+/// Number of subranges a mismatching range is split into
+pub const SPLIT_FACTOR: usize = 16;
+
+/// Maximum number of ranges sent in a single message
+pub const MAX_RANGES: usize = 256;
+
+/// Maximum number of round trips before giving up on a peer
+pub const MAX_ROUNDS: usize = 16;
+
+/// Maximum number of events requested in a single bulk request
+pub const MAX_BULK_EVENTS: usize = 256;
+
+/// Key of an event in the reconciliation order
+pub type SyncKey = (u64, [u8; 32]);
+
+/// Reconciliation keys of the events in the DAG, mapped to their topic
+pub type SyncIndex = BTreeMap<SyncKey, Option<blake3::Hash>>;
+
+/// Fingerprint of the keys contained in a range
+#[derive(Clone, Debug, PartialEq, Eq, SerialEncodable, SerialDecodable)]
+pub struct Fingerprint {
+    /// Number of keys in the range
+    pub count: u64,
+    /// Hash over the XOR of the event IDs and the count
+    pub hash: blake3::Hash,
+}
+
+/// Contents of a [`SyncRange`]
+#[derive(Clone, Debug, PartialEq, SerialEncodable, SerialDecodable)]
+pub enum RangeItems {
+    /// Fingerprint of the sender's keys in the range
+    Fingerprint(Fingerprint),
+    /// All of the sender's keys in the range
+    Keys(Vec<SyncKey>),
+}
+
+/// A key range `[lower, upper)` along with its contents on the sender's side.
+/// An `upper` bound of `None` means the range is unbounded.
+#[derive(Clone, Debug, PartialEq, SerialEncodable, SerialDecodable)]
+pub struct SyncRange {
+    /// Inclusive lower bound
+    pub lower: SyncKey,
+    /// Exclusive upper bound
+    pub upper: Option<SyncKey>,
+    /// Sender's contents of the range
+    pub items: RangeItems,
+}
+
+/// Iterate over the keys of the given index within `[lower, upper)`
+/// whose topic matches the filter. Inverted ranges are empty.
+fn range_keys<'a>(
+    index: &'a SyncIndex,
+    filter: &'a TopicFilter,
+    lower: &SyncKey,
+    upper: &Option<SyncKey>,
+) -> impl Iterator<Item = &'a SyncKey> {
+    let upper = match upper {
+        Some(upper) if upper < lower => Bound::Excluded(*lower),
+        Some(upper) => Bound::Excluded(*upper),
+        None => Bound::Unbounded,
+    };
+    index
+        .range((Bound::Included(*lower), upper))
+        .filter(|(_, topic)| filter.matches(topic))
+        .map(|(key, _)| key)
+}
+
+/// Compute the fingerprint of the given keys
+fn fingerprint<'a>(keys: impl Iterator<Item = &'a SyncKey>) -> Fingerprint {
+    let mut acc = [0u8; 32];
+    let mut count: u64 = 0;
+    for (_, id) in keys {
+        for (a, b) in acc.iter_mut().zip(id.iter()) {
+            *a ^= b;
+        }
+        count += 1;
+    }
+
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&acc);
+    hasher.update(&count.to_le_bytes());
+    Fingerprint { count, hash: hasher.finalize() }
+}
+
+/// Create the initial range covering the whole set
+pub fn initial_range(index: &SyncIndex, filter: &TopicFilter) -> SyncRange {
+    let lower = (0, [0u8; 32]);
+    SyncRange {
+        lower,
+        upper: None,
+        items: RangeItems::Fingerprint(fingerprint(range_keys(index, filter, &lower, &None))),
+    }
+}
+
+/// Answer the ranges a syncing peer sent us. Ranges with a matching
+/// fingerprint are dropped, the rest are answered with our keys or
+/// split into subranges carrying our fingerprints.
+pub fn respond(index: &SyncIndex, filter: &TopicFilter, ranges: &[SyncRange]) -> Vec<SyncRange> {
+    let mut reply = vec![];
+
+    for range in ranges {
+        let keys: Vec<&SyncKey> = range_keys(index, filter, &range.lower, &range.upper).collect();
+
+        if let RangeItems::Fingerprint(theirs) = &range.items {
+            if &fingerprint(keys.iter().copied()) == theirs {
+                continue
+            }
+        }
+
+        if keys.len() <= MAX_RANGE_KEYS {
+            reply.push(SyncRange {
+                lower: range.lower,
+                upper: range.upper,
+                items: RangeItems::Keys(keys.into_iter().copied().collect()),
+            });
+            continue
+        }
+
+        // Split the range on our own keys, so each subrange holds
+        // about the same amount of them.
+        let chunk_size = keys.len().div_ceil(SPLIT_FACTOR);
+        for (i, chunk) in keys.chunks(chunk_size).enumerate() {
+            let lower = if i == 0 { range.lower } else { *chunk[0] };
+            let upper = match keys.get((i + 1) * chunk_size) {
+                Some(next) => Some(**next),
+                None => range.upper,
+            };
+
+            reply.push(SyncRange {
+                lower,
+                upper,
+                items: RangeItems::Fingerprint(fingerprint(chunk.iter().copied())),
+            });
+        }
+    }
+
+    reply
+}
+
+/// Process a peer's answer to our ranges. Keys the peer has and we don't
+/// are added to `missing`, and ranges that still differ are returned so
+/// they can be sent in the next round.
+pub fn process(
+    index: &SyncIndex,
+    filter: &TopicFilter,
+    reply: &[SyncRange],
+    missing: &mut BTreeSet<SyncKey>,
+) -> Vec<SyncRange> {
+    let mut next = vec![];
+
+    for range in reply {
+        match &range.items {
+            RangeItems::Keys(keys) => {
+                for key in keys {
+                    if !index.contains_key(key) {
+                        missing.insert(*key);
+                    }
+                }
+            }
+
+            RangeItems::Fingerprint(theirs) => {
+                let ours = fingerprint(range_keys(index, filter, &range.lower, &range.upper));
+                if &ours != theirs {
+                    next.push(SyncRange {
+                        lower: range.lower,
+                        upper: range.upper,
+                        items: RangeItems::Fingerprint(ours),
+                    });
+                }
+            }
+        }
+    }
+
+    next
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(layer: u64, n: u64) -> SyncKey {
+        (layer, *blake3::hash(&n.to_le_bytes()).as_bytes())
+    }
+
+    fn index(keys: impl IntoIterator<Item = SyncKey>) -> SyncIndex {
+        keys.into_iter().map(|key| (key, None)).collect()
+    }
+
+    /// Run the reconciliation between two indexes and return the keys
+    /// `ours` is missing along with the number of round trips.
+    fn reconcile(
+        ours: &SyncIndex,
+        theirs: &SyncIndex,
+        filter: &TopicFilter,
+    ) -> (BTreeSet<SyncKey>, usize) {
+        let mut missing = BTreeSet::new();
+        let mut ranges = vec![initial_range(ours, filter)];
+        let mut rounds = 0;
+
+        while !ranges.is_empty() {
+            let reply = respond(theirs, filter, &ranges);
+            ranges = process(ours, filter, &reply, &mut missing);
+            rounds += 1;
+        }
+
+        (missing, rounds)
+    }
+
+    #[test]
+    fn reconcile_sets() {
+        let all = TopicFilter::all();
+        let common = index((0..10_000).map(|n| key(n / 10, n)));
+
+        // Identical sets reconcile in a single round trip
+        let (missing, rounds) = reconcile(&common, &common, &all);
+        assert!(missing.is_empty());
+        assert_eq!(rounds, 1);
+
+        // We're missing a contiguous tail and a few scattered events,
+        // and the peer is missing some of ours.
+        let mut ours = common.clone();
+        let mut theirs = common.clone();
+        let mut expected = BTreeSet::new();
+        for n in 10_000..10_500 {
+            theirs.insert(key(n / 10, n), None);
+            expected.insert(key(n / 10, n));
+        }
+        for n in [3, 1234, 5678, 9999] {
+            ours.remove(&key(n / 10, n));
+            expected.insert(key(n / 10, n));
+        }
+        for n in 20_000..20_010 {
+            ours.insert(key(n / 10, n), None);
+        }
+
+        let (missing, rounds) = reconcile(&ours, &theirs, &all);
+        assert_eq!(missing, expected);
+        assert!(rounds <= 5);
+
+        // Syncing from scratch just asks for everything
+        let (missing, rounds) = reconcile(&SyncIndex::new(), &theirs, &all);
+        assert_eq!(missing, theirs.keys().copied().collect());
+        assert!(rounds <= 5);
+    }
+
+    #[test]
+    fn reconcile_topics() {
+        let dev = blake3::hash(b"#dev");
+        let random = blake3::hash(b"#random");
+
+        // The peer holds events of the global topic and of two others
+        let mut theirs = SyncIndex::new();
+        for n in 0..3_000 {
+            let topic = match n % 3 {
+                0 => None,
+                1 => Some(dev),
+                _ => Some(random),
+            };
+            theirs.insert(key(n / 10, n), topic);
+        }
+
+        // Following a single topic only yields its events and the global ones
+        let filter = TopicFilter::only([dev]);
+        let (missing, _) = reconcile(&SyncIndex::new(), &theirs, &filter);
+        let expected: BTreeSet<SyncKey> = theirs
+            .iter()
+            .filter(|(_, topic)| topic.is_none() || topic == &&Some(dev))
+            .map(|(key, _)| *key)
+            .collect();
+        assert_eq!(missing, expected);
+
+        // Events of topics we don't follow don't make the sets differ
+        let ours: SyncIndex = theirs
+            .iter()
+            .filter(|(_, topic)| topic != &&Some(random))
+            .map(|(k, t)| (*k, *t))
+            .collect();
+        let (missing, rounds) = reconcile(&ours, &theirs, &filter);
+        assert!(missing.is_empty());
+        assert_eq!(rounds, 1);
+    }
+}
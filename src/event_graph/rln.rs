@@ -0,0 +1,680 @@
+/* This file is part of DarkFi (https://dark.fi)
+ *
+ * Copyright (C) 2020-2024 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Rate-Limit-Nullifiers for the Event Graph
+//!
+//! <https://darkrenaissance.github.io/darkfi/crypto/rln.html>
+//!
+//! Every event carries an [`EventRln`] signal proving that its author is
+//! a member of the RLN membership tree, together with a nullifier unique
+//! to the author and the epoch, and a secret share of the author's key.
+//! A member may publish a single event per epoch. Publishing a second one
+//! reveals a second share, which lets anyone recover the secret key. The
+//! recovered secret is banned and the evidence is broadcasted to the
+//! network as an [`RlnSlash`] message, so every node can ban it as well.
+//!
+//! Membership is registered with [`Rln::register`], which appends an
+//! identity commitment to the local membership tree, or with
+//! [`Rln::add_root`], which accepts a root maintained elsewhere, e.g.
+//! by an on-chain registry.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{
+        atomic::{AtomicUsize, Ordering::SeqCst},
+        Arc,
+    },
+    time::{Duration, UNIX_EPOCH},
+};
+
+use darkfi_sdk::{
+    bridgetree::Position,
+    crypto::{pasta_prelude::*, poseidon_hash, util::hash_to_base, MerkleNode, MerkleTree},
+    pasta::pallas,
+};
+use darkfi_serial::{
+    async_trait, deserialize_async, serialize_async, SerialDecodable, SerialEncodable,
+};
+use log::{debug, error, info, warn};
+use rand::rngs::OsRng;
+use sled_overlay::sled;
+use smol::{
+    lock::{Mutex, RwLock},
+    Executor, Timer,
+};
+
+use super::{Event, EventRln, EventValidator};
+use crate::{
+    impl_p2p_message,
+    net::*,
+    zk::{empty_witnesses, halo2::Value, Proof, ProvingKey, VerifyingKey, Witness, ZkCircuit},
+    zkas::{Analyzer, Compiler, Lexer, Parser, ZkBinary},
+    Error, Result,
+};
+
+/// RLN application identifier used in the external nullifier
+const RLN_IDENTIFIER: pallas::Base = pallas::Base::from_raw([0, 0, 42, 42]);
+/// Derivation path of identity commitments, has to match the circuit
+const IDENTITY_DERIVATION_PATH: u64 = 11;
+/// Derivation path of internal nullifiers, has to match the circuit
+const NULLIFIER_DERIVATION_PATH: u64 = 12;
+
+/// Personalization used to hash an event ID into the signalled message
+const RLN_MESSAGE_PERSONALIZATION: &[u8] = b"DarkFi:RlnEvent";
+
+/// Source code of the RLN signalling circuit
+const RLN_SIGNAL_ZK: &str = include_str!("proof/rln_signal.zk");
+
+/// Malicious behaviour threshold for peers sending bogus slashing evidence
+const MALICIOUS_THRESHOLD: usize = 5;
+
+/// Atomic pointer to an [`Rln`] instance
+pub type RlnPtr = Arc<Rln>;
+
+/// A single RLN signal, used as evidence in an [`RlnSlash`]
+#[derive(Debug, Clone, PartialEq, SerialEncodable, SerialDecodable)]
+pub struct RlnShare {
+    /// Message hash (`x`) the signal was made for
+    pub message_hash: pallas::Base,
+    /// Membership Merkle root the proof was made against
+    pub root: MerkleNode,
+    /// Secret share (`y`) of the signalling identity
+    pub y: pallas::Base,
+    /// Serialized ZK proof of the signal
+    pub proof: Vec<u8>,
+}
+
+/// A P2P message carrying the evidence of a double-signal. Both shares
+/// are valid signals for the same epoch and nullifier, so together they
+/// reveal the secret key of the offending identity.
+#[derive(Debug, Clone, SerialEncodable, SerialDecodable)]
+pub struct RlnSlash {
+    /// Epoch the identity double-signalled in
+    pub epoch: u64,
+    /// Internal nullifier shared by both signals
+    pub internal_nullifier: pallas::Base,
+    /// First signal seen
+    pub first: RlnShare,
+    /// Conflicting signal
+    pub second: RlnShare,
+}
+impl_p2p_message!(RlnSlash, "EventGraph::RlnSlash");
+
+/// RLN membership state
+struct RlnMembership {
+    /// Membership Merkle tree of identity commitments
+    tree: MerkleTree,
+    /// Leaf positions of the registered identity commitments
+    leaves: BTreeMap<MerkleNode, Position>,
+    /// Accepted membership roots
+    roots: BTreeSet<MerkleNode>,
+}
+
+/// Rate-Limit-Nullifiers
+///
+/// Spam protection for the Event Graph. Used as an [`EventValidator`],
+/// it rejects events without a valid RLN signal and slashes identities
+/// signalling more than once in an epoch.
+pub struct Rln {
+    /// Epoch length in milliseconds
+    epoch_length: u64,
+    /// Membership tree and accepted roots
+    membership: RwLock<RlnMembership>,
+    /// DB holding seen shares.
+    /// The scheme is `(k=epoch||internal_nullifier, v=RlnShare)`
+    shares: sled::Tree,
+    /// DB holding banned identities.
+    /// The scheme is `(k=identity_commitment, v=secret_key)`
+    banned: sled::Tree,
+    /// DB holding the last epoch our own identities signalled in, so a
+    /// restart within an epoch doesn't make us slash ourselves.
+    /// The scheme is `(k=identity_commitment, v=epoch)`
+    signalled: sled::Tree,
+    /// Lock serializing our own signals
+    signal_lock: Mutex<()>,
+    /// Compiled signalling circuit
+    signal_zkbin: ZkBinary,
+    /// Proving key for the signalling circuit
+    signal_pk: ProvingKey,
+    /// Verifying key for the signalling circuit
+    signal_vk: VerifyingKey,
+    /// P2P network pointer, used to broadcast slashing evidence
+    p2p: P2pPtr,
+}
+
+impl Rln {
+    /// Create a new Rln instance using the given epoch length in milliseconds.
+    /// The signalling circuit is compiled and its keys are built here, which
+    /// takes a while.
+    pub async fn new(sled_db: &sled::Db, epoch_length: u64, p2p: P2pPtr) -> Result<RlnPtr> {
+        assert!(epoch_length > 0);
+        let shares = sled_db.open_tree("rln_shares")?;
+        let banned = sled_db.open_tree("rln_banned")?;
+        let signalled = sled_db.open_tree("rln_signalled")?;
+
+        info!(target: "event_graph::rln", "Compiling RLN signal circuit");
+        let signal_zkbin = Self::compile_circuit("rln_signal.zk", RLN_SIGNAL_ZK)?;
+        let signal_circuit = ZkCircuit::new(empty_witnesses(&signal_zkbin)?, &signal_zkbin);
+        info!(target: "event_graph::rln", "Building RLN signal proving key");
+        let signal_pk = ProvingKey::build(signal_zkbin.k, &signal_circuit);
+        info!(target: "event_graph::rln", "Building RLN signal verifying key");
+        let signal_vk = VerifyingKey::build(signal_zkbin.k, &signal_circuit);
+
+        let membership = RlnMembership {
+            tree: MerkleTree::new(1),
+            leaves: BTreeMap::new(),
+            roots: BTreeSet::new(),
+        };
+
+        Ok(Arc::new(Self {
+            epoch_length,
+            membership: RwLock::new(membership),
+            shares,
+            banned,
+            signalled,
+            signal_lock: Mutex::new(()),
+            signal_zkbin,
+            signal_pk,
+            signal_vk,
+            p2p,
+        }))
+    }
+
+    /// Compile zkas source code into a [`ZkBinary`]
+    fn compile_circuit(filename: &str, source: &str) -> Result<ZkBinary> {
+        let source = source.replace('\t', "    ").replace("\r\n", "\n");
+        let tokens = Lexer::new(filename, source.chars()).lex()?;
+        let (namespace, k, constants, witnesses, statements) =
+            Parser::new(filename, source.chars(), tokens).parse()?;
+        let mut analyzer =
+            Analyzer::new(filename, source.chars(), constants, witnesses, statements);
+        analyzer.analyze_types()?;
+
+        let bincode = Compiler::new(
+            filename,
+            source.chars(),
+            namespace,
+            k,
+            analyzer.constants,
+            analyzer.witnesses,
+            analyzer.statements,
+            analyzer.literals,
+            false,
+        )
+        .compile()?;
+
+        ZkBinary::decode(&bincode)
+    }
+
+    /// Derive the identity commitment of the given secret key.
+    /// This is the value that has to be registered in the membership tree.
+    pub fn identity_commitment(secret_key: pallas::Base) -> pallas::Base {
+        poseidon_hash([pallas::Base::from(IDENTITY_DERIVATION_PATH), secret_key])
+    }
+
+    /// Register an identity commitment in the local membership tree.
+    /// Every node has to register the same commitments in the same order
+    /// to end up with the same root.
+    pub async fn register(&self, identity_commitment: pallas::Base) {
+        let leaf = MerkleNode::from(identity_commitment);
+        let mut membership = self.membership.write().await;
+        if membership.leaves.contains_key(&leaf) {
+            return
+        }
+
+        membership.tree.append(leaf);
+        let leaf_pos = membership.tree.mark().unwrap();
+        membership.leaves.insert(leaf, leaf_pos);
+        // Older roots stay valid so in-flight signals aren't rejected
+        let root = membership.tree.root(0).unwrap();
+        membership.roots.insert(root);
+    }
+
+    /// Accept a membership root maintained outside of this node,
+    /// e.g. by an on-chain registry. Signals made against it can be
+    /// verified, but only locally registered identities can signal.
+    pub async fn add_root(&self, root: MerkleNode) {
+        self.membership.write().await.roots.insert(root);
+    }
+
+    /// Return the current root of the local membership tree
+    pub async fn root(&self) -> Option<MerkleNode> {
+        let membership = self.membership.read().await;
+        if membership.leaves.is_empty() {
+            return None
+        }
+        membership.tree.root(0)
+    }
+
+    /// Compute the epoch a timestamp in milliseconds belongs to
+    pub fn epoch(&self, timestamp: u64) -> u64 {
+        timestamp / self.epoch_length
+    }
+
+    /// Hash an event ID into the message signalled by its RLN proof
+    fn message_hash(event: &Event) -> pallas::Base {
+        hash_to_base(RLN_MESSAGE_PERSONALIZATION, &[event.id().as_bytes()])
+    }
+
+    /// Derive the internal nullifier and the `a_1` coefficient of a
+    /// secret key for the given epoch
+    fn nullifier(secret_key: pallas::Base, epoch: u64) -> (pallas::Base, pallas::Base) {
+        let external_nullifier = poseidon_hash([pallas::Base::from(epoch), RLN_IDENTIFIER]);
+        let a_1 = poseidon_hash([secret_key, external_nullifier]);
+        (poseidon_hash([pallas::Base::from(NULLIFIER_DERIVATION_PATH), a_1]), a_1)
+    }
+
+    /// Attach an RLN signal to the given event, using the given secret key
+    /// registered in the local membership tree. If this identity already
+    /// signalled in the event's epoch, we wait for the next epoch and move
+    /// the event timestamp there, so we never slash ourselves.
+    /// The signal has to be created after any changes to the event ID.
+    pub async fn signal(&self, event: &mut Event, secret_key: pallas::Base) -> Result<()> {
+        let leaf = MerkleNode::from(Self::identity_commitment(secret_key));
+
+        let (leaf_pos, path, root) = {
+            let membership = self.membership.read().await;
+            let Some(leaf_pos) = membership.leaves.get(&leaf) else {
+                return Err(Error::RlnSignalInvalid("identity is not a registered member"))
+            };
+            let path = membership.tree.witness(*leaf_pos, 0).unwrap();
+            (*leaf_pos, path, membership.tree.root(0).unwrap())
+        };
+
+        let _signal_lock = self.signal_lock.lock().await;
+        let mut epoch = self.epoch(event.timestamp);
+        if let Some(last_epoch) = self.signalled.get(leaf.inner().to_repr())? {
+            let last_epoch: u64 = deserialize_async(&last_epoch).await?;
+            if epoch <= last_epoch {
+                let next_epoch = (last_epoch + 1) * self.epoch_length;
+                let wait =
+                    next_epoch.saturating_sub(UNIX_EPOCH.elapsed().unwrap().as_millis() as u64);
+                debug!(
+                    target: "event_graph::rln::signal()",
+                    "Already signalled in epoch {}, waiting {}ms", last_epoch, wait,
+                );
+                Timer::after(Duration::from_millis(wait)).await;
+                event.timestamp = next_epoch.max(UNIX_EPOCH.elapsed().unwrap().as_millis() as u64);
+                epoch = self.epoch(event.timestamp);
+            }
+        }
+
+        let message_hash = Self::message_hash(event);
+        let (internal_nullifier, a_1) = Self::nullifier(secret_key, epoch);
+        let y = a_1 * message_hash + secret_key;
+
+        let witnesses = vec![
+            Witness::Base(Value::known(secret_key)),
+            Witness::MerklePath(Value::known(path.try_into().unwrap())),
+            Witness::Uint32(Value::known(u64::from(leaf_pos).try_into().unwrap())),
+            Witness::Base(Value::known(message_hash)),
+            Witness::Base(Value::known(pallas::Base::from(epoch))),
+            Witness::Base(Value::known(RLN_IDENTIFIER)),
+        ];
+        let public_inputs = vec![
+            pallas::Base::from(epoch),
+            RLN_IDENTIFIER,
+            message_hash,
+            root.inner(),
+            internal_nullifier,
+            y,
+        ];
+
+        let circuit = ZkCircuit::new(witnesses, &self.signal_zkbin);
+        let proof = Proof::create(&self.signal_pk, &[circuit], &public_inputs, &mut OsRng)?;
+
+        event.rln =
+            Some(EventRln { epoch, root, internal_nullifier, y, proof: proof.as_ref().to_vec() });
+        self.signalled.insert(leaf.inner().to_repr(), serialize_async(&epoch).await)?;
+
+        Ok(())
+    }
+
+    /// Verify a single signal against the accepted membership roots
+    async fn verify_share(
+        &self,
+        epoch: u64,
+        internal_nullifier: pallas::Base,
+        share: &RlnShare,
+    ) -> bool {
+        if !self.membership.read().await.roots.contains(&share.root) {
+            return false
+        }
+
+        let public_inputs = vec![
+            pallas::Base::from(epoch),
+            RLN_IDENTIFIER,
+            share.message_hash,
+            share.root.inner(),
+            internal_nullifier,
+            share.y,
+        ];
+
+        Proof::new(share.proof.clone()).verify(&self.signal_vk, &public_inputs).is_ok()
+    }
+
+    /// Check if the identity behind a nullifier has been banned
+    fn is_banned(&self, epoch: u64, internal_nullifier: pallas::Base) -> Result<bool> {
+        for record in self.banned.iter() {
+            let (_, secret_key) = record?;
+            let secret_key = pallas::Base::from_repr(secret_key.as_ref().try_into().unwrap());
+            if Self::nullifier(secret_key.unwrap(), epoch).0 == internal_nullifier {
+                return Ok(true)
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Ban the identity of the given secret key.
+    /// Returns `false` if it was already banned.
+    fn ban(&self, secret_key: pallas::Base) -> Result<bool> {
+        let identity_commitment = Self::identity_commitment(secret_key);
+        let previous =
+            self.banned.insert(identity_commitment.to_repr(), &secret_key.to_repr()[..])?;
+        if previous.is_none() {
+            warn!(
+                target: "event_graph::rln",
+                "Banned RLN identity {:?}", identity_commitment,
+            );
+        }
+        Ok(previous.is_none())
+    }
+
+    /// Verify the RLN signal of an event and record its share.
+    /// Returns the slashing evidence if the event's author double-signalled,
+    /// in which case the author has already been banned.
+    pub async fn verify_event(&self, event: &Event) -> Result<Option<RlnSlash>> {
+        let Some(rln) = &event.rln else {
+            return Err(Error::RlnSignalInvalid("event has no RLN signal"))
+        };
+
+        if rln.epoch != self.epoch(event.timestamp) {
+            return Err(Error::RlnSignalInvalid("epoch does not match event timestamp"))
+        }
+
+        if self.is_banned(rln.epoch, rln.internal_nullifier)? {
+            return Err(Error::RlnSignalInvalid("identity is banned"))
+        }
+
+        let share = RlnShare {
+            message_hash: Self::message_hash(event),
+            root: rln.root,
+            y: rln.y,
+            proof: rln.proof.clone(),
+        };
+
+        if !self.verify_share(rln.epoch, rln.internal_nullifier, &share).await {
+            return Err(Error::RlnSignalInvalid("proof verification failed"))
+        }
+
+        let mut key = rln.epoch.to_be_bytes().to_vec();
+        key.extend_from_slice(&rln.internal_nullifier.to_repr());
+
+        let Some(first) = self.shares.get(&key)? else {
+            self.shares.insert(key, serialize_async(&share).await)?;
+            return Ok(None)
+        };
+
+        // The same event coming from another peer carries the same share
+        let first: RlnShare = deserialize_async(&first).await?;
+        if first.message_hash == share.message_hash {
+            return Ok(None)
+        }
+
+        let secret_key =
+            sss_recover(&[(first.message_hash, first.y), (share.message_hash, share.y)]);
+        self.ban(secret_key)?;
+
+        Ok(Some(RlnSlash {
+            epoch: rln.epoch,
+            internal_nullifier: rln.internal_nullifier,
+            first,
+            second: share,
+        }))
+    }
+
+    /// Verify slashing evidence received from the network and ban the
+    /// revealed identity. Returns `true` if the identity was not banned
+    /// before, meaning the evidence should be relayed further.
+    pub async fn handle_slash(&self, slash: &RlnSlash) -> Result<bool> {
+        if slash.first.message_hash == slash.second.message_hash {
+            return Err(Error::RlnSignalInvalid("slashing shares are for the same message"))
+        }
+
+        for share in [&slash.first, &slash.second] {
+            if !self.verify_share(slash.epoch, slash.internal_nullifier, share).await {
+                return Err(Error::RlnSignalInvalid("slashing proof verification failed"))
+            }
+        }
+
+        let secret_key = sss_recover(&[
+            (slash.first.message_hash, slash.first.y),
+            (slash.second.message_hash, slash.second.y),
+        ]);
+
+        // Sanity check that the shares really belong to the revealed secret
+        if Self::nullifier(secret_key, slash.epoch).0 != slash.internal_nullifier {
+            return Err(Error::RlnSignalInvalid("slashing shares do not reveal the identity"))
+        }
+
+        self.ban(secret_key)
+    }
+}
+
+#[async_trait]
+impl EventValidator for Rln {
+    async fn validate(&self, event: &Event) -> std::result::Result<(), String> {
+        match self.verify_event(event).await {
+            Ok(None) => Ok(()),
+            Ok(Some(slash)) => {
+                info!(
+                    target: "event_graph::rln",
+                    "Broadcasting RLN slashing evidence for epoch {}", slash.epoch,
+                );
+                self.p2p.broadcast(&slash).await;
+                Err(Error::RlnDoubleSignal(slash.epoch).to_string())
+            }
+            Err(e) => Err(e.to_string()),
+        }
+    }
+}
+
+/// Recover a secret from given secret shares
+pub fn sss_recover(shares: &[(pallas::Base, pallas::Base)]) -> pallas::Base {
+    let mut secret = pallas::Base::zero();
+    for (j, share_j) in shares.iter().enumerate() {
+        let mut prod = pallas::Base::one();
+        for (i, share_i) in shares.iter().enumerate() {
+            if i != j {
+                prod *= share_i.0 * (share_i.0 - share_j.0).invert().unwrap();
+            }
+        }
+
+        prod *= share_j.1;
+        secret += prod;
+    }
+
+    secret
+}
+
+/// P2P protocol relaying RLN slashing evidence
+pub struct ProtocolRln {
+    /// Pointer to the connected peer
+    channel: ChannelPtr,
+    /// Pointer to the Rln instance
+    rln: RlnPtr,
+    /// `MessageSubscriber` for `RlnSlash`
+    slash_sub: MessageSubscription<RlnSlash>,
+    /// Peer malicious message count
+    malicious_count: AtomicUsize,
+    /// P2P jobs manager pointer
+    jobsman: ProtocolJobsManagerPtr,
+}
+
+#[async_trait]
+impl ProtocolBase for ProtocolRln {
+    async fn start(self: Arc<Self>, ex: Arc<Executor<'_>>) -> Result<()> {
+        self.jobsman.clone().start(ex.clone());
+        self.jobsman.clone().spawn(self.clone().handle_slash(), ex.clone()).await;
+        Ok(())
+    }
+
+    fn name(&self) -> &'static str {
+        "ProtocolRln"
+    }
+}
+
+impl ProtocolRln {
+    pub async fn init(rln: RlnPtr, channel: ChannelPtr) -> Result<ProtocolBasePtr> {
+        let msg_subsystem = channel.message_subsystem();
+        msg_subsystem.add_dispatch::<RlnSlash>().await;
+
+        let slash_sub = channel.subscribe_msg::<RlnSlash>().await?;
+
+        Ok(Arc::new(Self {
+            channel: channel.clone(),
+            rln,
+            slash_sub,
+            malicious_count: AtomicUsize::new(0),
+            jobsman: ProtocolJobsManager::new("ProtocolRln", channel.clone()),
+        }))
+    }
+
+    /// Protocol function handling `RlnSlash`.
+    /// Valid evidence for an identity we haven't banned yet is relayed
+    /// to the rest of the network.
+    async fn handle_slash(self: Arc<Self>) -> Result<()> {
+        loop {
+            let slash = match self.slash_sub.receive().await {
+                Ok(v) => v,
+                Err(_) => continue,
+            };
+
+            match self.rln.handle_slash(&slash).await {
+                Ok(true) => {
+                    self.rln
+                        .p2p
+                        .broadcast_with_exclude(slash.as_ref(), &[self.channel.address().clone()])
+                        .await;
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    error!(
+                        target: "event_graph::rln::handle_slash()",
+                        "[RLN] Invalid slashing evidence from {}: {}", self.channel.address(), e,
+                    );
+                    let malicious_count = self.malicious_count.fetch_add(1, SeqCst);
+                    if malicious_count + 1 == MALICIOUS_THRESHOLD {
+                        self.channel.stop().await;
+                        return Err(Error::ChannelStopped)
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event_graph::{NULL_ID, N_EVENT_PARENTS};
+
+    fn event(timestamp: u64, content: &[u8]) -> Event {
+        Event {
+            timestamp,
+            content: content.to_vec(),
+            parents: [NULL_ID; N_EVENT_PARENTS],
+            layer: 1,
+            author: None,
+            rln: None,
+            topic: None,
+        }
+    }
+
+    #[test]
+    fn rln_signal_and_slash() {
+        let ex = Arc::new(Executor::new());
+        smol::block_on(ex.run(async {
+            let p2p = P2p::new(Settings::default(), ex.clone()).await.unwrap();
+            let sled_db = sled::Config::new().temporary(true).open().unwrap();
+            let rln = Rln::new(&sled_db, 1000, p2p.clone()).await.unwrap();
+
+            let secret_key = pallas::Base::random(&mut OsRng);
+            let other_key = pallas::Base::random(&mut OsRng);
+            rln.register(Rln::identity_commitment(other_key)).await;
+            rln.register(Rln::identity_commitment(secret_key)).await;
+
+            // Unsignalled events and unregistered identities are rejected
+            let mut event_a = event(10_500, b"a");
+            assert!(rln.validate(&event_a).await.is_err());
+            assert!(rln.signal(&mut event_a, pallas::Base::random(&mut OsRng)).await.is_err());
+
+            rln.signal(&mut event_a, secret_key).await.unwrap();
+            assert!(rln.validate(&event_a).await.is_ok());
+            // Seeing the same event again is fine
+            assert!(rln.validate(&event_a).await.is_ok());
+
+            // Tampering with the content breaks the proof
+            let mut tampered = event_a.clone();
+            tampered.content = b"b".to_vec();
+            assert!(rln.validate(&tampered).await.is_err());
+
+            // A second event in the same epoch reveals the secret.
+            // Forget our own signals, otherwise we'd wait for the next epoch.
+            rln.signalled.clear().unwrap();
+            let mut event_b = event(10_900, b"b");
+            rln.signal(&mut event_b, secret_key).await.unwrap();
+            assert_eq!(event_b.rln.as_ref().unwrap().epoch, 10);
+
+            let slash = rln.verify_event(&event_b).await.unwrap().unwrap();
+            assert_eq!(
+                sss_recover(&[
+                    (slash.first.message_hash, slash.first.y),
+                    (slash.second.message_hash, slash.second.y),
+                ]),
+                secret_key
+            );
+
+            // The identity is now banned in every epoch
+            rln.signalled.clear().unwrap();
+            let mut event_c = event(11_500, b"c");
+            rln.signal(&mut event_c, secret_key).await.unwrap();
+            assert!(rln.validate(&event_c).await.is_err());
+
+            // The evidence verifies, but the identity is already banned
+            assert!(!rln.handle_slash(&slash).await.unwrap());
+            let mut bogus = slash.clone();
+            bogus.second.y = pallas::Base::random(&mut OsRng);
+            assert!(rln.handle_slash(&bogus).await.is_err());
+        }));
+    }
+
+    #[test]
+    fn rln_sss_recover() {
+        let secret_key = pallas::Base::random(&mut OsRng);
+        let a_1 = pallas::Base::random(&mut OsRng);
+        let shares: Vec<_> = (1..3)
+            .map(|x| {
+                let x = pallas::Base::from(x);
+                (x, a_1 * x + secret_key)
+            })
+            .collect();
+        assert_eq!(sss_recover(&shares), secret_key);
+    }
+}
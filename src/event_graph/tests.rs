@@ -20,19 +20,34 @@
 
 use std::sync::Arc;
 
+use darkfi_sdk::crypto::Keypair;
 use log::{info, warn};
-use rand::{prelude::SliceRandom, rngs::ThreadRng};
+use rand::{
+    prelude::SliceRandom,
+    rngs::{OsRng, ThreadRng},
+};
 use sled_overlay::sled;
 use smol::{channel, future, Executor};
 use url::Url;
 
 use crate::{
     event_graph::{
+        archive::ARCHIVE_FEATURE,
+        event::EVENT_FEATURE,
         proto::{EventPut, ProtocolEventGraph},
+        reconcile::RECONCILE_FEATURE,
+        topic::TOPICS_FEATURE,
         Event, EventGraph,
     },
-    net::{session::SESSION_DEFAULT, P2p, Settings},
-    system::sleep,
+    net::{
+        session::SESSION_DEFAULT,
+        transport::sim::{SimNetwork, SimNetworkPtr, SimSettings},
+        P2p, Settings,
+    },
+    system::{
+        sim_clock::{SimClock, SimClockPtr},
+        sleep,
+    },
 };
 
 // Number of nodes to spawn and number of peers each node connects to
@@ -41,6 +56,9 @@ const N_CONNS: usize = 2;
 //const N_NODES: usize = 50;
 //const N_CONNS: usize = N_NODES / 3;
 
+// Port the nodes listen on in the simulated network
+const PORT: u16 = 13200;
+
 fn init_logger() {
     let mut cfg = simplelog::ConfigBuilder::new();
     cfg.add_filter_ignore("sled".to_string());
@@ -57,6 +75,7 @@ fn init_logger() {
     cfg.add_filter_ignore("net::channel::subscribe_msg()".to_string());
     cfg.add_filter_ignore("net::channel::main_receive_loop()".to_string());
     cfg.add_filter_ignore("net::tcp".to_string());
+    cfg.add_filter_ignore("net::sim".to_string());
 
     // We check this error so we can execute same file tests in parallel,
     // otherwise second one fails to init logger here.
@@ -75,27 +94,38 @@ fn init_logger() {
 }
 
 async fn spawn_node(
-    inbound_addrs: Vec<Url>,
+    network: &SimNetworkPtr,
+    name: &str,
     peers: Vec<Url>,
     ex: Arc<Executor<'static>>,
 ) -> Arc<EventGraph> {
     let settings = Settings {
         localnet: true,
-        inbound_addrs,
+        inbound_addrs: vec![network.endpoint(name, PORT)],
         outbound_connections: 0,
         outbound_connect_timeout: 2,
         inbound_connections: usize::MAX,
         peers,
-        allowed_transports: vec!["tcp".to_string()],
+        node_id: name.to_string(),
+        allowed_transports: vec!["sim".to_string()],
         ..Default::default()
     };
 
     let p2p = P2p::new(settings, ex.clone()).await.unwrap();
     let sled_db = sled::Config::new().temporary(true).open().unwrap();
-    let event_graph =
-        EventGraph::new(p2p.clone(), sled_db, "/tmp".into(), false, "dag", 1, ex.clone())
-            .await
-            .unwrap();
+    let event_graph = EventGraph::new(
+        p2p.clone(),
+        sled_db,
+        "/tmp".into(),
+        false,
+        "dag",
+        1,
+        false,
+        None,
+        ex.clone(),
+    )
+    .await
+    .unwrap();
     *event_graph.synced.write().await = true;
     let event_graph_ = event_graph.clone();
 
@@ -112,8 +142,8 @@ async fn spawn_node(
 }
 
 async fn bootstrap_nodes(
+    network: &SimNetworkPtr,
     peer_indexes: &[usize],
-    starting_port: usize,
     rng: &mut ThreadRng,
     ex: Arc<Executor<'static>>,
 ) -> Vec<Arc<EventGraph>> {
@@ -127,18 +157,12 @@ async fn bootstrap_nodes(
         let peer_indexes_to_connect: Vec<_> =
             peer_indexes_copy.choose_multiple(rng, N_CONNS).collect();
 
-        let mut peers = vec![];
-        for peer_index in peer_indexes_to_connect {
-            let port = starting_port + peer_index;
-            peers.push(Url::parse(&format!("tcp://127.0.0.1:{}", port)).unwrap());
-        }
-
-        let event_graph = spawn_node(
-            vec![Url::parse(&format!("tcp://127.0.0.1:{}", starting_port + i)).unwrap()],
-            peers,
-            ex.clone(),
-        )
-        .await;
+        let peers = peer_indexes_to_connect
+            .into_iter()
+            .map(|peer_index| network.endpoint(&format!("node{peer_index}"), PORT))
+            .collect();
+
+        let event_graph = spawn_node(network, &format!("node{i}"), peers, ex.clone()).await;
 
         eg_instances.push(event_graph);
     }
@@ -157,10 +181,10 @@ async fn bootstrap_nodes(
 async fn assert_dags(eg_instances: &[Arc<EventGraph>], expected_len: usize, rng: &mut ThreadRng) {
     let random_node = eg_instances.choose(rng).unwrap();
     let last_layer_tips =
-        random_node.unreferenced_tips.read().await.last_key_value().unwrap().1.clone();
+        random_node.unreferenced_tips.read().await[&None].last_key_value().unwrap().1.clone();
     for (i, eg) in eg_instances.iter().enumerate() {
         let node_last_layer_tips =
-            eg.unreferenced_tips.read().await.last_key_value().unwrap().1.clone();
+            eg.unreferenced_tips.read().await[&None].last_key_value().unwrap().1.clone();
         assert!(
             eg.dag.len() == expected_len,
             "Node {}, expected {} events, have {}",
@@ -176,20 +200,27 @@ async fn assert_dags(eg_instances: &[Arc<EventGraph>], expected_len: usize, rng:
     }
 }
 
+// The nodes run over a simulated network, so all the threads enter its
+// clock and the waits below pass in virtual time.
 macro_rules! test_body {
     ($real_call:ident) => {
         init_logger();
 
         let ex = Arc::new(Executor::new());
         let ex_ = ex.clone();
+        let clock = SimClock::new();
         let (signal, shutdown) = channel::unbounded::<()>();
 
         // Run a thread for each node.
         easy_parallel::Parallel::new()
-            .each(0..N_NODES, |_| future::block_on(ex.run(shutdown.recv())))
+            .each(0..N_NODES, |_| {
+                let _guard = clock.enter();
+                future::block_on(ex.run(shutdown.recv()))
+            })
             .finish(|| {
+                let _guard = clock.enter();
                 future::block_on(async {
-                    $real_call(ex_).await;
+                    $real_call(ex_, clock.clone()).await;
                     drop(signal);
                 })
             });
@@ -201,12 +232,15 @@ fn eventgraph_propagation() {
     test_body!(eventgraph_propagation_real);
 }
 
-async fn eventgraph_propagation_real(ex: Arc<Executor<'static>>) {
+async fn eventgraph_propagation_real(ex: Arc<Executor<'static>>, clock: SimClockPtr) {
     let mut rng = rand::thread_rng();
     let peer_indexes: Vec<usize> = (0..N_NODES).collect();
 
     // Bootstrap nodes
-    let mut eg_instances = bootstrap_nodes(&peer_indexes, 13200, &mut rng, ex.clone()).await;
+    let network =
+        SimNetwork::new("eventgraph_propagation", SimSettings::default(), clock, ex.clone())
+            .unwrap();
+    let mut eg_instances = bootstrap_nodes(&network, &peer_indexes, &mut rng, ex.clone()).await;
 
     // Grab genesis event
     let random_node = eg_instances.choose(&mut rng).unwrap();
@@ -226,11 +260,12 @@ async fn eventgraph_propagation_real(ex: Arc<Executor<'static>>) {
     assert!(event.parents.contains(&genesis_event_id));
     // The node adds it to their DAG, on layer 1.
     let event_id = random_node.dag_insert(&[event.clone()]).await.unwrap()[0];
-    let tips_layers = random_node.unreferenced_tips.read().await;
+    let tips = random_node.unreferenced_tips.read().await;
+    let tips_layers = &tips[&None];
     // Since genesis was referenced, its layer (0) have been removed
     assert_eq!(tips_layers.len(), 1);
     assert!(tips_layers.last_key_value().unwrap().1.get(&event_id).is_some());
-    drop(tips_layers);
+    drop(tips);
     info!("Broadcasting event {}", event_id);
     random_node.p2p.broadcast(&EventPut(event)).await;
     info!("Waiting 5s for event propagation");
@@ -255,10 +290,11 @@ async fn eventgraph_propagation_real(ex: Arc<Executor<'static>>) {
     let event2_id = random_node.dag_insert(&[event2.clone()]).await.unwrap()[0];
     // Genesis event + event from 2. + upper 3 events (layer 4)
     assert_eq!(random_node.dag.len(), 5);
-    let tips_layers = random_node.unreferenced_tips.read().await;
+    let tips = random_node.unreferenced_tips.read().await;
+    let tips_layers = &tips[&None];
     assert_eq!(tips_layers.len(), 1);
     assert!(tips_layers.get(&4).unwrap().get(&event2_id).is_some());
-    drop(tips_layers);
+    drop(tips);
 
     let event_chain =
         vec![(event0_id, event0.parents), (event1_id, event1.parents), (event2_id, event2.parents)];
@@ -341,18 +377,12 @@ async fn eventgraph_propagation_real(ex: Arc<Executor<'static>>) {
         let peer_indexes_to_connect: Vec<_> =
             peer_indexes.choose_multiple(&mut rng, N_CONNS).collect();
 
-        let mut peers = vec![];
-        for peer_index in peer_indexes_to_connect {
-            let port = 13200 + peer_index;
-            peers.push(Url::parse(&format!("tcp://127.0.0.1:{}", port)).unwrap());
-        }
-
-        let event_graph = spawn_node(
-            vec![Url::parse(&format!("tcp://127.0.0.1:{}", 13200 + N_NODES + 1)).unwrap()],
-            peers,
-            ex.clone(),
-        )
-        .await;
+        let peers = peer_indexes_to_connect
+            .into_iter()
+            .map(|peer_index| network.endpoint(&format!("node{peer_index}"), PORT))
+            .collect();
+
+        let event_graph = spawn_node(&network, &format!("node{N_NODES}"), peers, ex.clone()).await;
 
         eg_instances.push(event_graph.clone());
 
@@ -374,6 +404,7 @@ async fn eventgraph_propagation_real(ex: Arc<Executor<'static>>) {
     for eg in eg_instances.iter() {
         eg.p2p.clone().stop().await;
     }
+    network.stop().await;
 }
 
 #[test]
@@ -382,13 +413,20 @@ fn eventgraph_chaotic_propagation() {
     test_body!(eventgraph_chaotic_propagation_real);
 }
 
-async fn eventgraph_chaotic_propagation_real(ex: Arc<Executor<'static>>) {
+async fn eventgraph_chaotic_propagation_real(ex: Arc<Executor<'static>>, clock: SimClockPtr) {
     let mut rng = rand::thread_rng();
     let peer_indexes: Vec<usize> = (0..N_NODES).collect();
     let n_events: usize = 100000;
 
     // Bootstrap nodes
-    let mut eg_instances = bootstrap_nodes(&peer_indexes, 14200, &mut rng, ex.clone()).await;
+    let network = SimNetwork::new(
+        "eventgraph_chaotic_propagation",
+        SimSettings::default(),
+        clock,
+        ex.clone(),
+    )
+    .unwrap();
+    let mut eg_instances = bootstrap_nodes(&network, &peer_indexes, &mut rng, ex.clone()).await;
 
     // =========================================
     // 1. Assert that everyone's DAG is the same
@@ -419,18 +457,12 @@ async fn eventgraph_chaotic_propagation_real(ex: Arc<Executor<'static>>) {
         let peer_indexes_to_connect: Vec<_> =
             peer_indexes.choose_multiple(&mut rng, N_CONNS).collect();
 
-        let mut peers = vec![];
-        for peer_index in peer_indexes_to_connect {
-            let port = 14200 + peer_index;
-            peers.push(Url::parse(&format!("tcp://127.0.0.1:{}", port)).unwrap());
-        }
-
-        let event_graph = spawn_node(
-            vec![Url::parse(&format!("tcp://127.0.0.1:{}", 14200 + N_NODES + 1)).unwrap()],
-            peers,
-            ex.clone(),
-        )
-        .await;
+        let peers = peer_indexes_to_connect
+            .into_iter()
+            .map(|peer_index| network.endpoint(&format!("node{peer_index}"), PORT))
+            .collect();
+
+        let event_graph = spawn_node(&network, &format!("node{N_NODES}"), peers, ex.clone()).await;
 
         eg_instances.push(event_graph.clone());
 
@@ -451,4 +483,74 @@ async fn eventgraph_chaotic_propagation_real(ex: Arc<Executor<'static>>) {
     for eg in eg_instances.iter() {
         eg.p2p.clone().stop().await;
     }
+    network.stop().await;
+}
+
+#[test]
+fn eventgraph_mixed_versions() {
+    test_body!(eventgraph_mixed_versions_real);
+}
+
+async fn eventgraph_mixed_versions_real(ex: Arc<Executor<'static>>, clock: SimClockPtr) {
+    let network =
+        SimNetwork::new("eventgraph_mixed_versions", SimSettings::default(), clock, ex.clone())
+            .unwrap();
+
+    // ===========================================================
+    // 1. Start a node speaking the versioned event encoding, and
+    //    a legacy one that doesn't advertise it nor the protocols
+    //    built on top of it. The legacy node has an event the
+    //    other one doesn't.
+    // ===========================================================
+    let node = spawn_node(&network, "node0", vec![], ex.clone()).await;
+    let legacy =
+        spawn_node(&network, "legacy", vec![network.endpoint("node0", PORT)], ex.clone()).await;
+    for feature in [EVENT_FEATURE, RECONCILE_FEATURE, TOPICS_FEATURE, ARCHIVE_FEATURE] {
+        legacy.p2p.remove_feature(feature);
+    }
+
+    let legacy_event = Event::new(vec![1, 2, 3, 4], &legacy).await;
+    legacy.dag_insert(&[legacy_event.clone()]).await.unwrap();
+
+    node.p2p.clone().start().await.unwrap();
+    legacy.p2p.clone().start().await.unwrap();
+    info!("Waiting 5s until the peers connect");
+    sleep(5).await;
+
+    // The connection is kept on both sides
+    assert_eq!(node.p2p.hosts().peers().len(), 1);
+    assert_eq!(legacy.p2p.hosts().peers().len(), 1);
+
+    // ===========================================================
+    // 2. Sync the new node from the legacy one, which falls back
+    //    to walking back from its tips.
+    // ===========================================================
+    node.dag_sync().await.unwrap();
+    assert!(node.dag.contains_key(legacy_event.id().as_bytes()).unwrap());
+
+    // ===========================================================
+    // 3. Legacy events still reach the legacy node, while events
+    //    only the versioned encoding can carry are not sent to it.
+    // ===========================================================
+    let event = Event::new(vec![1, 2, 3, 4, 5], &node).await;
+    node.dag_insert(&[event.clone()]).await.unwrap();
+    node.event_broadcast(&event, &[]).await;
+
+    let signed_event =
+        Event::new_signed(vec![1, 2, 3, 4, 6], &Keypair::random(&mut OsRng), &node).await;
+    assert!(!signed_event.is_legacy());
+    node.dag_insert(&[signed_event.clone()]).await.unwrap();
+    node.event_broadcast(&signed_event, &[]).await;
+
+    info!("Waiting 5s for event propagation");
+    sleep(5).await;
+
+    assert!(legacy.dag.contains_key(event.id().as_bytes()).unwrap());
+    assert!(!legacy.dag.contains_key(signed_event.id().as_bytes()).unwrap());
+    assert_eq!(legacy.p2p.hosts().peers().len(), 1);
+
+    // Stop the P2P network
+    node.p2p.clone().stop().await;
+    legacy.p2p.clone().stop().await;
+    network.stop().await;
 }
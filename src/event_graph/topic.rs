@@ -0,0 +1,97 @@
+/* This file is part of DarkFi (https://dark.fi)
+ *
+ * Copyright (C) 2020-2024 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Topic-scoped sub-DAGs.
+//!
+//! Events can be tagged with a topic. Tagged events may only reference
+//! parents of the same topic or the genesis event, so every topic forms
+//! its own sub-DAG rooted at the genesis, with its own set of tips.
+//! Untagged events form the global topic, which every node follows.
+//!
+//! Nodes pick the topics they follow with a [`TopicFilter`] and tell it
+//! to their peers, so they only sync and get relayed the events they
+//! care about.
+
+use std::collections::HashSet;
+
+use darkfi_serial::{async_trait, SerialDecodable, SerialEncodable};
+
+/// Name of the feature advertised by peers supporting topic filters
+pub const TOPICS_FEATURE: &str = "event_graph/topics";
+
+/// Version of the topics feature
+pub const TOPICS_VERSION: u32 = 1;
+
+/// Maximum number of topics a peer's filter may contain
+pub const MAX_FILTER_TOPICS: usize = 1024;
+
+/// Set of topics a node follows
+#[derive(Clone, Debug, Default, PartialEq, SerialEncodable, SerialDecodable)]
+pub struct TopicFilter(pub Option<HashSet<blake3::Hash>>);
+
+impl TopicFilter {
+    /// Filter following every topic
+    pub fn all() -> Self {
+        Self(None)
+    }
+
+    /// Filter following only the given topics, besides the global one
+    pub fn only(topics: impl IntoIterator<Item = blake3::Hash>) -> Self {
+        Self(Some(topics.into_iter().collect()))
+    }
+
+    /// Check if the filter follows the given topic.
+    /// The global topic is always followed.
+    pub fn matches(&self, topic: &Option<blake3::Hash>) -> bool {
+        match (&self.0, topic) {
+            (None, _) | (_, None) => true,
+            (Some(topics), Some(topic)) => topics.contains(topic),
+        }
+    }
+
+    /// Return the number of topics in the filter, if it is restricted
+    pub fn topic_count(&self) -> Option<usize> {
+        self.0.as_ref().map(|topics| topics.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn topic_filter_matches() {
+        let a = blake3::hash(b"#dev");
+        let b = blake3::hash(b"#random");
+
+        let all = TopicFilter::all();
+        assert!(all.matches(&None));
+        assert!(all.matches(&Some(a)));
+        assert_eq!(all.topic_count(), None);
+
+        let only_a = TopicFilter::only([a]);
+        assert!(only_a.matches(&None));
+        assert!(only_a.matches(&Some(a)));
+        assert!(!only_a.matches(&Some(b)));
+        assert_eq!(only_a.topic_count(), Some(1));
+
+        let none = TopicFilter::only([]);
+        assert!(none.matches(&None));
+        assert!(!none.matches(&Some(a)));
+    }
+}
@@ -55,6 +55,15 @@ pub(super) fn midnight_timestamp(days: i64) -> u64 {
     (cur_midnight + (DAY * days)) as u64
 }
 
+/// Build the key of an event in the time-ordered trees, so that
+/// iterating over them yields events sorted by `(timestamp, id)`.
+pub(super) fn timestamp_key(timestamp: u64, event_id: &[u8; 32]) -> [u8; 40] {
+    let mut key = [0u8; 40];
+    key[..8].copy_from_slice(&timestamp.to_be_bytes());
+    key[8..].copy_from_slice(event_id);
+    key
+}
+
 /// Calculate the number of days since a given midnight timestamp.
 pub(super) fn days_since(midnight_ts: u64) -> u64 {
     // Get current time
@@ -130,6 +139,9 @@ pub fn generate_genesis(days_rotation: u64) -> Event {
         content: GENESIS_CONTENTS.to_vec(),
         parents: [NULL_ID; N_EVENT_PARENTS],
         layer: 0,
+        author: None,
+        rln: None,
+        topic: None,
     }
 }
 
@@ -0,0 +1,119 @@
+/* This file is part of DarkFi (https://dark.fi)
+ *
+ * Copyright (C) 2020-2024 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::sync::Arc;
+
+use darkfi_serial::async_trait;
+
+use super::Event;
+
+/// Atomic pointer to an [`EventValidator`] implementation
+pub type EventValidatorPtr = Arc<dyn EventValidator>;
+
+/// Application-level validation of events.
+///
+/// [`Event::validate`] only checks the structure of an event, so an
+/// application registers a validator with its [`EventGraph`](super::EventGraph)
+/// to check the content as well. It is called on every event before it
+/// is inserted into the DAG: the ones we create ourselves, the ones peers
+/// broadcast to us and the ones we sync. Rejected events are neither
+/// inserted nor relayed, and peers sending them get penalized.
+#[async_trait]
+pub trait EventValidator: Send + Sync {
+    /// Validate the given event, returning the reason it is rejected
+    /// for if it shouldn't make it into the DAG.
+    async fn validate(&self, event: &Event) -> std::result::Result<(), String>;
+}
+
+/// Chain of [`EventValidator`]s, run in order. An event is rejected by
+/// the first validator that rejects it, so the cheap checks should go
+/// first.
+pub struct ValidatorChain(pub Vec<EventValidatorPtr>);
+
+#[async_trait]
+impl EventValidator for ValidatorChain {
+    async fn validate(&self, event: &Event) -> std::result::Result<(), String> {
+        for validator in &self.0 {
+            validator.validate(event).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use smol::Executor;
+
+    use super::*;
+    use crate::{
+        event_graph::EventGraph,
+        net::{P2p, Settings},
+        Error, Result,
+    };
+
+    /// Rejects events whose content starts with a zero byte
+    struct NoZeroes;
+
+    #[async_trait]
+    impl EventValidator for NoZeroes {
+        async fn validate(&self, event: &Event) -> std::result::Result<(), String> {
+            match event.content().first() {
+                Some(0) => Err("content starts with a zero".to_string()),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    #[test]
+    fn validator_rejects_events() -> Result<()> {
+        smol::block_on(async {
+            let ex = Arc::new(Executor::new());
+            let p2p = P2p::new(Settings::default(), ex.clone()).await?;
+            let sled_db = sled_overlay::sled::Config::new().temporary(true).open()?;
+            let validator: EventValidatorPtr = Arc::new(NoZeroes);
+            let event_graph = EventGraph::new(
+                p2p,
+                sled_db,
+                "/tmp".into(),
+                false,
+                "dag",
+                1,
+                false,
+                Some(validator),
+                ex,
+            )
+            .await?;
+
+            // Rejected events never make it into the DAG
+            let rejected_event = Event::new(vec![0u8, 1u8], &event_graph).await;
+            assert!(matches!(
+                event_graph.dag_insert(&[rejected_event.clone()]).await,
+                Err(Error::EventRejected(_))
+            ));
+            assert!(event_graph.dag_get(&rejected_event.id()).await?.is_none());
+
+            // Accepted ones do
+            let accepted_event = Event::new(vec![1u8, 0u8], &event_graph).await;
+            event_graph.dag_insert(&[accepted_event.clone()]).await?;
+            assert!(event_graph.dag_get(&accepted_event.id()).await?.is_some());
+
+            // Thanks for reading
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,205 @@
+/* This file is part of DarkFi (https://dark.fi)
+ *
+ * Copyright (C) 2020-2024 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Bao-style verified streaming over BLAKE3 trees.
+//!
+//! BLAKE3 hashes its input as a binary tree, and since [`MAX_CHUNK_SIZE`]
+//! is a power of two, every Geode chunk is a subtree of the file's tree.
+//! A file is identified by its BLAKE3 hash, which is the root of the tree,
+//! and each chunk by the chaining value of its subtree. This lets the list
+//! of chunk hashes be verified against the file hash on its own.
+//!
+//! Chunks are further split into [`GROUP_SIZE`] groups, which are the
+//! leaves of the chunk's subtree. A group can be sent along with an
+//! inclusion proof made of the sibling hashes on its path up to the
+//! chunk hash, so a chunk can be transferred and verified piece by piece,
+//! and a download can resume in the middle of a chunk.
+//!
+//! A file made of a single chunk is a special case, as its chunk is the
+//! whole tree: the chunk hash then is the file hash.
+
+use blake3::hazmat::{merge_subtrees_non_root, merge_subtrees_root, HasherExt, Mode};
+
+use super::MAX_CHUNK_SIZE;
+
+/// Size of the groups chunks are verified in (16 KiB)
+pub const GROUP_SIZE: usize = 16_384;
+
+/// A group of a chunk, along with its inclusion proof
+#[derive(Clone, Debug)]
+pub struct ChunkGroup {
+    /// Offset of the group within the chunk
+    pub offset: u64,
+    /// Contents of the group
+    pub data: Vec<u8>,
+    /// Sibling hashes on the path from the group to the chunk hash,
+    /// ordered from the top of the tree down
+    pub proof: Vec<blake3::Hash>,
+}
+
+/// Hash a subtree of the file's tree starting at the given offset.
+/// `is_root` must be set if the subtree is the whole file.
+fn subtree_hash(data: &[u8], offset: u64, is_root: bool) -> blake3::Hash {
+    if is_root {
+        return blake3::hash(data)
+    }
+
+    let mut hasher = blake3::Hasher::new();
+    hasher.set_input_offset(offset);
+    hasher.update(data);
+    hasher.finalize_non_root().into()
+}
+
+/// Merge two child hashes into their parent node
+fn parent_hash(left: &blake3::Hash, right: &blake3::Hash, is_root: bool) -> blake3::Hash {
+    if is_root {
+        return merge_subtrees_root(left.as_bytes(), right.as_bytes(), Mode::Hash)
+    }
+
+    merge_subtrees_non_root(left.as_bytes(), right.as_bytes(), Mode::Hash).into()
+}
+
+/// Number of leaves in the left subtree of a node with `n > 1` leaves,
+/// which is the largest power of two smaller than `n`, as BLAKE3 does.
+fn left_len(n: usize) -> usize {
+    1 << (usize::BITS - 1 - (n - 1).leading_zeros())
+}
+
+/// Merge a non-empty list of leaf hashes into the hash of their tree
+fn tree_hash(leaves: &[blake3::Hash], is_root: bool) -> blake3::Hash {
+    if leaves.len() == 1 {
+        return leaves[0]
+    }
+
+    let mid = left_len(leaves.len());
+    let left = tree_hash(&leaves[..mid], false);
+    let right = tree_hash(&leaves[mid..], false);
+    parent_hash(&left, &right, is_root)
+}
+
+/// Return the byte offset of the chunk at the given index in a file
+pub fn chunk_offset(index: usize) -> u64 {
+    (index * MAX_CHUNK_SIZE) as u64
+}
+
+/// Hash a chunk found at the given offset in a file. `is_root` must be set
+/// if the chunk is the only one of the file.
+pub fn chunk_hash(data: &[u8], offset: u64, is_root: bool) -> blake3::Hash {
+    subtree_hash(data, offset, is_root)
+}
+
+/// Compute the hash of a file given the hashes of its chunks in order
+pub fn file_hash(chunk_hashes: &[blake3::Hash]) -> blake3::Hash {
+    match chunk_hashes.len() {
+        0 => blake3::hash(&[]),
+        _ => tree_hash(chunk_hashes, true),
+    }
+}
+
+/// Compute the hashes of the groups of a chunk
+fn group_hashes(data: &[u8], offset: u64, is_root: bool) -> Vec<blake3::Hash> {
+    let is_root = is_root && data.len() <= GROUP_SIZE;
+    data.chunks(GROUP_SIZE)
+        .enumerate()
+        .map(|(i, group)| subtree_hash(group, offset + (i * GROUP_SIZE) as u64, is_root))
+        .collect()
+}
+
+/// Build the inclusion proof of the leaf at `index`
+fn prove(leaves: &[blake3::Hash], index: usize) -> Vec<blake3::Hash> {
+    if leaves.len() == 1 {
+        return vec![]
+    }
+
+    let mid = left_len(leaves.len());
+    let (sibling, mut rest) = if index < mid {
+        (tree_hash(&leaves[mid..], false), prove(&leaves[..mid], index))
+    } else {
+        (tree_hash(&leaves[..mid], false), prove(&leaves[mid..], index - mid))
+    };
+
+    rest.insert(0, sibling);
+    rest
+}
+
+/// Recompute the hash of a tree of `n` leaves from the leaf at `index`
+/// and its inclusion proof. Returns `None` if the proof is malformed.
+fn proof_root(
+    leaf: blake3::Hash,
+    n: usize,
+    index: usize,
+    proof: &[blake3::Hash],
+    is_root: bool,
+) -> Option<blake3::Hash> {
+    if n == 1 {
+        return proof.is_empty().then_some(leaf)
+    }
+
+    let (sibling, rest) = proof.split_first()?;
+    let mid = left_len(n);
+    if index < mid {
+        let left = proof_root(leaf, mid, index, rest, false)?;
+        Some(parent_hash(&left, sibling, is_root))
+    } else {
+        let right = proof_root(leaf, n - mid, index - mid, rest, false)?;
+        Some(parent_hash(sibling, &right, is_root))
+    }
+}
+
+/// Split a chunk into groups starting at the given offset within the chunk,
+/// each with its inclusion proof.
+pub fn chunk_groups(data: &[u8], offset: u64, is_root: bool, from: u64) -> Vec<ChunkGroup> {
+    let leaves = group_hashes(data, offset, is_root);
+    let first = (from as usize).div_ceil(GROUP_SIZE);
+
+    data.chunks(GROUP_SIZE)
+        .enumerate()
+        .skip(first)
+        .map(|(i, group)| ChunkGroup {
+            offset: (i * GROUP_SIZE) as u64,
+            data: group.to_vec(),
+            proof: prove(&leaves, i),
+        })
+        .collect()
+}
+
+/// Verify a group of a chunk of `chunk_size` bytes against the chunk hash,
+/// given the chunk's offset in the file and whether it's the only chunk.
+pub fn verify_group(
+    chunk_hash: &blake3::Hash,
+    offset: u64,
+    is_root: bool,
+    chunk_size: u64,
+    group: &ChunkGroup,
+) -> bool {
+    let chunk_size = chunk_size as usize;
+    if chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE || group.offset % GROUP_SIZE as u64 != 0 {
+        return false
+    }
+
+    // Every group is full, except the last one of the chunk
+    let index = group.offset as usize / GROUP_SIZE;
+    let n_groups = chunk_size.div_ceil(GROUP_SIZE);
+    let expected_len = GROUP_SIZE.min(chunk_size.saturating_sub(group.offset as usize));
+    if index >= n_groups || group.data.len() != expected_len {
+        return false
+    }
+
+    let leaf = subtree_hash(&group.data, offset + group.offset, is_root && n_groups == 1);
+    proof_root(leaf, n_groups, index, &group.proof, is_root).as_ref() == Some(chunk_hash)
+}
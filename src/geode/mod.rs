@@ -19,19 +19,19 @@
 //! Chunk-based file storage implementation.
 //! This is a building block for a DHT or something similar.
 //!
-//! The API supports file insertion and retrieval. There is intentionally no
-//! `remove` support. File removal should be handled externally, and then it
-//! is only required to run `garbage_collect()` to clean things up.
+//! The API supports file insertion, retrieval and removal, as well as
+//! streaming reads over stored files through [`GeodeReader`].
 //!
 //! The filesystem hierarchy stores two directories: `files` and `chunks`.
-//! `chunks` store [`MAX_CHUNK_SIZE`] files, where the filename is a BLAKE3
-//! hash of the chunk's contents.
+//! `chunks` store [`MAX_CHUNK_SIZE`] files, where the filename is the
+//! hash of the chunk's subtree in the file's BLAKE3 tree, as described
+//! in the [`bao`] module.
 //! `files` store metadata about a full file, which can be retrieved by
 //! concatenating the chunks in order. The filename of a file in `files`
-//! is the BLAKE3 hash of hashed chunks in the correct order.
+//! is the BLAKE3 hash of the file's contents.
 //!
 //! It might look like the following:
-//! ```
+//! ```text
 //! /files/7d4c0d5539057c8f9b60d32b423964beb38ecd8ea1ab203c0207990cbf0cad22
 //! /files/...
 //! /chunks/9d7abc2efa52b8be63ff82b756edb6822e09aa40fc587aba977185a5bb449c19
@@ -41,7 +41,7 @@
 //!
 //! In the above example, contents of `7d4c0d5539057c8f9b60d32b423964beb38ecd8ea1ab203c0207990cbf0cad22`
 //! may be:
-//! ```
+//! ```text
 //! 9d7abc2efa52b8be63ff82b756edb6822e09aa40fc587aba977185a5bb449c19
 //! fc432e087d16d8788e87640511e627be34a4a50533f1e5ed3e1370645a0266b8
 //! ```
@@ -52,23 +52,51 @@
 //! data, and by concatenating them we can retrieve the original file.
 //!
 //! It is important to note that multiple files can use the same chunks.
-//! This is some kind of naive deduplication, so we actually don't consider
-//! chunks to be specific to a single file and therefore when we do garbage
-//! collection, we keep chunks and files independent of each other.
+//! This is some kind of naive deduplication, so chunks are reference
+//! counted by the files using them, and a chunk is only deleted once
+//! the last file referencing it is removed.
+//!
+//! Alongside the two directories, an `index` sled database keeps track
+//! of the chunks we hold, their sizes, their positions in the files using
+//! them, and their reference counts.
+//! Chunks are verified when they are written, so lookups don't have to
+//! re-hash them. Chunks read through [`GeodeReader`] are verified again,
+//! and evicted from the index if they turn out to be corrupted, so they
+//! can be fetched again. The index is built with a full scan of the
+//! filesystem hierarchy the first time it is created.
+//!
+//! Stores written before chunks were hashed as BLAKE3 subtrees named
+//! their chunks after the plain BLAKE3 hash of their contents. They are
+//! migrated the first time they are opened: complete files are re-chunked
+//! and their metadata rewritten, while files missing some of their chunks
+//! are removed, since they can't be verified and have to be fetched again.
+//!
+//! Chunks being fetched from the network can be inserted group by group
+//! with [`Geode::insert_chunk_group`], which verifies each group as it
+//! arrives. The verified part is kept in a `.part` file next to the chunk
+//! until it is complete, so interrupted transfers can be resumed.
 
 use std::{collections::HashSet, path::PathBuf};
 
 use futures::AsyncRead;
 use log::{debug, info, warn};
+use sled_overlay::sled;
 use smol::{
     fs,
     fs::{File, OpenOptions},
-    io::{AsyncBufReadExt, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader, Cursor, SeekFrom},
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
     stream::StreamExt,
 };
 
 use crate::{Error, Result};
 
+pub mod bao;
+pub use bao::{ChunkGroup, GROUP_SIZE};
+
+mod reader;
+pub use reader::GeodeReader;
+use reader::ReaderChunk;
+
 /// Defined maximum size of a stored chunk (256 KiB)
 pub const MAX_CHUNK_SIZE: usize = 262_144;
 
@@ -76,6 +104,28 @@ pub const MAX_CHUNK_SIZE: usize = 262_144;
 const FILES_PATH: &str = "files";
 /// Path prefix where file chunks are stored
 const CHUNKS_PATH: &str = "chunks";
+/// Path prefix where the chunk index database is stored
+const INDEX_PATH: &str = "index";
+
+/// sled tree mapping available chunks to their size
+const SLED_CHUNK_INDEX_TREE: &[u8] = b"_chunk_index";
+/// sled tree mapping chunks to the number of files referencing them
+const SLED_CHUNK_REFS_TREE: &[u8] = b"_chunk_refs";
+/// sled tree mapping chunks to their position in the files using them
+const SLED_CHUNK_POSITIONS_TREE: &[u8] = b"_chunk_positions";
+/// sled tree holding metadata about the store itself
+const SLED_META_TREE: &[u8] = b"_meta";
+
+/// Key of the store format version in the meta tree
+const STORE_VERSION_KEY: &[u8] = b"version";
+/// Current store format version, with chunks named after their subtree hash
+const STORE_VERSION: u8 = 1;
+
+/// Extension of files being re-chunked by the legacy store migration
+const MIGRATION_EXT: &str = "migrate";
+
+/// Extension of partially fetched chunks
+const PARTIAL_CHUNK_EXT: &str = "part";
 
 /// `ChunkedFile` is a representation of a file we're trying to
 /// retrieve from `Geode`.
@@ -109,23 +159,79 @@ pub struct Geode {
     files_path: PathBuf,
     /// Path to the filesystem directory where file chunks are stored
     chunks_path: PathBuf,
+    /// Index of the chunks available locally, mapped to their size
+    chunk_index: sled::Tree,
+    /// Reference counts of chunks used by stored files
+    chunk_refs: sled::Tree,
+    /// Positions of chunks used by stored files, needed to verify them
+    chunk_positions: sled::Tree,
 }
 
 impl Geode {
     /// Instantiate a new [`Geode`] object.
     /// `base_path` defines the root directory where Geode will store its
-    /// file metadata and chunks.
+    /// file metadata, chunks, and chunk index.
     pub async fn new(base_path: &PathBuf) -> Result<Self> {
         let mut files_path: PathBuf = base_path.into();
         let mut chunks_path: PathBuf = base_path.into();
+        let mut index_path: PathBuf = base_path.into();
         files_path.push(FILES_PATH);
         chunks_path.push(CHUNKS_PATH);
+        index_path.push(INDEX_PATH);
 
         // Create necessary directory structure if needed
         fs::create_dir_all(&files_path).await?;
         fs::create_dir_all(&chunks_path).await?;
 
-        Ok(Self { files_path, chunks_path })
+        let sled_db = sled::open(&index_path)?;
+        let chunk_index = sled_db.open_tree(SLED_CHUNK_INDEX_TREE)?;
+        let chunk_refs = sled_db.open_tree(SLED_CHUNK_REFS_TREE)?;
+        let chunk_positions = sled_db.open_tree(SLED_CHUNK_POSITIONS_TREE)?;
+        let meta = sled_db.open_tree(SLED_META_TREE)?;
+
+        let geode = Self { files_path, chunks_path, chunk_index, chunk_refs, chunk_positions };
+
+        // A freshly created index has to be built from what's on the filesystem,
+        // and stores of an older format have to be migrated first.
+        let version = meta.get(STORE_VERSION_KEY)?;
+        if !sled_db.was_recovered() || version.as_deref() != Some(&[STORE_VERSION]) {
+            geode.migrate_legacy().await?;
+            geode.rebuild_index().await?;
+            meta.insert(STORE_VERSION_KEY, &[STORE_VERSION])?;
+        }
+
+        Ok(geode)
+    }
+
+    /// Return the filesystem path of the given file's metadata
+    fn file_path(&self, file_hash: &blake3::Hash) -> PathBuf {
+        let mut file_path = self.files_path.clone();
+        file_path.push(file_hash.to_hex().as_str());
+        file_path
+    }
+
+    /// Return the filesystem path of the given chunk
+    fn chunk_path(&self, chunk_hash: &blake3::Hash) -> PathBuf {
+        let mut chunk_path = self.chunks_path.clone();
+        chunk_path.push(chunk_hash.to_hex().as_str());
+        chunk_path
+    }
+
+    /// Return the filesystem path of the given chunk while it's being fetched
+    fn partial_chunk_path(&self, chunk_hash: &blake3::Hash) -> PathBuf {
+        self.chunk_path(chunk_hash).with_extension(PARTIAL_CHUNK_EXT)
+    }
+
+    /// Return the offset of a chunk within the files using it, and whether
+    /// it's the only chunk of the file. Returns `None` if no stored file
+    /// uses the chunk, in which case it can't be verified.
+    fn chunk_position(&self, chunk_hash: &blake3::Hash) -> Result<Option<(u64, bool)>> {
+        let Some(position) = self.chunk_positions.get(chunk_hash.as_bytes())? else {
+            return Ok(None)
+        };
+
+        let offset = u64::from_be_bytes(position[..8].try_into().unwrap());
+        Ok(Some((offset, position[8] == 1)))
     }
 
     /// Attempt to read chunk hashes from a given file path and return
@@ -144,78 +250,120 @@ impl Geode {
         Ok(read_chunks)
     }
 
-    /// Perform garbage collection over the filesystem hierarchy.
-    /// Returns sets representing deleted files and deleted chunks, respectively.
-    pub async fn garbage_collect(&self) -> Result<(HashSet<blake3::Hash>, HashSet<blake3::Hash>)> {
-        info!(target: "geode::garbage_collect()", "[Geode] Performing garbage collection");
-        // We track corrupt files and chunks here.
-        let mut deleted_files = HashSet::new();
-        let mut deleted_chunks = HashSet::new();
-        let mut deleted_chunk_paths = HashSet::new();
+    /// Read from a stream until the buffer is full or the stream ends,
+    /// so chunk boundaries don't depend on how the stream hands out data.
+    async fn read_chunk(stream: &mut (impl AsyncRead + Unpin), buf: &mut [u8]) -> Result<usize> {
+        let mut total = 0;
+        while total < buf.len() {
+            let bytes_read = stream.read(&mut buf[total..]).await?;
+            if bytes_read == 0 {
+                break
+            }
+            total += bytes_read;
+        }
 
-        // Scan through available chunks and check them for consistency.
-        let mut chunk_paths = fs::read_dir(&self.chunks_path).await?;
-        let mut buf = [0u8; MAX_CHUNK_SIZE];
-        while let Some(chunk) = chunk_paths.next().await {
-            let Ok(entry) = chunk else { continue };
-            let chunk_path = entry.path();
+        Ok(total)
+    }
 
-            // Skip if we're not a plain file
-            if !chunk_path.is_file() {
+    /// Migrate the files of a legacy store, whose chunks are named after the
+    /// plain BLAKE3 hash of their contents. Legacy metadata is recognized by
+    /// its chunk hashes not making up the file hash. Such files are rebuilt
+    /// from their chunks and inserted again, which rewrites their metadata.
+    /// Files missing some of their chunks are removed. Legacy chunks are
+    /// left behind and cleaned up by [`Geode::rebuild_index`].
+    async fn migrate_legacy(&self) -> Result<()> {
+        let mut legacy_files = vec![];
+        let mut file_paths = fs::read_dir(&self.files_path).await?;
+        while let Some(file) = file_paths.next().await {
+            let Ok(entry) = file else { continue };
+            let path = entry.path();
+
+            // Leftovers of an interrupted migration are rebuilt from scratch
+            if path.extension().is_some_and(|e| e == MIGRATION_EXT) {
+                let _ = fs::remove_file(&path).await;
                 continue
             }
 
-            // Make sure that the filename is a BLAKE3 hash
-            let file_name = match chunk_path.file_name().and_then(|n| n.to_str()) {
-                Some(v) => v,
-                None => continue,
-            };
-            let chunk_hash = match blake3::Hash::from_hex(file_name) {
-                Ok(v) => v,
-                Err(_) => continue,
-            };
+            let Some(file_name) = path.file_name().and_then(|n| n.to_str()) else { continue };
+            let Ok(file_hash) = blake3::Hash::from_hex(file_name) else { continue };
+            let Ok(chunk_hashes) = Self::read_metadata(&path).await else { continue };
 
-            // If there is a problem with opening the file, remove it.
-            let Ok(mut chunk_fd) = File::open(&chunk_path).await else {
-                deleted_chunk_paths.insert(chunk_path);
-                deleted_chunks.insert(chunk_hash);
-                continue
-            };
+            if bao::file_hash(&chunk_hashes) != file_hash {
+                legacy_files.push((path, file_hash, chunk_hashes));
+            }
+        }
 
-            // Perform consistency check
-            let Ok(bytes_read) = chunk_fd.read(&mut buf).await else {
-                deleted_chunk_paths.insert(chunk_path);
-                deleted_chunks.insert(chunk_hash);
-                buf = [0u8; MAX_CHUNK_SIZE];
-                continue
-            };
+        if legacy_files.is_empty() {
+            return Ok(())
+        }
 
-            let chunk_slice = &buf[..bytes_read];
-            let hashed_chunk = blake3::hash(chunk_slice);
+        info!(
+            target: "geode::migrate_legacy()",
+            "[Geode] Migrating {} files of a legacy store", legacy_files.len(),
+        );
 
-            // If the hash doesn't match the filename, remove it.
-            if chunk_hash != hashed_chunk {
-                deleted_chunk_paths.insert(chunk_path);
-                deleted_chunks.insert(chunk_hash);
-                buf = [0u8; MAX_CHUNK_SIZE];
+        for (path, file_hash, chunk_hashes) in legacy_files {
+            // Reassemble the file next to its metadata, checking the chunks
+            // against their legacy hashes on the way.
+            let tmp_path = path.with_extension(MIGRATION_EXT);
+            let mut tmp_fd = File::create(&tmp_path).await?;
+            let mut complete = true;
+            for chunk_hash in chunk_hashes.iter() {
+                match fs::read(self.chunk_path(chunk_hash)).await {
+                    Ok(chunk) if blake3::hash(&chunk) == *chunk_hash => {
+                        tmp_fd.write_all(&chunk).await?;
+                    }
+                    _ => {
+                        complete = false;
+                        break
+                    }
+                }
+            }
+            tmp_fd.sync_all().await?;
+            drop(tmp_fd);
+
+            // The legacy metadata goes away before inserting, so its chunks
+            // aren't treated as references of the new metadata.
+            fs::remove_file(&path).await?;
+
+            if !complete {
+                warn!(
+                    target: "geode::migrate_legacy()",
+                    "[Geode] Removing incomplete legacy file {}", file_hash,
+                );
+                fs::remove_file(&tmp_path).await?;
                 continue
             }
 
-            // Seems legit.
-            buf = [0u8; MAX_CHUNK_SIZE];
-        }
+            let (new_file_hash, _) = self.insert(File::open(&tmp_path).await?).await?;
+            fs::remove_file(&tmp_path).await?;
 
-        for chunk_path in &deleted_chunk_paths {
-            if let Err(e) = fs::remove_file(chunk_path).await {
+            // Legacy file names were the BLAKE3 hash of the contents too,
+            // so anything else means the file was corrupted.
+            if new_file_hash != file_hash {
                 warn!(
-                   target: "geode::garbage_collect()",
-                   "[Geode] Garbage collect failed to remove corrupted chunk: {}", e,
+                    target: "geode::migrate_legacy()",
+                    "[Geode] Legacy file {} is corrupted, removing it", file_hash,
                 );
+                self.remove(&new_file_hash).await?;
             }
         }
 
-        // Perform health check over file metadata. For now we just ensure they
-        // have the correct format.
+        info!(target: "geode::migrate_legacy()", "[Geode] Legacy store migrated");
+        Ok(())
+    }
+
+    /// Build the chunk index with a full scan over the filesystem hierarchy.
+    /// Files are checked for a correct format and chunks are verified against
+    /// the files using them, and anything corrupted or unused is removed.
+    async fn rebuild_index(&self) -> Result<()> {
+        info!(target: "geode::rebuild_index()", "[Geode] Building chunk index");
+        self.chunk_index.clear()?;
+        self.chunk_refs.clear()?;
+        self.chunk_positions.clear()?;
+
+        // Count chunk references of the stored files. For now we just ensure
+        // they have the correct format.
         let mut file_paths = fs::read_dir(&self.files_path).await?;
         while let Some(file) = file_paths.next().await {
             let Ok(entry) = file else { continue };
@@ -227,33 +375,187 @@ impl Geode {
             }
 
             // Make sure that the filename is a BLAKE3 hash
-            let file_name = match path.file_name().and_then(|n| n.to_str()) {
-                Some(v) => v,
-                None => continue,
-            };
-            let file_hash = match blake3::Hash::from_hex(file_name) {
-                Ok(v) => v,
-                Err(_) => continue,
-            };
+            let Some(file_name) = path.file_name().and_then(|n| n.to_str()) else { continue };
+            if blake3::Hash::from_hex(file_name).is_err() {
+                continue
+            }
 
             // The filename is a BLAKE3 hash. It should contain a newline-separated
             // list of chunks which represent the full file. If that is not the case
             // we will consider it a corrupted file and delete it.
-            if Self::read_metadata(&path).await.is_err() {
-                if let Err(e) = fs::remove_file(path).await {
-                    warn!(
-                       target: "geode::garbage_collect()",
-                       "[Geode] Garbage collect failed to remove corrupted file: {}", e,
-                    );
+            match Self::read_metadata(&path).await {
+                Ok(chunk_hashes) => self.add_refs(&chunk_hashes)?,
+                Err(_) => {
+                    if let Err(e) = fs::remove_file(path).await {
+                        warn!(
+                           target: "geode::rebuild_index()",
+                           "[Geode] Failed to remove corrupted file: {}", e,
+                        );
+                    }
+                }
+            }
+        }
+
+        // Scan through available chunks and check them for consistency.
+        let mut chunk_paths = fs::read_dir(&self.chunks_path).await?;
+        while let Some(chunk) = chunk_paths.next().await {
+            let Ok(entry) = chunk else { continue };
+            let chunk_path = entry.path();
+
+            // Skip if we're not a plain file
+            if !chunk_path.is_file() {
+                continue
+            }
+
+            // Make sure that the filename is a BLAKE3 hash. Partially fetched
+            // chunks can't be trusted without the index, so they're dropped.
+            let Some(file_name) = chunk_path.file_name().and_then(|n| n.to_str()) else { continue };
+            let chunk_hash = match blake3::Hash::from_hex(file_name) {
+                Ok(v) => Some(v),
+                Err(_) if chunk_path.extension().is_some_and(|e| e == PARTIAL_CHUNK_EXT) => None,
+                Err(_) => continue,
+            };
+
+            // Index the chunk if it's legit, otherwise remove it.
+            if let Some(chunk_hash) = chunk_hash {
+                if let (Ok(chunk), Some((offset, is_root))) =
+                    (fs::read(&chunk_path).await, self.chunk_position(&chunk_hash)?)
+                {
+                    if chunk.len() <= MAX_CHUNK_SIZE &&
+                        bao::chunk_hash(&chunk, offset, is_root) == chunk_hash
+                    {
+                        self.chunk_index
+                            .insert(chunk_hash.as_bytes(), &(chunk.len() as u64).to_be_bytes())?;
+                        continue
+                    }
                 }
+            }
+
+            if let Err(e) = fs::remove_file(&chunk_path).await {
+                warn!(
+                   target: "geode::rebuild_index()",
+                   "[Geode] Failed to remove corrupted chunk: {}", e,
+                );
+            }
+        }
 
-                deleted_files.insert(file_hash);
+        info!(target: "geode::rebuild_index()", "[Geode] Chunk index built");
+        Ok(())
+    }
+
+    /// Increment the reference counts of a file's chunks, and record their
+    /// positions. A chunk used several times by the same file is only
+    /// counted once.
+    fn add_refs(&self, chunk_hashes: &[blake3::Hash]) -> Result<()> {
+        let is_root = chunk_hashes.len() == 1;
+        for (index, chunk_hash) in chunk_hashes.iter().enumerate() {
+            let mut position = bao::chunk_offset(index).to_be_bytes().to_vec();
+            position.push(is_root as u8);
+            self.chunk_positions.insert(chunk_hash.as_bytes(), position)?;
+        }
+
+        let chunk_hashes: HashSet<_> = chunk_hashes.iter().collect();
+        for chunk_hash in chunk_hashes {
+            self.chunk_refs.update_and_fetch(chunk_hash.as_bytes(), |refs| {
+                let refs = refs.map_or(0, |r| u64::from_be_bytes(r.try_into().unwrap()));
+                Some((refs + 1).to_be_bytes().to_vec())
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Decrement the reference counts of the given chunks, and delete the
+    /// chunks no longer referenced by any file. Returns the deleted chunks.
+    async fn drop_refs(&self, chunk_hashes: &[blake3::Hash]) -> Result<HashSet<blake3::Hash>> {
+        let chunk_hashes: HashSet<_> = chunk_hashes.iter().copied().collect();
+        let mut deleted_chunks = HashSet::new();
+
+        for chunk_hash in chunk_hashes {
+            let refs = self.chunk_refs.update_and_fetch(chunk_hash.as_bytes(), |refs| {
+                let refs = u64::from_be_bytes(refs?.try_into().unwrap());
+                (refs > 1).then(|| (refs - 1).to_be_bytes().to_vec())
+            })?;
+
+            if refs.is_some() {
                 continue
             }
+
+            self.chunk_index.remove(chunk_hash.as_bytes())?;
+            self.chunk_positions.remove(chunk_hash.as_bytes())?;
+            let _ = fs::remove_file(self.partial_chunk_path(&chunk_hash)).await;
+            match fs::remove_file(self.chunk_path(&chunk_hash)).await {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    warn!(
+                        target: "geode::drop_refs()",
+                        "[Geode] Failed to remove chunk {}: {}", chunk_hash, e,
+                    );
+                    continue
+                }
+            }
+
+            deleted_chunks.insert(chunk_hash);
         }
 
-        info!(target: "geode::garbage_collect()", "[Geode] Garbage collection finished");
-        Ok((deleted_files, deleted_chunks))
+        Ok(deleted_chunks)
+    }
+
+    /// Write a verified chunk to the filesystem and index it, unless we
+    /// already hold it.
+    async fn write_chunk(&self, chunk_hash: &blake3::Hash, chunk_slice: &[u8]) -> Result<()> {
+        let chunk_path = self.chunk_path(chunk_hash);
+
+        // Indexed chunks were verified when they were written, so we don't
+        // have to read them back to skip the write.
+        if self.chunk_index.contains_key(chunk_hash.as_bytes())? && chunk_path.is_file() {
+            debug!(
+                target: "geode::write_chunk()",
+                "Existing chunk indexed. Skipping write to {:?}",
+                chunk_path,
+            );
+            return Ok(())
+        }
+
+        debug!(
+            target: "geode::write_chunk()",
+            "Chunk not indexed or unavailable. Writing chunk to {:?}",
+            chunk_path,
+        );
+        let mut chunk_fd = File::create(&chunk_path).await?;
+        chunk_fd.write_all(chunk_slice).await?;
+        chunk_fd.sync_all().await?;
+
+        self.chunk_index
+            .insert(chunk_hash.as_bytes(), &(chunk_slice.len() as u64).to_be_bytes())?;
+        Ok(())
+    }
+
+    /// Write file metadata, replacing any existing one, and update the
+    /// reference counts of the chunks accordingly.
+    async fn write_metadata(
+        &self,
+        file_hash: &blake3::Hash,
+        chunk_hashes: &[blake3::Hash],
+    ) -> Result<()> {
+        let file_path = self.file_path(file_hash);
+        let old_chunk_hashes = Self::read_metadata(&file_path).await.ok();
+
+        let mut file_fd = File::create(&file_path).await?;
+        for ch in chunk_hashes {
+            file_fd.write_all(format!("{}\n", ch.to_hex().as_str()).as_bytes()).await?;
+        }
+        file_fd.sync_all().await?;
+
+        // New references are added first, so chunks shared with the
+        // old metadata don't get deleted in between.
+        self.add_refs(chunk_hashes)?;
+        if let Some(old_chunk_hashes) = old_chunk_hashes {
+            self.drop_refs(&old_chunk_hashes).await?;
+        }
+
+        Ok(())
     }
 
     /// Insert a file into Geode. The function expects any kind of byte stream, which
@@ -265,71 +567,42 @@ impl Geode {
         mut stream: impl AsyncRead + Unpin,
     ) -> Result<(blake3::Hash, Vec<blake3::Hash>)> {
         info!(target: "geode::insert()", "[Geode] Inserting file...");
-        let mut file_hasher = blake3::Hasher::new();
         let mut chunk_hashes = vec![];
-        let mut buf = [0u8; MAX_CHUNK_SIZE];
+        let mut buf = vec![0u8; MAX_CHUNK_SIZE];
+        let mut bytes_read = Self::read_chunk(&mut stream, &mut buf).await?;
 
-        while let Ok(bytes_read) = stream.read(&mut buf).await {
-            if bytes_read == 0 {
-                break
-            }
+        while bytes_read > 0 {
+            // We read one chunk ahead, as a chunk is hashed differently
+            // when it's the only one of the file.
+            let mut next_buf = vec![0u8; MAX_CHUNK_SIZE];
+            let next_bytes_read = match bytes_read {
+                MAX_CHUNK_SIZE => Self::read_chunk(&mut stream, &mut next_buf).await?,
+                _ => 0,
+            };
 
             let chunk_slice = &buf[..bytes_read];
-            let chunk_hash = blake3::hash(chunk_slice);
-            file_hasher.update(chunk_slice);
+            let is_root = chunk_hashes.is_empty() && next_bytes_read == 0;
+            let chunk_hash =
+                bao::chunk_hash(chunk_slice, bao::chunk_offset(chunk_hashes.len()), is_root);
+            self.write_chunk(&chunk_hash, chunk_slice).await?;
             chunk_hashes.push(chunk_hash);
 
-            // Write the chunk to a file, if necessary. We first perform
-            // a consistency check and if things are fine, we don't have
-            // to perform a write, which is usually more expensive than
-            // reading from disk.
-            let mut chunk_path = self.chunks_path.clone();
-            chunk_path.push(chunk_hash.to_hex().as_str());
-            let mut chunk_fd =
-                OpenOptions::new().read(true).write(true).create(true).open(&chunk_path).await?;
-
-            let mut fs_buf = [0u8; MAX_CHUNK_SIZE];
-            let fs_bytes_read = chunk_fd.read(&mut fs_buf).await?;
-            let fs_chunk_slice = &fs_buf[..fs_bytes_read];
-            let fs_chunk_hash = blake3::hash(fs_chunk_slice);
-
-            if fs_chunk_hash != chunk_hash {
-                debug!(
-                    target: "geode::insert()",
-                    "Existing chunk inconsistent or unavailable. Writing chunk to {:?}",
-                    chunk_path,
-                );
-                // Here the chunk is broken, so we'll truncate and write the new one.
-                chunk_fd.set_len(0).await?;
-                chunk_fd.seek(SeekFrom::Start(0)).await?;
-                chunk_fd.write_all(chunk_slice).await?;
-            } else {
-                debug!(
-                    target: "geode::insert()",
-                    "Existing chunk consistent. Skipping write to {:?}",
-                    chunk_path,
-                );
-            }
-
-            buf = [0u8; MAX_CHUNK_SIZE];
+            buf = next_buf;
+            bytes_read = next_bytes_read;
         }
 
-        // This hash is the file's chunks hashed in order.
-        let file_hash = file_hasher.finalize();
-        let mut file_path = self.files_path.clone();
-        file_path.push(file_hash.to_hex().as_str());
+        // This hash is the root of the file's BLAKE3 tree.
+        let file_hash = bao::file_hash(&chunk_hashes);
 
         // We always overwrite the metadata.
-        let mut file_fd = File::create(&file_path).await?;
-        for ch in &chunk_hashes {
-            file_fd.write(format!("{}\n", ch.to_hex().as_str()).as_bytes()).await?;
-        }
+        self.write_metadata(&file_hash, &chunk_hashes).await?;
 
         Ok((file_hash, chunk_hashes))
     }
 
     /// Create and insert file metadata into Geode given a list of hashes.
-    /// Always overwrites any existing file.
+    /// Always overwrites any existing file. Returns [`Error::GeodeInvalidFile`]
+    /// if the chunk hashes don't make up the given file hash.
     pub async fn insert_file(
         &self,
         file_hash: &blake3::Hash,
@@ -337,35 +610,151 @@ impl Geode {
     ) -> Result<()> {
         info!(target: "geode::insert_file()", "[Geode] Inserting file metadata");
 
-        let mut file_path = self.files_path.clone();
-        file_path.push(file_hash.to_hex().as_str());
-        let mut file_fd = File::create(&file_path).await?;
-
-        for ch in chunk_hashes {
-            file_fd.write(format!("{}\n", ch.to_hex().as_str()).as_bytes()).await?;
+        if &bao::file_hash(chunk_hashes) != file_hash {
+            return Err(Error::GeodeInvalidFile)
         }
 
-        Ok(())
+        self.write_metadata(file_hash, chunk_hashes).await
     }
 
     /// Create and insert a single chunk into Geode given a stream.
-    /// Always overwrites any existing chunk. Returns the chunk hash once inserted.
-    pub async fn insert_chunk(&self, stream: impl AsRef<[u8]>) -> Result<blake3::Hash> {
+    /// The metadata of a file using the chunk has to be inserted first,
+    /// so the chunk can be verified. Returns [`Error::GeodeInvalidChunk`]
+    /// if the stream doesn't match the chunk hash.
+    pub async fn insert_chunk(
+        &self,
+        chunk_hash: &blake3::Hash,
+        stream: impl AsRef<[u8]>,
+    ) -> Result<()> {
         info!(target: "geode::insert_chunk()", "[Geode] Inserting single chunk");
 
-        let mut cursor = Cursor::new(&stream);
-        let mut chunk = [0u8; MAX_CHUNK_SIZE];
+        let chunk_slice = stream.as_ref();
+        let Some((offset, is_root)) = self.chunk_position(chunk_hash)? else {
+            return Err(Error::GeodeInvalidChunk)
+        };
 
-        let bytes_read = cursor.read(&mut chunk).await?;
-        let chunk_slice = &chunk[..bytes_read];
-        let chunk_hash = blake3::hash(chunk_slice);
+        if chunk_slice.len() > MAX_CHUNK_SIZE ||
+            &bao::chunk_hash(chunk_slice, offset, is_root) != chunk_hash
+        {
+            return Err(Error::GeodeInvalidChunk)
+        }
 
-        let mut chunk_path = self.chunks_path.clone();
-        chunk_path.push(chunk_hash.to_hex().as_str());
-        let mut chunk_fd = File::create(&chunk_path).await?;
-        chunk_fd.write_all(chunk_slice).await?;
+        self.write_chunk(chunk_hash, chunk_slice).await
+    }
+
+    /// Return the number of bytes of a chunk that are verified and stored.
+    /// This is where fetching a partially fetched chunk should resume from.
+    /// A group that was only partly written, e.g. because we were killed
+    /// in the middle of it, is truncated away, so it's fetched again.
+    pub async fn chunk_progress(&self, chunk_hash: &blake3::Hash) -> Result<u64> {
+        if let Some(size) = self.chunk_index.get(chunk_hash.as_bytes())? {
+            return Ok(u64::from_be_bytes(size.as_ref().try_into().unwrap()))
+        }
+
+        let partial_path = self.partial_chunk_path(chunk_hash);
+        let len = match fs::metadata(&partial_path).await {
+            Ok(metadata) => metadata.len(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+
+        let progress = len - len % GROUP_SIZE as u64;
+        if progress != len {
+            warn!(
+                target: "geode::chunk_progress()",
+                "[Geode] Truncating torn group at {} of chunk {}", progress, chunk_hash,
+            );
+            let partial_fd = OpenOptions::new().write(true).open(&partial_path).await?;
+            partial_fd.set_len(progress).await?;
+            partial_fd.sync_all().await?;
+        }
+
+        Ok(progress)
+    }
+
+    /// Read a stored chunk and split it into [`ChunkGroup`]s with their
+    /// inclusion proofs, starting with the group at `from` bytes into the
+    /// chunk. Returns the chunk size along with the groups.
+    pub async fn get_chunk_groups(
+        &self,
+        chunk_hash: &blake3::Hash,
+        from: u64,
+    ) -> Result<(u64, Vec<ChunkGroup>)> {
+        info!(target: "geode::get_chunk_groups()", "[Geode] Getting groups of chunk {}", chunk_hash);
+        let chunk_path = self.get_chunk(chunk_hash).await?;
+        let Some((offset, is_root)) = self.chunk_position(chunk_hash)? else {
+            return Err(Error::GeodeChunkNotFound)
+        };
+
+        // We're hashing the chunk anyway, so make sure we don't serve
+        // a corrupted one.
+        let chunk = fs::read(&chunk_path).await?;
+        if &bao::chunk_hash(&chunk, offset, is_root) != chunk_hash {
+            warn!(
+                target: "geode::get_chunk_groups()",
+                "[Geode] Chunk {} is corrupted, evicting it from the index", chunk_hash,
+            );
+            self.chunk_index.remove(chunk_hash.as_bytes())?;
+            return Err(Error::GeodeChunkNotFound)
+        }
+
+        Ok((chunk.len() as u64, bao::chunk_groups(&chunk, offset, is_root, from)))
+    }
+
+    /// Insert a group of a chunk being fetched, after verifying it against
+    /// the chunk hash. Groups have to be inserted in order, starting from
+    /// [`Geode::chunk_progress`]. Groups we already have are skipped.
+    /// Returns `true` once the chunk is complete.
+    pub async fn insert_chunk_group(
+        &self,
+        chunk_hash: &blake3::Hash,
+        chunk_size: u64,
+        group: &ChunkGroup,
+    ) -> Result<bool> {
+        debug!(
+            target: "geode::insert_chunk_group()",
+            "Inserting group at {} of chunk {}", group.offset, chunk_hash,
+        );
+
+        if self.get_chunk(chunk_hash).await.is_ok() {
+            return Ok(true)
+        }
+
+        let Some((offset, is_root)) = self.chunk_position(chunk_hash)? else {
+            return Err(Error::GeodeInvalidChunk)
+        };
+
+        let progress = self.chunk_progress(chunk_hash).await?;
+        if group.offset < progress {
+            return Ok(false)
+        }
+
+        if group.offset > progress ||
+            !bao::verify_group(chunk_hash, offset, is_root, chunk_size, group)
+        {
+            return Err(Error::GeodeInvalidChunk)
+        }
+
+        let partial_path = self.partial_chunk_path(chunk_hash);
+        let mut partial_fd =
+            OpenOptions::new().create(true).append(true).open(&partial_path).await?;
+        partial_fd.write_all(&group.data).await?;
+        partial_fd.sync_all().await?;
+
+        if progress + (group.data.len() as u64) < chunk_size {
+            return Ok(false)
+        }
+
+        // Every group was verified, but check the whole chunk once more
+        // before moving it in place, in case the chunk size was a lie.
+        let chunk = fs::read(&partial_path).await?;
+        fs::remove_file(&partial_path).await?;
+        if &bao::chunk_hash(&chunk, offset, is_root) != chunk_hash {
+            return Err(Error::GeodeInvalidChunk)
+        }
 
-        Ok(chunk_hash)
+        self.write_chunk(chunk_hash, &chunk).await?;
+        Ok(true)
     }
 
     /// Fetch file metadata from Geode. Returns [`ChunkedFile`] which gives a list
@@ -373,18 +762,17 @@ impl Geode {
     /// the read failed in any way (could also be the file does not exist).
     pub async fn get(&self, file_hash: &blake3::Hash) -> Result<ChunkedFile> {
         info!(target: "geode::get()", "[Geode] Getting file chunks for {}...", file_hash);
-        let mut file_path = self.files_path.clone();
-        file_path.push(file_hash.to_hex().as_str());
+        let file_path = self.file_path(file_hash);
 
         // Try to read the file metadata. If it's corrupt, return an error signalling
-        // that garbage collection needs to run.
+        // that the file needs to be removed and fetched again.
         let chunk_hashes = match Self::read_metadata(&file_path).await {
             Ok(v) => v,
             Err(e) => {
                 return match e {
                     // If the file is not found, return according error.
                     Error::Io(std::io::ErrorKind::NotFound) => Err(Error::GeodeFileNotFound),
-                    // Anything else should tell the client to do garbage collection
+                    // Anything else should tell the client to clean up the file
                     _ => Err(Error::GeodeNeedsGc),
                 }
             }
@@ -392,30 +780,18 @@ impl Geode {
 
         let mut chunked_file = ChunkedFile::new(&chunk_hashes);
 
-        // Iterate over chunks and find which chunks we have available locally.
-        let mut buf = [0u8; MAX_CHUNK_SIZE];
+        // Find which chunks we have available locally, using the index.
         for (chunk_hash, chunk_path) in chunked_file.0.iter_mut() {
-            let mut c_path = self.chunks_path.clone();
-            c_path.push(chunk_hash.to_hex().as_str());
-
-            if !c_path.exists() || !c_path.is_file() {
-                // TODO: We should be aggressive here and remove the non-file.
+            if !self.chunk_index.contains_key(chunk_hash.as_bytes())? {
                 continue
             }
 
-            // Perform chunk consistency check
-            let mut chunk_fd = File::open(&c_path).await?;
-            let bytes_read = chunk_fd.read(&mut buf).await?;
-            let chunk_slice = &buf[..bytes_read];
-            let hashed_chunk = blake3::hash(chunk_slice);
-            if &hashed_chunk != chunk_hash {
-                // The chunk is corrupted/inconsistent. Garbage collection should run.
-                buf = [0u8; MAX_CHUNK_SIZE];
+            let c_path = self.chunk_path(chunk_hash);
+            if !c_path.is_file() {
                 continue
             }
 
             *chunk_path = Some(c_path);
-            buf = [0u8; MAX_CHUNK_SIZE];
         }
 
         Ok(chunked_file)
@@ -425,25 +801,244 @@ impl Geode {
     /// if it is found.
     pub async fn get_chunk(&self, chunk_hash: &blake3::Hash) -> Result<PathBuf> {
         info!(target: "geode::get_chunk()", "[Geode] Getting chunk {}", chunk_hash);
-        let mut chunk_path = self.chunks_path.clone();
-        chunk_path.push(chunk_hash.to_hex().as_str());
+        let chunk_path = self.chunk_path(chunk_hash);
 
-        if !chunk_path.exists() || !chunk_path.is_file() {
-            // TODO: We should be aggressive here and remove the non-file.
+        if !self.chunk_index.contains_key(chunk_hash.as_bytes())? || !chunk_path.is_file() {
             return Err(Error::GeodeChunkNotFound)
         }
 
-        // Perform chunk consistency check
-        let mut buf = [0u8; MAX_CHUNK_SIZE];
-        let mut chunk_fd = File::open(&chunk_path).await?;
-        let bytes_read = chunk_fd.read(&mut buf).await?;
-        let chunk_slice = &buf[..bytes_read];
-        let hashed_chunk = blake3::hash(chunk_slice);
-        if &hashed_chunk != chunk_hash {
-            // The chunk is corrupted
-            return Err(Error::GeodeNeedsGc)
+        Ok(chunk_path)
+    }
+
+    /// Open a [`GeodeReader`] over a stored file, which supports streaming
+    /// and seeking to arbitrary byte ranges. Chunks are loaded lazily as the
+    /// reader reaches them. Returns [`Error::GeodeChunkNotFound`] if the file
+    /// is missing any chunks locally.
+    pub async fn reader(&self, file_hash: &blake3::Hash) -> Result<GeodeReader> {
+        info!(target: "geode::reader()", "[Geode] Opening reader for {}", file_hash);
+        let chunked_file = self.get(file_hash).await?;
+
+        let is_root = chunked_file.0.len() == 1;
+        let mut chunks = Vec::with_capacity(chunked_file.0.len());
+        let mut offset = 0;
+        for (hash, path) in chunked_file.0 {
+            let Some(path) = path else { return Err(Error::GeodeChunkNotFound) };
+            let Some(size) = self.chunk_index.get(hash.as_bytes())? else {
+                return Err(Error::GeodeChunkNotFound)
+            };
+            let size = u64::from_be_bytes(size.as_ref().try_into().unwrap());
+
+            chunks.push(ReaderChunk { hash, path, offset, size, is_root });
+            offset += size;
         }
 
-        Ok(chunk_path)
+        Ok(GeodeReader::new(chunks, self.chunk_index.clone()))
+    }
+
+    /// Remove a file from Geode. Chunks are reference counted, so only the
+    /// chunks no longer used by any other file get deleted.
+    /// Returns the set of deleted chunks.
+    pub async fn remove(&self, file_hash: &blake3::Hash) -> Result<HashSet<blake3::Hash>> {
+        info!(target: "geode::remove()", "[Geode] Removing file {}", file_hash);
+        let file_path = self.file_path(file_hash);
+
+        let chunk_hashes = match Self::read_metadata(&file_path).await {
+            Ok(v) => v,
+            Err(Error::Io(std::io::ErrorKind::NotFound)) => return Err(Error::GeodeFileNotFound),
+            Err(_) => {
+                // We can't tell which chunks corrupted metadata referenced,
+                // so their references are left in place.
+                warn!(
+                    target: "geode::remove()",
+                    "[Geode] Metadata of {} is corrupted, removing it", file_hash,
+                );
+                vec![]
+            }
+        };
+
+        fs::remove_file(&file_path).await?;
+        let deleted_chunks = self.drop_refs(&chunk_hashes).await?;
+
+        info!(
+            target: "geode::remove()",
+            "[Geode] Removed file {} and {} unused chunks", file_hash, deleted_chunks.len(),
+        );
+        Ok(deleted_chunks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smol::io::{AsyncSeekExt, Cursor, SeekFrom};
+
+    #[test]
+    fn geode_reader_and_remove() -> Result<()> {
+        let base_path = std::env::temp_dir().join(format!("geode_test_{}", std::process::id()));
+
+        smol::block_on(async {
+            let geode = Geode::new(&base_path).await?;
+
+            // Two files sharing their first chunk
+            let shared = vec![1u8; MAX_CHUNK_SIZE];
+            let file_a: Vec<u8> = [shared.clone(), vec![2u8; 1000]].concat();
+            let file_b: Vec<u8> = [shared, vec![3u8; 5000]].concat();
+            let (hash_a, chunks_a) = geode.insert(Cursor::new(&file_a)).await?;
+            let (hash_b, chunks_b) = geode.insert(Cursor::new(&file_b)).await?;
+            assert_eq!(chunks_a[0], chunks_b[0]);
+            assert!(geode.get(&hash_a).await?.is_complete());
+
+            // Read a range crossing the chunk boundary
+            let mut reader = geode.reader(&hash_a).await?;
+            assert_eq!(reader.size(), file_a.len() as u64);
+            reader.seek(SeekFrom::Start(MAX_CHUNK_SIZE as u64 - 10)).await?;
+            let mut buf = vec![0u8; 20];
+            reader.read_exact(&mut buf).await?;
+            assert_eq!(buf, file_a[MAX_CHUNK_SIZE - 10..MAX_CHUNK_SIZE + 10]);
+
+            // The shared chunk is kept until the last file using it is removed
+            let deleted = geode.remove(&hash_a).await?;
+            assert_eq!(deleted, HashSet::from([chunks_a[1]]));
+            assert!(matches!(geode.get(&hash_a).await, Err(Error::GeodeFileNotFound)));
+
+            let mut contents = vec![];
+            geode.reader(&hash_b).await?.read_to_end(&mut contents).await?;
+            assert_eq!(contents, file_b);
+
+            let deleted = geode.remove(&hash_b).await?;
+            assert_eq!(deleted, chunks_b.into_iter().collect::<HashSet<_>>());
+            assert!(matches!(geode.get_chunk(&chunks_a[0]).await, Err(Error::GeodeChunkNotFound)));
+
+            Ok::<_, Error>(())
+        })?;
+
+        std::fs::remove_dir_all(base_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn geode_legacy_migration() -> Result<()> {
+        let base_path = std::env::temp_dir().join(format!("geode_legacy_{}", std::process::id()));
+
+        smol::block_on(async {
+            // Lay out a legacy store by hand, with chunks named after their
+            // plain BLAKE3 hash. The second file is missing a chunk.
+            let files_path = base_path.join(FILES_PATH);
+            let chunks_path = base_path.join(CHUNKS_PATH);
+            fs::create_dir_all(&files_path).await?;
+            fs::create_dir_all(&chunks_path).await?;
+
+            let write_legacy = |contents: Vec<u8>, skip_chunk: Option<usize>| {
+                let files_path = files_path.clone();
+                let chunks_path = chunks_path.clone();
+                async move {
+                    let mut metadata = String::new();
+                    for (i, chunk) in contents.chunks(MAX_CHUNK_SIZE).enumerate() {
+                        let chunk_hash = blake3::hash(chunk);
+                        metadata.push_str(&format!("{}\n", chunk_hash.to_hex()));
+                        if skip_chunk != Some(i) {
+                            fs::write(chunks_path.join(chunk_hash.to_hex().as_str()), chunk)
+                                .await?;
+                        }
+                    }
+                    let file_hash = blake3::hash(&contents);
+                    fs::write(files_path.join(file_hash.to_hex().as_str()), metadata).await?;
+                    Ok::<_, Error>(file_hash)
+                }
+            };
+
+            let file_a: Vec<u8> = (0..MAX_CHUNK_SIZE * 2 + 1000).map(|i| i as u8).collect();
+            let file_b = vec![7u8; MAX_CHUNK_SIZE + 10];
+            let hash_a = write_legacy(file_a.clone(), None).await?;
+            let hash_b = write_legacy(file_b, Some(1)).await?;
+
+            // Complete files are migrated and keep their hash
+            let geode = Geode::new(&base_path).await?;
+            assert!(geode.get(&hash_a).await?.is_complete());
+            let mut contents = vec![];
+            geode.reader(&hash_a).await?.read_to_end(&mut contents).await?;
+            assert_eq!(contents, file_a);
+
+            // Incomplete ones can't be verified and are removed
+            assert!(matches!(geode.get(&hash_b).await, Err(Error::GeodeFileNotFound)));
+
+            // Legacy chunks are gone
+            let mut chunks = fs::read_dir(&chunks_path).await?;
+            let mut n_chunks = 0;
+            while let Some(chunk) = chunks.next().await {
+                chunk?;
+                n_chunks += 1;
+            }
+            assert_eq!(n_chunks, 3);
+
+            Ok::<_, Error>(())
+        })?;
+
+        std::fs::remove_dir_all(base_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn geode_verified_chunk_groups() -> Result<()> {
+        let base_path = std::env::temp_dir().join(format!("geode_groups_{}", std::process::id()));
+
+        smol::block_on(async {
+            let src = Geode::new(&base_path.join("src")).await?;
+            let dst = Geode::new(&base_path.join("dst")).await?;
+
+            let file: Vec<u8> = (0..MAX_CHUNK_SIZE + 40_000).map(|i| (i % 251) as u8).collect();
+            let (file_hash, chunk_hashes) = src.insert(Cursor::new(&file)).await?;
+            assert_eq!(file_hash, blake3::hash(&file));
+
+            // Metadata has to match the file hash
+            let mut bad_hashes = chunk_hashes.clone();
+            bad_hashes.swap(0, 1);
+            assert!(matches!(
+                dst.insert_file(&file_hash, &bad_hashes).await,
+                Err(Error::GeodeInvalidFile)
+            ));
+            dst.insert_file(&file_hash, &chunk_hashes).await?;
+
+            for chunk_hash in &chunk_hashes {
+                let (chunk_size, groups) = src.get_chunk_groups(chunk_hash, 0).await?;
+
+                // Tampered groups are rejected
+                let mut bad_group = groups[0].clone();
+                bad_group.data[0] ^= 1;
+                assert!(matches!(
+                    dst.insert_chunk_group(chunk_hash, chunk_size, &bad_group).await,
+                    Err(Error::GeodeInvalidChunk)
+                ));
+
+                // Transfer the first group, then resume from where we stopped
+                assert!(!dst.insert_chunk_group(chunk_hash, chunk_size, &groups[0]).await?);
+                let progress = dst.chunk_progress(chunk_hash).await?;
+                assert_eq!(progress, GROUP_SIZE as u64);
+
+                // A group torn by a crash is fetched again
+                let partial_path = dst.partial_chunk_path(chunk_hash);
+                let mut partial_fd = OpenOptions::new().append(true).open(&partial_path).await?;
+                partial_fd.write_all(&groups[1].data[..100]).await?;
+                partial_fd.sync_all().await?;
+                assert_eq!(dst.chunk_progress(chunk_hash).await?, progress);
+                assert_eq!(fs::metadata(&partial_path).await?.len(), progress);
+
+                let (_, groups) = src.get_chunk_groups(chunk_hash, progress).await?;
+                let mut complete = false;
+                for group in &groups {
+                    complete = dst.insert_chunk_group(chunk_hash, chunk_size, group).await?;
+                }
+                assert!(complete);
+            }
+
+            let mut contents = vec![];
+            dst.reader(&file_hash).await?.read_to_end(&mut contents).await?;
+            assert_eq!(contents, file);
+
+            Ok::<_, Error>(())
+        })?;
+
+        std::fs::remove_dir_all(base_path)?;
+        Ok(())
     }
 }
@@ -0,0 +1,204 @@
+/* This file is part of DarkFi (https://dark.fi)
+ *
+ * Copyright (C) 2020-2024 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Streaming reads over files stored in [`Geode`](super::Geode).
+
+use std::{
+    future::Future,
+    io,
+    path::PathBuf,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::{AsyncRead, AsyncSeek};
+use log::warn;
+use sled_overlay::sled;
+use smol::{fs, io::SeekFrom};
+
+use super::bao;
+
+/// Future loading a chunk from the filesystem
+type ChunkFuture = Pin<Box<dyn Future<Output = io::Result<Vec<u8>>> + Send>>;
+
+/// A chunk of a stored file, as seen by [`GeodeReader`]
+pub(super) struct ReaderChunk {
+    /// BLAKE3 hash of the chunk's contents
+    pub hash: blake3::Hash,
+    /// Path to the chunk on the filesystem
+    pub path: PathBuf,
+    /// Offset of the chunk within the file
+    pub offset: u64,
+    /// Size of the chunk
+    pub size: u64,
+    /// Whether the chunk is the only one of the file
+    pub is_root: bool,
+}
+
+/// `AsyncRead + AsyncSeek` reader over a file stored in Geode.
+///
+/// Chunks are only loaded from the filesystem once a read reaches them,
+/// and only one chunk is kept in memory at a time. Every loaded chunk is
+/// verified against its hash. A corrupted chunk is evicted from the index,
+/// so it shows up as missing and can be fetched again, and the read fails
+/// with [`io::ErrorKind::InvalidData`].
+pub struct GeodeReader {
+    /// The file's chunks in order
+    chunks: Vec<ReaderChunk>,
+    /// Geode's chunk index
+    chunk_index: sled::Tree,
+    /// Total size of the file
+    size: u64,
+    /// Current position within the file
+    position: u64,
+    /// The chunk currently in memory, along with its index in `chunks`
+    current: Option<(usize, Vec<u8>)>,
+    /// The chunk currently being loaded, along with its index in `chunks`
+    loading: Option<(usize, ChunkFuture)>,
+}
+
+impl GeodeReader {
+    pub(super) fn new(chunks: Vec<ReaderChunk>, chunk_index: sled::Tree) -> Self {
+        let size = chunks.last().map_or(0, |c| c.offset + c.size);
+        Self { chunks, chunk_index, size, position: 0, current: None, loading: None }
+    }
+
+    /// Total size of the file in bytes
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Return the index of the chunk containing the given position.
+    /// The position must be within the file.
+    fn chunk_at(&self, position: u64) -> usize {
+        self.chunks.partition_point(|c| c.offset + c.size <= position)
+    }
+
+    /// Read a chunk from the filesystem and verify its contents.
+    /// Corrupted chunks are removed from the index.
+    async fn load_chunk(
+        hash: blake3::Hash,
+        path: PathBuf,
+        offset: u64,
+        is_root: bool,
+        chunk_index: sled::Tree,
+    ) -> io::Result<Vec<u8>> {
+        let data = fs::read(&path).await?;
+        if bao::chunk_hash(&data, offset, is_root) != hash {
+            warn!(
+                target: "geode::reader::load_chunk()",
+                "[Geode] Chunk {} is corrupted, evicting it from the index", hash,
+            );
+            if let Err(e) = chunk_index.remove(hash.as_bytes()) {
+                warn!(
+                    target: "geode::reader::load_chunk()",
+                    "[Geode] Failed to evict corrupted chunk {}: {}", hash, e,
+                );
+            }
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Geode chunk is corrupted"))
+        }
+
+        Ok(data)
+    }
+}
+
+impl AsyncRead for GeodeReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        loop {
+            if this.position >= this.size || buf.is_empty() {
+                return Poll::Ready(Ok(0))
+            }
+
+            let idx = this.chunk_at(this.position);
+
+            // Serve the read from the chunk in memory if it's the right one
+            if let Some((current, data)) = &this.current {
+                if *current == idx {
+                    let start = (this.position - this.chunks[idx].offset) as usize;
+                    let n = buf.len().min(data.len() - start);
+                    buf[..n].copy_from_slice(&data[start..start + n]);
+                    this.position += n as u64;
+                    return Poll::Ready(Ok(n))
+                }
+            }
+
+            // Otherwise start loading it, unless we already are
+            let chunk = &this.chunks[idx];
+            match &mut this.loading {
+                Some((loading, fut)) if *loading == idx => match fut.as_mut().poll(cx) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(result) => {
+                        this.loading = None;
+                        let data = result?;
+                        if data.len() as u64 != chunk.size {
+                            return Poll::Ready(Err(io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                "Geode chunk size mismatch",
+                            )))
+                        }
+                        this.current = Some((idx, data));
+                    }
+                },
+
+                _ => {
+                    let fut = Self::load_chunk(
+                        chunk.hash,
+                        chunk.path.clone(),
+                        chunk.offset,
+                        chunk.is_root,
+                        this.chunk_index.clone(),
+                    );
+                    this.loading = Some((idx, Box::pin(fut)));
+                }
+            }
+        }
+    }
+}
+
+impl AsyncSeek for GeodeReader {
+    fn poll_seek(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        pos: SeekFrom,
+    ) -> Poll<io::Result<u64>> {
+        let this = self.get_mut();
+
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => this.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => this.position.checked_add_signed(offset),
+        };
+
+        let Some(position) = position else {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid seek to a negative or overflowing position",
+            )))
+        };
+
+        // Seeking past the end is allowed, reads will return EOF.
+        this.position = position;
+        Poll::Ready(Ok(position))
+    }
+}
@@ -27,6 +27,9 @@ pub mod blockchain;
 #[cfg(feature = "validator")]
 pub mod validator;
 
+#[cfg(feature = "dht")]
+pub mod dht;
+
 #[cfg(feature = "geode")]
 pub mod geode;
 
@@ -17,6 +17,7 @@
  */
 
 use std::{
+    collections::HashMap,
     fmt,
     sync::{
         atomic::{AtomicBool, Ordering::SeqCst},
@@ -41,7 +42,10 @@ use super::{
     dnet::{self, dnetev, DnetEvent},
     hosts::HostColor,
     message,
-    message::{SerializedMessage, VersionMessage, MAGIC_BYTES},
+    message::{
+        compress_payload, SerializedMessage, VersionMessage, COMPRESSION_THRESHOLD, MAGIC_BYTES,
+        MAGIC_BYTES_COMPRESSED, NAMESPACE_SEPARATOR,
+    },
     message_publisher::{MessageSubscription, MessageSubsystem},
     p2p::P2pPtr,
     session::{
@@ -74,12 +78,23 @@ impl ChannelInfo {
     }
 }
 
+/// What a channel sends and receives its messages over
+enum ChannelStream {
+    /// A transport stream split into its reading and writing halves
+    Transport {
+        reader: Mutex<ReadHalf<Box<dyn PtStream>>>,
+        writer: Mutex<WriteHalf<Box<dyn PtStream>>>,
+    },
+    /// A namespace multiplexed over the stream of a parent channel
+    Namespace { parent: ChannelPtr, namespace: String },
+}
+
 /// Async channel for communication between nodes.
 pub struct Channel {
-    /// The reading half of the transport stream
-    reader: Mutex<ReadHalf<Box<dyn PtStream>>>,
-    /// The writing half of the transport stream
-    writer: Mutex<WriteHalf<Box<dyn PtStream>>>,
+    /// The stream this channel communicates over
+    stream: ChannelStream,
+    /// Namespaced channels multiplexed over this one
+    namespaces: Mutex<HashMap<String, ChannelPtr>>,
     /// The message subsystem instance for this channel
     message_subsystem: MessageSubsystem,
     /// Publisher listening for stop signal for closing this channel
@@ -88,6 +103,8 @@ pub struct Channel {
     receive_task: StoppableTaskPtr,
     /// A boolean marking if this channel is stopped
     stopped: AtomicBool,
+    /// A boolean marking if both sides negotiated message compression
+    compression: AtomicBool,
     /// Weak pointer to respective session
     pub(in crate::net) session: SessionWeakPtr,
     /// The version message of the node we are connected to.
@@ -120,18 +137,49 @@ impl Channel {
         let info = ChannelInfo::new(resolve_addr, connect_addr.clone(), start_time);
 
         Arc::new(Self {
-            reader,
-            writer,
+            stream: ChannelStream::Transport { reader, writer },
+            namespaces: Mutex::new(HashMap::new()),
             message_subsystem,
             stop_publisher: Publisher::new(),
             receive_task: StoppableTask::new(),
             stopped: AtomicBool::new(false),
+            compression: AtomicBool::new(false),
             session,
             version,
             info,
         })
     }
 
+    /// Sets up a new channel for `namespace`, multiplexed over `parent`.
+    /// Messages sent over it get the namespace prepended to their command,
+    /// and the parent's receive loop routes them back to its message
+    /// subsystem. The channel shares the parent's debug info, and stops
+    /// along with it.
+    pub(in crate::net) async fn new_namespaced(
+        parent: ChannelPtr,
+        namespace: String,
+        session: SessionWeakPtr,
+    ) -> Arc<Self> {
+        let channel = Arc::new(Self {
+            stream: ChannelStream::Namespace {
+                parent: parent.clone(),
+                namespace: namespace.clone(),
+            },
+            namespaces: Mutex::new(HashMap::new()),
+            message_subsystem: MessageSubsystem::new(),
+            stop_publisher: Publisher::new(),
+            receive_task: StoppableTask::new(),
+            stopped: AtomicBool::new(false),
+            compression: AtomicBool::new(false),
+            session,
+            version: Mutex::new(None),
+            info: parent.info.clone(),
+        });
+
+        parent.namespaces.lock().await.insert(namespace, channel.clone());
+        channel
+    }
+
     /// Perform network handshake for message subsystem dispatchers.
     async fn setup_dispatchers(subsystem: &MessageSubsystem) {
         subsystem.add_dispatch::<message::VersionMessage>().await;
@@ -228,10 +276,38 @@ impl Channel {
 
     /// Sends the encoded payload of provided `SerializedMessage` by writing
     /// the data to the channel async stream.
+    ///
+    /// If the message is compressible, the peer negotiated compression and
+    /// the payload is large enough, the payload is compressed and the packet
+    /// is marked with the compressed magic bytes. Its decompressed length
+    /// is written in front of the compressed data.
     async fn send_message(&self, message: &SerializedMessage) -> Result<()> {
         assert!(!message.command.is_empty());
 
-        let stream = &mut *self.writer.lock().await;
+        let writer = match &self.stream {
+            ChannelStream::Transport { writer, .. } => writer,
+            ChannelStream::Namespace { parent, namespace } => {
+                // Namespaced messages go out over the parent channel,
+                // which takes care of compressing them.
+                let message = SerializedMessage {
+                    command: format!("{}{}{}", namespace, NAMESPACE_SEPARATOR, message.command),
+                    payload: message.payload.clone(),
+                    compressible: message.compressible,
+                };
+                return parent.send_serialized(&message).await
+            }
+        };
+
+        let compressed = if message.compressible &&
+            self.is_compressed() &&
+            message.payload.len() >= COMPRESSION_THRESHOLD
+        {
+            Some(compress_payload(&message.payload))
+        } else {
+            None
+        };
+
+        let stream = &mut *writer.lock().await;
         let mut written: usize = 0;
 
         dnetev!(self, SendMessage, {
@@ -241,7 +317,11 @@ impl Channel {
         });
 
         trace!(target: "net::channel::send_message()", "Sending magic...");
-        written += MAGIC_BYTES.encode_async(stream).await?;
+        if compressed.is_some() {
+            written += MAGIC_BYTES_COMPRESSED.encode_async(stream).await?;
+        } else {
+            written += MAGIC_BYTES.encode_async(stream).await?;
+        }
         trace!(target: "net::channel::send_message()", "Sent magic");
 
         trace!(target: "net::channel::send_message()", "Sending command...");
@@ -249,11 +329,20 @@ impl Channel {
         trace!(target: "net::channel::send_message()", "Sent command: {}", message.command);
 
         trace!(target: "net::channel::send_message()", "Sending payload...");
-        // First extract the length of the payload as a VarInt and write it to the stream.
-        written += VarInt(message.payload.len() as u64).encode_async(stream).await?;
-        // Then write the encoded payload itself to the stream.
-        stream.write_all(&message.payload).await?;
-        written += message.payload.len();
+        if let Some(compressed) = compressed {
+            // Write the decompressed length, so the receiver can bound its
+            // allocation, followed by the compressed length and data.
+            written += VarInt(message.payload.len() as u64).encode_async(stream).await?;
+            written += VarInt(compressed.len() as u64).encode_async(stream).await?;
+            stream.write_all(&compressed).await?;
+            written += compressed.len();
+        } else {
+            // First extract the length of the payload as a VarInt and write it to the stream.
+            written += VarInt(message.payload.len() as u64).encode_async(stream).await?;
+            // Then write the encoded payload itself to the stream.
+            stream.write_all(&message.payload).await?;
+            written += message.payload.len();
+        }
 
         trace!(target: "net::channel::send_message()", "Sent payload {} bytes, total bytes {}",
             message.payload.len(), written);
@@ -263,15 +352,17 @@ impl Channel {
         Ok(())
     }
 
-    /// Returns a decoded Message command. We start by extracting the length
-    /// from the stream, then allocate the precise buffer for this length
-    /// using stream.take(). This manual deserialization provides a basic
-    /// DDOS protection, since it prevents nodes from sending an arbitarily
-    /// large payload.
+    /// Returns a decoded Message command, along with a flag marking whether
+    /// the payload that follows is compressed, which is only accepted if
+    /// compression was negotiated on this channel. We start by extracting the
+    /// length from the stream, then allocate the precise buffer for this
+    /// length using stream.take(). This manual deserialization provides a
+    /// basic DDOS protection, since it prevents nodes from sending an
+    /// arbitarily large payload.
     pub async fn read_command<R: AsyncRead + Unpin + Send + Sized>(
         &self,
         stream: &mut R,
-    ) -> Result<String> {
+    ) -> Result<(String, bool)> {
         // Messages should have a 4 byte header of magic digits.
         // This is used for network debugging.
         let mut magic = [0u8; 4];
@@ -279,10 +370,22 @@ impl Channel {
         stream.read_exact(&mut magic).await?;
 
         trace!(target: "net::channel::read_command()", "Read magic {:?}", magic);
-        if magic != MAGIC_BYTES {
-            error!(target: "net::channel::read_command", "Error: Magic bytes mismatch");
-            return Err(Error::MalformedPacket)
-        }
+        let compressed = match magic {
+            MAGIC_BYTES => false,
+            // Only peers we negotiated compression with may send these
+            MAGIC_BYTES_COMPRESSED if self.is_compressed() => true,
+            MAGIC_BYTES_COMPRESSED => {
+                error!(
+                    target: "net::channel::read_command",
+                    "Error: Compressed packet without negotiated compression"
+                );
+                return Err(Error::MalformedPacket)
+            }
+            _ => {
+                error!(target: "net::channel::read_command", "Error: Magic bytes mismatch");
+                return Err(Error::MalformedPacket)
+            }
+        };
 
         // First extract the length from the stream
         let cmd_len = VarInt::decode_async(stream).await?.0;
@@ -296,7 +399,24 @@ impl Channel {
 
         let command = String::from_utf8(bytes)?;
 
-        Ok(command)
+        Ok((command, compressed))
+    }
+
+    /// Reads the payload of a message we have no use for off the stream
+    /// and discards it.
+    async fn skip_payload<R: AsyncRead + Unpin + Send + Sized>(
+        stream: &mut R,
+        compressed: bool,
+    ) -> Result<()> {
+        // Compressed payloads are prefixed with their decompressed length
+        if compressed {
+            VarInt::decode_async(stream).await?;
+        }
+
+        let len = VarInt::decode_async(stream).await?.0;
+        io::copy(stream.take(len), &mut io::sink()).await?;
+
+        Ok(())
     }
 
     /// Subscribe to a message on the message subsystem.
@@ -323,6 +443,21 @@ impl Channel {
 
         self.stopped.store(true, SeqCst);
 
+        // Namespaced channels can't outlive the channel they're multiplexed over
+        let namespaced: Vec<ChannelPtr> =
+            self.namespaces.lock().await.drain().map(|(_, channel)| channel).collect();
+        for channel in namespaced {
+            channel.stop().await;
+        }
+
+        // Stop routing messages to us if we got stopped on our own
+        if let ChannelStream::Namespace { parent, namespace } = &self.stream {
+            let mut namespaces = parent.namespaces.lock().await;
+            if namespaces.get(namespace).is_some_and(|c| Arc::ptr_eq(c, &self)) {
+                namespaces.remove(namespace);
+            }
+        }
+
         match result {
             Ok(()) => panic!("Channel task should never complete without error status"),
             // Send this error to all channel subscribers
@@ -339,13 +474,25 @@ impl Channel {
     async fn main_receive_loop(self: Arc<Self>) -> Result<()> {
         debug!(target: "net::channel::main_receive_loop()", "[START] {:?}", self);
 
+        let reader = match &self.stream {
+            ChannelStream::Transport { reader, .. } => reader,
+            ChannelStream::Namespace { parent, .. } => {
+                // Namespaced channels are fed by the receive loop of their
+                // parent, so we just wait for it to stop.
+                if let Ok(stop_sub) = parent.subscribe_stop().await {
+                    stop_sub.receive().await;
+                }
+                return Err(Error::ChannelStopped)
+            }
+        };
+
         // Acquire reader lock
-        let reader = &mut *self.reader.lock().await;
+        let reader = &mut *reader.lock().await;
 
         // Run loop
         loop {
-            let command = match self.read_command(reader).await {
-                Ok(command) => command,
+            let (command, compressed) = match self.read_command(reader).await {
+                Ok(v) => v,
                 Err(err) => {
                     if Self::is_eof_error(&err) {
                         info!(
@@ -378,11 +525,53 @@ impl Channel {
                 time: NanoTimestamp::current_time(),
             });
 
-            // Send result to our publishers
-            match self.message_subsystem.notify(&command, reader).await {
+            // Send result to our publishers, or the ones of the namespaced
+            // channel the message is meant for.
+            let result = match command.split_once(NAMESPACE_SEPARATOR) {
+                Some((namespace, command)) => {
+                    let channel = self.namespaces.lock().await.get(namespace).cloned();
+                    match channel {
+                        Some(channel) => {
+                            match channel
+                                .message_subsystem
+                                .notify(command, compressed, reader)
+                                .await
+                            {
+                                // The peer might run a newer version of the namespace
+                                // app, so like unknown namespaces this doesn't ban the
+                                // peer. We skip the payload and keep the namespace open.
+                                Err(Error::MissingDispatcher) => {
+                                    debug!(
+                                        target: "net::channel::main_receive_loop()",
+                                        "Skipping message {} for namespace {} on {:?}, missing dispatcher",
+                                        command, namespace, self,
+                                    );
+                                    Self::skip_payload(reader, compressed).await
+                                }
+                                res => res,
+                            }
+                        }
+                        None => {
+                            // We might have closed the namespace on our side,
+                            // so this isn't necessarily misbehaviour.
+                            debug!(
+                                target: "net::channel::main_receive_loop()",
+                                "Skipping message for unknown namespace {} on {:?}",
+                                namespace, self,
+                            );
+                            Self::skip_payload(reader, compressed).await
+                        }
+                    }
+                }
+                None => self.message_subsystem.notify(&command, compressed, reader).await,
+            };
+
+            match result {
                 Ok(()) => {}
                 // If we're getting messages without dispatchers, it's spam.
-                Err(Error::MissingDispatcher) => {
+                // Compressed payloads that fail to decompress or exceed the
+                // size limit are treated the same way.
+                Err(Error::MissingDispatcher) | Err(Error::MalformedPacket) => {
                     debug!(target: "net::channel::main_receive_loop()", "Stopping channel {:?}", self);
                     if let BanPolicy::Strict = self.p2p().settings().read().await.ban_policy {
                         self.ban(self.address()).await;
@@ -390,6 +579,8 @@ impl Channel {
 
                     return Err(Error::ChannelStopped)
                 }
+                // Stream errors while reading the payload
+                Err(Error::Io(_)) => return Err(Error::ChannelStopped),
                 Err(_) => unreachable!("You added a new error in notify()"),
             }
         }
@@ -465,6 +656,41 @@ impl Channel {
         *self.version.lock().await = Some(version);
     }
 
+    /// Returns true if the peer advertised the given feature version in
+    /// its version message. On a namespaced channel the feature has to be
+    /// advertised for our namespace. Returns false before the version
+    /// exchange has occurred.
+    pub async fn has_feature(&self, name: &str, version: u32) -> bool {
+        let Some(peer_version) = self.version.lock().await.clone() else { return false };
+
+        let name = match self.namespace() {
+            Some(namespace) => format!("{}{}{}", namespace, NAMESPACE_SEPARATOR, name),
+            None => name.to_string(),
+        };
+
+        peer_version.features.iter().any(|(feature, v)| feature == &name && *v == version)
+    }
+
+    /// Enable or disable payload compression on this channel. Called
+    /// by `ProtocolVersion` once the peer advertised support for it.
+    pub(crate) fn set_compression(&self, enabled: bool) {
+        self.compression.store(enabled, SeqCst);
+    }
+
+    /// Returns true if compressible messages are compressed on this channel.
+    pub fn is_compressed(&self) -> bool {
+        self.compression.load(SeqCst)
+    }
+
+    /// Returns the namespace this channel was opened for, if it is
+    /// multiplexed over another channel.
+    pub fn namespace(&self) -> Option<&str> {
+        match &self.stream {
+            ChannelStream::Transport { .. } => None,
+            ChannelStream::Namespace { namespace, .. } => Some(namespace),
+        }
+    }
+
     /// Returns the inner [`MessageSubsystem`] reference
     pub fn message_subsystem(&self) -> &MessageSubsystem {
         &self.message_subsystem
@@ -493,6 +719,15 @@ impl Channel {
 
 impl fmt::Debug for Channel {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "<Channel addr='{}' id={}>", self.address(), self.info.id)
+        match self.namespace() {
+            Some(namespace) => write!(
+                f,
+                "<Channel addr='{}' id={} namespace='{}'>",
+                self.address(),
+                self.info.id,
+                namespace
+            ),
+            None => write!(f, "<Channel addr='{}' id={}>", self.address(), self.info.id),
+        }
     }
 }
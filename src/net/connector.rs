@@ -67,6 +67,8 @@ impl Connector {
         let transport_mixing = settings.transport_mixing;
         let datastore = settings.p2p_datastore.clone();
         let outbound_connect_timeout = settings.outbound_connect_timeout;
+        #[cfg(any(test, feature = "p2p-sim"))]
+        let sim_source = settings.inbound_addrs.iter().find(|addr| addr.scheme() == "sim").cloned();
         drop(settings);
 
         let mut endpoint = url.clone();
@@ -84,7 +86,12 @@ impl Connector {
             }
         }
 
-        let dialer = Dialer::new(endpoint.clone(), datastore).await?;
+        #[cfg_attr(not(any(test, feature = "p2p-sim")), allow(unused_mut))]
+        let mut dialer = Dialer::new(endpoint.clone(), datastore).await?;
+        #[cfg(any(test, feature = "p2p-sim"))]
+        if let Some(source) = sim_source {
+            dialer.set_sim_source(&source);
+        }
         let timeout = Duration::from_secs(outbound_connect_timeout);
 
         let stop_fut = async {
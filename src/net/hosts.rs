@@ -1325,6 +1325,14 @@ impl Hosts {
                     );
                 }
 
+                #[cfg(any(test, feature = "p2p-sim"))]
+                "sim" => {
+                    trace!(
+                        target: "net::hosts::filter_addresses",
+                        "[Sim] Valid: {}", host_str,
+                    );
+                }
+
                 _ => continue,
             }
 
@@ -21,22 +21,65 @@ use darkfi_serial::{
 };
 use url::Url;
 
+use crate::{Error, Result};
+
 pub(in crate::net) const MAGIC_BYTES: [u8; 4] = [0xd9, 0xef, 0xb6, 0x7d];
 
+/// Magic bytes marking a packet whose payload is compressed. These are
+/// only ever sent to peers that advertised [`COMPRESSION_FEATURE`] in
+/// their `VersionMessage`, so older nodes never receive them.
+pub(in crate::net) const MAGIC_BYTES_COMPRESSED: [u8; 4] = [0xd9, 0xef, 0xb6, 0x7e];
+
+/// Feature name advertised in `VersionMessage::features` when a node
+/// is able to decode compressed packets.
+pub const COMPRESSION_FEATURE: &str = "compression";
+
+/// Version of the compression feature. Bumped when the codec changes.
+pub const COMPRESSION_VERSION: u32 = 1;
+
+/// Prefix of the feature names advertised in `VersionMessage::features`
+/// for each namespaced network a node multiplexes over its channels.
+/// The feature version encodes the MAJOR and MINOR of the namespace's
+/// app version, which have to match for the namespace to be opened.
+pub const NAMESPACE_FEATURE_PREFIX: &str = "namespace/";
+
+/// Separator between the namespace and the command of messages sent
+/// over namespaced channels.
+pub(in crate::net) const NAMESPACE_SEPARATOR: char = '/';
+
+/// Payloads smaller than this are always sent uncompressed, since
+/// the framing overhead would outweigh any gains.
+pub(in crate::net) const COMPRESSION_THRESHOLD: usize = 1024;
+
+/// Maximum allowed size of a decompressed payload. Enforced before
+/// allocating, so a small compressed packet can't be used to make us
+/// allocate arbitrary amounts of memory.
+pub const MAX_DECOMPRESSED_SIZE: u64 = 32 * 1024 * 1024;
+
 /// Generic message template.
 pub trait Message: 'static + Send + Sync + AsyncDecodable + AsyncEncodable {
     const NAME: &'static str;
+    /// Whether this message should be compressed when sent over a
+    /// channel where the peer negotiated compression. Opt in for
+    /// large and highly redundant messages.
+    const COMPRESSIBLE: bool = false;
 }
 
 /// Generic serialized message template.
 pub struct SerializedMessage {
     pub command: String,
     pub payload: Vec<u8>,
+    /// Whether the payload may be compressed on the wire
+    pub compressible: bool,
 }
 
 impl SerializedMessage {
     pub async fn new<M: Message>(message: &M) -> Self {
-        Self { command: M::NAME.to_string(), payload: serialize_async(message).await }
+        Self {
+            command: M::NAME.to_string(),
+            payload: serialize_async(message).await,
+            compressible: M::COMPRESSIBLE,
+        }
     }
 }
 
@@ -47,6 +90,36 @@ macro_rules! impl_p2p_message {
             const NAME: &'static str = $nm;
         }
     };
+    ($st:ty, $nm:expr, compressible) => {
+        impl Message for $st {
+            const NAME: &'static str = $nm;
+            const COMPRESSIBLE: bool = true;
+        }
+    };
+}
+
+/// Compress a serialized message payload.
+pub(in crate::net) fn compress_payload(payload: &[u8]) -> Vec<u8> {
+    lz4_flex::block::compress(payload)
+}
+
+/// Decompress a payload that is expected to expand to exactly
+/// `decompressed_len` bytes. The length is checked against
+/// [`MAX_DECOMPRESSED_SIZE`] before anything is allocated, and
+/// decompression fails if the data would expand past it.
+pub(in crate::net) fn decompress_payload(
+    compressed: &[u8],
+    decompressed_len: u64,
+) -> Result<Vec<u8>> {
+    if decompressed_len > MAX_DECOMPRESSED_SIZE {
+        return Err(Error::MalformedPacket)
+    }
+
+    let mut payload = vec![0u8; decompressed_len as usize];
+    match lz4_flex::block::decompress_into(compressed, &mut payload) {
+        Ok(written) if written as u64 == decompressed_len => Ok(payload),
+        _ => Err(Error::MalformedPacket),
+    }
 }
 
 /// Outbound keepalive message.
@@ -116,3 +189,33 @@ pub struct VerackMessage {
     pub app_version: semver::Version,
 }
 impl_p2p_message!(VerackMessage, "verack");
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compression_roundtrip() {
+        let payload: Vec<u8> = (0..65536u32).map(|i| (i % 7) as u8).collect();
+        let compressed = compress_payload(&payload);
+        assert!(compressed.len() < payload.len());
+
+        let decompressed = decompress_payload(&compressed, payload.len() as u64).unwrap();
+        assert_eq!(decompressed, payload);
+    }
+
+    #[test]
+    fn decompression_bounds() {
+        let payload = vec![0u8; 4096];
+        let compressed = compress_payload(&payload);
+
+        // Declared size over the limit is rejected before allocating
+        assert!(decompress_payload(&compressed, MAX_DECOMPRESSED_SIZE + 1).is_err());
+
+        // Data expanding past the declared size is rejected
+        assert!(decompress_payload(&compressed, 1024).is_err());
+
+        // Declared size larger than the actual data is rejected
+        assert!(decompress_payload(&compressed, 8192).is_err());
+    }
+}
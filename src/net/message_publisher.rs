@@ -24,9 +24,9 @@ use log::{debug, error, warn};
 use rand::{rngs::OsRng, Rng};
 use smol::{io::AsyncReadExt, lock::Mutex};
 
-use super::message::Message;
+use super::message::{decompress_payload, Message, MAX_DECOMPRESSED_SIZE};
 use crate::{net::transport::PtStream, system::timeout::timeout, Error, Result};
-use darkfi_serial::{AsyncDecodable, VarInt};
+use darkfi_serial::{deserialize_async, AsyncDecodable, VarInt};
 
 /// 64-bit identifier for message subscription.
 pub type MessageSubscriptionId = u64;
@@ -178,7 +178,11 @@ impl<M: Message> MessageSubscription<M> {
 /// Generic interface for the message dispatcher.
 #[async_trait]
 trait MessageDispatcherInterface: Send + Sync {
-    async fn trigger(&self, stream: &mut smol::io::ReadHalf<Box<dyn PtStream + 'static>>);
+    async fn trigger(
+        &self,
+        stream: &mut smol::io::ReadHalf<Box<dyn PtStream + 'static>>,
+        compressed: bool,
+    ) -> Result<()>;
 
     async fn trigger_error(&self, err: Error);
 
@@ -194,7 +198,51 @@ impl<M: Message> MessageDispatcherInterface for MessageDispatcher<M> {
     ///
     /// We extract the message length from the stream and use `take()`
     /// to allocate an appropiately sized buffer as a basic DDOS protection.
-    async fn trigger(&self, stream: &mut smol::io::ReadHalf<Box<dyn PtStream + 'static>>) {
+    ///
+    /// Compressed payloads are prefixed with their decompressed length,
+    /// which is checked against [`MAX_DECOMPRESSED_SIZE`] before reading
+    /// and decompressing the data. Malformed compressed payloads return
+    /// an error since the stream can't be trusted afterwards.
+    async fn trigger(
+        &self,
+        stream: &mut smol::io::ReadHalf<Box<dyn PtStream + 'static>>,
+        compressed: bool,
+    ) -> Result<()> {
+        if compressed {
+            let decompressed_len = VarInt::decode_async(stream).await?.0;
+            let compressed_len = VarInt::decode_async(stream).await?.0;
+            if decompressed_len > MAX_DECOMPRESSED_SIZE || compressed_len > MAX_DECOMPRESSED_SIZE {
+                error!(
+                    target: "net::message_publisher::trigger()",
+                    "Compressed {} payload exceeds size limit ({} -> {} bytes)",
+                    M::NAME, compressed_len, decompressed_len,
+                );
+                return Err(Error::MalformedPacket)
+            }
+
+            // Read through `take()`, so the buffer only grows with the
+            // bytes actually received rather than the advertised length.
+            let mut bytes = vec![];
+            stream.take(compressed_len).read_to_end(&mut bytes).await?;
+            if bytes.len() as u64 != compressed_len {
+                return Err(Error::MalformedPacket)
+            }
+            let payload = decompress_payload(&bytes, decompressed_len)?;
+
+            match deserialize_async::<M>(&payload).await {
+                Ok(payload) => self._trigger_all(Ok(Arc::new(payload))).await,
+                Err(err) => {
+                    error!(
+                        target: "net::message_publisher::trigger()",
+                        "Unable to decode data. Dropping...: {}",
+                        err,
+                    );
+                }
+            }
+
+            return Ok(())
+        }
+
         match VarInt::decode_async(stream).await {
             Ok(int) => {
                 // TODO: check the message length does not exceed some bound.
@@ -225,6 +273,8 @@ impl<M: Message> MessageDispatcherInterface for MessageDispatcher<M> {
                 );
             }
         }
+
+        Ok(())
     }
 
     /// Internal function that sends an error message to all subscriber channels.
@@ -252,9 +302,15 @@ impl MessageSubsystem {
         Self { dispatchers: Mutex::new(HashMap::new()) }
     }
 
-    /// Add a new dispatcher for specified [`Message`].
+    /// Add a new dispatcher for specified [`Message`], if one doesn't
+    /// already exist. Existing dispatchers are kept, so their active
+    /// subscriptions keep receiving messages.
     pub async fn add_dispatch<M: Message>(&self) {
-        self.dispatchers.lock().await.insert(M::NAME, Arc::new(MessageDispatcher::<M>::new()));
+        self.dispatchers
+            .lock()
+            .await
+            .entry(M::NAME)
+            .or_insert_with(|| Arc::new(MessageDispatcher::<M>::new()));
     }
 
     /// Subscribes to a [`Message`]. Using the Message name, the method
@@ -284,9 +340,11 @@ impl MessageSubsystem {
 
     /// Transmits a payload to a dispatcher.
     /// Returns an error if the payload fails to transmit.
+    /// `compressed` marks whether the payload on the stream is compressed.
     pub async fn notify(
         &self,
         command: &str,
+        compressed: bool,
         reader: &mut smol::io::ReadHalf<Box<dyn PtStream + 'static>>,
     ) -> Result<()> {
         let Some(dispatcher) = self.dispatchers.lock().await.get(command).cloned() else {
@@ -298,8 +356,7 @@ impl MessageSubsystem {
             return Err(Error::MissingDispatcher)
         };
 
-        dispatcher.trigger(reader).await;
-        Ok(())
+        dispatcher.trigger(reader, compressed).await
     }
 
     /// Concurrently transmits an error message across dispatchers.
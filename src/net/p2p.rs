@@ -16,33 +16,41 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc,
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
 };
 
 use futures::{stream::FuturesUnordered, TryFutureExt};
 use futures_rustls::rustls::crypto::{ring, CryptoProvider};
 use log::{debug, error, info, warn};
-use smol::{fs, lock::RwLock as AsyncRwLock, stream::StreamExt};
+use smol::{fs, lock::RwLock as AsyncRwLock, stream::StreamExt, Executor};
 use url::Url;
 
 use super::{
-    channel::ChannelPtr,
+    channel::{Channel, ChannelPtr},
     dnet::DnetEvent,
     hosts::{Hosts, HostsPtr},
-    message::{Message, SerializedMessage},
-    protocol::{protocol_registry::ProtocolRegistry, register_default_protocols},
+    message::{Message, SerializedMessage, NAMESPACE_FEATURE_PREFIX, NAMESPACE_SEPARATOR},
+    protocol::{
+        protocol_base::ProtocolBasePtr, protocol_registry::ProtocolRegistry,
+        register_default_protocols,
+    },
     session::{
-        InboundSession, InboundSessionPtr, ManualSession, ManualSessionPtr, OutboundSession,
-        OutboundSessionPtr, RefineSession, RefineSessionPtr, SeedSyncSession, SeedSyncSessionPtr,
+        remove_namespace_on_stop, InboundSession, InboundSessionPtr, ManualSession,
+        ManualSessionPtr, OutboundSession, OutboundSessionPtr, RefineSession, RefineSessionPtr,
+        SeedSyncSession, SeedSyncSessionPtr, SessionBitFlag, SessionWeakPtr, SESSION_DEFAULT,
+        SESSION_INBOUND, SESSION_MANUAL, SESSION_OUTBOUND,
     },
     settings::Settings,
 };
 use crate::{
     system::{ExecutorPtr, Publisher, PublisherPtr, Subscription},
     util::path::expand_path,
-    Result,
+    Error, Result,
 };
 
 #[cfg(target_family = "unix")]
@@ -51,6 +59,10 @@ use smol::fs::unix::PermissionsExt;
 /// Atomic pointer to the p2p interface
 pub type P2pPtr = Arc<P2p>;
 
+/// Namespaced channels opened over a channel, along with their network
+/// and attached protocols, waiting for the handshake to finish
+pub(in crate::net) type NamespacedChannels = Vec<(P2pPtr, ChannelPtr, Vec<ProtocolBasePtr>)>;
+
 /// Toplevel peer-to-peer networking interface
 pub struct P2p {
     /// Global multithreaded executor reference
@@ -75,6 +87,14 @@ pub struct P2p {
     pub dnet_enabled: AtomicBool,
     /// The publisher for which we can give dnet info over
     dnet_publisher: PublisherPtr<DnetEvent>,
+    /// Name of this network, if it is multiplexed over the channels
+    /// of another [`P2p`] instance
+    namespace: Option<String>,
+    /// Namespaced networks multiplexed over our channels
+    namespaces: Mutex<HashMap<String, P2pPtr>>,
+    /// Features advertised in our version message by the protocols
+    /// running on this network
+    features: Mutex<HashMap<String, u32>>,
 }
 
 impl P2p {
@@ -99,10 +119,18 @@ impl P2p {
         // Register a CryptoProvider for rustls
         let _ = CryptoProvider::install_default(ring::default_provider());
 
+        let self_ = Self::init(settings, executor, None);
+        register_default_protocols(self_.clone()).await;
+
+        Ok(self_)
+    }
+
+    /// Create the p2p instance and its sessions.
+    fn init(settings: Settings, executor: ExecutorPtr, namespace: Option<String>) -> P2pPtr {
         // Wrap the Settings into an Arc<RwLock>
         let settings = Arc::new(AsyncRwLock::new(settings));
 
-        let self_ = Arc::new_cyclic(|p2p| Self {
+        Arc::new_cyclic(|p2p| Self {
             executor,
             hosts: Hosts::new(Arc::clone(&settings)),
             protocol_registry: ProtocolRegistry::new(),
@@ -114,18 +142,212 @@ impl P2p {
             session_seedsync: SeedSyncSession::new(p2p.clone()),
             dnet_enabled: AtomicBool::new(false),
             dnet_publisher: Publisher::new(),
-        });
+            namespace,
+            namespaces: Mutex::new(HashMap::new()),
+            features: Mutex::new(HashMap::new()),
+        })
+    }
 
-        register_default_protocols(self_.clone()).await;
+    /// Create a network multiplexed over the channels of this one, so a
+    /// single daemon can serve several applications over one peer set.
+    ///
+    /// The returned instance has its own protocol registry, app version
+    /// and host lists, and shares our executor and the rest of our
+    /// settings. It doesn't open any connections itself: peers we connect
+    /// to advertising the same namespace with a compatible app version get
+    /// a namespaced channel opened over the connection, which is registered
+    /// in the namespace's hosts and has the protocols of its registry
+    /// attached.
+    ///
+    /// The namespace starts without seeds and peers. The ones configured
+    /// in its settings get dialed by our sessions when we start, and the
+    /// hosts learned from them join our host lists, so the namespace's
+    /// hosts are the peers among ours that share it. Namespaces should be
+    /// created and configured before calling [`P2p::start`].
+    pub async fn namespace(
+        self: &Arc<Self>,
+        name: &str,
+        app_version: semver::Version,
+    ) -> Result<P2pPtr> {
+        if self.namespace.is_some() {
+            return Err(Error::InvalidNamespace(format!("{name}: namespaces can't be nested")))
+        }
 
-        Ok(self_)
+        if name.is_empty() || name.contains(NAMESPACE_SEPARATOR) {
+            return Err(Error::InvalidNamespace(name.to_string()))
+        }
+
+        let mut settings = self.settings.read().await.clone();
+        settings.app_version = app_version;
+        settings.seeds.clear();
+        settings.peers.clear();
+
+        let mut namespaces = self.namespaces.lock().unwrap();
+        if namespaces.contains_key(name) {
+            return Err(Error::InvalidNamespace(format!("{name}: already exists")))
+        }
+
+        let namespace = Self::init(settings, self.executor(), Some(name.to_string()));
+        namespaces.insert(name.to_string(), namespace.clone());
+
+        Ok(namespace)
+    }
+
+    /// Returns the name of this network, if it is a namespace.
+    pub fn namespace_name(&self) -> Option<&str> {
+        self.namespace.as_deref()
+    }
+
+    /// Advertise a feature in the version message we send to our peers,
+    /// so protocols can check with [`Channel::has_feature`] whether a peer
+    /// understands newer messages before sending them. Features of a
+    /// namespace are advertised over the parent's channels, prefixed with
+    /// the namespace name. This has to be called before connecting to peers.
+    pub fn add_feature(&self, name: &str, version: u32) {
+        self.features.lock().unwrap().insert(name.to_string(), version);
+    }
+
+    /// Stop advertising a feature to the peers we connect to from now on.
+    pub fn remove_feature(&self, name: &str) {
+        self.features.lock().unwrap().remove(name);
+    }
+
+    /// Returns the features advertised in our version message by our
+    /// protocols and by the protocols of our namespaces.
+    pub(in crate::net) fn features(&self) -> Vec<(String, u32)> {
+        let mut features: Vec<(String, u32)> =
+            self.features.lock().unwrap().iter().map(|(k, v)| (k.clone(), *v)).collect();
+
+        for (name, namespace) in self.namespaces.lock().unwrap().iter() {
+            for (feature, version) in namespace.features.lock().unwrap().iter() {
+                features.push((format!("{}{}{}", name, NAMESPACE_SEPARATOR, feature), *version));
+            }
+        }
+
+        features
+    }
+
+    /// Returns the feature advertised in our version message for each
+    /// of our namespaces.
+    pub(in crate::net) async fn namespace_features(&self) -> Vec<(String, u32)> {
+        let namespaces: Vec<P2pPtr> = self.namespaces.lock().unwrap().values().cloned().collect();
+
+        let mut features = Vec::with_capacity(namespaces.len());
+        for namespace in namespaces {
+            features.push(namespace.namespace_feature().await.unwrap());
+        }
+
+        features
+    }
+
+    /// Returns the feature advertising this namespace to our peers, which
+    /// they have to advertise back for the namespace to be opened.
+    async fn namespace_feature(&self) -> Option<(String, u32)> {
+        let name = self.namespace.as_ref()?;
+        let version = self.settings.read().await.app_version.clone();
+        let version = ((version.major as u32) << 16) | (version.minor as u32 & 0xffff);
+        Some((format!("{NAMESPACE_FEATURE_PREFIX}{name}"), version))
+    }
+
+    /// Open a namespaced channel over `channel` for each of our namespaces,
+    /// and attach the protocols from their registries. This happens before
+    /// the handshake, so messages from peers that finish it before we do
+    /// get buffered.
+    pub(in crate::net) async fn attach_namespaces(
+        &self,
+        type_id: SessionBitFlag,
+        channel: ChannelPtr,
+        executor: Arc<Executor<'_>>,
+    ) -> NamespacedChannels {
+        // Seed and refinery connections are not multiplexed
+        if type_id & SESSION_DEFAULT == 0 {
+            return vec![]
+        }
+
+        let namespaces: Vec<P2pPtr> = self.namespaces.lock().unwrap().values().cloned().collect();
+
+        let mut attached = Vec::with_capacity(namespaces.len());
+        for namespace in namespaces {
+            let name = namespace.namespace.clone().unwrap();
+            let session = namespace.session_weak(type_id);
+            let namespaced = Channel::new_namespaced(channel.clone(), name, session).await;
+            namespaced.clone().start(executor.clone());
+
+            let protocols = namespace
+                .protocol_registry()
+                .attach(type_id, namespaced.clone(), namespace.clone())
+                .await;
+
+            attached.push((namespace, namespaced, protocols));
+        }
+
+        attached
+    }
+
+    /// Once the handshake over `channel` finished, start the namespaced
+    /// channels the peer advertised as well, and stop the rest.
+    pub(in crate::net) async fn start_namespaces(
+        &self,
+        channel: ChannelPtr,
+        namespaces: NamespacedChannels,
+        executor: Arc<Executor<'_>>,
+    ) -> Result<()> {
+        let Some(version) = channel.version.lock().await.clone() else {
+            return Err(Error::ChannelStopped)
+        };
+
+        for (namespace, namespaced, protocols) in namespaces {
+            let feature = namespace.namespace_feature().await.unwrap();
+            if !version.features.contains(&feature) {
+                debug!(
+                    target: "net::p2p::start_namespaces()",
+                    "Peer {} doesn't share namespace {}", channel.address(), feature.0,
+                );
+                namespaced.stop().await;
+                continue
+            }
+
+            namespaced.set_version(version.clone()).await;
+
+            let stop_sub = namespaced.subscribe_stop().await?;
+            namespace.hosts().register_channel(namespaced.clone()).await;
+            executor
+                .spawn(remove_namespace_on_stop(namespace.clone(), namespaced.clone(), stop_sub))
+                .detach();
+
+            for protocol in protocols {
+                protocol.start(executor.clone()).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns a weak pointer to our session of the given type.
+    fn session_weak(&self, type_id: SessionBitFlag) -> SessionWeakPtr {
+        match type_id {
+            SESSION_INBOUND => Arc::downgrade(&self.session_inbound) as SessionWeakPtr,
+            SESSION_OUTBOUND => Arc::downgrade(&self.session_outbound) as SessionWeakPtr,
+            SESSION_MANUAL => Arc::downgrade(&self.session_manual) as SessionWeakPtr,
+            _ => unreachable!("Session {type_id} can't have namespaced channels"),
+        }
     }
 
     /// Starts inbound, outbound, and manual sessions.
     pub async fn start(self: Arc<Self>) -> Result<()> {
         debug!(target: "net::p2p::start", "P2P::start() [BEGIN]");
+
+        // Namespaces are driven by the sessions of their parent
+        if self.namespace.is_some() {
+            debug!(target: "net::p2p::start", "Namespace, nothing to start");
+            return Ok(())
+        }
         info!(target: "net::p2p::start", "[P2P] Starting P2P subsystem");
 
+        // Namespaces ride our connections, so our sessions seed from and
+        // dial the hosts configured for them as well.
+        self.add_namespace_hosts().await;
+
         // Start the inbound session
         if let Err(err) = self.session_inbound().start().await {
             error!(target: "net::p2p::start", "Failed to start inbound session!: {}", err);
@@ -149,10 +371,35 @@ impl P2p {
         Ok(())
     }
 
+    /// Add the seeds and peers configured for our namespaces to ours.
+    async fn add_namespace_hosts(&self) {
+        let namespaces: Vec<P2pPtr> = self.namespaces.lock().unwrap().values().cloned().collect();
+
+        let mut settings = self.settings.write().await;
+        for namespace in namespaces {
+            let namespace_settings = namespace.settings.read().await;
+            for seed in &namespace_settings.seeds {
+                if !settings.seeds.contains(seed) {
+                    settings.seeds.push(seed.clone());
+                }
+            }
+            for peer in &namespace_settings.peers {
+                if !settings.peers.contains(peer) {
+                    settings.peers.push(peer.clone());
+                }
+            }
+        }
+    }
+
     /// Reseed the P2P network.
     pub async fn seed(self: Arc<Self>) {
         debug!(target: "net::p2p::seed()", "P2P::seed() [BEGIN]");
 
+        if self.namespace.is_some() {
+            debug!(target: "net::p2p::seed()", "Namespace, nothing to seed");
+            return
+        }
+
         // Activate the seed session.
         self.session_seedsync().notify().await;
 
@@ -161,6 +408,15 @@ impl P2p {
 
     /// Stop the running P2P subsystem
     pub async fn stop(&self) {
+        // Namespaces just close their channels, the connections
+        // underneath belong to their parent.
+        if self.namespace.is_some() {
+            for channel in self.hosts().channels() {
+                channel.stop().await;
+            }
+            return
+        }
+
         // Stop the sessions
         self.session_manual().stop().await;
         self.session_inbound().stop().await;
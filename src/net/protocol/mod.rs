@@ -79,6 +79,27 @@ pub use protocol_seed::ProtocolSeed;
 /// or not we should propagate the message to rest nodes or skip it.
 pub mod protocol_generic;
 
+/// Dandelion++ relay protocol for sender privacy.
+///
+/// Messages are first forwarded along a stem of single peers, picked
+/// per epoch from our outbound channels, and at every hop transition
+/// with some probability to the fluff phase, where they are broadcasted
+/// to everyone. Nodes holding a stem message arm an embargo timer and
+/// fluff it themselves if it doesn't show up in fluff phase in time.
+pub mod protocol_dandelion;
+
+/// Request/response messaging over P2P channels.
+///
+/// Requests and responses are sent under their own message names, and
+/// clients keep a single request of each type in flight per peer, so
+/// responses match the requests in order. The client side handles
+/// timeouts, retrying on other peers, querying many peers and picking
+/// the majority response, reading requests answered with a stream of
+/// responses, and disconnects peers that keep timing out.
+/// The server side works like the generic protocol, answering requests
+/// it can't serve with an explicit "unavailable" message.
+pub mod protocol_request;
+
 /// Base trait for implementing P2P protocols
 pub mod protocol_base;
 /// Interface for registering arbitrary P2P protocols
@@ -0,0 +1,609 @@
+/* This file is part of DarkFi (https://dark.fi)
+ *
+ * Copyright (C) 2020-2024 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Weak,
+    },
+    time::UNIX_EPOCH,
+};
+
+use async_trait::async_trait;
+use darkfi_serial::{serialize_async, AsyncDecodable, AsyncEncodable, AsyncRead, AsyncWrite};
+use log::{debug, error};
+use rand::{rngs::OsRng, seq::SliceRandom, Rng};
+use smol::{
+    channel::{Receiver, Sender},
+    lock::RwLock,
+    Executor,
+};
+use url::Url;
+
+use super::{
+    super::{
+        channel::ChannelPtr,
+        message::Message,
+        message_publisher::MessageSubscription,
+        p2p::P2p,
+        session::{SessionBitFlag, SESSION_OUTBOUND},
+    },
+    protocol_base::{ProtocolBase, ProtocolBasePtr},
+    protocol_jobs_manager::{ProtocolJobsManager, ProtocolJobsManagerPtr},
+    P2pPtr,
+};
+use crate::{
+    system::{sleep, ExecutorPtr, StoppableTask, StoppableTaskPtr},
+    Error, Result,
+};
+
+/// A [`Message`] that can be relayed using the Dandelion++ protocol.
+///
+/// Stem-phase relaying wraps the message into a [`Stem`] message, so
+/// peers can tell the two phases apart. Its P2P name is configured
+/// using `STEM_NAME`.
+pub trait DandelionMessage: Message + Clone {
+    /// P2P message name of the stem-phase wrapper
+    const STEM_NAME: &'static str;
+}
+
+/// Stem-phase wrapper of a [`DandelionMessage`].
+#[derive(Clone, Debug)]
+pub struct Stem<M>(pub M);
+
+impl<M: DandelionMessage> Message for Stem<M> {
+    const NAME: &'static str = M::STEM_NAME;
+    const COMPRESSIBLE: bool = M::COMPRESSIBLE;
+}
+
+#[async_trait]
+impl<M: DandelionMessage> AsyncEncodable for Stem<M> {
+    async fn encode_async<W: AsyncWrite + Unpin + Send>(
+        &self,
+        w: &mut W,
+    ) -> std::io::Result<usize> {
+        self.0.encode_async(w).await
+    }
+}
+
+#[async_trait]
+impl<M: DandelionMessage> AsyncDecodable for Stem<M> {
+    async fn decode_async<D: AsyncRead + Unpin + Send>(d: &mut D) -> std::io::Result<Self> {
+        Ok(Self(M::decode_async(d).await?))
+    }
+}
+
+/// Relay phase of a Dandelion++ message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DandelionPhase {
+    /// Message is forwarded to a single stem peer
+    Stem,
+    /// Message is broadcasted to all peers
+    Fluff,
+}
+
+/// Dandelion++ relay configuration.
+#[derive(Clone, Debug)]
+pub struct DandelionSettings {
+    /// Duration of a stem epoch (in seconds). A new stem peer is
+    /// picked from our outbound channels on every epoch.
+    pub epoch_duration: u64,
+    /// Probability of transitioning a received stem message to the
+    /// fluff phase, in the range `[0.0, 1.0]`.
+    pub fluff_probability: f64,
+    /// Embargo timeout (in seconds). If a stem message isn't seen
+    /// in fluff phase before it expires, we fluff it ourselves.
+    pub embargo_timeout: u64,
+    /// Upper bound of the random delay (in seconds) added to each
+    /// embargo timeout, so nodes on a stem don't fluff together.
+    pub embargo_jitter: u64,
+    /// Maximum number of messages in the stempool. The oldest
+    /// received message is evicted when it's full.
+    pub max_stempool: usize,
+    /// Maximum number of stempool messages received from a single
+    /// peer. Its oldest message is evicted when it's reached.
+    pub max_peer_stem: usize,
+}
+
+impl Default for DandelionSettings {
+    fn default() -> Self {
+        Self {
+            epoch_duration: 600,
+            fluff_probability: 0.1,
+            embargo_timeout: 30,
+            embargo_jitter: 15,
+            max_stempool: 1024,
+            max_peer_stem: 64,
+        }
+    }
+}
+
+/// Stem-phase message along with its embargo deadline.
+struct StemEntry<M> {
+    message: M,
+    embargo: u64,
+    /// Channel the message was received from, `None` for our own
+    channel: Option<u32>,
+    /// Insertion order, used to evict the oldest messages
+    seq: u64,
+}
+
+/// Stem peer selected for the current epoch.
+struct StemPeer {
+    channel: ChannelPtr,
+    epoch_end: u64,
+}
+
+pub type DandelionHandlerPtr<M> = Arc<DandelionHandler<M>>;
+
+/// Defines a handler for Dandelion++ relayed messages, consisting of
+/// a message receiver, the stempool of messages still in stem phase,
+/// the current epoch stem peer, and a stoppable task to run the
+/// handler in the background.
+///
+/// Received messages are sent over the receiver along with their
+/// phase. Once validated, the application must call [`Self::relay`]
+/// to propagate them further. Messages in the stempool must be kept
+/// separate from any public pool until they get fluffed.
+pub struct DandelionHandler<M: DandelionMessage> {
+    // Since smol channels close if all senders or all receivers
+    // get dropped, we will keep one here to remain alive with the
+    // handler.
+    /// Message queue sender, passed to each P2P channel.
+    sender: Sender<(u32, M, DandelionPhase)>,
+    /// Message queue receiver listening for new messages
+    /// from all channels.
+    pub receiver: Receiver<(u32, M, DandelionPhase)>,
+    /// Sender of messages fluffed after their embargo expired, or
+    /// after getting evicted from the stempool
+    embargo_sender: Sender<M>,
+    /// Receiver of messages fluffed after their embargo expired, or
+    /// after getting evicted from the stempool.
+    /// The application should move these into its public pool.
+    pub embargo_receiver: Receiver<M>,
+    /// Messages in stem phase, mapped by their hash
+    stempool: RwLock<HashMap<blake3::Hash, StemEntry<M>>>,
+    /// Insertion counter of the stempool
+    stem_seq: AtomicU64,
+    /// Stem peer of the current epoch
+    stem_peer: RwLock<Option<StemPeer>>,
+    /// Relay configuration
+    settings: DandelionSettings,
+    /// Weak pointer to the P2P instance
+    p2p: Weak<P2p>,
+    /// Handler background task to run the messages listener
+    /// function with.
+    pub task: StoppableTaskPtr,
+    /// Background task fluffing messages with expired embargo
+    embargo_task: StoppableTaskPtr,
+}
+
+impl<M: DandelionMessage> DandelionHandler<M> {
+    /// Generate a new DandelionHandler for the provided P2P instance.
+    /// The handler also attaches its protocol.
+    pub async fn new(
+        p2p: &P2pPtr,
+        name: &'static str,
+        session: SessionBitFlag,
+        settings: DandelionSettings,
+    ) -> DandelionHandlerPtr<M> {
+        // Generate the message queue smol channels
+        let (sender, receiver) = smol::channel::unbounded();
+        let (embargo_sender, embargo_receiver) = smol::channel::unbounded();
+
+        // Create the handler
+        let handler = Arc::new(Self {
+            sender,
+            receiver,
+            embargo_sender,
+            embargo_receiver,
+            stempool: RwLock::new(HashMap::new()),
+            stem_seq: AtomicU64::new(0),
+            stem_peer: RwLock::new(None),
+            settings,
+            p2p: Arc::downgrade(p2p),
+            task: StoppableTask::new(),
+            embargo_task: StoppableTask::new(),
+        });
+
+        // Attach the protocol to the P2P instance
+        let _handler = handler.clone();
+        p2p.protocol_registry()
+            .register(session, move |channel, _| {
+                let handler = _handler.clone();
+                async move { ProtocolDandelion::init(channel, name, handler).await.unwrap() }
+            })
+            .await;
+
+        handler
+    }
+
+    /// Start the background task fluffing messages with expired embargo.
+    pub fn start(self: &Arc<Self>, executor: &ExecutorPtr) {
+        self.embargo_task.clone().start(
+            self.clone().embargo_loop(),
+            |res| async move {
+                match res {
+                    Ok(()) | Err(Error::DetachedTaskStopped) => { /* Do nothing */ }
+                    Err(e) => error!(target: "net::protocol_dandelion::start", "Failed starting embargo task: {e}"),
+                }
+            },
+            Error::DetachedTaskStopped,
+            executor.clone(),
+        );
+    }
+
+    /// Stop the handler background tasks.
+    pub async fn stop(&self) {
+        self.embargo_task.stop().await;
+        self.task.stop().await;
+    }
+
+    /// Compute the stempool identifier of a message.
+    async fn message_id(message: &M) -> blake3::Hash {
+        blake3::hash(&serialize_async(message).await)
+    }
+
+    /// Check if provided message is currently in stem phase.
+    pub async fn in_stempool(&self, message: &M) -> bool {
+        self.stempool.read().await.contains_key(&Self::message_id(message).await)
+    }
+
+    /// Retrieve all messages currently in stem phase.
+    pub async fn stempool(&self) -> Vec<M> {
+        self.stempool.read().await.values().map(|entry| entry.message.clone()).collect()
+    }
+
+    /// Relay a locally created message. Our own messages always start
+    /// in stem phase, unless we have no stem peer to forward them to.
+    /// Returns the phase the message was relayed in.
+    pub async fn broadcast(&self, message: &M) -> DandelionPhase {
+        self.relay_stem(None, message).await
+    }
+
+    /// Relay a validated message received from provided channel, in
+    /// the phase it was received. Stem messages transition to fluff
+    /// with the configured probability. Returns the phase the message
+    /// was relayed in, so the application knows when it went public.
+    pub async fn relay(&self, channel: u32, message: &M, phase: DandelionPhase) -> DandelionPhase {
+        match phase {
+            DandelionPhase::Stem => self.relay_stem(Some(channel), message).await,
+            DandelionPhase::Fluff => {
+                self.fluff(Some(channel), message).await;
+                DandelionPhase::Fluff
+            }
+        }
+    }
+
+    /// Track a stem message and forward it to our epoch stem peer.
+    async fn relay_stem(&self, channel: Option<u32>, message: &M) -> DandelionPhase {
+        let id = Self::message_id(message).await;
+
+        // Track the message and arm its embargo timer
+        let mut stempool = self.stempool.write().await;
+        if stempool.contains_key(&id) {
+            debug!(
+                target: "net::protocol_dandelion::relay_stem",
+                "Message {id} already in stempool, skipping"
+            );
+            return DandelionPhase::Stem
+        }
+        let jitter = OsRng.gen_range(0..=self.settings.embargo_jitter);
+        let embargo =
+            UNIX_EPOCH.elapsed().unwrap().as_secs() + self.settings.embargo_timeout + jitter;
+        let evicted = self.make_room(&mut stempool, channel);
+        let seq = self.stem_seq.fetch_add(1, Ordering::Relaxed);
+        stempool.insert(id, StemEntry { message: message.clone(), embargo, channel, seq });
+        drop(stempool);
+
+        // Evicted messages are fluffed right away, like expired ones,
+        // so flooding the stempool can't make them get lost.
+        for evicted in evicted {
+            self.release(evicted).await;
+        }
+
+        // Received messages transition to fluff randomly
+        if channel.is_some() && OsRng.gen_bool(self.settings.fluff_probability.clamp(0.0, 1.0)) {
+            debug!(
+                target: "net::protocol_dandelion::relay_stem",
+                "Message {id} transitioning to fluff phase"
+            );
+            self.fluff(channel, message).await;
+            return DandelionPhase::Fluff
+        }
+
+        // Forward to our stem peer, unless it's the one who sent it
+        let Some(stem_peer) = self.stem_peer().await else {
+            debug!(
+                target: "net::protocol_dandelion::relay_stem",
+                "No stem peer available, fluffing message {id}"
+            );
+            self.fluff(channel, message).await;
+            return DandelionPhase::Fluff
+        };
+
+        if Some(stem_peer.info.id) == channel {
+            self.fluff(channel, message).await;
+            return DandelionPhase::Fluff
+        }
+
+        if let Err(e) = stem_peer.send(&Stem(message.clone())).await {
+            debug!(
+                target: "net::protocol_dandelion::relay_stem",
+                "Sending message {id} to stem peer {} failed: {e}", stem_peer.address()
+            );
+            self.fluff(channel, message).await;
+            return DandelionPhase::Fluff
+        }
+
+        DandelionPhase::Stem
+    }
+
+    /// Evict stempool messages so a new one received from provided
+    /// channel fits within the configured bounds. The oldest message
+    /// of the channel is evicted when it reached its quota, and the
+    /// oldest received message when the stempool is full, our own
+    /// messages going last. Returns the evicted messages, which have
+    /// to be fluffed.
+    fn make_room(
+        &self,
+        stempool: &mut HashMap<blake3::Hash, StemEntry<M>>,
+        channel: Option<u32>,
+    ) -> Vec<M> {
+        let mut evicted = vec![];
+        let oldest = |stempool: &HashMap<blake3::Hash, StemEntry<M>>, peer_only: bool| {
+            stempool
+                .iter()
+                .filter(|(_, entry)| !peer_only || entry.channel == channel)
+                .min_by_key(|(_, entry)| (entry.channel.is_none(), entry.seq))
+                .map(|(id, _)| *id)
+        };
+
+        if channel.is_some() {
+            let count = stempool.values().filter(|entry| entry.channel == channel).count();
+            if count >= self.settings.max_peer_stem {
+                if let Some(id) = oldest(stempool, true) {
+                    debug!(
+                        target: "net::protocol_dandelion::make_room",
+                        "Peer stem quota reached, evicting message {id}"
+                    );
+                    evicted.extend(stempool.remove(&id).map(|entry| entry.message));
+                }
+            }
+        }
+
+        while stempool.len() >= self.settings.max_stempool {
+            let Some(id) = oldest(stempool, false) else { break };
+            debug!(
+                target: "net::protocol_dandelion::make_room",
+                "Stempool full, evicting message {id}"
+            );
+            evicted.extend(stempool.remove(&id).map(|entry| entry.message));
+        }
+
+        evicted
+    }
+
+    /// Remove the message from the stempool and broadcast it to all
+    /// peers, excluding provided channel.
+    async fn fluff(&self, channel: Option<u32>, message: &M) {
+        let id = Self::message_id(message).await;
+        self.stempool.write().await.remove(&id);
+
+        let Some(p2p) = self.p2p.upgrade() else { return };
+        let exclude_list: Vec<Url> = match channel.and_then(|c| p2p.get_channel(c)) {
+            Some(channel) => vec![channel.address().clone()],
+            None => vec![],
+        };
+        p2p.broadcast_with_exclude(message, &exclude_list).await;
+    }
+
+    /// Grab the stem peer of current epoch. If the epoch has ended or
+    /// the peer disconnected, a new one is picked at random from our
+    /// outbound channels.
+    async fn stem_peer(&self) -> Option<ChannelPtr> {
+        let now = UNIX_EPOCH.elapsed().unwrap().as_secs();
+
+        let mut stem_peer = self.stem_peer.write().await;
+        if let Some(peer) = stem_peer.as_ref() {
+            if now < peer.epoch_end && !peer.channel.is_stopped() {
+                return Some(peer.channel.clone())
+            }
+        }
+
+        let p2p = self.p2p.upgrade()?;
+        let outbound: Vec<ChannelPtr> = p2p
+            .hosts()
+            .peers()
+            .into_iter()
+            .filter(|c| c.session_type_id() & SESSION_OUTBOUND != 0)
+            .collect();
+
+        let Some(channel) = outbound.choose(&mut OsRng).cloned() else {
+            *stem_peer = None;
+            return None
+        };
+
+        debug!(
+            target: "net::protocol_dandelion::stem_peer",
+            "Picked {} as stem peer for the new epoch", channel.address()
+        );
+        *stem_peer = Some(StemPeer {
+            channel: channel.clone(),
+            epoch_end: now + self.settings.epoch_duration,
+        });
+
+        Some(channel)
+    }
+
+    /// Background loop fluffing stem messages whose embargo expired
+    /// without us seeing them in fluff phase.
+    async fn embargo_loop(self: Arc<Self>) -> Result<()> {
+        loop {
+            sleep(1).await;
+
+            let now = UNIX_EPOCH.elapsed().unwrap().as_secs();
+            let mut expired = vec![];
+            self.stempool.write().await.retain(|_, entry| {
+                if entry.embargo > now {
+                    return true
+                }
+                expired.push(entry.message.clone());
+                false
+            });
+
+            for message in expired {
+                debug!(
+                    target: "net::protocol_dandelion::embargo_loop",
+                    "Embargo expired, fluffing message {}", Self::message_id(&message).await
+                );
+                self.release(message).await;
+            }
+        }
+    }
+
+    /// Fluff a message we took out of the stempool ourselves, and pass
+    /// it to the application over the embargo channel.
+    async fn release(&self, message: M) {
+        if let Some(p2p) = self.p2p.upgrade() {
+            p2p.broadcast(&message).await;
+        }
+
+        if let Err(e) = self.embargo_sender.send(message).await {
+            debug!(
+                target: "net::protocol_dandelion::release",
+                "Sending to embargo channel failed: {e}"
+            );
+        }
+    }
+}
+
+/// Defines the Dandelion++ relay protocol, listening for both stem and
+/// fluff phase messages.
+pub struct ProtocolDandelion<M: DandelionMessage> {
+    /// The P2P channel fluff message subcription
+    fluff_sub: MessageSubscription<M>,
+    /// The P2P channel stem message subcription
+    stem_sub: MessageSubscription<Stem<M>>,
+    /// Pointer to the handler
+    handler: DandelionHandlerPtr<M>,
+    /// The P2P channel the protocol is serving
+    channel: ChannelPtr,
+    /// Pointer to the protocol job manager
+    jobsman: ProtocolJobsManagerPtr,
+}
+
+impl<M: DandelionMessage> ProtocolDandelion<M> {
+    /// Initialize a new Dandelion++ protocol.
+    pub async fn init(
+        channel: ChannelPtr,
+        name: &'static str,
+        handler: DandelionHandlerPtr<M>,
+    ) -> Result<ProtocolBasePtr> {
+        debug!(
+            target: "net::protocol_dandelion::init",
+            "Adding Dandelion++ protocol for message {name} to the protocol registry"
+        );
+
+        // Add the message dispatchers
+        let msg_subsystem = channel.message_subsystem();
+        msg_subsystem.add_dispatch::<M>().await;
+        msg_subsystem.add_dispatch::<Stem<M>>().await;
+
+        // Create the message subscriptions
+        let fluff_sub = channel.subscribe_msg::<M>().await?;
+        let stem_sub = channel.subscribe_msg::<Stem<M>>().await?;
+
+        Ok(Arc::new(Self {
+            fluff_sub,
+            stem_sub,
+            handler,
+            channel: channel.clone(),
+            jobsman: ProtocolJobsManager::new(name, channel),
+        }))
+    }
+
+    /// Listen for fluff phase messages and send them to the handler.
+    async fn handle_receive_fluff(self: Arc<Self>) -> Result<()> {
+        loop {
+            let msg = match self.fluff_sub.receive().await {
+                Ok(m) => m,
+                Err(e) => {
+                    debug!(
+                        target: "net::protocol_dandelion::handle_receive_fluff",
+                        "[{}] recv fail: {e}", self.jobsman.clone().name()
+                    );
+                    continue
+                }
+            };
+
+            self.forward((*msg).clone(), DandelionPhase::Fluff).await;
+        }
+    }
+
+    /// Listen for stem phase messages and send them to the handler.
+    /// Messages we already hold in stem phase are ignored, to prevent
+    /// relay loops.
+    async fn handle_receive_stem(self: Arc<Self>) -> Result<()> {
+        loop {
+            let msg = match self.stem_sub.receive().await {
+                Ok(m) => m,
+                Err(e) => {
+                    debug!(
+                        target: "net::protocol_dandelion::handle_receive_stem",
+                        "[{}] recv fail: {e}", self.jobsman.clone().name()
+                    );
+                    continue
+                }
+            };
+
+            if self.handler.in_stempool(&msg.0).await {
+                continue
+            }
+
+            self.forward(msg.0.clone(), DandelionPhase::Stem).await;
+        }
+    }
+
+    /// Send a received message across the handler smol channel.
+    async fn forward(&self, message: M, phase: DandelionPhase) {
+        if let Err(e) = self.handler.sender.send((self.channel.info.id, message, phase)).await {
+            debug!(
+                target: "net::protocol_dandelion::forward",
+                "[{}] sending to channel fail: {e}", self.jobsman.clone().name()
+            );
+        }
+    }
+}
+
+#[async_trait]
+impl<M: DandelionMessage> ProtocolBase for ProtocolDandelion<M> {
+    async fn start(self: Arc<Self>, ex: Arc<Executor<'_>>) -> Result<()> {
+        debug!(target: "net::protocol_dandelion::start", "START");
+        self.jobsman.clone().start(ex.clone());
+        self.jobsman.clone().spawn(self.clone().handle_receive_fluff(), ex.clone()).await;
+        self.jobsman.clone().spawn(self.clone().handle_receive_stem(), ex).await;
+        debug!(target: "net::protocol_dandelion::start", "END");
+        Ok(())
+    }
+
+    fn name(&self) -> &'static str {
+        self.jobsman.clone().name()
+    }
+}
@@ -0,0 +1,678 @@
+/* This file is part of DarkFi (https://dark.fi)
+ *
+ * Copyright (C) 2020-2024 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    hash::Hash,
+    marker::PhantomData,
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use darkfi_serial::{AsyncDecodable, AsyncEncodable, AsyncRead, AsyncWrite};
+use futures::future::{join_all, select, Either};
+use log::{debug, warn};
+use rand::{rngs::OsRng, seq::SliceRandom};
+use smol::{
+    channel::{Receiver, Sender},
+    lock::{Mutex, MutexGuardArc, RwLock},
+    Executor,
+};
+
+use super::{
+    super::{
+        channel::{Channel, ChannelPtr},
+        message::Message,
+        message_publisher::MessageSubscription,
+        session::SessionBitFlag,
+    },
+    protocol_base::{ProtocolBase, ProtocolBasePtr},
+    protocol_jobs_manager::{ProtocolJobsManager, ProtocolJobsManagerPtr},
+    P2pPtr,
+};
+use crate::{
+    system::{timeout::timeout, StoppableTask, StoppableTaskPtr},
+    Error, Result,
+};
+
+/// Feature name advertised in `VersionMessage::features` when a node
+/// answers requests it can't serve with an [`Unavailable`] message.
+pub const REQUEST_FEATURE: &str = "request";
+
+/// Version of the request feature.
+pub const REQUEST_VERSION: u32 = 1;
+
+/// A [`Message`] that expects a single [`RequestMessage::Response`]
+/// back from the peer it was sent to.
+///
+/// Requests and responses are sent under their own P2P names, so the
+/// protocol stays wire compatible with nodes exchanging them directly.
+/// Use [`impl_p2p_request!`] to implement it.
+pub trait RequestMessage: Message + Clone + Debug {
+    /// Message the peer responds with
+    type Response: Message + Clone + Debug;
+    /// P2P message name of the [`Unavailable`] response
+    const UNAVAILABLE_NAME: &'static str;
+}
+
+/// Implements [`RequestMessage`] for a request and response message
+/// pair, using the provided P2P name for their [`Unavailable`] message.
+#[macro_export]
+macro_rules! impl_p2p_request {
+    ($req:ty, $rep:ty, $nm:literal) => {
+        impl $crate::net::protocol::protocol_request::RequestMessage for $req {
+            type Response = $rep;
+            const UNAVAILABLE_NAME: &'static str = $nm;
+        }
+    };
+}
+
+/// Response signalling the peer can't serve a [`RequestMessage`] right
+/// now, so the client can move on without waiting for the timeout. Only
+/// sent to peers advertising [`REQUEST_FEATURE`].
+#[derive(Clone, Debug)]
+pub struct Unavailable<Q>(PhantomData<Q>);
+
+impl<Q> Default for Unavailable<Q> {
+    fn default() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl<Q: RequestMessage> Message for Unavailable<Q> {
+    const NAME: &'static str = Q::UNAVAILABLE_NAME;
+}
+
+#[async_trait]
+impl<Q: RequestMessage> AsyncEncodable for Unavailable<Q> {
+    async fn encode_async<W: AsyncWrite + Unpin + Send>(
+        &self,
+        _w: &mut W,
+    ) -> std::io::Result<usize> {
+        Ok(0)
+    }
+}
+
+#[async_trait]
+impl<Q: RequestMessage> AsyncDecodable for Unavailable<Q> {
+    async fn decode_async<D: AsyncRead + Unpin + Send>(_d: &mut D) -> std::io::Result<Self> {
+        Ok(Self::default())
+    }
+}
+
+/// Request/response client configuration.
+#[derive(Clone, Debug)]
+pub struct RequestSettings {
+    /// Time to wait for a response (in seconds)
+    pub timeout: u64,
+    /// Maximum number of peers to try in [`RequestResponse::request_any`]
+    pub retries: usize,
+    /// Consecutive timeouts after which a peer gets disconnected
+    pub max_timeouts: usize,
+}
+
+impl Default for RequestSettings {
+    fn default() -> Self {
+        Self { timeout: 15, retries: 3, max_timeouts: 5 }
+    }
+}
+
+/// Client state of a peer we sent requests to.
+struct PeerState {
+    /// The peer channel, to prune the state once it stops
+    channel: Weak<Channel>,
+    /// Consecutive timeouts of the peer
+    timeouts: usize,
+    /// Locks serializing requests of the same type, mapped by name
+    locks: HashMap<&'static str, Arc<Mutex<()>>>,
+}
+
+pub type RequestResponsePtr = Arc<RequestResponse>;
+
+/// Client side of the request/response protocol.
+///
+/// Sends [`RequestMessage`]s to peers and waits for their responses.
+/// Responses carry no request ID, so only a single request of each type
+/// is in flight per channel, and the peer answers them in order. Peers
+/// that keep timing out get penalized by disconnecting them.
+pub struct RequestResponse {
+    /// Client configuration
+    settings: RequestSettings,
+    /// Peers state mapped by channel ID
+    peers: Mutex<HashMap<u32, PeerState>>,
+}
+
+impl RequestResponse {
+    /// Generate a new request/response client using provided settings.
+    pub fn new(settings: RequestSettings) -> RequestResponsePtr {
+        Arc::new(Self { settings, peers: Mutex::new(HashMap::new()) })
+    }
+
+    /// Send provided request to the channel and wait for its response.
+    /// Returns `Error::ChannelTimeout` if the peer didn't respond in time,
+    /// and `Error::RequestUnavailable` if it can't serve the request.
+    pub async fn request<Q: RequestMessage>(
+        &self,
+        channel: &ChannelPtr,
+        request: &Q,
+    ) -> Result<Q::Response> {
+        let stream = self.request_stream(channel, request).await?;
+        let result = stream.next().await;
+        stream.close().await;
+        result
+    }
+
+    /// Send provided request to the channel, for requests the peer
+    /// answers with a stream of responses. The responses are read through
+    /// the returned [`ResponseStream`] until the caller has all it asked
+    /// for, as only the caller can tell when a stream ends.
+    ///
+    /// Responses left unread would be taken as responses to the next
+    /// request of this type to the peer, so the stream must either be
+    /// read to its end or the channel stopped.
+    pub async fn request_stream<Q: RequestMessage>(
+        &self,
+        channel: &ChannelPtr,
+        request: &Q,
+    ) -> Result<ResponseStream<'_, Q>> {
+        debug!(
+            target: "net::protocol_request::request_stream",
+            "Sending {} to peer {}", Q::NAME, channel.address(),
+        );
+
+        // Wait for any other request of this type to the peer to finish,
+        // otherwise we can't tell which one a response belongs to.
+        let guard = self.request_lock::<Q>(channel).await.lock_arc().await;
+
+        // Communication setup. Dispatchers are only added if missing,
+        // so existing subscriptions on the channel remain valid.
+        let msg_subsystem = channel.message_subsystem();
+        msg_subsystem.add_dispatch::<Q::Response>().await;
+        msg_subsystem.add_dispatch::<Unavailable<Q>>().await;
+        let response_sub = channel.subscribe_msg::<Q::Response>().await?;
+        let unavailable_sub = match channel.subscribe_msg::<Unavailable<Q>>().await {
+            Ok(s) => s,
+            Err(e) => {
+                response_sub.unsubscribe().await;
+                return Err(e)
+            }
+        };
+
+        let stream = ResponseStream {
+            client: self,
+            channel: channel.clone(),
+            response_sub,
+            unavailable_sub,
+            _guard: guard,
+        };
+
+        if let Err(e) = channel.send(request).await {
+            stream.close().await;
+            return Err(e)
+        }
+
+        Ok(stream)
+    }
+
+    /// Send provided request to random channels of the provided set,
+    /// one at a time, until one of them responds. At most `retries`
+    /// channels are tried.
+    pub async fn request_any<Q: RequestMessage>(
+        &self,
+        channels: &[ChannelPtr],
+        request: &Q,
+    ) -> Result<(ChannelPtr, Q::Response)> {
+        let mut channels = channels.to_vec();
+        channels.shuffle(&mut OsRng);
+        channels.truncate(self.settings.retries.max(1));
+
+        let mut last_error = Error::NetworkOperationFailed;
+        for channel in channels {
+            match self.request(&channel, request).await {
+                Ok(response) => return Ok((channel, response)),
+                Err(e) => {
+                    debug!(
+                        target: "net::protocol_request::request_any",
+                        "{} to peer {} failed: {e}", Q::NAME, channel.address(),
+                    );
+                    last_error = e;
+                }
+            }
+        }
+
+        Err(last_error)
+    }
+
+    /// Send provided request to all channels of the provided set
+    /// concurrently, and return the responses we received.
+    pub async fn request_all<Q: RequestMessage>(
+        &self,
+        channels: &[ChannelPtr],
+        request: &Q,
+    ) -> Vec<(ChannelPtr, Q::Response)> {
+        let futures = channels.iter().map(|channel| async move {
+            match self.request(channel, request).await {
+                Ok(response) => Some((channel.clone(), response)),
+                Err(e) => {
+                    debug!(
+                        target: "net::protocol_request::request_all",
+                        "{} to peer {} failed: {e}", Q::NAME, channel.address(),
+                    );
+                    None
+                }
+            }
+        });
+
+        join_all(futures).await.into_iter().flatten().collect()
+    }
+
+    /// Send provided request to all channels of the provided set and
+    /// group the responses by the key the provided function extracts
+    /// from them. Responses without a key are discarded. Returns the key
+    /// most peers agree on, along with the peers that responded with it.
+    /// Ties are resolved by picking the greatest key.
+    pub async fn request_majority<Q, K, F>(
+        &self,
+        channels: &[ChannelPtr],
+        request: &Q,
+        key: F,
+    ) -> Option<(K, Vec<(ChannelPtr, Q::Response)>)>
+    where
+        Q: RequestMessage,
+        K: Eq + Hash + Ord,
+        F: Fn(&Q::Response) -> Option<K>,
+    {
+        let mut groups: HashMap<K, Vec<(ChannelPtr, Q::Response)>> = HashMap::new();
+        for (channel, response) in self.request_all(channels, request).await {
+            if let Some(k) = key(&response) {
+                groups.entry(k).or_default().push((channel, response));
+            }
+        }
+
+        groups
+            .into_iter()
+            .max_by(|(a_key, a), (b_key, b)| a.len().cmp(&b.len()).then_with(|| a_key.cmp(b_key)))
+    }
+
+    /// Auxiliary function to grab the lock serializing requests of type
+    /// `Q` to provided channel. State of stopped channels is pruned.
+    async fn request_lock<Q: RequestMessage>(&self, channel: &ChannelPtr) -> Arc<Mutex<()>> {
+        let mut peers = self.peers.lock().await;
+        peers.retain(|_, state| state.channel.upgrade().is_some_and(|c| !c.is_stopped()));
+        let state = peers.entry(channel.info.id).or_insert_with(|| PeerState {
+            channel: Arc::downgrade(channel),
+            timeouts: 0,
+            locks: HashMap::new(),
+        });
+        state.locks.entry(Q::NAME).or_default().clone()
+    }
+
+    /// Auxiliary function to reset the consecutive timeouts of provided
+    /// channel after it responded.
+    async fn reset(&self, channel: &ChannelPtr) {
+        if let Some(state) = self.peers.lock().await.get_mut(&channel.info.id) {
+            state.timeouts = 0;
+        }
+    }
+
+    /// Auxiliary function to record a timeout of provided channel, and
+    /// disconnect it once it reaches the configured consecutive limit.
+    async fn penalize(&self, channel: &ChannelPtr) {
+        let mut peers = self.peers.lock().await;
+        let Some(state) = peers.get_mut(&channel.info.id) else { return };
+        state.timeouts += 1;
+        if state.timeouts < self.settings.max_timeouts {
+            return
+        }
+        peers.remove(&channel.info.id);
+        drop(peers);
+
+        warn!(
+            target: "net::protocol_request::penalize",
+            "Peer {} timed out {} consecutive requests, disconnecting",
+            channel.address(), self.settings.max_timeouts,
+        );
+        channel.stop().await;
+    }
+}
+
+/// Responses of a request sent with [`RequestResponse::request_stream`].
+/// Requests of the same type to the peer wait until the stream is closed.
+pub struct ResponseStream<'a, Q: RequestMessage> {
+    /// Client the request was sent with
+    client: &'a RequestResponse,
+    /// The channel the request was sent to
+    channel: ChannelPtr,
+    /// Subscription to the responses
+    response_sub: MessageSubscription<Q::Response>,
+    /// Subscription to the [`Unavailable`] message
+    unavailable_sub: MessageSubscription<Unavailable<Q>>,
+    /// Guard keeping other requests of the same type from being sent
+    _guard: MutexGuardArc<()>,
+}
+
+impl<Q: RequestMessage> ResponseStream<'_, Q> {
+    /// Wait for the next response, or for the peer to signal it can't
+    /// serve the request. Timeouts count against the peer, and the
+    /// configured timeout applies to each response separately.
+    pub async fn next(&self) -> Result<Q::Response> {
+        let response =
+            select(Box::pin(self.response_sub.receive()), Box::pin(self.unavailable_sub.receive()));
+        let result = match timeout(Duration::from_secs(self.client.settings.timeout), response)
+            .await
+        {
+            Ok(Either::Left((response, _))) => response.map(|r| r.as_ref().clone()),
+            Ok(Either::Right((unavailable, _))) => unavailable.and(Err(Error::RequestUnavailable)),
+            Err(_) => Err(Error::ChannelTimeout),
+        };
+
+        match result {
+            // The peer is responsive, even if it doesn't have what we asked
+            Ok(_) | Err(Error::RequestUnavailable) => self.client.reset(&self.channel).await,
+            Err(Error::ChannelTimeout) => self.client.penalize(&self.channel).await,
+            Err(_) => {}
+        }
+
+        result
+    }
+
+    /// Close the stream, allowing other requests of this type to be sent.
+    pub async fn close(self) {
+        self.response_sub.unsubscribe().await;
+        self.unavailable_sub.unsubscribe().await;
+    }
+}
+
+/// Defines request protocol action signal.
+#[derive(Debug)]
+pub enum ProtocolRequestAction<R> {
+    /// Send provided response message to the node
+    Response(R),
+    /// Send provided response messages to the node in order, answering
+    /// a request read with [`RequestResponse::request_stream`]
+    Responses(Vec<R>),
+    /// Tell the node we can't serve the request. Nodes that predate
+    /// [`REQUEST_FEATURE`] get no response at all.
+    Skip,
+    /// Stop the channel entirely
+    Stop,
+}
+
+pub type ProtocolRequestHandlerPtr<Q> = Arc<ProtocolRequestHandler<Q>>;
+
+/// Server side of the request/response protocol.
+///
+/// Works like a generic protocol handler: incoming requests from all
+/// channels are received through `receiver`, and each must be answered
+/// with an action signal. Responses are sent in the order the requests
+/// were received.
+pub struct ProtocolRequestHandler<Q: RequestMessage> {
+    /// Request queue sender, passed to each P2P channel.
+    sender: Sender<(u32, Q)>,
+    /// Request queue receiver listening for new requests
+    /// from all channels.
+    pub receiver: Receiver<(u32, Q)>,
+    /// Senders mapped by channel ID to propagate the
+    /// action signal after a request retrieval.
+    senders: RwLock<HashMap<u32, Sender<ProtocolRequestAction<Q::Response>>>>,
+    /// Handler background task to run the requests listener
+    /// function with.
+    pub task: StoppableTaskPtr,
+}
+
+impl<Q: RequestMessage> ProtocolRequestHandler<Q> {
+    /// Generate a new ProtocolRequestHandler for the provided P2P
+    /// instance. The handler also attaches its request protocol, and
+    /// advertises [`REQUEST_FEATURE`] to our peers.
+    pub async fn new(
+        p2p: &P2pPtr,
+        name: &'static str,
+        session: SessionBitFlag,
+    ) -> ProtocolRequestHandlerPtr<Q> {
+        let (sender, receiver) = smol::channel::unbounded::<(u32, Q)>();
+        let senders = RwLock::new(HashMap::new());
+        let task = StoppableTask::new();
+        let handler = Arc::new(Self { sender, receiver, senders, task });
+        p2p.add_feature(REQUEST_FEATURE, REQUEST_VERSION);
+
+        let _handler = handler.clone();
+        p2p.protocol_registry()
+            .register(session, move |channel, _| {
+                let handler = _handler.clone();
+                async move { ProtocolRequest::init(channel, name, handler).await.unwrap() }
+            })
+            .await;
+
+        handler
+    }
+
+    /// Registers a new channel sender to the handler map, pruning
+    /// stale(closed) channels from it.
+    async fn register_channel_sender(
+        &self,
+        channel: u32,
+        sender: Sender<ProtocolRequestAction<Q::Response>>,
+    ) {
+        let mut lock = self.senders.write().await;
+        lock.insert(channel, sender);
+        lock.retain(|_, sender| !sender.is_closed());
+    }
+
+    /// Sends provided request action to requested channel, if it exists.
+    pub async fn send_action(&self, channel: u32, action: ProtocolRequestAction<Q::Response>) {
+        debug!(
+            target: "net::protocol_request::ProtocolRequestHandler::send_action",
+            "Sending action {action:?} to channel {channel}..."
+        );
+
+        let mut lock = self.senders.write().await;
+        let Some(sender) = lock.get(&channel) else {
+            debug!(
+                target: "net::protocol_request::ProtocolRequestHandler::send_action",
+                "Channel wasn't found."
+            );
+            return
+        };
+
+        if let Err(e) = sender.send(action).await {
+            debug!(
+                target: "net::protocol_request::ProtocolRequestHandler::send_action",
+                "Channel {channel} send fail: {e}"
+            );
+            lock.remove(&channel);
+        };
+    }
+}
+
+/// Defines request/response protocol, serving requests of a channel.
+pub struct ProtocolRequest<Q: RequestMessage> {
+    /// The P2P channel request subcription
+    msg_sub: MessageSubscription<Q>,
+    /// The request smol channel sender
+    sender: Sender<(u32, Q)>,
+    /// Action signal smol channel receiver
+    receiver: Receiver<ProtocolRequestAction<Q::Response>>,
+    /// The P2P channel the protocol is serving
+    channel: ChannelPtr,
+    /// Pointer to the protocol job manager
+    jobsman: ProtocolJobsManagerPtr,
+}
+
+impl<Q: RequestMessage> ProtocolRequest<Q> {
+    /// Initialize a new request protocol.
+    pub async fn init(
+        channel: ChannelPtr,
+        name: &'static str,
+        handler: ProtocolRequestHandlerPtr<Q>,
+    ) -> Result<ProtocolBasePtr> {
+        debug!(
+            target: "net::protocol_request::init",
+            "Adding request protocol for message {name} to the protocol registry"
+        );
+
+        let msg_subsystem = channel.message_subsystem();
+        msg_subsystem.add_dispatch::<Q>().await;
+        msg_subsystem.add_dispatch::<Q::Response>().await;
+        msg_subsystem.add_dispatch::<Unavailable<Q>>().await;
+
+        let msg_sub = channel.subscribe_msg::<Q>().await?;
+
+        let (action_sender, receiver) = smol::channel::bounded(1);
+        handler.register_channel_sender(channel.info.id, action_sender).await;
+
+        Ok(Arc::new(Self {
+            msg_sub,
+            sender: handler.sender.clone(),
+            receiver,
+            channel: channel.clone(),
+            jobsman: ProtocolJobsManager::new(name, channel),
+        }))
+    }
+
+    /// Runs the request queue. Each received request is sent to our smol
+    /// channel, and then we wait for its action signal, responding to the
+    /// peer accordingly.
+    async fn handle_receive_request(self: Arc<Self>) -> Result<()> {
+        debug!(target: "net::protocol_request::handle_receive_request", "START");
+
+        loop {
+            let request = match self.msg_sub.receive().await {
+                Ok(r) => r,
+                Err(e) => {
+                    debug!(
+                        target: "net::protocol_request::handle_receive_request",
+                        "[{}] recv fail: {e}", self.jobsman.clone().name()
+                    );
+                    continue
+                }
+            };
+
+            if let Err(e) = self.sender.send((self.channel.info.id, request.as_ref().clone())).await
+            {
+                debug!(
+                    target: "net::protocol_request::handle_receive_request",
+                    "[{}] sending to channel fail: {e}", self.jobsman.clone().name()
+                );
+                continue
+            }
+
+            let action = match self.receiver.recv().await {
+                Ok(a) => a,
+                Err(e) => {
+                    debug!(
+                        target: "net::protocol_request::handle_receive_request",
+                        "[{}] action signal recv fail: {e}", self.jobsman.clone().name()
+                    );
+                    continue
+                }
+            };
+
+            match action {
+                ProtocolRequestAction::Response(r) => {
+                    if let Err(e) = self.channel.send(&r).await {
+                        debug!(
+                            target: "net::protocol_request::handle_receive_request",
+                            "[{}] Channel send fail: {e}", self.jobsman.clone().name()
+                        )
+                    };
+                }
+                ProtocolRequestAction::Responses(responses) => {
+                    for r in responses {
+                        if let Err(e) = self.channel.send(&r).await {
+                            debug!(
+                                target: "net::protocol_request::handle_receive_request",
+                                "[{}] Channel send fail: {e}", self.jobsman.clone().name()
+                            );
+                            break
+                        };
+                    }
+                }
+                ProtocolRequestAction::Skip => {
+                    debug!(
+                        target: "net::protocol_request::handle_receive_request",
+                        "[{}] Skip action signal received.", self.jobsman.clone().name()
+                    );
+
+                    if !self.channel.has_feature(REQUEST_FEATURE, REQUEST_VERSION).await {
+                        continue
+                    }
+                    if let Err(e) = self.channel.send(&Unavailable::<Q>::default()).await {
+                        debug!(
+                            target: "net::protocol_request::handle_receive_request",
+                            "[{}] Channel send fail: {e}", self.jobsman.clone().name()
+                        )
+                    };
+                }
+                ProtocolRequestAction::Stop => {
+                    self.channel.stop().await;
+                    return Err(Error::ChannelStopped)
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<Q: RequestMessage> ProtocolBase for ProtocolRequest<Q> {
+    async fn start(self: Arc<Self>, ex: Arc<Executor<'_>>) -> Result<()> {
+        debug!(target: "net::protocol_request::start", "START");
+        self.jobsman.clone().start(ex.clone());
+        self.jobsman.clone().spawn(self.clone().handle_receive_request(), ex).await;
+        debug!(target: "net::protocol_request::start", "END");
+        Ok(())
+    }
+
+    fn name(&self) -> &'static str {
+        self.jobsman.clone().name()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use darkfi_serial::{deserialize_async, serialize_async, SerialDecodable, SerialEncodable};
+
+    use super::{RequestMessage, Unavailable};
+    use crate::{impl_p2p_message, net::Message};
+
+    #[derive(Clone, Debug, SerialEncodable, SerialDecodable)]
+    struct PingRequest(u32);
+    impl_p2p_message!(PingRequest, "test_ping");
+
+    #[derive(Clone, Debug, PartialEq, SerialEncodable, SerialDecodable)]
+    struct PingResponse(u32);
+    impl_p2p_message!(PingResponse, "test_pong");
+
+    impl_p2p_request!(PingRequest, PingResponse, "test_ping_unavailable");
+
+    #[test]
+    fn unavailable_message() {
+        smol::block_on(async {
+            // Requests and responses keep their own names on the wire
+            assert_eq!(PingRequest::NAME, "test_ping");
+            assert_eq!(PingResponse::NAME, "test_pong");
+            assert_eq!(PingRequest::UNAVAILABLE_NAME, "test_ping_unavailable");
+            assert_eq!(Unavailable::<PingRequest>::NAME, PingRequest::UNAVAILABLE_NAME);
+
+            let encoded = serialize_async(&Unavailable::<PingRequest>::default()).await;
+            assert!(encoded.is_empty());
+            let _: Unavailable<PingRequest> = deserialize_async(&encoded).await.unwrap();
+        });
+    }
+}
@@ -26,15 +26,15 @@ use futures::{
     pin_mut,
 };
 use log::{debug, error};
-use smol::{lock::RwLock as AsyncRwLock, Executor, Timer};
+use smol::{lock::RwLock as AsyncRwLock, Executor};
 
 use super::super::{
     channel::ChannelPtr,
-    message::{VerackMessage, VersionMessage},
+    message::{VerackMessage, VersionMessage, COMPRESSION_FEATURE, COMPRESSION_VERSION},
     message_publisher::MessageSubscription,
     settings::Settings,
 };
-use crate::{Error, Result};
+use crate::{system::timeout::delay, Error, Result};
 
 /// Implements the protocol version handshake sent out by nodes at
 /// the beginning of a connection.
@@ -68,7 +68,7 @@ impl ProtocolVersion {
     pub async fn run(self: Arc<Self>, executor: Arc<Executor<'_>>) -> Result<()> {
         debug!(target: "net::protocol_version::run()", "START => address={}", self.channel.address());
         let timeout =
-            Timer::after(Duration::from_secs(self.settings.read().await.channel_handshake_timeout));
+            delay(Duration::from_secs(self.settings.read().await.channel_handshake_timeout));
         let version = self.clone().exchange_versions(executor);
 
         pin_mut!(timeout);
@@ -154,8 +154,20 @@ impl ProtocolVersion {
         let node_id = settings.node_id.clone();
         let app_version = settings.app_version.clone();
         let external_addrs = settings.external_addrs.clone();
+        let compression = settings.compression;
         drop(settings);
 
+        let mut features = vec![];
+        if compression {
+            features.push((COMPRESSION_FEATURE.to_string(), COMPRESSION_VERSION));
+        }
+
+        // Advertise the namespaced networks we multiplex over our channels
+        features.extend(self.channel.p2p().namespace_features().await);
+
+        // Advertise the features of the protocols running on our channels
+        features.extend(self.channel.p2p().features());
+
         let version = VersionMessage {
             node_id,
             version: app_version.clone(),
@@ -164,9 +176,9 @@ impl ProtocolVersion {
             resolve_recv_addr: self.channel.resolve_addr().clone(),
             ext_send_addr: external_addrs,
             /* NOTE: `features` is a list of enabled features in the
-            format Vec<(service, version)>. In the future, Protocols will
-            add their own data to this field when they are attached.*/
-            features: vec![],
+            format Vec<(service, version)>. Protocols add their own
+            data to this field using `P2p::add_feature()`.*/
+            features,
         };
         self.channel.send(&version).await?;
 
@@ -212,6 +224,13 @@ impl ProtocolVersion {
 
         // Receive version message
         let version = self.version_sub.receive().await?;
+
+        // Enable compression if both of us support it
+        let compression = self.settings.read().await.compression &&
+            version.features.iter().any(|(feature, feature_version)| {
+                feature == COMPRESSION_FEATURE && *feature_version == COMPRESSION_VERSION
+            });
+        self.channel.set_compression(compression);
         self.channel.set_version(version).await;
 
         // Send verack
@@ -40,7 +40,7 @@ use super::{
     Session, SessionBitFlag, SESSION_INBOUND,
 };
 use crate::{
-    system::{StoppableTask, StoppableTaskPtr, Subscription},
+    system::{Publisher, PublisherPtr, StoppableTask, StoppableTaskPtr, Subscription},
     Error, Result,
 };
 
@@ -51,6 +51,8 @@ pub struct InboundSession {
     pub(in crate::net) p2p: Weak<P2p>,
     acceptors: Mutex<Vec<AcceptorPtr>>,
     accept_tasks: Mutex<Vec<StoppableTaskPtr>>,
+    /// Publisher for the outcome of inbound channel handshakes
+    handshake_publisher: PublisherPtr<(Url, Result<()>)>,
 }
 
 impl InboundSession {
@@ -60,9 +62,16 @@ impl InboundSession {
             p2p,
             acceptors: Mutex::new(Vec::new()),
             accept_tasks: Mutex::new(Vec::new()),
+            handshake_publisher: Publisher::new(),
         })
     }
 
+    /// Get notified of the address of inbound channels once their
+    /// handshake and setup is done, along with its outcome
+    pub async fn subscribe_handshakes(&self) -> Subscription<(Url, Result<()>)> {
+        self.handshake_publisher.clone().subscribe().await
+    }
+
     /// Starts the inbound session. Begins by accepting connections and fails
     /// if the addresses are not configured. Then runs the channel subscription
     /// loop.
@@ -186,7 +195,10 @@ impl InboundSession {
 
         let stop_sub = channel.subscribe_stop().await;
 
-        match self.register_channel(channel.clone(), ex.clone()).await {
+        let res = self.register_channel(channel.clone(), ex.clone()).await;
+        self.handshake_publisher.notify((channel.info.connect_addr.clone(), res.clone())).await;
+
+        match res {
             Ok(()) => {
                 if let Ok(stop_sub) = stop_sub {
                     // Wait for a stop signal, then cleanup.
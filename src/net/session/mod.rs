@@ -96,6 +96,32 @@ pub async fn remove_sub_on_stop(
     debug!(target: "net::session::remove_sub_on_stop()", "[END]");
 }
 
+/// Removes a namespaced channel from the list of connected channels of
+/// its namespace when a stop signal is received. Namespaces don't dial
+/// out themselves, so there's no hostlist entry to downgrade.
+pub async fn remove_namespace_on_stop(
+    p2p: P2pPtr,
+    channel: ChannelPtr,
+    stop_sub: Subscription<Error>,
+) {
+    debug!(target: "net::session::remove_namespace_on_stop()", "[START]");
+    let hosts = p2p.hosts();
+
+    stop_sub.receive().await;
+
+    debug!(
+        target: "net::session::remove_namespace_on_stop()",
+        "Received stop event. Removing channel {:?}", channel,
+    );
+
+    hosts.unregister(channel.address());
+
+    if !p2p.is_connected() {
+        hosts.disconnect_publisher.notify(Error::NetworkNotConnected).await;
+    }
+    debug!(target: "net::session::remove_namespace_on_stop()", "[END]");
+}
+
 /// Session trait. Defines methods that are used across sessions.
 /// Implements registering the channel and initializing the channel by
 /// performing a network handshake.
@@ -124,6 +150,11 @@ pub trait Session: Sync {
         let protocols =
             p2p.protocol_registry().attach(self.type_id(), channel.clone(), p2p.clone()).await;
 
+        // Open the namespaced channels multiplexed over this one as well,
+        // so they can buffer messages during the handshake too.
+        let namespaces =
+            p2p.attach_namespaces(self.type_id(), channel.clone(), executor.clone()).await;
+
         // Perform the handshake protocol
         let protocol_version = ProtocolVersion::new(channel.clone(), p2p.settings().clone()).await;
         debug!(
@@ -161,6 +192,9 @@ pub trait Session: Sync {
             protocol.start(executor.clone()).await?;
         }
 
+        // Start the namespaces our peer shares with us
+        p2p.start_namespaces(channel.clone(), namespaces, executor.clone()).await?;
+
         trace!(target: "net::session::register_channel()", "[END]");
 
         Ok(())
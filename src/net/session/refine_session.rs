@@ -29,7 +29,6 @@ use futures::{
     future::{select, Either},
     pin_mut,
 };
-use smol::Timer;
 use std::{
     sync::{Arc, Weak},
     time::{Duration, Instant, UNIX_EPOCH},
@@ -48,7 +47,7 @@ use crate::{
         protocol::ProtocolVersion,
         session::{Session, SessionBitFlag, SESSION_REFINE},
     },
-    system::{sleep, StoppableTask, StoppableTaskPtr},
+    system::{sleep, timeout::delay, StoppableTask, StoppableTaskPtr},
     Error,
 };
 
@@ -136,7 +135,7 @@ impl RefineSession {
                 // Ensure the channel gets stopped by adding a timeout to the handshake. Otherwise if
                 // the handshake does not finish channel.stop() will never get called, resulting in
                 // zombie processes.
-                let timeout = Timer::after(Duration::from_secs(5));
+                let timeout = delay(Duration::from_secs(5));
 
                 pin_mut!(timeout);
                 pin_mut!(handshake);
@@ -104,6 +104,8 @@ pub struct Settings {
     /// Do not ban nodes that send messages without dispatchers if set
     /// to `Relaxed`. For most uses, should be set to `Strict`.
     pub ban_policy: BanPolicy,
+    /// Advertise and use payload compression with peers supporting it
+    pub compression: bool,
 }
 
 impl Default for Settings {
@@ -137,6 +139,7 @@ impl Default for Settings {
             time_with_no_connections: 30,
             blacklist: vec![],
             ban_policy: BanPolicy::Strict,
+            compression: true,
         }
     }
 }
@@ -265,6 +268,10 @@ pub struct SettingsOpt {
     #[serde(default)]
     #[structopt(skip)]
     pub ban_policy: BanPolicy,
+
+    /// Advertise and use payload compression with peers supporting it
+    #[structopt(long)]
+    pub compression: Option<bool>,
 }
 
 impl From<SettingsOpt> for Settings {
@@ -311,6 +318,7 @@ impl From<SettingsOpt> for Settings {
                 .unwrap_or(def.time_with_no_connections),
             blacklist: opt.blacklist,
             ban_policy: opt.ban_policy,
+            compression: opt.compression.unwrap_or(def.compression),
         }
     }
 }
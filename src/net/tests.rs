@@ -20,14 +20,29 @@
 
 use std::{collections::HashSet, net::TcpListener, panic, sync::Arc};
 
+use darkfi_serial::{SerialDecodable, SerialEncodable};
 use log::{error, info, warn};
 use rand::{prelude::SliceRandom, rngs::ThreadRng, Rng};
 use smol::{channel, future, Executor};
 use url::Url;
 
 use crate::{
-    net::{hosts::HostColor, P2p, Settings},
-    system::sleep,
+    impl_p2p_message,
+    net::{
+        hosts::HostColor,
+        message::{COMPRESSION_FEATURE, COMPRESSION_VERSION, MAGIC_BYTES_COMPRESSED},
+        protocol::protocol_dandelion::{
+            DandelionHandler, DandelionMessage, DandelionPhase, DandelionSettings,
+        },
+        session::SESSION_DEFAULT,
+        transport::sim::{SimNetwork, SimSettings},
+        Message, P2p, Settings,
+    },
+    system::{
+        msleep,
+        sim_clock::{SimClock, SimClockPtr},
+        sleep,
+    },
 };
 
 // Number of nodes to spawn and number of peers each node connects to
@@ -274,6 +289,44 @@ macro_rules! test_body {
     };
 }
 
+/// Like `test_body!`, but for tests over a simulated network. All the
+/// threads enter a simulated clock, which is passed to the test, so the
+/// nodes' timers run on virtual time.
+macro_rules! sim_test_body {
+    ($real_call:ident) => {
+        init_logger();
+
+        let ex = Arc::new(Executor::new());
+        let ex_ = ex.clone();
+        let clock = SimClock::new();
+        let (signal, shutdown) = channel::unbounded::<()>();
+
+        panic::set_hook(Box::new(|panic_info| {
+            error!("Panic occurred: {:?}", panic_info);
+        }));
+
+        // Run a thread for each node.
+        easy_parallel::Parallel::new()
+            .each(0..N_NODES, |_| {
+                let _guard = clock.enter();
+                let result = std::panic::catch_unwind(|| {
+                    let res = future::block_on(ex.run(shutdown.recv()));
+                    res
+                });
+                if let Err(err) = result {
+                    error!("Thread panicked: {:?}", err);
+                }
+            })
+            .finish(|| {
+                let _guard = clock.enter();
+                future::block_on(async {
+                    $real_call(ex_, clock.clone()).await;
+                    drop(signal);
+                });
+            });
+    };
+}
+
 #[test]
 fn p2p_test() {
     test_body!(p2p_test_real);
@@ -473,3 +526,365 @@ async fn p2p_test_real(ex: Arc<Executor<'static>>) {
         p2p.clone().stop().await;
     }
 }
+
+#[test]
+fn p2p_sim_partition() {
+    sim_test_body!(p2p_sim_partition_real);
+}
+
+/// Auxiliary function to wait until each of the provided nodes is
+/// connected to the expected number of peers, or the timeout expires.
+/// Over a simulated network, the timeout is in virtual time.
+async fn wait_for_peers(instances: &[Arc<P2p>], expected: usize, timeout: u64) -> bool {
+    for _ in 0..timeout * 10 {
+        if instances.iter().all(|p2p| p2p.hosts().peers().len() == expected) {
+            return true
+        }
+        msleep(100).await;
+    }
+    false
+}
+
+async fn p2p_sim_partition_real(ex: Arc<Executor<'static>>, clock: SimClockPtr) {
+    // ============================================================
+    // 1. Create a simulated network and a fully connected set of
+    //    manual nodes on it.
+    // ============================================================
+    let settings = SimSettings { seed: 7, loss: 0.05, ..Default::default() };
+    let network =
+        SimNetwork::new("p2p_sim_partition", settings, clock.clone(), ex.clone()).unwrap();
+    let names: Vec<String> = (0..N_NODES).map(|i| format!("node{i}")).collect();
+
+    let mut instances = vec![];
+    for (i, name) in names.iter().enumerate() {
+        // Each node dials the nodes after it, so we get a full mesh
+        let peers = names[i + 1..].iter().map(|peer| network.endpoint(peer, 26661)).collect();
+        let addr = network.endpoint(name, 26661);
+        let settings = Settings {
+            localnet: true,
+            inbound_addrs: vec![addr.clone()],
+            external_addrs: vec![addr],
+            outbound_connections: 0,
+            outbound_connect_timeout: 2,
+            inbound_connections: usize::MAX,
+            peers,
+            node_id: name.clone(),
+            allowed_transports: vec!["sim".to_string()],
+            ..Default::default()
+        };
+        let p2p = P2p::new(settings, ex.clone()).await.unwrap();
+        p2p.clone().start().await.unwrap();
+        instances.push(p2p);
+    }
+
+    assert!(wait_for_peers(&instances, N_NODES - 1, 30).await);
+    info!("Simulated mesh connected at virtual time {:?}", network.now());
+
+    // ============================================================
+    // 2. Partition the first node off the rest of the network and
+    //    verify everyone dropped the connections across the split.
+    // ============================================================
+    let rest: Vec<&str> = names[1..].iter().map(|name| name.as_str()).collect();
+    network.partition(&[&[names[0].as_str()], &rest]);
+
+    assert!(wait_for_peers(&instances[..1], 0, 30).await);
+    assert!(wait_for_peers(&instances[1..], N_NODES - 2, 30).await);
+
+    // ============================================================
+    // 3. Heal the partition and verify the manual sessions
+    //    reconnect the mesh.
+    // ============================================================
+    network.heal();
+    assert!(wait_for_peers(&instances, N_NODES - 1, 60).await);
+
+    for p2p in instances {
+        p2p.stop().await;
+    }
+    network.stop().await;
+}
+
+#[derive(Clone, Debug, PartialEq, SerialEncodable, SerialDecodable)]
+struct ChatMessage(String);
+impl_p2p_message!(ChatMessage, "chat");
+
+#[test]
+fn p2p_namespaces() {
+    sim_test_body!(p2p_namespaces_real);
+}
+
+async fn p2p_namespaces_real(ex: Arc<Executor<'static>>, clock: SimClockPtr) {
+    // ============================================================
+    // 1. Create a fully connected set of nodes, all sharing the
+    //    chat namespace. The last node runs an incompatible version
+    //    of the tasks namespace. node0 reaches node2 through a peer
+    //    configured on its chat namespace.
+    // ============================================================
+    let network =
+        SimNetwork::new("p2p_namespaces", SimSettings::default(), clock.clone(), ex.clone())
+            .unwrap();
+    let names: Vec<String> = (0..3).map(|i| format!("node{i}")).collect();
+
+    let mut instances = vec![];
+    let mut chats = vec![];
+    let mut tasks = vec![];
+    for (i, name) in names.iter().enumerate() {
+        let mut peers: Vec<Url> =
+            names[i + 1..].iter().map(|peer| network.endpoint(peer, 26661)).collect();
+        let chat_peers = if i == 0 { vec![peers.pop().unwrap()] } else { vec![] };
+        let addr = network.endpoint(name, 26661);
+        let settings = Settings {
+            localnet: true,
+            inbound_addrs: vec![addr.clone()],
+            external_addrs: vec![addr],
+            outbound_connections: 0,
+            inbound_connections: usize::MAX,
+            peers,
+            node_id: name.clone(),
+            allowed_transports: vec!["sim".to_string()],
+            ..Default::default()
+        };
+        let p2p = P2p::new(settings, ex.clone()).await.unwrap();
+
+        let chat = p2p.namespace("chat", semver::Version::new(0, 1, 0)).await.unwrap();
+        assert!(chat.settings().read().await.peers.is_empty());
+        chat.settings().write().await.peers = chat_peers;
+        let tasks_version = if i == 2 { 2 } else { 1 };
+        let task = p2p.namespace("tasks", semver::Version::new(tasks_version, 0, 0)).await.unwrap();
+        assert!(p2p.namespace("chat", semver::Version::new(0, 1, 0)).await.is_err());
+        assert!(chat.namespace("nested", semver::Version::new(0, 1, 0)).await.is_err());
+
+        p2p.clone().start().await.unwrap();
+        instances.push(p2p);
+        chats.push(chat);
+        tasks.push(task);
+    }
+
+    // ============================================================
+    // 2. Verify the namespaces were only opened between nodes
+    //    running compatible versions of them.
+    // ============================================================
+    assert!(wait_for_peers(&instances, 2, 30).await);
+    assert!(wait_for_peers(&chats, 2, 30).await);
+    assert!(wait_for_peers(&tasks[..2], 1, 30).await);
+    assert!(tasks[2].hosts().peers().is_empty());
+
+    // ============================================================
+    // 3. Send a message over a namespaced channel and verify it
+    //    arrives on the same namespace on the other end. Messages
+    //    the namespace has no dispatcher for are skipped, without
+    //    closing it.
+    // ============================================================
+    // node0 dialed node1, so on node1's side it's the inbound channel
+    let node2 = network.endpoint(&names[2], 26661);
+    let receiver = chats[1].hosts().peers().into_iter().find(|c| c.address() != &node2).unwrap();
+    assert_eq!(receiver.namespace(), Some("chat"));
+
+    let message = ChatMessage("hello".to_string());
+    let node1 = network.endpoint(&names[1], 26661);
+    let channel = chats[0].hosts().peers().into_iter().find(|c| c.address() == &node1).unwrap();
+    channel.send(&message).await.unwrap();
+    msleep(500).await;
+    assert!(!receiver.is_stopped());
+    assert!(wait_for_peers(&chats, 2, 30).await);
+
+    receiver.message_subsystem().add_dispatch::<ChatMessage>().await;
+    let chat_sub = receiver.subscribe_msg::<ChatMessage>().await.unwrap();
+    channel.send(&message).await.unwrap();
+    assert_eq!(*chat_sub.receive().await.unwrap(), message);
+
+    // ============================================================
+    // 4. Stop a namespace and verify the connections underneath
+    //    are kept alive.
+    // ============================================================
+    chats[0].stop().await;
+    assert!(wait_for_peers(&chats[..1], 0, 30).await);
+    assert!(wait_for_peers(&instances, 2, 30).await);
+    assert!(wait_for_peers(&tasks[..2], 1, 30).await);
+
+    for p2p in instances {
+        p2p.stop().await;
+    }
+    network.stop().await;
+}
+
+#[derive(Clone, Debug, PartialEq, SerialEncodable, SerialDecodable)]
+struct BulkMessage(Vec<u8>);
+impl_p2p_message!(BulkMessage, "bulk", compressible);
+
+#[test]
+fn p2p_compression() {
+    sim_test_body!(p2p_compression_real);
+}
+
+async fn p2p_compression_real(ex: Arc<Executor<'static>>, clock: SimClockPtr) {
+    // ============================================================
+    // 1. Create a fully connected set of nodes, the last one not
+    //    supporting compression.
+    // ============================================================
+    let network =
+        SimNetwork::new("p2p_compression", SimSettings::default(), clock.clone(), ex.clone())
+            .unwrap();
+    let names: Vec<String> = (0..3).map(|i| format!("node{i}")).collect();
+
+    let mut instances = vec![];
+    for (i, name) in names.iter().enumerate() {
+        let peers = names[i + 1..].iter().map(|peer| network.endpoint(peer, 26661)).collect();
+        let addr = network.endpoint(name, 26661);
+        let settings = Settings {
+            localnet: true,
+            inbound_addrs: vec![addr.clone()],
+            external_addrs: vec![addr],
+            outbound_connections: 0,
+            inbound_connections: usize::MAX,
+            peers,
+            node_id: name.clone(),
+            allowed_transports: vec!["sim".to_string()],
+            compression: i != 2,
+            ..Default::default()
+        };
+        let p2p = P2p::new(settings, ex.clone()).await.unwrap();
+        p2p.clone().start().await.unwrap();
+        instances.push(p2p);
+    }
+
+    assert!(wait_for_peers(&instances, 2, 30).await);
+
+    // ============================================================
+    // 2. Verify compression was only negotiated between the nodes
+    //    that both advertised it, and that compressed packets are
+    //    refused on channels where it wasn't.
+    // ============================================================
+    let mut frame = MAGIC_BYTES_COMPRESSED.to_vec();
+    frame.push(BulkMessage::NAME.len() as u8);
+    frame.extend_from_slice(BulkMessage::NAME.as_bytes());
+
+    for (i, p2p) in instances.iter().enumerate() {
+        let mut compressed = 0;
+        for channel in p2p.hosts().peers() {
+            let negotiated =
+                i != 2 && channel.has_feature(COMPRESSION_FEATURE, COMPRESSION_VERSION).await;
+            assert_eq!(channel.is_compressed(), negotiated);
+            assert_eq!(channel.read_command(&mut &frame[..]).await.is_ok(), negotiated);
+            compressed += negotiated as usize;
+        }
+        assert_eq!(compressed, if i == 2 { 0 } else { 1 });
+    }
+
+    // ============================================================
+    // 3. Send a large message from the first node to its peers, and
+    //    verify it arrives whether it was compressed or not.
+    // ============================================================
+    let mut subs = vec![];
+    for p2p in &instances[1..] {
+        let mut receivers = vec![];
+        for channel in p2p.hosts().peers() {
+            channel.message_subsystem().add_dispatch::<BulkMessage>().await;
+            receivers.push(channel.subscribe_msg::<BulkMessage>().await.unwrap());
+        }
+        subs.push(receivers);
+    }
+
+    let message = BulkMessage(vec![42; 64 * 1024]);
+    for channel in instances[0].hosts().peers() {
+        channel.send(&message).await.unwrap();
+    }
+
+    for receivers in &subs {
+        let received = receivers.iter().map(|sub| Box::pin(sub.receive()));
+        let (received, _, _) = futures::future::select_all(received).await;
+        assert_eq!(*received.unwrap(), message);
+    }
+
+    for p2p in instances {
+        p2p.stop().await;
+    }
+    network.stop().await;
+}
+
+#[derive(Clone, Debug, PartialEq, SerialEncodable, SerialDecodable)]
+struct TestTx(u32);
+impl_p2p_message!(TestTx, "test_tx");
+impl DandelionMessage for TestTx {
+    const STEM_NAME: &'static str = "test_tx_stem";
+}
+
+#[test]
+fn p2p_dandelion_eviction() {
+    sim_test_body!(p2p_dandelion_eviction_real);
+}
+
+async fn p2p_dandelion_eviction_real(ex: Arc<Executor<'static>>, clock: SimClockPtr) {
+    // ============================================================
+    // 1. Create two nodes, the first one dialing the second over an
+    //    outbound slot, so it becomes its stem peer. The first
+    //    node's stempool only fits a single message.
+    // ============================================================
+    let network = SimNetwork::new(
+        "p2p_dandelion_eviction",
+        SimSettings::default(),
+        clock.clone(),
+        ex.clone(),
+    )
+    .unwrap();
+    let names: Vec<String> = (0..2).map(|i| format!("node{i}")).collect();
+
+    let mut instances = vec![];
+    let mut handlers = vec![];
+    for (i, name) in names.iter().enumerate() {
+        let addr = network.endpoint(name, 26661);
+        let settings = Settings {
+            localnet: true,
+            inbound_addrs: vec![addr.clone()],
+            external_addrs: vec![addr],
+            outbound_connections: if i == 0 { 1 } else { 0 },
+            inbound_connections: usize::MAX,
+            node_id: name.clone(),
+            allowed_transports: vec!["sim".to_string()],
+            ..Default::default()
+        };
+        let p2p = P2p::new(settings, ex.clone()).await.unwrap();
+        if i == 0 {
+            p2p.hosts().whitelist_host(&network.endpoint(&names[1], 26661), 0).unwrap();
+        }
+
+        let settings = DandelionSettings {
+            fluff_probability: 0.0,
+            embargo_timeout: 3600,
+            embargo_jitter: 0,
+            max_stempool: 1,
+            ..Default::default()
+        };
+        let handler =
+            DandelionHandler::<TestTx>::new(&p2p, "test_tx", SESSION_DEFAULT, settings).await;
+        p2p.clone().start().await.unwrap();
+        instances.push(p2p);
+        handlers.push(handler);
+    }
+
+    assert!(wait_for_peers(&instances, 1, 30).await);
+
+    // ============================================================
+    // 2. Send a message in stem phase, then a second one evicting
+    //    it from the full stempool.
+    // ============================================================
+    assert_eq!(handlers[0].broadcast(&TestTx(1)).await, DandelionPhase::Stem);
+    assert_eq!(handlers[0].broadcast(&TestTx(2)).await, DandelionPhase::Stem);
+    assert_eq!(handlers[0].stempool().await, vec![TestTx(2)]);
+
+    // ============================================================
+    // 3. Verify the evicted message got fluffed to the network, and
+    //    handed to the application to move into its public pool.
+    // ============================================================
+    assert_eq!(handlers[0].embargo_receiver.recv().await.unwrap(), TestTx(1));
+    loop {
+        let (_, message, phase) = handlers[1].receiver.recv().await.unwrap();
+        if message == TestTx(1) && phase == DandelionPhase::Fluff {
+            break
+        }
+    }
+
+    for p2p in instances {
+        p2p.stop().await;
+    }
+    network.stop().await;
+}
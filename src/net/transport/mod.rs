@@ -47,6 +47,10 @@ pub(crate) mod nym;
 #[cfg(feature = "p2p-unix")]
 pub(crate) mod unix;
 
+/// Simulated in-process transport
+#[cfg(any(test, feature = "p2p-sim"))]
+pub mod sim;
+
 /// Dialer variants
 #[derive(Debug, Clone)]
 pub enum DialerVariant {
@@ -78,6 +82,10 @@ pub enum DialerVariant {
 
     /// SOCKS5 proxy
     Socks5(socks5::Socks5Dialer),
+
+    /// Simulated network
+    #[cfg(any(test, feature = "p2p-sim"))]
+    Sim(sim::SimDialer),
 }
 
 /// Listener variants
@@ -96,6 +104,10 @@ pub enum ListenerVariant {
     /// Unix socket
     #[cfg(feature = "p2p-unix")]
     Unix(unix::UnixListener),
+
+    /// Simulated network
+    #[cfg(any(test, feature = "p2p-sim"))]
+    Sim(sim::SimListener),
 }
 
 /// A dialer that is able to transparently operate over arbitrary transports.
@@ -200,6 +212,15 @@ impl Dialer {
                 Ok(Self { endpoint, variant })
             }
 
+            #[cfg(any(test, feature = "p2p-sim"))]
+            "sim" => {
+                // Build a simulated network dialer
+                enforce_hostport!(endpoint);
+                let variant = sim::SimDialer::new().await?;
+                let variant = DialerVariant::Sim(variant);
+                Ok(Self { endpoint, variant })
+            }
+
             x => {
                 error!("[P2P] Requested unsupported transport: {}", x);
                 Err(io::Error::from_raw_os_error(libc::ENETUNREACH))
@@ -271,6 +292,21 @@ impl Dialer {
                 let stream = dialer.do_dial().await?;
                 Ok(Box::new(stream))
             }
+
+            #[cfg(any(test, feature = "p2p-sim"))]
+            DialerVariant::Sim(dialer) => {
+                let stream = dialer.do_dial(&self.endpoint).await?;
+                Ok(Box::new(stream))
+            }
+        }
+    }
+
+    /// Set the local endpoint a simulated network dialer dials from,
+    /// so partitions and churn apply to it. No-op for other transports.
+    #[cfg(any(test, feature = "p2p-sim"))]
+    pub(crate) fn set_sim_source(&mut self, source: &Url) {
+        if let DialerVariant::Sim(dialer) = &mut self.variant {
+            dialer.set_source(source);
         }
     }
 
@@ -326,6 +362,15 @@ impl Listener {
                 Ok(Self { endpoint, variant })
             }
 
+            #[cfg(any(test, feature = "p2p-sim"))]
+            "sim" => {
+                // Build a simulated network listener
+                enforce_hostport!(endpoint);
+                let variant = sim::SimListener::new().await?;
+                let variant = ListenerVariant::Sim(variant);
+                Ok(Self { endpoint, variant })
+            }
+
             x => {
                 error!("[P2P] Requested unsupported transport: {}", x);
                 Err(io::Error::from_raw_os_error(libc::ENETUNREACH))
@@ -367,6 +412,12 @@ impl Listener {
                 let l = listener.do_listen(&path).await?;
                 Ok(Box::new(l))
             }
+
+            #[cfg(any(test, feature = "p2p-sim"))]
+            ListenerVariant::Sim(listener) => {
+                let l = listener.do_listen(&self.endpoint).await?;
+                Ok(Box::new(l))
+            }
         }
     }
 
@@ -0,0 +1,789 @@
+/* This file is part of DarkFi (https://dark.fi)
+ *
+ * Copyright (C) 2020-2024 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! In-process simulated network transport.
+//!
+//! Endpoints look like `sim://<node>.<network>:<port>`, where `network`
+//! is the name of a [`SimNetwork`] created beforehand. Connections are
+//! byte pipes between the two ends, and every write gets scheduled for
+//! delivery on the network's virtual clock, using the network's seeded
+//! RNG to pick its latency and whether it gets lost and retransmitted.
+//! The network can also be partitioned, and nodes taken offline, which
+//! resets all their connections.
+//!
+//! The network runs on a [`SimClock`], which the threads running the
+//! simulated nodes have to [enter](SimClock::enter), so their sleeps and
+//! timeouts (`system::sleep`, `system::timeout`, channel, handshake and
+//! request timeouts) wait for virtual time as well. The network driver
+//! advances the clock to the earliest scheduled delivery or timer. Before
+//! firing a timer it waits until the nodes were idle for a short while
+//! in real time, so nodes busy handling a delivery get to respond before
+//! their peers' timeouts expire.
+//!
+//! Deliveries happen in virtual time order, so given the same sequence
+//! of writes a network with the same seed delivers them identically.
+//! Tasks still run concurrently on the executor threads, and timestamps
+//! taken from the system time (like host `last_seen`) are real, so runs
+//! aren't bit-for-bit reproducible, but simulated minutes of protocol
+//! timers pass in moments.
+//!
+//! Only available in tests and with the `p2p-sim` feature.
+
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::{BinaryHeap, HashMap, HashSet, VecDeque},
+    io,
+    pin::Pin,
+    sync::{Arc, LazyLock, Mutex, Weak},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use futures::{
+    future::{select, Either},
+    pin_mut,
+};
+use log::{debug, error};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use smol::{
+    channel::{Receiver, Sender},
+    io::{AsyncRead, AsyncWrite},
+    Timer,
+};
+use url::Url;
+
+use super::{PtListener, PtStream};
+use crate::{
+    system::{
+        sim_clock::{SimClock, SimClockPtr},
+        ExecutorPtr, StoppableTask, StoppableTaskPtr,
+    },
+    Error, Result,
+};
+
+/// Registry of running simulated networks, by name
+static NETWORKS: LazyLock<Mutex<HashMap<String, Weak<SimNetwork>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// First port handed out to dialing ends of simulated connections
+const EPHEMERAL_PORT_START: u16 = 49152;
+
+/// Maximum number of times a single write can get lost
+const MAX_RETRANSMISSIONS: usize = 8;
+
+/// Simulated network configuration.
+#[derive(Clone, Debug)]
+pub struct SimSettings {
+    /// Seed of the network RNG
+    pub seed: u64,
+    /// Minimum one-way latency of a write
+    pub min_latency: Duration,
+    /// Maximum one-way latency of a write
+    pub max_latency: Duration,
+    /// Probability of a write getting lost, in the range `[0.0, 1.0]`.
+    /// Lost writes are retransmitted, like TCP does, so they only
+    /// arrive later.
+    pub loss: f64,
+    /// Delay added for each time a write got lost
+    pub retransmit_timeout: Duration,
+    /// Real time spent per unit of virtual time. With the default of
+    /// zero, the clock jumps straight to the next event.
+    pub time_scale: f64,
+    /// Real time the nodes have to be idle for before the clock jumps
+    /// to the next timer
+    pub settle_time: Duration,
+}
+
+impl Default for SimSettings {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            min_latency: Duration::from_millis(10),
+            max_latency: Duration::from_millis(50),
+            loss: 0.0,
+            retransmit_timeout: Duration::from_millis(200),
+            time_scale: 0.0,
+            settle_time: Duration::from_millis(10),
+        }
+    }
+}
+
+/// One direction of a simulated connection.
+#[derive(Default)]
+struct Pipe {
+    /// Delivered bytes waiting to be read
+    buffer: VecDeque<u8>,
+    /// Virtual time of the last scheduled delivery, so writes
+    /// arrive in the order they were made
+    last_delivery: Duration,
+    /// Flag indicating no more data will arrive
+    closed: bool,
+    /// Reader waiting for data
+    waker: Option<Waker>,
+}
+
+type PipePtr = Arc<Mutex<Pipe>>;
+
+impl Pipe {
+    /// Close the pipe and wake up its reader. If `reset` is set,
+    /// undelivered data gets discarded.
+    fn close(&mut self, reset: bool) {
+        self.closed = true;
+        if reset {
+            self.buffer.clear();
+        }
+        if let Some(waker) = self.waker.take() {
+            waker.wake()
+        }
+    }
+}
+
+/// A write scheduled for delivery on the virtual clock.
+struct Delivery {
+    /// Virtual time of the delivery
+    due: Duration,
+    /// Scheduling sequence number, ordering deliveries due together
+    seq: u64,
+    /// Pipe to deliver to
+    pipe: PipePtr,
+    /// Written bytes, or `None` when the writing end got closed
+    data: Option<Vec<u8>>,
+}
+
+impl PartialEq for Delivery {
+    fn eq(&self, other: &Self) -> bool {
+        self.due == other.due && self.seq == other.seq
+    }
+}
+
+impl Eq for Delivery {}
+
+impl PartialOrd for Delivery {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Delivery {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.due, self.seq).cmp(&(other.due, other.seq))
+    }
+}
+
+/// An established simulated connection between two nodes.
+struct Link {
+    /// Dialing node, if known
+    source: Option<String>,
+    /// Listening node
+    target: String,
+    /// Source to target direction
+    forward: PipePtr,
+    /// Target to source direction
+    backward: PipePtr,
+}
+
+impl Link {
+    fn involves(&self, node: &str) -> bool {
+        self.target == node || self.source.as_deref() == Some(node)
+    }
+
+    fn reset(&self) {
+        self.forward.lock().unwrap().close(true);
+        self.backward.lock().unwrap().close(true);
+    }
+
+    fn is_closed(&self) -> bool {
+        self.forward.lock().unwrap().closed && self.backward.lock().unwrap().closed
+    }
+}
+
+/// Mutable state of a simulated network.
+struct SimState {
+    /// Deliveries sequence counter
+    seq: u64,
+    /// Seeded network RNG
+    rng: StdRng,
+    /// Scheduled deliveries, earliest first
+    queue: BinaryHeap<Reverse<Delivery>>,
+    /// Listeners mapped by node and port
+    listeners: HashMap<(String, u16), Sender<(SimStream, Url)>>,
+    /// Next port handed out to a dialing end
+    next_port: u16,
+    /// Nodes currently offline
+    offline: HashSet<String>,
+    /// Partition group of each node, if the network is partitioned.
+    /// Nodes not in the map form a group of their own.
+    partitions: Option<HashMap<String, usize>>,
+    /// Established connections
+    links: Vec<Link>,
+}
+
+impl SimState {
+    /// Check if the two nodes are able to reach each other
+    fn reachable(&self, a: Option<&str>, b: &str) -> bool {
+        let Some(ref partitions) = self.partitions else { return true };
+        let group = |node: Option<&str>| node.and_then(|n| partitions.get(n)).copied();
+        group(a) == group(Some(b))
+    }
+}
+
+pub type SimNetworkPtr = Arc<SimNetwork>;
+
+/// Simulated network, connecting the `sim://` endpoints of its nodes.
+pub struct SimNetwork {
+    /// Name of the network, as used in endpoint hosts
+    name: String,
+    /// Network configuration
+    settings: SimSettings,
+    /// Network state
+    state: Mutex<SimState>,
+    /// Virtual clock of the network
+    clock: SimClockPtr,
+    /// Driver task advancing the clock and delivering writes
+    task: StoppableTaskPtr,
+}
+
+impl SimNetwork {
+    /// Create a new simulated network with the given name and settings,
+    /// running on the provided clock, and start its driver on the provided
+    /// executor. Fails if a network with the same name is already running.
+    pub fn new(
+        name: &str,
+        settings: SimSettings,
+        clock: SimClockPtr,
+        executor: ExecutorPtr,
+    ) -> io::Result<SimNetworkPtr> {
+        let mut networks = NETWORKS.lock().unwrap();
+        networks.retain(|_, network| network.strong_count() > 0);
+        if networks.contains_key(name) {
+            return Err(io::Error::from(io::ErrorKind::AddrInUse))
+        }
+
+        let state = SimState {
+            seq: 0,
+            rng: StdRng::seed_from_u64(settings.seed),
+            queue: BinaryHeap::new(),
+            listeners: HashMap::new(),
+            next_port: EPHEMERAL_PORT_START,
+            offline: HashSet::new(),
+            partitions: None,
+            links: vec![],
+        };
+
+        let network = Arc::new(Self {
+            name: name.to_string(),
+            settings,
+            state: Mutex::new(state),
+            clock,
+            task: StoppableTask::new(),
+        });
+        networks.insert(name.to_string(), Arc::downgrade(&network));
+        drop(networks);
+
+        network.task.clone().start(
+            network.clone().run(),
+            |res| async move {
+                match res {
+                    Ok(()) | Err(Error::DetachedTaskStopped) => { /* Do nothing */ }
+                    Err(e) => error!(target: "net::sim::run", "Simulated network failed: {e}"),
+                }
+            },
+            Error::DetachedTaskStopped,
+            executor,
+        );
+
+        Ok(network)
+    }
+
+    /// Grab the running network with the given name
+    pub(crate) fn get(name: &str) -> Option<SimNetworkPtr> {
+        NETWORKS.lock().unwrap().get(name).and_then(|network| network.upgrade())
+    }
+
+    /// Stop the network, resetting all its connections.
+    pub async fn stop(&self) {
+        self.task.stop().await;
+        NETWORKS.lock().unwrap().remove(&self.name);
+
+        let mut state = self.state.lock().unwrap();
+        state.queue.clear();
+        state.listeners.clear();
+        for link in state.links.drain(..) {
+            link.reset();
+        }
+    }
+
+    /// Name of the network
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Current virtual time of the network clock
+    pub fn now(&self) -> Duration {
+        self.clock.now()
+    }
+
+    /// Virtual clock of the network
+    pub fn clock(&self) -> SimClockPtr {
+        self.clock.clone()
+    }
+
+    /// Endpoint of the given node and port on this network
+    pub fn endpoint(&self, node: &str, port: u16) -> Url {
+        Url::parse(&format!("sim://{node}.{}:{port}", self.name)).unwrap()
+    }
+
+    /// Split the network into the provided groups of nodes. Nodes can
+    /// only reach nodes of their own group, and established connections
+    /// between groups get reset. Nodes not listed form a group of their own.
+    pub fn partition(&self, groups: &[&[&str]]) {
+        let mut partitions = HashMap::new();
+        for (index, group) in groups.iter().enumerate() {
+            for node in group.iter() {
+                partitions.insert(node.to_string(), index);
+            }
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.partitions = Some(partitions);
+        let mut links = std::mem::take(&mut state.links);
+        links.retain(|link| {
+            if state.reachable(link.source.as_deref(), &link.target) {
+                return true
+            }
+            link.reset();
+            false
+        });
+        state.links = links;
+    }
+
+    /// Remove any partitioning of the network
+    pub fn heal(&self) {
+        self.state.lock().unwrap().partitions = None;
+    }
+
+    /// Take the given node offline or bring it back online.
+    /// Offline nodes can't dial or be dialed, and going offline
+    /// resets all their connections.
+    pub fn set_online(&self, node: &str, online: bool) {
+        let mut state = self.state.lock().unwrap();
+        if online {
+            state.offline.remove(node);
+            return
+        }
+
+        state.offline.insert(node.to_string());
+        state.links.retain(|link| {
+            if !link.involves(node) {
+                return true
+            }
+            link.reset();
+            false
+        });
+    }
+
+    /// Check if the given node is online
+    pub fn is_online(&self, node: &str) -> bool {
+        !self.state.lock().unwrap().offline.contains(node)
+    }
+
+    /// Flip the online status of each listening node with the given
+    /// probability, using the network RNG. Returns the flipped nodes.
+    pub fn churn(&self, probability: f64) -> Vec<String> {
+        let mut state = self.state.lock().unwrap();
+        let mut nodes: Vec<String> = state.listeners.keys().map(|(node, _)| node.clone()).collect();
+        nodes.sort();
+        nodes.dedup();
+        nodes.retain(|_| state.rng.gen_bool(probability.clamp(0.0, 1.0)));
+        drop(state);
+
+        for node in &nodes {
+            let online = self.is_online(node);
+            self.set_online(node, !online);
+        }
+
+        nodes
+    }
+
+    /// Register a listener for the given node and port
+    fn listen(self: &Arc<Self>, node: &str, port: u16) -> io::Result<SimAcceptor> {
+        let mut state = self.state.lock().unwrap();
+        let key = (node.to_string(), port);
+        if state.listeners.get(&key).is_some_and(|sender| !sender.is_closed()) {
+            return Err(io::Error::from(io::ErrorKind::AddrInUse))
+        }
+
+        let (sender, receiver) = smol::channel::unbounded();
+        state.listeners.insert(key, sender);
+        Ok(SimAcceptor { network: self.clone(), node: node.to_string(), port, receiver })
+    }
+
+    /// Establish a connection from the given source node to a listening node
+    fn connect(
+        self: &Arc<Self>,
+        source: Option<&str>,
+        target: &str,
+        port: u16,
+    ) -> io::Result<SimStream> {
+        let mut state = self.state.lock().unwrap();
+        if state.offline.contains(target) || source.is_some_and(|s| state.offline.contains(s)) {
+            return Err(io::Error::from(io::ErrorKind::ConnectionRefused))
+        }
+        if !state.reachable(source, target) {
+            return Err(io::Error::from(io::ErrorKind::TimedOut))
+        }
+        let Some(sender) = state.listeners.get(&(target.to_string(), port)).cloned() else {
+            return Err(io::Error::from(io::ErrorKind::ConnectionRefused))
+        };
+
+        let forward = PipePtr::default();
+        let backward = PipePtr::default();
+        let local = self.clone().stream(forward.clone(), backward.clone());
+        let remote = self.clone().stream(backward.clone(), forward.clone());
+
+        // Hand out an ephemeral port for the dialing end
+        let local_port = state.next_port;
+        state.next_port = state.next_port.checked_add(1).unwrap_or(EPHEMERAL_PORT_START);
+        let peer = self.endpoint(source.unwrap_or("anonymous"), local_port);
+
+        if sender.try_send((remote, peer)).is_err() {
+            return Err(io::Error::from(io::ErrorKind::ConnectionRefused))
+        }
+
+        state.links.retain(|link| !link.is_closed());
+        state.links.push(Link {
+            source: source.map(|s| s.to_string()),
+            target: target.to_string(),
+            forward,
+            backward,
+        });
+
+        Ok(local)
+    }
+
+    fn stream(self: Arc<Self>, tx: PipePtr, rx: PipePtr) -> SimStream {
+        SimStream { network: self, tx, rx }
+    }
+
+    /// Schedule a write on the given pipe, picking its latency
+    fn schedule(&self, pipe: &PipePtr, data: Option<Vec<u8>>) {
+        let mut state = self.state.lock().unwrap();
+
+        let (min, max) = (self.settings.min_latency, self.settings.max_latency);
+        let mut latency = if max > min { state.rng.gen_range(min..=max) } else { min };
+        let loss = self.settings.loss.clamp(0.0, 1.0);
+        for _ in 0..MAX_RETRANSMISSIONS {
+            if !state.rng.gen_bool(loss) {
+                break
+            }
+            latency += self.settings.retransmit_timeout;
+        }
+
+        let mut lock = pipe.lock().unwrap();
+        let due = (self.clock.now() + latency).max(lock.last_delivery);
+        lock.last_delivery = due;
+        drop(lock);
+
+        state.seq += 1;
+        let seq = state.seq;
+        state.queue.push(Reverse(Delivery { due, seq, pipe: pipe.clone(), data }));
+        drop(state);
+
+        self.clock.notify();
+    }
+
+    /// Driver loop, advancing the virtual clock to the next scheduled
+    /// delivery or timer, and performing all deliveries due by then.
+    async fn run(self: Arc<Self>) -> Result<()> {
+        debug!(target: "net::sim::run", "[{}] START", self.name);
+        loop {
+            let now = self.clock.now();
+            let delivery = self.state.lock().unwrap().queue.peek().map(|delivery| delivery.0.due);
+            let timer = self.clock.next_timer();
+
+            let (due, is_timer) = match (delivery, timer) {
+                (Some(delivery), Some(timer)) if timer < delivery => (timer, true),
+                (Some(delivery), _) => (delivery, false),
+                (None, Some(timer)) => (timer, true),
+                (None, None) => {
+                    self.clock.wait().await;
+                    continue
+                }
+            };
+
+            // Wait for the event when running in scaled real time, and
+            // let the nodes go idle before a timer fires, unless another
+            // event gets scheduled meanwhile.
+            if due > now {
+                let mut wait = (due - now).mul_f64(self.settings.time_scale);
+                if is_timer {
+                    wait = wait.max(self.settings.settle_time);
+                }
+                if !wait.is_zero() {
+                    let timer = Timer::after(wait);
+                    let wakeup = self.clock.wait();
+                    pin_mut!(wakeup);
+                    if let Either::Right(_) = select(timer, wakeup).await {
+                        continue
+                    }
+                }
+            }
+
+            self.clock.advance(due);
+            let mut state = self.state.lock().unwrap();
+            while state.queue.peek().is_some_and(|delivery| delivery.0.due <= due) {
+                let Reverse(delivery) = state.queue.pop().unwrap();
+                let mut pipe = delivery.pipe.lock().unwrap();
+                if pipe.closed {
+                    continue
+                }
+                match delivery.data {
+                    Some(data) => {
+                        pipe.buffer.extend(data);
+                        if let Some(waker) = pipe.waker.take() {
+                            waker.wake()
+                        }
+                    }
+                    None => pipe.close(false),
+                }
+            }
+            drop(state);
+
+            // Let the receivers run before advancing the clock further
+            smol::future::yield_now().await;
+        }
+    }
+}
+
+/// Simulated connection stream
+pub struct SimStream {
+    /// Network the connection belongs to
+    network: SimNetworkPtr,
+    /// Pipe we write to
+    tx: PipePtr,
+    /// Pipe we read from
+    rx: PipePtr,
+}
+
+impl AsyncRead for SimStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut pipe = self.rx.lock().unwrap();
+        if pipe.buffer.is_empty() {
+            if pipe.closed {
+                return Poll::Ready(Ok(0))
+            }
+            pipe.waker = Some(cx.waker().clone());
+            return Poll::Pending
+        }
+
+        let len = buf.len().min(pipe.buffer.len());
+        for (dst, src) in buf.iter_mut().zip(pipe.buffer.drain(..len)) {
+            *dst = src;
+        }
+        Poll::Ready(Ok(len))
+    }
+}
+
+impl AsyncWrite for SimStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.tx.lock().unwrap().closed {
+            return Poll::Ready(Err(io::Error::from(io::ErrorKind::BrokenPipe)))
+        }
+        self.network.schedule(&self.tx, Some(buf.to_vec()));
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if !self.tx.lock().unwrap().closed {
+            self.network.schedule(&self.tx, None);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for SimStream {
+    fn drop(&mut self) {
+        // Nobody is reading anymore, so the other end's writes fail
+        self.rx.lock().unwrap().close(true);
+        if !self.tx.lock().unwrap().closed {
+            self.network.schedule(&self.tx, None);
+        }
+    }
+}
+
+impl PtStream for SimStream {}
+
+/// Split a `sim://` endpoint into its network, node and port
+fn parse_endpoint(endpoint: &Url) -> io::Result<(SimNetworkPtr, String, u16)> {
+    let unreachable = || io::Error::from_raw_os_error(libc::ENETUNREACH);
+    let host = endpoint.host_str().ok_or_else(unreachable)?;
+    let port = endpoint.port().ok_or_else(unreachable)?;
+    let (node, network) = host.split_once('.').ok_or_else(unreachable)?;
+    let network = SimNetwork::get(network).ok_or_else(unreachable)?;
+    Ok((network, node.to_string(), port))
+}
+
+/// Simulated network Dialer implementation
+#[derive(Debug, Clone, Default)]
+pub struct SimDialer {
+    /// Node we are dialing from, used to apply partitions and churn
+    source: Option<String>,
+}
+
+impl SimDialer {
+    /// Instantiate a new [`SimDialer`] object
+    pub(crate) async fn new() -> io::Result<Self> {
+        Ok(Self { source: None })
+    }
+
+    /// Set the node we are dialing from, using its `sim://` endpoint
+    pub(crate) fn set_source(&mut self, endpoint: &Url) {
+        self.source = endpoint
+            .host_str()
+            .and_then(|host| host.split_once('.'))
+            .map(|(node, _)| node.to_string());
+    }
+
+    /// Internal dial function
+    pub(crate) async fn do_dial(&self, endpoint: &Url) -> io::Result<SimStream> {
+        debug!(target: "net::sim::do_dial", "Dialing {endpoint} simulated endpoint...");
+        let (network, node, port) = parse_endpoint(endpoint)?;
+        network.connect(self.source.as_deref(), &node, port)
+    }
+}
+
+/// Simulated network Listener implementation
+#[derive(Debug, Clone)]
+pub struct SimListener;
+
+impl SimListener {
+    /// Instantiate a new [`SimListener`] object
+    pub(crate) async fn new() -> io::Result<Self> {
+        Ok(Self {})
+    }
+
+    /// Internal listen function
+    pub(crate) async fn do_listen(&self, endpoint: &Url) -> io::Result<SimAcceptor> {
+        let (network, node, port) = parse_endpoint(endpoint)?;
+        network.listen(&node, port)
+    }
+}
+
+/// Listening end of a simulated node
+pub struct SimAcceptor {
+    /// Network we listen on
+    network: SimNetworkPtr,
+    /// Node we listen for
+    node: String,
+    /// Port we listen on
+    port: u16,
+    /// Incoming connections
+    receiver: Receiver<(SimStream, Url)>,
+}
+
+impl Drop for SimAcceptor {
+    fn drop(&mut self) {
+        self.network.state.lock().unwrap().listeners.remove(&(self.node.clone(), self.port));
+    }
+}
+
+#[async_trait]
+impl PtListener for SimAcceptor {
+    async fn next(&self) -> io::Result<(Box<dyn PtStream>, Url)> {
+        let (stream, url) = match self.receiver.recv().await {
+            Ok(r) => r,
+            Err(_) => return Err(io::Error::from(io::ErrorKind::ConnectionAborted)),
+        };
+        Ok((Box::new(stream), url))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use smol::{
+        future,
+        io::{AsyncReadExt, AsyncWriteExt},
+        Executor,
+    };
+
+    use super::*;
+
+    #[test]
+    fn sim_transport() {
+        let ex = Arc::new(Executor::new());
+        let clock = SimClock::new();
+        let _guard = clock.enter();
+        future::block_on(ex.run(async {
+            let settings = SimSettings { seed: 42, loss: 0.5, ..Default::default() };
+            let network = SimNetwork::new("simtest", settings, clock.clone(), ex.clone()).unwrap();
+
+            let alice = network.endpoint("alice", 1);
+            let listener = SimListener::new().await.unwrap().do_listen(&alice).await.unwrap();
+
+            let mut dialer = SimDialer::new().await.unwrap();
+            dialer.set_source(&network.endpoint("bob", 1));
+            let mut stream = dialer.do_dial(&alice).await.unwrap();
+            let (mut inbound, peer) = listener.next().await.unwrap();
+            assert_eq!(peer.host_str(), Some("bob.simtest"));
+
+            // Writes arrive in order, after some virtual time passed
+            for i in 0..16u8 {
+                stream.write_all(&[i; 64]).await.unwrap();
+            }
+            let mut buf = vec![0u8; 16 * 64];
+            inbound.read_exact(&mut buf).await.unwrap();
+            for (i, chunk) in buf.chunks(64).enumerate() {
+                assert!(chunk.iter().all(|b| *b == i as u8));
+            }
+            assert!(network.now() >= Duration::from_millis(10));
+
+            // Sleeps pass in virtual time
+            let start = network.now();
+            crate::system::sleep(3600).await;
+            assert!(network.now() >= start + Duration::from_secs(3600));
+
+            // Partitions reset connections and refuse new ones
+            network.partition(&[&["alice"], &["bob"]]);
+            assert_eq!(inbound.read(&mut buf).await.unwrap(), 0);
+            assert!(stream.write_all(&[0]).await.is_err());
+            assert!(dialer.do_dial(&alice).await.is_err());
+            network.heal();
+
+            // Offline nodes can't be reached
+            network.set_online("alice", false);
+            assert!(dialer.do_dial(&alice).await.is_err());
+            network.set_online("alice", true);
+            assert!(dialer.do_dial(&alice).await.is_ok());
+
+            network.stop().await;
+        }));
+    }
+}
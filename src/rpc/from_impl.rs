@@ -135,11 +135,22 @@ impl From<event_graph::Event> for JsonValue {
     fn from(event: event_graph::Event) -> JsonValue {
         let parents =
             event.parents.into_iter().map(|id| JsonStr(id.to_string())).collect::<Vec<_>>();
+        let author = match event.author() {
+            Some(public_key) => JsonStr(public_key.to_string()),
+            None => JsonValue::Null,
+        };
+        let topic = match event.topic {
+            Some(topic) => JsonStr(topic.to_string()),
+            None => JsonValue::Null,
+        };
         json_map([
+            ("id", JsonStr(event.id().to_string())),
             ("timestamp", JsonNum(event.timestamp as f64)),
             ("content", JsonStr(bs58::encode(event.content()).into_string())),
             ("parents", JsonArray(parents)),
             ("layer", JsonNum(event.layer as f64)),
+            ("author", author),
+            ("topic", topic),
         ])
     }
 }
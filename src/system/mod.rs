@@ -18,7 +18,7 @@
 
 use std::{sync::Arc, time::Duration};
 
-use smol::{future::Future, Executor, Timer};
+use smol::{future::Future, Executor};
 
 /// Condition variable which allows a task to block until woken up
 pub mod condvar;
@@ -37,11 +37,16 @@ pub use publisher::{Publisher, PublisherPtr, Subscription};
 pub mod timeout;
 pub use timeout::io_timeout;
 
+/// Virtual clock for simulated networks, driving the sleeps and
+/// timeouts of the threads that entered it
+#[cfg(any(test, feature = "p2p-sim"))]
+pub mod sim_clock;
+
 pub type ExecutorPtr = Arc<Executor<'static>>;
 
 /// Sleep for any number of seconds.
 pub async fn sleep(seconds: u64) {
-    Timer::after(Duration::from_secs(seconds)).await;
+    timeout::delay(Duration::from_secs(seconds)).await;
 }
 
 pub async fn sleep_forever() {
@@ -52,7 +57,7 @@ pub async fn sleep_forever() {
 
 /// Sleep for any number of milliseconds.
 pub async fn msleep(millis: u64) {
-    Timer::after(Duration::from_millis(millis)).await;
+    timeout::delay(Duration::from_millis(millis)).await;
 }
 
 /// Run a task until it has fully completed, irrespective of whether the parent task still exists.
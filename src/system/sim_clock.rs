@@ -0,0 +1,292 @@
+/* This file is part of DarkFi (https://dark.fi)
+ *
+ * Copyright (C) 2020-2024 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::{
+    cell::RefCell,
+    cmp::{Ordering, Reverse},
+    collections::BinaryHeap,
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use smol::channel::{Receiver, Sender};
+
+thread_local! {
+    /// Simulated clock the current thread entered, if any
+    static CURRENT: RefCell<Option<SimClockPtr>> = const { RefCell::new(None) };
+}
+
+/// Returns the simulated clock the current thread entered, if any.
+pub fn current() -> Option<SimClockPtr> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+/// Shared state of a pending simulated timer.
+#[derive(Default)]
+struct TimerSlot {
+    /// Task waiting for the timer
+    waker: Option<Waker>,
+    /// Flag indicating the timer fired
+    fired: bool,
+    /// Flag indicating nobody waits for the timer anymore
+    cancelled: bool,
+}
+
+type TimerSlotPtr = Arc<Mutex<TimerSlot>>;
+
+/// A timer scheduled on the simulated clock.
+struct Timer {
+    /// Virtual time the timer fires at
+    due: Duration,
+    /// Scheduling sequence number, ordering timers due together
+    seq: u64,
+    /// Slot to notify
+    slot: TimerSlotPtr,
+}
+
+impl PartialEq for Timer {
+    fn eq(&self, other: &Self) -> bool {
+        self.due == other.due && self.seq == other.seq
+    }
+}
+
+impl Eq for Timer {}
+
+impl PartialOrd for Timer {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Timer {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.due, self.seq).cmp(&(other.due, other.seq))
+    }
+}
+
+/// Mutable state of a simulated clock.
+struct ClockState {
+    /// Current virtual time
+    now: Duration,
+    /// Timers sequence counter
+    seq: u64,
+    /// Pending timers, earliest first
+    timers: BinaryHeap<Reverse<Timer>>,
+}
+
+pub type SimClockPtr = Arc<SimClock>;
+
+/// Virtual clock of a simulation.
+///
+/// Threads running the simulated nodes [`enter`](SimClock::enter) the
+/// clock, so `system::sleep` and the `system::timeout` functions called
+/// from them wait for virtual time instead of real time. The clock never
+/// advances by itself: whoever drives the simulation moves it forward
+/// with [`advance`](SimClock::advance), usually to the earliest pending
+/// event once the nodes went quiet.
+pub struct SimClock {
+    /// Clock state
+    state: Mutex<ClockState>,
+    /// Signal for the driver that an event was scheduled
+    wakeup: (Sender<()>, Receiver<()>),
+}
+
+impl SimClock {
+    /// Create a new simulated clock, starting at zero.
+    pub fn new() -> SimClockPtr {
+        let state = ClockState { now: Duration::ZERO, seq: 0, timers: BinaryHeap::new() };
+        Arc::new(Self { state: Mutex::new(state), wakeup: smol::channel::bounded(1) })
+    }
+
+    /// Current virtual time
+    pub fn now(&self) -> Duration {
+        self.state.lock().unwrap().now
+    }
+
+    /// Make the current thread use this clock until the returned guard
+    /// gets dropped.
+    pub fn enter(self: &Arc<Self>) -> SimClockGuard {
+        let previous = CURRENT.with(|current| current.borrow_mut().replace(self.clone()));
+        SimClockGuard { previous }
+    }
+
+    /// Sleep for the given virtual duration.
+    pub fn sleep(self: &Arc<Self>, duration: Duration) -> SimSleep {
+        SimSleep { clock: self.clone(), due: self.now() + duration, slot: None }
+    }
+
+    /// Virtual time of the earliest pending timer, if any.
+    pub fn next_timer(&self) -> Option<Duration> {
+        let mut state = self.state.lock().unwrap();
+        while let Some(Reverse(timer)) = state.timers.peek() {
+            if !timer.slot.lock().unwrap().cancelled {
+                return Some(timer.due)
+            }
+            state.timers.pop();
+        }
+        None
+    }
+
+    /// Move the clock forward to the given virtual time, firing all
+    /// timers due by then. The clock never goes backwards.
+    pub fn advance(&self, to: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.now = state.now.max(to);
+        while state.timers.peek().is_some_and(|timer| timer.0.due <= state.now) {
+            let Reverse(timer) = state.timers.pop().unwrap();
+            let mut slot = timer.slot.lock().unwrap();
+            slot.fired = true;
+            if let Some(waker) = slot.waker.take() {
+                waker.wake()
+            }
+        }
+    }
+
+    /// Signal the driver that an event got scheduled.
+    pub fn notify(&self) {
+        let _ = self.wakeup.0.try_send(());
+    }
+
+    /// Wait until an event gets scheduled.
+    pub async fn wait(&self) {
+        let _ = self.wakeup.1.recv().await;
+    }
+
+    /// Schedule a timer firing at the given virtual time.
+    fn schedule(&self, due: Duration) -> TimerSlotPtr {
+        let slot = TimerSlotPtr::default();
+        let mut state = self.state.lock().unwrap();
+        state.seq += 1;
+        let seq = state.seq;
+        state.timers.push(Reverse(Timer { due, seq, slot: slot.clone() }));
+        drop(state);
+
+        self.notify();
+        slot
+    }
+}
+
+/// Guard keeping a thread in a simulated clock, restoring the clock it
+/// used before once dropped.
+pub struct SimClockGuard {
+    previous: Option<SimClockPtr>,
+}
+
+impl Drop for SimClockGuard {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        CURRENT.with(|current| *current.borrow_mut() = previous);
+    }
+}
+
+/// Future completing once the simulated clock reaches its due time.
+pub struct SimSleep {
+    /// Clock the sleep runs on
+    clock: SimClockPtr,
+    /// Virtual time to wake up at
+    due: Duration,
+    /// Timer slot, scheduled on the first poll
+    slot: Option<TimerSlotPtr>,
+}
+
+impl Future for SimSleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.clock.now() >= self.due {
+            return Poll::Ready(())
+        }
+
+        let slot = match self.slot {
+            Some(ref slot) => slot.clone(),
+            None => {
+                let slot = self.clock.schedule(self.due);
+                self.slot = Some(slot.clone());
+                slot
+            }
+        };
+
+        let mut slot = slot.lock().unwrap();
+        if slot.fired {
+            return Poll::Ready(())
+        }
+        slot.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl fmt::Debug for SimSleep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SimSleep").field("due", &self.due).finish_non_exhaustive()
+    }
+}
+
+impl Drop for SimSleep {
+    fn drop(&mut self) {
+        // Abandoned timers don't hold the clock back
+        if let Some(ref slot) = self.slot {
+            slot.lock().unwrap().cancelled = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use smol::future::{pending, poll_once};
+
+    use super::*;
+    use crate::system::{sleep, timeout::timeout};
+
+    #[test]
+    fn sim_clock() {
+        smol::block_on(async {
+            let clock = SimClock::new();
+            let _guard = clock.enter();
+
+            // Timers get scheduled once polled, and wait for the clock
+            let long = sleep(10);
+            smol::pin!(long);
+            assert!(poll_once(&mut long).await.is_none());
+            assert_eq!(clock.next_timer(), Some(Duration::from_secs(10)));
+
+            clock.advance(Duration::from_secs(5));
+            assert!(poll_once(&mut long).await.is_none());
+            clock.advance(Duration::from_secs(10));
+            assert!(poll_once(&mut long).await.is_some());
+            assert_eq!(clock.next_timer(), None);
+
+            // Timeouts expire in virtual time
+            let expiring = timeout(Duration::from_secs(1), pending::<()>());
+            smol::pin!(expiring);
+            assert!(poll_once(&mut expiring).await.is_none());
+            clock.advance(Duration::from_secs(11));
+            assert!(poll_once(&mut expiring).await.unwrap().is_err());
+
+            // Abandoned timers don't count as pending events
+            let mut abandoned = Box::pin(timeout(Duration::from_secs(60), pending::<()>()));
+            assert!(poll_once(&mut abandoned).await.is_none());
+            assert_eq!(clock.next_timer(), Some(Duration::from_secs(71)));
+            drop(abandoned);
+            assert_eq!(clock.next_timer(), None);
+        });
+    }
+}
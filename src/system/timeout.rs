@@ -29,6 +29,43 @@ use std::{
 use pin_project_lite::pin_project;
 use smol::Timer;
 
+#[cfg(any(test, feature = "p2p-sim"))]
+use super::sim_clock::{self, SimSleep};
+
+/// A future completing after a duration of time. On threads that entered
+/// a simulated clock, the duration passes in virtual time instead.
+#[derive(Debug)]
+pub struct Delay(DelayInner);
+
+#[derive(Debug)]
+enum DelayInner {
+    Real(Timer),
+    #[cfg(any(test, feature = "p2p-sim"))]
+    Sim(SimSleep),
+}
+
+/// Create a [`Delay`] completing after the given duration.
+pub fn delay(dur: Duration) -> Delay {
+    #[cfg(any(test, feature = "p2p-sim"))]
+    if let Some(clock) = sim_clock::current() {
+        return Delay(DelayInner::Sim(clock.sleep(dur)))
+    }
+
+    Delay(DelayInner::Real(Timer::after(dur)))
+}
+
+impl Future for Delay {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        match self.0 {
+            DelayInner::Real(ref mut timer) => Pin::new(timer).poll(cx).map(|_| ()),
+            #[cfg(any(test, feature = "p2p-sim"))]
+            DelayInner::Sim(ref mut sleep) => Pin::new(sleep).poll(cx),
+        }
+    }
+}
+
 /// Awaits an I/O future or times out after a duration of time.
 ///
 /// If you want to await a non I/O future consider using
@@ -55,7 +92,7 @@ pub async fn io_timeout<F, T>(dur: Duration, f: F) -> io::Result<T>
 where
     F: Future<Output = io::Result<T>>,
 {
-    Timeout { timeout: Timer::after(dur), future: f }.await
+    Timeout { timeout: delay(dur), future: f }.await
 }
 
 pin_project! {
@@ -67,7 +104,7 @@ pin_project! {
         #[pin]
         future: F,
         #[pin]
-        timeout: Timer,
+        timeout: Delay,
     }
 }
 
@@ -125,14 +162,14 @@ pin_project! {
         #[pin]
         future: F,
         #[pin]
-        delay: Timer,
+        delay: Delay,
     }
 }
 
 impl<F> TimeoutFuture<F> {
     #[allow(dead_code)]
     pub(super) fn new(future: F, dur: Duration) -> TimeoutFuture<F> {
-        TimeoutFuture { future, delay: Timer::after(dur) }
+        TimeoutFuture { future, delay: delay(dur) }
     }
 }
 
@@ -230,11 +230,16 @@ impl std::fmt::Debug for Transaction {
 }
 
 #[cfg(feature = "net")]
-use crate::net::Message;
+use crate::net::{protocol::protocol_dandelion::DandelionMessage, Message};
 
 #[cfg(feature = "net")]
 crate::impl_p2p_message!(Transaction, "tx");
 
+#[cfg(feature = "net")]
+impl DandelionMessage for Transaction {
+    const STEM_NAME: &'static str = "stemtx";
+}
+
 /// Calls tree bounds definitions
 // TODO: increase min to 2 when fees are implement
 pub const MIN_TX_CALLS: usize = 1;